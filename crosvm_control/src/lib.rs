@@ -651,6 +651,199 @@ pub unsafe extern "C" fn crosvm_client_resize_disk(
     .unwrap_or(false)
 }
 
+/// Replaces the image of a disk of the crosvm instance whose control socket is listening on
+/// `socket_path`.
+///
+/// Arguments:
+///
+/// * `socket_path` - Path to the crosvm control socket
+/// * `disk_index` - Index of the disk, in the order of the disk options on the command line
+/// * `disk_path` - Path to the new disk image
+/// * `read_only` - Open the new disk image read-only, only allowed for read-only disks
+///
+/// The function returns true on success or false if an error occurred.
+///
+/// # Safety
+///
+/// Function is unsafe due to raw pointer usage - a null pointer could be passed in. Usage of
+/// !raw_pointer.is_null() checks should prevent unsafe behavior but the caller should ensure no
+/// null pointers are passed.
+#[no_mangle]
+pub unsafe extern "C" fn crosvm_client_disk_change_media(
+    socket_path: *const c_char,
+    disk_index: u64,
+    disk_path: *const c_char,
+    read_only: bool,
+) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            if disk_path.is_null() {
+                return false;
+            }
+            // SAFETY: just checked that `disk_path` is not null.
+            let disk_path = Path::new(unsafe { CStr::from_ptr(disk_path) }.to_str().unwrap_or(""));
+            if let Ok(disk_index) = usize::try_from(disk_index) {
+                do_disk_change_media(socket_path, disk_index, disk_path, read_only).is_ok()
+            } else {
+                false
+            }
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Ejects the image of a disk of the crosvm instance whose control socket is listening on
+/// `socket_path`, leaving the disk with no medium.
+///
+/// The function returns true on success or false if an error occurred.
+///
+/// # Safety
+///
+/// Function is unsafe due to raw pointer usage - a null pointer could be passed in. Usage of
+/// !raw_pointer.is_null() checks should prevent unsafe behavior but the caller should ensure no
+/// null pointers are passed.
+#[no_mangle]
+pub unsafe extern "C" fn crosvm_client_disk_eject(
+    socket_path: *const c_char,
+    disk_index: u64,
+) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            if let Ok(disk_index) = usize::try_from(disk_index) {
+                let request = VmRequest::DiskCommand {
+                    disk_index,
+                    command: DiskControlCommand::Eject,
+                };
+                vms_request(&request, socket_path).is_ok()
+            } else {
+                false
+            }
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Saves the contents of a display of the crosvm instance whose control socket is listening on
+/// `socket_path` to a PNG file.
+///
+/// Arguments:
+///
+/// * `socket_path` - Path to the crosvm control socket
+/// * `display_id` - Id of the display, as reported by `crosvm gpu list-displays`
+/// * `png_path` - Path of the PNG file to write
+///
+/// The function returns true on success or false if an error occurred.
+///
+/// # Safety
+///
+/// Function is unsafe due to raw pointer usage - a null pointer could be passed in. Usage of
+/// !raw_pointer.is_null() checks should prevent unsafe behavior but the caller should ensure no
+/// null pointers are passed.
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub unsafe extern "C" fn crosvm_client_gpu_screenshot(
+    socket_path: *const c_char,
+    display_id: u32,
+    png_path: *const c_char,
+) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            if png_path.is_null() {
+                return false;
+            }
+            // SAFETY: just checked that `png_path` is not null.
+            let png_path = Path::new(unsafe { CStr::from_ptr(png_path) }.to_str().unwrap_or(""));
+            let screenshot = match do_gpu_screenshot(socket_path, display_id) {
+                Ok(screenshot) => screenshot,
+                Err(_) => return false,
+            };
+            let mut png = Vec::new();
+            screenshot
+                .write_png(&mut png)
+                .and_then(|()| std::fs::write(png_path, png))
+                .is_ok()
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Starts recording a display of the crosvm instance whose control socket is listening on
+/// `socket_path` to a video file.
+///
+/// Arguments:
+///
+/// * `socket_path` - Path to the crosvm control socket
+/// * `display_id` - Id of the display, as reported by `crosvm gpu list-displays`
+/// * `video_path` - Path of the video file to write, whose extension selects the format
+///
+/// The function returns true on success or false if an error occurred.
+///
+/// # Safety
+///
+/// Function is unsafe due to raw pointer usage - a null pointer could be passed in. Usage of
+/// !raw_pointer.is_null() checks should prevent unsafe behavior but the caller should ensure no
+/// null pointers are passed.
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub unsafe extern "C" fn crosvm_client_gpu_start_recording(
+    socket_path: *const c_char,
+    display_id: u32,
+    video_path: *const c_char,
+) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            if video_path.is_null() {
+                return false;
+            }
+            // SAFETY: just checked that `video_path` is not null.
+            let video_path =
+                Path::new(unsafe { CStr::from_ptr(video_path) }.to_str().unwrap_or(""));
+            matches!(
+                do_gpu_start_recording(socket_path, display_id, video_path),
+                Ok(GpuControlResult::DisplayRecordingStarted)
+            )
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Stops recording a display of the crosvm instance whose control socket is listening on
+/// `socket_path`, completing the video file.
+///
+/// The function returns true on success or false if an error occurred.
+///
+/// # Safety
+///
+/// Function is unsafe due to raw pointer usage - a null pointer could be passed in. Usage of
+/// !raw_pointer.is_null() checks should prevent unsafe behavior but the caller should ensure no
+/// null pointers are passed.
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub unsafe extern "C" fn crosvm_client_gpu_stop_recording(
+    socket_path: *const c_char,
+    display_id: u32,
+) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            matches!(
+                do_gpu_stop_recording(socket_path, display_id),
+                Ok(GpuControlResult::DisplayRecordingStopped)
+            )
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
+
 /// Similar to internally used `BalloonStats` but using `i64` instead of
 /// `Option<u64>`. `None` (or values bigger than `i64::max`) will be encoded as -1.
 #[repr(C)]
@@ -5,17 +5,20 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::mem::size_of;
 #[cfg(windows)]
 use std::num::NonZeroU32;
+use std::path::Path;
 use std::rc::Rc;
 use std::result;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use std::u32;
 
 use anyhow::Context;
@@ -51,15 +54,23 @@ use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
 use futures::FutureExt;
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::DiskControlCommand;
 use vm_control::DiskControlResult;
+use vm_control::DiskSnapshotCommand;
+use vm_control::DiskSnapshotInfo;
+use vm_control::DiskThrottleConfig;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 
 use crate::virtio::async_utils;
+use crate::virtio::block::stats::BlockStats;
+use crate::virtio::block::stats::RequestType;
 use crate::virtio::block::sys::*;
+use crate::virtio::block::throttle::IoDirection;
+use crate::virtio::block::throttle::Throttle;
 use crate::virtio::block::DiskOption;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::block::virtio_blk_config;
@@ -122,6 +133,8 @@ enum ExecuteError {
     Flush(disk::Error),
     #[error("not enough space in descriptor chain to write status")]
     MissingStatus,
+    #[error("no medium present")]
+    NoMedium,
     #[error("out of range")]
     OutOfRange,
     #[error("failed to read message: {0}")]
@@ -138,6 +151,8 @@ enum ExecuteError {
     ReceivingCommand(TubeError),
     #[error("failed to send command response: {0}")]
     SendingResponse(TubeError),
+    #[error("failed to wait for the I/O limits: {0}")]
+    ThrottleTimer(cros_async::Error),
     #[error("couldn't reset the timer: {0}")]
     TimerReset(base::Error),
     #[error("unsupported ({0})")]
@@ -164,12 +179,14 @@ impl ExecuteError {
             ExecuteError::DiscardWriteZeroes { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::MissingStatus => VIRTIO_BLK_S_IOERR,
+            ExecuteError::NoMedium => VIRTIO_BLK_S_IOERR,
             ExecuteError::OutOfRange { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReadIo { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReadOnly { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReceivingCommand(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::SendingResponse(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::ThrottleTimer(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::TimerReset(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteIo { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteStatus(_) => VIRTIO_BLK_S_IOERR,
@@ -186,7 +203,8 @@ impl ExecuteError {
             ExecuteError::ReadIo { .. }
             | ExecuteError::WriteIo { .. }
             | ExecuteError::Flush { .. }
-            | ExecuteError::DiscardWriteZeroes { .. } => LogLevel::Debug,
+            | ExecuteError::DiscardWriteZeroes { .. }
+            | ExecuteError::NoMedium => LogLevel::Debug,
             // Log all other failures as errors.
             _ => LogLevel::Error,
         }
@@ -214,10 +232,13 @@ type BlockId = [u8; ID_LEN];
 
 /// Tracks the state of an anynchronous disk.
 struct DiskState {
-    disk_image: Box<dyn AsyncDisk>,
+    /// `None` if the medium has been ejected.
+    disk_image: Option<Box<dyn AsyncDisk>>,
     read_only: bool,
     sparse: bool,
     id: Option<BlockId>,
+    /// Whether other workers hold their own clones of `disk_image`.
+    worker_per_queue: bool,
     /// A DiskState is owned by each worker's executor and cannot be shared by workers, thus
     /// `worker_shared_state` holds the state shared by workers in Arc.
     worker_shared_state: Arc<AsyncRwLock<WorkerSharedState>>,
@@ -226,9 +247,56 @@ struct DiskState {
 /// Disk state which can be modified by other worker threads
 struct WorkerSharedState {
     disk_size: Arc<AtomicU64>,
+    /// I/O limits shared by all the queues of the device.
+    throttle: Mutex<Throttle>,
+    /// Statistics of the requests processed by all the queues of the device.
+    stats: Mutex<BlockStats>,
+}
+
+/// Delays a request until it fits within the I/O limits of the disk.
+async fn throttle_request(
+    ex: &Executor,
+    disk_state: &AsyncRwLock<DiskState>,
+    req_type: u32,
+    data_len: usize,
+) -> result::Result<(), ExecuteError> {
+    let (direction, bytes) = match req_type {
+        VIRTIO_BLK_T_IN => (IoDirection::Read, data_len),
+        VIRTIO_BLK_T_OUT => (IoDirection::Write, data_len),
+        VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => (IoDirection::Write, 0),
+        _ => return Ok(()),
+    };
+
+    let delay = {
+        let disk_state = disk_state.read_lock().await;
+        let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
+        let mut throttle = worker_shared_state.throttle.lock();
+        throttle.consume(direction, bytes as u64, Instant::now())
+    };
+    // Wait without holding the locks so that control commands aren't held up by throttled
+    // requests.
+    if !delay.is_zero() {
+        let _trace = cros_tracing::trace_event!(VirtioBlk, "throttle");
+        TimerAsync::sleep(ex, delay)
+            .await
+            .map_err(ExecuteError::ThrottleTimer)?;
+    }
+    Ok(())
+}
+
+/// Runs `f` on the request statistics of the disk.
+async fn with_stats<R>(
+    disk_state: &AsyncRwLock<DiskState>,
+    f: impl FnOnce(&mut BlockStats) -> R,
+) -> R {
+    let disk_state = disk_state.read_lock().await;
+    let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
+    let mut stats = worker_shared_state.stats.lock();
+    f(&mut stats)
 }
 
 async fn process_one_request(
+    ex: &Executor,
     avail_desc: &mut DescriptorChain,
     disk_state: &AsyncRwLock<DiskState>,
     flush_timer: &RefCell<TimerAsync<Timer>>,
@@ -246,16 +314,46 @@ async fn process_one_request(
         .ok_or(ExecuteError::MissingStatus)?;
     let mut status_writer = writer.split_at(status_offset);
 
-    let status = match BlockAsync::execute_request(
-        reader,
-        writer,
-        disk_state,
-        flush_timer,
-        flush_timer_armed,
-    )
-    .await
-    {
-        Ok(()) => VIRTIO_BLK_S_OK,
+    with_stats(disk_state, BlockStats::start_request).await;
+    let start = Instant::now();
+    let mut request_type = None;
+    let result = async {
+        let req_header: virtio_blk_req_header = reader.read_obj().map_err(ExecuteError::Read)?;
+        let req_type = req_header.req_type.to_native();
+        request_type = match req_type {
+            VIRTIO_BLK_T_IN => Some(RequestType::Read),
+            VIRTIO_BLK_T_OUT => Some(RequestType::Write),
+            VIRTIO_BLK_T_FLUSH => Some(RequestType::Flush),
+            VIRTIO_BLK_T_DISCARD => Some(RequestType::Discard),
+            VIRTIO_BLK_T_WRITE_ZEROES => Some(RequestType::WriteZeroes),
+            _ => None,
+        };
+        let data_len = if req_type == VIRTIO_BLK_T_IN {
+            writer.available_bytes()
+        } else {
+            reader.available_bytes()
+        };
+        throttle_request(ex, disk_state, req_type, data_len).await?;
+        BlockAsync::execute_request(
+            req_header,
+            reader,
+            writer,
+            disk_state,
+            flush_timer,
+            flush_timer_armed,
+        )
+        .await
+    }
+    .await;
+    let latency = start.elapsed();
+    let size = result.as_ref().ok().copied();
+    with_stats(disk_state, |stats| {
+        stats.end_request(request_type, latency, size)
+    })
+    .await;
+
+    let status = match result {
+        Ok(_) => VIRTIO_BLK_S_OK,
         Err(e) => {
             match e.log_level() {
                 LogLevel::Debug => debug!("failed executing disk request: {:#}", e),
@@ -273,6 +371,7 @@ async fn process_one_request(
 
 /// Process one descriptor chain asynchronously.
 async fn process_one_chain(
+    ex: &Executor,
     queue: &RefCell<Queue>,
     mut avail_desc: DescriptorChain,
     disk_state: &AsyncRwLock<DiskState>,
@@ -281,8 +380,14 @@ async fn process_one_chain(
     flush_timer_armed: &RefCell<bool>,
 ) {
     let _trace = cros_tracing::trace_event!(VirtioBlk, "process_one_chain");
-    let len = match process_one_request(&mut avail_desc, disk_state, flush_timer, flush_timer_armed)
-        .await
+    let len = match process_one_request(
+        ex,
+        &mut avail_desc,
+        disk_state,
+        flush_timer,
+        flush_timer_armed,
+    )
+    .await
     {
         Ok(len) => len,
         Err(e) => {
@@ -300,6 +405,7 @@ async fn process_one_chain(
 // Receives messages from the guest and queues a task to complete the operations with the async
 // executor.
 async fn handle_queue(
+    ex: Executor,
     disk_state: Rc<AsyncRwLock<DiskState>>,
     queue: Queue,
     evt: EventAsync,
@@ -337,6 +443,7 @@ async fn handle_queue(
         };
         while let Some(descriptor_chain) = queue.borrow_mut().pop() {
             background_tasks.push(process_one_chain(
+                &ex,
                 &queue,
                 descriptor_chain,
                 &disk_state,
@@ -349,6 +456,7 @@ async fn handle_queue(
 }
 
 async fn handle_command_tube(
+    ex: &Executor,
     command_tube: &Option<AsyncTube>,
     interrupt: Interrupt,
     disk_state: Rc<AsyncRwLock<DiskState>>,
@@ -363,8 +471,34 @@ async fn handle_command_tube(
     loop {
         match command_tube.next().await {
             Ok(command) => {
+                // Commands that change the capacity must notify the guest to re-read it.
+                let changes_config = matches!(
+                    command,
+                    DiskControlCommand::Resize { .. }
+                        | DiskControlCommand::ChangeMedia { .. }
+                        | DiskControlCommand::Eject
+                );
                 let resp = match command {
                     DiskControlCommand::Resize { new_size } => resize(&disk_state, new_size).await,
+                    DiskControlCommand::Snapshot(snapshot_command) => {
+                        snapshot(&disk_state, &snapshot_command).await
+                    }
+                    DiskControlCommand::ChangeMedia {
+                        path,
+                        file,
+                        read_only,
+                    } => {
+                        change_media(ex, &disk_state, Some((path.as_path(), file, read_only))).await
+                    }
+                    DiskControlCommand::Eject => change_media(ex, &disk_state, None).await,
+                    DiskControlCommand::SetThrottle(config) => {
+                        set_throttle(&disk_state, config).await
+                    }
+                    DiskControlCommand::Commit { backing_file } => {
+                        modify_backing_chain(&disk_state, Some(backing_file)).await
+                    }
+                    DiskControlCommand::Stream => modify_backing_chain(&disk_state, None).await,
+                    DiskControlCommand::GetStats { reset } => get_stats(&disk_state, reset).await,
                 };
 
                 let resp_clone = resp.clone();
@@ -372,7 +506,7 @@ async fn handle_command_tube(
                     .send(resp_clone)
                     .await
                     .map_err(ExecuteError::SendingResponse)?;
-                if let DiskControlResult::Ok = resp {
+                if changes_config && resp == DiskControlResult::Ok {
                     interrupt.signal_config_changed();
                 }
             }
@@ -394,22 +528,31 @@ async fn resize(disk_state: &AsyncRwLock<DiskState>, new_size: u64) -> DiskContr
         return DiskControlResult::Err(SysError::new(libc::EROFS));
     }
 
+    let sparse = disk_state.sparse;
+    let disk_image = match &mut disk_state.disk_image {
+        Some(disk_image) => disk_image,
+        None => {
+            error!("Attempted to resize block device with no medium");
+            return DiskControlResult::Err(SysError::new(libc::ENODEV));
+        }
+    };
+
     info!("Resizing block device to {} bytes", new_size);
 
-    if let Err(e) = disk_state.disk_image.set_len(new_size) {
+    if let Err(e) = disk_image.set_len(new_size) {
         error!("Resizing disk failed! {:#}", e);
         return DiskControlResult::Err(SysError::new(libc::EIO));
     }
 
     // Allocate new space if the disk image is not sparse.
-    if !disk_state.sparse {
-        if let Err(e) = disk_state.disk_image.allocate(0, new_size) {
+    if !sparse {
+        if let Err(e) = disk_image.allocate(0, new_size) {
             error!("Allocating disk space after resize failed! {:#}", e);
             return DiskControlResult::Err(SysError::new(libc::EIO));
         }
     }
 
-    if let Ok(new_disk_size) = disk_state.disk_image.get_len() {
+    if let Ok(new_disk_size) = disk_image.get_len() {
         worker_shared_state
             .disk_size
             .store(new_disk_size, Ordering::Release);
@@ -417,6 +560,213 @@ async fn resize(disk_state: &AsyncRwLock<DiskState>, new_size: u64) -> DiskContr
     DiskControlResult::Ok
 }
 
+async fn snapshot(
+    disk_state: &AsyncRwLock<DiskState>,
+    command: &DiskSnapshotCommand,
+) -> DiskControlResult {
+    // Hold exclusive access to the disk so that no guest IO is in flight while the image's tables
+    // change.
+    let disk_state = disk_state.lock().await;
+    let worker_shared_state = Arc::clone(&disk_state.worker_shared_state);
+    let _worker_shared_state = worker_shared_state.lock().await;
+
+    if disk_state.read_only && !matches!(command, DiskSnapshotCommand::List) {
+        error!("Attempted to modify snapshots of read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    let disk_image = match &disk_state.disk_image {
+        Some(disk_image) => disk_image,
+        None => {
+            error!("Attempted to manage snapshots of block device with no medium");
+            return DiskControlResult::Err(SysError::new(libc::ENODEV));
+        }
+    };
+
+    info!("Disk snapshot command: {}", command);
+
+    let result = match command {
+        DiskSnapshotCommand::Create { name } => disk_image
+            .create_snapshot(name)
+            .await
+            .map(|()| DiskControlResult::Ok),
+        DiskSnapshotCommand::List => disk_image.list_snapshots().await.map(|snapshots| {
+            DiskControlResult::Snapshots(
+                snapshots
+                    .into_iter()
+                    .map(|s| DiskSnapshotInfo {
+                        id: s.id,
+                        name: s.name,
+                        date_sec: s.date_sec,
+                        date_nsec: s.date_nsec,
+                        vm_state_size: s.vm_state_size,
+                        disk_size: s.disk_size,
+                    })
+                    .collect(),
+            )
+        }),
+        DiskSnapshotCommand::Apply { name } => disk_image
+            .apply_snapshot(name)
+            .await
+            .map(|()| DiskControlResult::Ok),
+        DiskSnapshotCommand::Delete { name } => disk_image
+            .delete_snapshot(name)
+            .await
+            .map(|()| DiskControlResult::Ok),
+    };
+    result.unwrap_or_else(|e| {
+        error!("Disk snapshot command failed: {:#}", e);
+        let errno = match e {
+            disk::Error::UnsupportedOperation => libc::ENOTSUP,
+            _ => libc::EIO,
+        };
+        DiskControlResult::Err(SysError::new(errno))
+    })
+}
+
+/// Merges the disk image into its backing file if `backing_file` is given, or copies the data of
+/// the backing file into the disk image otherwise.
+async fn modify_backing_chain(
+    disk_state: &AsyncRwLock<DiskState>,
+    backing_file: Option<File>,
+) -> DiskControlResult {
+    // Hold exclusive access to the disk so that no guest IO is in flight while data moves between
+    // the images.
+    let disk_state = disk_state.lock().await;
+    let worker_shared_state = Arc::clone(&disk_state.worker_shared_state);
+    let _worker_shared_state = worker_shared_state.lock().await;
+
+    if disk_state.read_only {
+        error!("Attempted to modify the backing chain of read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    let disk_image = match &disk_state.disk_image {
+        Some(disk_image) => disk_image,
+        None => {
+            error!("Attempted to modify the backing chain of block device with no medium");
+            return DiskControlResult::Err(SysError::new(libc::ENODEV));
+        }
+    };
+
+    let result = match backing_file {
+        Some(backing_file) => {
+            info!("Committing block device image to its backing file");
+            disk_image.commit(backing_file).await
+        }
+        None => {
+            info!("Streaming backing file into block device image");
+            disk_image.stream().await
+        }
+    };
+    match result {
+        Ok(()) => DiskControlResult::Ok,
+        Err(e) => {
+            error!("Modifying the backing chain failed: {:#}", e);
+            let errno = match e {
+                disk::Error::UnsupportedOperation => libc::ENOTSUP,
+                _ => libc::EIO,
+            };
+            DiskControlResult::Err(SysError::new(errno))
+        }
+    }
+}
+
+async fn get_stats(disk_state: &AsyncRwLock<DiskState>, reset: bool) -> DiskControlResult {
+    let disk_stats = with_stats(disk_state, |stats| {
+        let disk_stats = stats.to_disk_stats();
+        if reset {
+            stats.reset();
+        }
+        disk_stats
+    })
+    .await;
+    DiskControlResult::Stats(disk_stats)
+}
+
+async fn set_throttle(
+    disk_state: &AsyncRwLock<DiskState>,
+    config: DiskThrottleConfig,
+) -> DiskControlResult {
+    let disk_state = disk_state.read_lock().await;
+    let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
+
+    info!("Setting block device I/O limits to {:?}", config);
+
+    *worker_shared_state.throttle.lock() = Throttle::new(config);
+    DiskControlResult::Ok
+}
+
+/// Replaces the disk image with the one in `medium`, or ejects it if `medium` is `None`.
+async fn change_media(
+    ex: &Executor,
+    disk_state: &AsyncRwLock<DiskState>,
+    medium: Option<(&Path, File, bool)>,
+) -> DiskControlResult {
+    // Acquire exclusive access so that no request is in flight on the old image while it is
+    // replaced.
+    let mut disk_state = disk_state.lock().await;
+    let worker_shared_state = Arc::clone(&disk_state.worker_shared_state);
+    let worker_shared_state = worker_shared_state.lock().await;
+
+    if disk_state.worker_per_queue {
+        // The other workers would keep serving requests from their clones of the old image.
+        error!("Changing media is not supported for block devices with multiple workers");
+        return DiskControlResult::Err(SysError::new(libc::ENOTSUP));
+    }
+
+    let new_image = match medium {
+        Some((path, file, read_only)) => {
+            // VIRTIO_BLK_F_RO was negotiated when the device was activated, so a writable device
+            // can't switch to read-only media.
+            if read_only && !disk_state.read_only {
+                error!("Attempted to insert read-only medium into writable block device");
+                return DiskControlResult::Err(SysError::new(libc::EROFS));
+            }
+
+            info!("Changing block device medium to {}", path.display());
+
+            let new_image =
+                disk::create_disk_file(file, disk_state.sparse, disk::MAX_NESTING_DEPTH, path)
+                    .and_then(|disk_image| disk_image.to_async_disk(ex));
+            match new_image {
+                Ok(disk_image) => Some(disk_image),
+                Err(e) => {
+                    error!("Opening new medium failed! {:#}", e);
+                    return DiskControlResult::Err(SysError::new(libc::EIO));
+                }
+            }
+        }
+        None => {
+            info!("Ejecting block device medium");
+            None
+        }
+    };
+
+    let new_disk_size = match new_image.as_ref().map(|disk_image| disk_image.get_len()) {
+        Some(Ok(disk_size)) => disk_size,
+        Some(Err(e)) => {
+            error!("Getting size of new medium failed! {:#}", e);
+            return DiskControlResult::Err(SysError::new(libc::EIO));
+        }
+        None => 0,
+    };
+
+    // Write back any in-memory state of the old image before it is closed.
+    if let Some(old_image) = &disk_state.disk_image {
+        if let Err(e) = old_image.flush().await {
+            error!("Flushing old medium failed! {:#}", e);
+            return DiskControlResult::Err(SysError::new(libc::EIO));
+        }
+    }
+
+    disk_state.disk_image = new_image;
+    worker_shared_state
+        .disk_size
+        .store(new_disk_size, Ordering::Release);
+    DiskControlResult::Ok
+}
+
 /// Periodically flushes the disk when the given timer fires.
 async fn flush_disk(
     disk_state: Rc<AsyncRwLock<DiskState>>,
@@ -433,13 +783,9 @@ async fn flush_disk(
         // fsync will be committed eventually.
         *armed.borrow_mut() = false;
 
-        disk_state
-            .read_lock()
-            .await
-            .disk_image
-            .fsync()
-            .await
-            .map_err(ControlError::FsyncDisk)?;
+        if let Some(disk_image) = &disk_state.read_lock().await.disk_image {
+            disk_image.fsync().await.map_err(ControlError::FsyncDisk)?;
+        }
     }
 }
 
@@ -476,7 +822,8 @@ async fn run_worker(
     let flush_timer_armed = Rc::new(RefCell::new(false));
 
     // Handles control requests.
-    let control = handle_command_tube(control_tube, interrupt.clone(), disk_state.clone()).fuse();
+    let control =
+        handle_command_tube(ex, control_tube, interrupt.clone(), disk_state.clone()).fuse();
     pin_mut!(control);
 
     // Handle all the queues in one sub-select call.
@@ -521,6 +868,7 @@ async fn run_worker(
                         let (tx, rx) = oneshot::channel();
                         let kick_evt = queue.event().try_clone().expect("Failed to clone queue event");
                         let (handle_queue_future, remote_handle) = handle_queue(
+                            ex.clone(),
                             Rc::clone(disk_state),
                             queue,
                             EventAsync::new(kick_evt, ex).expect("Failed to create async event for queue"),
@@ -586,7 +934,8 @@ pub struct BlockAsync {
     // We need to make boot_index public bc the field is used by the main crate to determine boot
     // order
     boot_index: Option<usize>,
-    // `None` iff `self.worker_per_queue == false` and the worker thread is running.
+    // `None` if `self.worker_per_queue == false` and the worker thread is running, or if the medium
+    // has been ejected.
     disk_image: Option<Box<dyn DiskFile>>,
     disk_size: Arc<AtomicU64>,
     avail_features: u64,
@@ -603,7 +952,7 @@ pub struct BlockAsync {
     worker_threads: BTreeMap<
         usize,
         (
-            WorkerThread<(Box<dyn DiskFile>, Option<Tube>)>,
+            WorkerThread<(Option<Box<dyn DiskFile>>, Option<Tube>)>,
             mpsc::UnboundedSender<WorkerCmd>,
         ),
     >,
@@ -682,6 +1031,8 @@ impl BlockAsync {
         let disk_size = Arc::new(AtomicU64::new(disk_size));
         let shared_state = Arc::new(AsyncRwLock::new(WorkerSharedState {
             disk_size: disk_size.clone(),
+            throttle: Mutex::new(Throttle::new(disk_option.throttle)),
+            stats: Mutex::new(BlockStats::default()),
         }));
 
         Ok(BlockAsync {
@@ -736,24 +1087,23 @@ impl BlockAsync {
         avail_features
     }
 
-    // Execute a single block device request.
+    // Execute a single block device request and return the number of bytes of the disk it covers.
     // `writer` includes the data region only; the status byte is not included.
     // It is up to the caller to convert the result of this function into a status byte
     // and write it to the expected location in guest memory.
     async fn execute_request(
+        req_header: virtio_blk_req_header,
         reader: &mut Reader,
         writer: &mut Writer,
         disk_state: &AsyncRwLock<DiskState>,
         flush_timer: &RefCell<TimerAsync<Timer>>,
         flush_timer_armed: &RefCell<bool>,
-    ) -> result::Result<(), ExecuteError> {
+    ) -> result::Result<u64, ExecuteError> {
         // Acquire immutable access to prevent tasks from resizing disk.
         let disk_state = disk_state.read_lock().await;
         // Acquire immutable access to prevent other worker threads from resizing disk.
         let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
 
-        let req_header: virtio_blk_req_header = reader.read_obj().map_err(ExecuteError::Read)?;
-
         let req_type = req_header.req_type.to_native();
         let sector = req_header.sector.to_native();
 
@@ -781,18 +1131,21 @@ impl BlockAsync {
         }
 
         let disk_size = worker_shared_state.disk_size.load(Ordering::Relaxed);
-        match req_type {
+        let size = match req_type {
             VIRTIO_BLK_T_IN => {
                 let data_len = writer.available_bytes();
                 if data_len == 0 {
-                    return Ok(());
+                    return Ok(0);
                 }
                 let offset = sector
                     .checked_shl(u32::from(SECTOR_SHIFT))
                     .ok_or(ExecuteError::OutOfRange)?;
                 let _trace = cros_tracing::trace_event!(VirtioBlk, "in", offset, data_len);
                 check_range(offset, data_len as u64, disk_size)?;
-                let disk_image = &disk_state.disk_image;
+                let disk_image = disk_state
+                    .disk_image
+                    .as_ref()
+                    .ok_or(ExecuteError::NoMedium)?;
                 writer
                     .write_all_from_at_fut(&**disk_image, data_len, offset)
                     .await
@@ -801,18 +1154,22 @@ impl BlockAsync {
                         sector,
                         desc_error,
                     })?;
+                data_len as u64
             }
             VIRTIO_BLK_T_OUT => {
                 let data_len = reader.available_bytes();
                 if data_len == 0 {
-                    return Ok(());
+                    return Ok(0);
                 }
                 let offset = sector
                     .checked_shl(u32::from(SECTOR_SHIFT))
                     .ok_or(ExecuteError::OutOfRange)?;
                 let _trace = cros_tracing::trace_event!(VirtioBlk, "out", offset, data_len);
                 check_range(offset, data_len as u64, disk_size)?;
-                let disk_image = &disk_state.disk_image;
+                let disk_image = disk_state
+                    .disk_image
+                    .as_ref()
+                    .ok_or(ExecuteError::NoMedium)?;
                 reader
                     .read_exact_to_at_fut(&**disk_image, data_len, offset)
                     .await
@@ -831,6 +1188,7 @@ impl BlockAsync {
                         .reset(flush_delay, None)
                         .map_err(ExecuteError::TimerReset)?;
                 }
+                data_len as u64
             }
             VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
                 #[allow(clippy::if_same_then_else)]
@@ -841,9 +1199,10 @@ impl BlockAsync {
                 };
                 if req_type == VIRTIO_BLK_T_DISCARD && !disk_state.sparse {
                     // Discard is a hint; if this is a non-sparse disk, just ignore it.
-                    return Ok(());
+                    return Ok(0);
                 }
 
+                let mut total_length = 0u64;
                 while reader.available_bytes() >= size_of::<virtio_blk_discard_write_zeroes>() {
                     let seg: virtio_blk_discard_write_zeroes =
                         reader.read_obj().map_err(ExecuteError::Read)?;
@@ -874,14 +1233,17 @@ impl BlockAsync {
                         .checked_shl(u32::from(SECTOR_SHIFT))
                         .ok_or(ExecuteError::OutOfRange)?;
                     check_range(offset, length, disk_size)?;
+                    let disk_image = disk_state
+                        .disk_image
+                        .as_ref()
+                        .ok_or(ExecuteError::NoMedium)?;
 
                     if req_type == VIRTIO_BLK_T_DISCARD {
                         // Since Discard is just a hint and some filesystems may not implement
                         // FALLOC_FL_PUNCH_HOLE, ignore punch_hole errors.
-                        let _ = disk_state.disk_image.punch_hole(offset, length).await;
+                        let _ = disk_image.punch_hole(offset, length).await;
                     } else {
-                        disk_state
-                            .disk_image
+                        disk_image
                             .write_zeroes_at(offset, length)
                             .await
                             .map_err(|e| ExecuteError::DiscardWriteZeroes {
@@ -891,15 +1253,15 @@ impl BlockAsync {
                                 flags,
                             })?;
                     }
+                    total_length = total_length.saturating_add(length);
                 }
+                total_length
             }
             VIRTIO_BLK_T_FLUSH => {
                 let _trace = cros_tracing::trace_event!(VirtioBlk, "flush");
-                disk_state
-                    .disk_image
-                    .fdatasync()
-                    .await
-                    .map_err(ExecuteError::Flush)?;
+                if let Some(disk_image) = &disk_state.disk_image {
+                    disk_image.fdatasync().await.map_err(ExecuteError::Flush)?;
+                }
 
                 if *flush_timer_armed.borrow() {
                     flush_timer
@@ -908,6 +1270,7 @@ impl BlockAsync {
                         .map_err(ExecuteError::TimerReset)?;
                     *flush_timer_armed.borrow_mut() = false;
                 }
+                0
             }
             VIRTIO_BLK_T_GET_ID => {
                 let _trace = cros_tracing::trace_event!(VirtioBlk, "get_id");
@@ -916,10 +1279,11 @@ impl BlockAsync {
                 } else {
                     return Err(ExecuteError::Unsupported(req_type));
                 }
+                0
             }
             t => return Err(ExecuteError::Unsupported(t)),
         };
-        Ok(())
+        Ok(size)
     }
 
     /// Builds and returns the config structure used to specify block features.
@@ -953,7 +1317,7 @@ impl BlockAsync {
         idx: usize,
         interrupt: Interrupt,
     ) -> anyhow::Result<&(
-        WorkerThread<(Box<dyn DiskFile>, Option<Tube>)>,
+        WorkerThread<(Option<Box<dyn DiskFile>>, Option<Tube>)>,
         mpsc::UnboundedSender<WorkerCmd>,
     )> {
         let key = if self.worker_per_queue { idx } else { 0 };
@@ -964,19 +1328,21 @@ impl BlockAsync {
         let ex = self.create_executor();
         let control_tube = self.control_tube.take();
         let disk_image = if self.worker_per_queue {
-            self.disk_image
-                .as_ref()
-                .context("Failed to ref a disk image")?
-                .try_clone()
-                .context("Failed to clone a disk image")?
+            Some(
+                self.disk_image
+                    .as_ref()
+                    .context("Failed to ref a disk image")?
+                    .try_clone()
+                    .context("Failed to clone a disk image")?,
+            )
         } else {
-            self.disk_image
-                .take()
-                .context("Failed to take a disk image")?
+            // `None` if the medium was ejected by a previous worker.
+            self.disk_image.take()
         };
         let read_only = self.read_only;
         let sparse = self.sparse;
         let id = self.id;
+        let worker_per_queue = self.worker_per_queue;
         let worker_shared_state = self.shared_state.clone();
 
         let (worker_tx, worker_rx) = mpsc::unbounded();
@@ -984,16 +1350,17 @@ impl BlockAsync {
             let async_control =
                 control_tube.map(|c| AsyncTube::new(&ex, c).expect("failed to create async tube"));
 
-            let async_image = match disk_image.to_async_disk(&ex) {
+            let async_image = disk_image.map(|disk_image| match disk_image.to_async_disk(&ex) {
                 Ok(d) => d,
                 Err(e) => panic!("Failed to create async disk {:#}", e),
-            };
+            });
 
             let disk_state = Rc::new(AsyncRwLock::new(DiskState {
                 disk_image: async_image,
                 read_only,
                 sparse,
                 id,
+                worker_per_queue,
                 worker_shared_state,
             }));
 
@@ -1009,8 +1376,10 @@ impl BlockAsync {
                     )
                     .await;
                     // Flush any in-memory disk image state to file.
-                    if let Err(e) = disk_state.lock().await.disk_image.flush().await {
-                        error!("failed to flush disk image when stopping worker: {e:?}");
+                    if let Some(disk_image) = &disk_state.lock().await.disk_image {
+                        if let Err(e) = disk_image.flush().await {
+                            error!("failed to flush disk image when stopping worker: {e:?}");
+                        }
                     }
                     r
                 })
@@ -1024,7 +1393,9 @@ impl BlockAsync {
                 Err(_) => panic!("too many refs to the disk"),
             };
             (
-                disk_state.disk_image.into_inner(),
+                disk_state
+                    .disk_image
+                    .map(|disk_image| disk_image.into_inner()),
                 async_control.map(Tube::from),
             )
         });
@@ -1136,7 +1507,7 @@ impl VirtioDevice for BlockAsync {
         let mut success = false;
         while let Some((_, (worker_thread, _))) = self.worker_threads.pop_first() {
             let (disk_image, control_tube) = worker_thread.stop();
-            self.disk_image = Some(disk_image);
+            self.disk_image = disk_image;
             if let Some(control_tube) = control_tube {
                 self.control_tube = Some(control_tube);
             }
@@ -1159,7 +1530,7 @@ impl VirtioDevice for BlockAsync {
         // Shutdown the workers.
         while let Some((_, (worker_thread, _))) = self.worker_threads.pop_first() {
             let (disk_image, control_tube) = worker_thread.stop();
-            self.disk_image = Some(disk_image);
+            self.disk_image = disk_image;
             if let Some(control_tube) = control_tube {
                 self.control_tube = Some(control_tube);
             }
@@ -1216,6 +1587,7 @@ mod tests {
     use hypervisor::ProtectionType;
     use tempfile::tempfile;
     use tempfile::TempDir;
+    use vm_control::DiskStats;
     use vm_memory::GuestAddress;
 
     use super::*;
@@ -1406,16 +1778,20 @@ mod tests {
         let flush_timer_armed = Rc::new(RefCell::new(false));
 
         let disk_state = Rc::new(AsyncRwLock::new(DiskState {
-            disk_image: Box::new(af),
+            disk_image: Some(Box::new(af)),
             read_only: false,
             sparse: true,
             id: None,
+            worker_per_queue: false,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                throttle: Mutex::new(Throttle::new(DiskThrottleConfig::default())),
+                stats: Mutex::new(BlockStats::default()),
             })),
         }));
 
         let fut = process_one_request(
+            &ex,
             &mut avail_desc,
             &disk_state,
             &flush_timer,
@@ -1429,6 +1805,22 @@ mod tests {
         let status_offset = GuestAddress((0x1000 + size_of_val(&req_hdr) + 512) as u64);
         let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
         assert_eq!(status, VIRTIO_BLK_S_OK);
+
+        let stats = match ex.run_until(get_stats(&disk_state, true)).unwrap() {
+            DiskControlResult::Stats(stats) => stats,
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.read.latency_us.count, 1);
+        assert_eq!(stats.read.errors, 0);
+        assert_eq!(stats.read.size_bytes.sum, 512);
+        assert_eq!(stats.queue_depth.count, 1);
+
+        let stats = match ex.run_until(get_stats(&disk_state, false)).unwrap() {
+            DiskControlResult::Stats(stats) => stats,
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(stats, DiskStats::default(), "stats should have been reset");
     }
 
     #[test]
@@ -1474,16 +1866,20 @@ mod tests {
         ));
         let flush_timer_armed = Rc::new(RefCell::new(false));
         let disk_state = Rc::new(AsyncRwLock::new(DiskState {
-            disk_image: Box::new(af),
+            disk_image: Some(Box::new(af)),
             read_only: false,
             sparse: true,
             id: None,
+            worker_per_queue: false,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                throttle: Mutex::new(Throttle::new(DiskThrottleConfig::default())),
+                stats: Mutex::new(BlockStats::default()),
             })),
         }));
 
         let fut = process_one_request(
+            &ex,
             &mut avail_desc,
             &disk_state,
             &flush_timer,
@@ -1497,6 +1893,14 @@ mod tests {
         let status_offset = GuestAddress((0x1000 + size_of_val(&req_hdr) + 512 * 2) as u64);
         let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
         assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        let stats = match ex.run_until(get_stats(&disk_state, false)).unwrap() {
+            DiskControlResult::Stats(stats) => stats,
+            r => panic!("unexpected result {:?}", r),
+        };
+        assert_eq!(stats.read.latency_us.count, 1);
+        assert_eq!(stats.read.errors, 1);
+        assert_eq!(stats.read.size_bytes.count, 0);
     }
 
     #[test]
@@ -1544,16 +1948,20 @@ mod tests {
         let id = b"a20-byteserialnumber";
 
         let disk_state = Rc::new(AsyncRwLock::new(DiskState {
-            disk_image: Box::new(af),
+            disk_image: Some(Box::new(af)),
             read_only: false,
             sparse: true,
             id: Some(*id),
+            worker_per_queue: false,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                throttle: Mutex::new(Throttle::new(DiskThrottleConfig::default())),
+                stats: Mutex::new(BlockStats::default()),
             })),
         }));
 
         let fut = process_one_request(
+            &ex,
             &mut avail_desc,
             &disk_state,
             &flush_timer,
@@ -1814,6 +2222,119 @@ mod tests {
         );
     }
 
+    // TODO(b/270225199): enable this test on Windows once IoSource::into_source is implemented,
+    // or after finding a good way to prevent BlockAsync::drop() from panicking due to that.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn change_media() {
+        let f = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+        let disk_image: Box<dyn DiskFile> = Box::new(f);
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let (control_tube, control_tube_device) = Tube::pair().unwrap();
+
+        let features = base_features(ProtectionType::Unprotected);
+        let disk_option = DiskOption::default();
+        let mut b = BlockAsync::new(
+            features,
+            disk_image,
+            &disk_option,
+            Some(control_tube_device),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut q0 = QueueConfig::new(DEFAULT_QUEUE_SIZE, 0);
+        q0.set_ready(true);
+        let q0 = q0
+            .activate(&mem, Event::new().unwrap())
+            .expect("QueueConfig::activate");
+
+        let interrupt = Interrupt::new_for_test();
+        b.activate(mem.clone(), interrupt.clone(), BTreeMap::from([(0, q0)]))
+            .expect("activate should succeed");
+
+        // A read-only medium can't be inserted into a writable device.
+        let tempdir = TempDir::new().unwrap();
+        let path = tempdir.path().join("new_disk");
+        // The image type is detected by reading the file, so it can't be opened write-only.
+        let new_disk = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        new_disk.set_len(0x2000).unwrap();
+        control_tube
+            .send(&DiskControlCommand::ChangeMedia {
+                path: path.clone(),
+                file: new_disk.try_clone().unwrap(),
+                read_only: true,
+            })
+            .unwrap();
+        assert_eq!(
+            control_tube.recv::<DiskControlResult>().unwrap(),
+            DiskControlResult::Err(SysError::new(libc::EROFS)),
+        );
+
+        control_tube
+            .send(&DiskControlCommand::ChangeMedia {
+                path,
+                file: new_disk,
+                read_only: false,
+            })
+            .unwrap();
+        assert_eq!(
+            control_tube.recv::<DiskControlResult>().unwrap(),
+            DiskControlResult::Ok,
+            "change media command should succeed"
+        );
+        let mut capacity = [0u8; 8];
+        b.read_config(0, &mut capacity);
+        assert_eq!(
+            capacity,
+            // 0x2000 >> SECTOR_SHIFT (9) = 0x10
+            [0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            "read_config should read the capacity of the new medium"
+        );
+        assert_eq!(
+            interrupt
+                .get_interrupt_evt()
+                .wait_timeout(Duration::from_millis(300)),
+            Ok(base::EventWaitResult::Signaled),
+            "interrupt should be signaled"
+        );
+        assert_eq!(
+            interrupt.read_interrupt_status(),
+            crate::virtio::INTERRUPT_STATUS_CONFIG_CHANGED as u8,
+            "INTERRUPT_STATUS_CONFIG_CHANGED should be signaled"
+        );
+
+        control_tube.send(&DiskControlCommand::Eject).unwrap();
+        assert_eq!(
+            control_tube.recv::<DiskControlResult>().unwrap(),
+            DiskControlResult::Ok,
+            "eject command should succeed"
+        );
+        assert_eq!(b.disk_size.load(Ordering::Acquire), 0);
+
+        // The device can be reset and reactivated with no medium.
+        assert!(b.reset(), "reset should succeed");
+        assert!(b.disk_image.is_none(), "medium should stay ejected");
+        let mut q0 = QueueConfig::new(DEFAULT_QUEUE_SIZE, 0);
+        q0.set_ready(true);
+        let q0 = q0
+            .activate(&mem, Event::new().unwrap())
+            .expect("QueueConfig::activate");
+        b.activate(mem, Interrupt::new_for_test(), BTreeMap::from([(0, q0)]))
+            .expect("re-activate should succeed");
+    }
+
     // TODO(b/270225199): enable this test on Windows once IoSource::into_source is implemented,
     // or after finding a good way to prevent BlockAsync::drop() from panicking due to that.
     #[cfg(any(target_os = "android", target_os = "linux"))]
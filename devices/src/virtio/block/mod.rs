@@ -2,20 +2,27 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::OpenOptions;
+use std::io::Read;
 #[cfg(windows)]
 use std::num::NonZeroU32;
 use std::path::PathBuf;
 
+use anyhow::Context;
+use base::open_file_or_duplicate;
 use cros_async::ExecutorKind;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
 use serde::Serializer;
+use vm_control::DiskThrottleConfig;
 
 use crate::PciAddress;
 
 pub mod asynchronous;
+mod stats;
 pub(crate) mod sys;
+mod throttle;
 
 pub use asynchronous::BlockAsync;
 
@@ -130,6 +137,17 @@ pub struct DiskOption {
 
     /// Specify PCI address will be used to attach this device
     pub pci_address: Option<PciAddress>,
+
+    #[serde(default)]
+    /// I/O limits of the device, which can be changed at runtime with
+    /// `DiskControlCommand::SetThrottle`.
+    pub throttle: DiskThrottleConfig,
+
+    #[serde(default)]
+    /// File holding the key of an encrypted disk image: the passphrase of a LUKS encrypted qcow2
+    /// image, or a 32 or 64 byte AES-XTS key for other images. `/proc/self/fd/N` can be used to
+    /// pass the key through an inherited file descriptor.
+    pub key_file: Option<PathBuf>,
 }
 
 impl Default for DiskOption {
@@ -149,12 +167,32 @@ impl Default for DiskOption {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: Default::default(),
+            key_file: None,
         }
     }
 }
 
+impl DiskOption {
+    /// Reads the key of an encrypted disk image from `key_file`, if there is one.
+    fn read_key(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        let key_file = match &self.key_file {
+            Some(key_file) => key_file,
+            None => return Ok(None),
+        };
+        let mut file = open_file_or_duplicate(key_file, OpenOptions::new().read(true))
+            .with_context(|| format!("failed to open key file {}", key_file.display()))?;
+        let mut key = Vec::new();
+        file.read_to_end(&mut key)
+            .with_context(|| format!("failed to read key file {}", key_file.display()))?;
+        Ok(Some(key))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::num::NonZeroU64;
+
     #[cfg(any(target_os = "android", target_os = "linux"))]
     use cros_async::sys::linux::ExecutorKindSys;
     #[cfg(windows)]
@@ -208,6 +246,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -230,6 +270,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: Some(5),
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -252,6 +294,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -274,6 +318,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -296,6 +342,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -318,6 +366,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
         let params = from_block_arg("/some/path.img,sparse=false").unwrap();
@@ -338,6 +388,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -360,6 +412,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -382,6 +436,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -404,6 +460,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -426,6 +484,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -449,6 +509,8 @@ mod tests {
                     packed_queue: false,
                     bootindex: None,
                     pci_address: None,
+                    throttle: Default::default(),
+                    key_file: None,
                 }
             );
         }
@@ -472,6 +534,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
         let err = from_block_arg("/some/path.img,id=DISK_ID_IS_WAY_TOO_LONG").unwrap_err();
@@ -507,6 +571,8 @@ mod tests {
                 packed_queue: false,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -529,6 +595,8 @@ mod tests {
                 packed_queue: true,
                 bootindex: None,
                 pci_address: None,
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -555,6 +623,8 @@ mod tests {
                     dev: 1,
                     func: 1,
                 }),
+                throttle: Default::default(),
+                key_file: None,
             }
         );
 
@@ -585,8 +655,34 @@ mod tests {
                     dev: 1,
                     func: 1,
                 }),
+                throttle: Default::default(),
+                key_file: None,
+            }
+        );
+    }
+
+    #[test]
+    fn params_throttle() {
+        let params =
+            from_block_arg("/some/path.img,throttle=[read-iops=1000,write-bps=1048576]").unwrap();
+        assert_eq!(
+            params.throttle,
+            DiskThrottleConfig {
+                read_iops: NonZeroU64::new(1000),
+                write_bps: NonZeroU64::new(1048576),
+                ..Default::default()
             }
         );
+
+        // Zero limits are rejected, unlimited is expressed by omitting the key.
+        assert!(from_block_arg("/some/path.img,throttle=[read-iops=0]").is_err());
+        assert!(from_block_arg("/some/path.img,throttle=[read-iops-max=10]").is_err());
+    }
+
+    #[test]
+    fn params_key_file() {
+        let params = from_block_arg("/some/path.img,key-file=/proc/self/fd/5").unwrap();
+        assert_eq!(params.key_file, Some(PathBuf::from("/proc/self/fd/5")));
     }
 
     #[test]
@@ -607,6 +703,8 @@ mod tests {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: Default::default(),
+            key_file: None,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -628,6 +726,8 @@ mod tests {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: Default::default(),
+            key_file: None,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -649,6 +749,8 @@ mod tests {
             packed_queue: false,
             bootindex: None,
             pci_address: None,
+            throttle: Default::default(),
+            key_file: None,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -0,0 +1,241 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Latency, size and queue depth statistics of the requests processed by a block device.
+
+use std::ops::Range;
+use std::time::Duration;
+
+use metrics::Histogram;
+use metrics::SummaryStats;
+use vm_control::DiskHistogram;
+use vm_control::DiskHistogramBucket;
+use vm_control::DiskRequestStats;
+use vm_control::DiskStats;
+
+/// Type of a request that statistics are kept for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestType {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+}
+
+// Returns the ranges `0..first`, `first..2 * first`, ... `last..u64::MAX`, where `last` is `first`
+// times a power of two.
+fn power_of_two_buckets(first: u64, last: u64) -> Vec<Range<u64>> {
+    let mut buckets = vec![0..first];
+    let mut start = first;
+    while start < last {
+        buckets.push(start..start * 2);
+        start *= 2;
+    }
+    buckets.push(last..u64::MAX);
+    buckets
+}
+
+fn new_histogram(first: u64, last: u64) -> Histogram<u64> {
+    Histogram::new(&power_of_two_buckets(first, last)).expect("bucket ranges are in order")
+}
+
+fn add(histogram: &mut Histogram<u64>, value: u64) {
+    // The buckets cover every value except u64::MAX.
+    histogram
+        .add(value.min(u64::MAX - 1))
+        .expect("value fits in a bucket");
+}
+
+fn to_disk_histogram(histogram: &Histogram<u64>) -> DiskHistogram {
+    DiskHistogram {
+        count: histogram.count(),
+        sum: histogram.sum().unwrap_or(0),
+        min: histogram.min(),
+        max: histogram.max(),
+        buckets: histogram
+            .buckets()
+            .filter(|(_, stat)| stat.count() > 0)
+            .map(|(range, stat)| DiskHistogramBucket {
+                start: range.start,
+                end: range.end,
+                count: stat.count(),
+            })
+            .collect(),
+    }
+}
+
+struct RequestStats {
+    errors: u64,
+    /// Latency in microseconds, from 16 us to 16 s.
+    latency_us: Histogram<u64>,
+    /// Size in bytes, from 512 bytes to 4 MiB.
+    size_bytes: Histogram<u64>,
+}
+
+impl RequestStats {
+    fn new() -> RequestStats {
+        RequestStats {
+            errors: 0,
+            latency_us: new_histogram(16, 1 << 24),
+            size_bytes: new_histogram(512, 4 << 20),
+        }
+    }
+
+    fn to_disk_request_stats(&self) -> DiskRequestStats {
+        DiskRequestStats {
+            errors: self.errors,
+            latency_us: to_disk_histogram(&self.latency_us),
+            size_bytes: to_disk_histogram(&self.size_bytes),
+        }
+    }
+}
+
+/// Request statistics of a block device, shared by all of its queues.
+pub struct BlockStats {
+    read: RequestStats,
+    write: RequestStats,
+    flush: RequestStats,
+    discard: RequestStats,
+    write_zeroes: RequestStats,
+    in_flight: u64,
+    queue_depth: Histogram<u64>,
+}
+
+impl Default for BlockStats {
+    fn default() -> BlockStats {
+        BlockStats {
+            read: RequestStats::new(),
+            write: RequestStats::new(),
+            flush: RequestStats::new(),
+            discard: RequestStats::new(),
+            write_zeroes: RequestStats::new(),
+            in_flight: 0,
+            queue_depth: new_histogram(1, 256),
+        }
+    }
+}
+
+impl BlockStats {
+    /// Records that the device received a request.
+    pub fn start_request(&mut self) {
+        self.in_flight += 1;
+        add(&mut self.queue_depth, self.in_flight);
+    }
+
+    /// Records that a request started with `start_request` completed after `latency`.
+    /// `request_type` is `None` for requests that statistics aren't kept for, and `size` is `None`
+    /// if the request failed.
+    pub fn end_request(
+        &mut self,
+        request_type: Option<RequestType>,
+        latency: Duration,
+        size: Option<u64>,
+    ) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        let stats = match request_type {
+            Some(RequestType::Read) => &mut self.read,
+            Some(RequestType::Write) => &mut self.write,
+            Some(RequestType::Flush) => &mut self.flush,
+            Some(RequestType::Discard) => &mut self.discard,
+            Some(RequestType::WriteZeroes) => &mut self.write_zeroes,
+            None => return,
+        };
+        add(&mut stats.latency_us, latency.as_micros() as u64);
+        match size {
+            Some(size) => add(&mut stats.size_bytes, size),
+            None => stats.errors += 1,
+        }
+    }
+
+    /// Clears the statistics of completed requests. Requests in flight are still counted.
+    pub fn reset(&mut self) {
+        *self = BlockStats {
+            in_flight: self.in_flight,
+            ..Default::default()
+        };
+    }
+
+    pub fn to_disk_stats(&self) -> DiskStats {
+        DiskStats {
+            read: self.read.to_disk_request_stats(),
+            write: self.write.to_disk_request_stats(),
+            flush: self.flush.to_disk_request_stats(),
+            discard: self.discard.to_disk_request_stats(),
+            write_zeroes: self.write_zeroes.to_disk_request_stats(),
+            in_flight: self.in_flight,
+            queue_depth: to_disk_histogram(&self.queue_depth),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(
+            power_of_two_buckets(16, 64),
+            vec![0..16, 16..32, 32..64, 64..u64::MAX]
+        );
+    }
+
+    #[test]
+    fn record_requests() {
+        let mut stats = BlockStats::default();
+        stats.start_request();
+        stats.start_request();
+        stats.end_request(
+            Some(RequestType::Read),
+            Duration::from_micros(100),
+            Some(4096),
+        );
+        stats.start_request();
+        stats.end_request(Some(RequestType::Read), Duration::from_micros(20), None);
+        stats.end_request(None, Duration::from_micros(5), None);
+
+        let disk_stats = stats.to_disk_stats();
+        assert_eq!(disk_stats.in_flight, 0);
+        assert_eq!(disk_stats.read.errors, 1);
+        assert_eq!(disk_stats.read.latency_us.count, 2);
+        assert_eq!(disk_stats.read.latency_us.min, Some(20));
+        assert_eq!(disk_stats.read.latency_us.max, Some(100));
+        assert_eq!(
+            disk_stats.read.latency_us.buckets,
+            vec![
+                DiskHistogramBucket {
+                    start: 16,
+                    end: 32,
+                    count: 1
+                },
+                DiskHistogramBucket {
+                    start: 64,
+                    end: 128,
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(disk_stats.read.size_bytes.count, 1);
+        assert_eq!(disk_stats.read.size_bytes.sum, 4096);
+        assert_eq!(disk_stats.write, DiskRequestStats::default());
+        // Depths of 1, 2 and 2.
+        assert_eq!(disk_stats.queue_depth.count, 3);
+        assert_eq!(disk_stats.queue_depth.max, Some(2));
+    }
+
+    #[test]
+    fn reset_keeps_in_flight() {
+        let mut stats = BlockStats::default();
+        stats.start_request();
+        stats.start_request();
+        stats.end_request(Some(RequestType::Flush), Duration::from_micros(10), Some(0));
+        stats.reset();
+
+        let disk_stats = stats.to_disk_stats();
+        assert_eq!(disk_stats.in_flight, 1);
+        assert_eq!(disk_stats.flush, DiskRequestStats::default());
+        assert_eq!(disk_stats.queue_depth, DiskHistogram::default());
+    }
+}
@@ -8,6 +8,7 @@ use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::fd::AsRawFd;
 
+use anyhow::bail;
 use anyhow::Context;
 use base::add_fd_flags;
 use base::flock;
@@ -32,6 +33,14 @@ pub fn get_seg_max(queue_size: u16) -> u32 {
 impl DiskOption {
     /// Open the specified disk file.
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
+        if let Some(uri) = self.path.to_str().filter(|path| disk::is_nbd_uri(path)) {
+            if self.key_file.is_some() {
+                bail!("key-file is not supported for NBD exports");
+            }
+            return disk::create_nbd_disk_file(uri, self.read_only)
+                .with_context(|| format!("failed to open NBD export {}", uri));
+        }
+
         let mut options = OpenOptions::new();
         options.read(true).write(!self.read_only);
 
@@ -54,8 +63,20 @@ impl DiskOption {
                 .with_context(|| format!("failed to set O_DIRECT to {}", &self.path.display()))?;
         }
 
-        disk::create_disk_file(raw_image, self.sparse, disk::MAX_NESTING_DEPTH, &self.path)
-            .context("create_disk_file failed")
+        match self.read_key()? {
+            Some(key) => disk::create_encrypted_disk_file(
+                raw_image,
+                self.sparse,
+                disk::MAX_NESTING_DEPTH,
+                &self.path,
+                &key,
+            )
+            .context("create_encrypted_disk_file failed"),
+            None => {
+                disk::create_disk_file(raw_image, self.sparse, disk::MAX_NESTING_DEPTH, &self.path)
+                    .context("create_disk_file failed")
+            }
+        }
     }
 }
 
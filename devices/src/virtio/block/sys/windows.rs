@@ -50,6 +50,15 @@ impl DiskOption {
         let file = open_option
             .open(&self.path)
             .context("Failed to open disk file")?;
+        if let Some(key) = self.read_key()? {
+            return Ok(disk::create_encrypted_disk_file(
+                file,
+                self.sparse,
+                disk::MAX_NESTING_DEPTH,
+                &self.path,
+                &key,
+            )?);
+        }
         let image_type = disk::detect_image_type(
             &file,
             self.async_executor == Some(ExecutorKindSys::Overlapped.into()),
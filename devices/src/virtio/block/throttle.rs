@@ -0,0 +1,162 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Token bucket based I/O limits for block devices.
+
+use std::num::NonZeroU64;
+use std::time::Duration;
+use std::time::Instant;
+
+use vm_control::DiskThrottleConfig;
+
+use crate::virtio::token_bucket::TokenBucket;
+
+/// Direction of a throttled request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoDirection {
+    Read,
+    Write,
+}
+
+/// I/O limits of a block device. Each configured limit is enforced by its own token bucket.
+pub struct Throttle {
+    read_iops: Option<TokenBucket>,
+    write_iops: Option<TokenBucket>,
+    read_bps: Option<TokenBucket>,
+    write_bps: Option<TokenBucket>,
+}
+
+impl Throttle {
+    pub fn new(config: DiskThrottleConfig) -> Throttle {
+        let now = Instant::now();
+        let bucket =
+            |rate: Option<NonZeroU64>, burst| rate.map(|r| TokenBucket::new(r, burst, now));
+        Throttle {
+            read_iops: bucket(config.read_iops, config.read_iops_burst),
+            write_iops: bucket(config.write_iops, config.write_iops_burst),
+            read_bps: bucket(config.read_bps, config.read_bps_burst),
+            write_bps: bucket(config.write_bps, config.write_bps_burst),
+        }
+    }
+
+    /// Accounts for one request transferring `bytes` bytes and returns how long it must be delayed
+    /// to stay within the limits.
+    pub fn consume(&mut self, direction: IoDirection, bytes: u64, now: Instant) -> Duration {
+        let (iops, bps) = match direction {
+            IoDirection::Read => (&mut self.read_iops, &mut self.read_bps),
+            IoDirection::Write => (&mut self.write_iops, &mut self.write_bps),
+        };
+        let iops_delay = iops
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.consume(1, now));
+        let bps_delay = bps
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.consume(bytes, now));
+        iops_delay.max(bps_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nz(v: u64) -> Option<NonZeroU64> {
+        NonZeroU64::new(v)
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut throttle = Throttle::new(DiskThrottleConfig::default());
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(
+                throttle.consume(IoDirection::Write, 1 << 20, now),
+                Duration::ZERO
+            );
+        }
+    }
+
+    #[test]
+    fn iops_burst_then_rate() {
+        let mut throttle = Throttle::new(DiskThrottleConfig {
+            read_iops: nz(10),
+            read_iops_burst: nz(2),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 512, now),
+            Duration::ZERO
+        );
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 512, now),
+            Duration::ZERO
+        );
+        // The burst is used up, so each further request waits for one more token.
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 512, now),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 512, now),
+            Duration::from_millis(200)
+        );
+        // Writes are limited separately.
+        assert_eq!(
+            throttle.consume(IoDirection::Write, 512, now),
+            Duration::ZERO
+        );
+        // The debt is repaid over time and the bucket refills up to the burst size.
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 512, later),
+            Duration::ZERO
+        );
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 512, later),
+            Duration::ZERO
+        );
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 512, later),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn bandwidth_limit() {
+        let mut throttle = Throttle::new(DiskThrottleConfig {
+            write_bps: nz(1 << 20),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        // A request larger than the burst is let through after the deficit has been refilled.
+        assert_eq!(
+            throttle.consume(IoDirection::Write, 3 << 20, now),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            throttle.consume(IoDirection::Read, 3 << 20, now),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn longest_delay_wins() {
+        let mut throttle = Throttle::new(DiskThrottleConfig {
+            write_iops: nz(1),
+            write_bps: nz(1000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert_eq!(
+            throttle.consume(IoDirection::Write, 500, now),
+            Duration::ZERO
+        );
+        // The second op has to wait a second, its bytes only half a second.
+        assert_eq!(
+            throttle.consume(IoDirection::Write, 1000, now),
+            Duration::from_secs(1)
+        );
+    }
+}
@@ -5,10 +5,12 @@
 mod edid;
 mod parameters;
 mod protocol;
+mod recorder;
 mod virtio_gpu;
 
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -40,6 +42,8 @@ use base::WaitContext;
 use base::WorkerThread;
 use data_model::*;
 pub use gpu_display::EventDevice;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use gpu_display::VncAddress as GpuVncAddress;
 use gpu_display::*;
 pub use parameters::GpuParameters;
 use rutabaga_gfx::*;
@@ -248,6 +252,7 @@ fn build(
     #[cfg(windows)] wndproc_thread: &mut Option<WindowProcedureThread>,
     udmabuf: bool,
     #[cfg(windows)] gpu_display_wait_descriptor_ctrl_wr: SendTube,
+    record_files: BTreeMap<u32, File>,
 ) -> Option<VirtioGpu> {
     let mut display_opt = None;
     for display_backend in display_backends {
@@ -284,6 +289,7 @@ fn build(
         external_blob,
         fixed_blob_mapping,
         udmabuf,
+        record_files,
     )
 }
 
@@ -1114,6 +1120,9 @@ pub enum DisplayBackend {
     #[cfg(any(target_os = "android", target_os = "linux"))]
     /// Open a connection to the X server at the given display if given.
     X(Option<String>),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    /// Serve each scanout to VNC clients, the first one on the given address.
+    Vnc(VncAddress),
     /// Emulate a display without actually displaying it.
     Stub,
     #[cfg(windows)]
@@ -1132,6 +1141,8 @@ impl DisplayBackend {
             DisplayBackend::Wayland(path) => GpuDisplay::open_wayland(path.as_ref()),
             #[cfg(any(target_os = "android", target_os = "linux"))]
             DisplayBackend::X(display) => GpuDisplay::open_x(display.as_deref()),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            DisplayBackend::Vnc(address) => GpuDisplay::open_vnc(address),
             DisplayBackend::Stub => GpuDisplay::open_stub(),
             #[cfg(windows)]
             DisplayBackend::WinApi(display_properties) => match wndproc_thread.take() {
@@ -1176,6 +1187,9 @@ pub struct Gpu {
     )>,
     display_backends: Vec<DisplayBackend>,
     display_params: Vec<GpuDisplayParameters>,
+    // Files opened for the displays recorded from startup, by display id. They are handed over to
+    // the first worker, so recordings do not resume after the device sleeps.
+    record_files: BTreeMap<u32, File>,
     display_event: Arc<AtomicBool>,
     rutabaga_builder: RutabagaBuilder,
     pci_address: Option<PciAddress>,
@@ -1219,13 +1233,30 @@ impl Gpu {
         channels: &BTreeMap<String, PathBuf>,
         #[cfg(windows)] wndproc_thread: WindowProcedureThread,
         #[cfg(any(target_os = "android", target_os = "linux"))] gpu_cgroup_path: Option<&PathBuf>,
-    ) -> Gpu {
+    ) -> anyhow::Result<Gpu> {
         let mut display_params = gpu_parameters.display_params.clone();
         if display_params.is_empty() {
             display_params.push(Default::default());
         }
         let (display_width, display_height) = display_params[0].get_virtual_display_size();
 
+        // The files are created now as the device may not be able to access their paths once
+        // sandboxed.
+        let record_files = display_params
+            .iter()
+            .enumerate()
+            .filter_map(|(display_id, params)| {
+                let path = params.record.as_ref()?;
+                Some(
+                    File::create(path)
+                        .with_context(|| {
+                            format!("failed to create recording file {}", path.display())
+                        })
+                        .map(|file| (display_id as u32, file)),
+                )
+            })
+            .collect::<anyhow::Result<_>>()?;
+
         let mut rutabaga_channels: Vec<RutabagaChannel> = Vec::new();
         for (channel_name, path) in channels {
             match &channel_name[..] {
@@ -1280,7 +1311,7 @@ impl Gpu {
         let (gpu_display_wait_descriptor_ctrl_wr, gpu_display_wait_descriptor_ctrl_rd) =
             Tube::directional_pair().expect("failed to create wait descriptor control pair.");
 
-        Gpu {
+        Ok(Gpu {
             exit_evt_wrtube,
             gpu_control_tube: Some(gpu_control_tube),
             mapper: Arc::new(Mutex::new(None)),
@@ -1289,6 +1320,7 @@ impl Gpu {
             worker_thread: None,
             display_backends,
             display_params,
+            record_files,
             display_event: Arc::new(AtomicBool::new(false)),
             rutabaga_builder,
             pci_address: gpu_parameters.pci_address,
@@ -1310,7 +1342,7 @@ impl Gpu {
             gpu_cgroup_path: gpu_cgroup_path.cloned(),
             sleep_requested: Arc::new(AtomicBool::new(false)),
             worker_snapshot: None,
-        }
+        })
     }
 
     /// Initializes the internal device state so that it can begin processing virtqueues.
@@ -1347,6 +1379,7 @@ impl Gpu {
             self.gpu_display_wait_descriptor_ctrl_wr
                 .try_clone()
                 .expect("failed to clone wait context control channel"),
+            std::mem::take(&mut self.record_files),
         )?;
 
         for event_device in self.event_devices.take().expect("missing event_devices") {
@@ -1381,6 +1414,7 @@ impl Gpu {
 
         let display_backends = self.display_backends.clone();
         let display_params = self.display_params.clone();
+        let record_files = std::mem::take(&mut self.record_files);
         let display_event = self.display_event.clone();
         let event_devices = self.event_devices.take().expect("missing event_devices");
         let external_blob = self.external_blob;
@@ -1456,6 +1490,7 @@ impl Gpu {
                 udmabuf,
                 #[cfg(windows)]
                 gpu_display_wait_descriptor_ctrl_wr,
+                record_files,
             ) {
                 Some(backend) => backend,
                 None => {
@@ -1667,6 +1702,10 @@ impl VirtioDevice for Gpu {
             keep_rds.push(event_device.as_raw_descriptor());
         }
 
+        for file in self.record_files.values() {
+            keep_rds.push(file.as_raw_descriptor());
+        }
+
         keep_rds
     }
 
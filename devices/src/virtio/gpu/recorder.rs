@@ -0,0 +1,591 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Recording of the frames flushed to a scanout into a video file.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use anyhow::anyhow;
+#[cfg(not(all(feature = "ffmpeg", any(target_os = "android", target_os = "linux"))))]
+use anyhow::bail;
+use anyhow::Context;
+use base::error;
+use base::warn;
+
+/// The number of frames waiting to be written to the file beyond which new frames are dropped, so
+/// that a slow encoder or disk never holds up the display.
+const QUEUED_FRAMES: usize = 4;
+
+/// Writes frames of the dimensions given when the writer was created to a video file.
+trait FrameWriter {
+    /// Appends a frame of RGBA8888 pixels, shown `timestamp_us` microseconds after the start of
+    /// the recording.
+    fn write_frame(&mut self, timestamp_us: u64, rgba: &[u8]) -> anyhow::Result<()>;
+
+    /// Writes whatever the file still needs to be complete. No frame may be written afterwards.
+    fn finish(&mut self) -> anyhow::Result<()>;
+}
+
+/// A frame waiting to be written by the recorder thread.
+struct Frame {
+    timestamp_us: u64,
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+/// Records the contents of a scanout each time it is flushed.
+///
+/// The frames are encoded and written by a thread of their own. Frames flushed while that thread
+/// is still busy with `QUEUED_FRAMES` others are dropped.
+pub struct ScanoutRecorder {
+    path: PathBuf,
+    start: Instant,
+    last_timestamp_us: Option<u64>,
+    frames: Option<SyncSender<Frame>>,
+    thread: Option<JoinHandle<anyhow::Result<()>>>,
+    dropped_frames: u64,
+}
+
+impl ScanoutRecorder {
+    /// Starts a recording to `file`, which was opened from `path`, and writes the header of the
+    /// video.
+    ///
+    /// The format is chosen from the extension of `path`: frames are stored uncompressed in a Y4M
+    /// file for `.y4m`, and otherwise encoded losslessly with FFV1 into the container matching the
+    /// extension, which requires the `ffmpeg` feature. The video is `width` by `height` pixels, and
+    /// `frame_rate` is only used as a hint by the formats that require one.
+    pub fn new(
+        path: PathBuf,
+        file: File,
+        width: u32,
+        height: u32,
+        frame_rate: u32,
+    ) -> anyhow::Result<Self> {
+        let (frames, receiver) = sync_channel(QUEUED_FRAMES);
+        let (ready_sender, ready) = std::sync::mpsc::channel();
+        let writer_path = path.clone();
+        let thread = thread::Builder::new()
+            .name("v_gpu_recorder".to_string())
+            .spawn(move || {
+                // The FFmpeg contexts can't be moved to another thread, so the writer is created by
+                // the thread that uses it.
+                let mut writer =
+                    match Self::create_writer(&writer_path, file, width, height, frame_rate) {
+                        Ok(writer) => writer,
+                        Err(e) => {
+                            let _ = ready_sender.send(Err(e));
+                            return Ok(());
+                        }
+                    };
+                let _ = ready_sender.send(Ok(()));
+                write_frames(&mut *writer, width, height, receiver)
+            })
+            .context("failed to start the recorder thread")?;
+        ready
+            .recv()
+            .context("the recorder thread exited")?
+            .with_context(|| format!("cannot record to {}", path.display()))?;
+
+        Ok(ScanoutRecorder {
+            path,
+            start: Instant::now(),
+            last_timestamp_us: None,
+            frames: Some(frames),
+            thread: Some(thread),
+            dropped_frames: 0,
+        })
+    }
+
+    fn create_writer(
+        path: &Path,
+        file: File,
+        width: u32,
+        height: u32,
+        frame_rate: u32,
+    ) -> anyhow::Result<Box<dyn FrameWriter>> {
+        let is_y4m = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("y4m"));
+        if is_y4m {
+            Ok(Box::new(Y4mWriter::new(
+                BufWriter::new(file),
+                width,
+                height,
+                frame_rate,
+            )?))
+        } else {
+            Self::encoded_writer(path, file, width, height)
+        }
+    }
+
+    #[cfg(all(feature = "ffmpeg", any(target_os = "android", target_os = "linux")))]
+    fn encoded_writer(
+        path: &Path,
+        file: File,
+        width: u32,
+        height: u32,
+    ) -> anyhow::Result<Box<dyn FrameWriter>> {
+        Ok(Box::new(ffmpeg_writer::FfmpegWriter::new(
+            path, file, width, height,
+        )?))
+    }
+
+    #[cfg(not(all(feature = "ffmpeg", any(target_os = "android", target_os = "linux"))))]
+    fn encoded_writer(
+        _path: &Path,
+        _file: File,
+        _width: u32,
+        _height: u32,
+    ) -> anyhow::Result<Box<dyn FrameWriter>> {
+        bail!("only .y4m files are supported without the ffmpeg feature")
+    }
+
+    /// Returns the path of the file the scanout is recorded to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queues a frame of RGBA8888 pixels for the recording, timestamped with the current time. The
+    /// frame is dropped if too many frames are already waiting to be written.
+    ///
+    /// Frames whose dimensions differ from those of the video are cropped, or padded with black
+    /// pixels. Returns the error that stopped the recorder thread, if any.
+    pub fn record_frame(&mut self, width: u32, height: u32, rgba: Vec<u8>) -> anyhow::Result<()> {
+        // Frames can be flushed faster than the timestamps resolution, but they must still be
+        // ordered in the video.
+        let mut timestamp_us = self.start.elapsed().as_micros() as u64;
+        if let Some(last_timestamp_us) = self.last_timestamp_us {
+            timestamp_us = timestamp_us.max(last_timestamp_us + 1);
+        }
+
+        let frames = match &self.frames {
+            Some(frames) => frames,
+            None => return Err(anyhow!("the recording is complete")),
+        };
+        match frames.try_send(Frame {
+            timestamp_us,
+            width,
+            height,
+            rgba,
+        }) {
+            Ok(()) => {
+                self.last_timestamp_us = Some(timestamp_us);
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                self.dropped_frames += 1;
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => self.finish(),
+        }
+    }
+
+    /// Completes the recording once the queued frames are written. It is also completed when the
+    /// recorder is dropped, but errors are then only logged.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        // The thread completes the file once no frame can be sent anymore.
+        self.frames = None;
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        if self.dropped_frames > 0 {
+            warn!(
+                "dropped {} frames that could not be written to {} in time",
+                self.dropped_frames,
+                self.path.display()
+            );
+        }
+        thread
+            .join()
+            .map_err(|_| anyhow!("the recorder thread panicked"))?
+    }
+}
+
+impl Drop for ScanoutRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!(
+                "failed to complete the recording to {}: {:#}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Writes the frames received from the recorder to `writer` until the recorder stops sending them,
+/// then completes the file.
+fn write_frames(
+    writer: &mut dyn FrameWriter,
+    width: u32,
+    height: u32,
+    frames: Receiver<Frame>,
+) -> anyhow::Result<()> {
+    let stride = width as usize * 4;
+    // Holds the frames whose dimensions differ from those of the video once cropped or padded.
+    let mut resized = Vec::new();
+    for frame in frames {
+        if (frame.width, frame.height) == (width, height) {
+            writer.write_frame(frame.timestamp_us, &frame.rgba)?;
+            continue;
+        }
+
+        let copied_stride = stride.min(frame.width as usize * 4);
+        resized.clear();
+        resized.resize(stride * height as usize, 0);
+        for (dst, src) in resized
+            .chunks_exact_mut(stride)
+            .zip(frame.rgba.chunks_exact(frame.width as usize * 4))
+        {
+            dst[..copied_stride].copy_from_slice(&src[..copied_stride]);
+        }
+        writer.write_frame(frame.timestamp_us, &resized)?;
+    }
+    writer.finish()
+}
+
+/// Converts a pixel to BT.601 limited range YUV.
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    // The coefficients keep each component within [16, 240].
+    (y as u8, u as u8, v as u8)
+}
+
+/// Writes frames to a YUV4MPEG2 stream in the 4:4:4 format, which can be read by most video tools.
+///
+/// Y4M streams have a constant frame rate, so the timestamp of each frame, in microseconds since
+/// the start of the recording, is stored in the `Xtimestamp_us` parameter of its header.
+struct Y4mWriter<W: Write> {
+    out: W,
+    pixel_count: usize,
+    // The Y, U and V planes of the frame being written.
+    planes: Vec<u8>,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Writes the header of a stream of `width` by `height` frames to `out`.
+    fn new(mut out: W, width: u32, height: u32, frame_rate: u32) -> anyhow::Result<Self> {
+        writeln!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+            width, height, frame_rate
+        )?;
+        let pixel_count = width as usize * height as usize;
+        Ok(Y4mWriter {
+            out,
+            pixel_count,
+            planes: vec![0; pixel_count * 3],
+        })
+    }
+}
+
+impl<W: Write> FrameWriter for Y4mWriter<W> {
+    fn write_frame(&mut self, timestamp_us: u64, rgba: &[u8]) -> anyhow::Result<()> {
+        writeln!(self.out, "FRAME Xtimestamp_us={}", timestamp_us)?;
+
+        let (y_plane, uv_planes) = self.planes.split_at_mut(self.pixel_count);
+        let (u_plane, v_plane) = uv_planes.split_at_mut(self.pixel_count);
+        for (i, pixel) in rgba.chunks_exact(4).take(self.pixel_count).enumerate() {
+            (y_plane[i], u_plane[i], v_plane[i]) = rgb_to_yuv(pixel[0], pixel[1], pixel[2]);
+        }
+        self.out.write_all(&self.planes)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "ffmpeg", any(target_os = "android", target_os = "linux")))]
+mod ffmpeg_writer {
+    use std::fs::File;
+    use std::path::Path;
+
+    use anyhow::anyhow;
+    use anyhow::Context;
+    use ffmpeg::avcodec::AvBuffer;
+    use ffmpeg::avcodec::AvBufferSource;
+    use ffmpeg::avcodec::AvCodecContext;
+    use ffmpeg::avcodec::AvCodecIterator;
+    use ffmpeg::avcodec::AvFrame;
+    use ffmpeg::avcodec::AvPacket;
+    use ffmpeg::avcodec::AvPixelFormat;
+    use ffmpeg::avcodec::Dimensions;
+    use ffmpeg::avcodec::PlaneDescriptor;
+    use ffmpeg::avcodec::TryReceiveResult;
+    use ffmpeg::avformat::AvOutputContext;
+    use ffmpeg::AVPixelFormat_AV_PIX_FMT_BGR0;
+    use ffmpeg::AVRational;
+    use ffmpeg::AV_CODEC_FLAG_GLOBAL_HEADER;
+
+    use super::FrameWriter;
+
+    /// Timestamps are given in microseconds.
+    const TIME_BASE: AVRational = AVRational {
+        num: 1,
+        den: 1_000_000,
+    };
+
+    /// Pixels handed to the encoder.
+    struct FrameBuffer(Vec<u8>);
+
+    impl AvBufferSource for FrameBuffer {
+        fn as_ptr(&self) -> *const u8 {
+            self.0.as_ptr()
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    /// Encodes frames with the lossless FFV1 codec into the container chosen by FFmpeg from the
+    /// file name.
+    pub struct FfmpegWriter {
+        output: AvOutputContext,
+        encoder: AvCodecContext,
+        packet: AvPacket<'static>,
+        width: u32,
+        height: u32,
+    }
+
+    impl FfmpegWriter {
+        /// Opens the encoder for `width` by `height` frames and writes the header of the file.
+        pub fn new(path: &Path, file: File, width: u32, height: u32) -> anyhow::Result<Self> {
+            let mut output = AvOutputContext::new(path, file)?;
+            let encoder = Self::open_encoder(&output, width, height)?;
+            output.write_header(&encoder)?;
+            Ok(FfmpegWriter {
+                output,
+                encoder,
+                packet: AvPacket::empty(),
+                width,
+                height,
+            })
+        }
+
+        fn open_encoder(
+            output: &AvOutputContext,
+            width: u32,
+            height: u32,
+        ) -> anyhow::Result<AvCodecContext> {
+            let codec = AvCodecIterator::new()
+                .find(|codec| codec.is_encoder() && codec.name() == "ffv1")
+                .context("the FFV1 encoder is not available")?;
+            let mut builder = codec.build_encoder()?;
+            builder.set_dimensions(Dimensions { width, height });
+            builder.set_time_base(TIME_BASE);
+            builder.set_pix_fmt(
+                AvPixelFormat::try_from(AVPixelFormat_AV_PIX_FMT_BGR0)
+                    .map_err(|_| anyhow!("unsupported pixel format"))?,
+            );
+            if output.needs_global_header() {
+                builder.set_flags(AV_CODEC_FLAG_GLOBAL_HEADER);
+            }
+            Ok(builder.build()?)
+        }
+
+        /// Writes the packets the encoder has produced to the file.
+        fn write_packets(&mut self) -> anyhow::Result<()> {
+            loop {
+                match self.encoder.try_receive_packet(&mut self.packet)? {
+                    TryReceiveResult::Received => {
+                        self.output.write_packet(&mut self.packet, TIME_BASE)?
+                    }
+                    TryReceiveResult::TryAgain | TryReceiveResult::FlushCompleted => return Ok(()),
+                }
+            }
+        }
+    }
+
+    impl FrameWriter for FfmpegWriter {
+        fn write_frame(&mut self, timestamp_us: u64, rgba: &[u8]) -> anyhow::Result<()> {
+            let (width, height) = (self.width, self.height);
+            let mut bgrx = Vec::with_capacity(rgba.len());
+            for pixel in rgba.chunks_exact(4) {
+                bgrx.extend_from_slice(&[pixel[2], pixel[1], pixel[0], 0]);
+            }
+            let mut builder = AvFrame::builder()?;
+            builder.set_dimensions(Dimensions { width, height })?;
+            builder.set_format(
+                AvPixelFormat::try_from(AVPixelFormat_AV_PIX_FMT_BGR0)
+                    .map_err(|_| anyhow!("unsupported pixel format"))?,
+            )?;
+            let mut frame = builder.build_owned(
+                [AvBuffer::new(FrameBuffer(bgrx)).context("failed to allocate frame buffer")?],
+                [PlaneDescriptor {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: width as usize * 4,
+                }],
+            )?;
+            frame.set_pts(timestamp_us as i64);
+
+            while !self.encoder.try_send_frame(&frame)? {
+                self.write_packets()?;
+            }
+            self.write_packets()
+        }
+
+        fn finish(&mut self) -> anyhow::Result<()> {
+            self.encoder.flush_encoder()?;
+            self.write_packets()?;
+            self.output.finish()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // Splits a Y4M stream into its header and its frames along with their timestamps.
+    fn parse_y4m(data: &[u8]) -> (String, Vec<(u64, Vec<u8>)>) {
+        let header_end = data.iter().position(|&b| b == b'\n').unwrap();
+        let header = String::from_utf8(data[..header_end].to_vec()).unwrap();
+        let frame_size = header
+            .split(' ')
+            .skip(1)
+            .filter_map(|param| match param.split_at(1) {
+                ("W", width) | ("H", width) => Some(width.parse::<usize>().unwrap()),
+                _ => None,
+            })
+            .product::<usize>()
+            * 3;
+
+        let mut frames = Vec::new();
+        let mut rest = &data[header_end + 1..];
+        while !rest.is_empty() {
+            let frame_header_end = rest.iter().position(|&b| b == b'\n').unwrap();
+            let timestamp_us = std::str::from_utf8(&rest[..frame_header_end])
+                .unwrap()
+                .strip_prefix("FRAME Xtimestamp_us=")
+                .unwrap()
+                .parse()
+                .unwrap();
+            rest = &rest[frame_header_end + 1..];
+            frames.push((timestamp_us, rest[..frame_size].to_vec()));
+            rest = &rest[frame_size..];
+        }
+        (header, frames)
+    }
+
+    #[test]
+    fn rgb_to_yuv_limits() {
+        assert_eq!(rgb_to_yuv(0, 0, 0), (16, 128, 128));
+        assert_eq!(rgb_to_yuv(255, 255, 255), (235, 128, 128));
+        assert_eq!(rgb_to_yuv(255, 0, 0), (82, 90, 240));
+        assert_eq!(rgb_to_yuv(0, 0, 255), (41, 240, 110));
+    }
+
+    #[test]
+    fn y4m_frames() {
+        let mut writer = Y4mWriter::new(Vec::new(), 2, 1, 60).unwrap();
+        writer
+            .write_frame(0, &[255, 0, 0, 255, 255, 255, 255, 0])
+            .unwrap();
+        writer
+            .write_frame(16667, &[0, 0, 0, 255, 0, 0, 0, 255])
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut expected = b"YUV4MPEG2 W2 H1 F60:1 Ip A1:1 C444\n".to_vec();
+        expected.extend_from_slice(b"FRAME Xtimestamp_us=0\n");
+        expected.extend_from_slice(&[82, 235, 90, 128, 240, 128]);
+        expected.extend_from_slice(b"FRAME Xtimestamp_us=16667\n");
+        expected.extend_from_slice(&[16, 16, 128, 128, 128, 128]);
+        assert_eq!(writer.out, expected);
+    }
+
+    #[test]
+    fn record_header_without_frames() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("display.y4m");
+        let file = File::create(&path).unwrap();
+        let mut recorder = ScanoutRecorder::new(path.clone(), file, 4, 3, 30).unwrap();
+        recorder.finish().unwrap();
+
+        let (header, frames) = parse_y4m(&read(&path).unwrap());
+        assert_eq!(header, "YUV4MPEG2 W4 H3 F30:1 Ip A1:1 C444");
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn record_resized_frames() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("display.y4m");
+        let file = File::create(&path).unwrap();
+        let mut recorder = ScanoutRecorder::new(path.clone(), file, 2, 2, 30).unwrap();
+
+        // No more than `QUEUED_FRAMES` frames are recorded, so none of them is dropped.
+        let white = [255u8; 4];
+        recorder.record_frame(2, 2, white.repeat(4)).unwrap();
+        // Smaller frames are padded with black pixels.
+        recorder.record_frame(1, 1, white.to_vec()).unwrap();
+        // Larger frames are cropped.
+        recorder.record_frame(3, 3, white.repeat(9)).unwrap();
+        recorder.finish().unwrap();
+        drop(recorder);
+
+        let (header, frames) = parse_y4m(&read(&path).unwrap());
+        assert_eq!(header, "YUV4MPEG2 W2 H2 F30:1 Ip A1:1 C444");
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].1[..4], [235, 235, 235, 235]);
+        assert_eq!(frames[1].1[..4], [235, 16, 16, 16]);
+        assert_eq!(frames[2].1[..4], [235, 235, 235, 235]);
+    }
+
+    #[test]
+    fn record_timestamps_increase() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("display.y4m");
+        let file = File::create(&path).unwrap();
+        let mut recorder = ScanoutRecorder::new(path.clone(), file, 1, 1, 60).unwrap();
+        for _ in 0..10 {
+            recorder.record_frame(1, 1, vec![0, 0, 0, 0]).unwrap();
+        }
+        drop(recorder);
+
+        // Some frames may have been dropped, but those written are ordered.
+        let (_, frames) = parse_y4m(&read(&path).unwrap());
+        assert!(!frames.is_empty());
+        assert!(frames.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[cfg(not(all(feature = "ffmpeg", any(target_os = "android", target_os = "linux"))))]
+    #[test]
+    fn encoded_format_requires_ffmpeg() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("display.mkv");
+        let file = File::create(&path).unwrap();
+        assert!(ScanoutRecorder::new(path, file, 2, 2, 60).is_err());
+    }
+}
@@ -5,24 +5,31 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap as Map;
 use std::collections::BTreeSet as Set;
+use std::fs::File;
 use std::io::IoSliceMut;
 use std::num::NonZeroU32;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::result::Result;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+use anyhow::bail;
 use anyhow::Context;
 use base::error;
+use base::warn;
 use base::FromRawDescriptor;
 use base::IntoRawDescriptor;
+use base::MemoryMappingBuilder;
 use base::Protection;
 use base::SafeDescriptor;
+use base::SharedMemory;
 use base::VolatileSlice;
 use gpu_display::*;
 use hypervisor::MemCacheType;
 use libc::c_void;
+use rutabaga_gfx::DrmFormat;
 use rutabaga_gfx::ResourceCreate3D;
 use rutabaga_gfx::ResourceCreateBlob;
 use rutabaga_gfx::Rutabaga;
@@ -50,6 +57,7 @@ use vm_control::gpu::DisplayParameters;
 use vm_control::gpu::GpuControlCommand;
 use vm_control::gpu::GpuControlResult;
 use vm_control::gpu::MouseMode;
+use vm_control::gpu::DEFAULT_REFRESH_RATE;
 use vm_control::VmMemorySource;
 use vm_memory::udmabuf::UdmabufDriver;
 use vm_memory::udmabuf::UdmabufDriverTrait;
@@ -62,6 +70,11 @@ use super::protocol::GpuResponsePlaneInfo;
 use super::protocol::VirtioGpuResult;
 use super::protocol::VIRTIO_GPU_BLOB_FLAG_CREATE_GUEST_HANDLE;
 use super::protocol::VIRTIO_GPU_BLOB_MEM_HOST3D;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM;
+use super::recorder::ScanoutRecorder;
 use super::VirtioScanoutBlobData;
 use crate::virtio::gpu::edid::DisplayInfo;
 use crate::virtio::gpu::edid::EdidBytes;
@@ -85,6 +98,80 @@ fn to_safe_descriptor(r: RutabagaDescriptor) -> SafeDescriptor {
     unsafe { SafeDescriptor::from_raw_descriptor(r.into_raw_descriptor()) }
 }
 
+fn virtio_gpu_format_to_drm(format: u32) -> Option<DrmFormat> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => Some(DrmFormat::new(b'X', b'R', b'2', b'4')),
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM => Some(DrmFormat::new(b'A', b'R', b'2', b'4')),
+        VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some(DrmFormat::new(b'X', b'B', b'2', b'4')),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM => Some(DrmFormat::new(b'A', b'B', b'2', b'4')),
+        _ => None,
+    }
+}
+
+/// Returns the byte offsets of the red, green and blue channels of a pixel in the given format, and
+/// the offset of its alpha channel if it has one. Only 32-bit RGB formats are supported.
+fn rgba_channel_offsets(format: DrmFormat) -> Option<([usize; 3], Option<usize>)> {
+    match &format.to_bytes() {
+        b"XR24" => Some(([2, 1, 0], None)),
+        b"AR24" => Some(([2, 1, 0], Some(3))),
+        b"XB24" => Some(([0, 1, 2], None)),
+        b"AB24" => Some(([0, 1, 2], Some(3))),
+        _ => None,
+    }
+}
+
+/// Reads back the contents of a resource as RGBA8888 pixels, without padding between rows. Returns
+/// the width and height of the resource along with the pixels.
+fn read_resource_rgba(
+    resource: &VirtioGpuResource,
+    rutabaga: &mut Rutabaga,
+) -> anyhow::Result<(u32, u32, Vec<u8>)> {
+    let (width, height, format) = match resource.scanout_data {
+        Some(data) => (data.width, data.height, Some(data.drm_format)),
+        None => (
+            resource.width,
+            resource.height,
+            resource
+                .format
+                .and_then(virtio_gpu_format_to_drm)
+                .or_else(|| {
+                    let query = rutabaga.query(resource.resource_id).ok()?;
+                    Some(DrmFormat::from(query.drm_fourcc))
+                }),
+        ),
+    };
+    let format = format.context("unknown resource format")?;
+    let (rgb, alpha) = rgba_channel_offsets(format)
+        .with_context(|| format!("unsupported resource format {:?}", format))?;
+    if width == 0 || height == 0 {
+        bail!("the resource is empty");
+    }
+
+    let stride = width.checked_mul(4).context("resource is too wide")?;
+    let mut pixels = vec![0u8; stride as usize * height as usize];
+    let mut transfer = Transfer3D::new_2d(0, 0, width, height);
+    transfer.stride = stride;
+    rutabaga
+        .transfer_read(
+            0,
+            resource.resource_id,
+            transfer,
+            Some(IoSliceMut::new(&mut pixels)),
+        )
+        .context("failed to read resource")?;
+
+    for pixel in pixels.chunks_exact_mut(4) {
+        let rgba = [
+            pixel[rgb[0]],
+            pixel[rgb[1]],
+            pixel[rgb[2]],
+            alpha.map_or(0xff, |a| pixel[a]),
+        ];
+        pixel.copy_from_slice(&rgba);
+    }
+    Ok((width, height, pixels))
+}
+
 struct VirtioGpuResource {
     resource_id: u32,
     width: u32,
@@ -94,6 +181,8 @@ struct VirtioGpuResource {
     scanout_data: Option<VirtioScanoutBlobData>,
     display_import: Option<u32>,
     rutabaga_external_mapping: bool,
+    // The virtio-gpu format given at creation, if any. Only used to read the resource back.
+    format: Option<u32>,
 
     // Only saved for snapshotting, so that we can re-attach backing iovecs with the correct new
     // host addresses.
@@ -106,6 +195,7 @@ struct VirtioGpuResourceSnapshot {
     width: u32,
     height: u32,
     size: u64,
+    format: Option<u32>,
 
     backing_iovecs: Option<Vec<(GuestAddress, usize)>>,
 }
@@ -123,6 +213,7 @@ impl VirtioGpuResource {
             scanout_data: None,
             display_import: None,
             rutabaga_external_mapping: false,
+            format: None,
             backing_iovecs: None,
         }
     }
@@ -138,12 +229,14 @@ impl VirtioGpuResource {
             width: self.width,
             height: self.height,
             size: self.size,
+            format: self.format,
             backing_iovecs: self.backing_iovecs.clone(),
         }
     }
 
     fn restore(s: VirtioGpuResourceSnapshot) -> Self {
         let mut resource = VirtioGpuResource::new(s.resource_id, s.width, s.height, s.size);
+        resource.format = s.format;
         resource.backing_iovecs = s.backing_iovecs;
         resource
     }
@@ -165,6 +258,9 @@ struct VirtioGpuScanout {
 
     resource_id: Option<NonZeroU32>,
     position: Option<(u32, u32)>,
+
+    // If this scanout is being recorded, the recorder receiving its flushed frames.
+    recorder: Option<ScanoutRecorder>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -199,6 +295,7 @@ impl VirtioGpuScanout {
             parent_scanout_id: None,
             resource_id: None,
             position: None,
+            recorder: None,
         }
     }
 
@@ -216,6 +313,7 @@ impl VirtioGpuScanout {
             parent_scanout_id: None,
             resource_id: None,
             position: None,
+            recorder: None,
         }
     }
 
@@ -345,6 +443,19 @@ impl VirtioGpuScanout {
         resource: &mut VirtioGpuResource,
         rutabaga: &mut Rutabaga,
     ) -> VirtioGpuResult {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = read_resource_rgba(resource, rutabaga)
+                .and_then(|(width, height, rgba)| recorder.record_frame(width, height, rgba))
+            {
+                error!(
+                    "stopping the recording to {}: {:#}",
+                    recorder.path().display(),
+                    e
+                );
+                self.recorder = None;
+            }
+        }
+
         let surface_id = match self.surface_id {
             Some(id) => id,
             _ => return Ok(OkNoData),
@@ -524,6 +635,7 @@ impl VirtioGpu {
         external_blob: bool,
         fixed_blob_mapping: bool,
         udmabuf: bool,
+        mut record_files: Map<u32, File>,
     ) -> Option<VirtioGpu> {
         let mut udmabuf_driver = None;
         if udmabuf {
@@ -538,10 +650,23 @@ impl VirtioGpu {
             .iter()
             .enumerate()
             .map(|(display_index, display_param)| {
-                (
-                    display_index as u32,
-                    VirtioGpuScanout::new_primary(display_index as u32, display_param.clone()),
-                )
+                let display_id = display_index as u32;
+                let mut scanout = VirtioGpuScanout::new_primary(display_id, display_param.clone());
+                if let (Some(path), Some(file)) =
+                    (&display_param.record, record_files.remove(&display_id))
+                {
+                    match ScanoutRecorder::new(
+                        path.clone(),
+                        file,
+                        scanout.width,
+                        scanout.height,
+                        display_param.refresh_rate,
+                    ) {
+                        Ok(recorder) => scanout.recorder = Some(recorder),
+                        Err(e) => error!("failed to record display {}: {:#}", display_id, e),
+                    }
+                }
+                (display_id, scanout)
             })
             .collect::<Map<_, _>>();
         let cursor_scanout = VirtioGpuScanout::new_cursor();
@@ -605,6 +730,15 @@ impl VirtioGpu {
             let new_scanout_id = *available_scanout_ids.iter().next().unwrap();
             available_scanout_ids.remove(&new_scanout_id);
 
+            if let Some(path) = &display_params.record {
+                warn!(
+                    "not recording display {} to {}: use `crosvm gpu start-recording` for added \
+                     displays",
+                    new_scanout_id,
+                    path.display()
+                );
+            }
+
             self.scanouts.insert(
                 new_scanout_id,
                 VirtioGpuScanout::new_primary(new_scanout_id, display_params),
@@ -674,16 +808,114 @@ impl VirtioGpu {
         }
     }
 
+    /// Reads back the resource scanned out to the given display.
+    fn screenshot(&mut self, display_id: u32) -> GpuControlResult {
+        let resource_id = match self.scanouts.get(&display_id) {
+            Some(VirtioGpuScanout {
+                resource_id: Some(resource_id),
+                ..
+            }) => resource_id.get(),
+            Some(_) => {
+                return GpuControlResult::ErrString(
+                    "the display is not showing any resource".to_string(),
+                )
+            }
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        match self.read_screenshot(resource_id) {
+            Ok((width, height, rgba)) => GpuControlResult::DisplayScreenshot {
+                width,
+                height,
+                rgba,
+            },
+            Err(e) => GpuControlResult::ErrString(format!("failed to take screenshot: {:#}", e)),
+        }
+    }
+
+    /// Copies the contents of a resource to shared memory as RGBA8888 pixels, without padding
+    /// between rows. Returns the width and height of the resource along with the shared memory.
+    fn read_screenshot(&mut self, resource_id: u32) -> anyhow::Result<(u32, u32, SharedMemory)> {
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .context("invalid resource id")?;
+        let (width, height, pixels) = read_resource_rgba(resource, &mut self.rutabaga)?;
+
+        let shm = SharedMemory::new("gpu_screenshot", pixels.len() as u64)
+            .context("failed to create shared memory")?;
+        MemoryMappingBuilder::new(pixels.len())
+            .from_shared_memory(&shm)
+            .build()
+            .context("failed to map shared memory")?
+            .write_slice(&pixels, 0)
+            .context("failed to write shared memory")?;
+        Ok((width, height, shm))
+    }
+
+    /// Starts recording the frames flushed to the given display to `file`, replacing any recording
+    /// in progress.
+    fn start_recording(&mut self, display_id: u32, path: PathBuf, file: File) -> GpuControlResult {
+        let scanout = match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        let refresh_rate = scanout
+            .display_params
+            .as_ref()
+            .map_or(DEFAULT_REFRESH_RATE, |params| params.refresh_rate);
+        match ScanoutRecorder::new(path, file, scanout.width, scanout.height, refresh_rate) {
+            Ok(recorder) => {
+                if let Some(mut previous) = scanout.recorder.replace(recorder) {
+                    if let Err(e) = previous.finish() {
+                        error!(
+                            "failed to complete the recording to {}: {:#}",
+                            previous.path().display(),
+                            e
+                        );
+                    }
+                }
+                GpuControlResult::DisplayRecordingStarted
+            }
+            Err(e) => GpuControlResult::ErrString(format!("failed to start recording: {:#}", e)),
+        }
+    }
+
+    /// Stops recording the given display.
+    fn stop_recording(&mut self, display_id: u32) -> GpuControlResult {
+        let scanout = match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        match scanout.recorder.take() {
+            Some(mut recorder) => match recorder.finish() {
+                Ok(()) => GpuControlResult::DisplayRecordingStopped,
+                Err(e) => GpuControlResult::ErrString(format!(
+                    "failed to complete the recording to {}: {:#}",
+                    recorder.path().display(),
+                    e
+                )),
+            },
+            None => GpuControlResult::ErrString("the display is not being recorded".to_string()),
+        }
+    }
+
     /// Performs the given command to interact with or modify the device.
     pub fn process_gpu_control_command(&mut self, cmd: GpuControlCommand) -> GpuControlResult {
         match cmd {
             GpuControlCommand::AddDisplays { displays } => self.add_displays(displays),
             GpuControlCommand::ListDisplays => self.list_displays(),
             GpuControlCommand::RemoveDisplays { display_ids } => self.remove_displays(display_ids),
+            GpuControlCommand::Screenshot { display_id } => self.screenshot(display_id),
             GpuControlCommand::SetDisplayMouseMode {
                 display_id,
                 mouse_mode,
             } => self.set_display_mouse_mode(display_id, mouse_mode),
+            GpuControlCommand::StartRecording {
+                display_id,
+                path,
+                file,
+            } => self.start_recording(display_id, path, file),
+            GpuControlCommand::StopRecording { display_id } => self.stop_recording(display_id),
         }
     }
 
@@ -894,12 +1126,13 @@ impl VirtioGpu {
         self.rutabaga
             .resource_create_3d(resource_id, resource_create_3d)?;
 
-        let resource = VirtioGpuResource::new(
+        let mut resource = VirtioGpuResource::new(
             resource_id,
             resource_create_3d.width,
             resource_create_3d.height,
             0,
         );
+        resource.format = Some(resource_create_3d.format);
 
         // Rely on rutabaga to check for duplicate resource ids.
         self.resources.insert(resource_id, resource);
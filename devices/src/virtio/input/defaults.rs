@@ -90,6 +90,51 @@ pub fn new_rotary_config(idx: u32) -> VirtioInputConfig {
     )
 }
 
+/// Instantiates a VirtioInputConfig object with the default configuration for a tablet, an absolute
+/// pointer which lets the guest cursor follow the host one. It supports X and Y axes, left, right
+/// and middle buttons and a wheel.
+pub fn new_tablet_config(
+    idx: u32,
+    width: u32,
+    height: u32,
+    name: Option<&str>,
+) -> VirtioInputConfig {
+    let name = name
+        .map(|name| name.as_bytes().to_vec())
+        .unwrap_or(name_with_index(b"Crosvm Virtio Tablet ", idx));
+    VirtioInputConfig::new(
+        virtio_input_device_ids::new(0, 0, 0, 0),
+        name,
+        name_with_index(b"virtio-tablet-", idx),
+        virtio_input_bitmap::new([0u8; 128]),
+        default_tablet_events(),
+        default_tablet_absinfo(width, height),
+    )
+}
+
+/// Instantiates a VirtioInputConfig object with the default configuration for a gamepad. It has
+/// two sticks whose axes range from `axis_min` to `axis_max`, two analog triggers ranging from 0 to
+/// `trigger_max`, a directional pad reported as a hat and the buttons of a standard gamepad.
+pub fn new_gamepad_config(
+    idx: u32,
+    axis_min: i32,
+    axis_max: i32,
+    trigger_max: i32,
+    name: Option<&str>,
+) -> VirtioInputConfig {
+    let name = name
+        .map(|name| name.as_bytes().to_vec())
+        .unwrap_or(name_with_index(b"Crosvm Virtio Gamepad ", idx));
+    VirtioInputConfig::new(
+        virtio_input_device_ids::new(0, 0, 0, 0),
+        name,
+        name_with_index(b"virtio-gamepad-", idx),
+        virtio_input_bitmap::new([0u8; 128]),
+        default_gamepad_events(),
+        default_gamepad_absinfo(axis_min, axis_max, trigger_max),
+    )
+}
+
 /// Instantiates a VirtioInputConfig object with the default configuration for a touchscreen (no
 /// multitouch support).
 pub fn new_single_touch_config(
@@ -198,6 +243,62 @@ fn default_trackpad_events() -> BTreeMap<u16, virtio_input_bitmap> {
     supported_events
 }
 
+fn default_tablet_absinfo(width: u32, height: u32) -> BTreeMap<u16, virtio_input_absinfo> {
+    let mut absinfo: BTreeMap<u16, virtio_input_absinfo> = BTreeMap::new();
+    absinfo.insert(ABS_X, virtio_input_absinfo::new(0, width, 0, 0));
+    absinfo.insert(ABS_Y, virtio_input_absinfo::new(0, height, 0, 0));
+    absinfo
+}
+
+fn default_tablet_events() -> BTreeMap<u16, virtio_input_bitmap> {
+    let mut supported_events: BTreeMap<u16, virtio_input_bitmap> = BTreeMap::new();
+    supported_events.insert(
+        EV_KEY,
+        virtio_input_bitmap::from_bits(&[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]),
+    );
+    supported_events.insert(EV_ABS, virtio_input_bitmap::from_bits(&[ABS_X, ABS_Y]));
+    supported_events.insert(EV_REL, virtio_input_bitmap::from_bits(&[REL_WHEEL]));
+    supported_events
+}
+
+fn default_gamepad_absinfo(
+    axis_min: i32,
+    axis_max: i32,
+    trigger_max: i32,
+) -> BTreeMap<u16, virtio_input_absinfo> {
+    let mut absinfo: BTreeMap<u16, virtio_input_absinfo> = BTreeMap::new();
+    // The minimums are signed values, sent in their two's complement representation.
+    let stick = virtio_input_absinfo::new(axis_min as u32, axis_max as u32, 0, 0);
+    for axis in [ABS_X, ABS_Y, ABS_RX, ABS_RY] {
+        absinfo.insert(axis, stick);
+    }
+    for axis in [ABS_Z, ABS_RZ] {
+        absinfo.insert(axis, virtio_input_absinfo::new(0, trigger_max as u32, 0, 0));
+    }
+    for axis in [ABS_HAT0X, ABS_HAT0Y] {
+        absinfo.insert(axis, virtio_input_absinfo::new(-1i32 as u32, 1, 0, 0));
+    }
+    absinfo
+}
+
+fn default_gamepad_events() -> BTreeMap<u16, virtio_input_bitmap> {
+    let mut supported_events: BTreeMap<u16, virtio_input_bitmap> = BTreeMap::new();
+    supported_events.insert(
+        EV_KEY,
+        virtio_input_bitmap::from_bits(&[
+            BTN_SOUTH, BTN_EAST, BTN_NORTH, BTN_WEST, BTN_TL, BTN_TR, BTN_SELECT, BTN_START,
+            BTN_MODE, BTN_THUMBL, BTN_THUMBR,
+        ]),
+    );
+    supported_events.insert(
+        EV_ABS,
+        virtio_input_bitmap::from_bits(&[
+            ABS_X, ABS_Y, ABS_Z, ABS_RX, ABS_RY, ABS_RZ, ABS_HAT0X, ABS_HAT0Y,
+        ]),
+    );
+    supported_events
+}
+
 fn default_mouse_events() -> BTreeMap<u16, virtio_input_bitmap> {
     let mut supported_events: BTreeMap<u16, virtio_input_bitmap> = BTreeMap::new();
     supported_events.insert(
@@ -392,4 +493,39 @@ mod tests {
         expected_bitmap[2] = 0b1u8;
         assert_eq!(events[&EV_SW].bitmap, expected_bitmap);
     }
+
+    #[test]
+    fn test_new_tablet_config() {
+        let config = new_tablet_config(1, 800, 600, None);
+        assert_eq!(config.name, b"Crosvm Virtio Tablet 1".to_vec());
+        assert_eq!(config.serial_name, b"virtio-tablet-1".to_vec());
+
+        let events = config.supported_events;
+        assert_eq!(events.len(), 3);
+        // BTN_LEFT, BTN_RIGHT and BTN_MIDDLE are bits 0x110 to 0x112.
+        assert_eq!(events[&EV_KEY].bitmap[0x110 / 8], 0b111);
+        assert_eq!(events[&EV_ABS].bitmap[0], 0b11);
+
+        assert_eq!(u32::from(config.axis_info[&ABS_X].max), 800);
+        assert_eq!(u32::from(config.axis_info[&ABS_Y].max), 600);
+    }
+
+    #[test]
+    fn test_new_gamepad_config() {
+        let config = new_gamepad_config(0, -100, 100, 1023, Some("pad"));
+        assert_eq!(config.name, b"pad".to_vec());
+        assert_eq!(config.serial_name, b"virtio-gamepad-0".to_vec());
+
+        let events = config.supported_events;
+        assert_eq!(events.len(), 2);
+        // BTN_SOUTH, BTN_EAST, BTN_NORTH, BTN_WEST, BTN_TL and BTN_TR share the byte at 0x130.
+        assert_eq!(events[&EV_KEY].bitmap[0x130 / 8], 0b11011011);
+        assert_eq!(events[&EV_ABS].min_size(), 3);
+
+        let stick = config.axis_info[&ABS_RY];
+        assert_eq!(u32::from(stick.min) as i32, -100);
+        assert_eq!(u32::from(stick.max), 100);
+        assert_eq!(u32::from(config.axis_info[&ABS_RZ].max), 1023);
+        assert_eq!(u32::from(config.axis_info[&ABS_HAT0X].min) as i32, -1);
+    }
 }
@@ -20,6 +20,11 @@ use super::evdev::ungrab_evdev;
 use super::InputError;
 use super::Result;
 
+/// Number of received events beyond which no more events are read from a source until some of them
+/// are sent to the guest, so that a guest which doesn't consume its events, for instance because
+/// its driver isn't loaded, holds back the source instead of growing the queue without bound.
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
 /// Encapsulates a socket or device node into an abstract event source, providing a common
 /// interface.
 /// It supports read and write operations to provide and accept events just like an event device
@@ -41,6 +46,11 @@ pub trait EventSource: AsRawDescriptor {
     fn receive_events(&mut self) -> Result<usize>;
     /// Returns the number of received events that have not been filtered or consumed yet.
     fn available_events_count(&self) -> usize;
+    /// Returns whether too many events are waiting to be consumed for more to be received, in which
+    /// case `receive_events` reads nothing from the source.
+    fn is_full(&self) -> bool {
+        self.available_events_count() >= MAX_QUEUED_EVENTS
+    }
     /// Returns the next available event
     fn pop_available_event(&mut self) -> Option<virtio_input_event>;
     /// Sends a status update event to the source
@@ -65,8 +75,12 @@ impl<T> EventSourceImpl<T>
 where
     T: Read + Write,
 {
-    // Receive events from the source and store them in a queue.
+    // Receive events from the source and store them in a queue, unless the queue is full. The queue
+    // holds at most one read buffer worth of events beyond `MAX_QUEUED_EVENTS`.
     fn receive_events<E: InputEventDecoder>(&mut self) -> Result<usize> {
+        if self.queue.len() >= MAX_QUEUED_EVENTS {
+            return Ok(0);
+        }
         let read = self
             .source
             .read(&mut self.read_buffer[self.read_idx..])
@@ -150,8 +164,14 @@ where
     T: Read + Write + AsRawDescriptor,
 {
     pub fn new(source: T) -> SocketEventSource<T> {
+        Self::with_capacity(source, 16)
+    }
+
+    /// Creates a source reading up to `capacity` events at once, which must be enough to hold the
+    /// largest message of a source with message framing.
+    pub fn with_capacity(source: T, capacity: usize) -> SocketEventSource<T> {
         SocketEventSource {
-            evt_source_impl: EventSourceImpl::new(source, 16 * virtio_input_event::SIZE),
+            evt_source_impl: EventSourceImpl::new(source, capacity * virtio_input_event::SIZE),
         }
     }
 }
@@ -258,6 +278,7 @@ mod tests {
     use crate::virtio::input::event_source::input_event;
     use crate::virtio::input::event_source::virtio_input_event;
     use crate::virtio::input::event_source::EventSourceImpl;
+    use crate::virtio::input::event_source::MAX_QUEUED_EVENTS;
 
     struct SourceMock {
         events: Vec<u8>,
@@ -385,4 +406,29 @@ mod tests {
             "no events should pop"
         );
     }
+
+    #[test]
+    fn full_queue() {
+        let evts = instantiate_input_events(4usize);
+        let mut source = EventSourceImpl::new(SourceMock::new(&evts), input_event::SIZE * 4);
+        // The mock source returns the same events on every read.
+        while source.available_events() < MAX_QUEUED_EVENTS {
+            assert_eq!(
+                source.receive_events::<input_event>().unwrap(),
+                evts.len(),
+                "should receive all events"
+            );
+        }
+        assert_eq!(
+            source.receive_events::<input_event>().unwrap(),
+            0,
+            "a full queue should not receive events"
+        );
+        source.pop_available_event();
+        assert_eq!(
+            source.receive_events::<input_event>().unwrap(),
+            evts.len(),
+            "should receive events again once some are consumed"
+        );
+    }
 }
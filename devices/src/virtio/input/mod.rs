@@ -21,7 +21,9 @@ use base::warn;
 use base::AsRawDescriptor;
 use base::Event;
 use base::EventToken;
+use base::EventType;
 use base::RawDescriptor;
+use base::StreamChannel;
 use base::WaitContext;
 use base::WorkerThread;
 use data_model::Le16;
@@ -33,6 +35,7 @@ use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
 use thiserror::Error;
+use vm_control::input::MAX_INJECTED_MESSAGE_EVENTS;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -345,14 +348,15 @@ impl VirtioInputConfig {
 struct Worker<T: EventSource> {
     interrupt: Interrupt,
     event_source: T,
+    injector: Option<SocketEventSource<StreamChannel>>,
     event_queue: Queue,
     status_queue: Queue,
 }
 
 impl<T: EventSource> Worker<T> {
     // Fills a virtqueue with events from the source.  Returns the number of bytes written.
-    fn fill_event_virtqueue(
-        event_source: &mut T,
+    fn fill_event_virtqueue<S: EventSource>(
+        event_source: &mut S,
         avail_desc: &mut DescriptorChain,
     ) -> Result<usize> {
         let writer = &mut avail_desc.writer;
@@ -368,20 +372,29 @@ impl<T: EventSource> Worker<T> {
         Ok(writer.bytes_written())
     }
 
-    // Send events from the source to the guest
+    // Send events from the source and the injected events to the guest
     fn send_events(&mut self) -> bool {
+        let mut needs_interrupt =
+            Self::send_source_events(&mut self.event_queue, &mut self.event_source);
+        if let Some(injector) = self.injector.as_mut() {
+            needs_interrupt |= Self::send_source_events(&mut self.event_queue, injector);
+        }
+        needs_interrupt
+    }
+
+    // Send events from an event source to the guest
+    fn send_source_events<S: EventSource>(event_queue: &mut Queue, event_source: &mut S) -> bool {
         let mut needs_interrupt = false;
 
         // Only consume from the queue iterator if we know we have events to send
-        while self.event_source.available_events_count() > 0 {
-            match self.event_queue.pop() {
+        while event_source.available_events_count() > 0 {
+            match event_queue.pop() {
                 None => {
                     break;
                 }
                 Some(mut avail_desc) => {
                     let bytes_written =
-                        match Worker::fill_event_virtqueue(&mut self.event_source, &mut avail_desc)
-                        {
+                        match Self::fill_event_virtqueue(event_source, &mut avail_desc) {
                             Ok(count) => count,
                             Err(e) => {
                                 error!("Input: failed to send events to guest: {}", e);
@@ -389,7 +402,7 @@ impl<T: EventSource> Worker<T> {
                             }
                         };
 
-                    self.event_queue.add_used(avail_desc, bytes_written as u32);
+                    event_queue.add_used(avail_desc, bytes_written as u32);
                     needs_interrupt = true;
                 }
             }
@@ -412,6 +425,27 @@ impl<T: EventSource> Worker<T> {
         Ok(reader.bytes_read())
     }
 
+    // Stops polling `event_source` while its queue is full, and polls it again once the guest has
+    // consumed some of its events.
+    fn update_polling<S: EventSource, K: EventToken>(
+        wait_ctx: &WaitContext<K>,
+        event_source: &S,
+        token: K,
+        polled: &mut bool,
+    ) -> base::Result<()> {
+        let full = event_source.is_full();
+        if *polled == full {
+            let event_type = if full {
+                EventType::None
+            } else {
+                EventType::Read
+            };
+            wait_ctx.modify(event_source, event_type, token)?;
+            *polled = !full;
+        }
+        Ok(())
+    }
+
     fn process_status_queue(&mut self) -> Result<bool> {
         let mut needs_interrupt = false;
         while let Some(mut avail_desc) = self.status_queue.pop() {
@@ -444,6 +478,7 @@ impl<T: EventSource> Worker<T> {
             EventQAvailable,
             StatusQAvailable,
             InputEventsAvailable,
+            InjectedEventsAvailable,
             InterruptResample,
             Kill,
         }
@@ -459,6 +494,15 @@ impl<T: EventSource> Worker<T> {
                 return;
             }
         };
+        if let Some(injector) = &self.injector {
+            if wait_ctx
+                .add(injector, Token::InjectedEventsAvailable)
+                .is_err()
+            {
+                error!("failed adding injected events to WaitContext.");
+                return;
+            }
+        }
         if let Some(resample_evt) = self.interrupt.get_resample_evt() {
             if wait_ctx
                 .add(resample_evt, Token::InterruptResample)
@@ -469,6 +513,8 @@ impl<T: EventSource> Worker<T> {
             }
         }
 
+        let mut source_polled = true;
+        let mut injector_polled = true;
         'wait: loop {
             let wait_events = match wait_ctx.wait() {
                 Ok(wait_events) => wait_events,
@@ -503,6 +549,14 @@ impl<T: EventSource> Worker<T> {
                         Err(e) => error!("error receiving events: {}", e),
                         Ok(_cnt) => eventq_needs_interrupt |= self.send_events(),
                     },
+                    Token::InjectedEventsAvailable => {
+                        if let Some(injector) = self.injector.as_mut() {
+                            match injector.receive_events() {
+                                Err(e) => error!("error receiving injected events: {}", e),
+                                Ok(_cnt) => eventq_needs_interrupt |= self.send_events(),
+                            }
+                        }
+                    }
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
@@ -518,6 +572,27 @@ impl<T: EventSource> Worker<T> {
             if statusq_needs_interrupt {
                 self.status_queue.trigger_interrupt(&self.interrupt);
             }
+
+            if let Err(e) = Self::update_polling(
+                &wait_ctx,
+                &self.event_source,
+                Token::InputEventsAvailable,
+                &mut source_polled,
+            ) {
+                error!("failed updating input events polling: {}", e);
+                break;
+            }
+            if let Some(injector) = &self.injector {
+                if let Err(e) = Self::update_polling(
+                    &wait_ctx,
+                    injector,
+                    Token::InjectedEventsAvailable,
+                    &mut injector_polled,
+                ) {
+                    error!("failed updating injected events polling: {}", e);
+                    break;
+                }
+            }
         }
 
         if let Err(e) = self.event_source.finalize() {
@@ -533,9 +608,24 @@ pub struct Input<T: EventSource + Send + 'static> {
     worker_thread: Option<WorkerThread<Worker<T>>>,
     config: VirtioInputConfig,
     source: Option<T>,
+    injector: Option<SocketEventSource<StreamChannel>>,
     virtio_features: u64,
 }
 
+impl<T> Input<T>
+where
+    T: EventSource + Send + 'static,
+{
+    /// Sets the channel through which the main process injects synthetic `virtio_input_event`s,
+    /// which are sent to the guest along with the events of the source.
+    pub fn set_event_injector(&mut self, channel: StreamChannel) {
+        self.injector = Some(SocketEventSource::with_capacity(
+            channel,
+            MAX_INJECTED_MESSAGE_EVENTS,
+        ));
+    }
+}
+
 /// Snapshot of [Input]'s state.
 #[derive(Serialize, Deserialize)]
 struct InputSnapshot {
@@ -548,10 +638,14 @@ where
     T: 'static + EventSource + Send,
 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
         if let Some(source) = &self.source {
-            return vec![source.as_raw_descriptor()];
+            keep_rds.push(source.as_raw_descriptor());
+        }
+        if let Some(injector) = &self.injector {
+            keep_rds.push(injector.as_raw_descriptor());
         }
-        Vec::new()
+        keep_rds
     }
 
     fn device_type(&self) -> DeviceType {
@@ -590,10 +684,12 @@ where
             .source
             .take()
             .context("tried to activate device without a source for events")?;
+        let injector = self.injector.take();
         self.worker_thread = Some(WorkerThread::start("v_input", move |kill_evt| {
             let mut worker = Worker {
                 interrupt,
                 event_source: source,
+                injector,
                 event_queue,
                 status_queue,
             };
@@ -608,6 +704,7 @@ where
         if let Some(worker_thread) = self.worker_thread.take() {
             let worker = worker_thread.stop();
             self.source = Some(worker.event_source);
+            self.injector = worker.injector;
             return true;
         }
         false
@@ -617,6 +714,7 @@ where
         if let Some(worker_thread) = self.worker_thread.take() {
             let worker = worker_thread.stop();
             self.source = Some(worker.event_source);
+            self.injector = worker.injector;
             let queues = BTreeMap::from([(0, worker.event_queue), (1, worker.status_queue)]);
             Ok(Some(queues))
         } else {
@@ -665,6 +763,7 @@ where
         worker_thread: None,
         config: VirtioInputConfig::from_evdev(&source)?,
         source: Some(EvdevEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
@@ -685,6 +784,7 @@ where
         worker_thread: None,
         config: defaults::new_single_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
@@ -705,6 +805,7 @@ where
         worker_thread: None,
         config: defaults::new_multi_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
@@ -726,6 +827,52 @@ where
         worker_thread: None,
         config: defaults::new_trackpad_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
+        injector: None,
+        virtio_features,
+    })
+}
+
+/// Creates a new virtio tablet device which reports absolute X and Y positions along with primary,
+/// secondary, middle button and wheel events.
+pub fn new_tablet<T>(
+    idx: u32,
+    source: T,
+    width: u32,
+    height: u32,
+    name: Option<&str>,
+    virtio_features: u64,
+) -> Result<Input<SocketEventSource<T>>>
+where
+    T: Read + Write + AsRawDescriptor + Send + 'static,
+{
+    Ok(Input {
+        worker_thread: None,
+        config: defaults::new_tablet_config(idx, width, height, name),
+        source: Some(SocketEventSource::new(source)),
+        injector: None,
+        virtio_features,
+    })
+}
+
+/// Creates a new virtio gamepad device with two sticks, two analog triggers, a directional pad and
+/// the buttons of a standard gamepad.
+pub fn new_gamepad<T>(
+    idx: u32,
+    source: T,
+    axis_min: i32,
+    axis_max: i32,
+    trigger_max: i32,
+    name: Option<&str>,
+    virtio_features: u64,
+) -> Result<Input<SocketEventSource<T>>>
+where
+    T: Read + Write + AsRawDescriptor + Send + 'static,
+{
+    Ok(Input {
+        worker_thread: None,
+        config: defaults::new_gamepad_config(idx, axis_min, axis_max, trigger_max, name),
+        source: Some(SocketEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
@@ -743,6 +890,7 @@ where
         worker_thread: None,
         config: defaults::new_mouse_config(idx),
         source: Some(SocketEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
@@ -760,6 +908,7 @@ where
         worker_thread: None,
         config: defaults::new_keyboard_config(idx),
         source: Some(SocketEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
@@ -777,6 +926,7 @@ where
         worker_thread: None,
         config: defaults::new_switches_config(idx),
         source: Some(SocketEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
@@ -794,6 +944,7 @@ where
         worker_thread: None,
         config: defaults::new_rotary_config(idx),
         source: Some(SocketEventSource::new(source)),
+        injector: None,
         virtio_features,
     })
 }
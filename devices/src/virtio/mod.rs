@@ -20,6 +20,7 @@ pub mod net;
 pub mod pvclock;
 mod queue;
 mod rng;
+mod token_bucket;
 #[cfg(feature = "vtpm")]
 mod tpm;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
@@ -68,6 +69,8 @@ pub use self::gpu::GpuMode;
 pub use self::gpu::GpuMouseMode;
 #[cfg(feature = "gpu")]
 pub use self::gpu::GpuParameters;
+#[cfg(all(any(target_os = "android", target_os = "linux"), feature = "gpu"))]
+pub use self::gpu::GpuVncAddress;
 #[cfg(feature = "gpu")]
 pub use self::gpu::GpuWsi;
 pub use self::interrupt::Interrupt;
@@ -2,16 +2,29 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod filter;
+mod pcap;
+mod rate_limit;
+mod rss;
 mod sys;
 
 use std::collections::BTreeMap;
 use std::fmt;
+use std::fs::File;
 use std::io;
+use std::io::Read;
 use std::io::Write;
 use std::net::Ipv4Addr;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::SocketAddrV4;
 use std::os::raw::c_uint;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::anyhow;
 use anyhow::Context;
@@ -19,30 +32,54 @@ use base::error;
 #[cfg(windows)]
 use base::named_pipes::OverlappedWrapper;
 use base::warn;
+use base::AsRawDescriptor;
 use base::Error as SysError;
 use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
 use base::ReadNotifier;
+use base::Timer;
+use base::TimerTrait;
+use base::Tube;
 use base::WaitContext;
 use base::WorkerThread;
 use data_model::Le16;
+use data_model::Le32;
 use data_model::Le64;
+#[cfg(feature = "slirp")]
+use net_util::slirp::HostFwd;
 use net_util::Error as TapError;
 use net_util::MacAddress;
 use net_util::TapT;
 use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_net;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_ANNOUNCE;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_ANNOUNCE_ACK;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_GUEST_OFFLOADS;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC_ADDR_SET;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC_TABLE_SET;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ_HASH_CONFIG;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ_RSS_CONFIG;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX_ALLMULTI;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX_PROMISC;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN_ADD;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN_DEL;
 use virtio_sys::virtio_net::VIRTIO_NET_ERR;
 use virtio_sys::virtio_net::VIRTIO_NET_OK;
+use vm_control::NetControlCommand;
+use vm_control::NetControlResult;
+use vm_control::NetRateLimitConfig;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -59,13 +96,35 @@ use crate::PciAddress;
 /// The maximum buffer size when segmentation offload is enabled. This
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
-#[cfg(windows)]
 pub(crate) const MAX_BUFFER_SIZE: usize = 65562;
 const QUEUE_SIZE: u16 = 256;
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
 pub static VHOST_NET_DEFAULT_PATH: &str = "/dev/vhost-net";
 
+/// Features implemented by the control queue of the userspace device: filtering of the received
+/// frames, and announcement of the guest after a restore (which relies on the status field of the
+/// config space).
+pub(crate) const CTRL_FEATURES: u64 = 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ
+    | 1 << virtio_net::VIRTIO_NET_F_CTRL_RX
+    | 1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN
+    | 1 << virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_ANNOUNCE
+    | 1 << virtio_net::VIRTIO_NET_F_STATUS;
+
+pub(crate) use filter::FilteringTap;
+pub(crate) use filter::RxFilter;
+pub(crate) use pcap::CapturingTap;
+pub(crate) use pcap::Direction;
+pub(crate) use pcap::PcapWriter;
+pub(crate) use pcap::SharedCapture;
+pub(crate) use rate_limit::RateLimiter;
+pub(crate) use rate_limit::SharedRateLimiter;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use rss::process_tx_hash_report;
+pub(crate) use rss::RssConfig;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use rss::SoftwareRss;
 pub(crate) use sys::process_rx;
 pub(crate) use sys::process_tx;
 pub(crate) use sys::validate_and_configure_tap;
@@ -80,6 +139,9 @@ pub enum NetError {
     /// Creating kill event failed.
     #[error("failed to create kill event: {0}")]
     CreateKillEvent(SysError),
+    /// Creating the timer resuming the frames held back by the rate limits failed.
+    #[error("failed to create rate limit timer: {0}")]
+    CreateRateLimitTimer(SysError),
     /// Creating WaitContext failed.
     #[error("failed to create wait context: {0}")]
     CreateWaitContext(SysError),
@@ -92,16 +154,39 @@ pub enum NetError {
     /// Invalid control command
     #[error("invalid control command")]
     InvalidCmd,
+    /// Starting the frame capture failed.
+    #[error("failed to start frame capture: {0}")]
+    PcapStart(io::Error),
+    /// Arming the timer resuming the frames held back by the rate limits failed.
+    #[error("failed to arm rate limit timer: {0}")]
+    RateLimitTimer(SysError),
     /// Error reading data from control queue.
     #[error("failed to read control message data: {0}")]
     ReadCtrlData(io::Error),
     /// Error reading header from control queue.
     #[error("failed to read control message header: {0}")]
     ReadCtrlHeader(io::Error),
+    /// Reading a frame from the tap failed.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[error("failed to read frame from tap: {0}")]
+    ReadTap(io::Error),
+    /// Receiving a command from the control tube failed.
+    #[error("failed to receive command message: {0}")]
+    ReceivingCommand(base::TubeError),
     /// There are no more available descriptors to receive into.
     #[cfg(any(target_os = "android", target_os = "linux"))]
     #[error("no rx descriptors available")]
     RxDescriptorsExhausted,
+    /// The rx rate limit was reached, and no frame can be received before the given time.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[error("rx rate limit reached for {0:?}")]
+    RxRateLimited(Duration),
+    /// The frames received from Slirp can't be rate limited.
+    #[error("rx rate limits are not supported with Slirp")]
+    RxRateLimitUnsupported,
+    /// Sending a response to the control tube failed.
+    #[error("failed to send response message: {0}")]
+    SendingResponse(base::TubeError),
     /// Failure creating the Slirp loop.
     #[cfg(windows)]
     #[error("error creating Slirp: {0}")]
@@ -170,6 +255,49 @@ pub enum NetParametersMode {
         netmask: Ipv4Addr,
         mac: MacAddress,
     },
+    /// Frames are exchanged with another VM or a user-mode switch over a connected unix
+    /// `SOCK_SEQPACKET` socket, one frame per packet.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(rename_all = "kebab-case")]
+    UnixSeqpacket {
+        unix_seqpacket: PathBuf,
+        mac: Option<MacAddress>,
+    },
+    /// Frames are exchanged with a user-mode switch such as passt or QEMU's `-netdev stream` over
+    /// a connected unix `SOCK_STREAM` socket, each frame preceded by its 32-bit big-endian length.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(rename_all = "kebab-case")]
+    UnixStream {
+        unix_stream: PathBuf,
+        mac: Option<MacAddress>,
+    },
+    /// Frames are exchanged as unix datagrams, sent to `dgram_remote` and received on
+    /// `dgram_local`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(rename_all = "kebab-case")]
+    UnixDgram {
+        dgram_local: PathBuf,
+        dgram_remote: PathBuf,
+        mac: Option<MacAddress>,
+    },
+    /// Frames are exchanged as UDP datagrams on a multicast group, which acts as a hub connecting
+    /// all its members.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[serde(rename_all = "kebab-case")]
+    Mcast {
+        mcast: SocketAddrV4,
+        mac: Option<MacAddress>,
+    },
+    /// User-mode networking provided by libslirp, which does not require any privilege on the
+    /// host.
+    #[cfg(feature = "slirp")]
+    #[serde(rename_all = "kebab-case")]
+    Slirp {
+        slirp: bool,
+        #[serde(default)]
+        host_fwd: Vec<HostFwd>,
+        mac: Option<MacAddress>,
+    },
 }
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -207,6 +335,15 @@ pub struct NetParameters {
     #[serde(default)]
     pub packed_queue: bool,
     pub pci_address: Option<PciAddress>,
+    /// Path of a pcapng file to capture the frames of the device to.
+    pub pcap: Option<PathBuf>,
+    /// Steer the received frames to the queue pairs in software, following the driver's receive
+    /// side scaling configuration, instead of letting a multiqueue tap spread them.
+    #[serde(default)]
+    pub rss: bool,
+    /// Limits of the frames received and transmitted by the guest.
+    #[serde(default)]
+    pub rate_limit: NetRateLimitConfig,
 }
 
 impl FromStr for NetParameters {
@@ -230,6 +367,136 @@ pub struct VirtioNetConfig {
     status: Le16,
     max_vq_pairs: Le16,
     mtu: Le16,
+    speed: Le32,
+    duplex: u8,
+    rss_max_key_size: u8,
+    rss_max_indirection_table_length: Le16,
+    supported_hash_types: Le32,
+}
+
+/// State configured through the control queue, shared by the workers of the device.
+pub struct CtrlState {
+    /// Replaced rather than modified while the workers hold on to it, so that they don't block the
+    /// control queue for the duration of a receive pass.
+    pub(crate) rx_filter: Mutex<Arc<RxFilter>>,
+    pub(crate) rss: Mutex<RssConfig>,
+    /// Set while the guest has been asked to announce itself and has not acknowledged it yet.
+    announce: AtomicBool,
+}
+
+impl CtrlState {
+    fn new(mac: Option<[u8; 6]>) -> Self {
+        CtrlState {
+            rx_filter: Mutex::new(Arc::new(RxFilter::new(mac))),
+            rss: Mutex::new(RssConfig::default()),
+            announce: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the receive filter, or `None` while it accepts every frame.
+    pub(crate) fn active_rx_filter(&self) -> Option<Arc<RxFilter>> {
+        let rx_filter = self.rx_filter.lock();
+        if rx_filter.accepts_all() {
+            None
+        } else {
+            Some(rx_filter.clone())
+        }
+    }
+}
+
+// Returns the control queue state if `feature`, which the command of `ctrl_hdr` depends on, was
+// negotiated.
+fn ctrl_state_for<'a>(
+    ctrl_state: Option<&'a CtrlState>,
+    ctrl_hdr: &virtio_net_ctrl_hdr,
+    acked_features: u64,
+    feature: u32,
+) -> Result<&'a CtrlState, NetError> {
+    match ctrl_state {
+        Some(ctrl_state) if acked_features & 1 << feature != 0 => Ok(ctrl_state),
+        _ => {
+            error!(
+                "net: control command {}:{} used without its feature",
+                ctrl_hdr.class, ctrl_hdr.cmd
+            );
+            Err(NetError::InvalidCmd)
+        }
+    }
+}
+
+// Reads a table of MAC addresses of the `VIRTIO_NET_CTRL_MAC_TABLE_SET` command.
+fn read_mac_table(reader: &mut Reader) -> Result<Vec<[u8; 6]>, NetError> {
+    let entries: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let entries = entries.to_native() as usize;
+    if entries.saturating_mul(6) > reader.available_bytes() {
+        error!("net: MAC table with {} entries is truncated", entries);
+        return Err(NetError::InvalidCmd);
+    }
+    (0..entries)
+        .map(|_| reader.read_obj().map_err(NetError::ReadCtrlData))
+        .collect()
+}
+
+// Reads the key that ends the `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` and
+// `VIRTIO_NET_CTRL_MQ_HASH_CONFIG` commands.
+fn read_hash_key(reader: &mut Reader) -> Result<Vec<u8>, NetError> {
+    let len: u8 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    if len > rss::RSS_MAX_KEY_SIZE {
+        error!("net: hash key of {} bytes is too long", len);
+        return Err(NetError::InvalidCmd);
+    }
+    let mut key = vec![0u8; len.into()];
+    reader
+        .read_exact(&mut key)
+        .map_err(NetError::ReadCtrlData)?;
+    Ok(key)
+}
+
+// Reads a `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` command and applies it to `rss`.
+fn set_rss_config(
+    reader: &mut Reader,
+    rss: &Mutex<RssConfig>,
+    vq_pairs: u16,
+) -> Result<(), NetError> {
+    let hash_types: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let indirection_table_mask: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let unclassified_queue: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let table_len = usize::from(indirection_table_mask.to_native()) + 1;
+    if !table_len.is_power_of_two() || table_len > rss::RSS_MAX_INDIRECTION_TABLE_LEN.into() {
+        error!("net: invalid RSS indirection table length: {}", table_len);
+        return Err(NetError::InvalidCmd);
+    }
+    let indirection_table = (0..table_len)
+        .map(|_| {
+            reader
+                .read_obj::<Le16>()
+                .map(|queue| queue.to_native())
+                .map_err(NetError::ReadCtrlData)
+        })
+        .collect::<Result<Vec<u16>, NetError>>()?;
+    let max_tx_vq: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let key = read_hash_key(reader)?;
+
+    let unclassified_queue = unclassified_queue.to_native();
+    let max_tx_vq = max_tx_vq.to_native();
+    if max_tx_vq == 0
+        || max_tx_vq > vq_pairs
+        || unclassified_queue >= vq_pairs
+        || indirection_table.iter().any(|&queue| queue >= vq_pairs)
+    {
+        error!(
+            "net: RSS configuration uses more queues than the {} pairs",
+            vq_pairs
+        );
+        return Err(NetError::InvalidCmd);
+    }
+    rss.lock().set_steering(
+        hash_types.to_native(),
+        key,
+        indirection_table,
+        unclassified_queue,
+    );
+    Ok(())
 }
 
 fn process_ctrl_request<T: TapT>(
@@ -237,10 +504,94 @@ fn process_ctrl_request<T: TapT>(
     tap: &mut T,
     acked_features: u64,
     vq_pairs: u16,
+    ctrl_state: Option<&CtrlState>,
 ) -> Result<(), NetError> {
     let ctrl_hdr: virtio_net_ctrl_hdr = reader.read_obj().map_err(NetError::ReadCtrlHeader)?;
 
     match ctrl_hdr.class as c_uint {
+        VIRTIO_NET_CTRL_RX => {
+            let ctrl_state = ctrl_state_for(
+                ctrl_state,
+                &ctrl_hdr,
+                acked_features,
+                virtio_net::VIRTIO_NET_F_CTRL_RX,
+            )?;
+            let on: u8 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            let mut rx_filter = ctrl_state.rx_filter.lock();
+            let rx_filter = Arc::make_mut(&mut rx_filter);
+            match ctrl_hdr.cmd as c_uint {
+                VIRTIO_NET_CTRL_RX_PROMISC => rx_filter.set_promisc(on != 0),
+                VIRTIO_NET_CTRL_RX_ALLMULTI => rx_filter.set_allmulti(on != 0),
+                _ => {
+                    error!("invalid cmd for VIRTIO_NET_CTRL_RX: {}", ctrl_hdr.cmd);
+                    return Err(NetError::InvalidCmd);
+                }
+            }
+        }
+        VIRTIO_NET_CTRL_MAC => match ctrl_hdr.cmd as c_uint {
+            VIRTIO_NET_CTRL_MAC_TABLE_SET => {
+                let ctrl_state = ctrl_state_for(
+                    ctrl_state,
+                    &ctrl_hdr,
+                    acked_features,
+                    virtio_net::VIRTIO_NET_F_CTRL_RX,
+                )?;
+                let uni_macs = read_mac_table(reader)?;
+                let multi_macs = read_mac_table(reader)?;
+                Arc::make_mut(&mut ctrl_state.rx_filter.lock()).set_mac_table(uni_macs, multi_macs);
+            }
+            VIRTIO_NET_CTRL_MAC_ADDR_SET => {
+                let ctrl_state = ctrl_state_for(
+                    ctrl_state,
+                    &ctrl_hdr,
+                    acked_features,
+                    virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR,
+                )?;
+                let mac: [u8; 6] = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+                Arc::make_mut(&mut ctrl_state.rx_filter.lock()).set_mac(mac);
+            }
+            _ => {
+                error!("invalid cmd for VIRTIO_NET_CTRL_MAC: {}", ctrl_hdr.cmd);
+                return Err(NetError::InvalidCmd);
+            }
+        },
+        VIRTIO_NET_CTRL_VLAN => {
+            let ctrl_state = ctrl_state_for(
+                ctrl_state,
+                &ctrl_hdr,
+                acked_features,
+                virtio_net::VIRTIO_NET_F_CTRL_VLAN,
+            )?;
+            let vid: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            let vid = vid.to_native();
+            if vid >= filter::MAX_VLAN {
+                error!("invalid VLAN ID: {}", vid);
+                return Err(NetError::InvalidCmd);
+            }
+            let mut rx_filter = ctrl_state.rx_filter.lock();
+            let rx_filter = Arc::make_mut(&mut rx_filter);
+            match ctrl_hdr.cmd as c_uint {
+                VIRTIO_NET_CTRL_VLAN_ADD => rx_filter.set_vlan(vid, true),
+                VIRTIO_NET_CTRL_VLAN_DEL => rx_filter.set_vlan(vid, false),
+                _ => {
+                    error!("invalid cmd for VIRTIO_NET_CTRL_VLAN: {}", ctrl_hdr.cmd);
+                    return Err(NetError::InvalidCmd);
+                }
+            }
+        }
+        VIRTIO_NET_CTRL_ANNOUNCE => {
+            let ctrl_state = ctrl_state_for(
+                ctrl_state,
+                &ctrl_hdr,
+                acked_features,
+                virtio_net::VIRTIO_NET_F_GUEST_ANNOUNCE,
+            )?;
+            if ctrl_hdr.cmd != VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8 {
+                error!("invalid cmd for VIRTIO_NET_CTRL_ANNOUNCE: {}", ctrl_hdr.cmd);
+                return Err(NetError::InvalidCmd);
+            }
+            ctrl_state.announce.store(false, Ordering::SeqCst);
+        }
         VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
             if ctrl_hdr.cmd != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET as u8 {
                 error!(
@@ -254,8 +605,8 @@ fn process_ctrl_request<T: TapT>(
             tap.set_offload(tap_offloads)
                 .map_err(NetError::TapSetOffload)?;
         }
-        VIRTIO_NET_CTRL_MQ => {
-            if ctrl_hdr.cmd == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8 {
+        VIRTIO_NET_CTRL_MQ => match ctrl_hdr.cmd as c_uint {
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET => {
                 let pairs: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
                 // Simple handle it now
                 if acked_features & 1 << virtio_net::VIRTIO_NET_F_MQ == 0
@@ -269,10 +620,38 @@ fn process_ctrl_request<T: TapT>(
                     return Err(NetError::InvalidCmd);
                 }
             }
-        }
+            VIRTIO_NET_CTRL_MQ_RSS_CONFIG => {
+                let ctrl_state = ctrl_state_for(
+                    ctrl_state,
+                    &ctrl_hdr,
+                    acked_features,
+                    virtio_net::VIRTIO_NET_F_RSS,
+                )?;
+                set_rss_config(reader, &ctrl_state.rss, vq_pairs)?;
+            }
+            VIRTIO_NET_CTRL_MQ_HASH_CONFIG => {
+                let ctrl_state = ctrl_state_for(
+                    ctrl_state,
+                    &ctrl_hdr,
+                    acked_features,
+                    virtio_net::VIRTIO_NET_F_HASH_REPORT,
+                )?;
+                let hash_types: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+                let _reserved: [u8; 8] = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+                let key = read_hash_key(reader)?;
+                ctrl_state
+                    .rss
+                    .lock()
+                    .set_hashing(hash_types.to_native(), key);
+            }
+            _ => {
+                error!("invalid cmd for VIRTIO_NET_CTRL_MQ: {}", ctrl_hdr.cmd);
+                return Err(NetError::InvalidCmd);
+            }
+        },
         _ => {
             warn!(
-                "unimplemented class for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
+                "unimplemented class for virtio-net control queue: {}",
                 ctrl_hdr.class
             );
             return Err(NetError::InvalidCmd);
@@ -282,16 +661,26 @@ fn process_ctrl_request<T: TapT>(
     Ok(())
 }
 
+/// Processes the requests of the control queue.
+///
+/// `ctrl_state` is required for the commands configuring the receive filter and acknowledging
+/// announcements, which are rejected without it.
 pub fn process_ctrl<T: TapT>(
     interrupt: &Interrupt,
     ctrl_queue: &mut Queue,
     tap: &mut T,
     acked_features: u64,
     vq_pairs: u16,
+    ctrl_state: Option<&CtrlState>,
 ) -> Result<(), NetError> {
     while let Some(mut desc_chain) = ctrl_queue.pop() {
-        if let Err(e) = process_ctrl_request(&mut desc_chain.reader, tap, acked_features, vq_pairs)
-        {
+        if let Err(e) = process_ctrl_request(
+            &mut desc_chain.reader,
+            tap,
+            acked_features,
+            vq_pairs,
+            ctrl_state,
+        ) {
             error!("process_ctrl_request failed: {}", e);
             desc_chain
                 .writer
@@ -319,10 +708,18 @@ pub enum Token {
     RxQueue,
     // The transmit queue has a frame that is ready to send from the guest.
     TxQueue,
+    // Same as RxQueue and TxQueue, for the pairs after the first one when a single worker
+    // services every pair.
+    PairRxQueue { index: usize },
+    PairTxQueue { index: usize },
     // The control queue has a message.
     CtrlQueue,
+    // A command was received on the control tube.
+    ControlTube,
     // Check if any interrupts need to be re-asserted.
     InterruptResample,
+    // The frames held back by the rate limits can go through again.
+    RateLimitTimer,
     // crosvm has requested the device to shut down.
     Kill,
 }
@@ -332,6 +729,12 @@ pub(super) struct Worker<T: TapT> {
     pub(super) rx_queue: Queue,
     pub(super) tx_queue: Queue,
     pub(super) ctrl_queue: Option<Queue>,
+    // Receive and transmit queues of the pairs after the first one, when the frames are steered
+    // to the receive queues in software.
+    pub(super) other_pairs: Vec<(Queue, Queue)>,
+    // Receive path hashing the frames, if the driver negotiated RSS or hash reporting.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub(super) software_rss: Option<SoftwareRss>,
     pub(super) tap: T,
     #[cfg(windows)]
     pub(super) overlapped_wrapper: OverlappedWrapper,
@@ -343,6 +746,17 @@ pub(super) struct Worker<T: TapT> {
     pub(super) deferred_rx: bool,
     acked_features: u64,
     vq_pairs: u16,
+    // Frame capture shared by the workers of the device, if one is in progress.
+    pub(super) capture: SharedCapture,
+    // Rate limits shared by the workers of the device, if any is configured.
+    pub(super) rate_limiter: SharedRateLimiter,
+    // Timer resuming the frames held back by the rate limits, created the first time they are
+    // reached, and when it expires if armed.
+    rate_limit_timer: Option<Timer>,
+    rate_limit_deadline: Option<Instant>,
+    pub(super) ctrl_state: Arc<CtrlState>,
+    // Tube receiving `NetControlCommand`s, only handled by the first worker.
+    control_tube: Option<Tube>,
     #[allow(dead_code)]
     kill_evt: Event,
 }
@@ -351,8 +765,106 @@ impl<T> Worker<T>
 where
     T: TapT + ReadNotifier,
 {
-    fn process_tx(&mut self) {
-        process_tx(&self.interrupt, &mut self.tx_queue, &mut self.tap)
+    // Returns how long to wait before transmitting the remaining frames of the pair if the rate
+    // limits were reached.
+    fn process_tx(&mut self, pair: usize) -> Option<Duration> {
+        let tx_queue = match pair {
+            0 => &mut self.tx_queue,
+            _ => &mut self.other_pairs[pair - 1].1,
+        };
+        let mut capture = self.capture.lock();
+        let mut rate_limiter = self.rate_limiter.lock();
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if self.acked_features & 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT != 0 {
+            return process_tx_hash_report(
+                &self.interrupt,
+                tx_queue,
+                &mut self.tap,
+                capture.writer(),
+                rate_limiter.limiter(),
+            );
+        }
+        process_tx(
+            &self.interrupt,
+            tx_queue,
+            &mut self.tap,
+            capture.writer(),
+            rate_limiter.limiter(),
+        )
+    }
+
+    fn handle_tx(&mut self, wait_ctx: &WaitContext<Token>, pair: usize) -> Result<(), NetError> {
+        match self.process_tx(pair) {
+            Some(wait) => self.arm_rate_limit_timer(wait_ctx, wait),
+            None => Ok(()),
+        }
+    }
+
+    // Makes sure that the rate limit timer expires within `wait`.
+    pub(super) fn arm_rate_limit_timer(
+        &mut self,
+        wait_ctx: &WaitContext<Token>,
+        wait: Duration,
+    ) -> Result<(), NetError> {
+        let deadline = Instant::now() + wait;
+        // An earlier expiration is kept, the frames still held back then arm the timer again.
+        if !matches!(self.rate_limit_deadline, Some(armed) if armed <= deadline) {
+            let timer = match &mut self.rate_limit_timer {
+                Some(timer) => timer,
+                None => {
+                    let timer = Timer::new().map_err(NetError::CreateRateLimitTimer)?;
+                    wait_ctx
+                        .add(&timer, Token::RateLimitTimer)
+                        .map_err(NetError::RateLimitTimer)?;
+                    self.rate_limit_timer.insert(timer)
+                }
+            };
+            timer.reset(wait, None).map_err(NetError::RateLimitTimer)?;
+            self.rate_limit_deadline = Some(deadline);
+        }
+        Ok(())
+    }
+
+    fn handle_control_command(&mut self) -> Result<(), NetError> {
+        let tube = match &self.control_tube {
+            Some(tube) => tube,
+            None => return Ok(()),
+        };
+        let command = tube
+            .recv::<NetControlCommand>()
+            .map_err(NetError::ReceivingCommand)?;
+        let result = match command {
+            NetControlCommand::StartCapture { file } => match PcapWriter::new(file) {
+                Ok(writer) => {
+                    self.capture.start(writer);
+                    NetControlResult::Ok
+                }
+                Err(e) => {
+                    error!("net: failed to start capture: {}", e);
+                    NetControlResult::Err(SysError::from(e))
+                }
+            },
+            NetControlCommand::StopCapture => {
+                self.capture.stop();
+                NetControlResult::Ok
+            }
+            NetControlCommand::SetRateLimit(config) => match check_rate_limit(&config) {
+                Ok(()) => {
+                    self.rate_limiter.set(config);
+                    NetControlResult::Ok
+                }
+                Err(e) => {
+                    error!("net: failed to set rate limits: {}", e);
+                    NetControlResult::Err(SysError::new(libc::EINVAL))
+                }
+            },
+            // Tap hotplug is handled by the VMM itself.
+            #[cfg(feature = "pci-hotplug")]
+            NetControlCommand::AddTap(_) | NetControlCommand::RemoveTap(_) => {
+                NetControlResult::Err(SysError::new(libc::EINVAL))
+            }
+        };
+        tube.send(&result).map_err(NetError::SendingResponse)
     }
 
     fn process_ctrl(&mut self) -> Result<(), NetError> {
@@ -367,6 +879,7 @@ where
             &mut self.tap,
             self.acked_features,
             self.vq_pairs,
+            Some(&self.ctrl_state),
         )
     }
 
@@ -395,6 +908,21 @@ where
                 .map_err(NetError::CreateWaitContext)?;
         }
 
+        for (index, (rx_queue, tx_queue)) in self.other_pairs.iter().enumerate() {
+            wait_ctx
+                .add_many(&[
+                    (rx_queue.event(), Token::PairRxQueue { index }),
+                    (tx_queue.event(), Token::PairTxQueue { index }),
+                ])
+                .map_err(NetError::CreateWaitContext)?;
+        }
+
+        if let Some(control_tube) = &self.control_tube {
+            wait_ctx
+                .add(control_tube.get_read_notifier(), Token::ControlTube)
+                .map_err(NetError::CreateWaitContext)?;
+        }
+
         if handle_interrupt_resample {
             if let Some(resample_evt) = self.interrupt.get_resample_evt() {
                 wait_ctx
@@ -428,7 +956,24 @@ where
                             error!("net: error reading tx queue Event: {}", e);
                             break 'wait;
                         }
-                        self.process_tx();
+                        self.handle_tx(&wait_ctx, 0)?;
+                    }
+                    Token::PairRxQueue { index } => {
+                        let _trace = cros_tracing::trace_event!(VirtioNet, "handle RxQueue event");
+                        if let Err(e) = self.other_pairs[index].0.event().wait() {
+                            error!("net: error reading rx queue Event: {}", e);
+                            break 'wait;
+                        }
+                        self.handle_rx_queue(&wait_ctx, tap_polling_enabled)?;
+                        tap_polling_enabled = true;
+                    }
+                    Token::PairTxQueue { index } => {
+                        let _trace = cros_tracing::trace_event!(VirtioNet, "handle TxQueue event");
+                        if let Err(e) = self.other_pairs[index].1.event().wait() {
+                            error!("net: error reading tx queue Event: {}", e);
+                            break 'wait;
+                        }
+                        self.handle_tx(&wait_ctx, index + 1)?;
                     }
                     Token::CtrlQueue => {
                         let _trace =
@@ -446,6 +991,16 @@ where
                             break 'wait;
                         }
                     }
+                    Token::ControlTube => {
+                        let _trace =
+                            cros_tracing::trace_event!(VirtioNet, "handle ControlTube event");
+                        if let Err(e) = self.handle_control_command() {
+                            error!("net: failed to handle control command: {}", e);
+                            if let Some(control_tube) = self.control_tube.take() {
+                                let _ = wait_ctx.delete(control_tube.get_read_notifier());
+                            }
+                        }
+                    }
                     Token::InterruptResample => {
                         let _trace =
                             cros_tracing::trace_event!(VirtioNet, "handle InterruptResample event");
@@ -453,6 +1008,22 @@ where
                         let _ = self.interrupt.get_resample_evt().unwrap().wait();
                         self.interrupt.do_interrupt_resample();
                     }
+                    Token::RateLimitTimer => {
+                        let _trace =
+                            cros_tracing::trace_event!(VirtioNet, "handle RateLimitTimer event");
+                        if let Some(Err(e)) = self.rate_limit_timer.as_mut().map(Timer::mark_waited)
+                        {
+                            error!("net: error reading rate limit timer: {}", e);
+                            break 'wait;
+                        }
+                        self.rate_limit_deadline = None;
+                        for pair in 0..=self.other_pairs.len() {
+                            self.handle_tx(&wait_ctx, pair)?;
+                        }
+                        // Polling the tap was stopped when the rx rate limit was reached.
+                        self.handle_rx_queue(&wait_ctx, false)?;
+                        tap_polling_enabled = true;
+                    }
                     Token::Kill => {
                         let _ = self.kill_evt.wait();
                         break 'wait;
@@ -476,6 +1047,15 @@ pub fn build_config(vq_pairs: u16, mtu: u16, mac: Option<[u8; 6]>) -> VirtioNetC
     }
 }
 
+// Frames received from Slirp are not held back by the rate limits, so only transmit limits can be
+// set on Windows.
+fn check_rate_limit(config: &NetRateLimitConfig) -> Result<(), NetError> {
+    if cfg!(windows) && (config.rx_bps.is_some() || config.rx_pps.is_some()) {
+        return Err(NetError::RxRateLimitUnsupported);
+    }
+    Ok(())
+}
+
 pub struct Net<T: TapT + ReadNotifier + 'static> {
     guest_mac: Option<[u8; 6]>,
     queue_sizes: Box<[u16]>,
@@ -485,6 +1065,10 @@ pub struct Net<T: TapT + ReadNotifier + 'static> {
     acked_features: u64,
     mtu: u16,
     pci_address: Option<PciAddress>,
+    capture: SharedCapture,
+    rate_limiter: SharedRateLimiter,
+    control_tube: Option<Tube>,
+    ctrl_state: Arc<CtrlState>,
     #[cfg(windows)]
     slirp_kill_evt: Option<Event>,
 }
@@ -493,6 +1077,12 @@ pub struct Net<T: TapT + ReadNotifier + 'static> {
 struct NetSnapshot {
     avail_features: u64,
     acked_features: u64,
+    // Missing from snapshots taken before receive filtering was supported.
+    #[serde(default)]
+    rx_filter: Option<RxFilter>,
+    // Missing from snapshots taken before receive side scaling was supported.
+    #[serde(default)]
+    rss: RssConfig,
 }
 
 impl<T> Net<T>
@@ -501,6 +1091,10 @@ where
 {
     /// Creates a new virtio network device from a tap device that has already been
     /// configured.
+    ///
+    /// With `software_rss`, the tap has a single queue and the received frames are steered to the
+    /// `vq_pairs` receive queues by the device, which then offers `VIRTIO_NET_F_RSS` and
+    /// `VIRTIO_NET_F_HASH_REPORT`.
     pub fn new(
         base_features: u64,
         tap: T,
@@ -508,15 +1102,20 @@ where
         mac_addr: Option<MacAddress>,
         use_packed_queue: bool,
         pci_address: Option<PciAddress>,
+        software_rss: bool,
     ) -> Result<Net<T>, NetError> {
-        let taps = tap.into_mq_taps(vq_pairs).map_err(NetError::TapOpen)?;
+        let taps = if software_rss {
+            vec![tap]
+        } else {
+            tap.into_mq_taps(vq_pairs).map_err(NetError::TapOpen)?
+        };
 
         let mut mtu = u16::MAX;
         // This would also validate a tap created by Self::new(), but that's a good thing as it
         // would ensure that any changes in the creation procedure are matched in the validation.
         // Plus we still need to set the offload and vnet_hdr_size values.
         for tap in &taps {
-            validate_and_configure_tap(tap, vq_pairs)?;
+            validate_and_configure_tap(tap, taps.len() as u16)?;
             mtu = std::cmp::min(mtu, tap.mtu().map_err(NetError::TapGetMtu)?);
         }
 
@@ -527,9 +1126,9 @@ where
         // See the network device feature bits section for further details:
         //     http://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1970003
         let mut avail_features = base_features
+            | CTRL_FEATURES
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_CSUM
-            | 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ
             | 1 << virtio_net::VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
@@ -541,6 +1140,11 @@ where
             avail_features |= 1 << virtio_net::VIRTIO_NET_F_MQ;
         }
 
+        if software_rss {
+            avail_features |=
+                1 << virtio_net::VIRTIO_NET_F_RSS | 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT;
+        }
+
         if use_packed_queue {
             avail_features |= 1 << VIRTIO_F_RING_PACKED;
         }
@@ -551,6 +1155,7 @@ where
 
         Self::new_internal(
             taps,
+            vq_pairs,
             avail_features,
             mtu,
             mac_addr,
@@ -562,21 +1167,27 @@ where
 
     pub(crate) fn new_internal(
         taps: Vec<T>,
+        vq_pairs: u16,
         avail_features: u64,
         mtu: u16,
         mac_addr: Option<MacAddress>,
         pci_address: Option<PciAddress>,
         #[cfg(windows)] slirp_kill_evt: Option<Event>,
     ) -> Result<Self, NetError> {
+        let guest_mac = mac_addr.map(|mac| mac.octets());
         let net = Self {
-            guest_mac: mac_addr.map(|mac| mac.octets()),
-            queue_sizes: vec![QUEUE_SIZE; taps.len() * 2 + 1].into_boxed_slice(),
+            guest_mac,
+            queue_sizes: vec![QUEUE_SIZE; usize::from(vq_pairs) * 2 + 1].into_boxed_slice(),
             worker_threads: Vec::new(),
             taps,
             avail_features,
             acked_features: 0u64,
             mtu,
             pci_address,
+            capture: SharedCapture::default(),
+            rate_limiter: SharedRateLimiter::default(),
+            control_tube: None,
+            ctrl_state: Arc::new(CtrlState::new(guest_mac)),
             #[cfg(windows)]
             slirp_kill_evt: None,
         };
@@ -584,10 +1195,35 @@ where
         Ok(net)
     }
 
+    /// Sets the tube receiving the `NetControlCommand`s of the device.
+    pub fn set_control_tube(&mut self, control_tube: Tube) {
+        self.control_tube = Some(control_tube);
+    }
+
+    /// Starts capturing the frames of the device to `file`, in the pcapng format.
+    pub fn start_capture(&mut self, file: File) -> Result<(), NetError> {
+        self.capture
+            .start(PcapWriter::new(file).map_err(NetError::PcapStart)?);
+        Ok(())
+    }
+
+    /// Replaces the rate limits of the frames received and transmitted by the guest.
+    pub fn set_rate_limit(&mut self, config: NetRateLimitConfig) -> Result<(), NetError> {
+        check_rate_limit(&config)?;
+        self.rate_limiter.set(config);
+        Ok(())
+    }
+
     /// Returns the maximum number of receive/transmit queue pairs for this device.
     /// Only relevant when multi-queue support is negotiated.
     fn max_virtqueue_pairs(&self) -> usize {
-        self.taps.len()
+        self.queue_sizes.len() / 2
+    }
+
+    /// Returns whether the frames are steered to the receive queues in software, by a single
+    /// worker servicing every queue pair.
+    fn software_rss(&self) -> bool {
+        self.avail_features & 1 << virtio_net::VIRTIO_NET_F_RSS != 0
     }
 }
 
@@ -616,6 +1252,14 @@ where
             keep_rds.push(tap.as_raw_descriptor());
         }
 
+        if let Some(capture) = self.capture.as_raw_descriptor() {
+            keep_rds.push(capture);
+        }
+
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
+
         keep_rds
     }
 
@@ -657,7 +1301,20 @@ where
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         let vq_pairs = self.queue_sizes.len() / 2;
-        let config_space = build_config(vq_pairs as u16, self.mtu, self.guest_mac);
+        // The driver may have changed the MAC address through the control queue.
+        let mac = self.ctrl_state.rx_filter.lock().mac();
+        let mut config_space = build_config(vq_pairs as u16, self.mtu, mac);
+        let mut status = virtio_net::VIRTIO_NET_S_LINK_UP as u16;
+        if self.ctrl_state.announce.load(Ordering::SeqCst) {
+            status |= virtio_net::VIRTIO_NET_S_ANNOUNCE as u16;
+        }
+        config_space.status = Le16::from(status);
+        if self.software_rss() {
+            config_space.rss_max_key_size = rss::RSS_MAX_KEY_SIZE;
+            config_space.rss_max_indirection_table_length =
+                Le16::from(rss::RSS_MAX_INDIRECTION_TABLE_LEN);
+            config_space.supported_hash_types = Le32::from(rss::SUPPORTED_HASH_TYPES);
+        }
         copy_config(data, 0, config_space.as_bytes(), offset);
     }
 
@@ -669,6 +1326,8 @@ where
     ) -> anyhow::Result<()> {
         let ctrl_vq_enabled = self.acked_features & (1 << virtio_net::VIRTIO_NET_F_CTRL_VQ) != 0;
         let mq_enabled = self.acked_features & (1 << virtio_net::VIRTIO_NET_F_MQ) != 0;
+        let vlan_filtering = self.acked_features & (1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN) != 0;
+        Arc::make_mut(&mut self.ctrl_state.rx_filter.lock()).set_vlan_filtering(vlan_filtering);
 
         let vq_pairs = if mq_enabled {
             self.max_virtqueue_pairs()
@@ -689,15 +1348,17 @@ where
             ));
         }
 
-        if self.taps.len() < vq_pairs {
+        // With a single tap, a single worker services every pair.
+        let num_workers = if self.software_rss() { 1 } else { vq_pairs };
+        if self.taps.len() < num_workers {
             return Err(anyhow!(
                 "net: expected {} taps, got {}",
-                vq_pairs,
+                num_workers,
                 self.taps.len()
             ));
         }
 
-        for i in 0..vq_pairs {
+        for i in 0..num_workers {
             let tap = self.taps.remove(0);
             let acked_features = self.acked_features;
             let interrupt = interrupt.clone();
@@ -710,6 +1371,36 @@ where
             } else {
                 None
             };
+            let other_pairs = if num_workers < vq_pairs {
+                (1..vq_pairs)
+                    .map(|_| {
+                        let rx_queue = queues.pop_first().unwrap().1;
+                        let tx_queue = queues.pop_first().unwrap().1;
+                        (rx_queue, tx_queue)
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            let software_rss = if acked_features
+                & (1 << virtio_net::VIRTIO_NET_F_RSS | 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT)
+                != 0
+            {
+                Some(SoftwareRss::new(
+                    acked_features & 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT != 0,
+                ))
+            } else {
+                None
+            };
+            let capture = self.capture.clone();
+            let rate_limiter = self.rate_limiter.clone();
+            let ctrl_state = self.ctrl_state.clone();
+            let control_tube = if first_queue {
+                self.control_tube.take()
+            } else {
+                None
+            };
             // Handle interrupt resampling on the first queue's thread.
             let handle_interrupt_resample = first_queue;
             let pairs = vq_pairs as u16;
@@ -722,11 +1413,20 @@ where
                         rx_queue,
                         tx_queue,
                         ctrl_queue,
+                        other_pairs,
+                        #[cfg(any(target_os = "android", target_os = "linux"))]
+                        software_rss,
                         tap,
                         #[cfg(windows)]
                         overlapped_wrapper,
                         acked_features,
                         vq_pairs: pairs,
+                        capture,
+                        rate_limiter,
+                        rate_limit_timer: None,
+                        rate_limit_deadline: None,
+                        ctrl_state,
+                        control_tube,
                         #[cfg(windows)]
                         rx_buf: [0u8; MAX_BUFFER_SIZE],
                         #[cfg(windows)]
@@ -762,10 +1462,18 @@ where
             if worker.ctrl_queue.is_some() {
                 ctrl_queue = worker.ctrl_queue.take();
             }
+            if worker.control_tube.is_some() {
+                self.control_tube = worker.control_tube.take();
+            }
             self.taps.push(worker.tap);
             queues.insert(queue_index + 0, worker.rx_queue);
             queues.insert(queue_index + 1, worker.tx_queue);
             queue_index += 2;
+            for (rx_queue, tx_queue) in worker.other_pairs {
+                queues.insert(queue_index + 0, rx_queue);
+                queues.insert(queue_index + 1, tx_queue);
+                queue_index += 2;
+            }
         }
         if let Some(ctrl_queue) = ctrl_queue {
             queues.insert(queue_index, ctrl_queue);
@@ -783,7 +1491,12 @@ where
                 // TODO: activate is just what we want at the moment, but we should probably move
                 // it into a "start workers" function to make it obvious that it isn't strictly
                 // used for activate events.
-                self.activate(mem, interrupt, queues)?;
+                self.activate(mem, interrupt.clone(), queues)?;
+                // Ask the guest to announce itself if it was just restored, since it may now be
+                // reachable through a different port of the network.
+                if self.ctrl_state.announce.load(Ordering::SeqCst) {
+                    interrupt.signal_config_changed();
+                }
                 Ok(())
             }
         }
@@ -793,6 +1506,8 @@ where
         serde_json::to_value(NetSnapshot {
             acked_features: self.acked_features,
             avail_features: self.avail_features,
+            rx_filter: Some(RxFilter::clone(&self.ctrl_state.rx_filter.lock())),
+            rss: self.ctrl_state.rss.lock().clone(),
         })
         .context("failed to snapshot virtio Net device")
     }
@@ -807,14 +1522,29 @@ where
             self.avail_features
         );
         self.acked_features = deser.acked_features;
+        *self.ctrl_state.rx_filter.lock() = Arc::new(
+            deser
+                .rx_filter
+                .unwrap_or_else(|| RxFilter::new(self.guest_mac)),
+        );
+        *self.ctrl_state.rss.lock() = deser.rss;
+        if self.acked_features & (1 << virtio_net::VIRTIO_NET_F_GUEST_ANNOUNCE) != 0 {
+            self.ctrl_state.announce.store(true, Ordering::SeqCst);
+        }
         Ok(())
     }
 
     fn reset(&mut self) -> bool {
         for worker_thread in self.worker_threads.drain(..) {
-            let worker = worker_thread.stop();
+            let mut worker = worker_thread.stop();
+            if worker.control_tube.is_some() {
+                self.control_tube = worker.control_tube.take();
+            }
             self.taps.push(worker.tap);
         }
+        *self.ctrl_state.rx_filter.lock() = Arc::new(RxFilter::new(self.guest_mac));
+        *self.ctrl_state.rss.lock() = RssConfig::default();
+        self.ctrl_state.announce.store(false, Ordering::SeqCst);
 
         true
     }
@@ -839,6 +1569,8 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::num::NonZeroU64;
+
     use serde_keyvalue::*;
 
     use super::*;
@@ -865,6 +1597,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -881,6 +1616,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -897,6 +1635,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -913,6 +1654,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -933,6 +1677,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -953,6 +1700,9 @@ mod tests {
                     dev: 1,
                     func: 1,
                 }),
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -966,6 +1716,210 @@ mod tests {
         assert!(from_net_arg("tap-name=tap,foomatic=true").is_err());
     }
 
+    #[test]
+    #[cfg(feature = "slirp")]
+    fn params_from_key_values_slirp() {
+        use net_util::slirp::HostFwd;
+
+        let params = from_net_arg("slirp").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    host_fwd: Vec::new(),
+                    mac: None,
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
+            }
+        );
+
+        let params = from_net_arg(
+            "slirp,host-fwd=[tcp:127.0.0.1:2222-:22,udp::5353-10.0.2.15:53],\
+                mac=\"3d:70:eb:61:1a:91\"",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    host_fwd: vec![
+                        HostFwd::from_str("tcp:127.0.0.1:2222-:22").unwrap(),
+                        HostFwd::from_str("udp::5353-10.0.2.15:53").unwrap(),
+                    ],
+                    mac: Some(MacAddress::from_str("3d:70:eb:61:1a:91").unwrap()),
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
+            }
+        );
+
+        // invalid forwarding rule
+        assert!(from_net_arg("slirp,host-fwd=[tcp:2222:22]").is_err());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn params_from_key_values_socket() {
+        let params = from_net_arg("unix-seqpacket=/run/switch.sock").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::UnixSeqpacket {
+                    unix_seqpacket: PathBuf::from("/run/switch.sock"),
+                    mac: None,
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
+            }
+        );
+
+        let params = from_net_arg("unix-stream=/tmp/passt_1.socket").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::UnixStream {
+                    unix_stream: PathBuf::from("/tmp/passt_1.socket"),
+                    mac: None,
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
+            }
+        );
+
+        let params = from_net_arg(
+            "dgram-local=/tmp/vm0.sock,dgram-remote=/tmp/vm1.sock,mac=\"3d:70:eb:61:1a:91\"",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::UnixDgram {
+                    dgram_local: PathBuf::from("/tmp/vm0.sock"),
+                    dgram_remote: PathBuf::from("/tmp/vm1.sock"),
+                    mac: Some(MacAddress::from_str("3d:70:eb:61:1a:91").unwrap()),
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
+            }
+        );
+
+        let params = from_net_arg("mcast=230.0.0.1:1234").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::Mcast {
+                    mcast: SocketAddrV4::from_str("230.0.0.1:1234").unwrap(),
+                    mac: None,
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
+            }
+        );
+
+        // both ends of the datagram socket are required
+        assert!(from_net_arg("dgram-local=/tmp/vm0.sock").is_err());
+        // a port is required
+        assert!(from_net_arg("mcast=230.0.0.1").is_err());
+    }
+
+    #[test]
+    fn params_from_key_values_pcap() {
+        let params = from_net_arg("tap-name=tap,pcap=/tmp/tap.pcapng").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::TapName {
+                    tap_name: "tap".to_string(),
+                    mac: None
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: Some(PathBuf::from("/tmp/tap.pcapng")),
+                rss: false,
+                rate_limit: Default::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn params_from_key_values_rss() {
+        let params = from_net_arg("tap-name=tap,vq-pairs=4,rss").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                vhost_net: None,
+                vq_pairs: Some(4),
+                mode: NetParametersMode::TapName {
+                    tap_name: "tap".to_string(),
+                    mac: None
+                },
+                packed_queue: false,
+                pci_address: None,
+                pcap: None,
+                rss: true,
+                rate_limit: Default::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn params_from_key_values_rate_limit() {
+        let params =
+            from_net_arg("tap-name=tap,rate-limit=[rx-bps=1000000,tx-pps=100,tx-pps-burst=10]")
+                .unwrap();
+        assert_eq!(
+            params.rate_limit,
+            NetRateLimitConfig {
+                rx_bps: NonZeroU64::new(1000000),
+                tx_pps: NonZeroU64::new(100),
+                tx_pps_burst: NonZeroU64::new(10),
+                ..Default::default()
+            }
+        );
+
+        assert!(from_net_arg("tap-name=tap,rate-limit=[rx-bps=0]").is_err());
+        assert!(from_net_arg("tap-name=tap,rate-limit=[rx-mbps=1]").is_err());
+    }
+
     #[test]
     #[cfg(any(target_os = "android", target_os = "linux"))]
     fn params_from_key_values_vhost_net() {
@@ -990,6 +1944,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -1005,6 +1962,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -1020,6 +1980,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -1036,6 +1999,9 @@ mod tests {
                 },
                 packed_queue: false,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -1052,6 +2018,9 @@ mod tests {
                 },
                 packed_queue: true,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -1068,6 +2037,9 @@ mod tests {
                 },
                 packed_queue: true,
                 pci_address: None,
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -1087,6 +2059,9 @@ mod tests {
                     dev: 1,
                     func: 1,
                 }),
+                pcap: None,
+                rss: false,
+                rate_limit: Default::default(),
             }
         );
 
@@ -1100,4 +2075,180 @@ mod tests {
         )
         .is_err());
     }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn ctrl_request(
+        ctrl_state: &CtrlState,
+        acked_features: u64,
+        request: &[u8],
+    ) -> Result<(), NetError> {
+        use net_util::sys::linux::fakes::FakeTap;
+        use net_util::TapTCommon;
+        use vm_memory::GuestAddress;
+
+        use crate::virtio::create_descriptor_chain;
+        use crate::virtio::DescriptorType;
+
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_all_at_addr(request, GuestAddress(0x1000))
+            .unwrap();
+        let mut chain = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),
+            GuestAddress(0x1000),
+            vec![(DescriptorType::Readable, request.len() as u32)],
+            0,
+        )
+        .unwrap();
+        let mut tap = FakeTap::new(false, false).unwrap();
+        process_ctrl_request(
+            &mut chain.reader,
+            &mut tap,
+            acked_features,
+            4,
+            Some(ctrl_state),
+        )
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn ctrl_rx_filter() {
+        const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let acked_features = CTRL_FEATURES;
+        let ctrl_state = CtrlState::new(Some(MAC));
+        let mut expected = RxFilter::new(Some(MAC));
+
+        ctrl_request(
+            &ctrl_state,
+            acked_features,
+            &[
+                VIRTIO_NET_CTRL_RX as u8,
+                VIRTIO_NET_CTRL_RX_PROMISC as u8,
+                0,
+            ],
+        )
+        .unwrap();
+        expected.set_promisc(false);
+        assert_eq!(**ctrl_state.rx_filter.lock(), expected);
+
+        let mut request = vec![
+            VIRTIO_NET_CTRL_MAC as u8,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET as u8,
+        ];
+        request.extend_from_slice(&1u32.to_le_bytes());
+        request.extend_from_slice(&[0x52, 0x54, 0x00, 0x65, 0x43, 0x21]);
+        request.extend_from_slice(&1u32.to_le_bytes());
+        request.extend_from_slice(&[0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb]);
+        ctrl_request(&ctrl_state, acked_features, &request).unwrap();
+        expected.set_mac_table(
+            vec![[0x52, 0x54, 0x00, 0x65, 0x43, 0x21]],
+            vec![[0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb]],
+        );
+        assert_eq!(**ctrl_state.rx_filter.lock(), expected);
+
+        // A table claiming more entries than the request holds.
+        let mut request = vec![
+            VIRTIO_NET_CTRL_MAC as u8,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET as u8,
+        ];
+        request.extend_from_slice(&2u32.to_le_bytes());
+        request.extend_from_slice(&[0x52, 0x54, 0x00, 0x65, 0x43, 0x21]);
+        assert!(ctrl_request(&ctrl_state, acked_features, &request).is_err());
+
+        let mut request = vec![VIRTIO_NET_CTRL_VLAN as u8, VIRTIO_NET_CTRL_VLAN_ADD as u8];
+        request.extend_from_slice(&5u16.to_le_bytes());
+        ctrl_request(&ctrl_state, acked_features, &request).unwrap();
+        expected.set_vlan(5, true);
+        assert_eq!(**ctrl_state.rx_filter.lock(), expected);
+
+        let mut request = vec![VIRTIO_NET_CTRL_VLAN as u8, VIRTIO_NET_CTRL_VLAN_ADD as u8];
+        request.extend_from_slice(&4096u16.to_le_bytes());
+        assert!(ctrl_request(&ctrl_state, acked_features, &request).is_err());
+
+        // Commands are rejected unless their feature was negotiated.
+        let acked_features = 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ;
+        assert!(ctrl_request(
+            &ctrl_state,
+            acked_features,
+            &[
+                VIRTIO_NET_CTRL_RX as u8,
+                VIRTIO_NET_CTRL_RX_PROMISC as u8,
+                1,
+            ],
+        )
+        .is_err());
+        assert_eq!(**ctrl_state.rx_filter.lock(), expected);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn ctrl_announce_ack() {
+        let ctrl_state = CtrlState::new(None);
+        ctrl_state.announce.store(true, Ordering::SeqCst);
+        ctrl_request(
+            &ctrl_state,
+            CTRL_FEATURES,
+            &[
+                VIRTIO_NET_CTRL_ANNOUNCE as u8,
+                VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8,
+            ],
+        )
+        .unwrap();
+        assert!(!ctrl_state.announce.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn ctrl_rss_config() {
+        let acked_features = CTRL_FEATURES | 1 << virtio_net::VIRTIO_NET_F_RSS;
+        let ctrl_state = CtrlState::new(None);
+        let key = [0x6du8; 40];
+        let rss_config = |table: [u16; 4]| {
+            let mut request = vec![
+                VIRTIO_NET_CTRL_MQ as u8,
+                VIRTIO_NET_CTRL_MQ_RSS_CONFIG as u8,
+            ];
+            request.extend_from_slice(&rss::SUPPORTED_HASH_TYPES.to_le_bytes());
+            // Indirection table mask and unclassified queue.
+            request.extend_from_slice(&3u16.to_le_bytes());
+            request.extend_from_slice(&1u16.to_le_bytes());
+            for queue in table {
+                request.extend_from_slice(&queue.to_le_bytes());
+            }
+            // Number of transmit queues.
+            request.extend_from_slice(&4u16.to_le_bytes());
+            request.push(key.len() as u8);
+            request.extend_from_slice(&key);
+            request
+        };
+
+        ctrl_request(&ctrl_state, acked_features, &rss_config([3, 2, 1, 0])).unwrap();
+        let mut expected = RssConfig::default();
+        expected.set_steering(rss::SUPPORTED_HASH_TYPES, key.to_vec(), vec![3, 2, 1, 0], 1);
+        assert_eq!(*ctrl_state.rss.lock(), expected);
+
+        // The device has 4 queue pairs.
+        assert!(ctrl_request(&ctrl_state, acked_features, &rss_config([4, 2, 1, 0])).is_err());
+        assert!(ctrl_request(&ctrl_state, CTRL_FEATURES, &rss_config([0, 1, 2, 3])).is_err());
+        assert_eq!(*ctrl_state.rss.lock(), expected);
+
+        // Hash reporting without steering.
+        let mut request = vec![
+            VIRTIO_NET_CTRL_MQ as u8,
+            VIRTIO_NET_CTRL_MQ_HASH_CONFIG as u8,
+        ];
+        request.extend_from_slice(&rss::SUPPORTED_HASH_TYPES.to_le_bytes());
+        request.extend_from_slice(&[0u8; 8]);
+        request.push(key.len() as u8);
+        request.extend_from_slice(&key);
+        assert!(ctrl_request(&ctrl_state, acked_features, &request).is_err());
+        ctrl_request(
+            &ctrl_state,
+            CTRL_FEATURES | 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT,
+            &request,
+        )
+        .unwrap();
+        expected.set_hashing(rss::SUPPORTED_HASH_TYPES, key.to_vec());
+        assert_eq!(*ctrl_state.rss.lock(), expected);
+    }
 }
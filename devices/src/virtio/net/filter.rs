@@ -0,0 +1,342 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Filtering of the received frames, as configured by the driver through the control queue.
+
+use std::io::Result as IoResult;
+
+use base::FileReadWriteVolatile;
+use base::VolatileSlice;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::pcap::gather;
+use super::pcap::VNET_HDR_LEN;
+
+/// Maximum number of entries of each of the unicast and multicast MAC tables. Larger tables are
+/// not stored, and all the frames of the corresponding class are accepted instead.
+pub const MAC_TABLE_ENTRIES: usize = 64;
+
+/// Number of VLAN IDs.
+pub const MAX_VLAN: u16 = 4096;
+
+const ETH_ALEN: usize = 6;
+const ETH_P_8021Q: u16 = 0x8100;
+const BROADCAST_ADDR: [u8; ETH_ALEN] = [0xff; ETH_ALEN];
+
+/// Number of bytes of a frame, including its virtio-net header, needed to decide whether to
+/// accept it: the destination and source addresses, the ethertype and the VLAN tag.
+const FILTER_HDR_LEN: usize = VNET_HDR_LEN + 2 * ETH_ALEN + 4;
+
+/// Receive filter of a virtio-net device.
+///
+/// The defaults match a device whose driver has not configured anything yet: every frame is
+/// accepted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RxFilter {
+    promisc: bool,
+    allmulti: bool,
+    /// Primary MAC address of the device, if known.
+    mac: Option<[u8; ETH_ALEN]>,
+    uni_macs: Vec<[u8; ETH_ALEN]>,
+    uni_overflow: bool,
+    multi_macs: Vec<[u8; ETH_ALEN]>,
+    multi_overflow: bool,
+    /// Whether tagged frames are only accepted for the VLANs of `vlans`.
+    vlan_filtering: bool,
+    /// Bitmap of the VLAN IDs added by the driver.
+    vlans: Vec<u64>,
+}
+
+impl RxFilter {
+    /// Creates a filter accepting every frame, for a device whose MAC address is `mac`.
+    pub fn new(mac: Option<[u8; ETH_ALEN]>) -> Self {
+        RxFilter {
+            promisc: true,
+            allmulti: false,
+            mac,
+            uni_macs: Vec::new(),
+            uni_overflow: false,
+            multi_macs: Vec::new(),
+            multi_overflow: false,
+            vlan_filtering: false,
+            vlans: vec![0; MAX_VLAN as usize / 64],
+        }
+    }
+
+    pub fn set_promisc(&mut self, on: bool) {
+        self.promisc = on;
+    }
+
+    pub fn set_allmulti(&mut self, on: bool) {
+        self.allmulti = on;
+    }
+
+    /// Returns the primary MAC address of the device, if known.
+    pub fn mac(&self) -> Option<[u8; ETH_ALEN]> {
+        self.mac
+    }
+
+    /// Sets the primary MAC address of the device.
+    pub fn set_mac(&mut self, mac: [u8; ETH_ALEN]) {
+        self.mac = Some(mac);
+    }
+
+    /// Replaces the additional unicast and multicast addresses accepted by the filter.
+    pub fn set_mac_table(
+        &mut self,
+        uni_macs: Vec<[u8; ETH_ALEN]>,
+        multi_macs: Vec<[u8; ETH_ALEN]>,
+    ) {
+        self.uni_overflow = uni_macs.len() > MAC_TABLE_ENTRIES;
+        self.uni_macs = if self.uni_overflow {
+            Vec::new()
+        } else {
+            uni_macs
+        };
+        self.multi_overflow = multi_macs.len() > MAC_TABLE_ENTRIES;
+        self.multi_macs = if self.multi_overflow {
+            Vec::new()
+        } else {
+            multi_macs
+        };
+    }
+
+    /// Enables the filtering of tagged frames, which is only done when the driver negotiated
+    /// `VIRTIO_NET_F_CTRL_VLAN`.
+    pub fn set_vlan_filtering(&mut self, on: bool) {
+        self.vlan_filtering = on;
+    }
+
+    /// Accepts the frames tagged with `vid` (if `on`) or stops accepting them.
+    ///
+    /// `vid` must be lower than `MAX_VLAN`.
+    pub fn set_vlan(&mut self, vid: u16, on: bool) {
+        let (word, bit) = (vid as usize / 64, vid % 64);
+        if on {
+            self.vlans[word] |= 1 << bit;
+        } else {
+            self.vlans[word] &= !(1 << bit);
+        }
+    }
+
+    /// Returns whether every frame is accepted, in which case there is no need to look at them.
+    pub fn accepts_all(&self) -> bool {
+        self.promisc
+    }
+
+    fn has_vlan(&self, vid: u16) -> bool {
+        self.vlans[vid as usize / 64] & (1 << (vid % 64)) != 0
+    }
+
+    /// Returns whether `buf`, a frame preceded by its virtio-net header, should be received.
+    pub fn accepts(&self, buf: &[u8]) -> bool {
+        if self.promisc {
+            return true;
+        }
+        let frame = match buf.get(VNET_HDR_LEN..) {
+            Some(frame) if frame.len() >= 2 * ETH_ALEN + 2 => frame,
+            // Leave runt frames to the guest.
+            _ => return true,
+        };
+
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        if self.vlan_filtering && ethertype == ETH_P_8021Q {
+            let vid = match frame.get(14..16) {
+                Some(tci) => u16::from_be_bytes([tci[0], tci[1]]) & (MAX_VLAN - 1),
+                None => return false,
+            };
+            if !self.has_vlan(vid) {
+                return false;
+            }
+        }
+
+        let dest = &frame[..ETH_ALEN];
+        if dest[0] & 1 != 0 {
+            // Multicast, which broadcast is a special case of.
+            dest == BROADCAST_ADDR
+                || self.allmulti
+                || self.multi_overflow
+                || self.multi_macs.iter().any(|mac| mac == dest)
+        } else {
+            match self.mac {
+                // Without a MAC address from the config space or the driver, there is no telling
+                // which unicast frames are meant for the guest.
+                None => true,
+                Some(mac) => {
+                    mac == dest || self.uni_overflow || self.uni_macs.iter().any(|mac| mac == dest)
+                }
+            }
+        }
+    }
+}
+
+/// Wraps a tap to drop the frames read from it that are rejected by a `RxFilter`.
+///
+/// A rejected frame is reported as a read of 0 bytes, so that the buffers it was read into can be
+/// reused for the next frame.
+pub struct FilteringTap<'a, T> {
+    tap: T,
+    filter: &'a RxFilter,
+}
+
+impl<'a, T> FilteringTap<'a, T> {
+    pub fn new(tap: T, filter: &'a RxFilter) -> Self {
+        FilteringTap { tap, filter }
+    }
+}
+
+impl<'a, T: FileReadWriteVolatile> FileReadWriteVolatile for FilteringTap<'a, T> {
+    fn read_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.read_vectored_volatile(&[slice])
+    }
+
+    fn read_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        let count = self.tap.read_vectored_volatile(bufs)?;
+        if self
+            .filter
+            .accepts(&gather(bufs, count.min(FILTER_HDR_LEN)))
+        {
+            Ok(count)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn write_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.tap.write_volatile(slice)
+    }
+
+    fn write_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        self.tap.write_vectored_volatile(bufs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const OTHER_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x65, 0x43, 0x21];
+    const MULTICAST_MAC: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+
+    fn frame(dest: [u8; 6], vid: Option<u16>) -> Vec<u8> {
+        let mut buf = vec![0u8; VNET_HDR_LEN];
+        buf.extend_from_slice(&dest);
+        buf.extend_from_slice(&OTHER_MAC);
+        if let Some(vid) = vid {
+            buf.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            buf.extend_from_slice(&vid.to_be_bytes());
+        }
+        buf.extend_from_slice(&0x0800u16.to_be_bytes());
+        buf.resize(buf.len() + 46, 0);
+        buf
+    }
+
+    #[test]
+    fn promisc_by_default() {
+        let mut filter = RxFilter::new(Some(MAC));
+        assert!(filter.accepts_all());
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(filter.accepts(&frame(MULTICAST_MAC, Some(5))));
+
+        filter.set_promisc(false);
+        assert!(!filter.accepts_all());
+    }
+
+    #[test]
+    fn unicast() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(filter.accepts(&frame(BROADCAST_ADDR, None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_mac_table(vec![OTHER_MAC], Vec::new());
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_mac(OTHER_MAC);
+        filter.set_mac_table(Vec::new(), Vec::new());
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(!filter.accepts(&frame(MAC, None)));
+
+        // Too many addresses for the table.
+        filter.set_mac_table(
+            vec![[0x02, 0, 0, 0, 0, 0]; MAC_TABLE_ENTRIES + 1],
+            Vec::new(),
+        );
+        assert!(filter.accepts(&frame(MAC, None)));
+    }
+
+    #[test]
+    fn unknown_mac() {
+        let mut filter = RxFilter::new(None);
+        filter.set_promisc(false);
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(!filter.accepts(&frame(MULTICAST_MAC, None)));
+    }
+
+    #[test]
+    fn multicast() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        assert!(!filter.accepts(&frame(MULTICAST_MAC, None)));
+
+        filter.set_mac_table(Vec::new(), vec![MULTICAST_MAC]);
+        assert!(filter.accepts(&frame(MULTICAST_MAC, None)));
+        assert!(!filter.accepts(&frame([0x33, 0x33, 0, 0, 0, 1], None)));
+
+        filter.set_allmulti(true);
+        assert!(filter.accepts(&frame([0x33, 0x33, 0, 0, 0, 1], None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+    }
+
+    #[test]
+    fn vlan() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        assert!(filter.accepts(&frame(MAC, Some(5))));
+
+        filter.set_vlan_filtering(true);
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(MAC, Some(5))));
+        filter.set_vlan(5, true);
+        assert!(filter.accepts(&frame(MAC, Some(5))));
+        // The priority bits are not part of the VLAN ID.
+        assert!(filter.accepts(&frame(MAC, Some(0xe005))));
+        assert!(!filter.accepts(&frame(MAC, Some(4095))));
+        filter.set_vlan(5, false);
+        assert!(!filter.accepts(&frame(MAC, Some(5))));
+    }
+
+    #[test]
+    fn filtering_tap() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+
+        let mut tap = tempfile::tempfile().unwrap();
+        for dest in [OTHER_MAC, MAC] {
+            std::io::Write::write_all(&mut tap, &frame(dest, None)).unwrap();
+        }
+        std::io::Seek::rewind(&mut tap).unwrap();
+
+        // Each read returns a single frame, like a tap.
+        let len = frame(MAC, None).len();
+        let mut buf = vec![0u8; len];
+        let mut filtering_tap = FilteringTap::new(&mut tap, &filter);
+        assert_eq!(
+            filtering_tap
+                .read_vectored_volatile(&[VolatileSlice::new(&mut buf)])
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            filtering_tap
+                .read_vectored_volatile(&[VolatileSlice::new(&mut buf)])
+                .unwrap(),
+            len
+        );
+        assert_eq!(&buf[VNET_HDR_LEN..VNET_HDR_LEN + 6], &MAC);
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Capture of the frames going through a virtio-net device, in the pcapng format.
+//!
+//! See https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html for the format.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::mem::size_of;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::MutexGuard;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use base::error;
+use base::AsRawDescriptor;
+use base::FileReadWriteVolatile;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use sync::Mutex;
+use virtio_sys::virtio_net::virtio_net_hdr_v1;
+use virtio_sys::virtio_net::VIRTIO_NET_HDR_F_DATA_VALID;
+use virtio_sys::virtio_net::VIRTIO_NET_HDR_F_NEEDS_CSUM;
+use virtio_sys::virtio_net::VIRTIO_NET_HDR_GSO_NONE;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+const EPB_FLAGS_INBOUND: u32 = 1;
+const EPB_FLAGS_OUTBOUND: u32 = 2;
+
+/// Size of the virtio-net header preceding each frame exchanged with the tap.
+pub(super) const VNET_HDR_LEN: usize = size_of::<virtio_net_hdr_v1>();
+
+/// Direction of a frame, from the point of view of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Frame received by the guest.
+    Rx,
+    /// Frame sent by the guest.
+    Tx,
+}
+
+/// Writes the frames going through a virtio-net device to a pcapng file.
+///
+/// The virtio-net header is stripped from the frames. When it carries offload information (a
+/// partial checksum or a segmentation request), it is described in a comment attached to the
+/// frame since the captured frame is then not what ends up on the wire.
+pub struct PcapWriter {
+    file: File,
+    failed: bool,
+}
+
+impl PcapWriter {
+    /// Creates a writer appending to `file`, and writes the section and interface headers.
+    pub fn new(mut file: File) -> IoResult<PcapWriter> {
+        let mut header = Vec::new();
+
+        let mut options = Vec::new();
+        push_option(&mut options, SHB_USERAPPL, b"crosvm");
+        push_option(&mut options, OPT_ENDOFOPT, &[]);
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // Major version.
+        body.extend_from_slice(&0u16.to_le_bytes()); // Minor version.
+        body.extend_from_slice(&(-1i64).to_le_bytes()); // Unknown section length.
+        body.extend_from_slice(&options);
+        push_block(&mut header, SECTION_HEADER_BLOCK, &body);
+
+        let mut options = Vec::new();
+        push_option(&mut options, IF_NAME, b"virtio-net");
+        push_option(&mut options, OPT_ENDOFOPT, &[]);
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes()); // Reserved.
+        body.extend_from_slice(&0u32.to_le_bytes()); // No snapshot length limit.
+        body.extend_from_slice(&options);
+        push_block(&mut header, INTERFACE_DESCRIPTION_BLOCK, &body);
+
+        file.write_all(&header)?;
+        Ok(PcapWriter {
+            file,
+            failed: false,
+        })
+    }
+
+    /// Records `buf`, a frame preceded by its virtio-net header.
+    ///
+    /// Errors are logged, and stop the capture.
+    pub fn write_frame(&mut self, direction: Direction, buf: &[u8]) {
+        if self.failed || buf.len() < VNET_HDR_LEN {
+            return;
+        }
+        let (vnet_hdr, frame) = buf.split_at(VNET_HDR_LEN);
+
+        let mut options = Vec::new();
+        let flags = match direction {
+            Direction::Rx => EPB_FLAGS_INBOUND,
+            Direction::Tx => EPB_FLAGS_OUTBOUND,
+        };
+        push_option(&mut options, EPB_FLAGS, &flags.to_le_bytes());
+        if let Some(comment) = describe_vnet_hdr(vnet_hdr) {
+            push_option(&mut options, OPT_COMMENT, comment.as_bytes());
+        }
+        push_option(&mut options, OPT_ENDOFOPT, &[]);
+
+        // Timestamps are in microseconds, the default resolution.
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let mut body = Vec::with_capacity(20 + frame.len() + 3 + options.len());
+        body.extend_from_slice(&0u32.to_le_bytes()); // Interface ID.
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // Captured length.
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // Original length.
+        push_padded(&mut body, frame);
+        body.extend_from_slice(&options);
+
+        let mut block = Vec::with_capacity(12 + body.len());
+        push_block(&mut block, ENHANCED_PACKET_BLOCK, &body);
+        // Each block is written at once so that the file can be read while it is captured.
+        if let Err(e) = self.file.write_all(&block) {
+            error!(
+                "net: failed to write captured frame, stopping capture: {}",
+                e
+            );
+            self.failed = true;
+        }
+    }
+}
+
+impl AsRawDescriptor for PcapWriter {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.file.as_raw_descriptor()
+    }
+}
+
+/// A frame capture shared by the workers of a device.
+///
+/// Whether a capture is in progress is tracked outside of the lock, so that the workers don't
+/// contend for it on every frame while nothing is captured.
+#[derive(Clone, Default)]
+pub struct SharedCapture {
+    active: Arc<AtomicBool>,
+    writer: Arc<Mutex<Option<PcapWriter>>>,
+}
+
+impl SharedCapture {
+    /// Starts writing the frames to `writer`, replacing the capture in progress, if any.
+    pub fn start(&self, writer: PcapWriter) {
+        *self.writer.lock() = Some(writer);
+        self.active.store(true, Ordering::Release);
+    }
+
+    /// Stops the capture in progress, if any.
+    pub fn stop(&self) {
+        self.active.store(false, Ordering::Release);
+        *self.writer.lock() = None;
+    }
+
+    /// Locks the capture if one is in progress.
+    pub fn lock(&self) -> CaptureGuard<'_> {
+        CaptureGuard(
+            self.active
+                .load(Ordering::Acquire)
+                .then(|| self.writer.lock()),
+        )
+    }
+
+    /// Returns the descriptor of the file the frames are written to, if a capture is in progress.
+    pub fn as_raw_descriptor(&self) -> Option<RawDescriptor> {
+        self.writer.lock().as_ref().map(|w| w.as_raw_descriptor())
+    }
+}
+
+/// The capture of a device, locked by `SharedCapture::lock` if one is in progress.
+pub struct CaptureGuard<'a>(Option<MutexGuard<'a, Option<PcapWriter>>>);
+
+impl CaptureGuard<'_> {
+    /// Returns the writer of the capture in progress, if any.
+    pub fn writer(&mut self) -> Option<&mut PcapWriter> {
+        self.0.as_deref_mut().and_then(Option::as_mut)
+    }
+}
+
+/// Describes the offload information of a virtio-net header, if any.
+fn describe_vnet_hdr(vnet_hdr: &[u8]) -> Option<String> {
+    let flags = vnet_hdr[0];
+    let gso_type = vnet_hdr[1];
+    let field = |offset: usize| u16::from_le_bytes([vnet_hdr[offset], vnet_hdr[offset + 1]]);
+
+    let mut description = String::new();
+    if flags & VIRTIO_NET_HDR_F_NEEDS_CSUM as u8 != 0 {
+        let _ = write!(
+            description,
+            "needs_csum csum_start={} csum_offset={}",
+            field(6),
+            field(8)
+        );
+    }
+    if flags & VIRTIO_NET_HDR_F_DATA_VALID as u8 != 0 {
+        if !description.is_empty() {
+            description.push(' ');
+        }
+        description.push_str("data_valid");
+    }
+    if gso_type != VIRTIO_NET_HDR_GSO_NONE as u8 {
+        if !description.is_empty() {
+            description.push(' ');
+        }
+        let _ = write!(
+            description,
+            "gso_type={} hdr_len={} gso_size={}",
+            gso_type,
+            field(2),
+            field(4)
+        );
+    }
+
+    if description.is_empty() {
+        None
+    } else {
+        Some(format!("virtio-net: {}", description))
+    }
+}
+
+/// Appends `data` to `buf`, padded to 32 bits.
+fn push_padded(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    push_padded(buf, value);
+}
+
+fn push_block(buf: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let total_len = (12 + body.len()) as u32;
+    buf.extend_from_slice(&block_type.to_le_bytes());
+    buf.extend_from_slice(&total_len.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf.extend_from_slice(&total_len.to_le_bytes());
+}
+
+/// Copies the first `len` bytes of `bufs`.
+pub(super) fn gather(bufs: &[VolatileSlice], len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    let mut copied = 0;
+    for buf in bufs {
+        if copied == len {
+            break;
+        }
+        let count = buf.size().min(len - copied);
+        buf.copy_to(&mut data[copied..copied + count]);
+        copied += count;
+    }
+    data
+}
+
+/// Wraps a tap to record the frames read from and written to it.
+pub struct CapturingTap<'a, T> {
+    tap: &'a mut T,
+    capture: &'a mut PcapWriter,
+}
+
+impl<'a, T> CapturingTap<'a, T> {
+    pub fn new(tap: &'a mut T, capture: &'a mut PcapWriter) -> Self {
+        CapturingTap { tap, capture }
+    }
+}
+
+impl<'a, T: FileReadWriteVolatile> FileReadWriteVolatile for CapturingTap<'a, T> {
+    fn read_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.read_vectored_volatile(&[slice])
+    }
+
+    fn read_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        let count = self.tap.read_vectored_volatile(bufs)?;
+        self.capture
+            .write_frame(Direction::Rx, &gather(bufs, count));
+        Ok(count)
+    }
+
+    fn write_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.write_vectored_volatile(&[slice])
+    }
+
+    fn write_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        let len = bufs.iter().map(|buf| buf.size()).sum();
+        let frame = gather(bufs, len);
+        let count = self.tap.write_vectored_volatile(bufs)?;
+        self.capture.write_frame(Direction::Tx, &frame);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::io::Seek;
+
+    use tempfile::tempfile;
+
+    use super::*;
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Returns the type and body of each block of `buf`.
+    fn blocks(mut buf: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !buf.is_empty() {
+            let block_type = read_u32(buf, 0);
+            let len = read_u32(buf, 4) as usize;
+            assert_eq!(read_u32(buf, len - 4) as usize, len);
+            blocks.push((block_type, buf[8..len - 4].to_vec()));
+            buf = &buf[len..];
+        }
+        blocks
+    }
+
+    fn capture(frames: &[(Direction, Vec<u8>)]) -> Vec<(u32, Vec<u8>)> {
+        let mut file = tempfile().unwrap();
+        let mut writer = PcapWriter::new(file.try_clone().unwrap()).unwrap();
+        for (direction, frame) in frames {
+            writer.write_frame(*direction, frame);
+        }
+        let mut buf = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut buf).unwrap();
+        blocks(&buf)
+    }
+
+    #[test]
+    fn headers() {
+        let blocks = capture(&[]);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(read_u32(&blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_ETHERNET.to_le_bytes());
+    }
+
+    #[test]
+    fn frames() {
+        let mut rx = vec![0u8; VNET_HDR_LEN];
+        rx.extend_from_slice(&[1, 2, 3, 4, 5]);
+        // A segmentation request from the guest.
+        let mut tx = vec![0u8; VNET_HDR_LEN];
+        tx[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+        tx[1] = 1;
+        tx[4..6].copy_from_slice(&1448u16.to_le_bytes());
+        tx.extend_from_slice(&[6, 7, 8, 9]);
+
+        let blocks = capture(&[(Direction::Rx, rx), (Direction::Tx, tx)]);
+        assert_eq!(blocks.len(), 4);
+
+        let (block_type, body) = &blocks[2];
+        assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+        assert_eq!(read_u32(body, 12), 5);
+        assert_eq!(read_u32(body, 16), 5);
+        // The header is stripped and the frame padded.
+        assert_eq!(&body[20..28], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        // epb_flags, then the end of the options.
+        assert_eq!(&body[28..32], &[2, 0, 4, 0]);
+        assert_eq!(read_u32(body, 32), EPB_FLAGS_INBOUND);
+        assert_eq!(&body[36..], &[0, 0, 0, 0]);
+
+        let (block_type, body) = &blocks[3];
+        assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+        assert_eq!(&body[20..24], &[6, 7, 8, 9]);
+        assert_eq!(read_u32(body, 28), EPB_FLAGS_OUTBOUND);
+        // The offload information is kept in a comment.
+        let comment_len = u16::from_le_bytes([body[34], body[35]]) as usize;
+        assert_eq!(&body[32..34], &OPT_COMMENT.to_le_bytes());
+        assert_eq!(
+            std::str::from_utf8(&body[36..36 + comment_len]).unwrap(),
+            "virtio-net: needs_csum csum_start=0 csum_offset=0 gso_type=1 hdr_len=0 gso_size=1448"
+        );
+    }
+
+    #[test]
+    fn capturing_tap() {
+        let mut file = tempfile().unwrap();
+        let mut writer = PcapWriter::new(file.try_clone().unwrap()).unwrap();
+        let mut tap = tempfile().unwrap();
+
+        let mut hdr = [0u8; VNET_HDR_LEN];
+        let mut frame = [0xaau8; 60];
+        let bufs = [VolatileSlice::new(&mut hdr), VolatileSlice::new(&mut frame)];
+        let mut capturing_tap = CapturingTap::new(&mut tap, &mut writer);
+        assert_eq!(
+            capturing_tap.write_vectored_volatile(&bufs).unwrap(),
+            VNET_HDR_LEN + 60
+        );
+
+        let mut buf = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut buf).unwrap();
+        let blocks = blocks(&buf);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(read_u32(&blocks[2].1, 12), 60);
+        assert_eq!(&blocks[2].1[20..80], &[0xaa; 60]);
+    }
+
+    #[test]
+    fn shared_capture() {
+        let capture = SharedCapture::default();
+        assert!(capture.lock().writer().is_none());
+        assert_eq!(capture.as_raw_descriptor(), None);
+
+        let file = tempfile().unwrap();
+        let fd = file.as_raw_descriptor();
+        capture.start(PcapWriter::new(file).unwrap());
+        assert!(capture.clone().lock().writer().is_some());
+        assert_eq!(capture.as_raw_descriptor(), Some(fd));
+
+        capture.stop();
+        assert!(capture.lock().writer().is_none());
+    }
+}
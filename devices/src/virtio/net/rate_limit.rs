@@ -0,0 +1,223 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Token bucket based rate limits for the frames received and transmitted by a network device.
+
+use std::num::NonZeroU64;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use sync::Mutex;
+use vm_control::NetRateLimitConfig;
+
+use super::pcap::Direction;
+use crate::virtio::token_bucket::TokenBucket;
+
+/// Rate limits of a network device. Each configured limit is enforced by its own token bucket.
+///
+/// A frame is let through as soon as the previous ones have been paid for, and is then charged in
+/// full, so a frame larger than the burst size only delays the frames following it.
+pub struct RateLimiter {
+    rx_pps: Option<TokenBucket>,
+    tx_pps: Option<TokenBucket>,
+    rx_bps: Option<TokenBucket>,
+    tx_bps: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: NetRateLimitConfig) -> RateLimiter {
+        let now = Instant::now();
+        let bucket =
+            |rate: Option<NonZeroU64>, burst| rate.map(|r| TokenBucket::new(r, burst, now));
+        RateLimiter {
+            rx_pps: bucket(config.rx_pps, config.rx_pps_burst),
+            tx_pps: bucket(config.tx_pps, config.tx_pps_burst),
+            rx_bps: bucket(config.rx_bps, config.rx_bps_burst),
+            tx_bps: bucket(config.tx_bps, config.tx_bps_burst),
+        }
+    }
+
+    /// Returns whether no limit is configured in either direction.
+    pub fn is_unlimited(&self) -> bool {
+        self.rx_pps.is_none()
+            && self.tx_pps.is_none()
+            && self.rx_bps.is_none()
+            && self.tx_bps.is_none()
+    }
+
+    fn buckets(
+        &mut self,
+        direction: Direction,
+    ) -> (&mut Option<TokenBucket>, &mut Option<TokenBucket>) {
+        match direction {
+            Direction::Rx => (&mut self.rx_pps, &mut self.rx_bps),
+            Direction::Tx => (&mut self.tx_pps, &mut self.tx_bps),
+        }
+    }
+
+    /// Returns how long the next frame going in `direction` must wait to stay within the limits,
+    /// or `None` if it can go through now.
+    pub fn wait_time(&mut self, direction: Direction, now: Instant) -> Option<Duration> {
+        let (pps, bps) = self.buckets(direction);
+        let wait = [pps, bps]
+            .into_iter()
+            .flatten()
+            .map(|bucket| bucket.consume(0, now))
+            .max()
+            .unwrap_or(Duration::ZERO);
+        if wait.is_zero() {
+            None
+        } else {
+            Some(wait)
+        }
+    }
+
+    /// Accounts for a frame of `len` bytes, not counting its virtio-net header, going in
+    /// `direction`.
+    pub fn consume(&mut self, direction: Direction, len: usize, now: Instant) {
+        let (pps, bps) = self.buckets(direction);
+        if let Some(bucket) = pps {
+            bucket.consume(1, now);
+        }
+        if let Some(bucket) = bps {
+            bucket.consume(len as u64, now);
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(NetRateLimitConfig::default())
+    }
+}
+
+/// Rate limits shared by the workers of a device.
+///
+/// Whether any limit is configured is tracked outside of the lock, so that the workers don't
+/// contend for it on every frame while the device is unlimited.
+#[derive(Clone, Default)]
+pub struct SharedRateLimiter {
+    active: Arc<AtomicBool>,
+    limiter: Arc<Mutex<Option<RateLimiter>>>,
+}
+
+impl SharedRateLimiter {
+    /// Replaces the limits with `config`, removing them if it doesn't set any.
+    pub fn set(&self, config: NetRateLimitConfig) {
+        let limiter = RateLimiter::new(config);
+        if limiter.is_unlimited() {
+            self.active.store(false, Ordering::Release);
+            *self.limiter.lock() = None;
+        } else {
+            *self.limiter.lock() = Some(limiter);
+            self.active.store(true, Ordering::Release);
+        }
+    }
+
+    /// Locks the limits if any is configured.
+    pub fn lock(&self) -> RateLimiterGuard<'_> {
+        RateLimiterGuard(
+            self.active
+                .load(Ordering::Acquire)
+                .then(|| self.limiter.lock()),
+        )
+    }
+}
+
+/// The limits of a device, locked by `SharedRateLimiter::lock` if any is configured.
+pub struct RateLimiterGuard<'a>(Option<MutexGuard<'a, Option<RateLimiter>>>);
+
+impl RateLimiterGuard<'_> {
+    /// Returns the limits, if any is configured.
+    pub fn limiter(&mut self) -> Option<&mut RateLimiter> {
+        self.0.as_deref_mut().and_then(Option::as_mut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nz(v: u64) -> Option<NonZeroU64> {
+        NonZeroU64::new(v)
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            limiter.consume(Direction::Tx, 65536, now);
+            assert_eq!(limiter.wait_time(Direction::Tx, now), None);
+        }
+    }
+
+    #[test]
+    fn pps_burst_then_rate() {
+        let mut limiter = RateLimiter::new(NetRateLimitConfig {
+            rx_pps: nz(10),
+            rx_pps_burst: nz(2),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(limiter.wait_time(Direction::Rx, now), None);
+            limiter.consume(Direction::Rx, 1500, now);
+        }
+        // The burst is used up but not overdrawn yet, so one more frame goes through and the next
+        // one waits for the token it borrowed.
+        assert_eq!(limiter.wait_time(Direction::Rx, now), None);
+        limiter.consume(Direction::Rx, 1500, now);
+        assert_eq!(
+            limiter.wait_time(Direction::Rx, now),
+            Some(Duration::from_millis(100))
+        );
+        // Transmitted frames are limited separately.
+        assert_eq!(limiter.wait_time(Direction::Tx, now), None);
+        // Once the debt is repaid, frames go through again.
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.wait_time(Direction::Rx, later), None);
+    }
+
+    #[test]
+    fn shared_limiter() {
+        let shared = SharedRateLimiter::default();
+        assert!(shared.lock().limiter().is_none());
+
+        shared.set(NetRateLimitConfig {
+            rx_pps: nz(10),
+            ..Default::default()
+        });
+        assert!(shared.clone().lock().limiter().is_some());
+
+        // Burst sizes alone don't limit anything.
+        shared.set(NetRateLimitConfig {
+            rx_pps_burst: nz(10),
+            ..Default::default()
+        });
+        assert!(shared.lock().limiter().is_none());
+    }
+
+    #[test]
+    fn longest_wait_wins() {
+        let mut limiter = RateLimiter::new(NetRateLimitConfig {
+            tx_pps: nz(1),
+            tx_bps: nz(1000),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        limiter.consume(Direction::Tx, 500, now);
+        assert_eq!(limiter.wait_time(Direction::Tx, now), None);
+        // The second frame owes a second worth of frames, but only half a second of bytes.
+        limiter.consume(Direction::Tx, 1000, now);
+        assert_eq!(
+            limiter.wait_time(Direction::Tx, now),
+            Some(Duration::from_secs(1))
+        );
+    }
+}
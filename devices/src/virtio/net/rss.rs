@@ -0,0 +1,553 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Receive side scaling and hash reporting, done in software by hashing the received frames
+//! before copying them to the receive queue selected by the driver's indirection table.
+
+use std::io;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::time::Duration;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::time::Instant;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::error;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::FileReadWriteVolatile;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::VolatileSlice;
+use serde::Deserialize;
+use serde::Serialize;
+use virtio_sys::virtio_net;
+use virtio_sys::virtio_net::virtio_net_hdr_v1_hash;
+
+use super::pcap::VNET_HDR_LEN;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use super::CapturingTap;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use super::Direction;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use super::PcapWriter;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use super::RateLimiter;
+use super::MAX_BUFFER_SIZE;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::virtio::Interrupt;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::virtio::Queue;
+use crate::virtio::Writer;
+
+/// Maximum length of the hash key.
+pub const RSS_MAX_KEY_SIZE: u8 = 40;
+
+/// Maximum number of entries of the indirection table.
+pub const RSS_MAX_INDIRECTION_TABLE_LEN: u16 = 128;
+
+/// Hash types supported by the device. The `_EX` types, which hash the addresses found in IPv6
+/// extension headers, are not.
+pub const SUPPORTED_HASH_TYPES: u32 = virtio_net::VIRTIO_NET_RSS_HASH_TYPE_IPv4
+    | virtio_net::VIRTIO_NET_RSS_HASH_TYPE_TCPv4
+    | virtio_net::VIRTIO_NET_RSS_HASH_TYPE_UDPv4
+    | virtio_net::VIRTIO_NET_RSS_HASH_TYPE_IPv6
+    | virtio_net::VIRTIO_NET_RSS_HASH_TYPE_TCPv6
+    | virtio_net::VIRTIO_NET_RSS_HASH_TYPE_UDPv6;
+
+/// Length of the header preceding the frames when `VIRTIO_NET_F_HASH_REPORT` is negotiated.
+pub const HASH_HDR_LEN: usize = size_of::<virtio_net_hdr_v1_hash>();
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_8021Q: u16 = 0x8100;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+// Longest input of the hash function: the IPv6 addresses and the ports.
+const MAX_HASH_INPUT_LEN: usize = 36;
+
+/// Hash of a frame, with the `VIRTIO_NET_HASH_REPORT_*` type of the fields it was computed over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHash {
+    pub value: u32,
+    pub report: u16,
+}
+
+// Hash and report types of the protocols of a family.
+struct FamilyHashTypes {
+    ip: (u32, u32),
+    tcp: (u32, u32),
+    udp: (u32, u32),
+}
+
+const IPV4_HASH_TYPES: FamilyHashTypes = FamilyHashTypes {
+    ip: (
+        virtio_net::VIRTIO_NET_RSS_HASH_TYPE_IPv4,
+        virtio_net::VIRTIO_NET_HASH_REPORT_IPv4,
+    ),
+    tcp: (
+        virtio_net::VIRTIO_NET_RSS_HASH_TYPE_TCPv4,
+        virtio_net::VIRTIO_NET_HASH_REPORT_TCPv4,
+    ),
+    udp: (
+        virtio_net::VIRTIO_NET_RSS_HASH_TYPE_UDPv4,
+        virtio_net::VIRTIO_NET_HASH_REPORT_UDPv4,
+    ),
+};
+
+const IPV6_HASH_TYPES: FamilyHashTypes = FamilyHashTypes {
+    ip: (
+        virtio_net::VIRTIO_NET_RSS_HASH_TYPE_IPv6,
+        virtio_net::VIRTIO_NET_HASH_REPORT_IPv6,
+    ),
+    tcp: (
+        virtio_net::VIRTIO_NET_RSS_HASH_TYPE_TCPv6,
+        virtio_net::VIRTIO_NET_HASH_REPORT_TCPv6,
+    ),
+    udp: (
+        virtio_net::VIRTIO_NET_RSS_HASH_TYPE_UDPv6,
+        virtio_net::VIRTIO_NET_HASH_REPORT_UDPv6,
+    ),
+};
+
+/// Computes the Toeplitz hash of `input` with `key`, as specified by Microsoft's RSS.
+fn toeplitz_hash(key: &[u8], input: &[u8]) -> u32 {
+    let key_byte = |i: usize| key.get(i).copied().unwrap_or(0);
+    // The 32 bits of the key starting at the position of the current bit of the input.
+    let mut window = u32::from_be_bytes([key_byte(0), key_byte(1), key_byte(2), key_byte(3)]);
+    let mut hash = 0;
+    for (i, byte) in input.iter().enumerate() {
+        let next = key_byte(i + 4);
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            window = window << 1 | u32::from(next >> (7 - bit) & 1);
+        }
+    }
+    hash
+}
+
+/// Hashing and steering configuration set by the driver through the control queue.
+///
+/// The defaults match a device whose driver has not configured anything yet: frames are neither
+/// hashed nor steered, and all of them go to the first receive queue.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RssConfig {
+    /// `VIRTIO_NET_RSS_HASH_TYPE_*` bits of the fields to hash.
+    hash_types: u32,
+    key: Vec<u8>,
+    /// Receive queue of each hash, masked by the length of the table. Empty unless frames are
+    /// steered.
+    indirection_table: Vec<u16>,
+    /// Receive queue of the frames that have no hash.
+    unclassified_queue: u16,
+}
+
+impl RssConfig {
+    /// Steers the frames to the receive queues of `indirection_table`, whose length must be a
+    /// power of two, as set by `VIRTIO_NET_CTRL_MQ_RSS_CONFIG`.
+    pub fn set_steering(
+        &mut self,
+        hash_types: u32,
+        key: Vec<u8>,
+        indirection_table: Vec<u16>,
+        unclassified_queue: u16,
+    ) {
+        self.hash_types = hash_types & SUPPORTED_HASH_TYPES;
+        self.key = key;
+        self.indirection_table = indirection_table;
+        self.unclassified_queue = unclassified_queue;
+    }
+
+    /// Hashes the frames only to report their hash, as set by `VIRTIO_NET_CTRL_MQ_HASH_CONFIG`.
+    pub fn set_hashing(&mut self, hash_types: u32, key: Vec<u8>) {
+        self.set_steering(hash_types, key, Vec::new(), 0);
+    }
+
+    /// Computes the hash of `frame`, an ethernet frame without its virtio-net header. Returns
+    /// `None` if the frame has none of the fields of the configured hash types.
+    pub fn hash(&self, frame: &[u8]) -> Option<FrameHash> {
+        let mut input = [0u8; MAX_HASH_INPUT_LEN];
+        let (len, report) = self.hash_input(frame, &mut input)?;
+        Some(FrameHash {
+            value: toeplitz_hash(&self.key, &input[..len]),
+            report: report as u16,
+        })
+    }
+
+    // Copies the fields of `frame` to hash to `input`, and returns their length with the report
+    // type of the hash.
+    fn hash_input(
+        &self,
+        frame: &[u8],
+        input: &mut [u8; MAX_HASH_INPUT_LEN],
+    ) -> Option<(usize, u32)> {
+        let be16 = |buf: &[u8]| u16::from_be_bytes([buf[0], buf[1]]);
+        let mut ethertype = be16(frame.get(12..14)?);
+        let mut l3_offset = 14;
+        if ethertype == ETH_P_8021Q {
+            ethertype = be16(frame.get(16..18)?);
+            l3_offset = 18;
+        }
+        let packet = frame.get(l3_offset..)?;
+
+        let (addrs, protocol, l4, types) = match ethertype {
+            ETH_P_IP => {
+                let ihl = usize::from(packet.first()? & 0xf) * 4;
+                if ihl < 20 || packet.len() < ihl {
+                    return None;
+                }
+                // Only the first fragment of a datagram has its ports, and all the fragments
+                // must get the same hash.
+                let fragmented = be16(&packet[6..8]) & 0x3fff != 0;
+                let protocol = if fragmented { None } else { Some(packet[9]) };
+                (&packet[12..20], protocol, &packet[ihl..], IPV4_HASH_TYPES)
+            }
+            ETH_P_IPV6 => {
+                if packet.len() < 40 {
+                    return None;
+                }
+                // Frames with extension headers are only hashed over their addresses.
+                (
+                    &packet[8..40],
+                    Some(packet[6]),
+                    &packet[40..],
+                    IPV6_HASH_TYPES,
+                )
+            }
+            _ => return None,
+        };
+
+        input[..addrs.len()].copy_from_slice(addrs);
+        let report = match (protocol, l4.get(..4)) {
+            (Some(IPPROTO_TCP), Some(ports)) if self.hash_types & types.tcp.0 != 0 => {
+                input[addrs.len()..addrs.len() + 4].copy_from_slice(ports);
+                return Some((addrs.len() + 4, types.tcp.1));
+            }
+            (Some(IPPROTO_UDP), Some(ports)) if self.hash_types & types.udp.0 != 0 => {
+                input[addrs.len()..addrs.len() + 4].copy_from_slice(ports);
+                return Some((addrs.len() + 4, types.udp.1));
+            }
+            _ if self.hash_types & types.ip.0 != 0 => types.ip.1,
+            _ => return None,
+        };
+        Some((addrs.len(), report))
+    }
+
+    /// Returns the receive queue a frame with `hash` is steered to.
+    pub fn queue(&self, hash: Option<FrameHash>) -> usize {
+        if self.indirection_table.is_empty() {
+            return 0;
+        }
+        let queue = match hash {
+            Some(hash) => {
+                self.indirection_table[hash.value as usize & (self.indirection_table.len() - 1)]
+            }
+            None => self.unclassified_queue,
+        };
+        queue.into()
+    }
+}
+
+// Frame waiting to be received in the queue it was steered to.
+struct SteeredFrame {
+    len: usize,
+    queue: usize,
+    hash: Option<FrameHash>,
+}
+
+/// Receive path of a worker steering the frames to the receive queues in software: each frame
+/// is read from the tap into a host buffer, hashed, and then copied to its receive queue.
+pub struct SoftwareRss {
+    buf: Vec<u8>,
+    pending: Option<SteeredFrame>,
+    hash_report: bool,
+}
+
+impl SoftwareRss {
+    /// Creates the receive path of a device, which adds the hash of the frames to their header if
+    /// `hash_report`.
+    pub fn new(hash_report: bool) -> Self {
+        SoftwareRss {
+            buf: vec![0u8; MAX_BUFFER_SIZE],
+            pending: None,
+            hash_report,
+        }
+    }
+
+    /// Returns the buffer the next frame, preceded by its `virtio_net_hdr_v1`, is read into.
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    /// Returns the frame of `len` bytes read into the buffer.
+    pub fn frame(&self, len: usize) -> &[u8] {
+        &self.buf[..len]
+    }
+
+    /// Steers the frame of `len` bytes read into the buffer to one of the `vq_pairs` receive
+    /// queues.
+    pub fn steer(&mut self, len: usize, config: &RssConfig, vq_pairs: usize) {
+        let hash = config.hash(self.buf.get(VNET_HDR_LEN..len).unwrap_or_default());
+        let queue = config.queue(hash);
+        self.pending = Some(SteeredFrame {
+            len,
+            // The configuration was validated against the number of queues, but the driver may
+            // have configured fewer of them since.
+            queue: if queue < vq_pairs { queue } else { 0 },
+            hash,
+        });
+    }
+
+    /// Returns the receive queue of the frame waiting to be received, if any.
+    pub fn pending_queue(&self) -> Option<usize> {
+        self.pending.as_ref().map(|frame| frame.queue)
+    }
+
+    /// Writes the frame waiting to be received to `writer`, and returns the number of bytes
+    /// written. The frame is dropped if it does not fit.
+    pub fn write_frame(&mut self, writer: &mut Writer) -> io::Result<usize> {
+        let frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => return Ok(0),
+        };
+        let (hdr, data) = self.buf[..frame.len].split_at(VNET_HDR_LEN.min(frame.len));
+        let hdr_len = if self.hash_report {
+            HASH_HDR_LEN
+        } else {
+            VNET_HDR_LEN
+        };
+        if writer.available_bytes() < hdr_len + data.len() {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        writer.write_all(hdr)?;
+        if self.hash_report {
+            let (value, report) = frame.hash.map_or(
+                (0, virtio_net::VIRTIO_NET_HASH_REPORT_NONE as u16),
+                |hash| (hash.value, hash.report),
+            );
+            writer.write_all(&value.to_le_bytes())?;
+            writer.write_all(&report.to_le_bytes())?;
+            writer.write_all(&[0u8; 2])?;
+        }
+        writer.write_all(data)?;
+        Ok(writer.bytes_written())
+    }
+}
+
+/// Transmits the frames of `tx_queue`, whose headers have the hash report fields, to `tap`, which
+/// expects frames preceded by a `virtio_net_hdr_v1`.
+///
+/// Returns how long to wait before transmitting the remaining frames if `rate_limiter` stopped the
+/// transmission.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn process_tx_hash_report<T: FileReadWriteVolatile>(
+    interrupt: &Interrupt,
+    tx_queue: &mut Queue,
+    tap: &mut T,
+    mut capture: Option<&mut PcapWriter>,
+    mut rate_limiter: Option<&mut RateLimiter>,
+) -> Option<Duration> {
+    let rate_limited = loop {
+        if let Some(wait) = rate_limiter
+            .as_deref_mut()
+            .and_then(|rate_limiter| rate_limiter.wait_time(Direction::Tx, Instant::now()))
+        {
+            break Some(wait);
+        }
+        let mut desc_chain = match tx_queue.pop() {
+            Some(desc_chain) => desc_chain,
+            None => break None,
+        };
+        let reader = &mut desc_chain.reader;
+        let mut hdr = [0u8; VNET_HDR_LEN];
+        if reader.available_bytes() < HASH_HDR_LEN || reader.read_exact(&mut hdr).is_err() {
+            error!("net: tx: frame is shorter than its header");
+        } else {
+            // The hash report fields are only meaningful in received frames.
+            reader.consume(HASH_HDR_LEN - VNET_HDR_LEN);
+            let mut bufs = vec![VolatileSlice::new(&mut hdr)];
+            bufs.extend(reader.get_remaining());
+            let expected_count: usize = bufs.iter().map(|buf| buf.size()).sum();
+            // Tap writes must be done in one call.
+            let res = match capture.as_deref_mut() {
+                Some(capture) => CapturingTap::new(tap, capture).write_vectored_volatile(&bufs),
+                None => tap.write_vectored_volatile(&bufs),
+            };
+            match res {
+                Ok(count) => {
+                    if count != expected_count {
+                        error!(
+                            "net: tx: wrote only {} bytes of {} byte frame",
+                            count, expected_count
+                        );
+                    }
+                    if let Some(rate_limiter) = rate_limiter.as_deref_mut() {
+                        rate_limiter.consume(
+                            Direction::Tx,
+                            count.saturating_sub(VNET_HDR_LEN),
+                            Instant::now(),
+                        );
+                    }
+                }
+                Err(e) => error!("net: tx: failed to write frame to tap: {}", e),
+            }
+        }
+
+        tx_queue.add_used(desc_chain, 0);
+    };
+
+    tx_queue.trigger_interrupt(interrupt);
+    rate_limited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Key and hashes from the verification suite of Microsoft's RSS documentation.
+    const KEY: [u8; 40] = [
+        0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f,
+        0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30,
+        0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+    ];
+
+    fn ipv4_frame(protocol: u8, flags_offset: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&ETH_P_IP.to_be_bytes());
+        let mut ip = [0u8; 20];
+        ip[0] = 0x45;
+        ip[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+        ip[9] = protocol;
+        ip[12..16].copy_from_slice(&[66, 9, 149, 187]);
+        ip[16..20].copy_from_slice(&[161, 142, 100, 80]);
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&2794u16.to_be_bytes());
+        frame.extend_from_slice(&1766u16.to_be_bytes());
+        frame.resize(frame.len() + 16, 0);
+        frame
+    }
+
+    fn ipv6_frame(next_header: u8) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&ETH_P_IPV6.to_be_bytes());
+        let mut ip = [0u8; 40];
+        ip[0] = 0x60;
+        ip[6] = next_header;
+        ip[8..24].copy_from_slice(&[
+            0x3f, 0xfe, 0x25, 0x01, 0x02, 0x00, 0x1f, 0xff, 0, 0, 0, 0, 0, 0, 0, 0x07,
+        ]);
+        ip[24..40].copy_from_slice(&[
+            0x3f, 0xfe, 0x25, 0x01, 0x02, 0x00, 0x00, 0x03, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        ]);
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&2794u16.to_be_bytes());
+        frame.extend_from_slice(&1766u16.to_be_bytes());
+        frame.resize(frame.len() + 16, 0);
+        frame
+    }
+
+    fn hashing_config(hash_types: u32) -> RssConfig {
+        let mut config = RssConfig::default();
+        config.set_hashing(hash_types, KEY.to_vec());
+        config
+    }
+
+    #[test]
+    fn ipv4_hash() {
+        let config = hashing_config(SUPPORTED_HASH_TYPES);
+        assert_eq!(
+            config.hash(&ipv4_frame(IPPROTO_TCP, 0)),
+            Some(FrameHash {
+                value: 0x51ccc178,
+                report: virtio_net::VIRTIO_NET_HASH_REPORT_TCPv4 as u16,
+            })
+        );
+        assert_eq!(
+            config.hash(&ipv4_frame(IPPROTO_UDP, 0)),
+            Some(FrameHash {
+                value: 0x51ccc178,
+                report: virtio_net::VIRTIO_NET_HASH_REPORT_UDPv4 as u16,
+            })
+        );
+        // Fragments are hashed over their addresses only.
+        assert_eq!(
+            config.hash(&ipv4_frame(IPPROTO_TCP, 0x2000)),
+            Some(FrameHash {
+                value: 0x323e8fc2,
+                report: virtio_net::VIRTIO_NET_HASH_REPORT_IPv4 as u16,
+            })
+        );
+    }
+
+    #[test]
+    fn ipv6_hash() {
+        let config = hashing_config(SUPPORTED_HASH_TYPES);
+        assert_eq!(
+            config.hash(&ipv6_frame(IPPROTO_TCP)),
+            Some(FrameHash {
+                value: 0x40207d3d,
+                report: virtio_net::VIRTIO_NET_HASH_REPORT_TCPv6 as u16,
+            })
+        );
+        // A hop-by-hop options header.
+        assert_eq!(
+            config.hash(&ipv6_frame(0)),
+            Some(FrameHash {
+                value: 0x2cc18cd5,
+                report: virtio_net::VIRTIO_NET_HASH_REPORT_IPv6 as u16,
+            })
+        );
+    }
+
+    #[test]
+    fn hash_types() {
+        // Without its hash type, a TCP segment is hashed over its addresses.
+        let config = hashing_config(virtio_net::VIRTIO_NET_RSS_HASH_TYPE_IPv4);
+        assert_eq!(
+            config.hash(&ipv4_frame(IPPROTO_TCP, 0)),
+            Some(FrameHash {
+                value: 0x323e8fc2,
+                report: virtio_net::VIRTIO_NET_HASH_REPORT_IPv4 as u16,
+            })
+        );
+        assert_eq!(config.hash(&ipv6_frame(IPPROTO_TCP)), None);
+
+        let config = hashing_config(virtio_net::VIRTIO_NET_RSS_HASH_TYPE_TCPv4);
+        assert_eq!(config.hash(&ipv4_frame(IPPROTO_UDP, 0)), None);
+        // Not an IP packet.
+        let mut frame = ipv4_frame(IPPROTO_TCP, 0);
+        frame[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(config.hash(&frame), None);
+    }
+
+    #[test]
+    fn vlan_tag() {
+        let config = hashing_config(SUPPORTED_HASH_TYPES);
+        let mut frame = ipv4_frame(IPPROTO_TCP, 0);
+        frame.splice(12..12, [0x81, 0x00, 0x00, 0x05]);
+        assert_eq!(config.hash(&frame).map(|hash| hash.value), Some(0x51ccc178));
+    }
+
+    #[test]
+    fn steering() {
+        let mut config = RssConfig::default();
+        assert_eq!(config.queue(config.hash(&ipv4_frame(IPPROTO_TCP, 0))), 0);
+
+        config.set_steering(SUPPORTED_HASH_TYPES, KEY.to_vec(), vec![0, 1, 2, 3], 2);
+        // 0x51ccc178 & 3 == 0, 0x323e8fc2 & 3 == 2.
+        assert_eq!(config.queue(config.hash(&ipv4_frame(IPPROTO_TCP, 0))), 0);
+        assert_eq!(config.queue(config.hash(&ipv4_frame(IPPROTO_TCP, 0x1))), 2);
+        let mut frame = ipv4_frame(IPPROTO_TCP, 0);
+        frame[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(config.queue(config.hash(&frame)), 2);
+
+        config.set_steering(SUPPORTED_HASH_TYPES, KEY.to_vec(), vec![1, 3], 0);
+        assert_eq!(config.queue(config.hash(&ipv4_frame(IPPROTO_TCP, 0))), 1);
+        assert_eq!(config.queue(config.hash(&ipv6_frame(IPPROTO_TCP))), 3);
+    }
+}
@@ -4,21 +4,39 @@
 
 use std::io;
 use std::result;
+use std::time::Duration;
+use std::time::Instant;
 
 use base::error;
 use base::warn;
 use base::EventType;
+use base::FileReadWriteVolatile;
 use base::ReadNotifier;
+use base::VolatileSlice;
 use base::WaitContext;
+use net_util::MacAddress;
 use net_util::TapT;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_net;
 use virtio_sys::virtio_net::virtio_net_hdr_v1;
 
+use super::super::super::net::pcap::VNET_HDR_LEN;
+use super::super::super::net::CapturingTap;
+use super::super::super::net::CtrlState;
+use super::super::super::net::Direction;
+use super::super::super::net::FilteringTap;
+use super::super::super::net::Net;
 use super::super::super::net::NetError;
+use super::super::super::net::PcapWriter;
+use super::super::super::net::RateLimiter;
+use super::super::super::net::RxFilter;
+use super::super::super::net::SoftwareRss;
 use super::super::super::net::Token;
 use super::super::super::net::Worker;
+use super::super::super::net::CTRL_FEATURES;
 use super::super::super::Interrupt;
 use super::super::super::Queue;
+use crate::PciAddress;
 
 // Ensure that the tap interface has the correct flags and sets the offload and VNET header size
 // to the appropriate values.
@@ -85,12 +103,21 @@ pub fn process_rx<T: TapT>(
     interrupt: &Interrupt,
     rx_queue: &mut Queue,
     mut tap: &mut T,
+    mut capture: Option<&mut PcapWriter>,
+    rx_filter: Option<&RxFilter>,
+    mut rate_limiter: Option<&mut RateLimiter>,
 ) -> result::Result<(), NetError> {
     let mut needs_interrupt = false;
     let mut exhausted_queue = false;
+    let mut rate_limited = None;
 
     // Read as many frames as possible.
     loop {
+        if let Some(wait) = rate_limit_wait(rate_limiter.as_deref_mut(), Direction::Rx) {
+            rate_limited = Some(wait);
+            break;
+        }
+
         let mut desc_chain = match rx_queue.peek() {
             Some(desc) => desc,
             None => {
@@ -100,8 +127,22 @@ pub fn process_rx<T: TapT>(
         };
 
         let writer = &mut desc_chain.writer;
+        let count = writer.available_bytes();
 
-        match writer.write_from(&mut tap, writer.available_bytes()) {
+        // Frames rejected by the filter are read as empty, leaving the descriptor available for
+        // the next frame.
+        let res = match (capture.as_deref_mut(), rx_filter) {
+            (Some(capture), Some(rx_filter)) => writer.write_from(
+                FilteringTap::new(CapturingTap::new(tap, capture), rx_filter),
+                count,
+            ),
+            (Some(capture), None) => writer.write_from(CapturingTap::new(tap, capture), count),
+            (None, Some(rx_filter)) => {
+                writer.write_from(FilteringTap::new(&mut tap, rx_filter), count)
+            }
+            (None, None) => writer.write_from(&mut tap, count),
+        };
+        match res {
             Ok(_) => {}
             Err(ref e) if e.kind() == io::ErrorKind::WriteZero => {
                 warn!("net: rx: buffer is too small to hold frame");
@@ -124,6 +165,13 @@ pub fn process_rx<T: TapT>(
             let desc_chain = desc_chain.pop();
             rx_queue.add_used(desc_chain, bytes_written);
             needs_interrupt = true;
+            if let Some(rate_limiter) = rate_limiter.as_deref_mut() {
+                rate_limiter.consume(
+                    Direction::Rx,
+                    (bytes_written as usize).saturating_sub(VNET_HDR_LEN),
+                    Instant::now(),
+                );
+            }
         }
     }
 
@@ -133,16 +181,121 @@ pub fn process_rx<T: TapT>(
 
     if exhausted_queue {
         Err(NetError::RxDescriptorsExhausted)
+    } else if let Some(wait) = rate_limited {
+        Err(NetError::RxRateLimited(wait))
     } else {
         Ok(())
     }
 }
 
-pub fn process_tx<T: TapT>(interrupt: &Interrupt, tx_queue: &mut Queue, mut tap: &mut T) {
-    while let Some(mut desc_chain) = tx_queue.pop() {
+// Returns how long to wait before the next frame going in `direction`, if the rate limit was
+// reached.
+fn rate_limit_wait(
+    rate_limiter: Option<&mut RateLimiter>,
+    direction: Direction,
+) -> Option<Duration> {
+    rate_limiter.and_then(|rate_limiter| rate_limiter.wait_time(direction, Instant::now()))
+}
+
+/// Receives the frames of `tap` in the queue of `rx_queues` they are steered to by `software_rss`.
+fn process_rx_steered<T: TapT>(
+    interrupt: &Interrupt,
+    rx_queues: &mut [&mut Queue],
+    tap: &mut T,
+    mut capture: Option<&mut PcapWriter>,
+    ctrl_state: &CtrlState,
+    software_rss: &mut SoftwareRss,
+    mut rate_limiter: Option<&mut RateLimiter>,
+) -> result::Result<(), NetError> {
+    let mut needs_interrupt = vec![false; rx_queues.len()];
+    let rx_filter = ctrl_state.active_rx_filter();
+
+    let result = loop {
+        // A frame that was read before its queue ran out of buffers is received first.
+        let queue = match software_rss.pending_queue() {
+            Some(queue) => queue,
+            None => {
+                if let Some(wait) = rate_limit_wait(rate_limiter.as_deref_mut(), Direction::Rx) {
+                    break Err(NetError::RxRateLimited(wait));
+                }
+                let slice = VolatileSlice::new(software_rss.buf_mut());
+                let res = match capture.as_deref_mut() {
+                    Some(capture) => CapturingTap::new(tap, capture).read_volatile(slice),
+                    None => tap.read_volatile(slice),
+                };
+                let len = match res {
+                    Ok(0) => break Ok(()),
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                    Err(e) => break Err(NetError::ReadTap(e)),
+                };
+                if let Some(rx_filter) = &rx_filter {
+                    if !rx_filter.accepts(software_rss.frame(len)) {
+                        continue;
+                    }
+                }
+                if let Some(rate_limiter) = rate_limiter.as_deref_mut() {
+                    rate_limiter.consume(
+                        Direction::Rx,
+                        len.saturating_sub(VNET_HDR_LEN),
+                        Instant::now(),
+                    );
+                }
+                software_rss.steer(len, &ctrl_state.rss.lock(), rx_queues.len());
+                software_rss.pending_queue().unwrap()
+            }
+        };
+
+        let mut desc_chain = match rx_queues[queue].pop() {
+            Some(desc) => desc,
+            None => break Err(NetError::RxDescriptorsExhausted),
+        };
+        let bytes_written = match software_rss.write_frame(&mut desc_chain.writer) {
+            Ok(count) => count as u32,
+            Err(e) => {
+                warn!("net: rx: failed to write frame: {}", e);
+                0
+            }
+        };
+        cros_tracing::trace_simple_print!("{bytes_written} bytes read from tap");
+        rx_queues[queue].add_used(desc_chain, bytes_written);
+        needs_interrupt[queue] = true;
+    };
+
+    for (rx_queue, needs_interrupt) in rx_queues.iter_mut().zip(needs_interrupt) {
+        if needs_interrupt {
+            rx_queue.trigger_interrupt(interrupt);
+        }
+    }
+    result
+}
+
+/// Transmits the frames of `tx_queue` to `tap`.
+///
+/// Returns how long to wait before transmitting the remaining frames if `rate_limiter` stopped the
+/// transmission.
+pub fn process_tx<T: TapT>(
+    interrupt: &Interrupt,
+    tx_queue: &mut Queue,
+    mut tap: &mut T,
+    mut capture: Option<&mut PcapWriter>,
+    mut rate_limiter: Option<&mut RateLimiter>,
+) -> Option<Duration> {
+    let rate_limited = loop {
+        if let Some(wait) = rate_limit_wait(rate_limiter.as_deref_mut(), Direction::Tx) {
+            break Some(wait);
+        }
+        let mut desc_chain = match tx_queue.pop() {
+            Some(desc_chain) => desc_chain,
+            None => break None,
+        };
         let reader = &mut desc_chain.reader;
         let expected_count = reader.available_bytes();
-        match reader.read_to(&mut tap, expected_count) {
+        let res = match capture.as_deref_mut() {
+            Some(capture) => reader.read_to(CapturingTap::new(tap, capture), expected_count),
+            None => reader.read_to(&mut tap, expected_count),
+        };
+        match res {
             Ok(count) => {
                 // Tap writes must be done in one call. If the entire frame was not
                 // written, it's an error.
@@ -153,14 +306,22 @@ pub fn process_tx<T: TapT>(interrupt: &Interrupt, tx_queue: &mut Queue, mut tap:
                     );
                 }
                 cros_tracing::trace_simple_print!("{count} bytes write to tap");
+                if let Some(rate_limiter) = rate_limiter.as_deref_mut() {
+                    rate_limiter.consume(
+                        Direction::Tx,
+                        count.saturating_sub(VNET_HDR_LEN),
+                        Instant::now(),
+                    );
+                }
             }
             Err(e) => error!("net: tx: failed to write frame to tap: {}", e),
         }
 
         tx_queue.add_used(desc_chain, 0);
-    }
+    };
 
     tx_queue.trigger_interrupt(interrupt);
+    rate_limited
 }
 
 impl<T> Worker<T>
@@ -179,6 +340,12 @@ where
                     .map_err(NetError::WaitContextDisableTap)?;
                 Ok(())
             }
+            Err(NetError::RxRateLimited(wait)) => {
+                wait_ctx
+                    .modify(&self.tap, EventType::None, Token::RxTap)
+                    .map_err(NetError::WaitContextDisableTap)?;
+                self.arm_rate_limit_timer(wait_ctx, wait)
+            }
             Err(e) => Err(e),
         }
     }
@@ -187,6 +354,20 @@ where
         wait_ctx: &WaitContext<Token>,
         tap_polling_enabled: bool,
     ) -> result::Result<(), NetError> {
+        // A frame steered to a queue without buffers may wait for this one, while the tap has
+        // nothing more to read.
+        if self
+            .software_rss
+            .as_ref()
+            .and_then(SoftwareRss::pending_queue)
+            .is_some()
+        {
+            match self.process_rx() {
+                Ok(()) | Err(NetError::RxDescriptorsExhausted) => {}
+                Err(NetError::RxRateLimited(wait)) => self.arm_rate_limit_timer(wait_ctx, wait)?,
+                Err(e) => return Err(e),
+            }
+        }
         if !tap_polling_enabled {
             wait_ctx
                 .modify(&self.tap, EventType::Read, Token::RxTap)
@@ -195,6 +376,61 @@ where
         Ok(())
     }
     pub(super) fn process_rx(&mut self) -> result::Result<(), NetError> {
-        process_rx(&self.interrupt, &mut self.rx_queue, &mut self.tap)
+        let mut capture = self.capture.lock();
+        let mut rate_limiter = self.rate_limiter.lock();
+        if let Some(software_rss) = &mut self.software_rss {
+            let mut rx_queues: Vec<&mut Queue> = std::iter::once(&mut self.rx_queue)
+                .chain(self.other_pairs.iter_mut().map(|(rx_queue, _)| rx_queue))
+                .collect();
+            return process_rx_steered(
+                &self.interrupt,
+                &mut rx_queues,
+                &mut self.tap,
+                capture.writer(),
+                &self.ctrl_state,
+                software_rss,
+                rate_limiter.limiter(),
+            );
+        }
+        let rx_filter = self.ctrl_state.active_rx_filter();
+        process_rx(
+            &self.interrupt,
+            &mut self.rx_queue,
+            &mut self.tap,
+            capture.writer(),
+            rx_filter.as_deref(),
+            rate_limiter.limiter(),
+        )
+    }
+}
+
+impl<T> Net<T>
+where
+    T: TapT + ReadNotifier,
+{
+    /// Creates a new virtio network device from a backend exchanging complete frames, such as
+    /// libslirp or a socket. Offloads are not advertised since such backends only handle complete,
+    /// checksummed frames.
+    pub fn new_without_offload(
+        base_features: u64,
+        tap: T,
+        mac_addr: Option<MacAddress>,
+        use_packed_queue: bool,
+        pci_address: Option<PciAddress>,
+    ) -> Result<Net<T>, NetError> {
+        validate_and_configure_tap(&tap, 1)?;
+        let mtu = tap.mtu().map_err(NetError::TapGetMtu)?;
+
+        let mut avail_features = base_features | CTRL_FEATURES | 1 << virtio_net::VIRTIO_NET_F_MTU;
+
+        if use_packed_queue {
+            avail_features |= 1 << VIRTIO_F_RING_PACKED;
+        }
+
+        if mac_addr.is_some() {
+            avail_features |= 1 << virtio_net::VIRTIO_NET_F_MAC;
+        }
+
+        Self::new_internal(vec![tap], 1, avail_features, mtu, mac_addr, pci_address)
     }
 }
@@ -8,6 +8,8 @@ use std::io::Write;
 use std::result;
 use std::sync::Arc;
 use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
 
 use base::error;
 use base::named_pipes::OverlappedWrapper;
@@ -22,8 +24,13 @@ use virtio_sys::virtio_net;
 use vm_memory::GuestMemory;
 
 use super::super::super::base_features;
+use super::super::super::net::pcap::VNET_HDR_LEN;
+use super::super::super::net::Direction;
 use super::super::super::net::Net;
 use super::super::super::net::NetError;
+use super::super::super::net::PcapWriter;
+use super::super::super::net::RateLimiter;
+use super::super::super::net::RxFilter;
 use super::super::super::net::Token;
 use super::super::super::net::Worker;
 use super::super::super::net::MAX_BUFFER_SIZE;
@@ -84,6 +91,8 @@ pub fn process_rx<T: TapT>(
     deferred_rx: &mut bool,
     rx_count: &mut usize,
     overlapped_wrapper: &mut OverlappedWrapper,
+    mut capture: Option<&mut PcapWriter>,
+    rx_filter: Option<&RxFilter>,
 ) -> bool {
     let mut needs_interrupt = false;
     let mut first_frame = true;
@@ -98,8 +107,19 @@ pub fn process_rx<T: TapT>(
         };
         match res {
             Ok(count) => {
+                let mut accepted = true;
+                if !*deferred_rx {
+                    if let Some(capture) = capture.as_deref_mut() {
+                        capture.write_frame(Direction::Rx, &rx_buf[..count]);
+                    }
+                    if let Some(rx_filter) = rx_filter {
+                        accepted = rx_filter.accepts(&rx_buf[..count]);
+                    }
+                }
                 *rx_count = count;
-                if !rx_single_frame(rx_queue, rx_buf, *rx_count) {
+                if !accepted {
+                    // Drop the frame and read the next one.
+                } else if !rx_single_frame(rx_queue, rx_buf, *rx_count) {
                     *deferred_rx = true;
                     break;
                 } else if first_frame {
@@ -155,7 +175,17 @@ pub fn process_rx<T: TapT>(
     needs_interrupt
 }
 
-pub fn process_tx<T: TapT>(interrupt: &Interrupt, tx_queue: &mut Queue, tap: &mut T) {
+/// Transmits the frames of `tx_queue` to `tap`.
+///
+/// Returns how long to wait before transmitting the remaining frames if `rate_limiter` stopped the
+/// transmission.
+pub fn process_tx<T: TapT>(
+    interrupt: &Interrupt,
+    tx_queue: &mut Queue,
+    tap: &mut T,
+    mut capture: Option<&mut PcapWriter>,
+    mut rate_limiter: Option<&mut RateLimiter>,
+) -> Option<Duration> {
     // Reads up to `buf.len()` bytes or until there is no more data in `r`, whichever
     // is smaller.
     fn read_to_end(r: &mut Reader, buf: &mut [u8]) -> io::Result<usize> {
@@ -171,23 +201,46 @@ pub fn process_tx<T: TapT>(interrupt: &Interrupt, tx_queue: &mut Queue, tap: &mu
         Ok(count)
     }
 
-    while let Some(mut desc_chain) = tx_queue.pop() {
+    let rate_limited = loop {
+        if let Some(wait) = rate_limiter
+            .as_deref_mut()
+            .and_then(|rate_limiter| rate_limiter.wait_time(Direction::Tx, Instant::now()))
+        {
+            break Some(wait);
+        }
+        let mut desc_chain = match tx_queue.pop() {
+            Some(desc_chain) => desc_chain,
+            None => break None,
+        };
         let mut frame = [0u8; MAX_BUFFER_SIZE];
         match read_to_end(&mut desc_chain.reader, &mut frame[..]) {
             Ok(len) => {
                 // We need to copy frame into continuous buffer before writing it to
                 // slirp because tap requires frame to complete in a single write.
-                if let Err(err) = tap.write_all(&frame[..len]) {
-                    error!("net: tx: failed to write to tap: {}", err);
+                match tap.write_all(&frame[..len]) {
+                    Ok(()) => {
+                        if let Some(capture) = capture.as_deref_mut() {
+                            capture.write_frame(Direction::Tx, &frame[..len]);
+                        }
+                        if let Some(rate_limiter) = rate_limiter.as_deref_mut() {
+                            rate_limiter.consume(
+                                Direction::Tx,
+                                len.saturating_sub(VNET_HDR_LEN),
+                                Instant::now(),
+                            );
+                        }
+                    }
+                    Err(err) => error!("net: tx: failed to write to tap: {}", err),
                 }
             }
             Err(e) => error!("net: tx: failed to read frame into buffer: {}", e),
         }
 
         tx_queue.add_used(desc_chain, 0);
-    }
+    };
 
     tx_queue.trigger_interrupt(interrupt);
+    rate_limited
 }
 
 impl<T> Worker<T>
@@ -195,6 +248,8 @@ where
     T: TapT + ReadNotifier,
 {
     pub(super) fn process_rx_slirp(&mut self) -> bool {
+        let mut capture = self.capture.lock();
+        let rx_filter = self.ctrl_state.active_rx_filter();
         process_rx(
             &self.interrupt,
             &mut self.rx_queue,
@@ -203,6 +258,8 @@ where
             &mut self.deferred_rx,
             &mut self.rx_count,
             &mut self.overlapped_wrapper,
+            capture.writer(),
+            rx_filter.as_deref(),
         )
     }
 
@@ -5,6 +5,7 @@
 use std::cmp;
 use std::io::Read;
 use std::io::Write;
+use std::mem::size_of;
 
 use base::warn;
 use data_model::Be16;
@@ -14,26 +15,58 @@ use zerocopy::AsBytes;
 use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
+use crate::virtio::scsi::constants::GET_CONFIGURATION;
+use crate::virtio::scsi::constants::GET_EVENT_STATUS_NOTIFICATION;
 use crate::virtio::scsi::constants::INQUIRY;
 use crate::virtio::scsi::constants::MAINTENANCE_IN;
 use crate::virtio::scsi::constants::MODE_SELECT_6;
 use crate::virtio::scsi::constants::MODE_SENSE_6;
+use crate::virtio::scsi::constants::PERSISTENT_RESERVE_IN;
+use crate::virtio::scsi::constants::PERSISTENT_RESERVE_OUT;
+use crate::virtio::scsi::constants::PREVENT_ALLOW_MEDIUM_REMOVAL;
+use crate::virtio::scsi::constants::PR_CLEAR;
+use crate::virtio::scsi::constants::PR_PREEMPT;
+use crate::virtio::scsi::constants::PR_PREEMPT_AND_ABORT;
+use crate::virtio::scsi::constants::PR_READ_KEYS;
+use crate::virtio::scsi::constants::PR_READ_RESERVATION;
+use crate::virtio::scsi::constants::PR_REGISTER;
+use crate::virtio::scsi::constants::PR_REGISTER_AND_IGNORE_EXISTING_KEY;
+use crate::virtio::scsi::constants::PR_RELEASE;
+use crate::virtio::scsi::constants::PR_REPORT_CAPABILITIES;
+use crate::virtio::scsi::constants::PR_RESERVE;
 use crate::virtio::scsi::constants::READ_10;
+use crate::virtio::scsi::constants::READ_12;
+use crate::virtio::scsi::constants::READ_16;
 use crate::virtio::scsi::constants::READ_6;
 use crate::virtio::scsi::constants::READ_CAPACITY_10;
 use crate::virtio::scsi::constants::READ_CAPACITY_16;
+use crate::virtio::scsi::constants::READ_TOC;
 use crate::virtio::scsi::constants::REPORT_LUNS;
+use crate::virtio::scsi::constants::REPORT_SUPPORTED_OPERATION_CODES;
 use crate::virtio::scsi::constants::REPORT_SUPPORTED_TASK_MANAGEMENT_FUNCTIONS;
 use crate::virtio::scsi::constants::SERVICE_ACTION_IN_16;
+use crate::virtio::scsi::constants::START_STOP_UNIT;
 use crate::virtio::scsi::constants::SYNCHRONIZE_CACHE_10;
 use crate::virtio::scsi::constants::TEST_UNIT_READY;
 use crate::virtio::scsi::constants::TYPE_DISK;
+use crate::virtio::scsi::constants::TYPE_ROM;
 use crate::virtio::scsi::constants::UNMAP;
+use crate::virtio::scsi::constants::VERIFY_10;
+use crate::virtio::scsi::constants::VERIFY_12;
+use crate::virtio::scsi::constants::VERIFY_16;
 use crate::virtio::scsi::constants::WRITE_10;
+use crate::virtio::scsi::constants::WRITE_12;
+use crate::virtio::scsi::constants::WRITE_16;
+use crate::virtio::scsi::constants::WRITE_AND_VERIFY_10;
+use crate::virtio::scsi::constants::WRITE_AND_VERIFY_12;
+use crate::virtio::scsi::constants::WRITE_AND_VERIFY_16;
 use crate::virtio::scsi::constants::WRITE_SAME_10;
 use crate::virtio::scsi::constants::WRITE_SAME_16;
 use crate::virtio::scsi::device::AsyncLogicalUnit;
 use crate::virtio::scsi::device::ExecuteError;
+use crate::virtio::scsi::device::MediaEvent;
+use crate::virtio::scsi::reservation;
+use crate::virtio::scsi::reservation::Access;
 use crate::virtio::Reader;
 use crate::virtio::Writer;
 
@@ -44,16 +77,34 @@ pub enum Command {
     Inquiry(Inquiry),
     ModeSelect6(ModeSelect6),
     ModeSense6(ModeSense6),
+    StartStopUnit(StartStopUnit),
+    PreventAllowMediumRemoval(PreventAllowMediumRemoval),
     ReadCapacity10(ReadCapacity10),
     ReadCapacity16(ReadCapacity16),
     Read10(Read10),
     Write10(Write10),
+    WriteAndVerify10(WriteAndVerify10),
+    Verify10(Verify10),
     SynchronizeCache10(SynchronizeCache10),
     WriteSame10(WriteSame10),
     Unmap(Unmap),
+    ReadToc(ReadToc),
+    GetConfiguration(GetConfiguration),
+    GetEventStatusNotification(GetEventStatusNotification),
+    PersistentReserveIn(PersistentReserveIn),
+    PersistentReserveOut(PersistentReserveOut),
+    Read16(Read16),
+    Write16(Write16),
+    WriteAndVerify16(WriteAndVerify16),
+    Verify16(Verify16),
     WriteSame16(WriteSame16),
     ReportLuns(ReportLuns),
+    ReportSupportedOpcodes(ReportSupportedOpcodes),
     ReportSupportedTMFs(ReportSupportedTMFs),
+    Read12(Read12),
+    Write12(Write12),
+    WriteAndVerify12(WriteAndVerify12),
+    Verify12(Verify12),
 }
 
 impl Command {
@@ -65,16 +116,37 @@ impl Command {
             INQUIRY => Ok(Self::Inquiry(Self::parse_command(cdb)?)),
             MODE_SELECT_6 => Ok(Self::ModeSelect6(Self::parse_command(cdb)?)),
             MODE_SENSE_6 => Ok(Self::ModeSense6(Self::parse_command(cdb)?)),
+            START_STOP_UNIT => Ok(Self::StartStopUnit(Self::parse_command(cdb)?)),
+            PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                Ok(Self::PreventAllowMediumRemoval(Self::parse_command(cdb)?))
+            }
             READ_CAPACITY_10 => Ok(Self::ReadCapacity10(Self::parse_command(cdb)?)),
             READ_10 => Ok(Self::Read10(Self::parse_command(cdb)?)),
             WRITE_10 => Ok(Self::Write10(Self::parse_command(cdb)?)),
+            WRITE_AND_VERIFY_10 => Ok(Self::WriteAndVerify10(Self::parse_command(cdb)?)),
+            VERIFY_10 => Ok(Self::Verify10(Self::parse_command(cdb)?)),
             SYNCHRONIZE_CACHE_10 => Ok(Self::SynchronizeCache10(Self::parse_command(cdb)?)),
             WRITE_SAME_10 => Ok(Self::WriteSame10(Self::parse_command(cdb)?)),
             UNMAP => Ok(Self::Unmap(Self::parse_command(cdb)?)),
+            READ_TOC => Ok(Self::ReadToc(Self::parse_command(cdb)?)),
+            GET_CONFIGURATION => Ok(Self::GetConfiguration(Self::parse_command(cdb)?)),
+            GET_EVENT_STATUS_NOTIFICATION => {
+                Ok(Self::GetEventStatusNotification(Self::parse_command(cdb)?))
+            }
+            PERSISTENT_RESERVE_IN => Ok(Self::PersistentReserveIn(Self::parse_command(cdb)?)),
+            PERSISTENT_RESERVE_OUT => Ok(Self::PersistentReserveOut(Self::parse_command(cdb)?)),
+            READ_16 => Ok(Self::Read16(Self::parse_command(cdb)?)),
+            WRITE_16 => Ok(Self::Write16(Self::parse_command(cdb)?)),
+            WRITE_AND_VERIFY_16 => Ok(Self::WriteAndVerify16(Self::parse_command(cdb)?)),
+            VERIFY_16 => Ok(Self::Verify16(Self::parse_command(cdb)?)),
             WRITE_SAME_16 => Ok(Self::WriteSame16(Self::parse_command(cdb)?)),
             SERVICE_ACTION_IN_16 => Self::parse_service_action_in_16(cdb),
             REPORT_LUNS => Ok(Self::ReportLuns(Self::parse_command(cdb)?)),
             MAINTENANCE_IN => Self::parse_maintenance_in(cdb),
+            READ_12 => Ok(Self::Read12(Self::parse_command(cdb)?)),
+            WRITE_12 => Ok(Self::Write12(Self::parse_command(cdb)?)),
+            WRITE_AND_VERIFY_12 => Ok(Self::WriteAndVerify12(Self::parse_command(cdb)?)),
+            VERIFY_12 => Ok(Self::Verify12(Self::parse_command(cdb)?)),
             _ => {
                 warn!("SCSI command {:#x?} is not implemented", op);
                 Err(ExecuteError::Unsupported(op))
@@ -92,6 +164,11 @@ impl Command {
         // Top three bits are reserved.
         let service_action = cdb[1] & 0x1f;
         match service_action {
+            REPORT_SUPPORTED_OPERATION_CODES => {
+                let r = ReportSupportedOpcodes::read_from(&cdb[..MAINTENANCE_IN_SIZE])
+                    .ok_or(ExecuteError::ReadCommand)?;
+                Ok(Self::ReportSupportedOpcodes(r))
+            }
             REPORT_SUPPORTED_TASK_MANAGEMENT_FUNCTIONS => {
                 let r = ReportSupportedTMFs::read_from(&cdb[..MAINTENANCE_IN_SIZE])
                     .ok_or(ExecuteError::ReadCommand)?;
@@ -127,32 +204,107 @@ impl Command {
         }
     }
 
+    /// Returns whether a pending unit attention condition should be reported instead of executing
+    /// the command. These commands neither report nor clear it.
+    pub fn reports_unit_attention(&self) -> bool {
+        !matches!(
+            self,
+            Self::Inquiry(_)
+                | Self::ReportLuns(_)
+                | Self::GetConfiguration(_)
+                | Self::GetEventStatusNotification(_)
+        )
+    }
+
+    /// Returns how the command accesses the medium, if persistent reservations can prevent it.
+    fn access(&self) -> Option<Access> {
+        match self {
+            Self::Read6(_)
+            | Self::Read10(_)
+            | Self::Read12(_)
+            | Self::Read16(_)
+            | Self::Verify10(_)
+            | Self::Verify12(_)
+            | Self::Verify16(_) => Some(Access::Read),
+            Self::ModeSelect6(_)
+            | Self::Write10(_)
+            | Self::Write12(_)
+            | Self::Write16(_)
+            | Self::WriteAndVerify10(_)
+            | Self::WriteAndVerify12(_)
+            | Self::WriteAndVerify16(_)
+            | Self::WriteSame10(_)
+            | Self::WriteSame16(_)
+            | Self::Unmap(_)
+            | Self::SynchronizeCache10(_) => Some(Access::Write),
+            _ => None,
+        }
+    }
+
     pub async fn execute(
         &self,
         reader: &mut Reader,
         writer: &mut Writer,
         dev: &AsyncLogicalUnit,
     ) -> Result<(), ExecuteError> {
+        if let (Some(access), Some(reservations)) = (self.access(), &dev.reservations) {
+            reservations.check_access(access)?;
+        }
         match self {
-            Self::TestUnitReady(_) => Ok(()), // noop as the device is ready.
+            // The device is ready as long as there is a medium.
+            Self::TestUnitReady(_) => dev.medium().map(|_| ()),
             Self::Read6(read6) => read6.emulate(writer, dev).await,
             Self::Inquiry(inquiry) => inquiry.emulate(writer, dev),
             Self::ModeSelect6(mode_select_6) => mode_select_6.emulate(reader, dev),
             Self::ModeSense6(mode_sense_6) => mode_sense_6.emulate(writer, dev),
+            Self::StartStopUnit(start_stop_unit) => start_stop_unit.emulate(dev),
+            Self::PreventAllowMediumRemoval(prevent_allow_medium_removal) => {
+                prevent_allow_medium_removal.emulate(dev)
+            }
             Self::ReadCapacity10(read_capacity_10) => read_capacity_10.emulate(writer, dev),
             Self::ReadCapacity16(read_capacity_16) => read_capacity_16.emulate(writer, dev),
             Self::Read10(read_10) => read_10.emulate(writer, dev).await,
             Self::Write10(write_10) => write_10.emulate(reader, dev).await,
+            Self::WriteAndVerify10(write_and_verify_10) => {
+                write_and_verify_10.emulate(reader, dev).await
+            }
+            Self::Verify10(verify_10) => verify_10.emulate(reader, dev).await,
             Self::SynchronizeCache10(synchronize_cache_10) => {
                 synchronize_cache_10.emulate(dev).await
             }
             Self::WriteSame10(write_same_10) => write_same_10.emulate(reader, dev).await,
             Self::Unmap(unmap) => unmap.emulate(reader, dev).await,
+            Self::ReadToc(read_toc) => read_toc.emulate(writer, dev),
+            Self::GetConfiguration(get_configuration) => get_configuration.emulate(writer, dev),
+            Self::GetEventStatusNotification(get_event_status_notification) => {
+                get_event_status_notification.emulate(writer, dev)
+            }
+            Self::PersistentReserveIn(persistent_reserve_in) => {
+                persistent_reserve_in.emulate(writer, dev)
+            }
+            Self::PersistentReserveOut(persistent_reserve_out) => {
+                persistent_reserve_out.emulate(reader, dev)
+            }
+            Self::Read16(read_16) => read_16.emulate(writer, dev).await,
+            Self::Write16(write_16) => write_16.emulate(reader, dev).await,
+            Self::WriteAndVerify16(write_and_verify_16) => {
+                write_and_verify_16.emulate(reader, dev).await
+            }
+            Self::Verify16(verify_16) => verify_16.emulate(reader, dev).await,
             Self::WriteSame16(write_same_16) => write_same_16.emulate(reader, dev).await,
             Self::ReportLuns(report_luns) => report_luns.emulate(writer),
+            Self::ReportSupportedOpcodes(report_supported_opcodes) => {
+                report_supported_opcodes.emulate(writer, dev)
+            }
             Self::ReportSupportedTMFs(report_supported_tmfs) => {
                 report_supported_tmfs.emulate(writer)
             }
+            Self::Read12(read_12) => read_12.emulate(writer, dev).await,
+            Self::Write12(write_12) => write_12.emulate(reader, dev).await,
+            Self::WriteAndVerify12(write_and_verify_12) => {
+                write_and_verify_12.emulate(reader, dev).await
+            }
+            Self::Verify12(verify_12) => verify_12.emulate(reader, dev).await,
         }
     }
 }
@@ -165,11 +317,19 @@ pub struct TestUnitReady {
     control: u8,
 }
 
+fn peripheral_device_type(dev: &AsyncLogicalUnit) -> u8 {
+    if dev.cdrom.is_some() {
+        TYPE_ROM
+    } else {
+        TYPE_DISK
+    }
+}
+
 fn check_lba_range(max_lba: u64, sector_num: u64, sector_len: usize) -> Result<(), ExecuteError> {
-    // Checking `sector_num + sector_len - 1 <= max_lba`, but we are being careful about overflows
-    // and underflows.
+    // `max_lba` is the number of blocks of the device, so we are checking
+    // `sector_num + sector_len <= max_lba`, but we are being careful about overflows.
     match sector_num.checked_add(sector_len as u64) {
-        Some(v) if v <= max_lba + 1 => Ok(()),
+        Some(v) if v <= max_lba => Ok(()),
         _ => Err(ExecuteError::LbaOutOfRange {
             length: sector_len,
             sector: sector_num,
@@ -184,13 +344,14 @@ async fn read_from_disk(
     xfer_blocks: usize,
     lba: u64,
 ) -> Result<(), ExecuteError> {
+    let disk_image = dev.medium()?;
     check_lba_range(dev.max_lba, lba, xfer_blocks)?;
     let block_size = dev.block_size;
     let count = xfer_blocks * block_size as usize;
     let offset = lba * block_size as u64;
     let before = writer.bytes_written();
     writer
-        .write_all_from_at_fut(&*dev.disk_image, count, offset)
+        .write_all_from_at_fut(disk_image, count, offset)
         .await
         .map_err(|desc_error| {
             let resid = count - (writer.bytes_written() - before);
@@ -274,9 +435,9 @@ impl Inquiry {
         let alloc_len = self.alloc_len();
         let mut outbuf = vec![0u8; cmp::max(writer.available_bytes(), alloc_len)];
         // Peripheral
-        outbuf[0] = TYPE_DISK;
-        // Removable bit. We currently do not support removable SCSI devices.
-        outbuf[1] = 0x0;
+        outbuf[0] = peripheral_device_type(dev);
+        // Removable bit. Only CD-ROM drives have removable media.
+        outbuf[1] = if dev.cdrom.is_some() { 0x80 } else { 0x0 };
         // Version 0x5 indicates that the device complies to SPC-3.
         outbuf[2] = 0x5;
         // Hierarchical Support | Response Data Format
@@ -294,7 +455,12 @@ impl Inquiry {
         // Vendor
         Self::fill_left_aligned_ascii(&mut outbuf[8..16], "CROSVM");
         // Product ID
-        Self::fill_left_aligned_ascii(&mut outbuf[16..32], "CROSVM HARDDISK");
+        let product_id = if dev.cdrom.is_some() {
+            "CROSVM CD-ROM"
+        } else {
+            "CROSVM HARDDISK"
+        };
+        Self::fill_left_aligned_ascii(&mut outbuf[16..32], product_id);
         // Product revision level
         Self::fill_left_aligned_ascii(&mut outbuf[32..36], "0.1");
 
@@ -311,7 +477,7 @@ impl Inquiry {
         let alloc_len = self.alloc_len();
         let mut outbuf = vec![0u8; cmp::max(4096, alloc_len)];
         // Peripheral
-        outbuf[0] = TYPE_DISK;
+        outbuf[0] = peripheral_device_type(dev);
         let page_code = self.page_code();
         outbuf[1] = page_code;
         match page_code {
@@ -321,8 +487,9 @@ impl Inquiry {
                 // 0x00: Supported VPD Pages (this command)
                 // 0x83: Device Identification
                 // 0xb0: Block Limits
+                // 0xb1: Block Device Characteristics
                 // 0xb2: Logical Block Provisioning
-                const SUPPORTED_VPD_PAGE_CODES: [u8; 4] = [0x00, 0x83, 0xb0, 0xb2];
+                const SUPPORTED_VPD_PAGE_CODES: [u8; 5] = [0x00, 0x83, 0xb0, 0xb1, 0xb2];
                 let page_code_len: u8 = SUPPORTED_VPD_PAGE_CODES
                     .len()
                     .try_into()
@@ -370,6 +537,15 @@ impl Inquiry {
                 // Maximum WRITE SAME length
                 outbuf[36..44].copy_from_slice(&dev.max_lba.to_be_bytes());
             }
+            // Block Device Characteristics
+            0xb1 => {
+                // Page length
+                outbuf[3] = 0x3c;
+                // Medium rotation rate: the medium is not rotating.
+                outbuf[4..6].copy_from_slice(&1u16.to_be_bytes());
+                // skip outbuf[6]: product type is not indicated.
+                // skip outbuf[7]: nominal form factor is not reported.
+            }
             // Logical Block Provisioning
             0xb2 => {
                 // Page length
@@ -717,6 +893,7 @@ pub struct ReadCapacity10 {
 
 impl ReadCapacity10 {
     fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        dev.medium()?;
         // Returned value is the block address of the last sector.
         // If the block address exceeds u32::MAX, we return u32::MAX.
         let block_address: u32 = dev.max_lba.saturating_sub(1).try_into().unwrap_or(u32::MAX);
@@ -741,6 +918,7 @@ pub struct ReadCapacity16 {
 impl ReadCapacity16 {
     fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
         let _trace = cros_tracing::trace_event!(VirtioScsi, "READ_CAPACITY(16)");
+        dev.medium()?;
         let mut outbuf = [0u8; 32];
         // Last logical block address
         outbuf[..8].copy_from_slice(&dev.max_lba.saturating_sub(1).to_be_bytes());
@@ -825,13 +1003,14 @@ async fn write_to_disk(
     if dev.read_only {
         return Err(ExecuteError::ReadOnly);
     }
+    let disk_image = dev.medium()?;
     check_lba_range(dev.max_lba, lba, xfer_blocks)?;
     let block_size = dev.block_size;
     let count = xfer_blocks * block_size as usize;
     let offset = lba * block_size as u64;
     let before = reader.bytes_read();
     reader
-        .read_exact_to_at_fut(&*dev.disk_image, count, offset)
+        .read_exact_to_at_fut(disk_image, count, offset)
         .await
         .map_err(|desc_error| {
             let resid = count - (reader.bytes_read() - before);
@@ -841,87 +1020,54 @@ async fn write_to_disk(
 
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
-pub struct SynchronizeCache10 {
+pub struct Read12 {
     opcode: u8,
-    immed_byte: u8,
+    rdprotect: u8,
     lba_bytes: [u8; 4],
+    xfer_len_bytes: [u8; 4],
     group_number: u8,
-    block_num_bytes: [u8; 2],
     control: u8,
 }
 
-impl SynchronizeCache10 {
-    async fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
-        let _trace = cros_tracing::trace_event!(VirtioScsi, "SYNCHRONIZE_CACHE(10)");
-        if dev.read_only {
-            return Err(ExecuteError::ReadOnly);
-        }
-        dev.disk_image.fdatasync().await.map_err(|e| {
-            warn!("failed to sync: {e}");
-            ExecuteError::SynchronizationError
-        })
+impl Read12 {
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
     }
-}
 
-async fn unmap(dev: &AsyncLogicalUnit, lba: u64, nblocks: u64) -> Result<(), ExecuteError> {
-    check_lba_range(dev.max_lba, lba, nblocks as usize)?;
-    let offset = lba * dev.block_size as u64;
-    let length = nblocks * dev.block_size as u64;
-    // Ignore the errors here since the device is not strictly required to unmap the LBAs.
-    let _ = dev.disk_image.punch_hole(offset, length).await;
-    Ok(())
-}
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
 
-async fn write_same(
-    dev: &AsyncLogicalUnit,
-    lba: u64,
-    nblocks: u64,
-    reader: &mut Reader,
-) -> Result<(), ExecuteError> {
-    check_lba_range(dev.max_lba, lba, nblocks as usize)?;
-    // The WRITE SAME command expects the device to transfer a single logical block from the
-    // Data-Out buffer.
-    reader.split_at(dev.block_size as usize);
-    if reader.get_remaining().iter().all(|s| s.is_all_zero()) {
-        let block_size = dev.block_size as u64;
-        // Ignore the errors here since the device is not strictly required to unmap the LBAs.
-        let _ = dev
-            .disk_image
-            .write_zeroes_at(lba * block_size, nblocks * block_size)
-            .await;
-        Ok(())
-    } else {
-        // TODO(b/309376528): If the specified data is not zero, raise error for now.
-        Err(ExecuteError::InvalidField)
+    async fn emulate(
+        &self,
+        writer: &mut Writer,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ(12)", lba, xfer_len);
+        read_from_disk(writer, dev, xfer_len, lba).await
     }
 }
 
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
-pub struct WriteSame10 {
+pub struct Write12 {
     opcode: u8,
-    wrprotect_anchor_unmap: u8,
+    wrprotect: u8,
     lba_bytes: [u8; 4],
-    group_number_field: u8,
-    nblocks_bytes: [u8; 2],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
     control: u8,
 }
 
-impl WriteSame10 {
-    fn lba(&self) -> u32 {
-        u32::from_be_bytes(self.lba_bytes)
-    }
-
-    fn nblocks(&self) -> u16 {
-        u16::from_be_bytes(self.nblocks_bytes)
-    }
-
-    fn unmap(&self) -> bool {
-        self.wrprotect_anchor_unmap & 0x8 != 0
+impl Write12 {
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
     }
 
-    fn anchor(&self) -> bool {
-        self.wrprotect_anchor_unmap & 0x10 != 0
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
     }
 
     async fn emulate(
@@ -929,120 +1075,63 @@ impl WriteSame10 {
         reader: &mut Reader,
         dev: &AsyncLogicalUnit,
     ) -> Result<(), ExecuteError> {
-        let lba = self.lba() as u64;
-        let nblocks = self.nblocks() as u64;
-        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE_SAME(10)", lba, nblocks);
-        if dev.read_only {
-            return Err(ExecuteError::ReadOnly);
-        }
-        if nblocks == 0 {
-            // crosvm does not allow the number of blocks to be zero.
-            return Err(ExecuteError::InvalidField);
-        }
-        if self.anchor() {
-            // crosvm currently does not support anchor operations.
-            return Err(ExecuteError::InvalidField);
-        }
-        if self.unmap() {
-            unmap(dev, lba, nblocks).await
-        } else {
-            write_same(dev, lba, nblocks, reader).await
-        }
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE(12)", lba, xfer_len);
+        write_to_disk(reader, dev, xfer_len, lba).await
     }
 }
 
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
-pub struct Unmap {
+pub struct Read16 {
     opcode: u8,
-    anchor_field: u8,
-    _reserved: [u8; 4],
-    group_number_field: u8,
-    param_list_len_bytes: [u8; 2],
+    rdprotect: u8,
+    lba_bytes: [u8; 8],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
     control: u8,
 }
 
-impl Unmap {
-    fn anchor(&self) -> bool {
-        self.anchor_field & 0x01 != 0
+impl Read16 {
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
     }
 
-    fn param_list_len(&self) -> u16 {
-        u16::from_be_bytes(self.param_list_len_bytes)
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
     }
 
     async fn emulate(
         &self,
-        reader: &mut Reader,
+        writer: &mut Writer,
         dev: &AsyncLogicalUnit,
     ) -> Result<(), ExecuteError> {
-        let _trace = cros_tracing::trace_event!(VirtioScsi, "UNMAP");
-        // Reject anchor == 1
-        if self.anchor() {
-            return Err(ExecuteError::InvalidField);
-        }
-        if dev.read_only {
-            return Err(ExecuteError::ReadOnly);
-        }
-        let param_list_len = self.param_list_len();
-        if 0 < param_list_len && param_list_len < 8 {
-            return Err(ExecuteError::InvalidParamLen);
-        }
-        // unmap data len
-        reader.consume(2);
-        let unmap_block_descriptors = {
-            let block_data_len = reader
-                .read_obj::<Be16>()
-                .map_err(ExecuteError::Read)?
-                .to_native();
-            // If the data length is not a multiple of 16, the last unmap block should be ignored.
-            block_data_len / 16
-        };
-        // reserved
-        reader.consume(4);
-        for _ in 0..unmap_block_descriptors {
-            let lba = reader
-                .read_obj::<Be64>()
-                .map_err(ExecuteError::Read)?
-                .to_native();
-            let nblocks = reader
-                .read_obj::<Be32>()
-                .map_err(ExecuteError::Read)?
-                .to_native() as u64;
-            // reserved
-            reader.consume(4);
-            unmap(dev, lba, nblocks).await?;
-        }
-        Ok(())
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ(16)", lba, xfer_len);
+        read_from_disk(writer, dev, xfer_len, lba).await
     }
 }
 
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
-pub struct WriteSame16 {
+pub struct Write16 {
     opcode: u8,
-    wrprotect_anchor_unmap: u8,
+    wrprotect: u8,
     lba_bytes: [u8; 8],
-    nblocks_bytes: [u8; 4],
-    group_number_field: u8,
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
     control: u8,
 }
 
-impl WriteSame16 {
+impl Write16 {
     fn lba(&self) -> u64 {
         u64::from_be_bytes(self.lba_bytes)
     }
 
-    fn nblocks(&self) -> u32 {
-        u32::from_be_bytes(self.nblocks_bytes)
-    }
-
-    fn unmap(&self) -> bool {
-        self.wrprotect_anchor_unmap & 0x8 != 0
-    }
-
-    fn anchor(&self) -> bool {
-        self.wrprotect_anchor_unmap & 0x10 != 0
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
     }
 
     async fn emulate(
@@ -1050,97 +1139,1345 @@ impl WriteSame16 {
         reader: &mut Reader,
         dev: &AsyncLogicalUnit,
     ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
         let lba = self.lba();
-        let nblocks = self.nblocks() as u64;
-        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE_SAME(16)", lba, nblocks);
-        if nblocks == 0 {
-            // crosvm does not allow the number of blocks to be zero.
-            return Err(ExecuteError::InvalidField);
-        }
-        if self.anchor() {
-            // crosvm currently does not support anchor operations.
-            return Err(ExecuteError::InvalidField);
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE(16)", lba, xfer_len);
+        write_to_disk(reader, dev, xfer_len, lba).await
+    }
+}
+
+// Returns the BYTCHK field of the VERIFY and WRITE AND VERIFY commands.
+fn byte_check(protect_bytchk: u8) -> u8 {
+    (protect_bytchk >> 1) & 0x3
+}
+
+async fn verify(
+    reader: &mut Reader,
+    dev: &AsyncLogicalUnit,
+    nblocks: usize,
+    lba: u64,
+    bytchk: u8,
+) -> Result<(), ExecuteError> {
+    let disk_image = dev.medium()?;
+    check_lba_range(dev.max_lba, lba, nblocks)?;
+    let block_size = dev.block_size as usize;
+    let mut expected = vec![0u8; block_size];
+    let mut actual = vec![0u8; block_size];
+    match bytchk {
+        // The medium is a host file, so there is nothing to verify beyond the LBA range.
+        0 => return Ok(()),
+        // The Data-Out buffer holds a single logical block to be compared with every block.
+        0x3 if nblocks > 0 => reader
+            .read_exact(&mut expected)
+            .map_err(ExecuteError::Read)?,
+        0x1 | 0x3 => {}
+        _ => return Err(ExecuteError::InvalidField),
+    }
+    for i in 0..nblocks as u64 {
+        if bytchk == 0x1 {
+            reader
+                .read_exact(&mut expected)
+                .map_err(ExecuteError::Read)?;
         }
-        if self.unmap() {
-            unmap(dev, lba, nblocks).await
-        } else {
-            write_same(dev, lba, nblocks, reader).await
+        disk_image
+            .read_double_buffered((lba + i) * block_size as u64, &mut actual)
+            .await
+            .map_err(ExecuteError::DiskRead)?;
+        if actual != expected {
+            return Err(ExecuteError::Miscompare(lba + i));
         }
     }
+    Ok(())
 }
 
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
-pub struct ReportLuns {
+pub struct Verify10 {
     opcode: u8,
-    _reserved: u8,
-    select_report: u8,
-    _reserved2: [u8; 3],
-    alloc_len_bytes: [u8; 4],
-    _reserved3: u8,
+    vrprotect_bytchk: u8,
+    lba_bytes: [u8; 4],
+    group_number: u8,
+    verification_len_bytes: [u8; 2],
     control: u8,
 }
 
-impl ReportLuns {
-    fn alloc_len(&self) -> usize {
-        u32::from_be_bytes(self.alloc_len_bytes) as usize
+impl Verify10 {
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
     }
 
-    fn emulate(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
-        let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_LUNS");
-        // We need at least 16 bytes.
-        if self.alloc_len() < 16 {
-            return Err(ExecuteError::InvalidField);
-        }
-        // Each LUN takes 8 bytes and we only support LUN0.
-        let lun_list_len = 8u32;
-        writer
-            .write_all(&lun_list_len.to_be_bytes())
-            .map_err(ExecuteError::Write)?;
-        let reserved = [0; 4];
-        writer.write_all(&reserved).map_err(ExecuteError::Write)?;
-        let lun0 = 0u64;
-        writer
-            .write_all(&lun0.to_be_bytes())
+    fn verification_len(&self) -> usize {
+        u16::from_be_bytes(self.verification_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let lba = self.lba();
+        let verification_len = self.verification_len();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "VERIFY(10)", lba, verification_len);
+        verify(
+            reader,
+            dev,
+            verification_len,
+            lba,
+            byte_check(self.vrprotect_bytchk),
+        )
+        .await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Verify12 {
+    opcode: u8,
+    vrprotect_bytchk: u8,
+    lba_bytes: [u8; 4],
+    verification_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Verify12 {
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
+
+    fn verification_len(&self) -> usize {
+        u32::from_be_bytes(self.verification_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let lba = self.lba();
+        let verification_len = self.verification_len();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "VERIFY(12)", lba, verification_len);
+        verify(
+            reader,
+            dev,
+            verification_len,
+            lba,
+            byte_check(self.vrprotect_bytchk),
+        )
+        .await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Verify16 {
+    opcode: u8,
+    vrprotect_bytchk: u8,
+    lba_bytes: [u8; 8],
+    verification_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Verify16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn verification_len(&self) -> usize {
+        u32::from_be_bytes(self.verification_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let lba = self.lba();
+        let verification_len = self.verification_len();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "VERIFY(16)", lba, verification_len);
+        verify(
+            reader,
+            dev,
+            verification_len,
+            lba,
+            byte_check(self.vrprotect_bytchk),
+        )
+        .await
+    }
+}
+
+async fn write_and_verify(
+    reader: &mut Reader,
+    dev: &AsyncLogicalUnit,
+    xfer_blocks: usize,
+    lba: u64,
+    bytchk: u8,
+) -> Result<(), ExecuteError> {
+    // The data is written through the disk image, so reading it back would always match what was
+    // just written. Only the values of BYTCHK that transfer one block per LBA are supported.
+    if bytchk > 0x1 {
+        return Err(ExecuteError::InvalidField);
+    }
+    write_to_disk(reader, dev, xfer_blocks, lba).await
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct WriteAndVerify10 {
+    opcode: u8,
+    wrprotect_bytchk: u8,
+    lba_bytes: [u8; 4],
+    group_number: u8,
+    xfer_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl WriteAndVerify10 {
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
+
+    fn xfer_len(&self) -> usize {
+        u16::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE_AND_VERIFY(10)", lba, xfer_len);
+        write_and_verify(
+            reader,
+            dev,
+            xfer_len,
+            lba,
+            byte_check(self.wrprotect_bytchk),
+        )
+        .await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct WriteAndVerify12 {
+    opcode: u8,
+    wrprotect_bytchk: u8,
+    lba_bytes: [u8; 4],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl WriteAndVerify12 {
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
+
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE_AND_VERIFY(12)", lba, xfer_len);
+        write_and_verify(
+            reader,
+            dev,
+            xfer_len,
+            lba,
+            byte_check(self.wrprotect_bytchk),
+        )
+        .await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct WriteAndVerify16 {
+    opcode: u8,
+    wrprotect_bytchk: u8,
+    lba_bytes: [u8; 8],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl WriteAndVerify16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE_AND_VERIFY(16)", lba, xfer_len);
+        write_and_verify(
+            reader,
+            dev,
+            xfer_len,
+            lba,
+            byte_check(self.wrprotect_bytchk),
+        )
+        .await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct SynchronizeCache10 {
+    opcode: u8,
+    immed_byte: u8,
+    lba_bytes: [u8; 4],
+    group_number: u8,
+    block_num_bytes: [u8; 2],
+    control: u8,
+}
+
+impl SynchronizeCache10 {
+    async fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "SYNCHRONIZE_CACHE(10)");
+        if dev.read_only {
+            return Err(ExecuteError::ReadOnly);
+        }
+        dev.medium()?.fdatasync().await.map_err(|e| {
+            warn!("failed to sync: {e}");
+            ExecuteError::SynchronizationError
+        })
+    }
+}
+
+async fn unmap(dev: &AsyncLogicalUnit, lba: u64, nblocks: u64) -> Result<(), ExecuteError> {
+    let disk_image = dev.medium()?;
+    check_lba_range(dev.max_lba, lba, nblocks as usize)?;
+    let offset = lba * dev.block_size as u64;
+    let length = nblocks * dev.block_size as u64;
+    // Ignore the errors here since the device is not strictly required to unmap the LBAs.
+    let _ = disk_image.punch_hole(offset, length).await;
+    Ok(())
+}
+
+async fn write_same(
+    dev: &AsyncLogicalUnit,
+    lba: u64,
+    nblocks: u64,
+    reader: &mut Reader,
+) -> Result<(), ExecuteError> {
+    let disk_image = dev.medium()?;
+    check_lba_range(dev.max_lba, lba, nblocks as usize)?;
+    // The WRITE SAME command expects the device to transfer a single logical block from the
+    // Data-Out buffer.
+    reader.split_at(dev.block_size as usize);
+    if reader.get_remaining().iter().all(|s| s.is_all_zero()) {
+        let block_size = dev.block_size as u64;
+        // Ignore the errors here since the device is not strictly required to unmap the LBAs.
+        let _ = disk_image
+            .write_zeroes_at(lba * block_size, nblocks * block_size)
+            .await;
+        Ok(())
+    } else {
+        // TODO(b/309376528): If the specified data is not zero, raise error for now.
+        Err(ExecuteError::InvalidField)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct WriteSame10 {
+    opcode: u8,
+    wrprotect_anchor_unmap: u8,
+    lba_bytes: [u8; 4],
+    group_number_field: u8,
+    nblocks_bytes: [u8; 2],
+    control: u8,
+}
+
+impl WriteSame10 {
+    fn lba(&self) -> u32 {
+        u32::from_be_bytes(self.lba_bytes)
+    }
+
+    fn nblocks(&self) -> u16 {
+        u16::from_be_bytes(self.nblocks_bytes)
+    }
+
+    fn unmap(&self) -> bool {
+        self.wrprotect_anchor_unmap & 0x8 != 0
+    }
+
+    fn anchor(&self) -> bool {
+        self.wrprotect_anchor_unmap & 0x10 != 0
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let lba = self.lba() as u64;
+        let nblocks = self.nblocks() as u64;
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE_SAME(10)", lba, nblocks);
+        if dev.read_only {
+            return Err(ExecuteError::ReadOnly);
+        }
+        if nblocks == 0 {
+            // crosvm does not allow the number of blocks to be zero.
+            return Err(ExecuteError::InvalidField);
+        }
+        if self.anchor() {
+            // crosvm currently does not support anchor operations.
+            return Err(ExecuteError::InvalidField);
+        }
+        if self.unmap() {
+            unmap(dev, lba, nblocks).await
+        } else {
+            write_same(dev, lba, nblocks, reader).await
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Unmap {
+    opcode: u8,
+    anchor_field: u8,
+    _reserved: [u8; 4],
+    group_number_field: u8,
+    param_list_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl Unmap {
+    fn anchor(&self) -> bool {
+        self.anchor_field & 0x01 != 0
+    }
+
+    fn param_list_len(&self) -> u16 {
+        u16::from_be_bytes(self.param_list_len_bytes)
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "UNMAP");
+        // Reject anchor == 1
+        if self.anchor() {
+            return Err(ExecuteError::InvalidField);
+        }
+        if dev.read_only {
+            return Err(ExecuteError::ReadOnly);
+        }
+        let param_list_len = self.param_list_len();
+        if 0 < param_list_len && param_list_len < 8 {
+            return Err(ExecuteError::InvalidParamLen);
+        }
+        // unmap data len
+        reader.consume(2);
+        let unmap_block_descriptors = {
+            let block_data_len = reader
+                .read_obj::<Be16>()
+                .map_err(ExecuteError::Read)?
+                .to_native();
+            // If the data length is not a multiple of 16, the last unmap block should be ignored.
+            block_data_len / 16
+        };
+        // reserved
+        reader.consume(4);
+        for _ in 0..unmap_block_descriptors {
+            let lba = reader
+                .read_obj::<Be64>()
+                .map_err(ExecuteError::Read)?
+                .to_native();
+            let nblocks = reader
+                .read_obj::<Be32>()
+                .map_err(ExecuteError::Read)?
+                .to_native() as u64;
+            // reserved
+            reader.consume(4);
+            unmap(dev, lba, nblocks).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct WriteSame16 {
+    opcode: u8,
+    wrprotect_anchor_unmap: u8,
+    lba_bytes: [u8; 8],
+    nblocks_bytes: [u8; 4],
+    group_number_field: u8,
+    control: u8,
+}
+
+impl WriteSame16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn nblocks(&self) -> u32 {
+        u32::from_be_bytes(self.nblocks_bytes)
+    }
+
+    fn unmap(&self) -> bool {
+        self.wrprotect_anchor_unmap & 0x8 != 0
+    }
+
+    fn anchor(&self) -> bool {
+        self.wrprotect_anchor_unmap & 0x10 != 0
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let lba = self.lba();
+        let nblocks = self.nblocks() as u64;
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE_SAME(16)", lba, nblocks);
+        if nblocks == 0 {
+            // crosvm does not allow the number of blocks to be zero.
+            return Err(ExecuteError::InvalidField);
+        }
+        if self.anchor() {
+            // crosvm currently does not support anchor operations.
+            return Err(ExecuteError::InvalidField);
+        }
+        if self.unmap() {
+            unmap(dev, lba, nblocks).await
+        } else {
+            write_same(dev, lba, nblocks, reader).await
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct ReportLuns {
+    opcode: u8,
+    _reserved: u8,
+    select_report: u8,
+    _reserved2: [u8; 3],
+    alloc_len_bytes: [u8; 4],
+    _reserved3: u8,
+    control: u8,
+}
+
+impl ReportLuns {
+    fn alloc_len(&self) -> usize {
+        u32::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_LUNS");
+        // We need at least 16 bytes.
+        if self.alloc_len() < 16 {
+            return Err(ExecuteError::InvalidField);
+        }
+        // Each LUN takes 8 bytes and we only support LUN0.
+        let lun_list_len = 8u32;
+        writer
+            .write_all(&lun_list_len.to_be_bytes())
+            .map_err(ExecuteError::Write)?;
+        let reserved = [0; 4];
+        writer.write_all(&reserved).map_err(ExecuteError::Write)?;
+        let lun0 = 0u64;
+        writer
+            .write_all(&lun0.to_be_bytes())
             .map_err(ExecuteError::Write)
     }
 }
 
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
-pub struct ReportSupportedTMFs {
+pub struct ReportSupportedTMFs {
+    opcode: u8,
+    service_action_field: u8,
+    _reserved1: [u8; 4],
+    alloc_len_bytes: [u8; 4],
+    _reserved2: u8,
+    control: u8,
+}
+
+impl ReportSupportedTMFs {
+    fn alloc_len(&self) -> u32 {
+        u32::from_be_bytes(self.alloc_len_bytes)
+    }
+
+    fn emulate(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_SUPPORTED_TMFs");
+        // The allocation length should be at least four.
+        if self.alloc_len() < 4 {
+            return Err(ExecuteError::InvalidField);
+        }
+        // We support LOGICAL UNIT RESET and TARGET RESET.
+        const LOGICAL_UNIT_RESET: u8 = 1 << 3;
+        const TARGET_RESET: u8 = 1 << 1;
+        writer
+            .write_obj(LOGICAL_UNIT_RESET | TARGET_RESET)
+            .map_err(ExecuteError::Write)?;
+        // Push reserved bytes.
+        let reserved = [0u8; 3];
+        writer.write_all(&reserved).map_err(ExecuteError::Write)?;
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PersistentReserveIn {
+    opcode: u8,
+    service_action_field: u8,
+    _reserved: [u8; 5],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl PersistentReserveIn {
+    fn service_action(&self) -> u8 {
+        self.service_action_field & 0x1f
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "PERSISTENT_RESERVE_IN");
+        let reservations = dev
+            .reservations
+            .as_ref()
+            .ok_or(ExecuteError::Unsupported(PERSISTENT_RESERVE_IN))?;
+        let outbuf = match self.service_action() {
+            PR_READ_KEYS => reservations.read(|state| {
+                let mut outbuf = Vec::with_capacity(8 + 8 * state.registrations.len());
+                outbuf.extend_from_slice(&state.generation.to_be_bytes());
+                // Additional length
+                outbuf.extend_from_slice(&(8 * state.registrations.len() as u32).to_be_bytes());
+                for key in state.registrations.values() {
+                    outbuf.extend_from_slice(&key.to_be_bytes());
+                }
+                outbuf
+            })?,
+            PR_READ_RESERVATION => reservations.read(|state| {
+                let mut outbuf = vec![0u8; 8];
+                outbuf[..4].copy_from_slice(&state.generation.to_be_bytes());
+                if let Some(reservation) = state.reservation {
+                    // Additional length
+                    outbuf[4..8].copy_from_slice(&16u32.to_be_bytes());
+                    let mut descriptor = [0u8; 16];
+                    descriptor[..8].copy_from_slice(&state.reservation_key().to_be_bytes());
+                    // The scope is always the logical unit, which is 0.
+                    descriptor[13] = reservation.reservation_type;
+                    outbuf.extend_from_slice(&descriptor);
+                }
+                outbuf
+            })?,
+            PR_REPORT_CAPABILITIES => {
+                let mut outbuf = vec![0u8; 8];
+                // Length
+                outbuf[..2].copy_from_slice(&8u16.to_be_bytes());
+                // Reservations stored in a file persist through power loss whether or not the
+                // guest asks for it, so PTPL_C and PTPL_A are set together.
+                let persistent = reservations.is_persistent() as u8;
+                outbuf[2] = persistent;
+                // TMV: the persistent reservation type mask is valid.
+                outbuf[3] = 0x80 | persistent;
+                const WR_EX_AR: u8 = 1 << 7;
+                const EX_AC_RO: u8 = 1 << 6;
+                const WR_EX_RO: u8 = 1 << 5;
+                const EX_AC: u8 = 1 << 3;
+                const WR_EX: u8 = 1 << 1;
+                const EX_AC_AR: u8 = 1 << 0;
+                outbuf[4] = WR_EX_AR | EX_AC_RO | WR_EX_RO | EX_AC | WR_EX;
+                outbuf[5] = EX_AC_AR;
+                outbuf
+            }
+            service_action => {
+                warn!(
+                    "service action {:#x?} for PERSISTENT_RESERVE_IN is not implemented",
+                    service_action
+                );
+                return Err(ExecuteError::InvalidField);
+            }
+        };
+        writer
+            .write_all(&outbuf[..cmp::min(self.alloc_len(), outbuf.len())])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PersistentReserveOut {
+    opcode: u8,
+    service_action_field: u8,
+    scope_type: u8,
+    _reserved: [u8; 2],
+    param_list_len_bytes: [u8; 4],
+    control: u8,
+}
+
+impl PersistentReserveOut {
+    fn service_action(&self) -> u8 {
+        self.service_action_field & 0x1f
+    }
+
+    fn scope(&self) -> u8 {
+        self.scope_type >> 4
+    }
+
+    fn reservation_type(&self) -> u8 {
+        self.scope_type & 0xf
+    }
+
+    fn param_list_len(&self) -> u32 {
+        u32::from_be_bytes(self.param_list_len_bytes)
+    }
+
+    fn emulate(&self, reader: &mut Reader, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let service_action = self.service_action();
+        let _trace =
+            cros_tracing::trace_event!(VirtioScsi, "PERSISTENT_RESERVE_OUT", service_action);
+        let reservations = dev
+            .reservations
+            .as_ref()
+            .ok_or(ExecuteError::Unsupported(PERSISTENT_RESERVE_OUT))?;
+        // The parameter list is 24 bytes long unless SPEC_I_PT is set, which is not supported.
+        if self.param_list_len() != 24 {
+            return Err(ExecuteError::InvalidParamLen);
+        }
+        let mut params = [0u8; 24];
+        reader.read_exact(&mut params).map_err(ExecuteError::Read)?;
+        let key = u64::from_be_bytes(params[..8].try_into().unwrap());
+        let service_action_key = u64::from_be_bytes(params[8..16].try_into().unwrap());
+        const SPEC_I_PT: u8 = 1 << 3;
+        const ALL_TG_PT: u8 = 1 << 2;
+        const APTPL: u8 = 1 << 0;
+        let flags = params[20];
+        if flags & (SPEC_I_PT | ALL_TG_PT) != 0
+            || (flags & APTPL != 0 && !reservations.is_persistent())
+        {
+            return Err(ExecuteError::InvalidFieldInParamList);
+        }
+        let reservation_type = self.reservation_type();
+        if matches!(
+            service_action,
+            PR_RESERVE | PR_RELEASE | PR_PREEMPT | PR_PREEMPT_AND_ABORT
+        ) && (self.scope() != 0 || !reservation::is_valid_type(reservation_type))
+        {
+            return Err(ExecuteError::InvalidField);
+        }
+        let initiator = reservations.initiator();
+        reservations.update(|state| match service_action {
+            PR_REGISTER => state.register(initiator, key, service_action_key, false),
+            PR_RESERVE => state.reserve(initiator, key, reservation_type),
+            PR_RELEASE => state.release(initiator, key, reservation_type),
+            PR_CLEAR => state.clear(initiator, key),
+            // crosvm completes commands in order, so there are no tasks to abort.
+            PR_PREEMPT | PR_PREEMPT_AND_ABORT => {
+                state.preempt(initiator, key, service_action_key, reservation_type)
+            }
+            PR_REGISTER_AND_IGNORE_EXISTING_KEY => {
+                state.register(initiator, key, service_action_key, true)
+            }
+            _ => {
+                warn!(
+                    "service action {:#x?} for PERSISTENT_RESERVE_OUT is not implemented",
+                    service_action
+                );
+                Err(ExecuteError::InvalidField)
+            }
+        })
+    }
+}
+
+// The commands supported by every logical unit as (opcode, service action, CDB length).
+const SUPPORTED_COMMANDS: &[(u8, Option<u8>, usize)] = &[
+    (TEST_UNIT_READY, None, size_of::<TestUnitReady>()),
+    (READ_6, None, size_of::<Read6>()),
+    (INQUIRY, None, size_of::<Inquiry>()),
+    (MODE_SELECT_6, None, size_of::<ModeSelect6>()),
+    (MODE_SENSE_6, None, size_of::<ModeSense6>()),
+    (START_STOP_UNIT, None, size_of::<StartStopUnit>()),
+    (
+        PREVENT_ALLOW_MEDIUM_REMOVAL,
+        None,
+        size_of::<PreventAllowMediumRemoval>(),
+    ),
+    (READ_CAPACITY_10, None, size_of::<ReadCapacity10>()),
+    (READ_10, None, size_of::<Read10>()),
+    (WRITE_10, None, size_of::<Write10>()),
+    (WRITE_AND_VERIFY_10, None, size_of::<WriteAndVerify10>()),
+    (VERIFY_10, None, size_of::<Verify10>()),
+    (SYNCHRONIZE_CACHE_10, None, size_of::<SynchronizeCache10>()),
+    (WRITE_SAME_10, None, size_of::<WriteSame10>()),
+    (UNMAP, None, size_of::<Unmap>()),
+    (READ_16, None, size_of::<Read16>()),
+    (WRITE_16, None, size_of::<Write16>()),
+    (WRITE_AND_VERIFY_16, None, size_of::<WriteAndVerify16>()),
+    (VERIFY_16, None, size_of::<Verify16>()),
+    (WRITE_SAME_16, None, size_of::<WriteSame16>()),
+    (
+        SERVICE_ACTION_IN_16,
+        Some(READ_CAPACITY_16),
+        size_of::<ReadCapacity16>(),
+    ),
+    (REPORT_LUNS, None, size_of::<ReportLuns>()),
+    (
+        MAINTENANCE_IN,
+        Some(REPORT_SUPPORTED_OPERATION_CODES),
+        size_of::<ReportSupportedOpcodes>(),
+    ),
+    (
+        MAINTENANCE_IN,
+        Some(REPORT_SUPPORTED_TASK_MANAGEMENT_FUNCTIONS),
+        size_of::<ReportSupportedTMFs>(),
+    ),
+    (READ_12, None, size_of::<Read12>()),
+    (WRITE_12, None, size_of::<Write12>()),
+    (WRITE_AND_VERIFY_12, None, size_of::<WriteAndVerify12>()),
+    (VERIFY_12, None, size_of::<Verify12>()),
+];
+
+// The persistent reservation commands, which are only supported by disks.
+const SUPPORTED_PR_COMMANDS: &[(u8, Option<u8>, usize)] = &[
+    (
+        PERSISTENT_RESERVE_IN,
+        Some(PR_READ_KEYS),
+        size_of::<PersistentReserveIn>(),
+    ),
+    (
+        PERSISTENT_RESERVE_IN,
+        Some(PR_READ_RESERVATION),
+        size_of::<PersistentReserveIn>(),
+    ),
+    (
+        PERSISTENT_RESERVE_IN,
+        Some(PR_REPORT_CAPABILITIES),
+        size_of::<PersistentReserveIn>(),
+    ),
+    (
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_REGISTER),
+        size_of::<PersistentReserveOut>(),
+    ),
+    (
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_RESERVE),
+        size_of::<PersistentReserveOut>(),
+    ),
+    (
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_RELEASE),
+        size_of::<PersistentReserveOut>(),
+    ),
+    (
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_CLEAR),
+        size_of::<PersistentReserveOut>(),
+    ),
+    (
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_PREEMPT),
+        size_of::<PersistentReserveOut>(),
+    ),
+    (
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_PREEMPT_AND_ABORT),
+        size_of::<PersistentReserveOut>(),
+    ),
+    (
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_REGISTER_AND_IGNORE_EXISTING_KEY),
+        size_of::<PersistentReserveOut>(),
+    ),
+];
+
+// The MMC commands that are only supported by CD-ROM drives.
+const SUPPORTED_MMC_COMMANDS: &[(u8, Option<u8>, usize)] = &[
+    (READ_TOC, None, size_of::<ReadToc>()),
+    (GET_CONFIGURATION, None, size_of::<GetConfiguration>()),
+    (
+        GET_EVENT_STATUS_NOTIFICATION,
+        None,
+        size_of::<GetEventStatusNotification>(),
+    ),
+];
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct ReportSupportedOpcodes {
     opcode: u8,
     service_action_field: u8,
-    _reserved1: [u8; 4],
+    rctd_reporting_options: u8,
+    requested_opcode: u8,
+    requested_service_action_bytes: [u8; 2],
     alloc_len_bytes: [u8; 4],
-    _reserved2: u8,
+    _reserved: u8,
     control: u8,
 }
 
-impl ReportSupportedTMFs {
-    fn alloc_len(&self) -> u32 {
-        u32::from_be_bytes(self.alloc_len_bytes)
+impl ReportSupportedOpcodes {
+    fn return_command_timeouts(&self) -> bool {
+        self.rctd_reporting_options & 0x80 != 0
     }
 
-    fn emulate(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
-        let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_SUPPORTED_TMFs");
-        // The allocation length should be at least four.
-        if self.alloc_len() < 4 {
+    fn reporting_options(&self) -> u8 {
+        self.rctd_reporting_options & 0x7
+    }
+
+    fn requested_service_action(&self) -> u16 {
+        u16::from_be_bytes(self.requested_service_action_bytes)
+    }
+
+    fn alloc_len(&self) -> usize {
+        u32::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_SUPPORTED_OPERATION_CODES");
+        // crosvm does not report command timeouts.
+        if self.return_command_timeouts() {
             return Err(ExecuteError::InvalidField);
         }
-        // We support LOGICAL UNIT RESET and TARGET RESET.
-        const LOGICAL_UNIT_RESET: u8 = 1 << 3;
-        const TARGET_RESET: u8 = 1 << 1;
+        let mmc_commands: &[(u8, Option<u8>, usize)] = if dev.cdrom.is_some() {
+            SUPPORTED_MMC_COMMANDS
+        } else {
+            &[]
+        };
+        let pr_commands: &[(u8, Option<u8>, usize)] = if dev.reservations.is_some() {
+            SUPPORTED_PR_COMMANDS
+        } else {
+            &[]
+        };
+        let mut commands = SUPPORTED_COMMANDS
+            .iter()
+            .chain(pr_commands)
+            .chain(mmc_commands);
+        let outbuf = match self.reporting_options() {
+            // All supported commands
+            0 => {
+                let mut outbuf = vec![0u8; 4];
+                for &(opcode, service_action, cdb_len) in commands {
+                    let mut descriptor = [0u8; 8];
+                    descriptor[0] = opcode;
+                    if let Some(service_action) = service_action {
+                        descriptor[2..4].copy_from_slice(&(service_action as u16).to_be_bytes());
+                        // SERVACTV
+                        descriptor[5] = 0x1;
+                    }
+                    descriptor[6..8].copy_from_slice(&(cdb_len as u16).to_be_bytes());
+                    outbuf.extend_from_slice(&descriptor);
+                }
+                // Command data length
+                let data_len = (outbuf.len() - 4) as u32;
+                outbuf[..4].copy_from_slice(&data_len.to_be_bytes());
+                outbuf
+            }
+            // One command, identified by its opcode and, if it has any, its service action.
+            reporting_options @ 1..=3 => {
+                let opcode = self.requested_opcode;
+                let requested_service_action = self.requested_service_action();
+                // `None` if the opcode is not supported at all.
+                let has_service_actions = commands
+                    .clone()
+                    .find(|&&(op, _, _)| op == opcode)
+                    .map(|&(_, service_action, _)| service_action.is_some());
+                match (reporting_options, has_service_actions) {
+                    // The command must not have service actions.
+                    (1, Some(true)) => return Err(ExecuteError::InvalidField),
+                    // The command must have service actions.
+                    (2, Some(false)) => return Err(ExecuteError::InvalidField),
+                    _ => {}
+                }
+                let command = commands.find(|&&(op, service_action, _)| {
+                    op == opcode
+                        && match service_action {
+                            Some(service_action) => {
+                                service_action as u16 == requested_service_action
+                            }
+                            None => true,
+                        }
+                });
+                let mut outbuf = vec![0u8; 4];
+                match command {
+                    Some(&(_, service_action, cdb_len)) => {
+                        // SUPPORT: the command is supported as described in the standard.
+                        outbuf[1] = 0x3;
+                        outbuf[2..4].copy_from_slice(&(cdb_len as u16).to_be_bytes());
+                        // CDB usage data. crosvm does not track which bits of each CDB are
+                        // evaluated, so every bit but those of the CONTROL byte is reported as
+                        // used.
+                        let mut usage = vec![0xffu8; cdb_len];
+                        usage[0] = opcode;
+                        if service_action.is_some() {
+                            usage[1] = 0x1f;
+                        }
+                        usage[cdb_len - 1] = 0;
+                        outbuf.extend_from_slice(&usage);
+                    }
+                    // SUPPORT: the command is not supported.
+                    None => outbuf[1] = 0x1,
+                }
+                outbuf
+            }
+            _ => return Err(ExecuteError::InvalidField),
+        };
         writer
-            .write_obj(LOGICAL_UNIT_RESET | TARGET_RESET)
-            .map_err(ExecuteError::Write)?;
-        // Push reserved bytes.
-        let reserved = [0u8; 3];
-        writer.write_all(&reserved).map_err(ExecuteError::Write)?;
+            .write_all(&outbuf[..cmp::min(self.alloc_len(), outbuf.len())])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct StartStopUnit {
+    opcode: u8,
+    immed_byte: u8,
+    _reserved: u8,
+    power_condition_modifier: u8,
+    power_condition_loej_start: u8,
+    control: u8,
+}
+
+impl StartStopUnit {
+    fn power_condition(&self) -> u8 {
+        self.power_condition_loej_start >> 4
+    }
+
+    fn load_eject(&self) -> bool {
+        self.power_condition_loej_start & 0x2 != 0
+    }
+
+    fn start(&self) -> bool {
+        self.power_condition_loej_start & 0x1 != 0
+    }
+
+    fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "START_STOP_UNIT");
+        // The LOEJ bit is ignored when a power condition is specified, and crosvm does not manage
+        // power conditions.
+        if self.power_condition() != 0 || !self.load_eject() {
+            return Ok(());
+        }
+        // Only CD-ROM drives can load and eject the medium.
+        let cdrom = dev.cdrom.as_ref().ok_or(ExecuteError::InvalidField)?;
+        if self.start() {
+            // Close the tray, which loads the medium left in it.
+            if cdrom.tray_open.replace(false) && dev.disk_image.is_some() {
+                cdrom.media_event.set(MediaEvent::NewMedia);
+            }
+        } else {
+            if cdrom.prevent_removal.get() {
+                return Err(ExecuteError::MediumRemovalPrevented);
+            }
+            if !cdrom.tray_open.replace(true) && dev.disk_image.is_some() {
+                cdrom.media_event.set(MediaEvent::MediaRemoval);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PreventAllowMediumRemoval {
+    opcode: u8,
+    _reserved: [u8; 3],
+    prevent_field: u8,
+    control: u8,
+}
+
+impl PreventAllowMediumRemoval {
+    fn prevent(&self) -> bool {
+        // MMC devices also define a persistent prevent in the second bit.
+        self.prevent_field & 0x3 != 0
+    }
+
+    fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "PREVENT_ALLOW_MEDIUM_REMOVAL");
+        // The command has no effect on devices without removable media.
+        if let Some(cdrom) = &dev.cdrom {
+            cdrom.prevent_removal.set(self.prevent());
+        }
         Ok(())
     }
 }
 
+// Writes the data of an MMC command, whose first two bytes hold the length of the rest of the data.
+fn write_mmc_data(
+    writer: &mut Writer,
+    mut outbuf: Vec<u8>,
+    alloc_len: usize,
+) -> Result<(), ExecuteError> {
+    let data_len: u16 = (outbuf.len() - 2)
+        .try_into()
+        .expect("MMC data length cannot exceed u16::MAX");
+    outbuf[..2].copy_from_slice(&data_len.to_be_bytes());
+    writer
+        .write_all(&outbuf[..cmp::min(alloc_len, outbuf.len())])
+        .map_err(ExecuteError::Write)
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct ReadToc {
+    opcode: u8,
+    msf_field: u8,
+    format_field: u8,
+    _reserved: [u8; 3],
+    track_session_number: u8,
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl ReadToc {
+    fn msf(&self) -> bool {
+        self.msf_field & 0x2 != 0
+    }
+
+    fn format(&self) -> u8 {
+        self.format_field & 0xf
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    // Returns a track descriptor of the only track of the medium, a data track, or of its lead-out
+    // area if `track_number` is 0xaa.
+    fn track_descriptor(&self, track_number: u8, lba: u64) -> [u8; 8] {
+        let mut desc = [0u8; 8];
+        // ADR: Q sub-channel encodes the current position | CONTROL: data track
+        desc[1] = 0x14;
+        desc[2] = track_number;
+        if self.msf() {
+            // Addresses in the MSF format start at 2 seconds (150 frames) and a second has 75
+            // frames.
+            let frames = lba + 150;
+            desc[5] = (frames / (60 * 75)).try_into().unwrap_or(u8::MAX);
+            desc[6] = (frames / 75 % 60) as u8;
+            desc[7] = (frames % 75) as u8;
+        } else {
+            desc[4..8].copy_from_slice(&lba.try_into().unwrap_or(u32::MAX).to_be_bytes());
+        }
+        desc
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ_TOC");
+        if dev.cdrom.is_none() {
+            return Err(ExecuteError::Unsupported(READ_TOC));
+        }
+        dev.medium()?;
+        // outbuf[0..2]: Data length. Will be filled later.
+        let mut outbuf = vec![0u8; 4];
+        match self.format() {
+            // TOC
+            0x0 => {
+                // First and last track numbers.
+                outbuf[2] = 1;
+                outbuf[3] = 1;
+                match self.track_session_number {
+                    0 | 1 => {
+                        outbuf.extend(self.track_descriptor(1, 0));
+                        outbuf.extend(self.track_descriptor(0xaa, dev.max_lba));
+                    }
+                    0xaa => outbuf.extend(self.track_descriptor(0xaa, dev.max_lba)),
+                    _ => return Err(ExecuteError::InvalidField),
+                }
+            }
+            // Session information
+            0x1 => {
+                // First and last complete session numbers.
+                outbuf[2] = 1;
+                outbuf[3] = 1;
+                // The first track in the last session.
+                outbuf.extend(self.track_descriptor(1, 0));
+            }
+            format => {
+                warn!("unsupported READ TOC format: {:#x?}", format);
+                return Err(ExecuteError::InvalidField);
+            }
+        }
+        write_mmc_data(writer, outbuf, self.alloc_len())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GetConfiguration {
+    opcode: u8,
+    rt_field: u8,
+    starting_feature_bytes: [u8; 2],
+    _reserved: [u8; 3],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl GetConfiguration {
+    fn request_type(&self) -> u8 {
+        self.rt_field & 0x3
+    }
+
+    fn starting_feature(&self) -> u16 {
+        u16::from_be_bytes(self.starting_feature_bytes)
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn feature_descriptor(
+        code: u16,
+        version: u8,
+        persistent: bool,
+        current: bool,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut desc = code.to_be_bytes().to_vec();
+        desc.push(version << 2 | (persistent as u8) << 1 | current as u8);
+        desc.push(data.len() as u8);
+        desc.extend_from_slice(data);
+        desc
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "GET_CONFIGURATION");
+        if dev.cdrom.is_none() {
+            return Err(ExecuteError::Unsupported(GET_CONFIGURATION));
+        }
+        const PROFILE_CD_ROM: u16 = 0x0008;
+        let medium_present = dev.medium().is_ok();
+        let mut random_readable = [0u8; 8];
+        random_readable[..4].copy_from_slice(&dev.block_size.to_be_bytes());
+        // Blocking: the number of logical blocks per readable unit.
+        random_readable[4..6].copy_from_slice(&1u16.to_be_bytes());
+        let features = [
+            // Profile List: the only profile is CD-ROM.
+            Self::feature_descriptor(
+                0x0000,
+                0,
+                true,
+                true,
+                &[0x00, 0x08, medium_present as u8, 0x00],
+            ),
+            // Core: the physical interface standard is SCSI.
+            Self::feature_descriptor(0x0001, 2, true, true, &[0, 0, 0, 1, 0, 0, 0, 0]),
+            // Removable Medium: a tray that can be locked and ejected by the drive.
+            Self::feature_descriptor(0x0003, 0, true, true, &[0x29, 0, 0, 0]),
+            // Random Readable
+            Self::feature_descriptor(0x0010, 0, false, medium_present, &random_readable),
+            // CD Read
+            Self::feature_descriptor(0x001e, 2, false, medium_present, &[0, 0, 0, 0]),
+        ];
+
+        let starting_feature = self.starting_feature();
+        let request_type = self.request_type();
+        let mut outbuf = vec![0u8; 8];
+        for desc in features {
+            let code = u16::from_be_bytes([desc[0], desc[1]]);
+            let current = desc[2] & 0x1 != 0;
+            let selected = match request_type {
+                // All features starting from the starting feature.
+                0x0 => code >= starting_feature,
+                // Current features starting from the starting feature.
+                0x1 => code >= starting_feature && current,
+                // Only the starting feature.
+                0x2 => code == starting_feature,
+                _ => return Err(ExecuteError::InvalidField),
+            };
+            if selected {
+                outbuf.extend(desc);
+            }
+        }
+        // Feature header: data length of the rest of the data (4 bytes) and the current profile.
+        let data_len = (outbuf.len() - 4) as u32;
+        outbuf[..4].copy_from_slice(&data_len.to_be_bytes());
+        if medium_present {
+            outbuf[6..8].copy_from_slice(&PROFILE_CD_ROM.to_be_bytes());
+        }
+        writer
+            .write_all(&outbuf[..cmp::min(self.alloc_len(), outbuf.len())])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GetEventStatusNotification {
+    opcode: u8,
+    polled_field: u8,
+    _reserved: [u8; 2],
+    notification_class_request: u8,
+    _reserved2: [u8; 2],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl GetEventStatusNotification {
+    fn polled(&self) -> bool {
+        self.polled_field & 0x1 != 0
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "GET_EVENT_STATUS_NOTIFICATION");
+        let cdrom = dev
+            .cdrom
+            .as_ref()
+            .ok_or(ExecuteError::Unsupported(GET_EVENT_STATUS_NOTIFICATION))?;
+        // crosvm does not support asynchronous event reporting.
+        if !self.polled() {
+            return Err(ExecuteError::InvalidField);
+        }
+        const MEDIA_CLASS: u8 = 1 << 4;
+        // outbuf[0..2]: Data length. Will be filled later.
+        let mut outbuf = vec![0u8; 4];
+        // Supported event classes
+        outbuf[3] = MEDIA_CLASS;
+        if self.notification_class_request & MEDIA_CLASS != 0 {
+            // Notification class: media
+            outbuf[2] = 0x04;
+            let tray_open = cdrom.tray_open.get();
+            let medium_present = dev.disk_image.is_some() && !tray_open;
+            // Each event is reported once.
+            let event = cdrom.media_event.replace(MediaEvent::NoChange);
+            // Media status: Media Present | Door or Tray open
+            let status = (medium_present as u8) << 1 | tray_open as u8;
+            // The start and end slots are unused since the drive has a single slot.
+            outbuf.extend([event as u8, status, 0, 0]);
+        } else {
+            // No Event Available
+            outbuf[2] = 0x80;
+        }
+        write_mmc_data(writer, outbuf, self.alloc_len())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1231,6 +2568,115 @@ mod tests {
         assert_eq!(write10.lba(), 0x00000000);
     }
 
+    #[test]
+    fn parse_read12() {
+        let cdb = [
+            0xa8, 0x00, 0x12, 0x34, 0x56, 0x78, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let read12 = match command {
+            Command::Read12(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(read12.xfer_len(), 0x00010000);
+        assert_eq!(read12.lba(), 0x12345678);
+    }
+
+    #[test]
+    fn parse_read16() {
+        let cdb = [
+            0x88, 0x00, 0x00, 0x00, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0x00, 0x01, 0x00,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let read16 = match command {
+            Command::Read16(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(read16.xfer_len(), 0x0100);
+        assert_eq!(read16.lba(), 0x0123456789);
+    }
+
+    #[test]
+    fn parse_write16() {
+        let cdb = [
+            0x8a, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0x00, 0x00, 0x00, 0x08,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let write16 = match command {
+            Command::Write16(w) => w,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(write16.xfer_len(), 0x0008);
+        assert_eq!(write16.lba(), 0xfffffffffffffffe);
+    }
+
+    #[test]
+    fn parse_verify10() {
+        let cdb = [0x2f, 0x02, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x20, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let verify10 = match command {
+            Command::Verify10(v) => v,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(verify10.verification_len(), 0x0020);
+        assert_eq!(verify10.lba(), 0x00001000);
+        assert_eq!(byte_check(verify10.vrprotect_bytchk), 1);
+    }
+
+    #[test]
+    fn parse_write_and_verify16() {
+        let cdb = [
+            0x8e, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let write_and_verify16 = match command {
+            Command::WriteAndVerify16(w) => w,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(write_and_verify16.xfer_len(), 0x0004);
+        assert_eq!(write_and_verify16.lba(), 0x0200000000);
+    }
+
+    #[test]
+    fn parse_report_supported_opcodes() {
+        let cdb = [
+            0xa3, 0x0c, 0x02, 0x9e, 0x00, 0x10, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let report_supported_opcodes = match command {
+            Command::ReportSupportedOpcodes(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(!report_supported_opcodes.return_command_timeouts());
+        assert_eq!(report_supported_opcodes.reporting_options(), 2);
+        assert_eq!(
+            report_supported_opcodes.requested_opcode,
+            SERVICE_ACTION_IN_16
+        );
+        assert_eq!(
+            report_supported_opcodes.requested_service_action(),
+            READ_CAPACITY_16 as u16
+        );
+        assert_eq!(report_supported_opcodes.alloc_len(), 0x0200);
+    }
+
+    #[test]
+    fn parse_persistent_reserve_out() {
+        let cdb = [0x5f, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let persistent_reserve_out = match command {
+            Command::PersistentReserveOut(p) => p,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(persistent_reserve_out.service_action(), PR_RESERVE);
+        assert_eq!(persistent_reserve_out.scope(), 0);
+        assert_eq!(persistent_reserve_out.reservation_type(), 1);
+        assert_eq!(persistent_reserve_out.param_list_len(), 24);
+    }
+
     #[test]
     fn parse_synchronize_cache_10() {
         let cdb = [0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
@@ -1273,4 +2719,60 @@ mod tests {
         };
         assert_eq!(report_supported_tmfs.alloc_len(), 0xabcdef12);
     }
+
+    #[test]
+    fn parse_start_stop_unit() {
+        let cdb = [0x1b, 0x01, 0x00, 0x00, 0x02, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let start_stop_unit = match command {
+            Command::StartStopUnit(s) => s,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(start_stop_unit.power_condition(), 0);
+        assert!(start_stop_unit.load_eject());
+        assert!(!start_stop_unit.start());
+    }
+
+    #[test]
+    fn parse_read_toc() {
+        let cdb = [0x43, 0x02, 0x01, 0x00, 0x00, 0x00, 0xaa, 0xab, 0xcd, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let read_toc = match command {
+            Command::ReadToc(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(read_toc.msf());
+        assert_eq!(read_toc.format(), 1);
+        assert_eq!(read_toc.track_session_number, 0xaa);
+        assert_eq!(read_toc.alloc_len(), 0xabcd);
+    }
+
+    #[test]
+    fn parse_get_configuration() {
+        let cdb = [0x46, 0x02, 0x00, 0x1e, 0x00, 0x00, 0x00, 0xab, 0xcd, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let get_configuration = match command {
+            Command::GetConfiguration(g) => g,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(get_configuration.request_type(), 2);
+        assert_eq!(get_configuration.starting_feature(), 0x1e);
+        assert_eq!(get_configuration.alloc_len(), 0xabcd);
+    }
+
+    #[test]
+    fn parse_get_event_status_notification() {
+        let cdb = [0x4a, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0xab, 0xcd, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let get_event_status_notification = match command {
+            Command::GetEventStatusNotification(g) => g,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(get_event_status_notification.polled());
+        assert_eq!(
+            get_event_status_notification.notification_class_request,
+            0x10
+        );
+        assert_eq!(get_event_status_notification.alloc_len(), 0xabcd);
+    }
 }
@@ -7,24 +7,36 @@
 //! crosvm currently supports only one logical unit in a target (LUN0), therefore a SCSI target is
 //! tied to a logical unit and a disk image belongs to a logical unit in crosvm.
 
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::path::PathBuf;
 use std::rc::Rc;
 
 use anyhow::Context;
 use base::error;
+use base::info;
 use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
 use base::Event;
+use base::Tube;
 use base::WorkerThread;
+use cros_async::sync::RwLock as AsyncRwLock;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use cros_async::ExecutorKind;
 use disk::AsyncDisk;
 use disk::DiskFile;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
 use futures::pin_mut;
 use futures::stream::FuturesUnordered;
 use futures::FutureExt;
@@ -37,6 +49,9 @@ use virtio_sys::virtio_scsi::virtio_scsi_ctrl_tmf_req;
 use virtio_sys::virtio_scsi::virtio_scsi_ctrl_tmf_resp;
 use virtio_sys::virtio_scsi::virtio_scsi_event;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_CDB_DEFAULT_SIZE;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_EVT_RESET_REMOVED;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_EVT_RESET_RESCAN;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_F_HOTPLUG;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_SENSE_DEFAULT_SIZE;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_BAD_TARGET;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_FUNCTION_REJECTED;
@@ -45,9 +60,13 @@ use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_INCORRECT_LUN;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_OK;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_AN_QUERY;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_AN_SUBSCRIBE;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_EVENTS_MISSED;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TRANSPORT_RESET;
+use vm_control::ScsiControlCommand;
+use vm_control::ScsiControlResult;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -59,8 +78,15 @@ use crate::virtio::copy_config;
 use crate::virtio::scsi::commands::Command;
 use crate::virtio::scsi::constants::CHECK_CONDITION;
 use crate::virtio::scsi::constants::GOOD;
+use crate::virtio::scsi::constants::HARDWARE_ERROR;
 use crate::virtio::scsi::constants::ILLEGAL_REQUEST;
 use crate::virtio::scsi::constants::MEDIUM_ERROR;
+use crate::virtio::scsi::constants::MISCOMPARE;
+use crate::virtio::scsi::constants::NOT_READY;
+use crate::virtio::scsi::constants::RESERVATION_CONFLICT;
+use crate::virtio::scsi::constants::UNIT_ATTENTION;
+use crate::virtio::scsi::reservation::PersistentReservations;
+use crate::virtio::scsi::shared_disk::SharedDisk;
 use crate::virtio::DescriptorChain;
 use crate::virtio::DeviceType as VirtioDeviceType;
 use crate::virtio::Interrupt;
@@ -97,6 +123,14 @@ const MAX_SECTORS: u32 = u32::MAX;
 // <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
 const FIXED_FORMAT_SENSE_SIZE: u32 = 18;
 
+// CD-ROM drives always use 2048-byte sectors.
+const CDROM_BLOCK_SIZE: u32 = 2048;
+// The block size of the disks attached while the device is running.
+const HOTPLUG_BLOCK_SIZE: u32 = 512;
+// The maximum number of events kept for the driver until it provides buffers in the eventq. Older
+// events are dropped and the driver is told that it missed events.
+const MAX_PENDING_EVENTS: usize = 64;
+
 #[repr(C, packed)]
 #[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
 struct VirtioScsiCmdReqHeader {
@@ -129,20 +163,40 @@ impl VirtioScsiCmdRespHeader {
     }
 }
 
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+struct VirtioScsiEvent {
+    event: u32,
+    lun: [u8; 8],
+    reason: u32,
+}
+
 /// Errors that happen while handling scsi commands.
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum ExecuteError {
+    #[error("failed to read from disk: {0}")]
+    DiskRead(disk::Error),
     #[error("invalid cdb field")]
     InvalidField,
+    #[error("invalid field in parameter list")]
+    InvalidFieldInParamList,
     #[error("invalid parameter length")]
     InvalidParamLen,
+    #[error("invalid release of persistent reservation")]
+    InvalidRelease,
     #[error("{length} bytes from sector {sector} exceeds end of this device {max_lba}")]
     LbaOutOfRange {
         length: usize,
         sector: u64,
         max_lba: u64,
     },
+    #[error("medium not present")]
+    MediumNotPresent,
+    #[error("medium removal prevented")]
+    MediumRemovalPrevented,
+    #[error("miscompare during verify at sector {0}")]
+    Miscompare(u64),
     #[error("failed to read message: {0}")]
     Read(io::Error),
     #[error("failed to read command from cdb")]
@@ -154,10 +208,16 @@ pub enum ExecuteError {
     },
     #[error("writing to a read only device")]
     ReadOnly,
+    #[error("reservation conflict")]
+    ReservationConflict,
+    #[error("failed to access the persistent reservations: {0}")]
+    ReservationFile(io::Error),
     #[error("saving parameters not supported")]
     SavingParamNotSupported,
     #[error("synchronization error")]
     SynchronizationError,
+    #[error("unit attention: {0:?}")]
+    UnitAttention(Sense),
     #[error("unsupported scsi command: {0}")]
     Unsupported(u8),
     #[error("failed to write message: {0}")]
@@ -176,7 +236,7 @@ impl ExecuteError {
         // The asc and ascq assignments are taken from the t10 SPC spec.
         // cf) Table 28 of <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
         let sense = match self {
-            Self::Read(_) | Self::ReadCommand => {
+            Self::Read(_) | Self::ReadCommand | Self::DiskRead(_) => {
                 // UNRECOVERED READ ERROR
                 Sense {
                     key: MEDIUM_ERROR,
@@ -200,6 +260,22 @@ impl ExecuteError {
                     ascq: 0x00,
                 }
             }
+            Self::InvalidFieldInParamList => {
+                // INVALID FIELD IN PARAMETER LIST
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x26,
+                    ascq: 0x00,
+                }
+            }
+            Self::InvalidRelease => {
+                // INVALID RELEASE OF PERSISTENT RESERVATION
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x26,
+                    ascq: 0x04,
+                }
+            }
             Self::InvalidParamLen => {
                 // INVALID PARAMETER LENGTH
                 Sense {
@@ -236,6 +312,39 @@ impl ExecuteError {
                 asc: 0x16,
                 ascq: 0x00,
             },
+            Self::MediumNotPresent => Sense {
+                // MEDIUM NOT PRESENT
+                key: NOT_READY,
+                asc: 0x3a,
+                ascq: 0x00,
+            },
+            Self::MediumRemovalPrevented => Sense {
+                // MEDIUM REMOVAL PREVENTED
+                key: ILLEGAL_REQUEST,
+                asc: 0x53,
+                ascq: 0x02,
+            },
+            Self::Miscompare(_) => Sense {
+                // MISCOMPARE DURING VERIFY OPERATION
+                key: MISCOMPARE,
+                asc: 0x1d,
+                ascq: 0x00,
+            },
+            Self::ReservationFile(_) => Sense {
+                // INTERNAL TARGET FAILURE
+                key: HARDWARE_ERROR,
+                asc: 0x44,
+                ascq: 0x00,
+            },
+            Self::UnitAttention(sense) => *sense,
+            // Reservation conflicts are reported with a status of their own and no sense data.
+            Self::ReservationConflict => {
+                let hdr = VirtioScsiCmdRespHeader {
+                    status: RESERVATION_CONFLICT,
+                    ..resp
+                };
+                return (hdr, Sense::default());
+            }
             // Ignore these errors.
             Self::ReadIo { resid, desc_error } | Self::WriteIo { resid, desc_error } => {
                 warn!("error while performing I/O {}", desc_error);
@@ -303,11 +412,26 @@ struct LogicalUnit {
     /// Block size of the target device.
     block_size: u32,
     read_only: bool,
+    /// Whether the logical unit is a CD-ROM drive.
+    cdrom: bool,
     // Represents the image on disk.
     disk_image: Box<dyn DiskFile>,
+    /// The persistent reservations of the logical unit. `None` for CD-ROM drives.
+    reservations: Option<PersistentReservations>,
 }
 
 impl LogicalUnit {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(LogicalUnit {
+            disk_image: self.disk_image.try_clone()?,
+            max_lba: self.max_lba,
+            block_size: self.block_size,
+            read_only: self.read_only,
+            cdrom: self.cdrom,
+            reservations: self.reservations.clone(),
+        })
+    }
+
     fn make_async(self, ex: &Executor) -> anyhow::Result<AsyncLogicalUnit> {
         let disk_image = self
             .disk_image
@@ -317,18 +441,65 @@ impl LogicalUnit {
             max_lba: self.max_lba,
             block_size: self.block_size,
             read_only: self.read_only,
-            disk_image,
+            disk_image: Some(disk_image),
+            cdrom: self.cdrom.then(CdromState::default),
+            unit_attention: Cell::new(None),
+            reservations: self.reservations,
         })
     }
 }
 
+/// Media events of a CD-ROM drive reported by the GET EVENT STATUS NOTIFICATION command.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MediaEvent {
+    /// The media status did not change.
+    #[default]
+    NoChange = 0,
+    /// The user requested the drive to eject the medium.
+    EjectRequest = 1,
+    /// A medium was inserted.
+    NewMedia = 2,
+    /// The medium was removed.
+    MediaRemoval = 3,
+}
+
+/// State of the removable medium of a CD-ROM drive.
+#[derive(Default)]
+pub struct CdromState {
+    /// Whether the guest opened the tray with START STOP UNIT. The medium stays in the tray and is
+    /// loaded again when the guest closes it.
+    pub tray_open: Cell<bool>,
+    /// Whether the guest prevented the removal of the medium.
+    pub prevent_removal: Cell<bool>,
+    /// The media event to report to the guest.
+    pub media_event: Cell<MediaEvent>,
+}
+
 /// A logical unit with an AsyncDisk as the disk.
 pub struct AsyncLogicalUnit {
     pub max_lba: u64,
     pub block_size: u32,
     pub read_only: bool,
-    // Represents the async image on disk.
-    pub disk_image: Box<dyn AsyncDisk>,
+    // Represents the async image on disk. `None` if there is no medium in a CD-ROM drive.
+    pub disk_image: Option<Box<dyn AsyncDisk>>,
+    // State of the medium if the logical unit is a CD-ROM drive.
+    pub cdrom: Option<CdromState>,
+    // Unit attention condition to report in response to the next command.
+    pub unit_attention: Cell<Option<Sense>>,
+    // The persistent reservations of the logical unit. `None` for CD-ROM drives.
+    pub reservations: Option<PersistentReservations>,
+}
+
+impl AsyncLogicalUnit {
+    /// Returns the disk image if the guest can access it.
+    pub fn medium(&self) -> Result<&dyn AsyncDisk, ExecuteError> {
+        if matches!(&self.cdrom, Some(cdrom) if cdrom.tray_open.get()) {
+            return Err(ExecuteError::MediumNotPresent);
+        }
+        self.disk_image
+            .as_deref()
+            .ok_or(ExecuteError::MediumNotPresent)
+    }
 }
 
 type TargetId = u8;
@@ -339,18 +510,7 @@ impl Targets {
         let logical_units = self
             .0
             .iter()
-            .map(|(id, logical_unit)| {
-                let disk_image = logical_unit.disk_image.try_clone()?;
-                Ok((
-                    *id,
-                    LogicalUnit {
-                        disk_image,
-                        max_lba: logical_unit.max_lba,
-                        block_size: logical_unit.block_size,
-                        read_only: logical_unit.read_only,
-                    },
-                ))
-            })
+            .map(|(id, logical_unit)| Ok((*id, logical_unit.try_clone()?)))
             .collect::<io::Result<_>>()?;
         Ok(Self(logical_units))
     }
@@ -368,6 +528,14 @@ pub struct DiskConfig {
     pub block_size: u32,
     /// Indicates whether the SCSI disk is read only.
     pub read_only: bool,
+    /// Indicates whether the device is a CD-ROM drive. CD-ROM drives are read only and have a
+    /// block size of 2048 bytes regardless of `block_size` and `read_only`.
+    pub cdrom: bool,
+    /// The file holding the persistent reservations of a disk shared with other crosvm
+    /// instances. Other disks keep their reservations in memory.
+    pub reservation_file: Option<File>,
+    /// The identifier of this crosvm instance among the initiators of a shared disk.
+    pub initiator_id: u64,
 }
 
 /// Vitio device for exposing SCSI command operations on a host file.
@@ -389,12 +557,22 @@ pub struct Controller {
     // Whether the devices handles requests in multiple request queues.
     // If true, each virtqueue will be handled in a separate worker thread.
     multi_queue: bool,
+    // Tube for the commands that change the logical units and the media of the CD-ROM drives.
+    control_tube: Option<Tube>,
 }
 
 impl Controller {
     /// Creates a virtio-scsi device.
-    pub fn new(base_features: u64, disks: Vec<DiskConfig>) -> anyhow::Result<Self> {
-        let multi_queue = disks.iter().all(|disk| disk.file.try_clone().is_ok());
+    pub fn new(
+        base_features: u64,
+        disks: Vec<DiskConfig>,
+        control_tube: Option<Tube>,
+    ) -> anyhow::Result<Self> {
+        // Every request worker has its own copy of the state of the CD-ROM drives, such as whether
+        // the guest prevented the removal of the medium, so they can use only one worker.
+        let multi_queue = disks
+            .iter()
+            .all(|disk| !disk.cdrom && disk.file.try_clone().is_ok());
         let num_queues = if multi_queue {
             MAX_NUM_QUEUES
         } else {
@@ -404,23 +582,35 @@ impl Controller {
             .into_iter()
             .enumerate()
             .map(|(i, disk)| {
+                let (block_size, read_only) = if disk.cdrom {
+                    (CDROM_BLOCK_SIZE, true)
+                } else {
+                    (disk.block_size, disk.read_only)
+                };
                 let max_lba = disk
                     .file
                     .get_len()
                     .context("Failed to get the length of the disk image")?
-                    / disk.block_size as u64;
+                    / block_size as u64;
+                let reservations = match disk.reservation_file {
+                    _ if disk.cdrom => None,
+                    Some(file) => Some(PersistentReservations::with_file(file, disk.initiator_id)),
+                    None => Some(PersistentReservations::new()),
+                };
                 let target = LogicalUnit {
                     max_lba,
-                    block_size: disk.block_size,
-                    read_only: disk.read_only,
+                    block_size,
+                    read_only,
+                    cdrom: disk.cdrom,
                     disk_image: disk.file,
+                    reservations,
                 };
                 Ok((i as TargetId, target))
             })
             .collect::<anyhow::Result<_>>()?;
         // b/300560198: Support feature bits in virtio-scsi.
         Ok(Self {
-            avail_features: base_features,
+            avail_features: base_features | 1 << VIRTIO_SCSI_F_HOTPLUG,
             queue_sizes: vec![DEFAULT_QUEUE_SIZE; num_queues],
             seg_max: get_seg_max(DEFAULT_QUEUE_SIZE),
             sense_size: VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
@@ -429,6 +619,7 @@ impl Controller {
             worker_threads: vec![],
             targets: Some(Targets(logical_units)),
             multi_queue,
+            control_tube,
         })
     }
 
@@ -453,7 +644,7 @@ impl Controller {
     fn execute_control(
         reader: &mut Reader,
         writer: &mut Writer,
-        target_ids: &BTreeSet<TargetId>,
+        target_ids: &RefCell<BTreeSet<TargetId>>,
     ) -> Result<(), ExecuteError> {
         let typ = reader.peek_obj::<u32>().map_err(ExecuteError::Read)?;
         match typ {
@@ -461,7 +652,7 @@ impl Controller {
                 let tmf = reader
                     .read_obj::<virtio_scsi_ctrl_tmf_req>()
                     .map_err(ExecuteError::Read)?;
-                let resp = Self::execute_tmf(tmf, target_ids);
+                let resp = Self::execute_tmf(tmf, &target_ids.borrow());
                 writer.write_obj(resp).map_err(ExecuteError::Write)?;
                 Ok(())
             }
@@ -517,19 +708,29 @@ impl Controller {
         reader: &mut Reader,
         resp_writer: &mut Writer,
         data_writer: &mut Writer,
-        targets: &BTreeMap<TargetId, AsyncLogicalUnit>,
+        targets: &AsyncRwLock<BTreeMap<TargetId, AsyncLogicalUnit>>,
         sense_size: u32,
         cdb_size: u32,
     ) -> Result<(), ExecuteError> {
         let req_header = reader
             .read_obj::<VirtioScsiCmdReqHeader>()
             .map_err(ExecuteError::Read)?;
-        match Self::get_logical_unit(req_header.lun, targets) {
+        let targets = targets.read_lock().await;
+        match Self::get_logical_unit(req_header.lun, &targets) {
             Some(target) => {
                 let mut cdb = vec![0; cdb_size as usize];
                 reader.read_exact(&mut cdb).map_err(ExecuteError::Read)?;
                 let command = Command::new(&cdb)?;
-                match command.execute(reader, data_writer, target).await {
+                let unit_attention = if command.reports_unit_attention() {
+                    target.unit_attention.take()
+                } else {
+                    None
+                };
+                let result = match unit_attention {
+                    Some(sense) => Err(ExecuteError::UnitAttention(sense)),
+                    None => command.execute(reader, data_writer, target).await,
+                };
+                match result {
                     Ok(()) => {
                         let hdr = VirtioScsiCmdRespHeader {
                             sense_len: 0,
@@ -543,7 +744,11 @@ impl Controller {
                         Ok(())
                     }
                     Err(err) => {
-                        error!("error while executing a scsi request: {err}");
+                        match err {
+                            // CD-ROM drives report these in the course of normal operation.
+                            ExecuteError::MediumNotPresent | ExecuteError::UnitAttention(_) => {}
+                            _ => error!("error while executing a scsi request: {err}"),
+                        }
                         let (hdr, sense) = err.as_resp();
                         resp_writer.write_obj(hdr).map_err(ExecuteError::Write)?;
                         sense.write_to(resp_writer, sense_size)
@@ -583,14 +788,22 @@ impl Controller {
 
 impl VirtioDevice for Controller {
     fn keep_rds(&self) -> Vec<base::RawDescriptor> {
-        match &self.targets {
+        let mut keep_rds = match &self.targets {
             Some(targets) => targets
                 .0
                 .values()
-                .flat_map(|t| t.disk_image.as_raw_descriptors())
+                .flat_map(|t| {
+                    let mut rds = t.disk_image.as_raw_descriptors();
+                    rds.extend(t.reservations.as_ref().and_then(|r| r.as_raw_descriptor()));
+                    rds
+                })
                 .collect(),
             None => vec![],
+        };
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
         }
+        keep_rds
     }
 
     fn features(&self) -> u64 {
@@ -649,14 +862,13 @@ impl VirtioDevice for Controller {
         // 0th virtqueue is the controlq.
         let controlq = queues.remove(&0).context("controlq should be present")?;
         // 1st virtqueue is the eventq.
-        // We do not send any events through eventq.
-        let _eventq = queues.remove(&1).context("eventq should be present")?;
+        let eventq = queues.remove(&1).context("eventq should be present")?;
         let targets = self.targets.take().context("failed to take SCSI targets")?;
         let target_ids = targets.target_ids();
         let sense_size = self.sense_size;
         let cdb_size = self.cdb_size;
         // The rest of the queues are request queues.
-        let request_queues = if self.multi_queue {
+        let request_queues: Vec<(Queue, Targets)> = if self.multi_queue {
             queues
                 .into_values()
                 .map(|queue| {
@@ -675,8 +887,13 @@ impl VirtioDevice for Controller {
                 targets,
             )]
         };
+        // The controlq worker forwards the commands from the host to the request workers, which
+        // own the logical units.
+        let (request_workers, worker_commands): (Vec<_>, Vec<_>) =
+            request_queues.iter().map(|_| mpsc::unbounded()).unzip();
 
         let intr = interrupt.clone();
+        let control_tube = self.control_tube.take();
         let worker_thread = WorkerThread::start("v_scsi_ctrlq", move |kill_evt| {
             let ex = Executor::with_executor_kind(executor_kind.into())
                 .expect("Failed to create an executor");
@@ -686,7 +903,14 @@ impl VirtioDevice for Controller {
                     intr,
                     controlq,
                     kill_evt,
-                    QueueType::Control { target_ids },
+                    QueueType::Control {
+                        target_ids: RefCell::new(target_ids),
+                    },
+                    WorkerControl::Host {
+                        control_tube,
+                        eventq,
+                        request_workers,
+                    },
                     sense_size,
                     cdb_size,
                 ))
@@ -697,7 +921,9 @@ impl VirtioDevice for Controller {
         });
         self.worker_threads.push(worker_thread);
 
-        for (i, (queue, targets)) in request_queues.into_iter().enumerate() {
+        for (i, ((queue, targets), worker_commands)) in
+            request_queues.into_iter().zip(worker_commands).enumerate()
+        {
             let interrupt = interrupt.clone();
             let worker_thread =
                 WorkerThread::start(format!("v_scsi_req_{}", i + 2), move |kill_evt| {
@@ -717,7 +943,8 @@ impl VirtioDevice for Controller {
                             interrupt,
                             queue,
                             kill_evt,
-                            QueueType::Request(async_logical_unit),
+                            QueueType::Request(AsyncRwLock::new(async_logical_unit)),
+                            WorkerControl::Forwarded(worker_commands),
                             sense_size,
                             cdb_size,
                         ))
@@ -733,8 +960,41 @@ impl VirtioDevice for Controller {
 }
 
 enum QueueType {
-    Control { target_ids: BTreeSet<TargetId> },
-    Request(BTreeMap<TargetId, AsyncLogicalUnit>),
+    Control {
+        target_ids: RefCell<BTreeSet<TargetId>>,
+    },
+    Request(AsyncRwLock<BTreeMap<TargetId, AsyncLogicalUnit>>),
+}
+
+/// A command forwarded by the controlq worker to a request worker.
+enum WorkerCommand {
+    ChangeMedia {
+        target: TargetId,
+        medium: Option<(PathBuf, File)>,
+        force: bool,
+    },
+    AddTarget {
+        target: TargetId,
+        logical_unit: LogicalUnit,
+    },
+    RemoveTarget {
+        target: TargetId,
+    },
+}
+
+type WorkerRequest = (WorkerCommand, oneshot::Sender<ScsiControlResult>);
+
+/// How a worker takes part in handling the commands from the host.
+enum WorkerControl {
+    /// The controlq worker receives the commands from `control_tube`, forwards them to
+    /// `request_workers` and notifies the driver of changes to the logical units through `eventq`.
+    Host {
+        control_tube: Option<Tube>,
+        eventq: Queue,
+        request_workers: Vec<mpsc::UnboundedSender<WorkerRequest>>,
+    },
+    /// A request worker executes the commands forwarded by the controlq worker.
+    Forwarded(mpsc::UnboundedReceiver<WorkerRequest>),
 }
 
 async fn run_worker(
@@ -743,6 +1003,7 @@ async fn run_worker(
     queue: Queue,
     kill_evt: Event,
     queue_type: QueueType,
+    worker_control: WorkerControl,
     sense_size: u32,
     cdb_size: u32,
 ) -> anyhow::Result<()> {
@@ -759,26 +1020,542 @@ async fn run_worker(
     let queue_handler = handle_queue(
         Rc::new(RefCell::new(queue)),
         EventAsync::new(kick_evt, ex).expect("Failed to create async event for queue"),
-        interrupt,
-        queue_type,
+        interrupt.clone(),
+        &queue_type,
         sense_size,
         cdb_size,
     )
     .fuse();
     pin_mut!(queue_handler);
 
+    let control_handler = match worker_control {
+        WorkerControl::Host {
+            control_tube,
+            eventq,
+            request_workers,
+        } => handle_host_commands(
+            ex,
+            control_tube,
+            eventq,
+            interrupt,
+            &queue_type,
+            request_workers,
+        )
+        .left_future(),
+        WorkerControl::Forwarded(worker_commands) => {
+            handle_worker_commands(ex, worker_commands, &queue_type).right_future()
+        }
+    }
+    .fuse();
+    pin_mut!(control_handler);
+
     futures::select! {
         _ = queue_handler => anyhow::bail!("queue handler exited unexpectedly"),
+        r = control_handler => r.context("failed to handle a control command"),
         r = resample => r.context("failed to resample an irq value"),
         r = kill => r.context("failed to wait on the kill event"),
     }
 }
 
+/// Events for the driver that wait for buffers in the eventq.
+struct EventQueue {
+    queue: Queue,
+    interrupt: Interrupt,
+    pending: VecDeque<VirtioScsiEvent>,
+    // Whether events were dropped since the last event that was delivered.
+    events_missed: bool,
+}
+
+impl EventQueue {
+    fn push(&mut self, event: VirtioScsiEvent) {
+        if self.pending.len() == MAX_PENDING_EVENTS {
+            self.pending.pop_front();
+            self.events_missed = true;
+        }
+        self.pending.push_back(event);
+        self.flush();
+    }
+
+    // Writes as many pending events as the driver has provided buffers for.
+    fn flush(&mut self) {
+        let mut delivered = false;
+        while !self.pending.is_empty() {
+            let Some(mut chain) = self.queue.pop() else {
+                break;
+            };
+            let mut event = self.pending.pop_front().unwrap();
+            if self.events_missed {
+                event.event |= VIRTIO_SCSI_T_EVENTS_MISSED;
+                self.events_missed = false;
+            }
+            if let Err(e) = chain.writer.write_obj(event) {
+                error!("failed to write a virtio-scsi event: {e}");
+            }
+            let len = chain.writer.bytes_written() as u32;
+            self.queue.add_used(chain, len);
+            delivered = true;
+        }
+        if delivered {
+            self.queue.trigger_interrupt(&self.interrupt);
+        }
+    }
+}
+
+// Returns a transport reset event which tells the driver that the logical unit of `target` was
+// attached or detached depending on `reason`.
+fn transport_reset_event(target: TargetId, reason: u32) -> VirtioScsiEvent {
+    VirtioScsiEvent {
+        event: VIRTIO_SCSI_T_TRANSPORT_RESET,
+        lun: [1, target, 0, 0, 0, 0, 0, 0],
+        reason,
+    }
+}
+
+async fn handle_host_commands(
+    ex: &Executor,
+    control_tube: Option<Tube>,
+    eventq: Queue,
+    interrupt: Interrupt,
+    queue_type: &QueueType,
+    request_workers: Vec<mpsc::UnboundedSender<WorkerRequest>>,
+) -> anyhow::Result<()> {
+    let (control_tube, target_ids) = match (control_tube, queue_type) {
+        (Some(control_tube), QueueType::Control { target_ids }) => (control_tube, target_ids),
+        _ => {
+            futures::future::pending::<()>().await;
+            return Ok(());
+        }
+    };
+    let control_tube =
+        AsyncTube::new(ex, control_tube).context("failed to create an async tube")?;
+    let kick_evt = eventq
+        .event()
+        .try_clone()
+        .context("failed to clone the eventq event")?;
+    let kick_evt =
+        EventAsync::new(kick_evt, ex).context("failed to create an async event for the eventq")?;
+    let events = RefCell::new(EventQueue {
+        queue: eventq,
+        interrupt,
+        pending: VecDeque::new(),
+        events_missed: false,
+    });
+
+    let eventq_handler = handle_eventq(kick_evt, &events).fuse();
+    pin_mut!(eventq_handler);
+    let command_handler =
+        handle_control_tube(&control_tube, target_ids, &request_workers, &events).fuse();
+    pin_mut!(command_handler);
+
+    futures::select! {
+        r = eventq_handler => r,
+        r = command_handler => r,
+    }
+}
+
+async fn handle_eventq(kick_evt: EventAsync, events: &RefCell<EventQueue>) -> anyhow::Result<()> {
+    loop {
+        kick_evt
+            .next_val()
+            .await
+            .context("failed to read the eventq event")?;
+        events.borrow_mut().flush();
+    }
+}
+
+async fn handle_control_tube(
+    control_tube: &AsyncTube,
+    target_ids: &RefCell<BTreeSet<TargetId>>,
+    request_workers: &[mpsc::UnboundedSender<WorkerRequest>],
+    events: &RefCell<EventQueue>,
+) -> anyhow::Result<()> {
+    loop {
+        let command = control_tube
+            .next::<ScsiControlCommand>()
+            .await
+            .context("failed to receive a command")?;
+        let result = execute_host_command(command, target_ids, request_workers, events).await;
+        control_tube
+            .send(result)
+            .await
+            .context("failed to send a response")?;
+    }
+}
+
+async fn execute_host_command(
+    command: ScsiControlCommand,
+    target_ids: &RefCell<BTreeSet<TargetId>>,
+    request_workers: &[mpsc::UnboundedSender<WorkerRequest>],
+    events: &RefCell<EventQueue>,
+) -> ScsiControlResult {
+    match command {
+        ScsiControlCommand::ChangeMedia { target, path, file } => {
+            forward_to_workers(request_workers, || {
+                Ok(WorkerCommand::ChangeMedia {
+                    target,
+                    medium: Some((path.clone(), file.try_clone()?)),
+                    force: false,
+                })
+            })
+            .await
+        }
+        ScsiControlCommand::Eject { target, force } => {
+            forward_to_workers(request_workers, || {
+                Ok(WorkerCommand::ChangeMedia {
+                    target,
+                    medium: None,
+                    force,
+                })
+            })
+            .await
+        }
+        ScsiControlCommand::AddDisk {
+            path,
+            file,
+            read_only,
+        } => {
+            let Some(target) = (0..=DEFAULT_MAX_TARGET)
+                .map(|target| target as TargetId)
+                .find(|target| !target_ids.borrow().contains(target))
+            else {
+                error!("No SCSI target is available for {}", path.display());
+                return ScsiControlResult::Err(SysError::new(libc::ENOSPC));
+            };
+            info!("Attaching {} to SCSI target {target}", path.display());
+            let logical_unit = disk::create_disk_file(file, true, disk::MAX_NESTING_DEPTH, &path)
+                .context("failed to open the disk image")
+                .and_then(|disk_image| {
+                    let len = disk_image
+                        .get_len()
+                        .context("failed to get the length of the disk image")?;
+                    // Every request worker needs its own instance of the logical unit. Clones of
+                    // most disk images don't share their state, so the workers share one image
+                    // through a thread that owns it instead.
+                    let disk_image: Box<dyn DiskFile> = if request_workers.len() > 1 {
+                        Box::new(SharedDisk::new(disk_image)?)
+                    } else {
+                        disk_image
+                    };
+                    Ok(LogicalUnit {
+                        max_lba: len / HOTPLUG_BLOCK_SIZE as u64,
+                        block_size: HOTPLUG_BLOCK_SIZE,
+                        read_only,
+                        cdrom: false,
+                        disk_image,
+                        reservations: Some(PersistentReservations::new()),
+                    })
+                });
+            let logical_unit = match logical_unit {
+                Ok(logical_unit) => logical_unit,
+                Err(e) => {
+                    error!("Opening {} failed: {e:#}", path.display());
+                    return ScsiControlResult::Err(SysError::new(libc::EIO));
+                }
+            };
+            let result = attach_to_workers(request_workers, target, &logical_unit).await;
+            if result != ScsiControlResult::Ok {
+                return result;
+            }
+            target_ids.borrow_mut().insert(target);
+            events
+                .borrow_mut()
+                .push(transport_reset_event(target, VIRTIO_SCSI_EVT_RESET_RESCAN));
+            ScsiControlResult::TargetAdded { target }
+        }
+        ScsiControlCommand::RemoveTarget { target } => {
+            if !target_ids.borrow().contains(&target) {
+                error!("SCSI target {target} does not exist");
+                return ScsiControlResult::Err(SysError::new(libc::ENODEV));
+            }
+            info!("Detaching SCSI target {target}");
+            let result = forward_to_workers(request_workers, || {
+                Ok(WorkerCommand::RemoveTarget { target })
+            })
+            .await;
+            if result != ScsiControlResult::Ok {
+                return result;
+            }
+            target_ids.borrow_mut().remove(&target);
+            events
+                .borrow_mut()
+                .push(transport_reset_event(target, VIRTIO_SCSI_EVT_RESET_REMOVED));
+            ScsiControlResult::Ok
+        }
+    }
+}
+
+/// Sends a command made by `make_command` to every request worker and returns the first error
+/// reported by the workers.
+async fn forward_to_workers(
+    request_workers: &[mpsc::UnboundedSender<WorkerRequest>],
+    make_command: impl FnMut() -> io::Result<WorkerCommand>,
+) -> ScsiControlResult {
+    match send_to_workers(request_workers, make_command).await {
+        Ok(results) => first_error(results),
+        Err(e) => ScsiControlResult::Err(e.into()),
+    }
+}
+
+/// Sends a command made by `make_command` to every request worker and returns the result of each
+/// worker, in the order of `request_workers`.
+async fn send_to_workers(
+    request_workers: &[mpsc::UnboundedSender<WorkerRequest>],
+    mut make_command: impl FnMut() -> io::Result<WorkerCommand>,
+) -> io::Result<Vec<ScsiControlResult>> {
+    // Make all the commands before sending any of them so that either every worker or none of
+    // them executes the command.
+    let commands = request_workers
+        .iter()
+        .map(|_| make_command())
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| {
+            error!("failed to prepare a command for the request workers: {e}");
+            e
+        })?;
+    let mut responses = Vec::new();
+    for (worker, command) in request_workers.iter().zip(commands) {
+        let (response_sender, response) = oneshot::channel();
+        if worker.unbounded_send((command, response_sender)).is_err() {
+            error!("a request worker exited");
+            responses.push(None);
+        } else {
+            responses.push(Some(response));
+        }
+    }
+    let mut results = Vec::new();
+    for response in responses {
+        let result = match response {
+            Some(response) => response.await.unwrap_or_else(|oneshot::Canceled| {
+                error!("a request worker exited without a response");
+                ScsiControlResult::Err(SysError::new(libc::EIO))
+            }),
+            None => ScsiControlResult::Err(SysError::new(libc::EIO)),
+        };
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Attaches `logical_unit` as `target` to every request worker. If any worker fails, the target
+/// is detached from the workers that attached it, so that none of them serves a target the guest
+/// is never told about.
+async fn attach_to_workers(
+    request_workers: &[mpsc::UnboundedSender<WorkerRequest>],
+    target: TargetId,
+    logical_unit: &LogicalUnit,
+) -> ScsiControlResult {
+    let results = match send_to_workers(request_workers, || {
+        Ok(WorkerCommand::AddTarget {
+            target,
+            logical_unit: logical_unit.try_clone()?,
+        })
+    })
+    .await
+    {
+        Ok(results) => results,
+        Err(e) => return ScsiControlResult::Err(e.into()),
+    };
+    if results
+        .iter()
+        .all(|result| *result == ScsiControlResult::Ok)
+    {
+        return ScsiControlResult::Ok;
+    }
+    for (worker, result) in request_workers.iter().zip(&results) {
+        if *result != ScsiControlResult::Ok {
+            continue;
+        }
+        let rollback = forward_to_workers(std::slice::from_ref(worker), || {
+            Ok(WorkerCommand::RemoveTarget { target })
+        })
+        .await;
+        if rollback != ScsiControlResult::Ok {
+            error!("failed to detach SCSI target {target} after a failed attach");
+        }
+    }
+    first_error(results)
+}
+
+fn first_error(results: impl IntoIterator<Item = ScsiControlResult>) -> ScsiControlResult {
+    results
+        .into_iter()
+        .find(|result| *result != ScsiControlResult::Ok)
+        .unwrap_or(ScsiControlResult::Ok)
+}
+
+async fn handle_worker_commands(
+    ex: &Executor,
+    mut worker_commands: mpsc::UnboundedReceiver<WorkerRequest>,
+    queue_type: &QueueType,
+) -> anyhow::Result<()> {
+    let QueueType::Request(targets) = queue_type else {
+        anyhow::bail!("commands can only be forwarded to request workers");
+    };
+    while let Some((command, response)) = worker_commands.next().await {
+        let result = match command {
+            WorkerCommand::ChangeMedia {
+                target,
+                medium,
+                force,
+            } => change_media(ex, targets, target, medium, force).await,
+            WorkerCommand::AddTarget {
+                target,
+                logical_unit,
+            } => add_target(ex, targets, target, logical_unit).await,
+            WorkerCommand::RemoveTarget { target } => remove_target(targets, target).await,
+        };
+        // The controlq worker may have stopped waiting for the response.
+        let _ = response.send(result);
+    }
+    // The controlq worker exited, so there are no more commands. The worker keeps handling
+    // requests until it is killed.
+    futures::future::pending::<()>().await;
+    Ok(())
+}
+
+// Establishes REPORTED LUNS DATA HAS CHANGED unit attention conditions. crosvm has a single
+// logical unit per target, so the change is reported to the remaining logical units of the
+// controller, which the driver reaches through the same initiator port.
+fn report_luns_changed(targets: &BTreeMap<TargetId, AsyncLogicalUnit>) {
+    for logical_unit in targets.values() {
+        // Do not replace a pending condition, such as a media change.
+        if logical_unit.unit_attention.get().is_none() {
+            logical_unit.unit_attention.set(Some(Sense {
+                key: UNIT_ATTENTION,
+                asc: 0x3f,
+                ascq: 0x0e,
+            }));
+        }
+    }
+}
+
+async fn add_target(
+    ex: &Executor,
+    targets: &AsyncRwLock<BTreeMap<TargetId, AsyncLogicalUnit>>,
+    target: TargetId,
+    logical_unit: LogicalUnit,
+) -> ScsiControlResult {
+    let logical_unit = match logical_unit.make_async(ex) {
+        Ok(logical_unit) => logical_unit,
+        Err(e) => {
+            error!("failed to attach SCSI target {target}: {e:#}");
+            return ScsiControlResult::Err(SysError::new(libc::EIO));
+        }
+    };
+    let mut targets = targets.lock().await;
+    if targets.contains_key(&target) {
+        error!("SCSI target {target} already exists");
+        return ScsiControlResult::Err(SysError::new(libc::EEXIST));
+    }
+    report_luns_changed(&targets);
+    targets.insert(target, logical_unit);
+    ScsiControlResult::Ok
+}
+
+async fn remove_target(
+    targets: &AsyncRwLock<BTreeMap<TargetId, AsyncLogicalUnit>>,
+    target: TargetId,
+) -> ScsiControlResult {
+    // Wait for the requests in flight on the logical unit to finish.
+    let mut targets = targets.lock().await;
+    if targets.remove(&target).is_none() {
+        error!("SCSI target {target} does not exist");
+        return ScsiControlResult::Err(SysError::new(libc::ENODEV));
+    }
+    report_luns_changed(&targets);
+    ScsiControlResult::Ok
+}
+
+/// Inserts `medium` into the CD-ROM drive of `target`, or ejects the medium if `medium` is
+/// `None`. Unless `force` is set, the medium is not removed if the guest prevented it.
+async fn change_media(
+    ex: &Executor,
+    targets: &AsyncRwLock<BTreeMap<TargetId, AsyncLogicalUnit>>,
+    target: TargetId,
+    medium: Option<(PathBuf, File)>,
+    force: bool,
+) -> ScsiControlResult {
+    // Acquire exclusive access so that no request is in flight on the old medium while it is
+    // replaced.
+    let mut targets = targets.lock().await;
+    let Some(logical_unit) = targets.get_mut(&target) else {
+        error!("SCSI target {target} does not exist");
+        return ScsiControlResult::Err(SysError::new(libc::ENODEV));
+    };
+    let Some(cdrom) = &logical_unit.cdrom else {
+        error!("SCSI target {target} is not a CD-ROM drive");
+        return ScsiControlResult::Err(SysError::new(libc::ENOTSUP));
+    };
+
+    if logical_unit.disk_image.is_some() && cdrom.prevent_removal.get() && !force {
+        // Let the guest know that the user wants the medium, so that it can allow the removal.
+        cdrom.media_event.set(MediaEvent::EjectRequest);
+        error!("The guest prevented the removal of the medium of SCSI target {target}");
+        return ScsiControlResult::Err(SysError::new(libc::EBUSY));
+    }
+
+    let (disk_image, media_event, unit_attention) = match medium {
+        Some((path, file)) => {
+            info!(
+                "Changing the medium of SCSI target {target} to {}",
+                path.display()
+            );
+            let disk_image = disk::create_disk_file(file, true, disk::MAX_NESTING_DEPTH, &path)
+                .and_then(|disk_image| disk_image.to_async_disk(ex));
+            match disk_image {
+                // NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+                Ok(disk_image) => (
+                    Some(disk_image),
+                    MediaEvent::NewMedia,
+                    Sense {
+                        key: UNIT_ATTENTION,
+                        asc: 0x28,
+                        ascq: 0x00,
+                    },
+                ),
+                Err(e) => {
+                    error!("Opening the new medium failed: {e:#}");
+                    return ScsiControlResult::Err(SysError::new(libc::EIO));
+                }
+            }
+        }
+        None => {
+            info!("Ejecting the medium of SCSI target {target}");
+            // MEDIUM NOT PRESENT
+            (
+                None,
+                MediaEvent::MediaRemoval,
+                Sense {
+                    key: UNIT_ATTENTION,
+                    asc: 0x3a,
+                    ascq: 0x00,
+                },
+            )
+        }
+    };
+
+    let max_lba = match disk_image.as_ref().map(|disk_image| disk_image.get_len()) {
+        Some(Ok(len)) => len / logical_unit.block_size as u64,
+        Some(Err(e)) => {
+            error!("Getting the size of the new medium failed: {e:#}");
+            return ScsiControlResult::Err(SysError::new(libc::EIO));
+        }
+        None => 0,
+    };
+
+    cdrom.tray_open.set(false);
+    cdrom.media_event.set(media_event);
+    logical_unit.unit_attention.set(Some(unit_attention));
+    logical_unit.disk_image = disk_image;
+    logical_unit.max_lba = max_lba;
+    ScsiControlResult::Ok
+}
+
 async fn handle_queue(
     queue: Rc<RefCell<Queue>>,
     evt: EventAsync,
     interrupt: Interrupt,
-    queue_type: QueueType,
+    queue_type: &QueueType,
     sense_size: u32,
     cdb_size: u32,
 ) {
@@ -798,12 +1575,7 @@ async fn handle_queue(
         }
         while let Some(chain) = queue.borrow_mut().pop() {
             background_tasks.push(process_one_chain(
-                &queue,
-                chain,
-                &interrupt,
-                &queue_type,
-                sense_size,
-                cdb_size,
+                &queue, chain, &interrupt, queue_type, sense_size, cdb_size,
             ));
         }
     }
@@ -888,7 +1660,36 @@ mod tests {
 
     use super::*;
     use crate::virtio::create_descriptor_chain;
+    use crate::virtio::scsi::constants::GET_EVENT_STATUS_NOTIFICATION;
+    use crate::virtio::scsi::constants::INQUIRY;
+    use crate::virtio::scsi::constants::MAINTENANCE_IN;
+    use crate::virtio::scsi::constants::PERSISTENT_RESERVE_IN;
+    use crate::virtio::scsi::constants::PERSISTENT_RESERVE_OUT;
+    use crate::virtio::scsi::constants::PREVENT_ALLOW_MEDIUM_REMOVAL;
+    use crate::virtio::scsi::constants::PR_PREEMPT;
+    use crate::virtio::scsi::constants::PR_READ_KEYS;
+    use crate::virtio::scsi::constants::PR_READ_RESERVATION;
+    use crate::virtio::scsi::constants::PR_REGISTER;
+    use crate::virtio::scsi::constants::PR_RELEASE;
+    use crate::virtio::scsi::constants::PR_RESERVE;
     use crate::virtio::scsi::constants::READ_10;
+    use crate::virtio::scsi::constants::READ_12;
+    use crate::virtio::scsi::constants::READ_16;
+    use crate::virtio::scsi::constants::READ_CAPACITY_10;
+    use crate::virtio::scsi::constants::READ_TOC;
+    use crate::virtio::scsi::constants::SERVICE_ACTION_IN_16;
+    use crate::virtio::scsi::constants::START_STOP_UNIT;
+    use crate::virtio::scsi::constants::TEST_UNIT_READY;
+    use crate::virtio::scsi::constants::VERIFY_10;
+    use crate::virtio::scsi::constants::VERIFY_12;
+    use crate::virtio::scsi::constants::VERIFY_16;
+    use crate::virtio::scsi::constants::WRITE_10;
+    use crate::virtio::scsi::constants::WRITE_12;
+    use crate::virtio::scsi::constants::WRITE_16;
+    use crate::virtio::scsi::constants::WRITE_AND_VERIFY_12;
+    use crate::virtio::scsi::constants::WRITE_AND_VERIFY_16;
+    use crate::virtio::scsi::reservation::EXCLUSIVE_ACCESS;
+    use crate::virtio::scsi::reservation::WRITE_EXCLUSIVE;
     use crate::virtio::DescriptorType;
 
     fn setup_disk(disk_size: u64) -> (File, Vec<u8>) {
@@ -971,14 +1772,17 @@ mod tests {
                     max_lba: 0x1000,
                     block_size,
                     read_only: false,
-                    disk_image,
+                    disk_image: Some(disk_image),
+                    cdrom: None,
+                    unit_attention: Cell::new(None),
+                    reservations: None,
                 };
                 (i as TargetId, logical_unit)
             })
             .collect();
         ex.run_until(process_one_request(
             &mut avail_desc,
-            &QueueType::Request(targets),
+            &QueueType::Request(AsyncRwLock::new(targets)),
             VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
             VIRTIO_SCSI_CDB_DEFAULT_SIZE,
         ))
@@ -1078,4 +1882,787 @@ mod tests {
         test_read_blocks(3, blocks, start_lba, xfer_blocks, 128u32);
         test_read_blocks(3, blocks, start_lba, xfer_blocks, 512u32);
     }
+
+    fn setup_disk_queue(ex: &Executor, blocks: u64) -> QueueType {
+        QueueType::Request(AsyncRwLock::new(BTreeMap::from([(
+            0,
+            setup_logical_unit(ex, blocks),
+        )])))
+    }
+
+    // Builds the CDB of a 16-byte data-transfer command.
+    fn cdb_16(opcode: u8, flags: u8, lba: u64, blocks: u32) -> [u8; 16] {
+        let mut cdb = [0; 16];
+        cdb[0] = opcode;
+        cdb[1] = flags;
+        cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+        cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+        cdb
+    }
+
+    // Builds the CDB of a 12-byte data-transfer command.
+    fn cdb_12(opcode: u8, flags: u8, lba: u32, blocks: u32) -> [u8; 12] {
+        let mut cdb = [0; 12];
+        cdb[0] = opcode;
+        cdb[1] = flags;
+        cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+        cdb[6..10].copy_from_slice(&blocks.to_be_bytes());
+        cdb
+    }
+
+    #[test]
+    fn read_write_blocks_12_16() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let disk = setup_disk_queue(&ex, 8);
+        let data: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+
+        let (resp, _) =
+            execute_command_with_data_out(&ex, &disk, 0, &cdb_16(WRITE_16, 0, 3, 2), &data, 0);
+        assert_eq!(sense(&resp), None);
+        let (resp, read) = execute_command(&ex, &disk, &cdb_16(READ_16, 0, 3, 2), 1024);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(read, data);
+
+        let (resp, _) = execute_command_with_data_out(
+            &ex,
+            &disk,
+            0,
+            &cdb_12(WRITE_12, 0, 6, 1),
+            &data[..512],
+            0,
+        );
+        assert_eq!(sense(&resp), None);
+        let (resp, read) = execute_command(&ex, &disk, &cdb_12(READ_12, 0, 3, 4), 2048);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(&read[..1024], &data[..]);
+        assert_eq!(&read[1536..], &data[..512]);
+
+        // The upper half of a 64-bit LBA must not be ignored.
+        let (resp, _) = execute_command(&ex, &disk, &cdb_16(READ_16, 0, 1 << 32, 1), 512);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x21, 0x00)));
+        let (resp, _) = execute_command(&ex, &disk, &cdb_12(READ_12, 0, 7, 2), 1024);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x21, 0x00)));
+    }
+
+    #[test]
+    fn verify_blocks() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let disk = setup_disk_queue(&ex, 8);
+        let mut data: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+        let (resp, _) = execute_command_with_data_out(
+            &ex,
+            &disk,
+            0,
+            &cdb_16(WRITE_AND_VERIFY_16, 0x2, 1, 2),
+            &data,
+            0,
+        );
+        assert_eq!(sense(&resp), None);
+
+        // BYTCHK=1: compare the Data-Out buffer with the medium.
+        let cdb = cdb_16(VERIFY_16, 0x2, 1, 2);
+        let (resp, _) = execute_command_with_data_out(&ex, &disk, 0, &cdb, &data, 0);
+        assert_eq!(sense(&resp), None);
+        data[700] ^= 0xff;
+        let (resp, _) = execute_command_with_data_out(&ex, &disk, 0, &cdb, &data, 0);
+        assert_eq!(sense(&resp), Some((MISCOMPARE, 0x1d, 0x00)));
+
+        // BYTCHK=3: compare a single block with every block.
+        let block = vec![0x5a; 512];
+        let (resp, _) = execute_command_with_data_out(
+            &ex,
+            &disk,
+            0,
+            &cdb_12(WRITE_AND_VERIFY_12, 0, 4, 2),
+            &[block.clone(), block.clone()].concat(),
+            0,
+        );
+        assert_eq!(sense(&resp), None);
+        let (resp, _) =
+            execute_command_with_data_out(&ex, &disk, 0, &cdb_12(VERIFY_12, 0x6, 4, 2), &block, 0);
+        assert_eq!(sense(&resp), None);
+        let (resp, _) =
+            execute_command_with_data_out(&ex, &disk, 0, &cdb_12(VERIFY_12, 0x6, 3, 2), &block, 0);
+        assert_eq!(sense(&resp), Some((MISCOMPARE, 0x1d, 0x00)));
+
+        // BYTCHK=0: only the range is checked.
+        let verify_10 = |lba: u8, blocks: u8, bytchk: u8| {
+            let cdb = [VERIFY_10, bytchk << 1, 0, 0, 0, lba, 0, 0, blocks, 0];
+            let (resp, _) = execute_command(&ex, &disk, &cdb, 0);
+            sense(&resp)
+        };
+        assert_eq!(verify_10(0, 8, 0), None);
+        assert_eq!(verify_10(4, 5, 0), Some((ILLEGAL_REQUEST, 0x21, 0x00)));
+        assert_eq!(verify_10(0, 1, 2), Some((ILLEGAL_REQUEST, 0x24, 0x00)));
+    }
+
+    #[test]
+    fn block_device_characteristics_vpd_page() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let disk = setup_disk_queue(&ex, 8);
+        let (resp, data) = execute_command(&ex, &disk, &[INQUIRY, 0x1, 0x00, 0, 0xff, 0], 0xff);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(
+            &data[4..4 + data[3] as usize],
+            &[0x00, 0x83, 0xb0, 0xb1, 0xb2]
+        );
+
+        let (resp, data) = execute_command(&ex, &disk, &[INQUIRY, 0x1, 0xb1, 0, 0x40, 0], 0x40);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(data[1], 0xb1);
+        assert_eq!(data[3], 0x3c);
+        // Non-rotating medium
+        assert_eq!(&data[4..6], &[0x00, 0x01]);
+    }
+
+    #[test]
+    fn report_supported_operation_codes() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let disk = setup_disk_queue(&ex, 8);
+        let cdrom = setup_cdrom(&ex, 4);
+        let report = |queue_type: &QueueType, options: u8, opcode: u8, service_action: u16| {
+            let mut cdb = [0u8; 12];
+            cdb[0] = MAINTENANCE_IN;
+            cdb[1] = 0x0c;
+            cdb[2] = options;
+            cdb[3] = opcode;
+            cdb[4..6].copy_from_slice(&service_action.to_be_bytes());
+            cdb[6..10].copy_from_slice(&1024u32.to_be_bytes());
+            execute_command(&ex, queue_type, &cdb, 1024)
+        };
+        // Returns the (opcode, service action, flags, CDB length) descriptors of all commands.
+        let all_commands = |queue_type: &QueueType| {
+            let (resp, data) = report(queue_type, 0, 0, 0);
+            assert_eq!(sense(&resp), None);
+            let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            data[4..4 + len]
+                .chunks(8)
+                .map(|d| {
+                    (
+                        d[0],
+                        u16::from_be_bytes([d[2], d[3]]),
+                        d[5],
+                        u16::from_be_bytes([d[6], d[7]]),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let commands = all_commands(&disk);
+        assert!(commands.contains(&(READ_16, 0, 0, 16)));
+        assert!(commands.contains(&(VERIFY_10, 0, 0, 10)));
+        assert!(commands.contains(&(MAINTENANCE_IN, 0x0c, 1, 12)));
+        assert!(commands.contains(&(SERVICE_ACTION_IN_16, 0x10, 1, 16)));
+        assert!(!commands.iter().any(|c| c.0 == READ_TOC));
+        assert!(all_commands(&cdrom).contains(&(READ_TOC, 0, 0, 10)));
+
+        // A single command without service actions
+        let (resp, data) = report(&disk, 1, WRITE_AND_VERIFY_12, 0);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(data[1], 0x3);
+        assert_eq!(&data[2..4], &[0, 12]);
+        assert_eq!(data[4], WRITE_AND_VERIFY_12);
+        let (resp, data) = report(&disk, 1, READ_TOC, 0);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(data[1], 0x1);
+        let (resp, _) = report(&disk, 1, MAINTENANCE_IN, 0);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x24, 0x00)));
+
+        // A single command with service actions
+        let (resp, data) = report(&disk, 2, SERVICE_ACTION_IN_16, 0x10);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(data[1], 0x3);
+        assert_eq!(&data[2..4], &[0, 16]);
+        assert_eq!(&data[4..6], &[SERVICE_ACTION_IN_16, 0x1f]);
+        let (resp, data) = report(&disk, 2, MAINTENANCE_IN, 0x05);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(data[1], 0x1);
+        let (resp, _) = report(&disk, 2, READ_16, 0);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x24, 0x00)));
+        let (resp, data) = report(&disk, 3, READ_16, 0);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(data[1], 0x3);
+    }
+
+    fn setup_cdrom(ex: &Executor, blocks: u64) -> QueueType {
+        let (file, _) = setup_disk(blocks * CDROM_BLOCK_SIZE as u64);
+        let logical_unit = LogicalUnit {
+            max_lba: blocks,
+            block_size: CDROM_BLOCK_SIZE,
+            read_only: true,
+            cdrom: true,
+            disk_image: Box::new(file),
+            reservations: None,
+        }
+        .make_async(ex)
+        .unwrap();
+        QueueType::Request(AsyncRwLock::new(BTreeMap::from([(0, logical_unit)])))
+    }
+
+    // Executes the command in `cdb` on target 0 and returns the response and the `data_len`
+    // bytes of data written by the device.
+    fn execute_command(
+        ex: &Executor,
+        queue_type: &QueueType,
+        cdb: &[u8],
+        data_len: u32,
+    ) -> (virtio_scsi_cmd_resp, Vec<u8>) {
+        execute_command_on_target(ex, queue_type, 0, cdb, data_len)
+    }
+
+    fn execute_command_on_target(
+        ex: &Executor,
+        queue_type: &QueueType,
+        target: TargetId,
+        cdb: &[u8],
+        data_len: u32,
+    ) -> (virtio_scsi_cmd_resp, Vec<u8>) {
+        execute_command_with_data_out(ex, queue_type, target, cdb, &[], data_len)
+    }
+
+    // Same as `execute_command_on_target`, but also passes `data_out` to the device.
+    fn execute_command_with_data_out(
+        ex: &Executor,
+        queue_type: &QueueType,
+        target: TargetId,
+        cdb: &[u8],
+        data_out: &[u8],
+        data_len: u32,
+    ) -> (virtio_scsi_cmd_resp, Vec<u8>) {
+        let mem = Rc::new(
+            GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+                .expect("Creating guest memory failed."),
+        );
+        let mut req_hdr = virtio_scsi_cmd_req {
+            lun: [1, target, 0, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        req_hdr.cdb[..cdb.len()].copy_from_slice(cdb);
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+        let data_out_offset = GuestAddress((0x1000 + size_of::<virtio_scsi_cmd_req>()) as u64);
+        mem.write_all_at_addr(data_out, data_out_offset)
+            .expect("writing data-out failed");
+        let mut descriptors = vec![(DescriptorType::Readable, size_of_val(&req_hdr) as u32)];
+        if !data_out.is_empty() {
+            descriptors.push((DescriptorType::Readable, data_out.len() as u32));
+        }
+        descriptors.extend([
+            (
+                DescriptorType::Writable,
+                size_of::<virtio_scsi_cmd_resp>() as u32,
+            ),
+            (DescriptorType::Writable, data_len),
+        ]);
+        let mut avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),
+            GuestAddress(0x1000),
+            descriptors,
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        ex.run_until(process_one_request(
+            &mut avail_desc,
+            queue_type,
+            VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
+            VIRTIO_SCSI_CDB_DEFAULT_SIZE,
+        ))
+        .expect("running executor failed");
+        let resp_offset = data_out_offset.unchecked_add(data_out.len() as u64);
+        let resp = mem
+            .read_obj_from_addr::<virtio_scsi_cmd_resp>(resp_offset)
+            .unwrap();
+        let mut data = vec![0; data_len as usize];
+        mem.get_slice_at_addr(
+            resp_offset.unchecked_add(size_of::<virtio_scsi_cmd_resp>() as u64),
+            data_len as usize,
+        )
+        .unwrap()
+        .copy_to(&mut data);
+        (resp, data)
+    }
+
+    // Returns the sense key, ASC and ASCQ of a response, or `None` if the command succeeded.
+    fn sense(resp: &virtio_scsi_cmd_resp) -> Option<(u8, u8, u8)> {
+        if resp.status == GOOD {
+            return None;
+        }
+        assert_eq!(resp.status, CHECK_CONDITION);
+        Some((resp.sense[2], resp.sense[12], resp.sense[13]))
+    }
+
+    fn test_unit_ready(ex: &Executor, queue_type: &QueueType) -> Option<(u8, u8, u8)> {
+        let (resp, _) = execute_command(ex, queue_type, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], 0);
+        sense(&resp)
+    }
+
+    fn change_media(
+        ex: &Executor,
+        queue_type: &QueueType,
+        blocks: Option<u64>,
+        force: bool,
+    ) -> ScsiControlResult {
+        let QueueType::Request(targets) = queue_type else {
+            panic!("not a request queue");
+        };
+        let medium = blocks.map(|blocks| setup_disk(blocks * CDROM_BLOCK_SIZE as u64).0);
+        ex.run_until(super::change_media(
+            ex,
+            targets,
+            0,
+            medium.map(|file| (PathBuf::from("medium.iso"), file)),
+            force,
+        ))
+        .expect("running executor failed")
+    }
+
+    #[test]
+    fn cdrom_change_media() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let cdrom = setup_cdrom(&ex, 4);
+        assert_eq!(test_unit_ready(&ex, &cdrom), None);
+
+        assert_eq!(
+            change_media(&ex, &cdrom, None, false),
+            ScsiControlResult::Ok
+        );
+        // The first command reports the removal, and the drive stays not ready afterwards.
+        assert_eq!(
+            test_unit_ready(&ex, &cdrom),
+            Some((UNIT_ATTENTION, 0x3a, 0x00))
+        );
+        assert_eq!(test_unit_ready(&ex, &cdrom), Some((NOT_READY, 0x3a, 0x00)));
+        let (resp, _) = execute_command(&ex, &cdrom, &[READ_10, 0, 0, 0, 0, 0, 0, 0, 1, 0], 2048);
+        assert_eq!(sense(&resp), Some((NOT_READY, 0x3a, 0x00)));
+
+        assert_eq!(
+            change_media(&ex, &cdrom, Some(8), false),
+            ScsiControlResult::Ok
+        );
+        assert_eq!(
+            test_unit_ready(&ex, &cdrom),
+            Some((UNIT_ATTENTION, 0x28, 0x00))
+        );
+        assert_eq!(test_unit_ready(&ex, &cdrom), None);
+        let (resp, data) = execute_command(
+            &ex,
+            &cdrom,
+            &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            8,
+        );
+        assert_eq!(sense(&resp), None);
+        // The last LBA and the block size.
+        assert_eq!(data, [0, 0, 0, 7, 0, 0, 0x08, 0x00]);
+    }
+
+    #[test]
+    fn cdrom_prevent_removal() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let cdrom = setup_cdrom(&ex, 4);
+        let (resp, _) = execute_command(
+            &ex,
+            &cdrom,
+            &[PREVENT_ALLOW_MEDIUM_REMOVAL, 0, 0, 0, 1, 0],
+            0,
+        );
+        assert_eq!(sense(&resp), None);
+
+        // Neither the guest nor the host can eject the medium.
+        let (resp, _) = execute_command(&ex, &cdrom, &[START_STOP_UNIT, 0, 0, 0, 0x2, 0], 0);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x53, 0x02)));
+        assert_eq!(
+            change_media(&ex, &cdrom, None, false),
+            ScsiControlResult::Err(SysError::new(libc::EBUSY))
+        );
+        // The guest is notified of the eject request.
+        let gesn = [
+            GET_EVENT_STATUS_NOTIFICATION,
+            0x1,
+            0,
+            0,
+            0x10,
+            0,
+            0,
+            0,
+            8,
+            0,
+        ];
+        let (resp, data) = execute_command(&ex, &cdrom, &gesn, 8);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(
+            data,
+            [0, 6, 0x04, 0x10, MediaEvent::EjectRequest as u8, 0x02, 0, 0]
+        );
+
+        // A forced eject succeeds.
+        assert_eq!(change_media(&ex, &cdrom, None, true), ScsiControlResult::Ok);
+        let (_, data) = execute_command(&ex, &cdrom, &gesn, 8);
+        assert_eq!(
+            data,
+            [0, 6, 0x04, 0x10, MediaEvent::MediaRemoval as u8, 0x00, 0, 0]
+        );
+    }
+
+    #[test]
+    fn cdrom_tray() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let cdrom = setup_cdrom(&ex, 4);
+        // Open the tray.
+        let (resp, _) = execute_command(&ex, &cdrom, &[START_STOP_UNIT, 0, 0, 0, 0x2, 0], 0);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(test_unit_ready(&ex, &cdrom), Some((NOT_READY, 0x3a, 0x00)));
+        // Close the tray, which loads the medium again.
+        let (resp, _) = execute_command(&ex, &cdrom, &[START_STOP_UNIT, 0, 0, 0, 0x3, 0], 0);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(test_unit_ready(&ex, &cdrom), None);
+    }
+
+    #[test]
+    fn cdrom_read_toc() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let cdrom = setup_cdrom(&ex, 300);
+        let (resp, data) =
+            execute_command(&ex, &cdrom, &[READ_TOC, 0, 0, 0, 0, 0, 0, 0, 20, 0], 20);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(
+            data,
+            [
+                0, 18, 1, 1, // header
+                0, 0x14, 1, 0, 0, 0, 0, 0, // track 1 at LBA 0
+                0, 0x14, 0xaa, 0, 0, 0, 0x01, 0x2c, // lead-out at LBA 300
+            ]
+        );
+
+        // The same in the MSF format: 300 blocks + 150 = 0:06:00.
+        let (resp, data) = execute_command(
+            &ex,
+            &cdrom,
+            &[READ_TOC, 0x2, 0, 0, 0, 0, 0xaa, 0, 12, 0],
+            12,
+        );
+        assert_eq!(sense(&resp), None);
+        assert_eq!(data, [0, 10, 1, 1, 0, 0x14, 0xaa, 0, 0, 0, 6, 0]);
+    }
+
+    #[test]
+    fn mmc_commands_unsupported_on_disks() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let (file, _) = setup_disk(4096);
+        let logical_unit = LogicalUnit {
+            max_lba: 8,
+            block_size: 512,
+            read_only: false,
+            cdrom: false,
+            disk_image: Box::new(file),
+            reservations: Some(PersistentReservations::new()),
+        }
+        .make_async(&ex)
+        .unwrap();
+        let disk = QueueType::Request(AsyncRwLock::new(BTreeMap::from([(0, logical_unit)])));
+        let (resp, _) = execute_command(&ex, &disk, &[READ_TOC, 0, 0, 0, 0, 0, 0, 0, 20, 0], 20);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x20, 0x00)));
+        assert_eq!(
+            change_media(&ex, &disk, None, false),
+            ScsiControlResult::Err(SysError::new(libc::ENOTSUP))
+        );
+    }
+
+    fn setup_logical_unit(ex: &Executor, blocks: u64) -> AsyncLogicalUnit {
+        let (file, _) = setup_disk(blocks * 512);
+        LogicalUnit {
+            max_lba: blocks,
+            block_size: 512,
+            read_only: false,
+            cdrom: false,
+            disk_image: Box::new(file),
+            reservations: Some(PersistentReservations::new()),
+        }
+        .make_async(ex)
+        .unwrap()
+    }
+
+    // Executes a PERSISTENT RESERVE OUT command on target 0.
+    fn persistent_reserve_out(
+        ex: &Executor,
+        queue_type: &QueueType,
+        service_action: u8,
+        reservation_type: u8,
+        key: u64,
+        service_action_key: u64,
+    ) -> virtio_scsi_cmd_resp {
+        let cdb = [
+            PERSISTENT_RESERVE_OUT,
+            service_action,
+            reservation_type,
+            0,
+            0,
+            0,
+            0,
+            0,
+            24,
+            0,
+        ];
+        let mut params = [0u8; 24];
+        params[..8].copy_from_slice(&key.to_be_bytes());
+        params[8..16].copy_from_slice(&service_action_key.to_be_bytes());
+        execute_command_with_data_out(ex, queue_type, 0, &cdb, &params, 0).0
+    }
+
+    #[test]
+    fn persistent_reservations() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let (file, _) = setup_disk(8 * 512);
+        let reservation_file = tempfile().unwrap();
+        // Two VMs sharing the disk image.
+        let setup_shared_disk = |initiator| {
+            let logical_unit = LogicalUnit {
+                max_lba: 8,
+                block_size: 512,
+                read_only: false,
+                cdrom: false,
+                disk_image: Box::new(file.try_clone().unwrap()),
+                reservations: Some(PersistentReservations::with_file(
+                    reservation_file.try_clone().unwrap(),
+                    initiator,
+                )),
+            }
+            .make_async(&ex)
+            .unwrap();
+            QueueType::Request(AsyncRwLock::new(BTreeMap::from([(0, logical_unit)])))
+        };
+        let vm1 = setup_shared_disk(1);
+        let vm2 = setup_shared_disk(2);
+        let write = |queue_type: &QueueType| {
+            let cdb = [WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+            execute_command_with_data_out(&ex, queue_type, 0, &cdb, &[0; 512], 0).0
+        };
+        let read = |queue_type: &QueueType| {
+            execute_command(&ex, queue_type, &[READ_10, 0, 0, 0, 0, 0, 0, 0, 1, 0], 512).0
+        };
+
+        for (vm, key) in [(&vm1, 0xa), (&vm2, 0xb)] {
+            let resp = persistent_reserve_out(&ex, vm, PR_REGISTER, 0, 0, key);
+            assert_eq!(sense(&resp), None);
+        }
+        let resp = persistent_reserve_out(&ex, &vm1, PR_RESERVE, WRITE_EXCLUSIVE, 0xa, 0);
+        assert_eq!(sense(&resp), None);
+
+        assert_eq!(sense(&write(&vm1)), None);
+        assert_eq!(sense(&read(&vm2)), None);
+        let resp = write(&vm2);
+        assert_eq!(resp.status, RESERVATION_CONFLICT);
+        assert_eq!({ resp.sense_len }, 0);
+
+        let (resp, keys) = execute_command(
+            &ex,
+            &vm2,
+            &[PERSISTENT_RESERVE_IN, PR_READ_KEYS, 0, 0, 0, 0, 0, 0, 24, 0],
+            24,
+        );
+        assert_eq!(sense(&resp), None);
+        assert_eq!(&keys[..8], &[0, 0, 0, 2, 0, 0, 0, 16]);
+        let mut registered = vec![
+            u64::from_be_bytes(keys[8..16].try_into().unwrap()),
+            u64::from_be_bytes(keys[16..24].try_into().unwrap()),
+        ];
+        registered.sort();
+        assert_eq!(registered, vec![0xa, 0xb]);
+
+        let (resp, reservation) = execute_command(
+            &ex,
+            &vm2,
+            &[
+                PERSISTENT_RESERVE_IN,
+                PR_READ_RESERVATION,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                24,
+                0,
+            ],
+            24,
+        );
+        assert_eq!(sense(&resp), None);
+        assert_eq!(&reservation[4..8], &[0, 0, 0, 16]);
+        assert_eq!(
+            u64::from_be_bytes(reservation[8..16].try_into().unwrap()),
+            0xa
+        );
+        assert_eq!(reservation[21], WRITE_EXCLUSIVE);
+
+        // The second VM takes over the disk.
+        let resp = persistent_reserve_out(&ex, &vm2, PR_PREEMPT, EXCLUSIVE_ACCESS, 0xb, 0xa);
+        assert_eq!(sense(&resp), None);
+        assert_eq!(sense(&write(&vm2)), None);
+        assert_eq!(read(&vm1).status, RESERVATION_CONFLICT);
+        let resp = persistent_reserve_out(&ex, &vm1, PR_RESERVE, EXCLUSIVE_ACCESS, 0xa, 0);
+        assert_eq!(resp.status, RESERVATION_CONFLICT);
+
+        // Invalid requests
+        let resp = persistent_reserve_out(&ex, &vm2, PR_RESERVE, 0x2, 0xb, 0);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x24, 0x00)));
+        let resp = persistent_reserve_out(&ex, &vm2, PR_RELEASE, WRITE_EXCLUSIVE, 0xb, 0);
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x26, 0x04)));
+
+        // CD-ROM drives do not support persistent reservations.
+        let cdrom = setup_cdrom(&ex, 4);
+        let (resp, _) = execute_command(
+            &ex,
+            &cdrom,
+            &[PERSISTENT_RESERVE_IN, PR_READ_KEYS, 0, 0, 0, 0, 0, 0, 8, 0],
+            8,
+        );
+        assert_eq!(sense(&resp), Some((ILLEGAL_REQUEST, 0x20, 0x00)));
+    }
+
+    #[test]
+    fn hotplug_targets() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let queue_type = QueueType::Request(AsyncRwLock::new(BTreeMap::from([(
+            0,
+            setup_logical_unit(&ex, 8),
+        )])));
+        let QueueType::Request(targets) = &queue_type else {
+            unreachable!();
+        };
+        let target_unit_ready = |target| {
+            let (resp, _) = execute_command_on_target(
+                &ex,
+                &queue_type,
+                target,
+                &[TEST_UNIT_READY, 0, 0, 0, 0, 0],
+                0,
+            );
+            resp
+        };
+
+        let (file, _) = setup_disk(4096);
+        let logical_unit = LogicalUnit {
+            max_lba: 8,
+            block_size: 512,
+            read_only: false,
+            cdrom: false,
+            disk_image: Box::new(file),
+            reservations: Some(PersistentReservations::new()),
+        };
+        assert_eq!(
+            ex.run_until(add_target(&ex, targets, 1, logical_unit))
+                .unwrap(),
+            ScsiControlResult::Ok
+        );
+        // The new logical unit is ready, and the existing one reports the change once.
+        assert_eq!(sense(&target_unit_ready(1)), None);
+        assert_eq!(
+            sense(&target_unit_ready(0)),
+            Some((UNIT_ATTENTION, 0x3f, 0x0e))
+        );
+        assert_eq!(sense(&target_unit_ready(0)), None);
+
+        assert_eq!(
+            ex.run_until(remove_target(targets, 1)).unwrap(),
+            ScsiControlResult::Ok
+        );
+        assert_eq!(
+            target_unit_ready(1).response,
+            VIRTIO_SCSI_S_BAD_TARGET as u8
+        );
+        assert_eq!(
+            sense(&target_unit_ready(0)),
+            Some((UNIT_ATTENTION, 0x3f, 0x0e))
+        );
+        assert_eq!(
+            ex.run_until(remove_target(targets, 1)).unwrap(),
+            ScsiControlResult::Err(SysError::new(libc::ENODEV))
+        );
+    }
+
+    #[test]
+    fn forward_to_request_workers() {
+        let ex = Executor::new().expect("creating an executor failed");
+        let queue_types: Vec<_> = (0..2)
+            .map(|_| {
+                QueueType::Request(AsyncRwLock::new(BTreeMap::from([(
+                    0,
+                    setup_logical_unit(&ex, 8),
+                )])))
+            })
+            .collect();
+        let (request_workers, worker_commands): (Vec<_>, Vec<_>) =
+            queue_types.iter().map(|_| mpsc::unbounded()).unzip();
+
+        let result = ex
+            .run_until(async {
+                let workers =
+                    futures::future::join_all(worker_commands.into_iter().zip(&queue_types).map(
+                        |(commands, queue_type)| handle_worker_commands(&ex, commands, queue_type),
+                    ));
+                let remove = forward_to_workers(&request_workers, || {
+                    Ok(WorkerCommand::RemoveTarget { target: 0 })
+                });
+                futures::select! {
+                    _ = workers.fuse() => panic!("request workers exited"),
+                    result = remove.fuse() => result,
+                }
+            })
+            .unwrap();
+        assert_eq!(result, ScsiControlResult::Ok);
+        for queue_type in &queue_types {
+            let (resp, _) = execute_command(&ex, queue_type, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], 0);
+            assert_eq!(resp.response, VIRTIO_SCSI_S_BAD_TARGET as u8);
+        }
+    }
+
+    #[test]
+    fn attach_rolls_back_on_failure() {
+        let ex = Executor::new().expect("creating an executor failed");
+        // Target 1 already exists on the second worker only.
+        let queue_types: Vec<_> = (0..2)
+            .map(|worker| {
+                let mut targets = BTreeMap::from([(0, setup_logical_unit(&ex, 8))]);
+                if worker == 1 {
+                    targets.insert(1, setup_logical_unit(&ex, 8));
+                }
+                QueueType::Request(AsyncRwLock::new(targets))
+            })
+            .collect();
+        let (request_workers, worker_commands): (Vec<_>, Vec<_>) =
+            queue_types.iter().map(|_| mpsc::unbounded()).unzip();
+        let (file, _) = setup_disk(4096);
+        let logical_unit = LogicalUnit {
+            max_lba: 8,
+            block_size: 512,
+            read_only: false,
+            cdrom: false,
+            disk_image: Box::new(SharedDisk::new(Box::new(file)).unwrap()),
+            reservations: Some(PersistentReservations::new()),
+        };
+
+        let result = ex
+            .run_until(async {
+                let workers =
+                    futures::future::join_all(worker_commands.into_iter().zip(&queue_types).map(
+                        |(commands, queue_type)| handle_worker_commands(&ex, commands, queue_type),
+                    ));
+                let attach = attach_to_workers(&request_workers, 1, &logical_unit);
+                futures::select! {
+                    _ = workers.fuse() => panic!("request workers exited"),
+                    result = attach.fuse() => result,
+                }
+            })
+            .unwrap();
+        assert_eq!(result, ScsiControlResult::Err(SysError::new(libc::EEXIST)));
+        let has_target = |queue_type: &QueueType| {
+            let QueueType::Request(targets) = queue_type else {
+                unreachable!();
+            };
+            ex.run_until(async { targets.read_lock().await.contains_key(&1) })
+                .unwrap()
+        };
+        assert!(!has_target(&queue_types[0]));
+        assert!(has_target(&queue_types[1]));
+    }
 }
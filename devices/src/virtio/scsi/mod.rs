@@ -12,6 +12,8 @@ pub(crate) mod sys;
 pub mod commands;
 pub mod constants;
 mod device;
+mod reservation;
+mod shared_disk;
 
 pub use device::Controller;
 pub use device::DiskConfig;
@@ -36,6 +38,20 @@ pub struct ScsiOption {
     /// adding specific command-line options.
     #[serde(default)]
     pub root: bool,
+    /// Whether the device is a read-only CD-ROM drive with 2048-byte sectors, whose medium can
+    /// be changed at runtime.
+    #[serde(default)]
+    pub cdrom: bool,
+    /// Whether the disk image is shared with other crosvm instances. Shared disks are not locked
+    /// exclusively and coordinate their SCSI-3 persistent reservations through a file next to the
+    /// image.
+    #[serde(default)]
+    pub shared: bool,
+    /// Identifies this crosvm instance among the instances that share the disk image. Required
+    /// for shared disks, and must stay the same across restarts of the VM so that it keeps its
+    /// persistent reservations.
+    #[serde(default)]
+    pub initiator_id: Option<u64>,
 }
 
 #[cfg(test)]
@@ -56,6 +72,9 @@ mod tests {
                 read_only: false,
                 block_size: 512,
                 root: false,
+                cdrom: false,
+                shared: false,
+                initiator_id: None,
             }
         );
 
@@ -67,6 +86,9 @@ mod tests {
                 read_only: true,
                 block_size: 512,
                 root: false,
+                cdrom: false,
+                shared: false,
+                initiator_id: None,
             }
         );
 
@@ -78,6 +100,9 @@ mod tests {
                 read_only: false,
                 block_size: 1024,
                 root: false,
+                cdrom: false,
+                shared: false,
+                initiator_id: None,
             }
         );
 
@@ -90,6 +115,38 @@ mod tests {
                 read_only: false,
                 block_size: 1024,
                 root: true,
+                cdrom: false,
+                shared: false,
+                initiator_id: None,
+            }
+        );
+
+        let scsi_option = from_key_values::<ScsiOption>("/path/to/install.iso,cdrom").unwrap();
+        assert_eq!(
+            scsi_option,
+            ScsiOption {
+                path: Path::new("/path/to/install.iso").to_path_buf(),
+                read_only: false,
+                block_size: 512,
+                root: false,
+                cdrom: true,
+                shared: false,
+                initiator_id: None,
+            }
+        );
+
+        let scsi_option =
+            from_key_values::<ScsiOption>("/path/to/shared.img,shared,initiator-id=7").unwrap();
+        assert_eq!(
+            scsi_option,
+            ScsiOption {
+                path: Path::new("/path/to/shared.img").to_path_buf(),
+                read_only: false,
+                block_size: 512,
+                root: false,
+                cdrom: false,
+                shared: true,
+                initiator_id: Some(7),
             }
         );
     }
@@ -0,0 +1,687 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! SCSI-3 persistent reservations.
+//!
+//! A guest is a single initiator, so the I_T nexuses of a logical unit are the crosvm instances
+//! that expose it. The reservation state of a disk shared between several VMs is stored in a file
+//! next to the disk image and is locked with `flock` while a command accesses it.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use serde::Deserialize;
+use serde::Serialize;
+use sync::Mutex;
+
+use crate::virtio::scsi::device::ExecuteError;
+use crate::virtio::scsi::sys::lock_reservations;
+use crate::virtio::scsi::sys::unlock_reservations;
+
+// Persistent reservation types
+/// Only the holder of the reservation can write to the logical unit.
+pub const WRITE_EXCLUSIVE: u8 = 0x1;
+/// Only the holder of the reservation can access the logical unit.
+pub const EXCLUSIVE_ACCESS: u8 = 0x3;
+/// Only registered initiators can write to the logical unit.
+pub const WRITE_EXCLUSIVE_REGISTRANTS_ONLY: u8 = 0x5;
+/// Only registered initiators can access the logical unit.
+pub const EXCLUSIVE_ACCESS_REGISTRANTS_ONLY: u8 = 0x6;
+/// Same as `WRITE_EXCLUSIVE_REGISTRANTS_ONLY`, but every registered initiator holds the
+/// reservation.
+pub const WRITE_EXCLUSIVE_ALL_REGISTRANTS: u8 = 0x7;
+/// Same as `EXCLUSIVE_ACCESS_REGISTRANTS_ONLY`, but every registered initiator holds the
+/// reservation.
+pub const EXCLUSIVE_ACCESS_ALL_REGISTRANTS: u8 = 0x8;
+
+/// Returns whether `reservation_type` is a persistent reservation type supported by crosvm.
+pub fn is_valid_type(reservation_type: u8) -> bool {
+    matches!(
+        reservation_type,
+        WRITE_EXCLUSIVE
+            | EXCLUSIVE_ACCESS
+            | WRITE_EXCLUSIVE_REGISTRANTS_ONLY
+            | EXCLUSIVE_ACCESS_REGISTRANTS_ONLY
+            | WRITE_EXCLUSIVE_ALL_REGISTRANTS
+            | EXCLUSIVE_ACCESS_ALL_REGISTRANTS
+    )
+}
+
+fn is_all_registrants(reservation_type: u8) -> bool {
+    matches!(
+        reservation_type,
+        WRITE_EXCLUSIVE_ALL_REGISTRANTS | EXCLUSIVE_ACCESS_ALL_REGISTRANTS
+    )
+}
+
+/// How a command accesses the medium of a logical unit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A persistent reservation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reservation {
+    /// The initiator that holds the reservation. Every registered initiator holds reservations of
+    /// the "all registrants" types.
+    pub holder: u64,
+    #[serde(rename = "type")]
+    pub reservation_type: u8,
+}
+
+/// The registrations and the reservation of a logical unit.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReservationState {
+    /// Incremented by every PERSISTENT RESERVE OUT command that changes the registrations.
+    pub generation: u32,
+    /// The reservation keys of the registered initiators.
+    pub registrations: BTreeMap<u64, u64>,
+    pub reservation: Option<Reservation>,
+}
+
+impl ReservationState {
+    /// Returns whether `initiator` holds the reservation.
+    pub fn is_holder(&self, initiator: u64) -> bool {
+        match self.reservation {
+            Some(r) if is_all_registrants(r.reservation_type) => {
+                self.registrations.contains_key(&initiator)
+            }
+            Some(r) => r.holder == initiator,
+            None => false,
+        }
+    }
+
+    /// Returns the reservation key reported for the reservation, which is zero for the "all
+    /// registrants" types.
+    pub fn reservation_key(&self) -> u64 {
+        match self.reservation {
+            Some(r) if !is_all_registrants(r.reservation_type) => {
+                self.registrations.get(&r.holder).copied().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Returns whether the reservation prevents `initiator` from accessing the medium.
+    pub fn conflicts(&self, initiator: u64, access: Access) -> bool {
+        let reservation = match self.reservation {
+            Some(r) if !self.is_holder(initiator) => r,
+            _ => return false,
+        };
+        let registered = self.registrations.contains_key(&initiator);
+        match reservation.reservation_type {
+            WRITE_EXCLUSIVE => access == Access::Write,
+            EXCLUSIVE_ACCESS => true,
+            WRITE_EXCLUSIVE_REGISTRANTS_ONLY | WRITE_EXCLUSIVE_ALL_REGISTRANTS => {
+                access == Access::Write && !registered
+            }
+            EXCLUSIVE_ACCESS_REGISTRANTS_ONLY | EXCLUSIVE_ACCESS_ALL_REGISTRANTS => !registered,
+            _ => false,
+        }
+    }
+
+    fn check_key(&self, initiator: u64, key: u64) -> Result<(), ExecuteError> {
+        if self.registrations.get(&initiator) == Some(&key) {
+            Ok(())
+        } else {
+            Err(ExecuteError::ReservationConflict)
+        }
+    }
+
+    fn unregister(&mut self, initiator: u64) {
+        let was_holder = self.is_holder(initiator);
+        self.registrations.remove(&initiator);
+        if let Some(r) = self.reservation {
+            // Reservations of the "all registrants" types are released with the last registration.
+            let release = if is_all_registrants(r.reservation_type) {
+                self.registrations.is_empty()
+            } else {
+                was_holder
+            };
+            if release {
+                self.reservation = None;
+            }
+        }
+    }
+
+    // Removes the registrations of the initiators other than `initiator` that use `key` and returns
+    // how many were removed.
+    fn remove_registrations(&mut self, initiator: u64, key: u64) -> usize {
+        let preempted: Vec<u64> = self
+            .registrations
+            .iter()
+            .filter(|&(&i, &k)| i != initiator && k == key)
+            .map(|(&i, _)| i)
+            .collect();
+        for i in &preempted {
+            self.unregister(*i);
+        }
+        preempted.len()
+    }
+
+    /// Handles the REGISTER and REGISTER AND IGNORE EXISTING KEY service actions.
+    pub fn register(
+        &mut self,
+        initiator: u64,
+        key: u64,
+        service_action_key: u64,
+        ignore_existing_key: bool,
+    ) -> Result<(), ExecuteError> {
+        let registered_key = self.registrations.get(&initiator).copied().unwrap_or(0);
+        if !ignore_existing_key && key != registered_key {
+            return Err(ExecuteError::ReservationConflict);
+        }
+        if service_action_key != 0 {
+            self.registrations.insert(initiator, service_action_key);
+        } else if self.registrations.contains_key(&initiator) {
+            self.unregister(initiator);
+        } else {
+            // Unregistering an unregistered initiator does nothing.
+            return Ok(());
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Handles the RESERVE service action.
+    pub fn reserve(
+        &mut self,
+        initiator: u64,
+        key: u64,
+        reservation_type: u8,
+    ) -> Result<(), ExecuteError> {
+        self.check_key(initiator, key)?;
+        match self.reservation {
+            None => {
+                self.reservation = Some(Reservation {
+                    holder: initiator,
+                    reservation_type,
+                });
+                Ok(())
+            }
+            Some(r) if self.is_holder(initiator) && r.reservation_type == reservation_type => {
+                Ok(())
+            }
+            Some(_) => Err(ExecuteError::ReservationConflict),
+        }
+    }
+
+    /// Handles the RELEASE service action.
+    pub fn release(
+        &mut self,
+        initiator: u64,
+        key: u64,
+        reservation_type: u8,
+    ) -> Result<(), ExecuteError> {
+        self.check_key(initiator, key)?;
+        match self.reservation {
+            Some(r) if self.is_holder(initiator) => {
+                if r.reservation_type != reservation_type {
+                    return Err(ExecuteError::InvalidRelease);
+                }
+                self.reservation = None;
+                Ok(())
+            }
+            // Releasing a reservation held by another initiator does nothing.
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles the CLEAR service action.
+    pub fn clear(&mut self, initiator: u64, key: u64) -> Result<(), ExecuteError> {
+        self.check_key(initiator, key)?;
+        self.registrations.clear();
+        self.reservation = None;
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Handles the PREEMPT and PREEMPT AND ABORT service actions.
+    pub fn preempt(
+        &mut self,
+        initiator: u64,
+        key: u64,
+        service_action_key: u64,
+        reservation_type: u8,
+    ) -> Result<(), ExecuteError> {
+        self.check_key(initiator, key)?;
+        let new_reservation = Some(Reservation {
+            holder: initiator,
+            reservation_type,
+        });
+        match self.reservation {
+            // Preempting with a zero key takes over a reservation shared by all registrants.
+            Some(r) if is_all_registrants(r.reservation_type) && service_action_key == 0 => {
+                self.registrations.retain(|&i, _| i == initiator);
+                self.reservation = new_reservation;
+            }
+            Some(r)
+                if !is_all_registrants(r.reservation_type)
+                    && self.registrations.get(&r.holder) == Some(&service_action_key) =>
+            {
+                self.remove_registrations(initiator, service_action_key);
+                self.reservation = new_reservation;
+            }
+            _ => {
+                if service_action_key == 0 {
+                    return Err(ExecuteError::InvalidFieldInParamList);
+                }
+                if self.remove_registrations(initiator, service_action_key) == 0 {
+                    return Err(ExecuteError::ReservationConflict);
+                }
+            }
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+}
+
+// A change of the reservation file within this long after the previous one may leave its
+// modification time unchanged, since most file systems use coarse timestamps.
+const MTIME_GRANULARITY: Duration = Duration::from_secs(1);
+
+// Identifies the contents of the reservation file.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct FileVersion {
+    len: u64,
+    modified: SystemTime,
+}
+
+impl FileVersion {
+    fn of(file: &File) -> Result<FileVersion, ExecuteError> {
+        let metadata = file.metadata().map_err(ExecuteError::ReservationFile)?;
+        Ok(FileVersion {
+            len: metadata.len(),
+            modified: metadata.modified().map_err(ExecuteError::ReservationFile)?,
+        })
+    }
+}
+
+// The state last read from the reservation file.
+struct CachedState {
+    version: FileVersion,
+    // Whether the state was read long enough after the last change that any later change also
+    // changes the version of the file.
+    settled: bool,
+    state: ReservationState,
+}
+
+impl CachedState {
+    // Must be called with the file locked.
+    fn new(file: &File, state: ReservationState) -> Result<CachedState, ExecuteError> {
+        let version = FileVersion::of(file)?;
+        let settled = SystemTime::now()
+            .duration_since(version.modified)
+            .map_or(false, |age| age > MTIME_GRANULARITY);
+        Ok(CachedState {
+            version,
+            settled,
+            state,
+        })
+    }
+}
+
+enum ReservationStore {
+    // The logical unit is only accessed by this crosvm instance.
+    Memory(ReservationState),
+    // The state is shared with the other crosvm instances that use the disk image. It is cached
+    // so that checking the access of every command only takes an `fstat` of the file while the
+    // reservations don't change.
+    File {
+        file: File,
+        cache: Option<CachedState>,
+    },
+}
+
+impl ReservationStore {
+    fn load(file: &mut File) -> Result<ReservationState, ExecuteError> {
+        let mut buf = Vec::new();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_to_end(&mut buf))
+            .map_err(ExecuteError::ReservationFile)?;
+        if buf.is_empty() {
+            return Ok(ReservationState::default());
+        }
+        serde_json::from_slice(&buf).map_err(|e| ExecuteError::ReservationFile(e.into()))
+    }
+
+    fn store(file: &mut File, state: &ReservationState) -> Result<(), ExecuteError> {
+        let buf = serde_json::to_vec(state).map_err(|e| ExecuteError::ReservationFile(e.into()))?;
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.write_all(&buf))
+            .and_then(|_| file.set_len(buf.len() as u64))
+            .map_err(ExecuteError::ReservationFile)
+    }
+}
+
+/// The persistent reservations of a logical unit, as seen by the guest of this crosvm instance.
+#[derive(Clone)]
+pub struct PersistentReservations {
+    // Identifies the guest among the initiators that share the logical unit.
+    initiator: u64,
+    // Shared by the copies of the logical unit in the request workers.
+    store: Arc<Mutex<ReservationStore>>,
+}
+
+impl PersistentReservations {
+    /// Creates the reservations of a logical unit that is not shared with other VMs.
+    pub fn new() -> Self {
+        PersistentReservations {
+            initiator: 1,
+            store: Arc::new(Mutex::new(ReservationStore::Memory(
+                ReservationState::default(),
+            ))),
+        }
+    }
+
+    /// Creates the reservations of a logical unit shared with other crosvm instances, whose
+    /// state is stored in `file`. `initiator` must differ from the initiators of the other
+    /// instances, and stay the same when this one is restarted so that it keeps its registration.
+    pub fn with_file(file: File, initiator: u64) -> Self {
+        PersistentReservations {
+            initiator,
+            store: Arc::new(Mutex::new(ReservationStore::File { file, cache: None })),
+        }
+    }
+
+    /// Returns the identifier of the guest as an initiator.
+    pub fn initiator(&self) -> u64 {
+        self.initiator
+    }
+
+    /// Returns the descriptor of the file holding the reservations, if any.
+    pub fn as_raw_descriptor(&self) -> Option<RawDescriptor> {
+        match &*self.store.lock() {
+            ReservationStore::Memory(_) => None,
+            ReservationStore::File { file, .. } => Some(file.as_raw_descriptor()),
+        }
+    }
+
+    /// Returns whether the reservations outlive this crosvm instance.
+    pub fn is_persistent(&self) -> bool {
+        matches!(*self.store.lock(), ReservationStore::File { .. })
+    }
+
+    /// Calls `f` with the current reservation state.
+    pub fn read<R>(&self, f: impl FnOnce(&ReservationState) -> R) -> Result<R, ExecuteError> {
+        match &mut *self.store.lock() {
+            ReservationStore::Memory(state) => Ok(f(state)),
+            ReservationStore::File { file, cache } => {
+                let version = FileVersion::of(file)?;
+                let cached = match cache.take() {
+                    Some(cached) if cached.settled && cached.version == version => cached,
+                    _ => {
+                        lock_reservations(file, false).map_err(ExecuteError::ReservationFile)?;
+                        let result = ReservationStore::load(file)
+                            .and_then(|state| CachedState::new(file, state));
+                        let _ = unlock_reservations(file);
+                        result?
+                    }
+                };
+                Ok(f(&cache.insert(cached).state))
+            }
+        }
+    }
+
+    /// Calls `f` to update the reservation state. The state is left untouched if `f` fails.
+    pub fn update<R>(
+        &self,
+        f: impl FnOnce(&mut ReservationState) -> Result<R, ExecuteError>,
+    ) -> Result<R, ExecuteError> {
+        match &mut *self.store.lock() {
+            ReservationStore::Memory(state) => {
+                let mut new_state = state.clone();
+                let result = f(&mut new_state)?;
+                *state = new_state;
+                Ok(result)
+            }
+            ReservationStore::File { file, cache } => {
+                lock_reservations(file, true).map_err(ExecuteError::ReservationFile)?;
+                let result = ReservationStore::load(file).and_then(|mut state| {
+                    let old_state = state.clone();
+                    let result = f(&mut state);
+                    if result.is_ok() && state != old_state {
+                        ReservationStore::store(file, &state)?;
+                    } else {
+                        state = old_state;
+                    }
+                    *cache = Some(CachedState::new(file, state)?);
+                    result
+                });
+                let _ = unlock_reservations(file);
+                result
+            }
+        }
+    }
+
+    /// Fails with a reservation conflict if the reservation prevents the guest from accessing the
+    /// medium.
+    pub fn check_access(&self, access: Access) -> Result<(), ExecuteError> {
+        let initiator = self.initiator;
+        if self.read(|state| state.conflicts(initiator, access))? {
+            Err(ExecuteError::ReservationConflict)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+
+    const A: u64 = 1;
+    const B: u64 = 2;
+    const C: u64 = 3;
+
+    fn registered(initiators: &[(u64, u64)]) -> ReservationState {
+        let mut state = ReservationState::default();
+        for &(initiator, key) in initiators {
+            state.register(initiator, 0, key, false).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn register() {
+        let mut state = ReservationState::default();
+        // An unregistered initiator must pass a zero key.
+        assert!(matches!(
+            state.register(A, 0xa, 0xa, false),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        state.register(A, 0, 0xa, false).unwrap();
+        assert_eq!(state.registrations, BTreeMap::from([(A, 0xa)]));
+        assert_eq!(state.generation, 1);
+
+        // Changing the key requires the current one, unless it is ignored.
+        assert!(matches!(
+            state.register(A, 0xb, 0xc, false),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        state.register(A, 0xa, 0xc, false).unwrap();
+        state.register(A, 0xb, 0xd, true).unwrap();
+        assert_eq!(state.registrations, BTreeMap::from([(A, 0xd)]));
+
+        state.register(A, 0xd, 0, false).unwrap();
+        assert!(state.registrations.is_empty());
+        assert_eq!(state.generation, 4);
+        // Unregistering again does nothing.
+        state.register(A, 0, 0, false).unwrap();
+        assert_eq!(state.generation, 4);
+    }
+
+    #[test]
+    fn write_exclusive() {
+        let mut state = registered(&[(A, 0xa), (B, 0xb)]);
+        assert!(matches!(
+            state.reserve(A, 0xb, WRITE_EXCLUSIVE),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        state.reserve(A, 0xa, WRITE_EXCLUSIVE).unwrap();
+        // Reserving again with the same type does nothing.
+        state.reserve(A, 0xa, WRITE_EXCLUSIVE).unwrap();
+        assert!(matches!(
+            state.reserve(B, 0xb, WRITE_EXCLUSIVE),
+            Err(ExecuteError::ReservationConflict)
+        ));
+
+        assert!(!state.conflicts(A, Access::Write));
+        assert!(!state.conflicts(B, Access::Read));
+        assert!(state.conflicts(B, Access::Write));
+        assert!(state.conflicts(C, Access::Write));
+
+        // Only the holder can release the reservation, with the same type.
+        state.release(B, 0xb, WRITE_EXCLUSIVE).unwrap();
+        assert!(state.reservation.is_some());
+        assert!(matches!(
+            state.release(A, 0xa, EXCLUSIVE_ACCESS),
+            Err(ExecuteError::InvalidRelease)
+        ));
+        state.release(A, 0xa, WRITE_EXCLUSIVE).unwrap();
+        assert_eq!(state.reservation, None);
+        assert!(!state.conflicts(C, Access::Write));
+    }
+
+    #[test]
+    fn registrants_only() {
+        let mut state = registered(&[(A, 0xa), (B, 0xb)]);
+        state
+            .reserve(A, 0xa, EXCLUSIVE_ACCESS_REGISTRANTS_ONLY)
+            .unwrap();
+        assert!(!state.conflicts(B, Access::Read));
+        assert!(!state.conflicts(B, Access::Write));
+        assert!(state.conflicts(C, Access::Read));
+        assert!(!state.is_holder(B));
+
+        // The reservation is released when its holder unregisters.
+        state.register(A, 0xa, 0, false).unwrap();
+        assert_eq!(state.reservation, None);
+    }
+
+    #[test]
+    fn all_registrants() {
+        let mut state = registered(&[(A, 0xa), (B, 0xb)]);
+        state
+            .reserve(A, 0xa, WRITE_EXCLUSIVE_ALL_REGISTRANTS)
+            .unwrap();
+        assert!(state.is_holder(B));
+        state
+            .reserve(B, 0xb, WRITE_EXCLUSIVE_ALL_REGISTRANTS)
+            .unwrap();
+        assert!(state.conflicts(C, Access::Write));
+        assert!(!state.conflicts(C, Access::Read));
+
+        // The reservation is released with the last registration.
+        state.register(A, 0xa, 0, false).unwrap();
+        assert!(state.reservation.is_some());
+        state.register(B, 0xb, 0, false).unwrap();
+        assert_eq!(state.reservation, None);
+    }
+
+    #[test]
+    fn preempt() {
+        let mut state = registered(&[(A, 0xa), (B, 0xb), (C, 0xb)]);
+        state.reserve(B, 0xb, EXCLUSIVE_ACCESS).unwrap();
+        assert!(state.conflicts(A, Access::Read));
+
+        // Preempting the holder's key takes over the reservation.
+        let generation = state.generation;
+        state.preempt(A, 0xa, 0xb, WRITE_EXCLUSIVE).unwrap();
+        assert_eq!(state.registrations, BTreeMap::from([(A, 0xa)]));
+        assert_eq!(
+            state.reservation,
+            Some(Reservation {
+                holder: A,
+                reservation_type: WRITE_EXCLUSIVE,
+            })
+        );
+        assert_eq!(state.generation, generation + 1);
+
+        // Preempting another key only removes its registrations.
+        state.register(B, 0, 0xb, false).unwrap();
+        state.preempt(A, 0xa, 0xb, WRITE_EXCLUSIVE).unwrap();
+        assert_eq!(state.registrations, BTreeMap::from([(A, 0xa)]));
+        assert!(matches!(
+            state.preempt(A, 0xa, 0xb, WRITE_EXCLUSIVE),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        assert!(matches!(
+            state.preempt(A, 0xa, 0, WRITE_EXCLUSIVE),
+            Err(ExecuteError::InvalidFieldInParamList)
+        ));
+    }
+
+    #[test]
+    fn preempt_all_registrants() {
+        let mut state = registered(&[(A, 0xa), (B, 0xb)]);
+        state
+            .reserve(B, 0xb, EXCLUSIVE_ACCESS_ALL_REGISTRANTS)
+            .unwrap();
+        state.preempt(A, 0xa, 0, EXCLUSIVE_ACCESS).unwrap();
+        assert_eq!(state.registrations, BTreeMap::from([(A, 0xa)]));
+        assert!(state.conflicts(B, Access::Read));
+    }
+
+    #[test]
+    fn clear() {
+        let mut state = registered(&[(A, 0xa), (B, 0xb)]);
+        state.reserve(B, 0xb, EXCLUSIVE_ACCESS).unwrap();
+        assert!(matches!(
+            state.clear(C, 0),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        state.clear(A, 0xa).unwrap();
+        assert_eq!(state.registrations, BTreeMap::new());
+        assert_eq!(state.reservation, None);
+    }
+
+    #[test]
+    fn shared_through_file() {
+        let file = tempfile().unwrap();
+        let a = PersistentReservations::with_file(file.try_clone().unwrap(), A);
+        let b = PersistentReservations::with_file(file, B);
+        assert!(a.is_persistent());
+
+        a.update(|state| state.register(a.initiator(), 0, 0xa, false))
+            .unwrap();
+        a.update(|state| state.reserve(a.initiator(), 0xa, WRITE_EXCLUSIVE))
+            .unwrap();
+        assert!(matches!(
+            b.check_access(Access::Write),
+            Err(ExecuteError::ReservationConflict)
+        ));
+        b.check_access(Access::Read).unwrap();
+
+        // A failed update does not change the state.
+        assert!(b
+            .update(|state| {
+                state.register(b.initiator(), 0, 0xb, false)?;
+                state.reserve(b.initiator(), 0xb, WRITE_EXCLUSIVE)
+            })
+            .is_err());
+        assert_eq!(b.read(|state| state.registrations.len()).unwrap(), 1);
+
+        // Changes made by another instance are seen right away.
+        b.update(|state| state.register(b.initiator(), 0, 0xb, false))
+            .unwrap();
+        assert_eq!(a.read(|state| state.registrations.len()).unwrap(), 2);
+        a.update(|state| state.release(a.initiator(), 0xa, WRITE_EXCLUSIVE))
+            .unwrap();
+        b.check_access(Access::Write).unwrap();
+    }
+}
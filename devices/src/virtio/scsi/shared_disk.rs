@@ -0,0 +1,407 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A disk image shared by the request workers of a multi-queue controller.
+//!
+//! Every request worker has its own executor, so a logical unit that is attached to all of them
+//! needs one instance of its disk image per worker. Instances made with `DiskFile::try_clone` only
+//! share the file, not the state kept in memory by formats such as qcow2, and most formats can't
+//! be cloned at all. Instead, the image is owned by a thread of its own and the workers send their
+//! requests to that thread through `SharedDisk` handles.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use base::AsRawDescriptors;
+use base::FileAllocate;
+use base::FileReadWriteAtVolatile;
+use base::FileSetLen;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use cros_async::block_on;
+use cros_async::BackingMemory;
+use cros_async::Executor;
+use cros_async::MemRegion;
+use cros_async::MemRegionIter;
+use cros_async::VecIoWrapper;
+use disk::AsyncDisk;
+use disk::DiskFile;
+use disk::DiskGetLen;
+use disk::ToAsyncDisk;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
+use futures::select;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+
+type Response<T> = oneshot::Sender<disk::Result<T>>;
+
+/// A request executed by the thread that owns the disk image.
+enum Request {
+    Read {
+        offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        regions: Vec<MemRegion>,
+        response: Response<usize>,
+    },
+    Write {
+        offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        regions: Vec<MemRegion>,
+        response: Response<usize>,
+    },
+    Flush(Response<()>),
+    Fsync(Response<()>),
+    Fdatasync(Response<()>),
+    PunchHole {
+        offset: u64,
+        length: u64,
+        response: Response<()>,
+    },
+    WriteZeroes {
+        offset: u64,
+        length: u64,
+        response: Response<()>,
+    },
+}
+
+async fn execute(disk: &dyn AsyncDisk, request: Request) {
+    // The requester may have stopped waiting for the response.
+    match request {
+        Request::Read {
+            offset,
+            mem,
+            regions,
+            response,
+        } => {
+            let result = disk
+                .read_to_mem(offset, mem, MemRegionIter::new(&regions))
+                .await;
+            let _ = response.send(result);
+        }
+        Request::Write {
+            offset,
+            mem,
+            regions,
+            response,
+        } => {
+            let result = disk
+                .write_from_mem(offset, mem, MemRegionIter::new(&regions))
+                .await;
+            let _ = response.send(result);
+        }
+        Request::Flush(response) => {
+            let _ = response.send(disk.flush().await);
+        }
+        Request::Fsync(response) => {
+            let _ = response.send(disk.fsync().await);
+        }
+        Request::Fdatasync(response) => {
+            let _ = response.send(disk.fdatasync().await);
+        }
+        Request::PunchHole {
+            offset,
+            length,
+            response,
+        } => {
+            let _ = response.send(disk.punch_hole(offset, length).await);
+        }
+        Request::WriteZeroes {
+            offset,
+            length,
+            response,
+        } => {
+            let _ = response.send(disk.write_zeroes_at(offset, length).await);
+        }
+    }
+}
+
+// Executes the requests concurrently until every handle is dropped.
+async fn serve(disk: &dyn AsyncDisk, requests: mpsc::UnboundedReceiver<Request>) {
+    let mut requests = requests.fuse();
+    let mut pending = FuturesUnordered::new();
+    loop {
+        select! {
+            request = requests.next() => match request {
+                Some(request) => pending.push(execute(disk, request)),
+                None => break,
+            },
+            _ = pending.select_next_some() => {}
+        }
+    }
+    while pending.next().await.is_some() {}
+}
+
+/// A handle to a disk image owned by a thread of its own. Clones of the handle send their requests
+/// to the same instance of the image.
+#[derive(Clone)]
+pub struct SharedDisk {
+    requests: mpsc::UnboundedSender<Request>,
+    len: u64,
+}
+
+impl SharedDisk {
+    /// Starts the thread that owns `disk`. The thread exits once every handle is dropped.
+    pub fn new(disk: Box<dyn DiskFile>) -> anyhow::Result<SharedDisk> {
+        let len = disk
+            .get_len()
+            .context("failed to get the length of the disk image")?;
+        let (requests, receiver) = mpsc::unbounded();
+        let (ready_sender, ready) = std::sync::mpsc::channel();
+        thread::Builder::new()
+            .name("v_scsi_disk".to_string())
+            .spawn(move || {
+                let ex = match Executor::new() {
+                    Ok(ex) => ex,
+                    Err(e) => {
+                        let _ = ready_sender.send(Err(anyhow::Error::new(e)));
+                        return;
+                    }
+                };
+                let disk = match disk.to_async_disk(&ex) {
+                    Ok(disk) => disk,
+                    Err(e) => {
+                        let _ = ready_sender.send(Err(anyhow::Error::new(e)));
+                        return;
+                    }
+                };
+                let _ = ready_sender.send(Ok(()));
+                let _ = ex.run_until(serve(&*disk, receiver));
+            })
+            .context("failed to start the disk thread")?;
+        ready
+            .recv()
+            .context("the disk thread exited")?
+            .context("failed to create the async disk")?;
+        Ok(SharedDisk { requests, len })
+    }
+
+    // Returns `None` if the disk thread exited before responding.
+    async fn request<T>(
+        &self,
+        make_request: impl FnOnce(Response<T>) -> Request,
+    ) -> Option<disk::Result<T>> {
+        let (response, result) = oneshot::channel();
+        self.requests.unbounded_send(make_request(response)).ok()?;
+        result.await.ok()
+    }
+}
+
+fn disk_thread_exited() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the disk thread exited")
+}
+
+impl fmt::Debug for SharedDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedDisk")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl DiskGetLen for SharedDisk {
+    fn get_len(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+}
+
+impl FileSetLen for SharedDisk {
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "shared disks can't be resized",
+        ))
+    }
+}
+
+impl FileAllocate for SharedDisk {
+    fn allocate(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "shared disks can't be preallocated",
+        ))
+    }
+}
+
+impl AsRawDescriptors for SharedDisk {
+    fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        // The descriptors of the image belong to the disk thread.
+        Vec::new()
+    }
+}
+
+impl FileReadWriteAtVolatile for SharedDisk {
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let mem = Arc::new(VecIoWrapper::from(vec![0u8; slice.size()]));
+        let regions = vec![MemRegion {
+            offset: 0,
+            len: slice.size(),
+        }];
+        let count = block_on(self.request(|response| Request::Read {
+            offset,
+            mem: mem.clone(),
+            regions,
+            response,
+        }))
+        .ok_or_else(disk_thread_exited)?
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let data: Vec<u8> = Arc::try_unwrap(mem)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "read buffer still in use"))?
+            .into();
+        slice.copy_from(&data[..count]);
+        Ok(count)
+    }
+
+    fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let mut data = vec![0u8; slice.size()];
+        slice.copy_to(&mut data);
+        let regions = vec![MemRegion {
+            offset: 0,
+            len: data.len(),
+        }];
+        block_on(self.request(|response| Request::Write {
+            offset,
+            mem: Arc::new(VecIoWrapper::from(data)),
+            regions,
+            response,
+        }))
+        .ok_or_else(disk_thread_exited)?
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+impl DiskFile for SharedDisk {
+    fn try_clone(&self) -> io::Result<Box<dyn DiskFile>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl ToAsyncDisk for SharedDisk {
+    fn to_async_disk(self: Box<Self>, _ex: &Executor) -> disk::Result<Box<dyn AsyncDisk>> {
+        Ok(self)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncDisk for SharedDisk {
+    fn into_inner(self: Box<Self>) -> Box<dyn DiskFile> {
+        self
+    }
+
+    async fn flush(&self) -> disk::Result<()> {
+        self.request(Request::Flush)
+            .await
+            .unwrap_or_else(|| Err(disk::Error::IoFlush(disk_thread_exited())))
+    }
+
+    async fn fsync(&self) -> disk::Result<()> {
+        self.request(Request::Fsync)
+            .await
+            .unwrap_or_else(|| Err(disk::Error::IoFsync(disk_thread_exited())))
+    }
+
+    async fn fdatasync(&self) -> disk::Result<()> {
+        self.request(Request::Fdatasync)
+            .await
+            .unwrap_or_else(|| Err(disk::Error::IoFdatasync(disk_thread_exited())))
+    }
+
+    async fn read_to_mem<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: MemRegionIter<'a>,
+    ) -> disk::Result<usize> {
+        let regions = mem_offsets.collect();
+        self.request(|response| Request::Read {
+            offset: file_offset,
+            mem,
+            regions,
+            response,
+        })
+        .await
+        .unwrap_or_else(|| Err(disk::Error::ReadingData(disk_thread_exited())))
+    }
+
+    async fn write_from_mem<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: MemRegionIter<'a>,
+    ) -> disk::Result<usize> {
+        let regions = mem_offsets.collect();
+        self.request(|response| Request::Write {
+            offset: file_offset,
+            mem,
+            regions,
+            response,
+        })
+        .await
+        .unwrap_or_else(|| Err(disk::Error::WritingData(disk_thread_exited())))
+    }
+
+    async fn punch_hole(&self, file_offset: u64, length: u64) -> disk::Result<()> {
+        self.request(|response| Request::PunchHole {
+            offset: file_offset,
+            length,
+            response,
+        })
+        .await
+        .unwrap_or_else(|| Err(disk::Error::IoPunchHole(disk_thread_exited())))
+    }
+
+    async fn write_zeroes_at(&self, file_offset: u64, length: u64) -> disk::Result<()> {
+        self.request(|response| Request::WriteZeroes {
+            offset: file_offset,
+            length,
+            response,
+        })
+        .await
+        .unwrap_or_else(|| Err(disk::Error::WriteZeroes(disk_thread_exited())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::tempfile;
+
+    use super::*;
+
+    #[test]
+    fn clones_share_the_image() {
+        let file: File = tempfile().unwrap();
+        file.set_len(0x1000).unwrap();
+        let mut a = SharedDisk::new(Box::new(file)).unwrap();
+        let mut b = a.try_clone().unwrap();
+        assert_eq!(b.get_len().unwrap(), 0x1000);
+
+        let mut data = [0x5au8; 512];
+        a.write_all_at_volatile(VolatileSlice::new(&mut data), 512)
+            .unwrap();
+        let mut buf = [0u8; 512];
+        b.read_exact_at_volatile(VolatileSlice::new(&mut buf), 512)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        let ex = Executor::new().unwrap();
+        let async_disk = b.to_async_disk(&ex).unwrap();
+        ex.run_until(async {
+            async_disk.write_zeroes_at(512, 512).await.unwrap();
+            async_disk.fdatasync().await.unwrap();
+            let mut buf = [0xffu8; 512];
+            async_disk
+                .read_double_buffered(512, &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(buf, [0u8; 512]);
+        })
+        .unwrap();
+    }
+}
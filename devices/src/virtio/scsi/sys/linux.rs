@@ -2,9 +2,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::ffi::OsString;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
 
+use anyhow::bail;
 use anyhow::Context;
 use base::flock;
 use base::open_file_or_duplicate;
@@ -15,13 +19,16 @@ use crate::virtio::scsi::ScsiOption;
 
 impl ScsiOption {
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
+        // CD-ROM drives are always read-only.
+        let read_only = self.read_only || self.cdrom;
         let mut options = OpenOptions::new();
-        options.read(true).write(!self.read_only);
+        options.read(true).write(!read_only);
 
         let raw_image: File = open_file_or_duplicate(&self.path, &options)
             .with_context(|| format!("failed to load disk image {}", self.path.display()))?;
-        // Lock the disk image to prevent other crosvm instances from using it.
-        let lock_op = if self.read_only {
+        // Lock the disk image to prevent other crosvm instances from using it, unless it is shared
+        // with them.
+        let lock_op = if read_only || self.shared {
             FlockOperation::LockShared
         } else {
             FlockOperation::LockExclusive
@@ -29,8 +36,66 @@ impl ScsiOption {
         flock(&raw_image, lock_op, true)
             .with_context(|| format!("failed to lock disk image {}", self.path.display()))?;
 
+        if self.shared {
+            if self.initiator_id.is_none() {
+                bail!(
+                    "shared disk {} requires an initiator-id",
+                    self.path.display()
+                );
+            }
+            // Formats like qcow2 keep metadata in memory that the other instances don't see.
+            let image_type = disk::detect_image_type(&raw_image, false)
+                .with_context(|| format!("failed to detect the type of {}", self.path.display()))?;
+            if image_type != disk::ImageType::Raw {
+                bail!(
+                    "shared disk {} must be a raw image, not {:?}",
+                    self.path.display(),
+                    image_type
+                );
+            }
+        }
+
         // We only support sparse disks for now.
         disk::create_disk_file(raw_image, true, disk::MAX_NESTING_DEPTH, &self.path)
             .context("create_disk_file failed")
     }
+
+    /// Returns the path of the file that holds the persistent reservations of a shared disk.
+    pub fn reservation_file_path(&self) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(".pr");
+        PathBuf::from(path)
+    }
+
+    /// Opens the file that holds the persistent reservations of the disk if it is shared with
+    /// other crosvm instances.
+    pub fn open_reservation_file(&self) -> anyhow::Result<Option<File>> {
+        if !self.shared || self.cdrom {
+            return Ok(None);
+        }
+        let path = self.reservation_file_path();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open reservation file {}", path.display()))?;
+        Ok(Some(file))
+    }
+}
+
+/// Locks the file holding the persistent reservations of a shared disk.
+pub fn lock_reservations(file: &File, exclusive: bool) -> io::Result<()> {
+    let lock_op = if exclusive {
+        FlockOperation::LockExclusive
+    } else {
+        FlockOperation::LockShared
+    };
+    flock(file, lock_op, false).map_err(io::Error::from)
+}
+
+/// Unlocks the file holding the persistent reservations of a shared disk.
+pub fn unlock_reservations(file: &File) -> io::Result<()> {
+    flock(file, FlockOperation::Unlock, false).map_err(io::Error::from)
 }
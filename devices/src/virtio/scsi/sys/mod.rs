@@ -5,7 +5,9 @@
 cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
         mod linux;
+        pub use self::linux::*;
     } else if #[cfg(windows)] {
         mod windows;
+        pub use self::windows::*;
     }
 }
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
+use std::io;
+
 use anyhow::bail;
 use anyhow::Context;
 use disk::DiskFile;
@@ -12,4 +15,16 @@ impl ScsiOption {
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
         bail!("ScsiOption::open() is yet to be implemented for windows.")
     }
+
+    pub fn open_reservation_file(&self) -> anyhow::Result<Option<File>> {
+        bail!("ScsiOption::open_reservation_file() is yet to be implemented for windows.")
+    }
+}
+
+pub fn lock_reservations(_file: &File, _exclusive: bool) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+pub fn unlock_reservations(_file: &File) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
 }
@@ -0,0 +1,46 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Token bucket shared by the I/O limits of the virtio devices.
+
+use std::num::NonZeroU64;
+use std::time::Duration;
+use std::time::Instant;
+
+/// A token bucket refilled at `rate` tokens per second, holding at most `capacity` tokens.
+///
+/// Consumers take tokens up front and may drive the bucket into debt. They must then wait until the
+/// debt has been repaid, which keeps concurrent consumers in order without a separate queue.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket. `burst` defaults to one second worth of tokens.
+    pub(crate) fn new(rate: NonZeroU64, burst: Option<NonZeroU64>, now: Instant) -> TokenBucket {
+        let capacity = burst.unwrap_or(rate).get() as f64;
+        TokenBucket {
+            rate: rate.get() as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    /// Takes `amount` tokens and returns how long the caller must wait before using them.
+    pub(crate) fn consume(&mut self, amount: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
@@ -242,7 +242,7 @@ pub fn run_gpu_device(opts: Options) -> anyhow::Result<()> {
         &channels,
         /* gpu_cgroup_path */
         None,
-    )));
+    )?));
 
     let backend = Box::new(GpuBackend {
         ex: ex.clone(),
@@ -308,7 +308,7 @@ pub fn run_gpu_device_worker(
         base_features,
         /* channels= */ &Default::default(),
         wndproc_thread,
-    )));
+    )?));
 
     let ex = Executor::new().context("failed to create executor")?;
 
@@ -70,7 +70,7 @@ async fn run_tx_queue<T: TapT>(
             }
         }
 
-        process_tx(&doorbell, &mut queue, &mut tap);
+        process_tx(&doorbell, &mut queue, &mut tap, None, None);
     }
     queue
 }
@@ -100,7 +100,14 @@ async fn run_ctrl_queue<T: TapT>(
             }
         }
 
-        if let Err(e) = process_ctrl(&doorbell, &mut queue, &mut tap, acked_features, vq_pairs) {
+        if let Err(e) = process_ctrl(
+            &doorbell,
+            &mut queue,
+            &mut tap,
+            acked_features,
+            vq_pairs,
+            None,
+        ) {
             error!("Failed to process ctrl queue: {}", e);
             break;
         }
@@ -159,7 +159,7 @@ async fn run_rx_queue<T: TapT>(
             }
         }
 
-        match process_rx(&doorbell, &mut queue, tap.as_source_mut()) {
+        match process_rx(&doorbell, &mut queue, tap.as_source_mut(), None, None, None) {
             Ok(()) => {}
             Err(NetError::RxDescriptorsExhausted) => {
                 if let Err(e) = kick_evt.next_val().await {
@@ -141,6 +141,8 @@ async fn run_rx_queue<T: TapT>(
             &mut deferred_rx,
             &mut rx_count,
             &mut overlapped_wrapper,
+            None,
+            None,
         );
         if needs_interrupt {
             call_evt.signal_used_queue(queue.vector());
@@ -4,6 +4,7 @@
 
 //! Asynchronous disk image helpers.
 
+use std::fs::File;
 use std::io;
 use std::sync::Arc;
 use std::time::Duration;
@@ -26,6 +27,7 @@ use crate::DiskFile;
 use crate::DiskGetLen;
 use crate::Error;
 use crate::Result;
+use crate::SnapshotInfo;
 
 /// Async wrapper around a non-async `DiskFile` using a `BlockingPool`.
 ///
@@ -75,11 +77,27 @@ pub trait DiskFlush {
     fn flush(&mut self) -> io::Result<()>;
 }
 
+/// Internal snapshots, see the methods of the same name in `AsyncDisk`.
+pub trait DiskSnapshot {
+    fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>>;
+    fn create_snapshot(&mut self, name: &str) -> Result<()>;
+    fn apply_snapshot(&mut self, name: &str) -> Result<()>;
+    fn delete_snapshot(&mut self, name: &str) -> Result<()>;
+}
+
+/// Backing chain operations, see the methods of the same name in `AsyncDisk`.
+pub trait DiskBackingChain {
+    fn commit(&mut self, backing_file: File) -> Result<()>;
+    fn stream(&mut self) -> Result<()>;
+}
+
 #[async_trait(?Send)]
 impl<
         T: 'static
+            + DiskBackingChain
             + DiskFile
             + DiskFlush
+            + DiskSnapshot
             + Send
             + FileAllocate
             + FileSetLen
@@ -205,4 +223,48 @@ impl<
             })
             .await
     }
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        let inner_clone = self.inner.clone();
+        self.blocking_pool
+            .spawn(move || inner_clone.lock().list_snapshots())
+            .await
+    }
+
+    async fn create_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_string();
+        self.blocking_pool
+            .spawn(move || inner_clone.lock().create_snapshot(&name))
+            .await
+    }
+
+    async fn apply_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_string();
+        self.blocking_pool
+            .spawn(move || inner_clone.lock().apply_snapshot(&name))
+            .await
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_string();
+        self.blocking_pool
+            .spawn(move || inner_clone.lock().delete_snapshot(&name))
+            .await
+    }
+
+    async fn commit(&self, backing_file: File) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        self.blocking_pool
+            .spawn(move || inner_clone.lock().commit(backing_file))
+            .await
+    }
+
+    async fn stream(&self) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        self.blocking_pool
+            .spawn(move || inner_clone.lock().stream())
+            .await
+    }
 }
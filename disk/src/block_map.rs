@@ -0,0 +1,381 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Read-only access to disk image formats that store the virtual disk in fixed size blocks, each of
+//! which is either absent from the image or stored contiguously in the image file.
+
+use std::cmp::min;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use base::AsRawDescriptor;
+use base::FileAllocate;
+use base::FileReadWriteAtVolatile;
+use base::FileSetLen;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use cros_async::BackingMemory;
+use cros_async::Executor;
+use cros_async::IoSource;
+
+use crate::AsyncDisk;
+use crate::DiskFile;
+use crate::DiskGetLen;
+use crate::Error as DiskError;
+use crate::Result as DiskResult;
+use crate::ToAsyncDisk;
+
+/// Location of the data of one block of the virtual disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockMapping {
+    /// The block is not stored in the image and reads as zeroes.
+    Zero,
+    /// The block is stored in the image file starting at the given offset.
+    Data(u64),
+}
+
+/// The block layout of an image, as parsed from its metadata.
+pub trait BlockMap: Debug + Send + Sync + 'static {
+    /// Size of the virtual disk in bytes.
+    fn size(&self) -> u64;
+
+    /// Size of a block in bytes.
+    fn block_size(&self) -> u64;
+
+    /// Returns the location of block number `block`, which is always within `size()`.
+    ///
+    /// Implementations must only return offsets that are within the image file, so that adding an
+    /// offset within the block can't overflow.
+    fn lookup(&self, block: u64) -> BlockMapping;
+}
+
+/// Returns the image file offset of the data at virtual disk offset `offset`, or `None` if it reads
+/// as zeroes, along with the number of bytes that are contiguous from there.
+fn locate(map: &dyn BlockMap, offset: u64) -> io::Result<(Option<u64>, u64)> {
+    let size = map.size();
+    if offset >= size {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("offset {} is past the end of the disk", offset),
+        ));
+    }
+    let block_size = map.block_size();
+    let block_offset = offset % block_size;
+    let len = min(block_size - block_offset, size - offset);
+    let file_offset = match map.lookup(offset / block_size) {
+        BlockMapping::Zero => None,
+        BlockMapping::Data(start) => Some(start + block_offset),
+    };
+    Ok((file_offset, len))
+}
+
+/// A read-only disk image in a block mapped format.
+#[derive(Debug)]
+pub struct BlockMappedDisk<M: BlockMap> {
+    file: File,
+    map: Arc<M>,
+}
+
+impl<M: BlockMap> BlockMappedDisk<M> {
+    pub fn new(file: File, map: M) -> BlockMappedDisk<M> {
+        BlockMappedDisk {
+            file,
+            map: Arc::new(map),
+        }
+    }
+}
+
+impl<M: BlockMap> DiskGetLen for BlockMappedDisk<M> {
+    fn get_len(&self) -> io::Result<u64> {
+        Ok(self.map.size())
+    }
+}
+
+impl<M: BlockMap> FileSetLen for BlockMappedDisk<M> {
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl<M: BlockMap> AsRawDescriptor for BlockMappedDisk<M> {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.file.as_raw_descriptor()
+    }
+}
+
+// Performs reads up to the block boundary.
+impl<M: BlockMap> FileReadWriteAtVolatile for BlockMappedDisk<M> {
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let (file_offset, len) = locate(&*self.map, offset)?;
+        let subslice = if slice.size() as u64 > len {
+            slice
+                .sub_slice(0, len as usize)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?
+        } else {
+            slice
+        };
+        match file_offset {
+            None => {
+                subslice.write_bytes(0);
+                Ok(subslice.size())
+            }
+            Some(file_offset) => self.file.read_at_volatile(subslice, file_offset),
+        }
+    }
+
+    fn write_at_volatile(&mut self, _slice: VolatileSlice, _offset: u64) -> io::Result<usize> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl<M: BlockMap> DiskFile for BlockMappedDisk<M> {
+    fn try_clone(&self) -> io::Result<Box<dyn DiskFile>> {
+        Ok(Box::new(BlockMappedDisk {
+            file: self.file.try_clone()?,
+            map: self.map.clone(),
+        }))
+    }
+}
+
+/// A block mapped disk that implements `AsyncDisk` for access.
+pub struct AsyncBlockMappedDisk<M: BlockMap> {
+    inner: IoSource<File>,
+    map: Arc<M>,
+}
+
+impl<M: BlockMap> ToAsyncDisk for BlockMappedDisk<M> {
+    fn to_async_disk(self: Box<Self>, ex: &Executor) -> DiskResult<Box<dyn AsyncDisk>> {
+        Ok(Box::new(AsyncBlockMappedDisk {
+            inner: ex.async_from(self.file).map_err(DiskError::ToAsync)?,
+            map: self.map,
+        }))
+    }
+}
+
+impl<M: BlockMap> DiskGetLen for AsyncBlockMappedDisk<M> {
+    fn get_len(&self) -> io::Result<u64> {
+        Ok(self.map.size())
+    }
+}
+
+impl<M: BlockMap> FileSetLen for AsyncBlockMappedDisk<M> {
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+impl<M: BlockMap> FileAllocate for AsyncBlockMappedDisk<M> {
+    fn allocate(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl<M: BlockMap> AsyncDisk for AsyncBlockMappedDisk<M> {
+    fn into_inner(self: Box<Self>) -> Box<dyn DiskFile> {
+        Box::new(BlockMappedDisk {
+            file: self.inner.into_source(),
+            map: self.map,
+        })
+    }
+
+    async fn flush(&self) -> DiskResult<()> {
+        // Do nothing because it's read-only.
+        Ok(())
+    }
+
+    async fn fsync(&self) -> DiskResult<()> {
+        // Do nothing because it's read-only.
+        Ok(())
+    }
+
+    async fn fdatasync(&self) -> DiskResult<()> {
+        // Do nothing because it's read-only.
+        Ok(())
+    }
+
+    /// Reads data from `file_offset` to the end of the current block and writes them into memory
+    /// `mem` at `mem_offsets`.
+    async fn read_to_mem<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: cros_async::MemRegionIter<'a>,
+    ) -> DiskResult<usize> {
+        let (image_offset, len) =
+            locate(&*self.map, file_offset).map_err(DiskError::ReadingData)?;
+        let mem_offsets = mem_offsets.take_bytes(len as usize);
+        match image_offset {
+            None => {
+                let mut count = 0;
+                for region in mem_offsets {
+                    mem.get_volatile_slice(region)
+                        .map_err(DiskError::GuestMemory)?
+                        .write_bytes(0);
+                    count += region.len;
+                }
+                Ok(count)
+            }
+            Some(image_offset) => self
+                .inner
+                .read_to_mem(Some(image_offset), mem, mem_offsets)
+                .await
+                .map_err(DiskError::ReadToMem),
+        }
+    }
+
+    async fn write_from_mem<'a>(
+        &'a self,
+        _file_offset: u64,
+        _mem: Arc<dyn BackingMemory + Send + Sync>,
+        _mem_offsets: cros_async::MemRegionIter<'a>,
+    ) -> DiskResult<usize> {
+        Err(DiskError::UnsupportedOperation)
+    }
+
+    async fn punch_hole(&self, _file_offset: u64, _length: u64) -> DiskResult<()> {
+        Err(DiskError::UnsupportedOperation)
+    }
+
+    async fn write_zeroes_at(&self, _file_offset: u64, _length: u64) -> DiskResult<()> {
+        Err(DiskError::UnsupportedOperation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cros_async::MemRegion;
+    use cros_async::MemRegionIter;
+    use vm_memory::GuestAddress;
+    use vm_memory::GuestMemory;
+
+    use super::*;
+
+    /// Four 16 byte blocks of a 56 byte disk; the odd blocks are stored in reverse order.
+    #[derive(Debug)]
+    struct TestMap;
+
+    impl BlockMap for TestMap {
+        fn size(&self) -> u64 {
+            56
+        }
+
+        fn block_size(&self) -> u64 {
+            16
+        }
+
+        fn lookup(&self, block: u64) -> BlockMapping {
+            match block {
+                1 => BlockMapping::Data(16),
+                3 => BlockMapping::Data(0),
+                _ => BlockMapping::Zero,
+            }
+        }
+    }
+
+    fn test_image() -> BlockMappedDisk<TestMap> {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut data: Vec<u8> = (0..32).collect();
+        file.write_all_at_volatile(VolatileSlice::new(&mut data), 0)
+            .unwrap();
+        BlockMappedDisk::new(file, TestMap)
+    }
+
+    fn expected_contents() -> Vec<u8> {
+        let mut expected = vec![0u8; 56];
+        for i in 0..16 {
+            expected[16 + i] = 16 + i as u8;
+        }
+        for i in 0..8 {
+            expected[48 + i] = i as u8;
+        }
+        expected
+    }
+
+    #[test]
+    fn read_blocks() {
+        let mut image = test_image();
+        let mut buf = vec![0x55u8; 56];
+        image
+            .read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        assert_eq!(buf, expected_contents());
+    }
+
+    #[test]
+    fn read_stops_at_block_boundary() {
+        let mut image = test_image();
+        let mut buf = vec![0u8; 32];
+        let count = image
+            .read_at_volatile(VolatileSlice::new(&mut buf), 20)
+            .unwrap();
+        assert_eq!(count, 12);
+        assert_eq!(&buf[..12], &expected_contents()[20..32]);
+    }
+
+    #[test]
+    fn read_past_end() {
+        let mut image = test_image();
+        let mut buf = vec![0u8; 8];
+        let err = image
+            .read_at_volatile(VolatileSlice::new(&mut buf), 56)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn write_fails() {
+        let mut image = test_image();
+        let mut buf = vec![0u8; 8];
+        assert!(image
+            .write_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .is_err());
+    }
+
+    #[test]
+    fn async_read_blocks() {
+        let ex = Executor::new().unwrap();
+        ex.run_until(async {
+            let image = Box::new(test_image()).to_async_disk(&ex).unwrap();
+            let guest_mem = Arc::new(GuestMemory::new(&[(GuestAddress(0), 4096)]).unwrap());
+            guest_mem
+                .write_all_at_addr(&[0x55u8; 56], GuestAddress(0))
+                .unwrap();
+            let mut count = 0;
+            while count < 56 {
+                count += image
+                    .read_to_mem(
+                        count as u64,
+                        guest_mem.clone(),
+                        MemRegionIter::new(&[MemRegion {
+                            offset: count as u64,
+                            len: 56 - count,
+                        }]),
+                    )
+                    .await
+                    .unwrap();
+            }
+            let mut buf = vec![0u8; 56];
+            guest_mem.read_at_addr(&mut buf, GuestAddress(0)).unwrap();
+            assert_eq!(buf, expected_contents());
+        })
+        .unwrap();
+    }
+}
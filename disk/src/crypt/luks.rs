@@ -0,0 +1,301 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Unlocking of LUKS1 headers, as used by qcow2 images with `crypt_method` LUKS.
+//!
+//! Only the "aes" cipher in "xts-plain64" mode is supported, with sha1, sha256 or sha512 as the
+//! PBKDF2 and anti-forensic splitter hash.
+
+use std::str;
+
+use base::FileReadWriteAtVolatile;
+use base::VolatileSlice;
+use sha1::Sha1;
+use sha2::Digest;
+use sha2::Sha256;
+use sha2::Sha512;
+
+use super::Error;
+use super::Result;
+use super::XtsCipher;
+use super::SECTOR_SIZE;
+
+pub const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+
+const HEADER_SIZE: usize = 592;
+const NUM_KEY_SLOTS: usize = 8;
+const KEY_SLOTS_OFFSET: usize = 208;
+const KEY_SLOT_SIZE: usize = 48;
+const KEY_SLOT_ACTIVE: u32 = 0x00AC_71F3;
+const DIGEST_SIZE: usize = 20;
+const SALT_SIZE: usize = 32;
+// The number of anti-forensic stripes every LUKS1 implementation uses.
+const STRIPES: u32 = 4000;
+
+#[derive(Clone, Copy, Debug)]
+enum Hash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+// Hashes each digest sized chunk of `buf` together with its index, as the anti-forensic splitter
+// does between stripes.
+fn diffuse<D: Digest>(buf: &mut [u8]) {
+    let digest_size = <D as Digest>::output_size();
+    for (i, chunk) in buf.chunks_mut(digest_size).enumerate() {
+        let mut hasher = D::new();
+        hasher.update((i as u32).to_be_bytes());
+        hasher.update(&*chunk);
+        let digest = hasher.finalize();
+        let len = chunk.len();
+        chunk.copy_from_slice(&digest[..len]);
+    }
+}
+
+impl Hash {
+    fn from_spec(spec: &str) -> Result<Hash> {
+        match spec {
+            "sha1" => Ok(Hash::Sha1),
+            "sha256" => Ok(Hash::Sha256),
+            "sha512" => Ok(Hash::Sha512),
+            _ => Err(Error::UnsupportedHash(spec.to_string())),
+        }
+    }
+
+    fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+        match self {
+            Hash::Sha1 => pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, iterations, out),
+            Hash::Sha256 => pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, out),
+            Hash::Sha512 => pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, out),
+        }
+    }
+
+    fn diffuse(self, buf: &mut [u8]) {
+        match self {
+            Hash::Sha1 => diffuse::<Sha1>(buf),
+            Hash::Sha256 => diffuse::<Sha256>(buf),
+            Hash::Sha512 => diffuse::<Sha512>(buf),
+        }
+    }
+}
+
+fn xor_into(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+// Recovers the key that was split into `stripes` by the anti-forensic splitter.
+fn af_merge(hash: Hash, material: &[u8], key_bytes: usize) -> Vec<u8> {
+    let mut key = vec![0u8; key_bytes];
+    let mut stripes = material.chunks_exact(key_bytes).peekable();
+    while let Some(stripe) = stripes.next() {
+        xor_into(&mut key, stripe);
+        if stripes.peek().is_some() {
+            hash.diffuse(&mut key);
+        }
+    }
+    key
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+// Returns the NUL terminated string in the 32 byte field at `offset`.
+fn string_field(buf: &[u8], offset: usize) -> Result<&str> {
+    let field = &buf[offset..offset + 32];
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| Error::InvalidLuksHeader("non-UTF-8 string"))
+}
+
+/// Reads the LUKS1 header at `header_offset` of `file` and returns the cipher for the volume's
+/// master key, unlocked with `passphrase` from the first key slot it opens.
+///
+/// Key material offsets are relative to the start of the header.
+pub fn unlock<F: FileReadWriteAtVolatile + ?Sized>(
+    file: &mut F,
+    header_offset: u64,
+    passphrase: &[u8],
+) -> Result<XtsCipher> {
+    let mut header = [0u8; HEADER_SIZE];
+    file.read_exact_at_volatile(VolatileSlice::new(&mut header), header_offset)
+        .map_err(Error::ReadingLuksHeader)?;
+    if &header[..6] != LUKS_MAGIC {
+        return Err(Error::InvalidLuksHeader("bad magic"));
+    }
+    let version = u16::from_be_bytes([header[6], header[7]]);
+    if version != 1 {
+        return Err(Error::UnsupportedLuksVersion(version));
+    }
+    let cipher_name = string_field(&header, 8)?;
+    let cipher_mode = string_field(&header, 40)?;
+    if cipher_name != "aes" || cipher_mode != "xts-plain64" {
+        return Err(Error::UnsupportedCipher(format!(
+            "{}-{}",
+            cipher_name, cipher_mode
+        )));
+    }
+    let hash = Hash::from_spec(string_field(&header, 72)?)?;
+    let key_bytes = be32(&header, 108) as usize;
+    if key_bytes != 32 && key_bytes != 64 {
+        return Err(Error::InvalidLuksHeader("bad key size"));
+    }
+    let mk_digest = &header[112..112 + DIGEST_SIZE];
+    let mk_digest_salt = &header[132..132 + SALT_SIZE];
+    let mk_digest_iter = be32(&header, 164);
+
+    for slot in header[KEY_SLOTS_OFFSET..]
+        .chunks_exact(KEY_SLOT_SIZE)
+        .take(NUM_KEY_SLOTS)
+    {
+        if be32(slot, 0) != KEY_SLOT_ACTIVE {
+            continue;
+        }
+        let iterations = be32(slot, 4);
+        let salt = &slot[8..8 + SALT_SIZE];
+        let material_offset = u64::from(be32(slot, 40)) * SECTOR_SIZE;
+        if be32(slot, 44) != STRIPES || iterations == 0 {
+            return Err(Error::InvalidLuksHeader("bad key slot"));
+        }
+
+        let mut slot_key = vec![0u8; key_bytes];
+        hash.pbkdf2(passphrase, salt, iterations, &mut slot_key);
+        let slot_cipher = XtsCipher::new(&slot_key)?;
+
+        // The key material is a whole number of sectors, encrypted with sectors numbered from
+        // zero.
+        let mut material = vec![0u8; key_bytes * STRIPES as usize];
+        file.read_exact_at_volatile(
+            VolatileSlice::new(&mut material),
+            header_offset + material_offset,
+        )
+        .map_err(Error::ReadingLuksHeader)?;
+        slot_cipher.decrypt(0, &mut material);
+
+        let master_key = af_merge(hash, &material, key_bytes);
+        let mut digest = [0u8; DIGEST_SIZE];
+        hash.pbkdf2(&master_key, mk_digest_salt, mk_digest_iter, &mut digest);
+        if digest == mk_digest {
+            return XtsCipher::new(&master_key);
+        }
+    }
+    Err(Error::IncorrectPassphrase)
+}
+
+/// Returns a LUKS1 header with key slot 0 holding `master_key` protected by `passphrase`, followed
+/// by the slot's key material.
+#[cfg(test)]
+pub(crate) fn create_header(passphrase: &[u8], master_key: &[u8], hash_spec: &str) -> Vec<u8> {
+    const ITERATIONS: u32 = 1000;
+    const MATERIAL_SECTOR: u32 = 8;
+    let hash = Hash::from_spec(hash_spec).unwrap();
+    let key_bytes = master_key.len();
+
+    let mut image = vec![0u8; MATERIAL_SECTOR as usize * SECTOR_SIZE as usize];
+    image[..6].copy_from_slice(LUKS_MAGIC);
+    image[6..8].copy_from_slice(&1u16.to_be_bytes());
+    image[8..11].copy_from_slice(b"aes");
+    image[40..51].copy_from_slice(b"xts-plain64");
+    image[72..72 + hash_spec.len()].copy_from_slice(hash_spec.as_bytes());
+    image[108..112].copy_from_slice(&(key_bytes as u32).to_be_bytes());
+    image[132..132 + SALT_SIZE].fill(0x5a);
+    let mut digest = [0u8; DIGEST_SIZE];
+    hash.pbkdf2(
+        master_key,
+        &image[132..132 + SALT_SIZE],
+        ITERATIONS,
+        &mut digest,
+    );
+    image[112..112 + DIGEST_SIZE].copy_from_slice(&digest);
+    image[164..168].copy_from_slice(&ITERATIONS.to_be_bytes());
+
+    let slot = &mut image[KEY_SLOTS_OFFSET..KEY_SLOTS_OFFSET + KEY_SLOT_SIZE];
+    slot[0..4].copy_from_slice(&KEY_SLOT_ACTIVE.to_be_bytes());
+    slot[4..8].copy_from_slice(&ITERATIONS.to_be_bytes());
+    slot[8..8 + SALT_SIZE].fill(0xa5);
+    slot[40..44].copy_from_slice(&MATERIAL_SECTOR.to_be_bytes());
+    slot[44..48].copy_from_slice(&STRIPES.to_be_bytes());
+    let mut slot_key = vec![0u8; key_bytes];
+    hash.pbkdf2(
+        passphrase,
+        &slot[8..8 + SALT_SIZE],
+        ITERATIONS,
+        &mut slot_key,
+    );
+
+    // Split the master key: all stripes but the last are arbitrary, the last one is chosen so that
+    // merging them yields the master key.
+    let mut material = vec![0u8; key_bytes * STRIPES as usize];
+    let mut diffused = vec![0u8; key_bytes];
+    let (random, last) = material.split_at_mut(key_bytes * (STRIPES as usize - 1));
+    for (i, stripe) in random.chunks_exact_mut(key_bytes).enumerate() {
+        stripe.fill(i as u8);
+        xor_into(&mut diffused, stripe);
+        hash.diffuse(&mut diffused);
+    }
+    last.copy_from_slice(&diffused);
+    xor_into(last, master_key);
+    XtsCipher::new(&slot_key).unwrap().encrypt(0, &mut material);
+    image.extend_from_slice(&material);
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unlock_header(header: &[u8], passphrase: &[u8]) -> Result<XtsCipher> {
+        // Place the header at an offset, as in a qcow2 image.
+        let mut file = tempfile::tempfile().unwrap();
+        let mut data = header.to_vec();
+        file.write_all_at_volatile(VolatileSlice::new(&mut data), 65536)
+            .unwrap();
+        unlock(&mut file, 65536, passphrase)
+    }
+
+    fn same_key(cipher: &XtsCipher, key: &[u8]) -> bool {
+        let mut a = vec![0u8; 512];
+        let mut b = vec![0u8; 512];
+        cipher.encrypt(1, &mut a);
+        XtsCipher::new(key).unwrap().encrypt(1, &mut b);
+        a == b
+    }
+
+    #[test]
+    fn unlock_sha256() {
+        let master_key: Vec<u8> = (0..64).collect();
+        let header = create_header(b"secret", &master_key, "sha256");
+        let cipher = unlock_header(&header, b"secret").unwrap();
+        assert!(same_key(&cipher, &master_key));
+    }
+
+    #[test]
+    fn unlock_sha1_aes128() {
+        let master_key: Vec<u8> = (100..132).collect();
+        let header = create_header(b"secret", &master_key, "sha1");
+        let cipher = unlock_header(&header, b"secret").unwrap();
+        assert!(same_key(&cipher, &master_key));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let header = create_header(b"secret", &[1u8; 64], "sha256");
+        assert!(matches!(
+            unlock_header(&header, b"guess"),
+            Err(Error::IncorrectPassphrase)
+        ));
+    }
+
+    #[test]
+    fn unsupported_cipher() {
+        let mut header = create_header(b"secret", &[1u8; 64], "sha256");
+        header[40..51].copy_from_slice(b"cbc-essiv:s");
+        assert!(matches!(
+            unlock_header(&header, b"secret"),
+            Err(Error::UnsupportedCipher(_))
+        ));
+    }
+}
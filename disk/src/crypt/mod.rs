@@ -0,0 +1,620 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! AES-XTS encryption of disk images.
+//!
+//! Data is encrypted in 512 byte sectors, each using its sector number as the XTS tweak
+//! ("plain64" IVs), which is compatible with dm-crypt, LUKS and qcow2 encryption.
+
+#[cfg(feature = "qcow")]
+pub mod luks;
+
+use std::cmp::min;
+use std::fmt;
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+
+use aes::cipher::consts::U16;
+use aes::cipher::BlockDecrypt;
+use aes::cipher::BlockEncrypt;
+use aes::cipher::BlockSizeUser;
+use aes::cipher::KeyInit;
+use aes::Aes128;
+use aes::Aes256;
+use aes::Block;
+use async_trait::async_trait;
+use base::AsRawDescriptors;
+use base::FileAllocate;
+use base::FileReadWriteAtVolatile;
+use base::FileSetLen;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use cros_async::BackingMemory;
+use cros_async::Executor;
+use remain::sorted;
+use thiserror::Error;
+
+use crate::AsyncDisk;
+use crate::DiskFile;
+use crate::DiskGetLen;
+use crate::Error as DiskError;
+use crate::Result as DiskResult;
+use crate::ToAsyncDisk;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("the passphrase doesn't unlock any key slot")]
+    IncorrectPassphrase,
+    #[error("invalid LUKS header: {0}")]
+    InvalidLuksHeader(&'static str),
+    #[error("invalid XTS key length {0}, must be 32 or 64 bytes")]
+    InvalidXtsKeyLength(usize),
+    #[error("failed to read the LUKS header: {0}")]
+    ReadingLuksHeader(io::Error),
+    #[error("unsupported LUKS cipher: {0}")]
+    UnsupportedCipher(String),
+    #[error("unsupported LUKS hash: {0}")]
+    UnsupportedHash(String),
+    #[error("unsupported LUKS version: {0}")]
+    UnsupportedLuksVersion(u16),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Size of the unit of encryption; each sector is encrypted with its own tweak.
+pub const SECTOR_SIZE: u64 = 512;
+
+// Largest amount of data encrypted at once by `write_zeroes_at`.
+const MAX_ZEROES_CHUNK: u64 = 1 << 20;
+
+#[derive(Clone)]
+enum XtsKeys {
+    Aes128 {
+        data: Box<Aes128>,
+        tweak: Box<Aes128>,
+    },
+    Aes256 {
+        data: Box<Aes256>,
+        tweak: Box<Aes256>,
+    },
+}
+
+/// AES-XTS with 512 byte sectors and the little endian sector number as the tweak.
+#[derive(Clone)]
+pub struct XtsCipher {
+    keys: XtsKeys,
+}
+
+// Don't print the key schedules.
+impl Debug for XtsCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XtsCipher").finish_non_exhaustive()
+    }
+}
+
+fn xor_block(block: &mut Block, other: &Block) {
+    for (b, o) in block.iter_mut().zip(other.iter()) {
+        *b ^= o;
+    }
+}
+
+// Encrypts or decrypts one XTS data unit in place. `data` must be a multiple of the AES block size.
+fn xts_data_unit<C: BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>>(
+    data_key: &C,
+    tweak_key: &C,
+    tweak_value: u64,
+    data: &mut [u8],
+    encrypt: bool,
+) {
+    let mut tweak = Block::default();
+    tweak[..8].copy_from_slice(&tweak_value.to_le_bytes());
+    tweak_key.encrypt_block(&mut tweak);
+    for chunk in data.chunks_exact_mut(16) {
+        let block = Block::from_mut_slice(chunk);
+        xor_block(block, &tweak);
+        if encrypt {
+            data_key.encrypt_block(block);
+        } else {
+            data_key.decrypt_block(block);
+        }
+        xor_block(block, &tweak);
+        // Multiply the tweak by the primitive element of GF(2^128).
+        let carry = tweak[15] >> 7;
+        for i in (1..16).rev() {
+            tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+        }
+        tweak[0] = (tweak[0] << 1) ^ (0x87 * carry);
+    }
+}
+
+impl XtsCipher {
+    /// Creates a cipher from a 32 byte (AES-128) or 64 byte (AES-256) XTS key. The first half of
+    /// the key encrypts the data and the second half the tweaks.
+    pub fn new(key: &[u8]) -> Result<XtsCipher> {
+        let (data, tweak) = key.split_at(key.len() / 2);
+        let keys = match key.len() {
+            32 => XtsKeys::Aes128 {
+                data: Box::new(Aes128::new_from_slice(data).unwrap()),
+                tweak: Box::new(Aes128::new_from_slice(tweak).unwrap()),
+            },
+            64 => XtsKeys::Aes256 {
+                data: Box::new(Aes256::new_from_slice(data).unwrap()),
+                tweak: Box::new(Aes256::new_from_slice(tweak).unwrap()),
+            },
+            len => return Err(Error::InvalidXtsKeyLength(len)),
+        };
+        Ok(XtsCipher { keys })
+    }
+
+    fn crypt(&self, first_sector: u64, data: &mut [u8], encrypt: bool) {
+        for (i, sector) in data.chunks_exact_mut(SECTOR_SIZE as usize).enumerate() {
+            let sector_num = first_sector.wrapping_add(i as u64);
+            match &self.keys {
+                XtsKeys::Aes128 { data, tweak } => {
+                    xts_data_unit(&**data, &**tweak, sector_num, sector, encrypt)
+                }
+                XtsKeys::Aes256 { data, tweak } => {
+                    xts_data_unit(&**data, &**tweak, sector_num, sector, encrypt)
+                }
+            }
+        }
+    }
+
+    /// Encrypts whole sectors in place, numbering them from `first_sector`. Trailing bytes that
+    /// don't make up a full sector are left untouched.
+    pub fn encrypt(&self, first_sector: u64, data: &mut [u8]) {
+        self.crypt(first_sector, data, true)
+    }
+
+    /// Decrypts whole sectors in place, numbering them from `first_sector`. Trailing bytes that
+    /// don't make up a full sector are left untouched.
+    pub fn decrypt(&self, first_sector: u64, data: &mut [u8]) {
+        self.crypt(first_sector, data, false)
+    }
+
+    /// Fills `buf` with the decrypted data at `offset` of `file`, whose sectors are numbered by
+    /// their offset in `file`.
+    pub fn read_at<F: FileReadWriteAtVolatile + ?Sized>(
+        &self,
+        file: &mut F,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let (start, len) = sector_range(offset, buf.len());
+        let mut sectors = vec![0u8; len];
+        file.read_exact_at_volatile(VolatileSlice::new(&mut sectors), start)?;
+        self.decrypt(start / SECTOR_SIZE, &mut sectors);
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&sectors[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    /// Encrypts `data` and writes it at `offset` of `file`, whose sectors are numbered by their
+    /// offset in `file`. Partially written sectors are read and decrypted first.
+    pub fn write_at<F: FileReadWriteAtVolatile + ?Sized>(
+        &self,
+        file: &mut F,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (start, len) = sector_range(offset, data.len());
+        let mut sectors = vec![0u8; len];
+        if start != offset || len != data.len() {
+            file.read_exact_at_volatile(VolatileSlice::new(&mut sectors), start)?;
+            self.decrypt(start / SECTOR_SIZE, &mut sectors);
+        }
+        let skip = (offset - start) as usize;
+        sectors[skip..skip + data.len()].copy_from_slice(data);
+        self.encrypt(start / SECTOR_SIZE, &mut sectors);
+        file.write_all_at_volatile(VolatileSlice::new(&mut sectors), start)
+    }
+}
+
+// Returns the start and length of the whole sectors covering `len` bytes at `offset`.
+fn sector_range(offset: u64, len: usize) -> (u64, usize) {
+    let start = offset - offset % SECTOR_SIZE;
+    let end = (offset + len as u64 + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+    (start, (end - start) as usize)
+}
+
+/// A disk whose contents are encrypted with AES-XTS, using the sector number within the disk as
+/// the tweak. Accesses that aren't sector aligned read and rewrite the sectors they touch.
+#[derive(Debug)]
+pub struct EncryptedDisk {
+    inner: Box<dyn DiskFile>,
+    cipher: XtsCipher,
+}
+
+impl EncryptedDisk {
+    /// Wraps `inner`, whose contents are encrypted with the XTS key `key`.
+    pub fn new(inner: Box<dyn DiskFile>, key: &[u8]) -> Result<EncryptedDisk> {
+        Ok(EncryptedDisk {
+            inner,
+            cipher: XtsCipher::new(key)?,
+        })
+    }
+}
+
+impl DiskGetLen for EncryptedDisk {
+    fn get_len(&self) -> io::Result<u64> {
+        self.inner.get_len()
+    }
+}
+
+impl FileSetLen for EncryptedDisk {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+impl AsRawDescriptors for EncryptedDisk {
+    fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        self.inner.as_raw_descriptors()
+    }
+}
+
+impl FileReadWriteAtVolatile for EncryptedDisk {
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let mut buf = vec![0u8; slice.size()];
+        self.cipher.read_at(&mut *self.inner, offset, &mut buf)?;
+        slice.copy_from(&buf);
+        Ok(buf.len())
+    }
+
+    fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let mut buf = vec![0u8; slice.size()];
+        slice.copy_to(&mut buf);
+        self.cipher.write_at(&mut *self.inner, offset, &buf)?;
+        Ok(buf.len())
+    }
+}
+
+impl DiskFile for EncryptedDisk {
+    fn try_clone(&self) -> io::Result<Box<dyn DiskFile>> {
+        Ok(Box::new(EncryptedDisk {
+            inner: self.inner.try_clone()?,
+            cipher: self.cipher.clone(),
+        }))
+    }
+}
+
+impl ToAsyncDisk for EncryptedDisk {
+    fn to_async_disk(self: Box<Self>, ex: &Executor) -> DiskResult<Box<dyn AsyncDisk>> {
+        Ok(Box::new(AsyncEncryptedDisk {
+            inner: self.inner.to_async_disk(ex)?,
+            cipher: self.cipher,
+        }))
+    }
+}
+
+/// An `EncryptedDisk` that implements `AsyncDisk` for access. Data is encrypted and decrypted in
+/// bounce buffers.
+pub struct AsyncEncryptedDisk {
+    inner: Box<dyn AsyncDisk>,
+    cipher: XtsCipher,
+}
+
+impl AsyncEncryptedDisk {
+    // Reads and decrypts the whole sectors covering `len` bytes at `offset`. Returns the sectors
+    // along with the position of `offset` in them.
+    async fn read_sectors(&self, offset: u64, len: usize) -> DiskResult<(Vec<u8>, usize)> {
+        let (start, sectors_len) = sector_range(offset, len);
+        let mut sectors = vec![0u8; sectors_len];
+        let mut count = 0;
+        while count < sectors.len() {
+            let n = self
+                .inner
+                .read_double_buffered(start + count as u64, &mut sectors[count..])
+                .await?;
+            if n == 0 {
+                return Err(DiskError::ReadingData(io::Error::from(
+                    io::ErrorKind::UnexpectedEof,
+                )));
+            }
+            count += n;
+        }
+        self.cipher.decrypt(start / SECTOR_SIZE, &mut sectors);
+        Ok((sectors, (offset - start) as usize))
+    }
+
+    // Encrypts and writes `data` at `offset`, rewriting partially covered sectors.
+    async fn write_sectors(&self, offset: u64, data: &[u8]) -> DiskResult<()> {
+        let (start, sectors_len) = sector_range(offset, data.len());
+        let (mut sectors, skip) = if start != offset || sectors_len != data.len() {
+            self.read_sectors(offset, data.len()).await?
+        } else {
+            (vec![0u8; sectors_len], 0)
+        };
+        sectors[skip..skip + data.len()].copy_from_slice(data);
+        self.cipher.encrypt(start / SECTOR_SIZE, &mut sectors);
+        let mut count = 0;
+        while count < sectors.len() {
+            let n = self
+                .inner
+                .write_double_buffered(start + count as u64, &sectors[count..])
+                .await?;
+            if n == 0 {
+                return Err(DiskError::WritingData(io::Error::from(
+                    io::ErrorKind::WriteZero,
+                )));
+            }
+            count += n;
+        }
+        Ok(())
+    }
+}
+
+impl DiskGetLen for AsyncEncryptedDisk {
+    fn get_len(&self) -> io::Result<u64> {
+        self.inner.get_len()
+    }
+}
+
+impl FileSetLen for AsyncEncryptedDisk {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+impl FileAllocate for AsyncEncryptedDisk {
+    fn allocate(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.inner.allocate(offset, length)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncDisk for AsyncEncryptedDisk {
+    fn into_inner(self: Box<Self>) -> Box<dyn DiskFile> {
+        Box::new(EncryptedDisk {
+            inner: self.inner.into_inner(),
+            cipher: self.cipher,
+        })
+    }
+
+    async fn flush(&self) -> DiskResult<()> {
+        self.inner.flush().await
+    }
+
+    async fn fsync(&self) -> DiskResult<()> {
+        self.inner.fsync().await
+    }
+
+    async fn fdatasync(&self) -> DiskResult<()> {
+        self.inner.fdatasync().await
+    }
+
+    async fn read_to_mem<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: cros_async::MemRegionIter<'a>,
+    ) -> DiskResult<usize> {
+        let regions: Vec<_> = mem_offsets.collect();
+        let len = regions.iter().map(|r| r.len).sum();
+        let (sectors, mut pos) = self.read_sectors(file_offset, len).await?;
+        for region in regions {
+            mem.get_volatile_slice(region)
+                .map_err(DiskError::GuestMemory)?
+                .copy_from(&sectors[pos..pos + region.len]);
+            pos += region.len;
+        }
+        Ok(len)
+    }
+
+    async fn write_from_mem<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: cros_async::MemRegionIter<'a>,
+    ) -> DiskResult<usize> {
+        let mut data = Vec::new();
+        for region in mem_offsets {
+            let start = data.len();
+            data.resize(start + region.len, 0);
+            mem.get_volatile_slice(region)
+                .map_err(DiskError::GuestMemory)?
+                .copy_to(&mut data[start..]);
+        }
+        self.write_sectors(file_offset, &data).await?;
+        Ok(data.len())
+    }
+
+    // A hole in the underlying disk would decrypt to garbage, so write encrypted zeroes instead.
+    async fn punch_hole(&self, file_offset: u64, length: u64) -> DiskResult<()> {
+        self.write_zeroes_at(file_offset, length).await
+    }
+
+    async fn write_zeroes_at(&self, file_offset: u64, length: u64) -> DiskResult<()> {
+        let mut done = 0;
+        while done < length {
+            let chunk = min(length - done, MAX_ZEROES_CHUNK);
+            self.write_sectors(file_offset + done, &vec![0u8; chunk as usize])
+                .await?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cros_async::MemRegion;
+    use cros_async::MemRegionIter;
+    use vm_memory::GuestAddress;
+    use vm_memory::GuestMemory;
+
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Vectors 1 and 2 of IEEE 1619-2007, which use 32 byte data units.
+    #[test]
+    fn xts_aes128_vectors() {
+        let aes = |k: u8| Aes128::new_from_slice(&[k; 16]).unwrap();
+
+        let mut data = [0u8; 32];
+        xts_data_unit(&aes(0), &aes(0), 0, &mut data, true);
+        assert_eq!(
+            data.to_vec(),
+            from_hex("917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e")
+        );
+        xts_data_unit(&aes(0), &aes(0), 0, &mut data, false);
+        assert_eq!(data, [0u8; 32]);
+
+        let mut data = [0x44u8; 32];
+        xts_data_unit(&aes(0x11), &aes(0x22), 0x3333333333, &mut data, true);
+        assert_eq!(
+            data.to_vec(),
+            from_hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0")
+        );
+    }
+
+    #[test]
+    fn invalid_key_length() {
+        assert!(matches!(
+            XtsCipher::new(&[0u8; 48]),
+            Err(Error::InvalidXtsKeyLength(48))
+        ));
+    }
+
+    #[test]
+    fn sectors_use_their_own_tweak() {
+        let cipher = XtsCipher::new(&[7u8; 64]).unwrap();
+        let mut data = vec![0u8; 1024];
+        cipher.encrypt(10, &mut data);
+        assert_ne!(data[..512], data[512..]);
+        let mut second = vec![0u8; 512];
+        cipher.encrypt(11, &mut second);
+        assert_eq!(data[512..], second[..]);
+        cipher.decrypt(10, &mut data);
+        assert_eq!(data, vec![0u8; 1024]);
+    }
+
+    fn test_disk() -> (EncryptedDisk, Box<dyn DiskFile>) {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(4096).unwrap();
+        let raw: Box<dyn DiskFile> = Box::new(file);
+        let disk = EncryptedDisk::new(raw.try_clone().unwrap(), &[3u8; 32]).unwrap();
+        (disk, raw)
+    }
+
+    #[test]
+    fn unaligned_write_read() {
+        let (mut disk, mut raw) = test_disk();
+        // Sectors that were never written decrypt to arbitrary data.
+        let mut before = vec![0u8; 300];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut before), 0)
+            .unwrap();
+        let mut data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        disk.write_all_at_volatile(VolatileSlice::new(&mut data), 300)
+            .unwrap();
+
+        let mut buf = vec![0u8; 1000];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 300)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        // The data around the write was preserved.
+        let mut buf = vec![0xffu8; 300];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        assert_eq!(buf, before);
+
+        // The raw disk holds the data encrypted.
+        let mut buf = vec![0u8; 1000];
+        raw.read_exact_at_volatile(VolatileSlice::new(&mut buf), 300)
+            .unwrap();
+        assert_ne!(buf, data);
+    }
+
+    #[cfg(feature = "qcow")]
+    #[test]
+    fn unencrypted_qcow2_with_key() {
+        use std::path::Path;
+
+        use crate::QcowFile;
+
+        let file = tempfile::tempfile().unwrap();
+        QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+        let mut disk = crate::create_encrypted_disk_file(
+            file.try_clone().unwrap(),
+            false,
+            2,
+            Path::new("disk.qcow2"),
+            &[5u8; 32],
+        )
+        .unwrap();
+        let mut data = vec![0x55u8; 512];
+        disk.write_all_at_volatile(VolatileSlice::new(&mut data), 4096)
+            .unwrap();
+        drop(disk);
+
+        // The qcow2 image holds the data encrypted with the key.
+        let mut qcow = QcowFile::from(file, 1).unwrap();
+        let mut buf = vec![0u8; 512];
+        qcow.read_exact_at_volatile(VolatileSlice::new(&mut buf), 4096)
+            .unwrap();
+        assert_ne!(buf, data);
+        XtsCipher::new(&[5u8; 32]).unwrap().decrypt(8, &mut buf);
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn async_write_read() {
+        let (disk, _raw) = test_disk();
+        let ex = Executor::new().unwrap();
+        ex.run_until(async {
+            let disk = Box::new(disk).to_async_disk(&ex).unwrap();
+            let guest_mem = Arc::new(GuestMemory::new(&[(GuestAddress(0), 4096)]).unwrap());
+            let data: Vec<u8> = (0..100).collect();
+            guest_mem.write_all_at_addr(&data, GuestAddress(0)).unwrap();
+            let regions = [
+                MemRegion { offset: 0, len: 40 },
+                MemRegion {
+                    offset: 40,
+                    len: 60,
+                },
+            ];
+            disk.write_from_mem(1000, guest_mem.clone(), MemRegionIter::new(&regions))
+                .await
+                .unwrap();
+            disk.write_zeroes_at(1020, 10).await.unwrap();
+
+            let count = disk
+                .read_to_mem(
+                    1000,
+                    guest_mem.clone(),
+                    MemRegionIter::new(&[MemRegion {
+                        offset: 1024,
+                        len: 100,
+                    }]),
+                )
+                .await
+                .unwrap();
+            assert_eq!(count, 100);
+            let mut buf = vec![0u8; 100];
+            guest_mem
+                .read_at_addr(&mut buf, GuestAddress(1024))
+                .unwrap();
+            let mut expected = data.clone();
+            expected[20..30].fill(0);
+            assert_eq!(buf, expected);
+
+            // The synchronous disk sees the same contents.
+            let mut disk = disk.into_inner();
+            let mut buf = vec![0u8; 100];
+            disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 1000)
+                .unwrap();
+            assert_eq!(buf, expected);
+        })
+        .unwrap();
+    }
+}
@@ -32,8 +32,14 @@ pub(crate) use asynchronous::AsyncDiskFileWrapper;
 #[cfg(feature = "qcow")]
 mod qcow;
 #[cfg(feature = "qcow")]
+pub use qcow::backing_file_full_path;
+#[cfg(feature = "qcow")]
+pub use qcow::QcowCheckResult;
+#[cfg(feature = "qcow")]
 pub use qcow::QcowFile;
 #[cfg(feature = "qcow")]
+pub use qcow::RefcountMismatch;
+#[cfg(feature = "qcow")]
 pub use qcow::QCOW_MAGIC;
 mod sys;
 
@@ -66,6 +72,31 @@ use android_sparse::AndroidSparse;
 use android_sparse::SPARSE_HEADER_MAGIC;
 use sys::read_from_disk;
 
+#[cfg(any(feature = "vhdx", feature = "vmdk"))]
+mod block_map;
+#[cfg(feature = "encryption")]
+mod crypt;
+#[cfg(feature = "encryption")]
+pub use crypt::EncryptedDisk;
+#[cfg(feature = "encryption")]
+pub use crypt::Error as CryptError;
+#[cfg(all(feature = "nbd", any(target_os = "android", target_os = "linux")))]
+mod nbd;
+#[cfg(all(feature = "nbd", any(target_os = "android", target_os = "linux")))]
+pub use nbd::Error as NbdError;
+#[cfg(all(feature = "nbd", any(target_os = "android", target_os = "linux")))]
+pub use nbd::NbdDisk;
+#[cfg(all(feature = "nbd", any(target_os = "android", target_os = "linux")))]
+pub use nbd::NbdUri;
+#[cfg(feature = "vhdx")]
+mod vhdx;
+#[cfg(feature = "vhdx")]
+use vhdx::VHDX_SIGNATURE;
+#[cfg(feature = "vmdk")]
+mod vmdk;
+#[cfg(feature = "vmdk")]
+use vmdk::VMDK_MAGIC;
+
 /// Nesting depth limit for disk formats that can open other disk files.
 pub const MAX_NESTING_DEPTH: u32 = 10;
 
@@ -81,8 +112,22 @@ pub enum Error {
     #[cfg(feature = "composite-disk")]
     #[error("failure in composite disk: {0}")]
     CreateCompositeDisk(composite::Error),
+    #[cfg(feature = "encryption")]
+    #[error("failure in encrypted disk: {0}")]
+    CreateEncryptedDisk(crypt::Error),
+    #[cfg(all(feature = "nbd", any(target_os = "android", target_os = "linux")))]
+    #[error("failure in nbd disk: {0}")]
+    CreateNbdDisk(nbd::Error),
     #[error("failure creating single file disk: {0}")]
     CreateSingleFileDisk(cros_async::AsyncError),
+    #[cfg(feature = "vhdx")]
+    #[error("failure in vhdx disk: {0}")]
+    CreateVhdxDisk(vhdx::Error),
+    #[cfg(feature = "vmdk")]
+    #[error("failure in vmdk disk: {0}")]
+    CreateVmdkDisk(vmdk::Error),
+    #[error("disk encryption support is not enabled")]
+    EncryptionNotSupported,
     #[error("failure with fdatasync: {0}")]
     Fdatasync(cros_async::AsyncError),
     #[error("failure with fsync: {0}")]
@@ -99,6 +144,8 @@ pub enum Error {
     HostFsType(base::Error),
     #[error("maximum disk nesting depth exceeded")]
     MaxNestingDepthExceeded,
+    #[error("nbd disk support is not enabled")]
+    NbdNotSupported,
     #[error("failure to punch hole: {0}")]
     PunchHole(cros_async::AsyncError),
     #[error("failure to punch hole for block device file: {0}")]
@@ -196,6 +243,8 @@ pub enum ImageType {
     Qcow2,
     CompositeDisk,
     AndroidSparse,
+    Vhdx,
+    Vmdk,
 }
 
 fn log_host_fs_type(file: &File) -> Result<()> {
@@ -240,7 +289,15 @@ pub fn detect_image_type(file: &File, overlapped_mode: bool) -> Result<ImageType
         }
     }
 
-    #[allow(unused_variables)] // magic4 is only used with the qcow or android-sparse features.
+    #[cfg(feature = "vhdx")]
+    if let Some(vhdx_magic) = magic.data.get(0..VHDX_SIGNATURE.len()) {
+        if vhdx_magic == VHDX_SIGNATURE {
+            return Ok(ImageType::Vhdx);
+        }
+    }
+
+    #[allow(unused_variables)]
+    // magic4 is only used with the qcow, android-sparse or vmdk features.
     if let Some(magic4) = magic.data.get(0..4) {
         #[cfg(feature = "qcow")]
         if magic4 == QCOW_MAGIC.to_be_bytes() {
@@ -250,6 +307,10 @@ pub fn detect_image_type(file: &File, overlapped_mode: bool) -> Result<ImageType
         if magic4 == SPARSE_HEADER_MAGIC.to_le_bytes() {
             return Ok(ImageType::AndroidSparse);
         }
+        #[cfg(feature = "vmdk")]
+        if magic4 == VMDK_MAGIC.to_le_bytes() {
+            return Ok(ImageType::Vmdk);
+        }
     }
 
     Ok(ImageType::Raw)
@@ -302,10 +363,10 @@ pub fn create_disk_file_of_type(
             Box::new(raw_image) as Box<dyn DiskFile>
         }
         #[cfg(feature = "qcow")]
-        ImageType::Qcow2 => {
-            Box::new(QcowFile::from(raw_image, max_nesting_depth).map_err(Error::QcowError)?)
-                as Box<dyn DiskFile>
-        }
+        ImageType::Qcow2 => Box::new(
+            QcowFile::from_path(raw_image, image_path, max_nesting_depth)
+                .map_err(Error::QcowError)?,
+        ) as Box<dyn DiskFile>,
         #[cfg(feature = "composite-disk")]
         ImageType::CompositeDisk => {
             // Valid composite disk header present
@@ -324,11 +385,100 @@ pub fn create_disk_file_of_type(
             Box::new(AndroidSparse::from_file(raw_image).map_err(Error::CreateAndroidSparseDisk)?)
                 as Box<dyn DiskFile>
         }
+        #[cfg(feature = "vhdx")]
+        ImageType::Vhdx => {
+            Box::new(vhdx::open(raw_image).map_err(Error::CreateVhdxDisk)?) as Box<dyn DiskFile>
+        }
+        #[cfg(feature = "vmdk")]
+        ImageType::Vmdk => {
+            Box::new(vmdk::open(raw_image).map_err(Error::CreateVmdkDisk)?) as Box<dyn DiskFile>
+        }
         #[allow(unreachable_patterns)]
         _ => return Err(Error::UnknownType),
     })
 }
 
+/// Inspect the image file type and create a disk file that decrypts it with `key`.
+///
+/// qcow2 images using LUKS encryption are unlocked with `key` as the passphrase. Any other image,
+/// including an unencrypted qcow2 image, is treated as encrypted with AES-XTS, using `key` as the
+/// 32 or 64 byte XTS key.
+pub fn create_encrypted_disk_file(
+    raw_image: File,
+    is_sparse_file: bool,
+    max_nesting_depth: u32,
+    image_path: &Path,
+    key: &[u8],
+) -> Result<Box<dyn DiskFile>> {
+    #[cfg(feature = "encryption")]
+    {
+        let image_type = detect_image_type(&raw_image, false)?;
+        let disk = match image_type {
+            #[cfg(feature = "qcow")]
+            ImageType::Qcow2 => {
+                if max_nesting_depth == 0 {
+                    return Err(Error::MaxNestingDepthExceeded);
+                }
+                let qcow = QcowFile::from_path_with_key(
+                    raw_image,
+                    image_path,
+                    max_nesting_depth - 1,
+                    Some(key),
+                )
+                .map_err(Error::QcowError)?;
+                if qcow.is_encrypted() {
+                    return Ok(Box::new(qcow));
+                }
+                Box::new(qcow)
+            }
+            _ => create_disk_file_of_type(
+                raw_image,
+                is_sparse_file,
+                max_nesting_depth,
+                image_path,
+                image_type,
+            )?,
+        };
+        Ok(Box::new(
+            EncryptedDisk::new(disk, key).map_err(Error::CreateEncryptedDisk)?,
+        ))
+    }
+    #[cfg(not(feature = "encryption"))]
+    {
+        let _ = (
+            raw_image,
+            is_sparse_file,
+            max_nesting_depth,
+            image_path,
+            key,
+        );
+        Err(Error::EncryptionNotSupported)
+    }
+}
+
+/// Returns true if `path` names a disk exported by an NBD server, such as
+/// `nbd://HOST[:PORT][/EXPORT]` or `nbd+unix:///[EXPORT]?socket=PATH`, rather than a file.
+pub fn is_nbd_uri(path: &str) -> bool {
+    ["nbd://", "nbd+tcp://", "nbd+unix://"]
+        .iter()
+        .any(|scheme| path.starts_with(scheme))
+}
+
+/// Connect to the NBD export at `uri` and create a disk file for it.
+pub fn create_nbd_disk_file(uri: &str, read_only: bool) -> Result<Box<dyn DiskFile>> {
+    #[cfg(all(feature = "nbd", any(target_os = "android", target_os = "linux")))]
+    {
+        Ok(Box::new(
+            NbdDisk::new(uri, read_only).map_err(Error::CreateNbdDisk)?,
+        ))
+    }
+    #[cfg(not(all(feature = "nbd", any(target_os = "android", target_os = "linux"))))]
+    {
+        let _ = (uri, read_only);
+        Err(Error::NbdNotSupported)
+    }
+}
+
 /// An asynchronously accessible disk.
 #[async_trait(?Send)]
 pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
@@ -409,6 +559,54 @@ pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
         )
         .await
     }
+
+    /// Lists the internal snapshots stored in the disk image.
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Creates an internal snapshot named `name` of the current disk contents.
+    async fn create_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Reverts the disk contents to the internal snapshot `name`.
+    async fn apply_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Deletes the internal snapshot `name`.
+    async fn delete_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Merges the data of the disk image into its backing file. `backing_file` is a writable
+    /// handle to the backing file.
+    async fn commit(&self, _backing_file: File) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Copies the data of the backing file into the disk image and drops the backing file.
+    async fn stream(&self) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+}
+
+/// Describes an internal snapshot stored in a disk image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// Identifier of the snapshot, unique within the image.
+    pub id: String,
+    /// Name given to the snapshot when it was created.
+    pub name: String,
+    /// Creation time in seconds since the epoch.
+    pub date_sec: u32,
+    /// Nanoseconds part of the creation time.
+    pub date_nsec: u32,
+    /// Size of the VM state saved along with the disk contents, zero for disk-only snapshots.
+    pub vm_state_size: u64,
+    /// Size of the virtual disk when the snapshot was taken, if recorded.
+    pub disk_size: Option<u64>,
 }
 
 /// A disk backed by a single file that implements `AsyncDisk` for access.
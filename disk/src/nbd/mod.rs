@@ -0,0 +1,1398 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Client for disks exported by a Network Block Device server, such as `nbd-server` or `qemu-nbd`.
+//!
+//! Exports are located with the URIs used by qemu: `nbd://HOST[:PORT][/EXPORT]` for TCP and
+//! `nbd+unix:///[EXPORT]?socket=PATH` for unix sockets. Requests are sent one at a time. When the
+//! connection drops, the client reconnects and retries the request a few times before failing it.
+//! Reconnections to a TCP server go to the address the first connection was made to, so that they
+//! don't need name resolution.
+
+mod protocol;
+
+use std::cmp::min;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base::clear_fd_flags;
+use base::warn;
+use base::AsRawDescriptor;
+use base::AsRawDescriptors;
+use base::FileAllocate;
+use base::FileReadWriteAtVolatile;
+use base::FileSetLen;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use cros_async::sync::RwLock as AsyncRwLock;
+use cros_async::AsyncWrapper;
+use cros_async::BackingMemory;
+use cros_async::Executor;
+use cros_async::IoSource;
+use cros_async::MemRegionIter;
+use cros_async::TimerAsync;
+use protocol::Export;
+use protocol::ReplyError;
+use protocol::ReplyReader;
+use protocol::Request;
+use protocol::CMD_DISC;
+use protocol::CMD_FLUSH;
+use protocol::CMD_READ;
+use protocol::CMD_TRIM;
+use protocol::CMD_WRITE;
+use protocol::CMD_WRITE_ZEROES;
+use protocol::FLAG_READ_ONLY;
+use protocol::FLAG_SEND_FLUSH;
+use protocol::FLAG_SEND_TRIM;
+use protocol::FLAG_SEND_WRITE_ZEROES;
+use remain::sorted;
+use thiserror::Error;
+
+use crate::AsyncDisk;
+use crate::DiskFile;
+use crate::DiskGetLen;
+use crate::Error as DiskError;
+use crate::Result as DiskResult;
+use crate::ToAsyncDisk;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to connect to the NBD server: {0}")]
+    Connect(io::Error),
+    #[error("the export size changed from {0} to {1} bytes")]
+    ExportChanged(u64, u64),
+    #[error("the NBD server rejected the export: {0}")]
+    ExportRejected(String),
+    #[error("NBD handshake failed: {0}")]
+    Handshake(io::Error),
+    #[error("invalid NBD URI {0}")]
+    InvalidUri(String),
+    #[error("NBD protocol error: {0}")]
+    Protocol(&'static str),
+    #[error("the export is read-only")]
+    ReadOnlyExport,
+    #[error("unsupported NBD server: {0}")]
+    UnsupportedServer(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const DEFAULT_PORT: u16 = 10809;
+
+// Time allowed for connecting to the server and completing the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Number of times a request is retried on a new connection, and the delay before the first retry.
+// The delay doubles after every attempt.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+// Largest request sent to the server. Servers must accept at least 32 MiB of data per request.
+const MAX_REQUEST_SIZE: u64 = 32 << 20;
+// Largest trim or write zeroes request, which don't carry any data.
+const MAX_ZEROES_REQUEST_SIZE: u64 = 1 << 30;
+// Size of the buffers written when the server doesn't support write zeroes requests.
+const ZEROES_BUFFER_SIZE: u64 = 1 << 20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Address {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+}
+
+/// Location of an NBD export.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NbdUri {
+    address: Address,
+    export: String,
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl NbdUri {
+    /// Parses an `nbd://`, `nbd+tcp://` or `nbd+unix://` URI.
+    pub fn parse(uri: &str) -> Result<NbdUri> {
+        let invalid = || Error::InvalidUri(uri.to_string());
+        let (scheme, rest) = uri.split_once("://").ok_or_else(invalid)?;
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+        let (authority, export) = match rest.split_once('/') {
+            Some((authority, export)) => (authority, percent_decode(export).ok_or_else(invalid)?),
+            None => (rest, String::new()),
+        };
+        let address = match scheme {
+            "nbd" | "nbd+tcp" => {
+                if query.is_some() {
+                    return Err(invalid());
+                }
+                let (host, port) = match authority.strip_prefix('[') {
+                    // IPv6 addresses are enclosed in brackets.
+                    Some(rest) => {
+                        let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+                        match port {
+                            "" => (host, None),
+                            port => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+                        }
+                    }
+                    None => match authority.split_once(':') {
+                        Some((host, port)) => (host, Some(port)),
+                        None => (authority, None),
+                    },
+                };
+                if host.is_empty() {
+                    return Err(invalid());
+                }
+                let port = match port {
+                    Some(port) => port.parse().map_err(|_| invalid())?,
+                    None => DEFAULT_PORT,
+                };
+                Address::Tcp {
+                    host: host.to_string(),
+                    port,
+                }
+            }
+            "nbd+unix" => {
+                if !authority.is_empty() {
+                    return Err(invalid());
+                }
+                let socket = query
+                    .into_iter()
+                    .flat_map(|query| query.split('&'))
+                    .find_map(|param| param.strip_prefix("socket="))
+                    .and_then(percent_decode)
+                    .filter(|socket| !socket.is_empty())
+                    .ok_or_else(invalid)?;
+                Address::Unix(PathBuf::from(socket))
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(NbdUri { address, export })
+    }
+
+    /// Returns the path of the server socket for `nbd+unix://` URIs.
+    pub fn socket_path(&self) -> Option<&Path> {
+        match &self.address {
+            Address::Tcp { .. } => None,
+            Address::Unix(path) => Some(path),
+        }
+    }
+
+    // Connects to the server, at `peer` if it is the known address of a TCP server.
+    fn connect(&self, peer: Option<SocketAddr>) -> io::Result<Stream> {
+        match &self.address {
+            Address::Tcp { host, port } => {
+                let stream = match peer {
+                    Some(peer) => TcpStream::connect(peer)?,
+                    None => TcpStream::connect((host.as_str(), *port))?,
+                };
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl fmt::Display for NbdUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.address {
+            Address::Tcp { host, port } if host.contains(':') => {
+                write!(f, "nbd://[{}]:{}/{}", host, port, self.export)
+            }
+            Address::Tcp { host, port } => write!(f, "nbd://{}:{}/{}", host, port, self.export),
+            Address::Unix(path) => {
+                write!(f, "nbd+unix:///{}?socket={}", self.export, path.display())
+            }
+        }
+    }
+}
+
+/// Connection to the server.
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    // Returns the address of a TCP server.
+    fn tcp_peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(s) => s.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => {
+                s.set_read_timeout(timeout)?;
+                s.set_write_timeout(timeout)
+            }
+            Stream::Unix(s) => {
+                s.set_read_timeout(timeout)?;
+                s.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    // Tells the server that the client is going away. Errors are ignored since the connection is
+    // closed right after.
+    fn disconnect(&mut self, handle: u64) {
+        let request = Request {
+            command: CMD_DISC,
+            handle,
+            offset: 0,
+            length: 0,
+        };
+        let _ = self.write_all(&request.to_bytes());
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawDescriptor for Stream {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match self {
+            Stream::Tcp(s) => s.as_raw_descriptor(),
+            Stream::Unix(s) => s.as_raw_descriptor(),
+        }
+    }
+}
+
+/// A connection in the transmission phase. `S` is the blocking `Stream`, or the `IoSource` it
+/// was converted to for async access.
+#[derive(Debug)]
+struct Session<S> {
+    stream: S,
+    next_handle: u64,
+}
+
+impl<S> Session<S> {
+    fn request(&mut self, command: u16, offset: u64, length: u32) -> Request {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        Request {
+            command,
+            handle,
+            offset,
+            length,
+        }
+    }
+}
+
+// Connects to the export at `uri`, through `peer` if the server address is known. `expected` is
+// the export of the first connection, which a new connection has to match.
+fn open_session(
+    uri: &NbdUri,
+    peer: Option<SocketAddr>,
+    read_only: bool,
+    expected: Option<Export>,
+) -> Result<(Session<Stream>, Export)> {
+    let mut stream = uri.connect(peer).map_err(Error::Connect)?;
+    stream
+        .set_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(Error::Connect)?;
+    let export = protocol::handshake(&mut stream, &uri.export)?;
+    stream.set_timeout(None).map_err(Error::Connect)?;
+    if !read_only && export.has_flag(FLAG_READ_ONLY) {
+        return Err(Error::ReadOnlyExport);
+    }
+    if let Some(expected) = expected {
+        if export.size != expected.size {
+            return Err(Error::ExportChanged(expected.size, export.size));
+        }
+    }
+    Ok((
+        Session {
+            stream,
+            next_handle: 0,
+        },
+        export,
+    ))
+}
+
+/// Why a request failed.
+enum RequestError {
+    /// The server failed the request. The connection can still be used.
+    Server(io::Error),
+    /// The connection failed. The request can be retried on a new connection.
+    Connection(io::Error),
+}
+
+impl From<ReplyError> for RequestError {
+    fn from(e: ReplyError) -> RequestError {
+        match e {
+            ReplyError::Server(error) => RequestError::Server(protocol::server_error(error)),
+            ReplyError::Protocol(msg) => {
+                RequestError::Connection(io::Error::new(io::ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+}
+
+impl Session<Stream> {
+    // Sends `request` with the `data` of a write request and waits for the reply, which fills
+    // `buf` for a read request.
+    fn send(
+        &mut self,
+        request: Request,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> std::result::Result<(), RequestError> {
+        let mut message = request.to_bytes().to_vec();
+        message.extend_from_slice(data);
+        self.stream
+            .write_all(&message)
+            .map_err(RequestError::Connection)?;
+        let mut reader = ReplyReader::new(request, buf);
+        while reader.bytes_needed() > 0 {
+            let mut bytes = vec![0u8; reader.bytes_needed()];
+            self.stream
+                .read_exact(&mut bytes)
+                .map_err(RequestError::Connection)?;
+            reader.consume(&bytes)?;
+        }
+        reader.finish().map_err(RequestError::from)
+    }
+}
+
+async fn read_exact(source: &IoSource<AsyncWrapper<Stream>>, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(len);
+    while bytes.len() < len {
+        let (count, buf) = source
+            .read_to_vec(None, vec![0u8; len - bytes.len()])
+            .await?;
+        if count == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        bytes.extend_from_slice(&buf[..count]);
+    }
+    Ok(bytes)
+}
+
+async fn write_all(source: &IoSource<AsyncWrapper<Stream>>, mut data: Vec<u8>) -> io::Result<()> {
+    while !data.is_empty() {
+        let (count, mut buf) = source.write_from_vec(None, data).await?;
+        if count == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        buf.drain(..count);
+        data = buf;
+    }
+    Ok(())
+}
+
+impl Session<IoSource<AsyncWrapper<Stream>>> {
+    async fn send(
+        &mut self,
+        request: Request,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> std::result::Result<(), RequestError> {
+        let mut message = request.to_bytes().to_vec();
+        message.extend_from_slice(data);
+        write_all(&self.stream, message)
+            .await
+            .map_err(RequestError::Connection)?;
+        let mut reader = ReplyReader::new(request, buf);
+        while reader.bytes_needed() > 0 {
+            let bytes = read_exact(&self.stream, reader.bytes_needed())
+                .await
+                .map_err(RequestError::Connection)?;
+            reader.consume(&bytes)?;
+        }
+        reader.finish().map_err(RequestError::from)
+    }
+}
+
+// Returns how long to wait before the retry following `attempt` failed attempts, or `None` if the
+// request should fail.
+fn reconnect_delay(attempt: u32) -> Option<Duration> {
+    if attempt < RECONNECT_ATTEMPTS {
+        Some(RECONNECT_DELAY * (1 << attempt))
+    } else {
+        None
+    }
+}
+
+fn to_io_error(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// A disk exported by an NBD server.
+#[derive(Debug)]
+pub struct NbdDisk {
+    uri: NbdUri,
+    // Address of a TCP server, resolved by the first connection.
+    peer: Option<SocketAddr>,
+    read_only: bool,
+    export: Export,
+    // `None` after the connection failed, until the next request reconnects.
+    session: Option<Session<Stream>>,
+}
+
+impl NbdDisk {
+    /// Connects to the export at `uri`, which is parsed by `NbdUri::parse`. Fails if the export is
+    /// read-only and `read_only` is false.
+    pub fn new(uri: &str, read_only: bool) -> Result<NbdDisk> {
+        let uri = NbdUri::parse(uri)?;
+        let (session, export) = open_session(&uri, None, read_only, None)?;
+        Ok(NbdDisk {
+            uri,
+            peer: session.stream.tcp_peer_addr(),
+            read_only,
+            export,
+            session: Some(session),
+        })
+    }
+
+    fn request(
+        &mut self,
+        command: u16,
+        offset: u64,
+        length: u32,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let mut attempt = 0;
+        loop {
+            if self.session.is_none() {
+                match open_session(&self.uri, self.peer, self.read_only, Some(self.export)) {
+                    Ok((session, _)) => self.session = Some(session),
+                    Err(e) => {
+                        let delay = reconnect_delay(attempt).ok_or_else(|| to_io_error(e))?;
+                        thread::sleep(delay);
+                        attempt += 1;
+                        continue;
+                    }
+                }
+            }
+            let session = self.session.as_mut().unwrap();
+            let request = session.request(command, offset, length);
+            match session.send(request, data, buf) {
+                Ok(()) => return Ok(()),
+                Err(RequestError::Server(e)) => return Err(e),
+                Err(RequestError::Connection(e)) => {
+                    warn!("connection to {} failed, reconnecting: {}", self.uri, e);
+                    self.session = None;
+                    let delay = reconnect_delay(attempt).ok_or(e)?;
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for NbdDisk {
+    fn drop(&mut self) {
+        if let Some(session) = &mut self.session {
+            let request = session.request(CMD_DISC, 0, 0);
+            session.stream.disconnect(request.handle);
+        }
+    }
+}
+
+impl DiskGetLen for NbdDisk {
+    fn get_len(&self) -> io::Result<u64> {
+        Ok(self.export.size)
+    }
+}
+
+impl FileSetLen for NbdDisk {
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "NBD exports can't be resized",
+        ))
+    }
+}
+
+impl AsRawDescriptors for NbdDisk {
+    fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        self.session
+            .iter()
+            .map(|session| session.stream.as_raw_descriptor())
+            .collect()
+    }
+}
+
+impl FileReadWriteAtVolatile for NbdDisk {
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if offset >= self.export.size {
+            return Ok(0);
+        }
+        let len = min(
+            slice.size() as u64,
+            min(self.export.size - offset, MAX_REQUEST_SIZE),
+        ) as usize;
+        let mut buf = vec![0u8; len];
+        self.request(CMD_READ, offset, len as u32, &[], &mut buf)?;
+        slice.copy_from(&buf);
+        Ok(len)
+    }
+
+    fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if offset >= self.export.size {
+            return Ok(0);
+        }
+        let len = min(
+            slice.size() as u64,
+            min(self.export.size - offset, MAX_REQUEST_SIZE),
+        ) as usize;
+        let mut data = vec![0u8; len];
+        slice.copy_to(&mut data);
+        self.request(CMD_WRITE, offset, len as u32, &data, &mut [])?;
+        Ok(len)
+    }
+}
+
+impl DiskFile for NbdDisk {}
+
+impl ToAsyncDisk for NbdDisk {
+    fn to_async_disk(mut self: Box<Self>, ex: &Executor) -> DiskResult<Box<dyn AsyncDisk>> {
+        let session = match self.session.take() {
+            Some(session) => Some(Session {
+                stream: ex
+                    .async_from(AsyncWrapper::new(session.stream))
+                    .map_err(DiskError::ToAsync)?,
+                next_handle: session.next_handle,
+            }),
+            None => None,
+        };
+        Ok(Box::new(AsyncNbdDisk {
+            uri: self.uri.clone(),
+            peer: self.peer,
+            read_only: self.read_only,
+            export: self.export,
+            ex: ex.clone(),
+            session: AsyncRwLock::new(session),
+        }))
+    }
+}
+
+/// An `NbdDisk` converted for async access.
+pub struct AsyncNbdDisk {
+    uri: NbdUri,
+    peer: Option<SocketAddr>,
+    read_only: bool,
+    export: Export,
+    ex: Executor,
+    session: AsyncRwLock<Option<Session<IoSource<AsyncWrapper<Stream>>>>>,
+}
+
+impl AsyncNbdDisk {
+    async fn request(
+        &self,
+        command: u16,
+        offset: u64,
+        length: u32,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        // Holding the lock for the whole request keeps the replies in order.
+        let mut session = self.session.lock().await;
+        let mut attempt = 0;
+        loop {
+            if session.is_none() {
+                let uri = self.uri.clone();
+                let peer = self.peer;
+                let read_only = self.read_only;
+                let export = self.export;
+                match self
+                    .ex
+                    .spawn_blocking(move || open_session(&uri, peer, read_only, Some(export)))
+                    .await
+                {
+                    Ok((new_session, _)) => {
+                        *session = Some(Session {
+                            stream: self.ex.async_from(AsyncWrapper::new(new_session.stream))?,
+                            next_handle: new_session.next_handle,
+                        })
+                    }
+                    Err(e) => {
+                        let delay = reconnect_delay(attempt).ok_or_else(|| to_io_error(e))?;
+                        TimerAsync::sleep(&self.ex, delay)
+                            .await
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                        attempt += 1;
+                        continue;
+                    }
+                }
+            }
+            let active = session.as_mut().unwrap();
+            let request = active.request(command, offset, length);
+            match active.send(request, data, buf).await {
+                Ok(()) => return Ok(()),
+                Err(RequestError::Server(e)) => return Err(e),
+                Err(RequestError::Connection(e)) => {
+                    warn!("connection to {} failed, reconnecting: {}", self.uri, e);
+                    *session = None;
+                    let delay = reconnect_delay(attempt).ok_or(e)?;
+                    TimerAsync::sleep(&self.ex, delay)
+                        .await
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Sends requests without data for the range, split in chunks the server accepts.
+    async fn zeroes_request(&self, command: u16, offset: u64, length: u64) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < length {
+            let len = min(length - pos, MAX_ZEROES_REQUEST_SIZE);
+            self.request(command, offset + pos, len as u32, &[], &mut [])
+                .await?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    async fn flush_export(&self) -> io::Result<()> {
+        if self.export.has_flag(FLAG_SEND_FLUSH) {
+            self.request(CMD_FLUSH, 0, 0, &[], &mut []).await
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for AsyncNbdDisk {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.get_mut() {
+            let request = session.request(CMD_DISC, 0, 0);
+            session.stream.as_source_mut().disconnect(request.handle);
+        }
+    }
+}
+
+impl DiskGetLen for AsyncNbdDisk {
+    fn get_len(&self) -> io::Result<u64> {
+        Ok(self.export.size)
+    }
+}
+
+impl FileSetLen for AsyncNbdDisk {
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "NBD exports can't be resized",
+        ))
+    }
+}
+
+impl FileAllocate for AsyncNbdDisk {
+    fn allocate(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "NBD exports can't be preallocated",
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncDisk for AsyncNbdDisk {
+    fn into_inner(mut self: Box<Self>) -> Box<dyn DiskFile> {
+        let session = self.session.get_mut().take().and_then(|session| {
+            let stream = session.stream.into_source().into_inner();
+            // The stream was made non-blocking for the executor.
+            match clear_fd_flags(stream.as_raw_descriptor(), libc::O_NONBLOCK) {
+                Ok(()) => Some(Session {
+                    stream,
+                    next_handle: session.next_handle,
+                }),
+                // Dropping the connection makes the next request reconnect.
+                Err(e) => {
+                    warn!("failed to make the NBD connection blocking: {}", e);
+                    None
+                }
+            }
+        });
+        Box::new(NbdDisk {
+            uri: self.uri.clone(),
+            peer: self.peer,
+            read_only: self.read_only,
+            export: self.export,
+            session,
+        })
+    }
+
+    async fn flush(&self) -> DiskResult<()> {
+        self.flush_export().await.map_err(DiskError::IoFlush)
+    }
+
+    async fn fsync(&self) -> DiskResult<()> {
+        self.flush_export().await.map_err(DiskError::IoFsync)
+    }
+
+    async fn fdatasync(&self) -> DiskResult<()> {
+        self.flush_export().await.map_err(DiskError::IoFdatasync)
+    }
+
+    async fn read_to_mem<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: MemRegionIter<'a>,
+    ) -> DiskResult<usize> {
+        let regions: Vec<_> = mem_offsets.collect();
+        let len: usize = regions.iter().map(|r| r.len).sum();
+        let mut data = vec![0u8; len];
+        for (i, chunk) in data.chunks_mut(MAX_REQUEST_SIZE as usize).enumerate() {
+            let offset = file_offset + (i as u64) * MAX_REQUEST_SIZE;
+            self.request(CMD_READ, offset, chunk.len() as u32, &[], chunk)
+                .await
+                .map_err(DiskError::ReadingData)?;
+        }
+        let mut pos = 0;
+        for region in regions {
+            mem.get_volatile_slice(region)
+                .map_err(DiskError::GuestMemory)?
+                .copy_from(&data[pos..pos + region.len]);
+            pos += region.len;
+        }
+        Ok(len)
+    }
+
+    async fn write_from_mem<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: MemRegionIter<'a>,
+    ) -> DiskResult<usize> {
+        let mut data = Vec::new();
+        for region in mem_offsets {
+            let start = data.len();
+            data.resize(start + region.len, 0);
+            mem.get_volatile_slice(region)
+                .map_err(DiskError::GuestMemory)?
+                .copy_to(&mut data[start..]);
+        }
+        for (i, chunk) in data.chunks(MAX_REQUEST_SIZE as usize).enumerate() {
+            let offset = file_offset + (i as u64) * MAX_REQUEST_SIZE;
+            self.request(CMD_WRITE, offset, chunk.len() as u32, chunk, &mut [])
+                .await
+                .map_err(DiskError::WritingData)?;
+        }
+        Ok(data.len())
+    }
+
+    // Trimming is only a hint, so it does nothing if the server doesn't support it.
+    async fn punch_hole(&self, file_offset: u64, length: u64) -> DiskResult<()> {
+        if self.export.has_flag(FLAG_SEND_TRIM) {
+            self.zeroes_request(CMD_TRIM, file_offset, length)
+                .await
+                .map_err(DiskError::IoPunchHole)?;
+        }
+        Ok(())
+    }
+
+    async fn write_zeroes_at(&self, file_offset: u64, length: u64) -> DiskResult<()> {
+        if self.export.has_flag(FLAG_SEND_WRITE_ZEROES) {
+            return self
+                .zeroes_request(CMD_WRITE_ZEROES, file_offset, length)
+                .await
+                .map_err(DiskError::WriteZeroes);
+        }
+        let zeroes = vec![0u8; min(length, ZEROES_BUFFER_SIZE) as usize];
+        let mut pos = 0;
+        while pos < length {
+            let len = min(length - pos, ZEROES_BUFFER_SIZE) as usize;
+            self.request(
+                CMD_WRITE,
+                file_offset + pos,
+                len as u32,
+                &zeroes[..len],
+                &mut [],
+            )
+            .await
+            .map_err(DiskError::WriteZeroes)?;
+            pos += len as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::sync::Mutex;
+
+    use cros_async::MemRegion;
+    use tempfile::TempDir;
+    use vm_memory::GuestAddress;
+    use vm_memory::GuestMemory;
+
+    use super::protocol::*;
+    use super::*;
+
+    const EXPORT_NAME: &str = "golden";
+    const REP_ERR_UNSUP: u32 = REP_FLAG_ERROR | 1;
+    const REP_ERR_UNKNOWN: u32 = REP_FLAG_ERROR | 6;
+
+    #[derive(Default)]
+    struct ServerState {
+        data: Vec<u8>,
+        read_only: bool,
+        // Refuse structured replies, like older servers.
+        simple_replies: bool,
+        // Close the connection when receiving the request with this index, counting from 0.
+        drop_request: Option<usize>,
+        // Fail requests covering this offset with EIO.
+        bad_offset: Option<u64>,
+        requests: usize,
+        connections: usize,
+        commands: Vec<u16>,
+    }
+
+    struct TestServer {
+        // Removed with the socket when the server is dropped.
+        _dir: TempDir,
+        uri: String,
+        state: Arc<Mutex<ServerState>>,
+    }
+
+    fn read_u32(stream: &mut UnixStream) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn send_option_reply(
+        stream: &mut UnixStream,
+        option: u32,
+        reply_type: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+        reply.extend_from_slice(&option.to_be_bytes());
+        reply.extend_from_slice(&reply_type.to_be_bytes());
+        reply.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        reply.extend_from_slice(data);
+        stream.write_all(&reply)
+    }
+
+    fn chunk_header(flags: u16, reply_type: u16, handle: u64, length: u32) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        header.extend_from_slice(&flags.to_be_bytes());
+        header.extend_from_slice(&reply_type.to_be_bytes());
+        header.extend_from_slice(&handle.to_be_bytes());
+        header.extend_from_slice(&length.to_be_bytes());
+        header
+    }
+
+    fn simple_reply(error: u32, handle: u64) -> Vec<u8> {
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+        reply.extend_from_slice(&error.to_be_bytes());
+        reply.extend_from_slice(&handle.to_be_bytes());
+        reply
+    }
+
+    // Returns false once the client is done with the export.
+    fn serve_option(
+        stream: &mut UnixStream,
+        state: &Mutex<ServerState>,
+        structured: &mut bool,
+    ) -> io::Result<bool> {
+        let mut header = [0u8; 16];
+        stream.read_exact(&mut header)?;
+        assert_eq!(
+            u64::from_be_bytes(header[0..8].try_into().unwrap()),
+            IHAVEOPT
+        );
+        let option = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let mut data = vec![0u8; u32::from_be_bytes(header[12..16].try_into().unwrap()) as usize];
+        stream.read_exact(&mut data)?;
+        let state = state.lock().unwrap();
+        match option {
+            OPT_STRUCTURED_REPLY if !state.simple_replies => {
+                *structured = true;
+                send_option_reply(stream, option, REP_ACK, &[])?;
+            }
+            OPT_GO => {
+                let name_len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+                if &data[4..4 + name_len] != EXPORT_NAME.as_bytes() {
+                    send_option_reply(stream, option, REP_ERR_UNKNOWN, b"no such export")?;
+                    return Ok(true);
+                }
+                let mut flags = FLAG_HAS_FLAGS | FLAG_SEND_FLUSH | FLAG_SEND_TRIM;
+                if state.read_only {
+                    flags |= FLAG_READ_ONLY;
+                } else {
+                    flags |= FLAG_SEND_WRITE_ZEROES;
+                }
+                let mut info = Vec::new();
+                info.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+                info.extend_from_slice(&(state.data.len() as u64).to_be_bytes());
+                info.extend_from_slice(&flags.to_be_bytes());
+                send_option_reply(stream, option, REP_INFO, &info)?;
+                send_option_reply(stream, option, REP_ACK, &[])?;
+                return Ok(false);
+            }
+            _ => send_option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+        }
+        Ok(true)
+    }
+
+    // Returns false when the connection should be closed.
+    fn serve_request(
+        stream: &mut UnixStream,
+        state: &Mutex<ServerState>,
+        structured: bool,
+    ) -> io::Result<bool> {
+        let mut header = [0u8; REQUEST_SIZE];
+        stream.read_exact(&mut header)?;
+        assert_eq!(
+            u32::from_be_bytes(header[0..4].try_into().unwrap()),
+            REQUEST_MAGIC
+        );
+        let command = u16::from_be_bytes(header[6..8].try_into().unwrap());
+        let handle = u64::from_be_bytes(header[8..16].try_into().unwrap());
+        let offset = u64::from_be_bytes(header[16..24].try_into().unwrap());
+        let length = u32::from_be_bytes(header[24..28].try_into().unwrap()) as usize;
+        let mut data = vec![0u8; if command == CMD_WRITE { length } else { 0 }];
+        stream.read_exact(&mut data)?;
+
+        let mut state = state.lock().unwrap();
+        let index = state.requests;
+        state.requests += 1;
+        if command == CMD_DISC || state.drop_request == Some(index) {
+            return Ok(false);
+        }
+        state.commands.push(command);
+        let range = offset as usize..offset as usize + length;
+        let failed = range.end > state.data.len()
+            || state
+                .bad_offset
+                .map_or(false, |bad| range.contains(&(bad as usize)));
+        let mut reply = Vec::new();
+        if failed {
+            if structured {
+                reply = chunk_header(REPLY_FLAG_DONE, REPLY_TYPE_ERROR, handle, 6);
+                reply.extend_from_slice(&(libc::EIO as u32).to_be_bytes());
+                reply.extend_from_slice(&0u16.to_be_bytes());
+            } else {
+                reply = simple_reply(libc::EIO as u32, handle);
+            }
+        } else {
+            match command {
+                CMD_READ if structured => {
+                    // Send the second half first, as a hole if it only contains zeroes.
+                    let mid = offset as usize + length / 2;
+                    let second = &state.data[mid..range.end];
+                    if !second.is_empty() && second.iter().all(|&b| b == 0) {
+                        reply.extend(chunk_header(0, REPLY_TYPE_OFFSET_HOLE, handle, 12));
+                        reply.extend_from_slice(&(mid as u64).to_be_bytes());
+                        reply.extend_from_slice(&(second.len() as u32).to_be_bytes());
+                    } else if !second.is_empty() {
+                        reply.extend(chunk_header(
+                            0,
+                            REPLY_TYPE_OFFSET_DATA,
+                            handle,
+                            8 + second.len() as u32,
+                        ));
+                        reply.extend_from_slice(&(mid as u64).to_be_bytes());
+                        reply.extend_from_slice(second);
+                    }
+                    let first = &state.data[range.start..mid];
+                    if first.is_empty() {
+                        reply.extend(chunk_header(REPLY_FLAG_DONE, REPLY_TYPE_NONE, handle, 0));
+                    } else {
+                        reply.extend(chunk_header(
+                            REPLY_FLAG_DONE,
+                            REPLY_TYPE_OFFSET_DATA,
+                            handle,
+                            8 + first.len() as u32,
+                        ));
+                        reply.extend_from_slice(&offset.to_be_bytes());
+                        reply.extend_from_slice(first);
+                    }
+                }
+                CMD_READ => {
+                    reply = simple_reply(0, handle);
+                    reply.extend_from_slice(&state.data[range]);
+                }
+                _ => {
+                    match command {
+                        CMD_WRITE => state.data[range].copy_from_slice(&data),
+                        CMD_TRIM | CMD_WRITE_ZEROES => state.data[range].fill(0),
+                        _ => {}
+                    }
+                    if structured {
+                        reply = chunk_header(REPLY_FLAG_DONE, REPLY_TYPE_NONE, handle, 0);
+                    } else {
+                        reply = simple_reply(0, handle);
+                    }
+                }
+            }
+        }
+        stream.write_all(&reply)?;
+        Ok(true)
+    }
+
+    fn serve_connection(mut stream: UnixStream, state: &Mutex<ServerState>) -> io::Result<()> {
+        state.lock().unwrap().connections += 1;
+        let mut greeting = Vec::new();
+        greeting.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        greeting.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        greeting.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&greeting)?;
+        let client_flags = read_u32(&mut stream)?;
+        assert_eq!(
+            client_flags,
+            u32::from(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)
+        );
+        let mut structured = false;
+        while serve_option(&mut stream, state, &mut structured)? {}
+        while serve_request(&mut stream, state, structured)? {}
+        Ok(())
+    }
+
+    impl TestServer {
+        fn new(state: ServerState) -> TestServer {
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join("nbd.sock");
+            let listener = UnixListener::bind(&path).unwrap();
+            let state = Arc::new(Mutex::new(state));
+            let server_state = state.clone();
+            // The thread exits when the listener fails after the socket is removed, or stays
+            // blocked until the test process exits.
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let _ = serve_connection(stream, &server_state);
+                        }
+                        Err(_) => break,
+                    }
+                }
+            });
+            TestServer {
+                uri: format!("nbd+unix:///{}?socket={}", EXPORT_NAME, path.display()),
+                _dir: dir,
+                state,
+            }
+        }
+
+        fn with_data(data: Vec<u8>) -> TestServer {
+            TestServer::new(ServerState {
+                data,
+                ..Default::default()
+            })
+        }
+
+        fn state(&self) -> std::sync::MutexGuard<ServerState> {
+            self.state.lock().unwrap()
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    #[test]
+    fn parse_uris() {
+        assert_eq!(
+            NbdUri::parse("nbd://localhost/golden").unwrap(),
+            NbdUri {
+                address: Address::Tcp {
+                    host: "localhost".to_string(),
+                    port: DEFAULT_PORT
+                },
+                export: "golden".to_string(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd+tcp://[::1]:10900").unwrap(),
+            NbdUri {
+                address: Address::Tcp {
+                    host: "::1".to_string(),
+                    port: 10900
+                },
+                export: String::new(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd+unix:///my%20disk?socket=/run/nbd.sock").unwrap(),
+            NbdUri {
+                address: Address::Unix(PathBuf::from("/run/nbd.sock")),
+                export: "my disk".to_string(),
+            }
+        );
+        for uri in [
+            "nbd://",
+            "nbd://host:port/export",
+            "nbd://host/export?socket=/run/nbd.sock",
+            "nbd+unix:///export",
+            "nbd+unix://host/export?socket=/run/nbd.sock",
+            "nbd+ssh://host/export",
+            "/path/to/disk.img",
+        ] {
+            assert!(NbdUri::parse(uri).is_err(), "{} should be rejected", uri);
+        }
+        assert!(crate::is_nbd_uri("nbd+unix:///?socket=/run/nbd.sock"));
+        assert!(!crate::is_nbd_uri("/tmp/nbd://disk.img"));
+    }
+
+    #[test]
+    fn sync_read_write() {
+        let server = TestServer::with_data(pattern(4096));
+        let mut disk = NbdDisk::new(&server.uri, false).unwrap();
+        assert_eq!(disk.get_len().unwrap(), 4096);
+
+        let mut buf = vec![0u8; 1000];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 100)
+            .unwrap();
+        assert_eq!(buf, pattern(4096)[100..1100]);
+
+        let data: Vec<u8> = (0..296).map(|i| i as u8).collect();
+        disk.write_all_at_volatile(VolatileSlice::new(&mut data.clone()), 3800)
+            .unwrap();
+        assert_eq!(server.state().data[3800..], data);
+
+        // Reads stop at the end of the export.
+        let mut buf = vec![0u8; 200];
+        assert_eq!(
+            disk.read_at_volatile(VolatileSlice::new(&mut buf), 4000)
+                .unwrap(),
+            96
+        );
+        assert_eq!(buf[..96], data[200..296]);
+    }
+
+    #[test]
+    fn simple_replies() {
+        let server = TestServer::new(ServerState {
+            data: pattern(2048),
+            simple_replies: true,
+            ..Default::default()
+        });
+        let mut disk = NbdDisk::new(&server.uri, false).unwrap();
+        assert!(!disk.export.structured_replies);
+        let mut buf = vec![0u8; 2048];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        assert_eq!(buf, pattern(2048));
+    }
+
+    #[test]
+    fn server_error() {
+        let server = TestServer::new(ServerState {
+            data: pattern(2048),
+            bad_offset: Some(1024),
+            ..Default::default()
+        });
+        let mut disk = NbdDisk::new(&server.uri, false).unwrap();
+        let mut buf = vec![0u8; 512];
+        let err = disk
+            .read_at_volatile(VolatileSlice::new(&mut buf), 1000)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        // The connection is still used after an error.
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        assert_eq!(server.state().connections, 1);
+    }
+
+    #[test]
+    fn reconnect() {
+        let server = TestServer::new(ServerState {
+            data: pattern(2048),
+            drop_request: Some(1),
+            ..Default::default()
+        });
+        let mut disk = NbdDisk::new(&server.uri, false).unwrap();
+        let mut buf = vec![0u8; 512];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        // The server closes the connection instead of replying to this request.
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 512)
+            .unwrap();
+        assert_eq!(buf, pattern(2048)[512..1024]);
+        assert_eq!(server.state().connections, 2);
+    }
+
+    #[test]
+    fn read_only_export() {
+        let server = TestServer::new(ServerState {
+            data: pattern(1024),
+            read_only: true,
+            ..Default::default()
+        });
+        assert!(matches!(
+            NbdDisk::new(&server.uri, false),
+            Err(Error::ReadOnlyExport)
+        ));
+        NbdDisk::new(&server.uri, true).unwrap();
+    }
+
+    #[test]
+    fn unknown_export() {
+        let server = TestServer::with_data(pattern(1024));
+        let uri = server.uri.replace(EXPORT_NAME, "other");
+        match NbdDisk::new(&uri, true) {
+            Err(Error::ExportRejected(msg)) => assert_eq!(msg, "no such export"),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn async_requests() {
+        let server = TestServer::with_data(pattern(8192));
+        let disk = Box::new(NbdDisk::new(&server.uri, false).unwrap());
+        let ex = Executor::new().unwrap();
+        ex.run_until(async {
+            let disk = disk.to_async_disk(&ex).unwrap();
+            let guest_mem = Arc::new(GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap());
+            guest_mem
+                .write_all_at_addr(&[0x55; 1024], GuestAddress(0x1000))
+                .unwrap();
+
+            let count = disk
+                .write_from_mem(
+                    512,
+                    guest_mem.clone(),
+                    MemRegionIter::new(&[MemRegion {
+                        offset: 0x1000,
+                        len: 1024,
+                    }]),
+                )
+                .await
+                .unwrap();
+            assert_eq!(count, 1024);
+            disk.write_zeroes_at(1024, 256).await.unwrap();
+            disk.punch_hole(4096, 4096).await.unwrap();
+            disk.flush().await.unwrap();
+
+            let count = disk
+                .read_to_mem(
+                    0,
+                    guest_mem.clone(),
+                    MemRegionIter::new(&[
+                        MemRegion {
+                            offset: 0x4000,
+                            len: 2048,
+                        },
+                        MemRegion {
+                            offset: 0x8000,
+                            len: 6144,
+                        },
+                    ]),
+                )
+                .await
+                .unwrap();
+            assert_eq!(count, 8192);
+            let mut expected = pattern(8192);
+            expected[512..1536].fill(0x55);
+            expected[1024..1280].fill(0);
+            expected[4096..].fill(0);
+            let mut buf = vec![0u8; 8192];
+            guest_mem
+                .read_exact_at_addr(&mut buf[..2048], GuestAddress(0x4000))
+                .unwrap();
+            guest_mem
+                .read_exact_at_addr(&mut buf[2048..], GuestAddress(0x8000))
+                .unwrap();
+            assert_eq!(buf, expected);
+            assert_eq!(server.state().data, expected);
+            assert_eq!(
+                server.state().commands,
+                vec![CMD_WRITE, CMD_WRITE_ZEROES, CMD_TRIM, CMD_FLUSH, CMD_READ]
+            );
+
+            // The synchronous disk keeps using the same connection.
+            let mut disk = disk.into_inner();
+            let mut buf = vec![0u8; 512];
+            disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 512)
+                .unwrap();
+            assert_eq!(buf, expected[512..1024]);
+            assert_eq!(server.state().connections, 1);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn async_reconnect() {
+        let server = TestServer::new(ServerState {
+            data: pattern(4096),
+            drop_request: Some(0),
+            ..Default::default()
+        });
+        let disk = Box::new(NbdDisk::new(&server.uri, false).unwrap());
+        let ex = Executor::new().unwrap();
+        ex.run_until(async {
+            let disk = disk.to_async_disk(&ex).unwrap();
+            let guest_mem = Arc::new(GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap());
+            disk.read_to_mem(
+                1024,
+                guest_mem.clone(),
+                MemRegionIter::new(&[MemRegion {
+                    offset: 0,
+                    len: 1024,
+                }]),
+            )
+            .await
+            .unwrap();
+            let mut buf = vec![0u8; 1024];
+            guest_mem
+                .read_exact_at_addr(&mut buf, GuestAddress(0))
+                .unwrap();
+            assert_eq!(buf, pattern(4096)[1024..2048]);
+            assert_eq!(server.state().connections, 2);
+        })
+        .unwrap();
+    }
+}
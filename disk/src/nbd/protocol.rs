@@ -0,0 +1,615 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Encoding of the NBD protocol messages, following
+//! <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md>.
+//!
+//! Only the fixed newstyle handshake is supported. Replies are decoded by `ReplyReader` without
+//! doing any I/O itself, so that the same code serves the blocking and the async clients.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use super::Error;
+use super::Result;
+
+pub const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+pub const IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+pub const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+pub const REQUEST_MAGIC: u32 = 0x2560_9513;
+pub const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+pub const STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+// Handshake flags sent by the server, and the client flags echoing them.
+pub const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+pub const OPT_GO: u32 = 7;
+pub const OPT_STRUCTURED_REPLY: u32 = 8;
+
+pub const REP_ACK: u32 = 1;
+pub const REP_INFO: u32 = 3;
+pub const REP_FLAG_ERROR: u32 = 1 << 31;
+
+pub const INFO_EXPORT: u16 = 0;
+
+// Transmission flags of an export.
+pub const FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const FLAG_READ_ONLY: u16 = 1 << 1;
+pub const FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+pub const CMD_READ: u16 = 0;
+pub const CMD_WRITE: u16 = 1;
+pub const CMD_DISC: u16 = 2;
+pub const CMD_FLUSH: u16 = 3;
+pub const CMD_TRIM: u16 = 4;
+pub const CMD_WRITE_ZEROES: u16 = 6;
+
+pub const REPLY_FLAG_DONE: u16 = 1 << 0;
+
+pub const REPLY_TYPE_NONE: u16 = 0;
+pub const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+pub const REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+pub const REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+pub const REPLY_TYPE_ERROR_OFFSET: u16 = (1 << 15) | 2;
+
+pub const REQUEST_SIZE: usize = 28;
+// Simple replies are this long, and structured replies have a 4 byte length after it.
+const REPLY_HEADER_SIZE: usize = 16;
+
+// Upper bound for option reply payloads, which are only a few bytes long for the options we send.
+const MAX_OPTION_REPLY_SIZE: u32 = 64 * 1024;
+
+// The error values defined by the protocol, which match the Linux errno values.
+const NBD_ERRORS: [u32; 8] = [
+    libc::EPERM as u32,
+    libc::EIO as u32,
+    libc::ENOMEM as u32,
+    libc::EINVAL as u32,
+    libc::ENOSPC as u32,
+    libc::EOVERFLOW as u32,
+    libc::ENOTSUP as u32,
+    libc::ESHUTDOWN as u32,
+];
+
+/// Converts an error value sent by the server into an `io::Error`.
+pub fn server_error(error: u32) -> io::Error {
+    if NBD_ERRORS.contains(&error) {
+        io::Error::from_raw_os_error(error as i32)
+    } else {
+        io::Error::from_raw_os_error(libc::EIO)
+    }
+}
+
+/// Properties of the export selected during the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Export {
+    pub size: u64,
+    pub flags: u16,
+    pub structured_replies: bool,
+}
+
+impl Export {
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & FLAG_HAS_FLAGS != 0 && self.flags & flag != 0
+    }
+}
+
+fn read_be<const N: usize, S: Read>(stream: &mut S) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn send_option<S: Write>(stream: &mut S, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(16 + data.len());
+    message.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    message.extend_from_slice(&option.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message)
+}
+
+/// Reads an option reply and returns its type and payload.
+fn read_option_reply<S: Read>(stream: &mut S, option: u32) -> Result<(u32, Vec<u8>)> {
+    let magic = u64::from_be_bytes(read_be(stream).map_err(Error::Handshake)?);
+    let reply_option = u32::from_be_bytes(read_be(stream).map_err(Error::Handshake)?);
+    let reply_type = u32::from_be_bytes(read_be(stream).map_err(Error::Handshake)?);
+    let length = u32::from_be_bytes(read_be(stream).map_err(Error::Handshake)?);
+    if magic != OPTION_REPLY_MAGIC || reply_option != option {
+        return Err(Error::Protocol("bad option reply"));
+    }
+    if length > MAX_OPTION_REPLY_SIZE {
+        return Err(Error::Protocol("option reply too long"));
+    }
+    let mut data = vec![0u8; length as usize];
+    stream.read_exact(&mut data).map_err(Error::Handshake)?;
+    Ok((reply_type, data))
+}
+
+/// Performs the fixed newstyle handshake on `stream` and enters the transmission phase for
+/// `export_name`. Structured replies are used if the server supports them.
+pub fn handshake<S: Read + Write>(stream: &mut S, export_name: &str) -> Result<Export> {
+    let magic = u64::from_be_bytes(read_be(stream).map_err(Error::Handshake)?);
+    if magic != NBD_MAGIC {
+        return Err(Error::Protocol("bad magic"));
+    }
+    let magic = u64::from_be_bytes(read_be(stream).map_err(Error::Handshake)?);
+    if magic != IHAVEOPT {
+        return Err(Error::UnsupportedServer("oldstyle handshake"));
+    }
+    let server_flags = u16::from_be_bytes(read_be(stream).map_err(Error::Handshake)?);
+    if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(Error::UnsupportedServer("no fixed newstyle handshake"));
+    }
+    let client_flags = u32::from(server_flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES));
+    stream
+        .write_all(&client_flags.to_be_bytes())
+        .map_err(Error::Handshake)?;
+
+    send_option(stream, OPT_STRUCTURED_REPLY, &[]).map_err(Error::Handshake)?;
+    let (reply_type, _) = read_option_reply(stream, OPT_STRUCTURED_REPLY)?;
+    let structured_replies = reply_type == REP_ACK;
+
+    // Ask for the export without requesting any information besides the mandatory size and
+    // flags.
+    let mut go = Vec::new();
+    go.extend_from_slice(&(export_name.len() as u32).to_be_bytes());
+    go.extend_from_slice(export_name.as_bytes());
+    go.extend_from_slice(&0u16.to_be_bytes());
+    send_option(stream, OPT_GO, &go).map_err(Error::Handshake)?;
+    let mut export = None;
+    loop {
+        let (reply_type, data) = read_option_reply(stream, OPT_GO)?;
+        match reply_type {
+            REP_ACK => break,
+            REP_INFO => {
+                if data.len() >= 12 && u16::from_be_bytes([data[0], data[1]]) == INFO_EXPORT {
+                    export = Some(Export {
+                        size: u64::from_be_bytes(data[2..10].try_into().unwrap()),
+                        flags: u16::from_be_bytes([data[10], data[11]]),
+                        structured_replies,
+                    });
+                }
+            }
+            t if t & REP_FLAG_ERROR != 0 => {
+                return Err(Error::ExportRejected(
+                    String::from_utf8_lossy(&data).into_owned(),
+                ))
+            }
+            _ => return Err(Error::Protocol("unexpected reply to NBD_OPT_GO")),
+        }
+    }
+    export.ok_or(Error::Protocol("no export information"))
+}
+
+/// A request sent to the server during the transmission phase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub command: u16,
+    pub handle: u64,
+    pub offset: u64,
+    pub length: u32,
+}
+
+impl Request {
+    pub fn to_bytes(self) -> [u8; REQUEST_SIZE] {
+        let mut buf = [0u8; REQUEST_SIZE];
+        buf[0..4].copy_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        // Command flags are left at zero.
+        buf[6..8].copy_from_slice(&self.command.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.handle.to_be_bytes());
+        buf[16..24].copy_from_slice(&self.offset.to_be_bytes());
+        buf[24..28].copy_from_slice(&self.length.to_be_bytes());
+        buf
+    }
+}
+
+/// Why a request failed.
+#[derive(Debug)]
+pub enum ReplyError {
+    /// The server completed the request with this error value.
+    Server(u32),
+    /// The server sent a malformed reply. The connection can't be used anymore.
+    Protocol(&'static str),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Header,
+    StructuredLength {
+        flags: u16,
+        reply_type: u16,
+    },
+    DataOffset {
+        flags: u16,
+        length: u32,
+    },
+    Data {
+        flags: u16,
+        start: usize,
+        length: usize,
+    },
+    Payload {
+        flags: u16,
+        reply_type: u16,
+        length: usize,
+    },
+    Done,
+}
+
+/// Decodes the reply to a request. The caller repeatedly reads `bytes_needed()` bytes from the
+/// connection and passes them to `consume()` until no more bytes are needed. The data of read
+/// replies is copied into the buffer given to `new()`.
+pub struct ReplyReader<'a> {
+    request: Request,
+    buf: &'a mut [u8],
+    state: State,
+    error: Option<u32>,
+}
+
+impl<'a> ReplyReader<'a> {
+    /// Creates a reader for the reply to `request`. `buf` receives the data of a read request and
+    /// must be `request.length` bytes long; it is unused for other requests.
+    pub fn new(request: Request, buf: &'a mut [u8]) -> ReplyReader<'a> {
+        ReplyReader {
+            request,
+            buf,
+            state: State::Header,
+            error: None,
+        }
+    }
+
+    /// Returns the number of bytes to pass to the next call of `consume()`, or zero if the whole
+    /// reply has been read.
+    pub fn bytes_needed(&self) -> usize {
+        match self.state {
+            State::Header => REPLY_HEADER_SIZE,
+            State::StructuredLength { .. } => 4,
+            State::DataOffset { .. } => 8,
+            State::Data { length, .. } | State::Payload { length, .. } => length,
+            State::Done => 0,
+        }
+    }
+
+    /// Decodes the next `bytes_needed()` bytes of the reply.
+    pub fn consume(&mut self, bytes: &[u8]) -> std::result::Result<(), ReplyError> {
+        debug_assert_eq!(bytes.len(), self.bytes_needed());
+        self.state = match self.state {
+            State::Header => self.consume_header(bytes)?,
+            State::StructuredLength { flags, reply_type } => {
+                let length = u32::from_be_bytes(bytes.try_into().unwrap());
+                self.chunk_state(flags, reply_type, length)?
+            }
+            State::DataOffset { flags, length } => {
+                let offset = u64::from_be_bytes(bytes.try_into().unwrap());
+                let length = length as usize - 8;
+                let start = offset
+                    .checked_sub(self.request.offset)
+                    .filter(|start| {
+                        start
+                            .checked_add(length as u64)
+                            .map_or(false, |end| end <= self.buf.len() as u64)
+                    })
+                    .ok_or(ReplyError::Protocol("data chunk out of range"))?;
+                self.next_chunk_state(
+                    flags,
+                    State::Data {
+                        flags,
+                        start: start as usize,
+                        length,
+                    },
+                )
+            }
+            State::Data { flags, start, .. } => {
+                self.buf[start..start + bytes.len()].copy_from_slice(bytes);
+                self.end_chunk(flags)
+            }
+            State::Payload {
+                flags, reply_type, ..
+            } => {
+                self.consume_payload(reply_type, bytes)?;
+                self.end_chunk(flags)
+            }
+            State::Done => return Err(ReplyError::Protocol("reply already complete")),
+        };
+        Ok(())
+    }
+
+    /// Returns the outcome of the request once the whole reply has been read.
+    pub fn finish(self) -> std::result::Result<(), ReplyError> {
+        match self.error {
+            Some(error) => Err(ReplyError::Server(error)),
+            None => Ok(()),
+        }
+    }
+
+    fn consume_header(&mut self, bytes: &[u8]) -> std::result::Result<State, ReplyError> {
+        let magic = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+        let handle = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+        if handle != self.request.handle {
+            return Err(ReplyError::Protocol("reply to unknown request"));
+        }
+        match magic {
+            SIMPLE_REPLY_MAGIC => {
+                let error = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+                if error != 0 {
+                    self.error = Some(error);
+                    Ok(State::Done)
+                } else if self.request.command == CMD_READ && !self.buf.is_empty() {
+                    Ok(State::Data {
+                        flags: REPLY_FLAG_DONE,
+                        start: 0,
+                        length: self.buf.len(),
+                    })
+                } else {
+                    Ok(State::Done)
+                }
+            }
+            STRUCTURED_REPLY_MAGIC => Ok(State::StructuredLength {
+                flags: u16::from_be_bytes([bytes[4], bytes[5]]),
+                reply_type: u16::from_be_bytes([bytes[6], bytes[7]]),
+            }),
+            _ => Err(ReplyError::Protocol("bad reply magic")),
+        }
+    }
+
+    fn chunk_state(
+        &self,
+        flags: u16,
+        reply_type: u16,
+        length: u32,
+    ) -> std::result::Result<State, ReplyError> {
+        let data_chunk = matches!(reply_type, REPLY_TYPE_OFFSET_DATA | REPLY_TYPE_OFFSET_HOLE);
+        if data_chunk && self.request.command != CMD_READ {
+            return Err(ReplyError::Protocol("data chunk for a non-read request"));
+        }
+        match reply_type {
+            REPLY_TYPE_OFFSET_DATA if length > 8 => Ok(State::DataOffset { flags, length }),
+            REPLY_TYPE_OFFSET_DATA => Err(ReplyError::Protocol("empty data chunk")),
+            REPLY_TYPE_OFFSET_HOLE if length != 12 => Err(ReplyError::Protocol("bad hole chunk")),
+            REPLY_TYPE_NONE if length != 0 => Err(ReplyError::Protocol("bad none chunk")),
+            _ if length as usize > self.buf.len() + 4096 => {
+                Err(ReplyError::Protocol("reply chunk too long"))
+            }
+            _ => Ok(self.next_chunk_state(
+                flags,
+                State::Payload {
+                    flags,
+                    reply_type,
+                    length: length as usize,
+                },
+            )),
+        }
+    }
+
+    // Skips states that don't need any bytes.
+    fn next_chunk_state(&self, flags: u16, state: State) -> State {
+        match state {
+            State::Data { length: 0, .. } | State::Payload { length: 0, .. } => {
+                self.end_chunk(flags)
+            }
+            state => state,
+        }
+    }
+
+    fn end_chunk(&self, flags: u16) -> State {
+        if flags & REPLY_FLAG_DONE != 0 {
+            State::Done
+        } else {
+            State::Header
+        }
+    }
+
+    fn consume_payload(
+        &mut self,
+        reply_type: u16,
+        bytes: &[u8],
+    ) -> std::result::Result<(), ReplyError> {
+        match reply_type {
+            REPLY_TYPE_OFFSET_HOLE => {
+                let offset = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+                let length = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+                let start = offset
+                    .checked_sub(self.request.offset)
+                    .filter(|start| {
+                        start
+                            .checked_add(u64::from(length))
+                            .map_or(false, |end| end <= self.buf.len() as u64)
+                    })
+                    .ok_or(ReplyError::Protocol("hole chunk out of range"))?;
+                self.buf[start as usize..start as usize + length as usize].fill(0);
+            }
+            REPLY_TYPE_ERROR | REPLY_TYPE_ERROR_OFFSET => {
+                if bytes.len() < 6 {
+                    return Err(ReplyError::Protocol("bad error chunk"));
+                }
+                self.error = Some(u32::from_be_bytes(bytes[0..4].try_into().unwrap()));
+            }
+            // Unknown error types still fail the request; other unknown types are ignored.
+            t if t & (1 << 15) != 0 => self.error = Some(libc::EIO as u32),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn structured_header(flags: u16, reply_type: u16, handle: u64, length: u32) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        header.extend_from_slice(&flags.to_be_bytes());
+        header.extend_from_slice(&reply_type.to_be_bytes());
+        header.extend_from_slice(&handle.to_be_bytes());
+        header.extend_from_slice(&length.to_be_bytes());
+        header
+    }
+
+    fn feed(reader: &mut ReplyReader, mut reply: &[u8]) -> std::result::Result<(), ReplyError> {
+        while reader.bytes_needed() > 0 {
+            let n = reader.bytes_needed();
+            reader.consume(&reply[..n])?;
+            reply = &reply[n..];
+        }
+        assert!(reply.is_empty(), "reply has trailing bytes");
+        Ok(())
+    }
+
+    #[test]
+    fn request_encoding() {
+        let request = Request {
+            command: CMD_WRITE,
+            handle: 0x1122334455667788,
+            offset: 0x1000,
+            length: 512,
+        };
+        assert_eq!(
+            request.to_bytes().to_vec(),
+            vec![
+                0x25, 0x60, 0x95, 0x13, 0, 0, 0, 1, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
+                0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 2, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn simple_read_reply() {
+        let request = Request {
+            command: CMD_READ,
+            handle: 7,
+            offset: 0,
+            length: 4,
+        };
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+        reply.extend_from_slice(&0u32.to_be_bytes());
+        reply.extend_from_slice(&7u64.to_be_bytes());
+        reply.extend_from_slice(&[1, 2, 3, 4]);
+
+        let mut buf = [0u8; 4];
+        let mut reader = ReplyReader::new(request, &mut buf);
+        feed(&mut reader, &reply).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn structured_read_reply() {
+        let request = Request {
+            command: CMD_READ,
+            handle: 1,
+            offset: 0x200,
+            length: 8,
+        };
+        // The second half of the range arrives first, then a hole for the first half.
+        let mut reply = structured_header(0, REPLY_TYPE_OFFSET_DATA, 1, 12);
+        reply.extend_from_slice(&0x204u64.to_be_bytes());
+        reply.extend_from_slice(&[5, 6, 7, 8]);
+        reply.extend(structured_header(
+            REPLY_FLAG_DONE,
+            REPLY_TYPE_OFFSET_HOLE,
+            1,
+            12,
+        ));
+        reply.extend_from_slice(&0x200u64.to_be_bytes());
+        reply.extend_from_slice(&4u32.to_be_bytes());
+
+        let mut buf = [0xffu8; 8];
+        let mut reader = ReplyReader::new(request, &mut buf);
+        feed(&mut reader, &reply).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn structured_error_reply() {
+        let request = Request {
+            command: CMD_WRITE,
+            handle: 3,
+            offset: 0,
+            length: 512,
+        };
+        let mut reply = structured_header(REPLY_FLAG_DONE, REPLY_TYPE_ERROR, 3, 9);
+        reply.extend_from_slice(&(libc::ENOSPC as u32).to_be_bytes());
+        reply.extend_from_slice(&3u16.to_be_bytes());
+        reply.extend_from_slice(b"bad");
+
+        let mut reader = ReplyReader::new(request, &mut []);
+        feed(&mut reader, &reply).unwrap();
+        match reader.finish() {
+            Err(ReplyError::Server(e)) => assert_eq!(e, libc::ENOSPC as u32),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn data_chunk_out_of_range() {
+        let request = Request {
+            command: CMD_READ,
+            handle: 1,
+            offset: 0x200,
+            length: 4,
+        };
+        let mut reply = structured_header(REPLY_FLAG_DONE, REPLY_TYPE_OFFSET_DATA, 1, 12);
+        reply.extend_from_slice(&0x202u64.to_be_bytes());
+        reply.extend_from_slice(&[5, 6, 7, 8]);
+
+        let mut buf = [0u8; 4];
+        let mut reader = ReplyReader::new(request, &mut buf);
+        assert!(matches!(
+            feed(&mut reader, &reply),
+            Err(ReplyError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn chunk_offset_overflow() {
+        let request = Request {
+            command: CMD_READ,
+            handle: 1,
+            offset: 0,
+            length: 4,
+        };
+        let mut reply = structured_header(REPLY_FLAG_DONE, REPLY_TYPE_OFFSET_DATA, 1, 12);
+        reply.extend_from_slice(&(u64::MAX - 1).to_be_bytes());
+        reply.extend_from_slice(&[5, 6, 7, 8]);
+        let mut buf = [0u8; 4];
+        let mut reader = ReplyReader::new(request, &mut buf);
+        assert!(matches!(
+            feed(&mut reader, &reply),
+            Err(ReplyError::Protocol(_))
+        ));
+
+        let mut reply = structured_header(REPLY_FLAG_DONE, REPLY_TYPE_OFFSET_HOLE, 1, 12);
+        reply.extend_from_slice(&(u64::MAX - 1).to_be_bytes());
+        reply.extend_from_slice(&4u32.to_be_bytes());
+        let mut reader = ReplyReader::new(request, &mut buf);
+        assert!(matches!(
+            feed(&mut reader, &reply),
+            Err(ReplyError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn reply_to_other_request() {
+        let request = Request {
+            command: CMD_FLUSH,
+            handle: 1,
+            offset: 0,
+            length: 0,
+        };
+        let reply = structured_header(REPLY_FLAG_DONE, REPLY_TYPE_NONE, 2, 0);
+        let mut reader = ReplyReader::new(request, &mut []);
+        assert!(matches!(
+            feed(&mut reader, &reply),
+            Err(ReplyError::Protocol(_))
+        ));
+    }
+}
@@ -8,6 +8,7 @@ mod vec_cache;
 
 use std::cmp::max;
 use std::cmp::min;
+use std::cmp::Ordering;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
@@ -17,7 +18,10 @@ use std::io::SeekFrom;
 use std::io::Write;
 use std::mem::size_of;
 use std::path::Path;
+use std::path::PathBuf;
 use std::str;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use base::error;
 use base::open_file_or_duplicate;
@@ -37,19 +41,29 @@ use libc::EINVAL;
 use libc::ENOSPC;
 use libc::ENOTSUP;
 use remain::sorted;
+use serde::Serialize;
 use thiserror::Error;
 
+use crate::asynchronous::DiskBackingChain;
 use crate::asynchronous::DiskFlush;
+use crate::asynchronous::DiskSnapshot;
 use crate::create_disk_file;
+use crate::crypt::luks;
+use crate::crypt::XtsCipher;
+use crate::crypt::SECTOR_SIZE;
+use crate::detect_image_type;
 use crate::qcow::qcow_raw_file::QcowRawFile;
 use crate::qcow::refcount::RefCount;
 use crate::qcow::vec_cache::CacheMap;
 use crate::qcow::vec_cache::Cacheable;
 use crate::qcow::vec_cache::VecCache;
+use crate::sys::file_id;
 use crate::AsyncDisk;
 use crate::AsyncDiskFileWrapper;
 use crate::DiskFile;
 use crate::DiskGetLen;
+use crate::ImageType;
+use crate::SnapshotInfo;
 use crate::ToAsyncDisk;
 
 #[sorted]
@@ -61,8 +75,8 @@ pub enum Error {
     BackingFileOpen(Box<crate::Error>),
     #[error("backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
-    #[error("compressed blocks not supported")]
-    CompressedBlocksNotSupported,
+    #[error("failed to commit data to the backing file: {0}")]
+    CommittingData(io::Error),
     #[error("failed to evict cache: {0}")]
     EvictingCache(io::Error),
     #[error("file larger than max of {}: {0}", MAX_QCOW_FILE_SIZE)]
@@ -71,12 +85,16 @@ pub enum Error {
     GettingFileSize(io::Error),
     #[error("failed to get refcount: {0}")]
     GettingRefcount(refcount::Error),
+    #[error("file is not the backing file of the image")]
+    IncorrectBackingFile,
     #[error("failed to parse filename: {0}")]
     InvalidBackingFileName(str::Utf8Error),
     #[error("invalid cluster index")]
     InvalidClusterIndex,
     #[error("invalid cluster size")]
     InvalidClusterSize,
+    #[error("invalid encryption header extension")]
+    InvalidCryptHeader,
     #[error("invalid index")]
     InvalidIndex,
     #[error("invalid L1 table offset")]
@@ -91,6 +109,14 @@ pub enum Error {
     InvalidRefcountTableOffset,
     #[error("invalid refcount table size: {0}")]
     InvalidRefcountTableSize(u64),
+    #[error("invalid snapshot name")]
+    InvalidSnapshotName,
+    #[error("invalid snapshot table")]
+    InvalidSnapshotTable,
+    #[error("image is encrypted and no key was given")]
+    MissingEncryptionKey,
+    #[error("image has no backing file")]
+    NoBackingFile,
     #[error("no free clusters")]
     NoFreeClusters,
     #[error("no refcount clusters")]
@@ -119,16 +145,40 @@ pub enum Error {
     SettingRefcountRefcount(io::Error),
     #[error("size too small for number of clusters")]
     SizeTooSmallForNumberOfClusters,
+    #[error("snapshot already exists: {0}")]
+    SnapshotExists(String),
+    #[error("snapshot not found: {0}")]
+    SnapshotNotFound(String),
+    #[error("snapshot disk size {0} doesn't match the image size")]
+    SnapshotSizeMismatch(u64),
+    #[error("failed to copy data from the backing file: {0}")]
+    StreamingData(io::Error),
+    #[error("can't drop the backing file of an image with internal snapshots")]
+    StreamingWithSnapshots,
+    #[error("failed to sync caches: {0}")]
+    SyncingCaches(io::Error),
     #[error("l1 entry table too large: {0}")]
     TooManyL1Entries(u64),
     #[error("ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("too many snapshots: {0}")]
+    TooManySnapshots(u32),
+    #[error("failed to unlock the image: {0}")]
+    UnlockingImage(crate::crypt::Error),
+    #[error("unsupported backing file type for commit: {0:?}")]
+    UnsupportedBackingFileType(ImageType),
+    #[error("unsupported compression type: {0}")]
+    UnsupportedCompressionType(u8),
+    #[error("unsupported encryption method: {0}")]
+    UnsupportedEncryption(u32),
     #[error("unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("unsupported version: {0}")]
     UnsupportedVersion(u32),
     #[error("failed to write header: {0}")]
     WritingHeader(io::Error),
+    #[error("failed to update snapshots: {0}")]
+    WritingSnapshots(io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -158,11 +208,36 @@ const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 // Flags
 const COMPRESSED_FLAG: u64 = 1 << 62;
+// The "copied" flag of L1 and L2 entries, set when the refcount of the cluster is exactly one and it
+// can be modified in place. It is kept in the entries of the active tables held in memory.
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1 << 0;
 
-// The format supports a "header extension area", that crosvm does not use.
+// Compression methods for compressed clusters, from the compression_type header field.
+const COMPRESSION_TYPE_ZLIB: u8 = 0;
+const COMPRESSION_TYPE_ZSTD: u8 = 1;
+// Compressed cluster sizes are counted in 512 byte sectors.
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+
+// Offset of the backing_file_offset header field, which is directly followed by backing_file_size.
+const BACKING_FILE_OFFSET_OFFSET: u64 = 8;
+// Offset of the nb_snapshots header field, which is directly followed by snapshots_offset.
+const NB_SNAPSHOTS_OFFSET: u64 = 60;
+// Same limits as qemu for the number of snapshots and the extra data stored with each.
+const MAX_SNAPSHOTS: u32 = 65536;
+const MAX_SNAPSHOT_EXTRA_DATA_SIZE: u32 = 1024;
+// Size of the fixed part of a snapshot table entry, before the extra data, ID and name.
+const SNAPSHOT_ENTRY_HEADER_SIZE: usize = 40;
+
+// The format supports a "header extension area". crosvm only uses the full disk encryption header
+// pointer, and only for images that are already encrypted.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_CRYPT: u32 = 0x0537_be77;
+
+// Values of the crypt_method header field.
+const CRYPT_METHOD_NONE: u32 = 0;
+const CRYPT_METHOD_LUKS: u32 = 2;
 
 // Defined by the specification
 const MAX_BACKING_FILE_SIZE: u32 = 1023;
@@ -195,6 +270,12 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    // Only present if header_size is larger than V3_BARE_HEADER_SIZE.
+    pub compression_type: u8,
+
+    // Location of the LUKS header of encrypted images, from the header extensions.
+    pub crypt_header_offset: u64,
+    pub crypt_header_length: u64,
 
     // Post-header entries
     pub backing_file_path: Option<String>,
@@ -256,8 +337,20 @@ impl QcowHeader {
             autoclear_features: read_u64_from_file(f)?,
             refcount_order: read_u32_from_file(f)?,
             header_size: read_u32_from_file(f)?,
+            compression_type: COMPRESSION_TYPE_ZLIB,
+            crypt_header_offset: 0,
+            crypt_header_length: 0,
             backing_file_path: None,
         };
+        if header.header_size > V3_BARE_HEADER_SIZE {
+            let mut compression_type = [0u8; 1];
+            f.read_exact(&mut compression_type)
+                .map_err(Error::ReadingHeader)?;
+            header.compression_type = compression_type[0];
+        }
+        if header.version == 3 {
+            header.read_extensions(f)?;
+        }
         if header.backing_file_size > MAX_BACKING_FILE_SIZE {
             return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
         }
@@ -275,6 +368,32 @@ impl QcowHeader {
         Ok(header)
     }
 
+    // Reads the header extensions that follow the header, up to the end of the first cluster.
+    fn read_extensions(&mut self, f: &mut File) -> Result<()> {
+        let cluster_size = 0x01u64 << self.cluster_bits.clamp(MIN_CLUSTER_BITS, MAX_CLUSTER_BITS);
+        let mut offset = u64::from(self.header_size);
+        while offset + 8 <= cluster_size {
+            f.seek(SeekFrom::Start(offset))
+                .map_err(Error::ReadingHeader)?;
+            let extension_type = read_u32_from_file(f)?;
+            let length = read_u32_from_file(f)?;
+            match extension_type {
+                HEADER_EXT_END => break,
+                HEADER_EXT_CRYPT => {
+                    if length != 16 {
+                        return Err(Error::InvalidCryptHeader);
+                    }
+                    self.crypt_header_offset = read_u64_from_file(f)?;
+                    self.crypt_header_length = read_u64_from_file(f)?;
+                }
+                _ => {}
+            }
+            // Extension data is padded to a multiple of 8 bytes.
+            offset += 8 + div_round_up_u64(u64::from(length), 8) * 8;
+        }
+        Ok(())
+    }
+
     pub fn create_for_size_and_path(size: u64, backing_file: Option<&str>) -> Result<QcowHeader> {
         let cluster_bits: u32 = DEFAULT_CLUSTER_BITS;
         let cluster_size: u32 = 0x01 << cluster_bits;
@@ -329,6 +448,9 @@ impl QcowHeader {
             autoclear_features: 0,
             refcount_order: DEFAULT_REFCOUNT_ORDER,
             header_size: V3_BARE_HEADER_SIZE,
+            compression_type: COMPRESSION_TYPE_ZLIB,
+            crypt_header_offset: 0,
+            crypt_header_length: 0,
             backing_file_path: backing_file.map(String::from),
         })
     }
@@ -365,9 +487,24 @@ impl QcowHeader {
         write_u64_to_file(file, self.autoclear_features)?;
         write_u32_to_file(file, self.refcount_order)?;
         write_u32_to_file(file, self.header_size)?;
-        write_u32_to_file(file, 0)?; // header extension type: end of header extension area
+        if self.header_size > V3_BARE_HEADER_SIZE {
+            // The compression type is followed by padding up to the end of the header.
+            file.write_all(&[self.compression_type])
+                .map_err(Error::WritingHeader)?;
+            file.seek(SeekFrom::Start(u64::from(self.header_size)))
+                .map_err(Error::WritingHeader)?;
+        }
+        if self.crypt_header_length != 0 {
+            write_u32_to_file(file, HEADER_EXT_CRYPT)?;
+            write_u32_to_file(file, 16)?;
+            write_u64_to_file(file, self.crypt_header_offset)?;
+            write_u64_to_file(file, self.crypt_header_length)?;
+        }
+        write_u32_to_file(file, HEADER_EXT_END)?; // end of header extension area
         write_u32_to_file(file, 0)?; // length of header extension data: 0
         if let Some(backing_file_path) = self.backing_file_path.as_ref() {
+            file.seek(SeekFrom::Start(self.backing_file_offset))
+                .map_err(Error::WritingHeader)?;
             write!(file, "{}", backing_file_path).map_err(Error::WritingHeader)?;
         }
 
@@ -386,6 +523,141 @@ impl QcowHeader {
     }
 }
 
+/// A cluster whose refcount doesn't match the number of references to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct RefcountMismatch {
+    /// Offset of the cluster in the image file.
+    pub offset: u64,
+    /// Refcount stored in the image.
+    pub refcount: u16,
+    /// Number of references to the cluster found by walking the image's tables.
+    pub references: u16,
+}
+
+/// The result of checking the refcounts of a qcow2 image with `QcowFile::check`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct QcowCheckResult {
+    /// Clusters with a higher refcount than references. They waste space but are otherwise
+    /// harmless.
+    pub leaks: Vec<RefcountMismatch>,
+    /// Clusters with a lower refcount than references, for example a cluster referenced by two L2
+    /// entries with a refcount of one. Writing to them corrupts the data of the other references.
+    pub errors: Vec<RefcountMismatch>,
+    /// True if the refcounts were rebuilt. `leaks` and `errors` describe the image as found before
+    /// the repair.
+    pub repaired: bool,
+}
+
+impl QcowCheckResult {
+    /// Returns true if the refcounts of the image were consistent.
+    pub fn is_clean(&self) -> bool {
+        self.leaks.is_empty() && self.errors.is_empty()
+    }
+}
+
+// An entry of the snapshot table.
+#[derive(Clone, Debug)]
+struct QcowSnapshot {
+    l1_table_offset: u64,
+    l1_size: u32,
+    id: String,
+    name: String,
+    date_sec: u32,
+    date_nsec: u32,
+    vm_clock_nsec: u64,
+    vm_state_size: u32,
+    // Kept as is so that fields crosvm doesn't know about survive rewriting the table.
+    extra_data: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    // Reads a snapshot table entry, including its padding, from the current position of `f`.
+    fn read_from(f: &mut File) -> Result<QcowSnapshot> {
+        let l1_table_offset = read_u64_from_file(f)?;
+        let l1_size = read_u32_from_file(f)?;
+        let id_size = read_u16_from_file(f)?;
+        let name_size = read_u16_from_file(f)?;
+        let date_sec = read_u32_from_file(f)?;
+        let date_nsec = read_u32_from_file(f)?;
+        let vm_clock_nsec = read_u64_from_file(f)?;
+        let vm_state_size = read_u32_from_file(f)?;
+        let extra_data_size = read_u32_from_file(f)?;
+        if extra_data_size > MAX_SNAPSHOT_EXTRA_DATA_SIZE {
+            return Err(Error::InvalidSnapshotTable);
+        }
+
+        let mut read_bytes = |len: usize| {
+            let mut bytes = vec![0u8; len];
+            f.read_exact(&mut bytes).map_err(Error::ReadingHeader)?;
+            Ok(bytes)
+        };
+        let extra_data = read_bytes(extra_data_size as usize)?;
+        let id = String::from_utf8_lossy(&read_bytes(id_size as usize)?).into_owned();
+        let name = String::from_utf8_lossy(&read_bytes(name_size as usize)?).into_owned();
+
+        let entry_size = SNAPSHOT_ENTRY_HEADER_SIZE
+            + extra_data_size as usize
+            + id_size as usize
+            + name_size as usize;
+        let padding = entry_size.next_multiple_of(8) - entry_size;
+        f.seek(SeekFrom::Current(padding as i64))
+            .map_err(Error::ReadingHeader)?;
+
+        Ok(QcowSnapshot {
+            l1_table_offset,
+            l1_size,
+            id,
+            name,
+            date_sec,
+            date_nsec,
+            vm_clock_nsec,
+            vm_state_size,
+            extra_data,
+        })
+    }
+
+    // Serializes the entry, padded to a multiple of eight bytes as the snapshot table requires.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.l1_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.date_sec.to_be_bytes());
+        bytes.extend_from_slice(&self.date_nsec.to_be_bytes());
+        bytes.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        bytes.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.extra_data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.extra_data);
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.resize(bytes.len().next_multiple_of(8), 0);
+        bytes
+    }
+
+    // The size of the virtual disk when the snapshot was taken, if it was recorded.
+    fn disk_size(&self) -> Option<u64> {
+        let bytes = self.extra_data.get(8..16)?;
+        Some(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn info(&self) -> SnapshotInfo {
+        // The 64 bit VM state size in the extra data supersedes the 32 bit field.
+        let vm_state_size = match self.extra_data.get(0..8) {
+            Some(bytes) => u64::from_be_bytes(bytes.try_into().unwrap()),
+            None => u64::from(self.vm_state_size),
+        };
+        SnapshotInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            date_sec: self.date_sec,
+            date_nsec: self.date_nsec,
+            vm_state_size,
+            disk_size: self.disk_size(),
+        }
+    }
+}
+
 fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u32) -> u64 {
     // Use u64 as the product of the u32 inputs can overflow.
     let refcount_bytes = (0x01 << refcount_order as u64) / 8;
@@ -394,6 +666,15 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
     for_data + for_refcounts
 }
 
+/// Returns the path of `backing_file_name`, the backing file of the image at `image_path`. A
+/// relative name is relative to the directory of the image, as it is for qemu.
+pub fn backing_file_full_path(image_path: &Path, backing_file_name: &str) -> PathBuf {
+    match image_path.parent() {
+        Some(image_dir) => image_dir.join(backing_file_name),
+        None => PathBuf::from(backing_file_name),
+    }
+}
+
 /// Represents a qcow2 file. This is a sparse file format maintained by the qemu project.
 /// Full documentation of the format can be found in the qemu repository.
 ///
@@ -425,6 +706,53 @@ pub struct QcowFile {
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<dyn DiskFile>>,
+    // Identifies the file opened as `backing_file`, so that a file passed to `commit` can be
+    // checked against it. None if the backing file was set with `set_backing_file`.
+    backing_file_id: Option<(u64, u64)>,
+    // The most recently decompressed cluster and the L2 entry it was read from. Avoids
+    // decompressing the same cluster repeatedly for sequential reads smaller than a cluster.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+    snapshots: Vec<QcowSnapshot>,
+    // Encrypts the data clusters of encrypted images. Sectors are numbered by their offset in the
+    // image file.
+    cipher: Option<XtsCipher>,
+}
+
+// Where the data for a guest address is stored in the image.
+enum ClusterData {
+    // Neither the L2 table nor the data cluster is allocated.
+    Unallocated,
+    // The data is stored uncompressed at the given offset in the raw file.
+    Offset(u64),
+    // The cluster is compressed; holds the L2 entry describing the compressed data.
+    Compressed(u64),
+}
+
+// Data source handed to the `read_cb` callback.
+enum ReadSource<'a> {
+    // Read from the given file at the given offset.
+    File(&'a mut dyn DiskFile, u64),
+    // The data has already been read in to memory, for example by decompressing a cluster.
+    Buffer(&'a [u8]),
+    // Nothing is allocated, reads return zeros.
+    Zeros,
+}
+
+impl ReadSource<'_> {
+    // Fills `slice` with data from this source.
+    fn read_to(self, slice: VolatileSlice) -> io::Result<()> {
+        match self {
+            ReadSource::File(f, offset) => f.read_exact_at_volatile(slice, offset),
+            ReadSource::Buffer(data) => {
+                slice.copy_from(data);
+                Ok(())
+            }
+            ReadSource::Zeros => {
+                slice.write_bytes(0);
+                Ok(())
+            }
+        }
+    }
 }
 
 impl DiskFile for QcowFile {}
@@ -437,9 +765,76 @@ impl DiskFlush for QcowFile {
     }
 }
 
+impl DiskSnapshot for QcowFile {
+    fn list_snapshots(&self) -> crate::Result<Vec<SnapshotInfo>> {
+        Ok(self.snapshots())
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        QcowFile::create_snapshot(self, name).map_err(crate::Error::QcowError)
+    }
+
+    fn apply_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        QcowFile::apply_snapshot(self, name).map_err(crate::Error::QcowError)
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        QcowFile::delete_snapshot(self, name).map_err(crate::Error::QcowError)
+    }
+}
+
+impl DiskBackingChain for QcowFile {
+    fn commit(&mut self, backing_file: File) -> crate::Result<()> {
+        QcowFile::commit(self, backing_file, crate::MAX_NESTING_DEPTH)
+            .map_err(crate::Error::QcowError)
+    }
+
+    fn stream(&mut self) -> crate::Result<()> {
+        QcowFile::stream(self).map_err(crate::Error::QcowError)
+    }
+}
+
 impl QcowFile {
     /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
-    pub fn from(mut file: File, max_nesting_depth: u32) -> Result<QcowFile> {
+    pub fn from(file: File, max_nesting_depth: u32) -> Result<QcowFile> {
+        QcowFile::from_with_key(file, max_nesting_depth, None)
+    }
+
+    /// Creates a QcowFile from `file`, which must be a valid qcow2 image. If the image is LUKS
+    /// encrypted, it is unlocked with `passphrase`. The passphrase is ignored for images that
+    /// aren't encrypted.
+    pub fn from_with_key(
+        file: File,
+        max_nesting_depth: u32,
+        passphrase: Option<&[u8]>,
+    ) -> Result<QcowFile> {
+        QcowFile::open(file, None, max_nesting_depth, passphrase)
+    }
+
+    /// Creates a QcowFile from `file`, the valid qcow2 image at `image_path`. A relative backing
+    /// file path is resolved against the directory of `image_path`.
+    pub fn from_path(file: File, image_path: &Path, max_nesting_depth: u32) -> Result<QcowFile> {
+        QcowFile::from_path_with_key(file, image_path, max_nesting_depth, None)
+    }
+
+    /// Like `from_path`, but unlocks a LUKS encrypted image with `passphrase`.
+    pub fn from_path_with_key(
+        file: File,
+        image_path: &Path,
+        max_nesting_depth: u32,
+        passphrase: Option<&[u8]>,
+    ) -> Result<QcowFile> {
+        QcowFile::open(file, Some(image_path), max_nesting_depth, passphrase)
+    }
+
+    // Relative backing file paths are resolved against the directory of `image_path` if it is
+    // known, and against the current directory otherwise.
+    fn open(
+        mut file: File,
+        image_path: Option<&Path>,
+        max_nesting_depth: u32,
+        passphrase: Option<&[u8]>,
+    ) -> Result<QcowFile> {
         let header = QcowHeader::new(&mut file)?;
 
         // Only v3 files are supported.
@@ -463,20 +858,46 @@ impl QcowFile {
             return Err(Error::FileTooBig(header.size));
         }
 
+        if header.compression_type != COMPRESSION_TYPE_ZLIB
+            && header.compression_type != COMPRESSION_TYPE_ZSTD
+        {
+            return Err(Error::UnsupportedCompressionType(header.compression_type));
+        }
+
+        let cipher = match header.crypt_method {
+            CRYPT_METHOD_NONE => None,
+            CRYPT_METHOD_LUKS => {
+                let passphrase = passphrase.ok_or(Error::MissingEncryptionKey)?;
+                if header.crypt_header_length == 0 {
+                    return Err(Error::InvalidCryptHeader);
+                }
+                Some(
+                    luks::unlock(&mut file, header.crypt_header_offset, passphrase)
+                        .map_err(Error::UnlockingImage)?,
+                )
+            }
+            method => return Err(Error::UnsupportedEncryption(method)),
+        };
+
+        let mut backing_file_id = None;
         let backing_file = if let Some(backing_file_path) = header.backing_file_path.as_ref() {
-            let path = backing_file_path.clone();
+            let path = match image_path {
+                Some(image_path) => backing_file_full_path(image_path, backing_file_path),
+                None => PathBuf::from(backing_file_path),
+            };
             let backing_raw_file = open_file_or_duplicate(
-                Path::new(&path),
+                &path,
                 OpenOptions::new().read(true), // TODO(b/190435784): Add support for O_DIRECT.
             )
             .map_err(|e| Error::BackingFileIo(e.into()))?;
+            backing_file_id = Some(file_id(&backing_raw_file).map_err(Error::BackingFileIo)?);
             // is_sparse_file is false because qcow is internally sparse and we don't need file
             // system sparseness on top of that.
             let backing_file = create_disk_file(
                 backing_raw_file,
                 /* is_sparse_file= */ false,
                 max_nesting_depth,
-                Path::new(&path),
+                &path,
             )
             .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
             Some(backing_file)
@@ -527,6 +948,7 @@ impl QcowFile {
 
         let mut raw_file =
             QcowRawFile::from(file, cluster_size).ok_or(Error::InvalidClusterSize)?;
+        let snapshots = QcowFile::read_snapshots(&mut raw_file, &header)?;
         if refcount_rebuild_required {
             QcowFile::rebuild_refcounts(&mut raw_file, header.clone())?;
         }
@@ -544,7 +966,7 @@ impl QcowFile {
                 .read_pointer_table(
                     header.l1_table_offset,
                     num_l2_clusters,
-                    Some(L1_TABLE_OFFSET_MASK),
+                    Some(L1_TABLE_OFFSET_MASK | CLUSTER_USED_FLAG),
                 )
                 .map_err(Error::ReadingHeader)?,
         );
@@ -585,6 +1007,10 @@ impl QcowFile {
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            backing_file_id,
+            decompressed_cluster: None,
+            snapshots,
+            cipher,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -605,7 +1031,7 @@ impl QcowFile {
     /// Creates a new QcowFile at the given path.
     pub fn new(file: File, virtual_size: u64) -> Result<QcowFile> {
         let header = QcowHeader::create_for_size_and_path(virtual_size, None)?;
-        QcowFile::new_from_header(file, header, 1)
+        QcowFile::new_from_header(file, None, header, 1)
     }
 
     /// Creates a new QcowFile at the given path.
@@ -614,37 +1040,75 @@ impl QcowFile {
         backing_file_name: &str,
         backing_file_max_nesting_depth: u32,
     ) -> Result<QcowFile> {
-        let backing_path = Path::new(backing_file_name);
+        QcowFile::create_from_backing(
+            file,
+            None,
+            backing_file_name,
+            backing_file_max_nesting_depth,
+        )
+    }
+
+    /// Creates a new QcowFile in `file`, the image at `image_path`, backed by `backing_file_name`.
+    /// A relative `backing_file_name` is resolved against the directory of `image_path`, and is
+    /// stored as is in the header.
+    pub fn new_from_backing_at(
+        file: File,
+        image_path: &Path,
+        backing_file_name: &str,
+        backing_file_max_nesting_depth: u32,
+    ) -> Result<QcowFile> {
+        QcowFile::create_from_backing(
+            file,
+            Some(image_path),
+            backing_file_name,
+            backing_file_max_nesting_depth,
+        )
+    }
+
+    fn create_from_backing(
+        file: File,
+        image_path: Option<&Path>,
+        backing_file_name: &str,
+        backing_file_max_nesting_depth: u32,
+    ) -> Result<QcowFile> {
+        let backing_path = match image_path {
+            Some(image_path) => backing_file_full_path(image_path, backing_file_name),
+            None => PathBuf::from(backing_file_name),
+        };
         let backing_raw_file = open_file_or_duplicate(
-            backing_path,
+            &backing_path,
             OpenOptions::new().read(true), // TODO(b/190435784): add support for O_DIRECT.
         )
         .map_err(|e| Error::BackingFileIo(e.into()))?;
+        let backing_file_id = file_id(&backing_raw_file).map_err(Error::BackingFileIo)?;
         // is_sparse_file is false because qcow is internally sparse and we don't need file
         // system sparseness on top of that.
         let backing_file = create_disk_file(
             backing_raw_file,
             /* is_sparse_file= */ false,
             backing_file_max_nesting_depth,
-            backing_path,
+            &backing_path,
         )
         .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
         let size = backing_file.get_len().map_err(Error::BackingFileIo)?;
         let header = QcowHeader::create_for_size_and_path(size, Some(backing_file_name))?;
-        let mut result = QcowFile::new_from_header(file, header, backing_file_max_nesting_depth)?;
+        let mut result =
+            QcowFile::new_from_header(file, image_path, header, backing_file_max_nesting_depth)?;
         result.backing_file = Some(backing_file);
+        result.backing_file_id = Some(backing_file_id);
         Ok(result)
     }
 
     fn new_from_header(
         mut file: File,
+        image_path: Option<&Path>,
         header: QcowHeader,
         max_nesting_depth: u32,
     ) -> Result<QcowFile> {
         file.seek(SeekFrom::Start(0)).map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
 
-        let mut qcow = Self::from(file, max_nesting_depth)?;
+        let mut qcow = Self::open(file, image_path, max_nesting_depth, None)?;
 
         // Set the refcount for each refcount table cluster.
         let cluster_size = 0x01u64 << qcow.header.cluster_bits;
@@ -657,15 +1121,471 @@ impl QcowFile {
             let mut unref_clusters = qcow
                 .set_cluster_refcount(cluster_addr, 1)
                 .map_err(Error::SettingRefcountRefcount)?;
-            qcow.unref_clusters.append(&mut unref_clusters);
+            // Nothing can depend on the on-disk state of a new image yet, so the clusters can be
+            // reused right away.
+            qcow.avail_clusters.append(&mut unref_clusters);
             cluster_addr += cluster_size;
         }
 
         Ok(qcow)
     }
 
+    /// Returns true if the data clusters of the image are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
     pub fn set_backing_file(&mut self, backing: Option<Box<dyn DiskFile>>) {
         self.backing_file = backing;
+        self.backing_file_id = None;
+    }
+
+    /// Merges the data stored in this image into its backing file, for example to shorten a chain
+    /// of overlays. `backing_file` must be a writable handle to the image's backing file, as the
+    /// one opened along with the image is read-only. The data is removed from this image once it
+    /// is safely in the backing file, and `backing_file` replaces the image's backing file.
+    /// `backing_file` is rejected if it isn't the same file as the backing file opened with the
+    /// image.
+    ///
+    /// Only raw and qcow2 backing files are supported. A qcow2 backing file is opened with
+    /// `max_nesting_depth`, which also opens its own backing files.
+    pub fn commit(&mut self, backing_file: File, max_nesting_depth: u32) -> Result<()> {
+        if self.backing_file.is_none() {
+            return Err(Error::NoBackingFile);
+        }
+        // Writing the clusters to any other file would lose them once they are removed from this
+        // image.
+        let id = file_id(&backing_file).map_err(Error::BackingFileIo)?;
+        if self.backing_file_id != Some(id) {
+            return Err(Error::IncorrectBackingFile);
+        }
+        let image_type = detect_image_type(&backing_file, false)
+            .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+        let backing_file: Box<dyn DiskFile> = match image_type {
+            ImageType::Raw => {
+                let mut backing_file = backing_file;
+                self.copy_clusters_to(&mut backing_file)
+                    .map_err(Error::CommittingData)?;
+                Box::new(backing_file)
+            }
+            ImageType::Qcow2 => {
+                let mut backing_file = QcowFile::from(backing_file, max_nesting_depth)
+                    .map_err(|e| Error::BackingFileOpen(Box::new(crate::Error::QcowError(e))))?;
+                self.copy_clusters_to(&mut backing_file)
+                    .map_err(Error::CommittingData)?;
+                Box::new(backing_file)
+            }
+            t => return Err(Error::UnsupportedBackingFileType(t)),
+        };
+        // Reads must see the data just written, not what was cached by the old backing file.
+        self.backing_file = Some(backing_file);
+        self.deallocate_all_clusters()
+            .map_err(Error::CommittingData)
+    }
+
+    /// Copies all data this image reads from its backing file into the image and removes the
+    /// reference to the backing file, so that the image no longer depends on it. Images with
+    /// internal snapshots are rejected, as the snapshots still read from the backing file.
+    pub fn stream(&mut self) -> Result<()> {
+        if self.backing_file.is_none() {
+            return Err(Error::NoBackingFile);
+        }
+        if !self.snapshots.is_empty() {
+            return Err(Error::StreamingWithSnapshots);
+        }
+        self.copy_clusters_from_backing()
+            .map_err(Error::StreamingData)?;
+
+        // All data is in this image now, drop the backing file from the header.
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(BACKING_FILE_OFFSET_OFFSET))
+            .map_err(Error::SeekingFile)?;
+        file.write_all(&[0u8; size_of::<u64>() + size_of::<u32>()])
+            .map_err(Error::WritingHeader)?;
+        file.sync_data().map_err(Error::WritingHeader)?;
+        self.header.backing_file_offset = 0;
+        self.header.backing_file_size = 0;
+        self.header.backing_file_path = None;
+        self.backing_file = None;
+        self.backing_file_id = None;
+        Ok(())
+    }
+
+    // Writes the data of every cluster allocated in this image to the same offset in `dest` and
+    // syncs it.
+    fn copy_clusters_to<F: FileReadWriteAtVolatile + FileSync>(
+        &mut self,
+        dest: &mut F,
+    ) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let mut buf = vec![0u8; cluster_size as usize];
+        for l1_index in 0..self.l1_table.len() {
+            let l2_table = match self.l2_table_entries(l1_index)? {
+                Some(l2_table) => l2_table,
+                None => continue,
+            };
+            for (l2_index, _) in l2_table.iter().enumerate().filter(|(_, &e)| e != 0) {
+                let address = self.cluster_address(l1_index, l2_index);
+                let count = self.limit_range_file(address, cluster_size as usize);
+                self.read_exact_at_volatile(VolatileSlice::new(&mut buf[..count]), address)?;
+                dest.write_all_at_volatile(VolatileSlice::new(&mut buf[..count]), address)?;
+            }
+        }
+        dest.fsync()
+    }
+
+    // Deallocates every cluster of the image, so that all reads are served by the backing file.
+    fn deallocate_all_clusters(&mut self) -> std::io::Result<()> {
+        for l1_index in 0..self.l1_table.len() {
+            let l2_table = match self.l2_table_entries(l1_index)? {
+                Some(l2_table) => l2_table,
+                None => continue,
+            };
+            for (l2_index, _) in l2_table.iter().enumerate().filter(|(_, &e)| e != 0) {
+                self.deallocate_cluster(self.cluster_address(l1_index, l2_index))?;
+            }
+        }
+        self.fsync()
+    }
+
+    // Writes the data of every cluster that is read from the backing file to this image, skipping
+    // clusters that are all zeros.
+    fn copy_clusters_from_backing(&mut self) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let mut buf = vec![0u8; cluster_size as usize];
+        for l1_index in 0..self.l1_table.len() {
+            // All the clusters covered by a missing L2 table are read from the backing file.
+            let l2_table = self
+                .l2_table_entries(l1_index)?
+                .unwrap_or_else(|| vec![0; self.l2_entries as usize]);
+            for (l2_index, _) in l2_table.iter().enumerate().filter(|(_, &e)| e == 0) {
+                let address = self.cluster_address(l1_index, l2_index);
+                if address >= self.virtual_size() {
+                    break;
+                }
+                let count = self.limit_range_file(address, cluster_size as usize);
+                let data = &mut buf[..count];
+                self.read_exact_at_volatile(VolatileSlice::new(data), address)?;
+                if data.iter().any(|&b| b != 0) {
+                    self.write_all_at_volatile(VolatileSlice::new(data), address)?;
+                }
+            }
+        }
+        self.fsync()
+    }
+
+    // Returns the entries of the L2 table referenced by the L1 entry at `l1_index`, or None if
+    // the L2 table isn't allocated.
+    fn l2_table_entries(&mut self, l1_index: usize) -> std::io::Result<Option<Vec<u64>>> {
+        let l2_addr = self.l1_table[l1_index] & L1_TABLE_OFFSET_MASK;
+        if l2_addr == 0 {
+            return Ok(None);
+        }
+        if let Some(l2_table) = self.l2_cache.get(&l1_index) {
+            return Ok(Some(l2_table.get_values().to_vec()));
+        }
+        Self::read_l2_cluster(&mut self.raw_file, l2_addr).map(Some)
+    }
+
+    // Returns the guest address of the cluster described by entry `l2_index` of the L2 table
+    // referenced by the L1 entry at `l1_index`.
+    fn cluster_address(&self, l1_index: usize, l2_index: usize) -> u64 {
+        (l1_index as u64 * self.l2_entries + l2_index as u64) * self.raw_file.cluster_size()
+    }
+
+    /// Returns the internal snapshots stored in the image.
+    pub fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.snapshots.iter().map(QcowSnapshot::info).collect()
+    }
+
+    /// Returns the size of the image's clusters in bytes.
+    pub fn cluster_size(&self) -> u64 {
+        self.raw_file.cluster_size()
+    }
+
+    /// Returns the path of the backing file as stored in the image header.
+    pub fn backing_file_path(&self) -> Option<&str> {
+        self.header.backing_file_path.as_deref()
+    }
+
+    /// Returns the number of guest clusters whose data is stored in this image, as opposed to
+    /// being read from the backing file or as zeros.
+    pub fn allocated_clusters(&mut self) -> Result<u64> {
+        self.sync_caches().map_err(Error::SyncingCaches)?;
+        let mut allocated = 0;
+        for &l2_addr in self.l1_table.get_values().iter().filter(|&&addr| addr != 0) {
+            let l2_table =
+                Self::read_l2_cluster(&mut self.raw_file, l2_addr & L1_TABLE_OFFSET_MASK)
+                    .map_err(Error::ReadingPointers)?;
+            allocated += l2_table.iter().filter(|&&entry| entry != 0).count() as u64;
+        }
+        Ok(allocated)
+    }
+
+    /// Compares the refcount of every cluster in the image with the number of references to it
+    /// from the image's tables. If `repair` is true and any of them don't match, the refcounts are
+    /// rebuilt from the references.
+    pub fn check(&mut self, repair: bool) -> Result<QcowCheckResult> {
+        self.sync_caches().map_err(Error::SyncingCaches)?;
+
+        let cluster_size = self.raw_file.cluster_size();
+        let file_size = self
+            .raw_file
+            .file_mut()
+            .metadata()
+            .map_err(Error::GettingFileSize)?
+            .len();
+        let num_clusters = div_round_up_u64(file_size, cluster_size);
+        if num_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyRefcounts(num_clusters));
+        }
+
+        let ref_table = self
+            .raw_file
+            .read_pointer_table(
+                self.header.refcount_table_offset,
+                u64::from(self.header.refcount_table_clusters) * cluster_size
+                    / size_of::<u64>() as u64,
+                None,
+            )
+            .map_err(Error::ReadingPointers)?;
+
+        let mut references = vec![0u16; num_clusters as usize];
+        QcowFile::count_references(&mut self.raw_file, &self.header, &mut references)?;
+        for &refblock_addr in ref_table.iter().filter(|&&addr| addr != 0) {
+            let index = (refblock_addr / cluster_size) as usize;
+            *references
+                .get_mut(index)
+                .ok_or(Error::InvalidClusterIndex)? += 1;
+        }
+
+        let mut result = QcowCheckResult::default();
+        let refcount_block_entries = self.refcounts.refcounts_per_block();
+        let mut refblock: Option<(u64, Vec<u16>)> = None;
+        for (index, &references) in references.iter().enumerate() {
+            let index = index as u64;
+            let refblock_addr = ref_table
+                .get((index / refcount_block_entries) as usize)
+                .copied()
+                .unwrap_or(0);
+            let refcount = if refblock_addr == 0 {
+                0
+            } else {
+                if refblock.as_ref().map(|(addr, _)| *addr) != Some(refblock_addr) {
+                    let block = self
+                        .raw_file
+                        .read_refcount_block(refblock_addr)
+                        .map_err(Error::ReadingRefCounts)?;
+                    refblock = Some((refblock_addr, block));
+                }
+                // Unwrap is safe as the block was read above.
+                refblock.as_ref().unwrap().1[(index % refcount_block_entries) as usize]
+            };
+            let mismatch = RefcountMismatch {
+                offset: index * cluster_size,
+                refcount,
+                references,
+            };
+            match refcount.cmp(&references) {
+                Ordering::Greater => result.leaks.push(mismatch),
+                Ordering::Less => result.errors.push(mismatch),
+                Ordering::Equal => (),
+            }
+        }
+
+        if repair && !result.is_clean() {
+            QcowFile::rebuild_refcounts(&mut self.raw_file, self.header.clone())?;
+            self.refcounts
+                .reload(&mut self.raw_file)
+                .map_err(Error::ReadingRefCounts)?;
+            self.rewrite_copied_flags()
+                .map_err(Error::RebuildingRefCounts)?;
+            self.unref_clusters.clear();
+            self.avail_clusters.clear();
+            self.find_avail_clusters()?;
+            result.repaired = true;
+        }
+
+        Ok(result)
+    }
+
+    /// Creates an internal snapshot named `name` of the current disk contents. The snapshot shares
+    /// all clusters with the active image, which copies them when they are modified.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(Error::InvalidSnapshotName);
+        }
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS as usize {
+            return Err(Error::TooManySnapshots(self.snapshots.len() as u32));
+        }
+
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .map_or(1, |id| id + 1)
+            .to_string();
+        self.create_snapshot_tables(id, name.to_string())
+            .map_err(Error::WritingSnapshots)
+    }
+
+    /// Reverts the disk contents to the internal snapshot with the given name or ID.
+    pub fn apply_snapshot(&mut self, name: &str) -> Result<()> {
+        let snapshot = self.snapshots[self.find_snapshot(name)?].clone();
+        if let Some(disk_size) = snapshot.disk_size() {
+            if disk_size != self.virtual_size() {
+                return Err(Error::SnapshotSizeMismatch(disk_size));
+            }
+        }
+        self.apply_snapshot_tables(&snapshot)
+            .map_err(Error::WritingSnapshots)
+    }
+
+    /// Deletes the internal snapshot with the given name or ID, freeing the clusters that only it
+    /// references.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        let index = self.find_snapshot(name)?;
+        self.delete_snapshot_tables(index)
+            .map_err(Error::WritingSnapshots)
+    }
+
+    // Returns the index of the snapshot matching `name`, looking at the IDs if no name matches.
+    fn find_snapshot(&self, name: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.name == name)
+            .or_else(|| self.snapshots.iter().position(|s| s.id == name))
+            .ok_or_else(|| Error::SnapshotNotFound(name.to_string()))
+    }
+
+    fn create_snapshot_tables(&mut self, id: String, name: String) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        // The snapshot gets a copy of the L1 table, and with it a reference to each of the L2
+        // tables and data clusters of the active image.
+        let l1_table: Vec<u64> = self
+            .l1_table
+            .get_values()
+            .iter()
+            .map(|entry| entry & L1_TABLE_OFFSET_MASK)
+            .collect();
+        self.add_tree_refcounts(&l1_table, 1)?;
+        let l1_table_offset = self.alloc_table_clusters(l1_table.len() * size_of::<u64>())?;
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)?;
+        self.rewrite_copied_flags()?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // Version 3 images require the VM state size and the disk size in the extra data.
+        let mut extra_data = 0u64.to_be_bytes().to_vec();
+        extra_data.extend_from_slice(&self.virtual_size().to_be_bytes());
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(QcowSnapshot {
+            l1_table_offset,
+            l1_size: l1_table.len() as u32,
+            id,
+            name,
+            date_sec: now.as_secs() as u32,
+            date_nsec: now.subsec_nanos(),
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            extra_data,
+        });
+        self.write_snapshot_table(snapshots)?;
+        self.sync_caches()
+    }
+
+    fn apply_snapshot_tables(&mut self, snapshot: &QcowSnapshot) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        // The snapshot's L1 table can be longer than needed for the disk, for example when it
+        // also holds the VM state. Only the part covering the disk is used.
+        let l1_entries = self.l1_table.len();
+        let mut l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            min(snapshot.l1_size as usize, l1_entries) as u64,
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        l1_table.resize(l1_entries, 0);
+
+        // Take the references for the new L1 table and point the image at it before dropping the
+        // references of the old one, so that clusters the image uses are never freed.
+        self.add_tree_refcounts(&l1_table, 1)?;
+        let old_l1_table = self.l1_table.get_values().to_vec();
+        write_table_with_copied_flags(
+            &mut self.raw_file,
+            &mut self.refcounts,
+            self.header.l1_table_offset,
+            &l1_table,
+        )?;
+        self.l1_table = VecCache::from_vec(l1_table);
+        // The cached tables are clean after the sync and no longer part of the image.
+        self.l2_cache.clear();
+        self.decompressed_cluster = None;
+        self.add_tree_refcounts(&old_l1_table, -1)?;
+        self.rewrite_copied_flags()?;
+        self.sync_caches()
+    }
+
+    fn delete_snapshot_tables(&mut self, index: usize) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        let mut snapshots = self.snapshots.clone();
+        let snapshot = snapshots.remove(index);
+        self.write_snapshot_table(snapshots)?;
+
+        // Nothing points at the snapshot's L1 table anymore, drop the references it holds.
+        let l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            snapshot.l1_size as u64,
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        self.add_tree_refcounts(&l1_table, -1)?;
+        self.free_table_clusters(
+            snapshot.l1_table_offset,
+            snapshot.l1_size as usize * size_of::<u64>(),
+        )?;
+        // Clusters that were only shared with the deleted snapshot can be modified in place.
+        self.rewrite_copied_flags()?;
+        self.sync_caches()
+    }
+
+    // Writes `snapshots` to a new snapshot table, points the header at it and frees the old one.
+    fn write_snapshot_table(&mut self, snapshots: Vec<QcowSnapshot>) -> std::io::Result<()> {
+        let table: Vec<u8> = snapshots.iter().flat_map(QcowSnapshot::to_bytes).collect();
+        let snapshots_offset = if table.is_empty() {
+            0
+        } else {
+            let offset = self.alloc_table_clusters(table.len())?;
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&table)?;
+            offset
+        };
+
+        // The table and everything it references must be on disk before the header points at it.
+        self.sync_caches()?;
+        let mut header_fields = (snapshots.len() as u32).to_be_bytes().to_vec();
+        header_fields.extend_from_slice(&snapshots_offset.to_be_bytes());
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(NB_SNAPSHOTS_OFFSET))?;
+        file.write_all(&header_fields)?;
+        file.sync_data()?;
+
+        let old_table_size = self.snapshots.iter().map(|s| s.to_bytes().len()).sum();
+        if old_table_size > 0 {
+            self.free_table_clusters(self.header.snapshots_offset, old_table_size)?;
+        }
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = snapshots_offset;
+        self.snapshots = snapshots;
+        Ok(())
     }
 
     /// Returns the first cluster in the file with a 0 refcount. Used for testing.
@@ -715,8 +1635,38 @@ impl QcowFile {
         Ok(())
     }
 
-    /// Rebuild the reference count tables.
-    fn rebuild_refcounts(raw_file: &mut QcowRawFile, header: QcowHeader) -> Result<()> {
+    // Reads the snapshot table that `header` points to.
+    fn read_snapshots(
+        raw_file: &mut QcowRawFile,
+        header: &QcowHeader,
+    ) -> Result<Vec<QcowSnapshot>> {
+        if header.nb_snapshots > MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots(header.nb_snapshots));
+        }
+        let file = raw_file.file_mut();
+        file.seek(SeekFrom::Start(header.snapshots_offset))
+            .map_err(Error::SeekingFile)?;
+        let mut snapshots = Vec::with_capacity(header.nb_snapshots as usize);
+        for _ in 0..header.nb_snapshots {
+            let snapshot = QcowSnapshot::read_from(file)?;
+            offset_is_cluster_boundary(snapshot.l1_table_offset, header.cluster_bits)?;
+            if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+                return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+            }
+            snapshots.push(snapshot);
+        }
+        Ok(snapshots)
+    }
+
+    // Adds a reference to `refcounts` for each cluster referenced by the image: the header, the
+    // LUKS header of encrypted images, the active and snapshot L1 tables along with the clusters
+    // reachable from them, the snapshot table and the refcount table. Refcount blocks are not
+    // included.
+    fn count_references(
+        raw_file: &mut QcowRawFile,
+        header: &QcowHeader,
+        refcounts: &mut [u16],
+    ) -> Result<()> {
         fn add_ref(refcounts: &mut [u16], cluster_size: u64, cluster_address: u64) -> Result<()> {
             let idx = (cluster_address / cluster_size) as usize;
             if idx >= refcounts.len() {
@@ -731,35 +1681,36 @@ impl QcowFile {
             add_ref(refcounts, cluster_size, 0)
         }
 
-        // Add references to the L1 table clusters.
+        // Add references to the clusters of the L1 table at `l1_table_offset`.
         fn set_l1_refcounts(
             refcounts: &mut [u16],
-            header: QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
         ) -> Result<()> {
-            let l1_clusters = div_round_up_u64(header.l1_size as u64, cluster_size);
-            let l1_table_offset = header.l1_table_offset;
+            let l1_clusters =
+                div_round_up_u64(l1_size as u64 * size_of::<u64>() as u64, cluster_size);
             for i in 0..l1_clusters {
                 add_ref(refcounts, cluster_size, l1_table_offset + i * cluster_size)?;
             }
             Ok(())
         }
 
-        // Traverse the L1 and L2 tables to find all reachable data clusters.
+        // Traverse the L1 table at `l1_table_offset` and its L2 tables to find all reachable data
+        // clusters. Every L1 table referencing an L2 table also holds a reference to each of the
+        // data clusters in it.
         fn set_data_refcounts(
             refcounts: &mut [u16],
-            header: QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
             raw_file: &mut QcowRawFile,
         ) -> Result<()> {
+            let cluster_bits = cluster_size.trailing_zeros();
             let l1_table = raw_file
-                .read_pointer_table(
-                    header.l1_table_offset,
-                    header.l1_size as u64,
-                    Some(L1_TABLE_OFFSET_MASK),
-                )
+                .read_pointer_table(l1_table_offset, l1_size as u64, Some(L1_TABLE_OFFSET_MASK))
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -770,12 +1721,21 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for l2_entry in l2_table {
+                        if l2_entry & COMPRESSED_FLAG != 0 {
+                            // Compressed data may span several host clusters, each of which
+                            // holds a reference for every compressed cluster stored in it.
+                            let (offset, size) = compressed_cluster_range(l2_entry, cluster_bits);
+                            let mut cluster_addr = offset - offset % cluster_size;
+                            while cluster_addr < offset + size {
+                                add_ref(refcounts, cluster_size, cluster_addr)?;
+                                cluster_addr += cluster_size;
+                            }
+                        } else if l2_entry & L2_TABLE_OFFSET_MASK != 0 {
+                            add_ref(refcounts, cluster_size, l2_entry & L2_TABLE_OFFSET_MASK)?;
                         }
                     }
                 }
@@ -784,6 +1744,38 @@ impl QcowFile {
             Ok(())
         }
 
+        // Add references to the snapshot table clusters.
+        fn set_snapshot_table_refcounts(
+            refcounts: &mut [u16],
+            snapshots_offset: u64,
+            snapshots: &[QcowSnapshot],
+            cluster_size: u64,
+        ) -> Result<()> {
+            let table_size: u64 = snapshots.iter().map(|s| s.to_bytes().len() as u64).sum();
+            for i in 0..div_round_up_u64(table_size, cluster_size) {
+                add_ref(refcounts, cluster_size, snapshots_offset + i * cluster_size)?;
+            }
+            Ok(())
+        }
+
+        // Add references to the clusters holding the LUKS header of encrypted images.
+        fn set_crypt_header_refcounts(
+            refcounts: &mut [u16],
+            header: &QcowHeader,
+            cluster_size: u64,
+        ) -> Result<()> {
+            let start = header.crypt_header_offset;
+            let end = start
+                .checked_add(header.crypt_header_length)
+                .ok_or(Error::InvalidCryptHeader)?;
+            let mut cluster_addr = start - start % cluster_size;
+            while cluster_addr < end {
+                add_ref(refcounts, cluster_size, cluster_addr)?;
+                cluster_addr += cluster_size;
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u16],
@@ -801,6 +1793,44 @@ impl QcowFile {
             Ok(())
         }
 
+        let cluster_size = raw_file.cluster_size();
+        set_header_refcount(refcounts, cluster_size)?;
+        set_crypt_header_refcounts(refcounts, header, cluster_size)?;
+        set_l1_refcounts(
+            refcounts,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+        )?;
+        set_data_refcounts(
+            refcounts,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+            raw_file,
+        )?;
+        let snapshots = QcowFile::read_snapshots(raw_file, header)?;
+        set_snapshot_table_refcounts(refcounts, header.snapshots_offset, &snapshots, cluster_size)?;
+        for snapshot in &snapshots {
+            set_l1_refcounts(
+                refcounts,
+                snapshot.l1_table_offset,
+                snapshot.l1_size,
+                cluster_size,
+            )?;
+            set_data_refcounts(
+                refcounts,
+                snapshot.l1_table_offset,
+                snapshot.l1_size,
+                cluster_size,
+                raw_file,
+            )?;
+        }
+        set_refcount_table_refcounts(refcounts, header.clone(), cluster_size)
+    }
+
+    /// Rebuild the reference count tables.
+    fn rebuild_refcounts(raw_file: &mut QcowRawFile, header: QcowHeader) -> Result<()> {
         // Allocate clusters for refblocks.
         // This needs to be done last so that we have the correct refcounts for all other
         // clusters.
@@ -825,7 +1855,7 @@ impl QcowFile {
                 }
 
                 *refblock_addr = first_free_cluster * cluster_size;
-                add_ref(refcounts, cluster_size, *refblock_addr)?;
+                refcounts[first_free_cluster as usize] += 1;
 
                 first_free_cluster += 1;
             }
@@ -932,10 +1962,7 @@ impl QcowFile {
         let mut refcounts = vec![0; max_valid_cluster_index as usize];
 
         // Find all references clusters and rebuild refcounts.
-        set_header_refcount(&mut refcounts, cluster_size)?;
-        set_l1_refcounts(&mut refcounts, header.clone(), cluster_size)?;
-        set_data_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
-        set_refcount_table_refcounts(&mut refcounts, header.clone(), cluster_size)?;
+        QcowFile::count_references(raw_file, &header, &mut refcounts)?;
 
         // Allocate clusters to store the new reference count blocks.
         let ref_table = alloc_refblocks(
@@ -991,9 +2018,9 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets the location of the data for the given guest address in the host file. If L1, L2, or
+    // data clusters have yet to be allocated, return `ClusterData::Unallocated`.
+    fn file_offset_read(&mut self, address: u64) -> std::io::Result<ClusterData> {
         if address >= self.virtual_size() {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1002,11 +2029,12 @@ impl QcowFile {
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?
+            & L1_TABLE_OFFSET_MASK;
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(ClusterData::Unallocated);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -1018,20 +2046,27 @@ impl QcowFile {
 
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let refcounts = &mut self.refcounts;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
+                write_table_with_copied_flags(
+                    raw_file,
+                    refcounts,
+                    l1_table[index] & L1_TABLE_OFFSET_MASK,
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
                 )
             })?;
         };
 
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            return Ok(None);
+        let l2_entry = self.l2_cache.get(&l1_index).unwrap()[l2_index];
+        if l2_entry == 0 {
+            return Ok(ClusterData::Unallocated);
+        }
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Ok(ClusterData::Compressed(l2_entry));
         }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        Ok(ClusterData::Offset(
+            (l2_entry & L2_TABLE_OFFSET_MASK) + self.raw_file.cluster_offset(address),
+        ))
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
@@ -1045,7 +2080,8 @@ impl QcowFile {
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?
+            & L1_TABLE_OFFSET_MASK;
         let l2_index = self.l2_table_index(address) as usize;
 
         let mut set_refcounts = Vec::new();
@@ -1058,18 +2094,20 @@ impl QcowFile {
                 let new_addr: u64 = self.get_new_cluster(None)?;
                 // The cluster refcount starts at one meaning it is used but doesn't need COW.
                 set_refcounts.push((new_addr, 1));
-                self.l1_table[l1_index] = new_addr;
+                self.l1_table[l1_index] = new_addr | CLUSTER_USED_FLAG;
                 VecCache::new(self.l2_entries as usize)
             } else {
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
             };
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let refcounts = &mut self.refcounts;
             self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
+                write_table_with_copied_flags(
+                    raw_file,
+                    refcounts,
+                    l1_table[index] & L1_TABLE_OFFSET_MASK,
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
                 )
             })?;
         }
@@ -1091,7 +2129,40 @@ impl QcowFile {
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
                 cluster_addr
             }
-            a => a,
+            l2_entry if l2_entry & COMPRESSED_FLAG != 0 => {
+                // Compressed clusters can't be modified in place. Copy the decompressed data to a
+                // new cluster and drop the reference to the compressed one.
+                let initial_data = self.decompress_cluster(l2_entry)?.to_vec();
+                let cluster_addr = self.append_data_cluster(Some(initial_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.unref_compressed_cluster(l2_entry)?;
+                cluster_addr
+            }
+            // Only clusters without the "copied" flag can be shared with a snapshot.
+            l2_entry if l2_entry & CLUSTER_USED_FLAG != 0 => l2_entry & L2_TABLE_OFFSET_MASK,
+            cluster_addr => {
+                let refcount = self
+                    .refcounts
+                    .get_cluster_refcount(&mut self.raw_file, cluster_addr)
+                    .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+                if refcount > 1 {
+                    // The cluster is shared with a snapshot. Write to a copy of it instead.
+                    let mut cluster_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                    let volatile_slice = VolatileSlice::new(&mut cluster_data);
+                    self.raw_file
+                        .file_mut()
+                        .read_exact_at_volatile(volatile_slice, cluster_addr)?;
+                    if let Some(cipher) = &self.cipher {
+                        cipher.decrypt(cluster_addr / SECTOR_SIZE, &mut cluster_data);
+                    }
+                    let new_addr = self.append_data_cluster(Some(cluster_data))?;
+                    self.update_cluster_addr(l1_index, l2_index, new_addr, &mut set_refcounts)?;
+                    set_refcounts.push((cluster_addr, refcount - 1));
+                    new_addr
+                } else {
+                    cluster_addr
+                }
+            }
         };
 
         for (addr, count) in set_refcounts {
@@ -1113,12 +2184,25 @@ impl QcowFile {
         if !self.l2_cache.get(&l1_index).unwrap().dirty() {
             // Free the previously used cluster if one exists. Modified tables are always
             // witten to new clusters so the L1 table can be committed to disk after they
-            // are and L1 never points at an invalid table.
+            // are and L1 never points at an invalid table. This also keeps tables shared with
+            // snapshots intact, they only lose the reference from the active L1 table.
             // The index must be valid from when it was insterted.
-            let addr = self.l1_table[l1_index];
-            if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+            let l1_entry = self.l1_table[l1_index];
+            if l1_entry != 0 {
+                let addr = l1_entry & L1_TABLE_OFFSET_MASK;
+                let refcount = if l1_entry & CLUSTER_USED_FLAG != 0 {
+                    1
+                } else {
+                    self.refcounts
+                        .get_cluster_refcount(&mut self.raw_file, addr)
+                        .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?
+                };
+                if refcount > 1 {
+                    set_refcounts.push((addr, refcount - 1));
+                } else {
+                    self.unref_clusters.push(addr);
+                    set_refcounts.push((addr, 0));
+                }
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1128,10 +2212,16 @@ impl QcowFile {
             // The cluster refcount starts at one indicating it is used but doesn't need
             // COW.
             set_refcounts.push((new_addr, 1));
-            self.l1_table[l1_index] = new_addr;
+            self.l1_table[l1_index] = new_addr | CLUSTER_USED_FLAG;
         }
+        // Clusters mapped here are newly allocated and not shared with anything.
+        let l2_entry = if cluster_addr == 0 {
+            0
+        } else {
+            cluster_addr | CLUSTER_USED_FLAG
+        };
         // 'unwrap' is OK because it was just added.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = cluster_addr;
+        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = l2_entry;
         Ok(())
     }
 
@@ -1162,7 +2252,18 @@ impl QcowFile {
     // Allocate and initialize a new data cluster. Returns the offset of the
     // cluster in to the file on success.
     fn append_data_cluster(&mut self, initial_data: Option<Vec<u8>>) -> std::io::Result<u64> {
-        let new_addr: u64 = self.get_new_cluster(initial_data)?;
+        let new_addr: u64 = if let Some(cipher) = self.cipher.clone() {
+            // The encryption depends on where the cluster ends up, and zeroes have to be
+            // encrypted too.
+            let mut data =
+                initial_data.unwrap_or_else(|| vec![0u8; self.raw_file.cluster_size() as usize]);
+            let new_addr = self.get_new_cluster(None)?;
+            cipher.encrypt(new_addr / SECTOR_SIZE, &mut data);
+            self.raw_file.write_cluster(new_addr, data)?;
+            new_addr
+        } else {
+            self.get_new_cluster(initial_data)?
+        };
         // The cluster refcount starts at one indicating it is used but doesn't need COW.
         let mut newly_unref = self.set_cluster_refcount(new_addr, 1)?;
         self.unref_clusters.append(&mut newly_unref);
@@ -1180,7 +2281,8 @@ impl QcowFile {
         let l2_addr_disk = *self
             .l1_table
             .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?
+            & L1_TABLE_OFFSET_MASK;
         let l2_index = self.l2_table_index(address) as usize;
 
         if l2_addr_disk == 0 {
@@ -1195,26 +2297,44 @@ impl QcowFile {
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let refcounts = &mut self.refcounts;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
+                write_table_with_copied_flags(
+                    raw_file,
+                    refcounts,
+                    l1_table[index] & L1_TABLE_OFFSET_MASK,
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
                 )
             })?;
         }
 
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
+        let l2_entry = self.l2_cache.get(&l1_index).unwrap()[l2_index];
+        if l2_entry == 0 {
             // This cluster is already unallocated; nothing to do.
             return Ok(());
         }
 
+        // Rewrite the L2 entry to remove the cluster mapping.
+        let mut set_refcounts = Vec::new();
+        self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return self.unref_compressed_cluster(l2_entry);
+        }
+
         // Decrement the refcount.
-        let refcount = self
-            .refcounts
-            .get_cluster_refcount(&mut self.raw_file, cluster_addr)
-            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let cluster_addr = l2_entry & L2_TABLE_OFFSET_MASK;
+        let refcount = if l2_entry & CLUSTER_USED_FLAG != 0 {
+            1
+        } else {
+            self.refcounts
+                .get_cluster_refcount(&mut self.raw_file, cluster_addr)
+                .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?
+        };
         if refcount == 0 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1223,10 +2343,6 @@ impl QcowFile {
         let mut newly_unref = self.set_cluster_refcount(cluster_addr, new_refcount)?;
         self.unref_clusters.append(&mut newly_unref);
 
-        // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
-
         if new_refcount == 0 {
             let cluster_size = self.raw_file.cluster_size();
             // This cluster is no longer in use; deallocate the storage.
@@ -1252,50 +2368,236 @@ impl QcowFile {
             let curr_addr = address + nwritten as u64;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            if self.backing_file.is_none() && count == self.raw_file.cluster_size() as usize {
-                // Full cluster and no backing file in use - deallocate the storage.
-                self.deallocate_cluster(curr_addr)?;
-            } else {
-                // Partial cluster - zero out the relevant bytes.
-                let offset = if self.backing_file.is_some() {
-                    // There is a backing file, so we need to allocate a cluster in order to
-                    // zero out the hole-punched bytes such that the backing file contents do not
-                    // show through.
-                    Some(self.file_offset_write(curr_addr)?)
+            if self.backing_file.is_none() && count == self.raw_file.cluster_size() as usize {
+                // Full cluster and no backing file in use - deallocate the storage.
+                self.deallocate_cluster(curr_addr)?;
+            } else {
+                // Partial cluster - zero out the relevant bytes.
+                let offset = if self.backing_file.is_some() {
+                    // There is a backing file, so we need to allocate a cluster in order to
+                    // zero out the hole-punched bytes such that the backing file contents do not
+                    // show through.
+                    Some(self.file_offset_write(curr_addr)?)
+                } else {
+                    match self.file_offset_read(curr_addr)? {
+                        // Any space in unallocated clusters can be left alone, since
+                        // unallocated clusters already read back as zeroes.
+                        ClusterData::Unallocated => None,
+                        // Compressed clusters and clusters shared with a snapshot have to be
+                        // copied before they can be modified.
+                        ClusterData::Offset(_) | ClusterData::Compressed(_) => {
+                            Some(self.file_offset_write(curr_addr)?)
+                        }
+                    }
+                };
+                if let Some(offset) = offset {
+                    // Partial cluster - zero it out.
+                    if let Some(cipher) = &self.cipher {
+                        cipher.write_at(self.raw_file.file_mut(), offset, &vec![0u8; count])?;
+                    } else {
+                        self.raw_file
+                            .file_mut()
+                            .write_zeroes_all_at(offset, count)?;
+                    }
+                }
+            }
+
+            nwritten += count;
+        }
+        Ok(())
+    }
+
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. Standard
+    // entries are reduced to the cluster offset and the "copied" flag, compressed entries are kept
+    // whole (minus the "copied" flag, which is never set for them) as all their bits are needed to
+    // find the data.
+    fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
+        let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
+        Ok(file_values
+            .iter()
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & (L2_TABLE_OFFSET_MASK | CLUSTER_USED_FLAG)
+                }
+            })
+            .collect())
+    }
+
+    // Reads and decompresses the cluster described by the compressed L2 entry `l2_entry`.
+    fn decompress_cluster(&mut self, l2_entry: u64) -> std::io::Result<&[u8]> {
+        // qemu never writes compressed clusters to encrypted images.
+        if self.cipher.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed cluster in an encrypted image",
+            ));
+        }
+        if !matches!(&self.decompressed_cluster, Some((entry, _)) if *entry == l2_entry) {
+            let (offset, size) = compressed_cluster_range(l2_entry, self.header.cluster_bits);
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            // The compressed data of the last cluster in the file can end before the sector that
+            // holds it does, so a short read is not an error.
+            let mut compressed = Vec::with_capacity(size as usize);
+            file.take(size).read_to_end(&mut compressed)?;
+
+            let mut cluster = vec![0u8; self.raw_file.cluster_size() as usize];
+            match self.header.compression_type {
+                COMPRESSION_TYPE_ZLIB => {
+                    // Raw deflate data, without a zlib header.
+                    let mut decompress = flate2::Decompress::new(false);
+                    decompress
+                        .decompress(&compressed, &mut cluster, flate2::FlushDecompress::Finish)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    if decompress.total_out() != cluster.len() as u64 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "short compressed cluster",
+                        ));
+                    }
+                }
+                COMPRESSION_TYPE_ZSTD => {
+                    let mut decoder = ruzstd::StreamingDecoder::new(compressed.as_slice())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                    decoder.read_exact(&mut cluster)?;
+                }
+                // Validated when the file is opened.
+                _ => return Err(std::io::Error::from_raw_os_error(ENOTSUP)),
+            }
+            self.decompressed_cluster = Some((l2_entry, cluster));
+        }
+        // unwrap is safe as the cluster was just decompressed if it wasn't there already.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Drops the references a compressed cluster holds on the host clusters storing its data.
+    // Host clusters that are left unreferenced are freed.
+    fn unref_compressed_cluster(&mut self, l2_entry: u64) -> std::io::Result<()> {
+        if matches!(&self.decompressed_cluster, Some((entry, _)) if *entry == l2_entry) {
+            self.decompressed_cluster = None;
+        }
+        self.add_compressed_cluster_refcount(l2_entry, -1)
+    }
+
+    // Adds `addend` to the refcounts of the host clusters storing the data of the compressed L2
+    // entry `l2_entry`.
+    fn add_compressed_cluster_refcount(&mut self, l2_entry: u64, addend: i32) -> io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let (offset, size) = compressed_cluster_range(l2_entry, self.header.cluster_bits);
+        let mut cluster_addr = offset - self.raw_file.cluster_offset(offset);
+        while cluster_addr < offset + size {
+            self.add_cluster_refcount(cluster_addr, addend)?;
+            cluster_addr += cluster_size;
+        }
+        Ok(())
+    }
+
+    // Adds `addend` to the refcount of the cluster at `address`. The cluster is freed if its
+    // refcount drops to zero.
+    fn add_cluster_refcount(&mut self, address: u64, addend: i32) -> io::Result<()> {
+        let refcount = self
+            .refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let new_refcount = u16::try_from(i32::from(refcount) + addend)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let mut newly_unref = self.set_cluster_refcount(address, new_refcount)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if new_refcount == 0 {
+            self.unref_clusters.push(address);
+        }
+        Ok(())
+    }
+
+    // Adds `addend` to the refcounts of the L2 tables referenced by `l1_table` and of the data
+    // clusters they reference. The L2 tables must be up to date on disk.
+    fn add_tree_refcounts(&mut self, l1_table: &[u64], addend: i32) -> std::io::Result<()> {
+        for &l1_entry in l1_table.iter().filter(|&&entry| entry != 0) {
+            let l2_addr = l1_entry & L1_TABLE_OFFSET_MASK;
+            let l2_table = Self::read_l2_cluster(&mut self.raw_file, l2_addr)?;
+            for &l2_entry in l2_table.iter().filter(|&&entry| entry != 0) {
+                if l2_entry & COMPRESSED_FLAG != 0 {
+                    self.add_compressed_cluster_refcount(l2_entry, addend)?;
                 } else {
-                    // Any space in unallocated clusters can be left alone, since
-                    // unallocated clusters already read back as zeroes.
-                    self.file_offset_read(curr_addr)?
-                };
-                if let Some(offset) = offset {
-                    // Partial cluster - zero it out.
-                    self.raw_file
-                        .file_mut()
-                        .write_zeroes_all_at(offset, count)?;
+                    self.add_cluster_refcount(l2_entry & L2_TABLE_OFFSET_MASK, addend)?;
                 }
             }
+            self.add_cluster_refcount(l2_addr, addend)?;
+        }
+        Ok(())
+    }
 
-            nwritten += count;
+    // Rewrites the active L1 table and its L2 tables so that their "copied" flags match the
+    // current refcounts. The L2 tables must be up to date on disk.
+    fn rewrite_copied_flags(&mut self) -> std::io::Result<()> {
+        // The flags held in memory may be stale too. The cached tables are clean, drop them so
+        // that they are read again with the new flags.
+        self.l2_cache.clear();
+        let l1_table: Vec<u64> = self
+            .l1_table
+            .get_values()
+            .iter()
+            .map(|entry| entry & L1_TABLE_OFFSET_MASK)
+            .collect();
+        for &l2_addr in l1_table.iter().filter(|&&addr| addr != 0) {
+            let l2_table: Vec<u64> = Self::read_l2_cluster(&mut self.raw_file, l2_addr)?
+                .iter()
+                .map(|entry| entry & !CLUSTER_USED_FLAG)
+                .collect();
+            write_table_with_copied_flags(
+                &mut self.raw_file,
+                &mut self.refcounts,
+                l2_addr,
+                &l2_table,
+            )?;
         }
+        let l1_table = with_copied_flags(&mut self.raw_file, &mut self.refcounts, &l1_table)?;
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)?;
+        self.l1_table = VecCache::from_vec(l1_table);
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
-    fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
-        let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
+    // Allocates contiguous clusters to hold a table of `size` bytes and returns the offset of the
+    // first one.
+    fn alloc_table_clusters(&mut self, size: usize) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let count = div_round_up_u64(size as u64, cluster_size);
+        let offset = if count == 1 {
+            self.get_new_cluster(None)?
+        } else {
+            // Freed clusters are scattered around the file, put larger tables at its end.
+            let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+            let mut clusters = Vec::new();
+            for _ in 0..count {
+                match self.raw_file.add_cluster_end(max_valid_cluster_offset)? {
+                    Some(cluster) => clusters.push(cluster),
+                    None => return Err(std::io::Error::from_raw_os_error(ENOSPC)),
+                }
+            }
+            clusters[0]
+        };
+        for i in 0..count {
+            self.add_cluster_refcount(offset + i * cluster_size, 1)?;
         }
-        Ok(file_values
-            .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
-            .collect())
+        Ok(offset)
+    }
+
+    // Drops the reference to each of the clusters holding the table of `size` bytes at `offset`.
+    fn free_table_clusters(&mut self, offset: u64, size: usize) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..div_round_up_u64(size as u64, cluster_size) {
+            self.add_cluster_refcount(offset + i * cluster_size, -1)?;
+        }
+        Ok(())
     }
 
     // Set the refcount for a cluster with the given address.
     // Returns a list of any refblocks that can be reused, this happens when a refblock is moved,
-    // the old location can be reused.
+    // the old location can be reused. The refcount of the old location is set to 0, as nothing
+    // references it anymore.
     fn set_cluster_refcount(&mut self, address: u64, refcount: u16) -> std::io::Result<Vec<u64>> {
         let mut added_clusters = Vec::new();
         let mut unref_clusters = Vec::new();
@@ -1345,7 +2647,13 @@ impl QcowFile {
         }
 
         for addr in added_clusters {
-            self.set_cluster_refcount(addr, 1)?;
+            let mut newly_unref = self.set_cluster_refcount(addr, 1)?;
+            unref_clusters.append(&mut newly_unref);
+        }
+        // The refblocks that were moved are no longer referenced by the refcount table.
+        for addr in unref_clusters.clone() {
+            let mut newly_unref = self.set_cluster_refcount(addr, 0)?;
+            unref_clusters.append(&mut newly_unref);
         }
         Ok(unref_clusters)
     }
@@ -1354,12 +2662,13 @@ impl QcowFile {
         // Write out all dirty L2 tables.
         for (l1_index, l2_table) in self.l2_cache.iter_mut().filter(|(_k, v)| v.dirty()) {
             // The index must be valid from when we insterted it.
-            let addr = self.l1_table[*l1_index];
+            let addr = self.l1_table[*l1_index] & L1_TABLE_OFFSET_MASK;
             if addr != 0 {
-                self.raw_file.write_pointer_table(
+                write_table_with_copied_flags(
+                    &mut self.raw_file,
+                    &mut self.refcounts,
                     addr,
                     l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
                 )?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
@@ -1375,10 +2684,11 @@ impl QcowFile {
         // guaranteed to be valid.
         let mut sync_required = false;
         if self.l1_table.dirty() {
-            self.raw_file.write_pointer_table(
+            write_table_with_copied_flags(
+                &mut self.raw_file,
+                &mut self.refcounts,
                 self.header.l1_table_offset,
                 self.l1_table.get_values(),
-                0,
             )?;
             self.l1_table.mark_clean();
             sync_required = true;
@@ -1391,12 +2701,11 @@ impl QcowFile {
     }
 
     // Reads `count` bytes starting at `address`, calling `cb` repeatedly with the data source,
-    // number of bytes read so far, offset to read from, and number of bytes to read from the file
-    // in that invocation. If None is given to `cb` in place of the backing file, the `cb` should
-    // infer zeros would have been read.
+    // number of bytes read so far, and number of bytes to read from the source in that
+    // invocation.
     fn read_cb<F>(&mut self, address: u64, count: usize, mut cb: F) -> std::io::Result<usize>
     where
-        F: FnMut(Option<&mut dyn DiskFile>, usize, u64, usize) -> std::io::Result<()>,
+        F: FnMut(ReadSource, usize, usize) -> std::io::Result<()>,
     {
         let read_count: usize = self.limit_range_file(address, count);
 
@@ -1406,12 +2715,33 @@ impl QcowFile {
             let file_offset = self.file_offset_read(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
-                cb(Some(self.raw_file.file_mut()), nread, offset, count)?;
-            } else if let Some(backing) = self.backing_file.as_mut() {
-                cb(Some(backing.as_mut()), nread, curr_addr, count)?;
-            } else {
-                cb(None, nread, 0, count)?;
+            match file_offset {
+                ClusterData::Offset(offset) => {
+                    if let Some(cipher) = &self.cipher {
+                        let mut data = vec![0u8; count];
+                        cipher.read_at(self.raw_file.file_mut(), offset, &mut data)?;
+                        cb(ReadSource::Buffer(&data), nread, count)?;
+                    } else {
+                        cb(
+                            ReadSource::File(self.raw_file.file_mut(), offset),
+                            nread,
+                            count,
+                        )?;
+                    }
+                }
+                ClusterData::Compressed(l2_entry) => {
+                    let cluster_offset = self.raw_file.cluster_offset(curr_addr) as usize;
+                    let cluster = self.decompress_cluster(l2_entry)?;
+                    let data = &cluster[cluster_offset..cluster_offset + count];
+                    cb(ReadSource::Buffer(data), nread, count)?;
+                }
+                ClusterData::Unallocated => {
+                    if let Some(backing) = self.backing_file.as_mut() {
+                        cb(ReadSource::File(backing.as_mut(), curr_addr), nread, count)?;
+                    } else {
+                        cb(ReadSource::Zeros, nread, count)?;
+                    }
+                }
             }
 
             nread += count;
@@ -1462,20 +2792,11 @@ impl Read for QcowFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = buf.len();
         let slice = VolatileSlice::new(buf);
-        let read_count = self.read_cb(
-            self.current_offset,
-            len,
-            |file, already_read, offset, count| {
+        let read_count =
+            self.read_cb(self.current_offset, len, |source, already_read, count| {
                 let sub_slice = slice.get_slice(already_read, count).unwrap();
-                match file {
-                    Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                    None => {
-                        sub_slice.write_bytes(0);
-                        Ok(())
-                    }
-                }
-            },
-        )?;
+                source.read_to(sub_slice)
+            })?;
         self.current_offset += read_count as u64;
         Ok(read_count)
     }
@@ -1515,12 +2836,17 @@ impl Seek for QcowFile {
 
 impl Write for QcowFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let cipher = self.cipher.clone();
         let write_count = self.write_cb(
             self.current_offset,
             buf.len(),
             |file, offset, raw_offset, count| {
+                let data = &buf[offset..(offset + count)];
+                if let Some(cipher) = &cipher {
+                    return cipher.write_at(file, raw_offset, data);
+                }
                 file.seek(SeekFrom::Start(raw_offset))?;
-                file.write_all(&buf[offset..(offset + count)])
+                file.write_all(data)
             },
         )?;
         self.current_offset += write_count as u64;
@@ -1534,21 +2860,21 @@ impl Write for QcowFile {
 
 impl FileReadWriteAtVolatile for QcowFile {
     fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
-        self.read_cb(offset, slice.size(), |file, read, offset, count| {
+        self.read_cb(offset, slice.size(), |source, read, count| {
             let sub_slice = slice.get_slice(read, count).unwrap();
-            match file {
-                Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                None => {
-                    sub_slice.write_bytes(0);
-                    Ok(())
-                }
-            }
+            source.read_to(sub_slice)
         })
     }
 
     fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let cipher = self.cipher.clone();
         self.write_cb(offset, slice.size(), |file, offset, raw_offset, count| {
             let sub_slice = slice.get_slice(offset, count).unwrap();
+            if let Some(cipher) = &cipher {
+                let mut data = vec![0u8; count];
+                sub_slice.copy_to(&mut data);
+                return cipher.write_at(file, raw_offset, &data);
+            }
             file.write_all_at_volatile(sub_slice, raw_offset)
         })
     }
@@ -1630,6 +2956,57 @@ fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     Ok(())
 }
 
+// Returns the L1 or L2 `table` with the "copied" flag set on the entries for clusters that aren't
+// shared with a snapshot, which tells other qcow2 implementations that the cluster can be modified
+// in place. The refcount is only looked up for entries that don't have the flag already.
+fn with_copied_flags(
+    raw_file: &mut QcowRawFile,
+    refcounts: &mut RefCount,
+    table: &[u64],
+) -> io::Result<Vec<u64>> {
+    let mut entries = Vec::with_capacity(table.len());
+    for &entry in table {
+        let copied = entry & CLUSTER_USED_FLAG != 0
+            || (entry != 0
+                && entry & COMPRESSED_FLAG == 0
+                && refcounts
+                    .get_cluster_refcount(raw_file, entry)
+                    .map_err(|_| io::Error::from_raw_os_error(EINVAL))?
+                    == 1);
+        entries.push(if copied {
+            entry | CLUSTER_USED_FLAG
+        } else {
+            entry
+        });
+    }
+    Ok(entries)
+}
+
+// Writes the L1 or L2 `table` to `offset` in `raw_file`, with the "copied" flags set.
+fn write_table_with_copied_flags(
+    raw_file: &mut QcowRawFile,
+    refcounts: &mut RefCount,
+    offset: u64,
+    table: &[u64],
+) -> io::Result<()> {
+    let entries = with_copied_flags(raw_file, refcounts, table)?;
+    raw_file.write_pointer_table(offset, &entries, 0)
+}
+
+// Returns the offset and size in the raw file of the compressed data described by the compressed L2
+// entry `l2_entry`.
+fn compressed_cluster_range(l2_entry: u64, cluster_bits: u32) -> (u64, u64) {
+    // The host offset is stored in the low bits and is followed by the number of additional
+    // sectors used by the compressed data. How many bits each field uses depends on the cluster
+    // size.
+    let csize_shift = 62 - (cluster_bits - 8);
+    let csize_mask = (1u64 << (cluster_bits - 8)) - 1;
+    let offset = l2_entry & ((1u64 << csize_shift) - 1);
+    let sectors = ((l2_entry >> csize_shift) & csize_mask) + 1;
+    let size = sectors * COMPRESSED_SECTOR_SIZE - offset % COMPRESSED_SECTOR_SIZE;
+    (offset, size)
+}
+
 // Ceiling of the division of `dividend`/`divisor`.
 fn div_round_up_u64(dividend: u64, divisor: u64) -> u64 {
     dividend / divisor + u64::from(dividend % divisor != 0)
@@ -1726,6 +3103,54 @@ mod tests {
         testfn(qcow_file); // File closed when the function exits.
     }
 
+    // Creates a qcow file whose first cluster is stored compressed as `compressed`, using the given
+    // compression type. Returns the file and the host offset of the compressed data.
+    fn compressed_file(compression_type: u8, compressed: &[u8]) -> (File, u64) {
+        let mut disk_file = tempfile().expect("failed to create temp file");
+        let (l2_addr, data_addr) = {
+            let mut q = QcowFile::new(disk_file.try_clone().unwrap(), 0x10_0000).unwrap();
+            // Allocate an L2 table and a data cluster to hold the compressed data.
+            write_all_at(&mut q, &[0xffu8], 0).expect("Failed to write.");
+            q.fsync().unwrap();
+            let data_addr = match q.file_offset_read(0).unwrap() {
+                ClusterData::Offset(offset) => offset,
+                _ => panic!("cluster not allocated"),
+            };
+            (q.l1_table[0] & L1_TABLE_OFFSET_MASK, data_addr)
+        };
+
+        disk_file.seek(SeekFrom::Start(data_addr)).unwrap();
+        disk_file.write_all(compressed).unwrap();
+        let sectors = div_round_up_u64(compressed.len() as u64, COMPRESSED_SECTOR_SIZE);
+        let l2_entry =
+            COMPRESSED_FLAG | ((sectors - 1) << (62 - (DEFAULT_CLUSTER_BITS - 8))) | data_addr;
+        disk_file.seek(SeekFrom::Start(l2_addr)).unwrap();
+        disk_file.write_all(&l2_entry.to_be_bytes()).unwrap();
+
+        if compression_type != COMPRESSION_TYPE_ZLIB {
+            // Extend the header to include the compression type.
+            disk_file.seek(SeekFrom::Start(100)).unwrap();
+            disk_file.write_all(&112u32.to_be_bytes()).unwrap();
+            disk_file.write_all(&[compression_type]).unwrap();
+        }
+        disk_file.seek(SeekFrom::Start(0)).unwrap();
+        (disk_file, data_addr)
+    }
+
+    // Data stored in the compressed cluster of `compressed_file`.
+    fn compressed_cluster_data() -> Vec<u8> {
+        (0..1u32 << DEFAULT_CLUSTER_BITS)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    fn deflate_cluster_data() -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&compressed_cluster_data()).unwrap();
+        encoder.finish().unwrap()
+    }
+
     // Test helper function to convert a normal slice to a VolatileSlice and write it.
     fn write_all_at(qcow: &mut QcowFile, data: &[u8], offset: u64) -> std::io::Result<()> {
         let mut mem = data.to_owned();
@@ -2447,6 +3872,591 @@ mod tests {
         });
     }
 
+    #[test]
+    fn read_compressed_zlib() {
+        let (disk_file, _) = compressed_file(COMPRESSION_TYPE_ZLIB, &deflate_cluster_data());
+        let mut q = QcowFile::from(disk_file, MAX_NESTING_DEPTH).unwrap();
+        let expected = compressed_cluster_data();
+        let mut buf = vec![0u8; expected.len()];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, expected);
+        // Reads within the compressed cluster.
+        let mut buf = [0u8; 16];
+        read_exact_at(&mut q, &mut buf, 0x1234).expect("Failed to read.");
+        assert_eq!(buf, expected[0x1234..0x1244]);
+        // The following cluster is unallocated.
+        read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+        assert_eq!(buf, [0u8; 16]);
+    }
+
+    #[test]
+    fn read_compressed_zstd() {
+        let data = compressed_cluster_data();
+        // A zstd frame holding the data as a single raw block.
+        let mut frame = vec![
+            0x28, 0xb5, 0x2f, 0xfd, // magic
+            0x60, // frame header descriptor: single segment, two byte content size
+            0x00, 0xff, // content size - 256
+            0x01, 0x00, 0x08, // last raw block of 0x10000 bytes
+        ];
+        frame.extend_from_slice(&data);
+        let (disk_file, _) = compressed_file(COMPRESSION_TYPE_ZSTD, &frame);
+        let mut q = QcowFile::from(disk_file, MAX_NESTING_DEPTH).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn invalid_compression_type() {
+        let (disk_file, _) = compressed_file(2, &deflate_cluster_data());
+        QcowFile::from(disk_file, MAX_NESTING_DEPTH).expect_err("Invalid compression type worked.");
+    }
+
+    #[test]
+    fn write_compressed_cluster() {
+        let (disk_file, data_addr) =
+            compressed_file(COMPRESSION_TYPE_ZLIB, &deflate_cluster_data());
+        let mut q = QcowFile::from(disk_file, MAX_NESTING_DEPTH).unwrap();
+        write_all_at(&mut q, b"test", 0x100).expect("Failed to write.");
+        let mut expected = compressed_cluster_data();
+        expected[0x100..0x104].copy_from_slice(b"test");
+        let mut buf = vec![0u8; expected.len()];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, expected);
+        // The compressed data is no longer referenced.
+        assert!(matches!(
+            q.file_offset_read(0).unwrap(),
+            ClusterData::Offset(_)
+        ));
+        let refcount = q
+            .refcounts
+            .get_cluster_refcount(&mut q.raw_file, data_addr)
+            .unwrap();
+        assert_eq!(refcount, 0);
+    }
+
+    #[test]
+    fn write_zeroes_compressed_cluster() {
+        let (disk_file, _) = compressed_file(COMPRESSION_TYPE_ZLIB, &deflate_cluster_data());
+        let mut q = QcowFile::from(disk_file, MAX_NESTING_DEPTH).unwrap();
+        // Zeroing part of the cluster keeps the rest of the data.
+        q.write_zeroes_all_at(0x200, 0x200)
+            .expect("Failed to write zeroes.");
+        let mut expected = compressed_cluster_data();
+        expected[0x200..0x400].fill(0);
+        let mut buf = vec![0u8; expected.len()];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn rebuild_refcounts_compressed() {
+        let (mut disk_file, data_addr) =
+            compressed_file(COMPRESSION_TYPE_ZLIB, &deflate_cluster_data());
+        let header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+        let mut raw_file = QcowRawFile::from(disk_file, 1 << DEFAULT_CLUSTER_BITS)
+            .expect("Failed to create QcowRawFile.");
+        QcowFile::rebuild_refcounts(&mut raw_file, header).expect("Failed to rebuild recounts.");
+        let mut q =
+            QcowFile::from(raw_file.file().try_clone().unwrap(), MAX_NESTING_DEPTH).unwrap();
+        let refcount = q
+            .refcounts
+            .get_cluster_refcount(&mut q.raw_file, data_addr)
+            .unwrap();
+        assert_eq!(refcount, 1);
+    }
+
+    // Returns the host offset of the data cluster holding `address`.
+    fn data_cluster_addr(qcow: &mut QcowFile, address: u64) -> u64 {
+        match qcow.file_offset_read(address).unwrap() {
+            ClusterData::Offset(offset) => offset - qcow.raw_file.cluster_offset(offset),
+            _ => panic!("cluster not allocated"),
+        }
+    }
+
+    fn cluster_refcount(qcow: &mut QcowFile, address: u64) -> u16 {
+        qcow.refcounts
+            .get_cluster_refcount(&mut qcow.raw_file, address)
+            .unwrap()
+    }
+
+    #[test]
+    fn snapshot_create_list() {
+        let disk_file = tempfile().expect("failed to create temp file");
+        let mut q = QcowFile::new(disk_file.try_clone().unwrap(), 0x10_0000).unwrap();
+        q.create_snapshot("first")
+            .expect("Failed to create snapshot.");
+        q.create_snapshot("second")
+            .expect("Failed to create snapshot.");
+        q.create_snapshot("first")
+            .expect_err("Duplicate snapshot name worked.");
+        q.create_snapshot("")
+            .expect_err("Empty snapshot name worked.");
+        drop(q);
+
+        let q = QcowFile::from(disk_file, MAX_NESTING_DEPTH).unwrap();
+        let snapshots = q.snapshots();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].id, "1");
+        assert_eq!(snapshots[0].name, "first");
+        assert_eq!(snapshots[1].id, "2");
+        assert_eq!(snapshots[1].name, "second");
+        assert_eq!(snapshots[1].vm_state_size, 0);
+        assert_eq!(snapshots[1].disk_size, Some(0x10_0000));
+    }
+
+    #[test]
+    fn snapshot_apply() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0x1_0000).expect("Failed to write.");
+            q.create_snapshot("snap")
+                .expect("Failed to create snapshot.");
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0x1_0000).expect("Failed to write.");
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0x4_0000).expect("Failed to write.");
+            let mut buf = [0u8; 0x1000];
+            read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0xaa));
+
+            q.apply_snapshot("snap").expect("Failed to apply snapshot.");
+            read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0x55));
+            read_exact_at(&mut q, &mut buf, 0x4_0000).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0));
+
+            // Writing after reverting doesn't modify the snapshot.
+            write_all_at(&mut q, &[0x77u8; 0x1000], 0x1_0000).expect("Failed to write.");
+            q.apply_snapshot("1").expect("Failed to apply snapshot.");
+            read_exact_at(&mut q, &mut buf, 0x1_0000).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0x55));
+            q.apply_snapshot("missing")
+                .expect_err("Applying a missing snapshot worked.");
+        });
+    }
+
+    #[test]
+    fn snapshot_write_zeroes() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x2_0000], 0).expect("Failed to write.");
+            q.create_snapshot("snap")
+                .expect("Failed to create snapshot.");
+            // Deallocates the first cluster and zeroes part of the second.
+            q.write_zeroes_all_at(0, 0x1_1000)
+                .expect("Failed to write zeroes.");
+            let mut buf = vec![0u8; 0x2_0000];
+            read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+            assert!(buf[..0x1_1000].iter().all(|b| *b == 0));
+            assert!(buf[0x1_1000..].iter().all(|b| *b == 0x55));
+
+            q.apply_snapshot("snap").expect("Failed to apply snapshot.");
+            read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0x55));
+        });
+    }
+
+    #[test]
+    fn snapshot_delete() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            q.create_snapshot("snap")
+                .expect("Failed to create snapshot.");
+            let data_addr = data_cluster_addr(&mut q, 0);
+            assert_eq!(cluster_refcount(&mut q, data_addr), 2);
+            // Shared clusters don't have the copied flag.
+            let l2_table = q
+                .raw_file
+                .read_pointer_cluster(q.l1_table[0] & L1_TABLE_OFFSET_MASK, None)
+                .unwrap();
+            assert_eq!(l2_table[0], data_addr);
+
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0).expect("Failed to write.");
+            let new_data_addr = data_cluster_addr(&mut q, 0);
+            assert_ne!(new_data_addr, data_addr);
+            assert_eq!(cluster_refcount(&mut q, data_addr), 1);
+            assert_eq!(cluster_refcount(&mut q, new_data_addr), 1);
+
+            q.delete_snapshot("snap")
+                .expect("Failed to delete snapshot.");
+            assert!(q.snapshots().is_empty());
+            assert_eq!(cluster_refcount(&mut q, data_addr), 0);
+            let mut buf = [0u8; 0x1000];
+            read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0xaa));
+            q.delete_snapshot("snap")
+                .expect_err("Deleting a missing snapshot worked.");
+        });
+    }
+
+    #[test]
+    fn snapshot_delete_shared() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            q.create_snapshot("snap")
+                .expect("Failed to create snapshot.");
+            q.delete_snapshot("snap")
+                .expect("Failed to delete snapshot.");
+            // The cluster is no longer shared and can be written in place again.
+            let data_addr = data_cluster_addr(&mut q, 0);
+            assert_eq!(cluster_refcount(&mut q, data_addr), 1);
+            let l2_table = q
+                .raw_file
+                .read_pointer_cluster(q.l1_table[0] & L1_TABLE_OFFSET_MASK, None)
+                .unwrap();
+            assert_eq!(l2_table[0], data_addr | CLUSTER_USED_FLAG);
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0).expect("Failed to write.");
+            assert_eq!(data_cluster_addr(&mut q, 0), data_addr);
+        });
+    }
+
+    #[test]
+    fn copied_flag_decides_cow() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            q.fsync().unwrap();
+            let data_addr = data_cluster_addr(&mut q, 0);
+            let l2_addr = q.l1_table[0] & L1_TABLE_OFFSET_MASK;
+            q.set_cluster_refcount(data_addr, 2).unwrap();
+
+            // The refcount isn't looked at while the entry has the "copied" flag.
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0).expect("Failed to write.");
+            assert_eq!(data_cluster_addr(&mut q, 0), data_addr);
+
+            // Without the flag, the refcount shows that the cluster is shared.
+            q.sync_caches().unwrap();
+            q.raw_file
+                .write_pointer_table(l2_addr, &[data_addr], 0)
+                .unwrap();
+            q.l2_cache.clear();
+            write_all_at(&mut q, &[0x33u8; 0x1000], 0).expect("Failed to write.");
+            assert_ne!(data_cluster_addr(&mut q, 0), data_addr);
+            assert_eq!(cluster_refcount(&mut q, data_addr), 1);
+        });
+    }
+
+    #[test]
+    fn rebuild_refcounts_snapshot() {
+        let mut disk_file = tempfile().expect("failed to create temp file");
+        let data_addr = {
+            let mut q = QcowFile::new(disk_file.try_clone().unwrap(), 0x10_0000).unwrap();
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            q.create_snapshot("snap")
+                .expect("Failed to create snapshot.");
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0).expect("Failed to write.");
+            q.fsync().unwrap();
+            data_cluster_addr(&mut q, 0)
+        };
+
+        let header = QcowHeader::new(&mut disk_file).expect("Failed to create Header.");
+        let mut raw_file = QcowRawFile::from(disk_file, 1 << DEFAULT_CLUSTER_BITS)
+            .expect("Failed to create QcowRawFile.");
+        QcowFile::rebuild_refcounts(&mut raw_file, header).expect("Failed to rebuild recounts.");
+        let mut q =
+            QcowFile::from(raw_file.file().try_clone().unwrap(), MAX_NESTING_DEPTH).unwrap();
+        assert_eq!(cluster_refcount(&mut q, data_addr), 1);
+        let snapshot_l1_table_offset = q.snapshots[0].l1_table_offset;
+        assert_eq!(cluster_refcount(&mut q, snapshot_l1_table_offset), 1);
+        let snapshots_offset = q.header.snapshots_offset;
+        assert_eq!(cluster_refcount(&mut q, snapshots_offset), 1);
+
+        q.apply_snapshot("snap").expect("Failed to apply snapshot.");
+        let mut buf = [0u8; 0x1000];
+        read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0x55));
+    }
+
+    #[test]
+    fn check_clean() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x3000], 0xf000).expect("Failed to write.");
+            q.create_snapshot("snap")
+                .expect("Failed to create snapshot.");
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0).expect("Failed to write.");
+            let result = q.check(false).expect("Failed to check.");
+            assert!(result.is_clean(), "{:?}", result);
+            assert!(!result.repaired);
+            assert_eq!(q.allocated_clusters().unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn check_repair_leak() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            // Allocate a cluster without referencing it.
+            let leaked_addr = q.get_new_cluster(None).unwrap();
+            q.add_cluster_refcount(leaked_addr, 1).unwrap();
+
+            let result = q.check(false).expect("Failed to check.");
+            assert_eq!(
+                result.leaks,
+                vec![RefcountMismatch {
+                    offset: leaked_addr,
+                    refcount: 1,
+                    references: 0,
+                }]
+            );
+            assert!(result.errors.is_empty());
+
+            let result = q.check(true).expect("Failed to check.");
+            assert!(result.repaired);
+            assert!(q.check(false).expect("Failed to check.").is_clean());
+            // The leaked cluster is reused for new data.
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0x10000).expect("Failed to write.");
+            assert_eq!(data_cluster_addr(&mut q, 0x10000), leaked_addr);
+        });
+    }
+
+    #[test]
+    fn check_repair_error() {
+        with_default_file(0x10_0000, |mut q| {
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            let data_addr = data_cluster_addr(&mut q, 0);
+            q.set_cluster_refcount(data_addr, 0).unwrap();
+
+            let result = q.check(true).expect("Failed to check.");
+            assert!(result.leaks.is_empty());
+            assert_eq!(
+                result.errors,
+                vec![RefcountMismatch {
+                    offset: data_addr,
+                    refcount: 0,
+                    references: 1,
+                }]
+            );
+            assert!(result.repaired);
+            assert!(q.check(false).expect("Failed to check.").is_clean());
+            assert_eq!(cluster_refcount(&mut q, data_addr), 1);
+
+            // The data cluster must not be handed out again.
+            write_all_at(&mut q, &[0xaau8; 0x1000], 0x10000).expect("Failed to write.");
+            let mut buf = [0u8; 0x1000];
+            read_exact_at(&mut q, &mut buf, 0).expect("Failed to read.");
+            assert!(buf.iter().all(|b| *b == 0x55));
+        });
+    }
+
+    #[test]
+    fn moved_refblock_unreferenced() {
+        with_default_file(0x10_0000, |mut q| {
+            let refblock_addr = |q: &mut QcowFile| {
+                q.raw_file
+                    .read_pointer_table(q.header.refcount_table_offset, 1, None)
+                    .unwrap()[0]
+            };
+            q.sync_caches().unwrap();
+            let old_addr = refblock_addr(&mut q);
+
+            // The first refcount change after a flush writes the refcount block to a new cluster.
+            write_all_at(&mut q, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            q.sync_caches().unwrap();
+            let new_addr = refblock_addr(&mut q);
+            assert_ne!(new_addr, old_addr);
+            assert_eq!(cluster_refcount(&mut q, new_addr), 1);
+            assert_eq!(cluster_refcount(&mut q, old_addr), 0);
+            assert!(q.check(false).expect("Failed to check.").is_clean());
+        });
+    }
+
+    // Creates a raw backing file in `dir` holding 0x11 bytes in its second cluster and an overlay
+    // on top of it holding 0x22 bytes in its third cluster.
+    fn backing_chain(dir: &TempDir) -> (std::path::PathBuf, File) {
+        let backing_path = dir.path().join("backing");
+        let mut backing = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&backing_path)
+            .unwrap();
+        backing.set_len(0x10_0000).unwrap();
+        backing.seek(SeekFrom::Start(0x10000)).unwrap();
+        backing.write_all(&[0x11u8; 0x10000]).unwrap();
+
+        let overlay_file = tempfile().unwrap();
+        let mut overlay = QcowFile::new_from_backing(
+            overlay_file.try_clone().unwrap(),
+            backing_path.to_str().unwrap(),
+            MAX_NESTING_DEPTH,
+        )
+        .unwrap();
+        write_all_at(&mut overlay, &[0x22u8; 0x10000], 0x20000).expect("Failed to write.");
+        (backing_path, overlay_file)
+    }
+
+    fn assert_cluster_data(q: &mut QcowFile, address: u64, value: u8) {
+        let mut buf = vec![0u8; 0x10000];
+        read_exact_at(q, &mut buf, address).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == value));
+    }
+
+    #[test]
+    fn commit_raw_backing() {
+        let dir = TempDir::new().unwrap();
+        let (backing_path, overlay_file) = backing_chain(&dir);
+        let mut q = QcowFile::from(overlay_file, MAX_NESTING_DEPTH).unwrap();
+        write_all_at(&mut q, &[0x33u8; 0x10000], 0x10000).expect("Failed to write.");
+
+        let backing_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&backing_path)
+            .unwrap();
+        q.commit(backing_file, MAX_NESTING_DEPTH)
+            .expect("Failed to commit.");
+        assert_eq!(q.allocated_clusters().unwrap(), 0);
+        assert_cluster_data(&mut q, 0, 0);
+        assert_cluster_data(&mut q, 0x10000, 0x33);
+        assert_cluster_data(&mut q, 0x20000, 0x22);
+        assert!(q.check(false).unwrap().is_clean());
+
+        let mut backing = File::open(&backing_path).unwrap();
+        let mut buf = vec![0u8; 0x20000];
+        backing.seek(SeekFrom::Start(0x10000)).unwrap();
+        backing.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x10000].iter().all(|b| *b == 0x33));
+        assert!(buf[0x10000..].iter().all(|b| *b == 0x22));
+    }
+
+    #[test]
+    fn commit_qcow_backing() {
+        let dir = TempDir::new().unwrap();
+        let backing_path = dir.path().join("backing.qcow2");
+        let backing_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&backing_path)
+            .unwrap();
+        let mut backing = QcowFile::new(backing_file, 0x10_0000).unwrap();
+        write_all_at(&mut backing, &[0x11u8; 0x10000], 0x10000).expect("Failed to write.");
+        drop(backing);
+
+        let mut q = QcowFile::new_from_backing(
+            tempfile().unwrap(),
+            backing_path.to_str().unwrap(),
+            MAX_NESTING_DEPTH,
+        )
+        .unwrap();
+        write_all_at(&mut q, &[0x22u8; 0x10000], 0x20000).expect("Failed to write.");
+        let backing_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&backing_path)
+            .unwrap();
+        q.commit(backing_file, MAX_NESTING_DEPTH)
+            .expect("Failed to commit.");
+        assert_eq!(q.allocated_clusters().unwrap(), 0);
+        assert_cluster_data(&mut q, 0x20000, 0x22);
+        drop(q);
+
+        let mut backing =
+            QcowFile::from(File::open(&backing_path).unwrap(), MAX_NESTING_DEPTH).unwrap();
+        assert_cluster_data(&mut backing, 0x10000, 0x11);
+        assert_cluster_data(&mut backing, 0x20000, 0x22);
+        assert!(backing.check(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn commit_wrong_backing_file() {
+        let dir = TempDir::new().unwrap();
+        let (_backing_path, overlay_file) = backing_chain(&dir);
+        let mut q = QcowFile::from(overlay_file, MAX_NESTING_DEPTH).unwrap();
+
+        let other_path = dir.path().join("other");
+        let other_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&other_path)
+            .unwrap();
+        other_file.set_len(0x10_0000).unwrap();
+        assert!(matches!(
+            q.commit(other_file, MAX_NESTING_DEPTH),
+            Err(Error::IncorrectBackingFile)
+        ));
+        // The overlay keeps its data and the other file is left untouched.
+        assert_eq!(q.allocated_clusters().unwrap(), 1);
+        assert_cluster_data(&mut q, 0x20000, 0x22);
+        let mut buf = vec![0u8; 0x10_0000];
+        File::open(&other_path)
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn commit_without_backing() {
+        with_default_file(0x10_0000, |mut q| {
+            let file = tempfile().unwrap();
+            q.commit(file, MAX_NESTING_DEPTH)
+                .expect_err("Commit without a backing file worked.");
+            q.stream()
+                .expect_err("Stream without a backing file worked.");
+        });
+    }
+
+    #[test]
+    fn stream_backing() {
+        let dir = TempDir::new().unwrap();
+        let (_backing_path, overlay_file) = backing_chain(&dir);
+        let mut q = QcowFile::from(overlay_file.try_clone().unwrap(), MAX_NESTING_DEPTH).unwrap();
+        q.stream().expect("Failed to stream.");
+        assert_eq!(q.backing_file_path(), None);
+        drop(q);
+
+        let mut q = QcowFile::from(overlay_file, MAX_NESTING_DEPTH).unwrap();
+        assert_eq!(q.backing_file_path(), None);
+        // Clusters that are zero in the backing file aren't copied.
+        assert_eq!(q.allocated_clusters().unwrap(), 2);
+        assert_cluster_data(&mut q, 0, 0);
+        assert_cluster_data(&mut q, 0x10000, 0x11);
+        assert_cluster_data(&mut q, 0x20000, 0x22);
+        assert!(q.check(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn stream_with_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let (_backing_path, overlay_file) = backing_chain(&dir);
+        let mut q = QcowFile::from(overlay_file, MAX_NESTING_DEPTH).unwrap();
+        q.create_snapshot("snap")
+            .expect("Failed to create snapshot.");
+        // The snapshot still reads from the backing file.
+        assert!(matches!(q.stream(), Err(Error::StreamingWithSnapshots)));
+        assert!(q.backing_file_path().is_some());
+        assert_eq!(q.allocated_clusters().unwrap(), 1);
+    }
+
+    #[test]
+    fn relative_backing_path() {
+        let dir = TempDir::new().unwrap();
+        backing_chain(&dir);
+        let overlay_path = dir.path().join("overlay");
+        let overlay_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&overlay_path)
+            .unwrap();
+        // The backing file name is stored as is, and is relative to the directory of the overlay.
+        let q = QcowFile::new_from_backing_at(
+            overlay_file.try_clone().unwrap(),
+            &overlay_path,
+            "backing",
+            MAX_NESTING_DEPTH,
+        )
+        .unwrap();
+        assert_eq!(q.backing_file_path(), Some("backing"));
+        drop(q);
+
+        let mut q = QcowFile::from_path(overlay_file, &overlay_path, MAX_NESTING_DEPTH).unwrap();
+        assert_cluster_data(&mut q, 0x10000, 0x11);
+        assert_eq!(
+            backing_file_full_path(&overlay_path, "/abs/backing"),
+            Path::new("/abs/backing")
+        );
+    }
+
     #[cfg_attr(windows, ignore = "TODO(b/257958782): Enable large test on windows")]
     #[test]
     fn nested_qcow() {
@@ -2568,4 +4578,102 @@ mod tests {
             }
         });
     }
+
+    // Creates an empty LUKS encrypted image protected by `passphrase`. The LUKS header is appended
+    // to the image and picked up by the refcount rebuild done when the image is opened.
+    fn encrypted_file(passphrase: &[u8]) -> File {
+        let mut disk_file = tempfile().expect("failed to create temp file");
+        QcowFile::new(disk_file.try_clone().unwrap(), 0x10_0000).unwrap();
+        let mut header = QcowHeader::new(&mut disk_file).unwrap();
+        let cluster_size = 0x01u64 << header.cluster_bits;
+        let crypt_header_offset =
+            div_round_up_u64(disk_file.metadata().unwrap().len(), cluster_size) * cluster_size;
+        let mut luks_header = luks::create_header(passphrase, &[0x42u8; 64], "sha256");
+        disk_file
+            .write_all_at_volatile(VolatileSlice::new(&mut luks_header), crypt_header_offset)
+            .unwrap();
+        header.crypt_method = CRYPT_METHOD_LUKS;
+        header.crypt_header_offset = crypt_header_offset;
+        header.crypt_header_length = luks_header.len() as u64;
+        header.compatible_features |= COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
+        disk_file.rewind().unwrap();
+        header.write_to(&mut disk_file).unwrap();
+        disk_file
+    }
+
+    #[test]
+    fn encrypted_write_read() {
+        let disk_file = encrypted_file(b"secret");
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        {
+            let mut q = QcowFile::from_with_key(disk_file.try_clone().unwrap(), 1, Some(b"secret"))
+                .unwrap();
+            assert!(q.is_encrypted());
+            write_all_at(&mut q, &data, 0x1_0100).unwrap();
+            q.write_zeroes_all_at(0x1_0200, 0x10).unwrap();
+            q.fsync().unwrap();
+        }
+
+        let mut expected = data.clone();
+        expected[0x100..0x110].fill(0);
+        let mut q =
+            QcowFile::from_with_key(disk_file.try_clone().unwrap(), 1, Some(b"secret")).unwrap();
+        let mut buf = vec![0xffu8; 3000];
+        read_exact_at(&mut q, &mut buf, 0x1_0100).unwrap();
+        assert_eq!(buf, expected);
+        // The rest of the cluster reads as zeroes.
+        let mut buf = vec![0xffu8; 0x100];
+        read_exact_at(&mut q, &mut buf, 0x1_0000).unwrap();
+        assert_eq!(buf, vec![0u8; 0x100]);
+
+        // The data is stored encrypted.
+        let offset = match q.file_offset_read(0x1_0100).unwrap() {
+            ClusterData::Offset(offset) => offset,
+            _ => panic!("cluster not allocated"),
+        };
+        let mut raw = vec![0u8; 3000];
+        disk_file
+            .try_clone()
+            .unwrap()
+            .read_exact_at_volatile(VolatileSlice::new(&mut raw), offset)
+            .unwrap();
+        assert_ne!(raw, expected);
+
+        // The LUKS header is accounted for in the refcounts.
+        assert!(q.check(false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn encrypted_snapshot_copy() {
+        let disk_file = encrypted_file(b"secret");
+        let mut q = QcowFile::from_with_key(disk_file, 1, Some(b"secret")).unwrap();
+        write_all_at(&mut q, &[0x55u8; 512], 0).unwrap();
+        q.create_snapshot("before").unwrap();
+        // The write copies the cluster shared with the snapshot to a new location.
+        write_all_at(&mut q, &[0xaau8; 16], 16).unwrap();
+        let mut buf = [0u8; 64];
+        read_exact_at(&mut q, &mut buf, 0).unwrap();
+        assert_eq!(buf[..16], [0x55u8; 16]);
+        assert_eq!(buf[16..32], [0xaau8; 16]);
+        assert_eq!(buf[32..], [0x55u8; 32]);
+
+        q.apply_snapshot("before").unwrap();
+        read_exact_at(&mut q, &mut buf, 0).unwrap();
+        assert_eq!(buf, [0x55u8; 64]);
+    }
+
+    #[test]
+    fn encrypted_needs_key() {
+        let disk_file = encrypted_file(b"secret");
+        assert!(matches!(
+            QcowFile::from(disk_file.try_clone().unwrap(), 1),
+            Err(Error::MissingEncryptionKey)
+        ));
+        assert!(matches!(
+            QcowFile::from_with_key(disk_file, 1, Some(b"guess")),
+            Err(Error::UnlockingImage(
+                crate::crypt::Error::IncorrectPassphrase
+            ))
+        ));
+    }
 }
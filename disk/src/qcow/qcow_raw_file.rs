@@ -16,6 +16,8 @@ use base::FileReadWriteAtVolatile;
 use base::VolatileSlice;
 use base::WriteZeroesAt;
 
+use super::COMPRESSED_FLAG;
+
 /// A qcow file. Allows reading/writing clusters and appending clusters.
 #[derive(Debug)]
 pub struct QcowRawFile {
@@ -65,8 +67,8 @@ impl QcowRawFile {
     }
 
     /// Writes `table` of u64 pointers to `offset` in the file.
-    /// `non_zero_flags` will be ORed with all non-zero values in `table`.
-    /// writing.
+    /// `non_zero_flags` will be ORed with all non-zero values in `table`, except for compressed
+    /// cluster descriptors, which are written unmodified.
     pub fn write_pointer_table(
         &mut self,
         offset: u64,
@@ -76,8 +78,8 @@ impl QcowRawFile {
         self.file.seek(SeekFrom::Start(offset))?;
         let mut buffer = BufWriter::with_capacity(size_of_val(table), &self.file);
         for addr in table {
-            let val = if *addr == 0 {
-                0
+            let val = if *addr == 0 || *addr & COMPRESSED_FLAG != 0 {
+                *addr
             } else {
                 *addr | non_zero_flags
             };
@@ -76,6 +76,18 @@ impl RefCount {
         })
     }
 
+    /// Discards the cached refcount blocks and reads the refcount table again, picking up changes
+    /// made to the refcounts on disk. Cached blocks must have been flushed beforehand.
+    pub fn reload(&mut self, raw_file: &mut QcowRawFile) -> io::Result<()> {
+        self.ref_table = VecCache::from_vec(raw_file.read_pointer_table(
+            self.refcount_table_offset,
+            self.ref_table.len() as u64,
+            None,
+        )?);
+        self.refblock_cache.clear();
+        Ok(())
+    }
+
     /// Returns the number of refcounts per block.
     pub fn refcounts_per_block(&self) -> u64 {
         self.refcount_block_entries
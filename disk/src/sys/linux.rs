@@ -3,9 +3,11 @@
 // found in the LICENSE file.
 
 use std::fs::File;
+use std::io;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
 
 use cros_async::Executor;
 
@@ -18,6 +20,13 @@ pub fn apply_raw_disk_file_options(_raw_image: &File, _is_sparse_file: bool) ->
     Ok(())
 }
 
+/// Returns the device and inode numbers of `file`, which identify the underlying file no matter
+/// how it was opened.
+pub fn file_id(file: &File) -> io::Result<(u64, u64)> {
+    let metadata = file.metadata()?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
 pub fn read_from_disk(
     mut file: &File,
     offset: u64,
@@ -147,6 +156,53 @@ mod tests {
         assert_eq!(image_type, ImageType::CompositeDisk);
     }
 
+    #[test]
+    #[cfg(feature = "vhdx")]
+    fn detect_image_type_vhdx() {
+        let mut t = tempfile::tempfile().unwrap();
+        // Write the VHDX file type identifier signature. The rest of the file is not filled in, so
+        // if detect_image_type is ever updated to validate more of the header, this test would need
+        // to be updated.
+        let buf = "vhdxfile".as_bytes();
+        t.write_all(buf).unwrap();
+        let image_type = detect_image_type(&t, false).expect("failed to detect image type");
+        assert_eq!(image_type, ImageType::Vhdx);
+    }
+
+    #[test]
+    #[cfg(feature = "vmdk")]
+    fn detect_image_type_vmdk() {
+        let mut t = tempfile::tempfile().unwrap();
+        // Write the VMDK sparse extent magic signature. The rest of the header is not filled in, so
+        // if detect_image_type is ever updated to validate more of the header, this test would need
+        // to be updated.
+        let buf = "KDMV".as_bytes();
+        t.write_all(buf).unwrap();
+        let image_type = detect_image_type(&t, false).expect("failed to detect image type");
+        assert_eq!(image_type, ImageType::Vmdk);
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn create_encrypted_raw_disk() {
+        let t = tempfile::tempfile().unwrap();
+        t.set_len(4096).unwrap();
+        let path = std::path::Path::new("");
+        assert!(matches!(
+            create_encrypted_disk_file(t.try_clone().unwrap(), false, 1, path, &[1u8; 16]),
+            Err(Error::CreateEncryptedDisk(_))
+        ));
+
+        let mut disk = create_encrypted_disk_file(t, false, 1, path, &[1u8; 64]).unwrap();
+        let mut data = [0x5au8; 512];
+        disk.write_all_at_volatile(base::VolatileSlice::new(&mut data), 512)
+            .unwrap();
+        let mut buf = [0u8; 512];
+        disk.read_exact_at_volatile(base::VolatileSlice::new(&mut buf), 512)
+            .unwrap();
+        assert_eq!(buf, data);
+    }
+
     #[test]
     fn detect_image_type_small_file() {
         let mut t = tempfile::tempfile().unwrap();
@@ -3,12 +3,17 @@
 // found in the LICENSE file.
 
 use std::fs::File;
+use std::io;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::mem::MaybeUninit;
+use std::os::windows::io::AsRawHandle;
 
 use base::read_overlapped_blocking;
 use cros_async::Executor;
+use winapi::um::fileapi::GetFileInformationByHandle;
+use winapi::um::fileapi::BY_HANDLE_FILE_INFORMATION;
 
 use crate::Error;
 use crate::Result;
@@ -30,6 +35,25 @@ pub fn apply_raw_disk_file_options(raw_image: &File, is_sparse_file: bool) -> Re
     Ok(())
 }
 
+/// Returns the volume serial number and file index of `file`, which identify the underlying file
+/// no matter how it was opened.
+pub fn file_id(file: &File) -> io::Result<(u64, u64)> {
+    let mut info = MaybeUninit::<BY_HANDLE_FILE_INFORMATION>::uninit();
+    // SAFETY:
+    // Safe because the handle is valid for the lifetime of `file` and `info` is large enough to
+    // hold the returned information.
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, info.as_mut_ptr()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY:
+    // Safe because GetFileInformationByHandle succeeded, so it filled in `info`.
+    let info = unsafe { info.assume_init() };
+    Ok((
+        u64::from(info.dwVolumeSerialNumber),
+        u64::from(info.nFileIndexHigh) << 32 | u64::from(info.nFileIndexLow),
+    ))
+}
+
 pub fn read_from_disk(
     mut file: &File,
     offset: u64,
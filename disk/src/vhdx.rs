@@ -0,0 +1,591 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Read-only support for fixed and dynamic VHDX images.
+//!
+//! https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-vhdx/83e061f8-f6e2-4de1-91bd-5d518a43d477
+
+use std::fs::File;
+use std::io;
+
+use base::FileReadWriteAtVolatile;
+use base::VolatileSlice;
+use remain::sorted;
+use thiserror::Error;
+
+use crate::block_map::BlockMap;
+use crate::block_map::BlockMappedDisk;
+use crate::block_map::BlockMapping;
+use crate::DiskGetLen;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to get the size of the image file: {0}")]
+    GettingFileSize(io::Error),
+    #[error("differencing VHDX images are not supported")]
+    HasParent,
+    #[error("invalid BAT entry {0:#x} for payload block {1}")]
+    InvalidBatEntry(u64, u64),
+    #[error("invalid block size {0}")]
+    InvalidBlockSize(u32),
+    #[error("invalid logical sector size {0}")]
+    InvalidLogicalSectorSize(u32),
+    #[error("invalid metadata table")]
+    InvalidMetadataTable,
+    #[error("invalid {0} region")]
+    InvalidRegion(&'static str),
+    #[error("invalid virtual disk size {0}")]
+    InvalidVirtualSize(u64),
+    #[error("the image log must be replayed before it can be opened read-only")]
+    LogReplayRequired,
+    #[error("missing required metadata item {0}")]
+    MissingMetadata(&'static str),
+    #[error("missing {0} region")]
+    MissingRegion(&'static str),
+    #[error("not a VHDX image")]
+    NotVhdx,
+    #[error("no valid header")]
+    NoValidHeader,
+    #[error("no valid region table")]
+    NoValidRegionTable,
+    #[error("failed to read image: {0}")]
+    ReadingImage(io::Error),
+    #[error("unsupported required metadata item")]
+    UnsupportedMetadata,
+    #[error("unsupported required region")]
+    UnsupportedRegion,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub const VHDX_SIGNATURE: &[u8; 8] = b"vhdxfile";
+
+const KIB: u64 = 1 << 10;
+const MIB: u64 = 1 << 20;
+
+const HEADER_SIGNATURE: &[u8; 4] = b"head";
+const HEADER_OFFSETS: [u64; 2] = [64 * KIB, 128 * KIB];
+const HEADER_SIZE: usize = 4 * KIB as usize;
+const HEADER_VERSION: u16 = 1;
+
+const REGION_TABLE_SIGNATURE: &[u8; 4] = b"regi";
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KIB, 256 * KIB];
+const REGION_TABLE_SIZE: usize = 64 * KIB as usize;
+const REGION_TABLE_HEADER_SIZE: usize = 16;
+const REGION_TABLE_ENTRY_SIZE: usize = 32;
+const MAX_REGION_TABLE_ENTRIES: usize = 2047;
+
+const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+const METADATA_TABLE_SIZE: usize = 64 * KIB as usize;
+const METADATA_TABLE_HEADER_SIZE: usize = 32;
+const METADATA_TABLE_ENTRY_SIZE: usize = 32;
+const MAX_METADATA_TABLE_ENTRIES: usize = 2047;
+const METADATA_FLAG_IS_REQUIRED: u32 = 1 << 2;
+
+const FILE_PARAMETERS_HAS_PARENT: u32 = 1 << 1;
+
+const MIN_BLOCK_SIZE: u32 = 1 << 20;
+const MAX_BLOCK_SIZE: u32 = 256 << 20;
+const MAX_VIRTUAL_SIZE: u64 = 64 << 40;
+
+const BAT_ENTRY_STATE_MASK: u64 = 0x7;
+const PAYLOAD_BLOCK_NOT_PRESENT: u64 = 0;
+const PAYLOAD_BLOCK_UNDEFINED: u64 = 1;
+const PAYLOAD_BLOCK_ZERO: u64 = 2;
+const PAYLOAD_BLOCK_UNMAPPED: u64 = 3;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+// Bits 20 to 63 of a BAT entry hold the file offset in MiB, so masking the low bits gives the
+// offset in bytes.
+const BAT_ENTRY_OFFSET_MASK: u64 = !(MIB - 1);
+
+/// Encodes a GUID in its on-disk byte order.
+const fn guid(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> [u8; 16] {
+    let a = data1.to_le_bytes();
+    let b = data2.to_le_bytes();
+    let c = data3.to_le_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3],
+        data4[4], data4[5], data4[6], data4[7],
+    ]
+}
+
+const BAT_REGION: [u8; 16] = guid(
+    0x2dc27766,
+    0xf623,
+    0x4200,
+    [0x9d, 0x64, 0x11, 0x5e, 0x9b, 0xfd, 0x4a, 0x08],
+);
+const METADATA_REGION: [u8; 16] = guid(
+    0x8b7ca206,
+    0x4790,
+    0x4b9a,
+    [0xb8, 0xfe, 0x57, 0x5f, 0x05, 0x0f, 0x88, 0x6e],
+);
+const FILE_PARAMETERS: [u8; 16] = guid(
+    0xcaa16737,
+    0xfa36,
+    0x4d43,
+    [0xb3, 0xb6, 0x33, 0xf0, 0xaa, 0x44, 0xe7, 0x6b],
+);
+const VIRTUAL_DISK_SIZE: [u8; 16] = guid(
+    0x2fa54224,
+    0xcd1b,
+    0x4876,
+    [0xb2, 0x11, 0x5d, 0xbe, 0xd8, 0x3b, 0xf4, 0xb8],
+);
+const LOGICAL_SECTOR_SIZE: [u8; 16] = guid(
+    0x8141bf1d,
+    0xa96f,
+    0x4709,
+    [0xba, 0x47, 0xf2, 0x33, 0xa8, 0xfa, 0xab, 0x5f],
+);
+// The remaining metadata items defined by the spec. Their values are not needed to read the image.
+const PHYSICAL_SECTOR_SIZE: [u8; 16] = guid(
+    0xcda348c7,
+    0x445d,
+    0x4471,
+    [0x9c, 0xc9, 0xe9, 0x88, 0x52, 0x51, 0xc5, 0x56],
+);
+const VIRTUAL_DISK_ID: [u8; 16] = guid(
+    0xbeca12ab,
+    0xb2e6,
+    0x4523,
+    [0x93, 0xef, 0xc3, 0x09, 0xe0, 0x00, 0xc7, 0x46],
+);
+
+/// CRC-32C (Castagnoli), as used by the VHDX checksums.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Checks the CRC-32C stored at offset 4 of a header or region table, which is computed with the
+/// checksum field itself set to zero.
+fn checksum_valid(buf: &[u8]) -> bool {
+    let stored = read_u32(buf, 4);
+    let mut copy = buf.to_vec();
+    copy[4..8].fill(0);
+    crc32c(&copy) == stored
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_exact_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    file.read_exact_at_volatile(VolatileSlice::new(&mut buf), offset)
+        .map_err(Error::ReadingImage)?;
+    Ok(buf)
+}
+
+/// Location of a region or metadata item.
+#[derive(Clone, Copy)]
+struct Extent {
+    offset: u64,
+    length: u64,
+}
+
+/// Reads both headers and returns the log GUID of the current one, the valid header with the
+/// highest sequence number.
+fn read_log_guid(file: &mut File) -> Result<[u8; 16]> {
+    let mut current: Option<(u64, [u8; 16])> = None;
+    for offset in HEADER_OFFSETS {
+        let header = read_exact_at(file, offset, HEADER_SIZE)?;
+        if &header[0..4] != HEADER_SIGNATURE
+            || !checksum_valid(&header)
+            || read_u16(&header, 66) != HEADER_VERSION
+        {
+            continue;
+        }
+        let sequence_number = read_u64(&header, 8);
+        if current.map_or(true, |(seq, _)| sequence_number > seq) {
+            current = Some((sequence_number, header[48..64].try_into().unwrap()));
+        }
+    }
+    current
+        .map(|(_, log_guid)| log_guid)
+        .ok_or(Error::NoValidHeader)
+}
+
+/// Reads the first valid region table and returns the BAT and metadata regions.
+fn read_regions(file: &mut File, file_len: u64) -> Result<(Extent, Extent)> {
+    for offset in REGION_TABLE_OFFSETS {
+        let table = read_exact_at(file, offset, REGION_TABLE_SIZE)?;
+        if &table[0..4] != REGION_TABLE_SIGNATURE || !checksum_valid(&table) {
+            continue;
+        }
+        let entry_count = read_u32(&table, 8) as usize;
+        if entry_count > MAX_REGION_TABLE_ENTRIES {
+            continue;
+        }
+        let mut bat = None;
+        let mut metadata = None;
+        for i in 0..entry_count {
+            let entry = &table[REGION_TABLE_HEADER_SIZE + i * REGION_TABLE_ENTRY_SIZE..];
+            let region = Extent {
+                offset: read_u64(entry, 16),
+                length: read_u32(entry, 24) as u64,
+            };
+            let required = read_u32(entry, 28) & 1 != 0;
+            let guid: [u8; 16] = entry[0..16].try_into().unwrap();
+            let name = match guid {
+                BAT_REGION => "BAT",
+                METADATA_REGION => "metadata",
+                _ if required => return Err(Error::UnsupportedRegion),
+                _ => continue,
+            };
+            if region.offset < MIB
+                || region.offset % MIB != 0
+                || region.length % MIB != 0
+                || region.offset.saturating_add(region.length) > file_len
+            {
+                return Err(Error::InvalidRegion(name));
+            }
+            if name == "BAT" {
+                bat = Some(region);
+            } else {
+                metadata = Some(region);
+            }
+        }
+        return Ok((
+            bat.ok_or(Error::MissingRegion("BAT"))?,
+            metadata.ok_or(Error::MissingRegion("metadata"))?,
+        ));
+    }
+    Err(Error::NoValidRegionTable)
+}
+
+/// The metadata items needed to read the image.
+struct Metadata {
+    block_size: u32,
+    has_parent: bool,
+    virtual_size: u64,
+    logical_sector_size: u32,
+}
+
+fn read_metadata(file: &mut File, region: Extent) -> Result<Metadata> {
+    if region.length < METADATA_TABLE_SIZE as u64 {
+        return Err(Error::InvalidRegion("metadata"));
+    }
+    let table = read_exact_at(file, region.offset, METADATA_TABLE_SIZE)?;
+    if &table[0..8] != METADATA_SIGNATURE {
+        return Err(Error::InvalidMetadataTable);
+    }
+    let entry_count = read_u16(&table, 10) as usize;
+    if entry_count > MAX_METADATA_TABLE_ENTRIES {
+        return Err(Error::InvalidMetadataTable);
+    }
+
+    let mut file_parameters = None;
+    let mut virtual_disk_size = None;
+    let mut logical_sector_size = None;
+    for i in 0..entry_count {
+        let entry = &table[METADATA_TABLE_HEADER_SIZE + i * METADATA_TABLE_ENTRY_SIZE..];
+        let item = Extent {
+            offset: read_u32(entry, 16) as u64,
+            length: read_u32(entry, 20) as u64,
+        };
+        let required = read_u32(entry, 24) & METADATA_FLAG_IS_REQUIRED != 0;
+        let guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        let (value, expected_len) = match guid {
+            FILE_PARAMETERS => (&mut file_parameters, 8),
+            VIRTUAL_DISK_SIZE => (&mut virtual_disk_size, 8),
+            LOGICAL_SECTOR_SIZE => (&mut logical_sector_size, 4),
+            PHYSICAL_SECTOR_SIZE | VIRTUAL_DISK_ID => continue,
+            _ if required => return Err(Error::UnsupportedMetadata),
+            _ => continue,
+        };
+        if item.length != expected_len || item.offset + item.length > region.length {
+            return Err(Error::InvalidMetadataTable);
+        }
+        *value = Some(read_exact_at(
+            file,
+            region.offset + item.offset,
+            item.length as usize,
+        )?);
+    }
+
+    let file_parameters = file_parameters.ok_or(Error::MissingMetadata("file parameters"))?;
+    let virtual_disk_size = virtual_disk_size.ok_or(Error::MissingMetadata("virtual disk size"))?;
+    let logical_sector_size =
+        logical_sector_size.ok_or(Error::MissingMetadata("logical sector size"))?;
+    Ok(Metadata {
+        block_size: read_u32(&file_parameters, 0),
+        has_parent: read_u32(&file_parameters, 4) & FILE_PARAMETERS_HAS_PARENT != 0,
+        virtual_size: read_u64(&virtual_disk_size, 0),
+        logical_sector_size: read_u32(&logical_sector_size, 0),
+    })
+}
+
+/// The block allocation table of a VHDX image.
+#[derive(Debug)]
+pub struct Vhdx {
+    virtual_size: u64,
+    block_size: u64,
+    blocks: Vec<BlockMapping>,
+}
+
+impl Vhdx {
+    fn from_file(file: &mut File) -> Result<Vhdx> {
+        let file_len = file.get_len().map_err(Error::GettingFileSize)?;
+        if read_exact_at(file, 0, VHDX_SIGNATURE.len())? != VHDX_SIGNATURE {
+            return Err(Error::NotVhdx);
+        }
+        if read_log_guid(file)? != [0u8; 16] {
+            return Err(Error::LogReplayRequired);
+        }
+        let (bat_region, metadata_region) = read_regions(file, file_len)?;
+
+        let metadata = read_metadata(file, metadata_region)?;
+        if metadata.has_parent {
+            return Err(Error::HasParent);
+        }
+        if metadata.logical_sector_size != 512 && metadata.logical_sector_size != 4096 {
+            return Err(Error::InvalidLogicalSectorSize(
+                metadata.logical_sector_size,
+            ));
+        }
+        if !metadata.block_size.is_power_of_two()
+            || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&metadata.block_size)
+        {
+            return Err(Error::InvalidBlockSize(metadata.block_size));
+        }
+        if metadata.virtual_size == 0
+            || metadata.virtual_size > MAX_VIRTUAL_SIZE
+            || metadata.virtual_size % metadata.logical_sector_size as u64 != 0
+        {
+            return Err(Error::InvalidVirtualSize(metadata.virtual_size));
+        }
+
+        // Every `chunk_ratio` payload block entries in the BAT are followed by a sector bitmap
+        // entry, which is only used by differencing images.
+        let block_size = metadata.block_size as u64;
+        let chunk_ratio = (1u64 << 23) * metadata.logical_sector_size as u64 / block_size;
+        let payload_blocks = (metadata.virtual_size + block_size - 1) / block_size;
+        let bat_entries = payload_blocks + (payload_blocks - 1) / chunk_ratio;
+        if bat_entries * 8 > bat_region.length {
+            return Err(Error::InvalidRegion("BAT"));
+        }
+        let bat = read_exact_at(file, bat_region.offset, bat_entries as usize * 8)?;
+
+        let blocks = (0..payload_blocks)
+            .map(|block| {
+                let entry = read_u64(&bat, (block + block / chunk_ratio) as usize * 8);
+                match entry & BAT_ENTRY_STATE_MASK {
+                    PAYLOAD_BLOCK_NOT_PRESENT
+                    | PAYLOAD_BLOCK_UNDEFINED
+                    | PAYLOAD_BLOCK_ZERO
+                    | PAYLOAD_BLOCK_UNMAPPED => Ok(BlockMapping::Zero),
+                    PAYLOAD_BLOCK_FULLY_PRESENT => {
+                        let offset = entry & BAT_ENTRY_OFFSET_MASK;
+                        if offset < MIB || offset >= file_len {
+                            return Err(Error::InvalidBatEntry(entry, block));
+                        }
+                        Ok(BlockMapping::Data(offset))
+                    }
+                    _ => Err(Error::InvalidBatEntry(entry, block)),
+                }
+            })
+            .collect::<Result<Vec<BlockMapping>>>()?;
+
+        Ok(Vhdx {
+            virtual_size: metadata.virtual_size,
+            block_size,
+            blocks,
+        })
+    }
+}
+
+impl BlockMap for Vhdx {
+    fn size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn lookup(&self, block: u64) -> BlockMapping {
+        self.blocks[block as usize]
+    }
+}
+
+/// Opens a VHDX image for read-only access.
+pub fn open(mut file: File) -> Result<BlockMappedDisk<Vhdx>> {
+    let vhdx = Vhdx::from_file(&mut file)?;
+    Ok(BlockMappedDisk::new(file, vhdx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: u64 = MIB;
+    const BAT_OFFSET: u64 = MIB;
+    const METADATA_OFFSET: u64 = 2 * MIB;
+    const DATA_OFFSET: u64 = 3 * MIB;
+
+    fn write_at(file: &mut File, offset: u64, data: &[u8]) {
+        let mut data = data.to_vec();
+        file.write_all_at_volatile(VolatileSlice::new(&mut data), offset)
+            .unwrap();
+    }
+
+    fn header(sequence_number: u64, log_guid: [u8; 16]) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(HEADER_SIGNATURE);
+        header[8..16].copy_from_slice(&sequence_number.to_le_bytes());
+        header[48..64].copy_from_slice(&log_guid);
+        header[66..68].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+        let checksum = crc32c(&header);
+        header[4..8].copy_from_slice(&checksum.to_le_bytes());
+        header
+    }
+
+    fn region_table() -> Vec<u8> {
+        let mut table = vec![0u8; REGION_TABLE_SIZE];
+        table[0..4].copy_from_slice(REGION_TABLE_SIGNATURE);
+        table[8..12].copy_from_slice(&2u32.to_le_bytes());
+        for (i, (guid, offset)) in [(BAT_REGION, BAT_OFFSET), (METADATA_REGION, METADATA_OFFSET)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = &mut table[REGION_TABLE_HEADER_SIZE + i * REGION_TABLE_ENTRY_SIZE..];
+            entry[0..16].copy_from_slice(&guid);
+            entry[16..24].copy_from_slice(&offset.to_le_bytes());
+            entry[24..28].copy_from_slice(&(MIB as u32).to_le_bytes());
+            entry[28..32].copy_from_slice(&1u32.to_le_bytes());
+        }
+        let checksum = crc32c(&table);
+        table[4..8].copy_from_slice(&checksum.to_le_bytes());
+        table
+    }
+
+    fn metadata(virtual_size: u64, file_parameters_flags: u32) -> Vec<u8> {
+        let mut metadata = vec![0u8; METADATA_TABLE_SIZE + 64];
+        metadata[0..8].copy_from_slice(METADATA_SIGNATURE);
+        metadata[10..12].copy_from_slice(&3u16.to_le_bytes());
+        let items: [([u8; 16], Vec<u8>); 3] = [
+            (FILE_PARAMETERS, {
+                let mut value = (BLOCK_SIZE as u32).to_le_bytes().to_vec();
+                value.extend_from_slice(&file_parameters_flags.to_le_bytes());
+                value
+            }),
+            (VIRTUAL_DISK_SIZE, virtual_size.to_le_bytes().to_vec()),
+            (LOGICAL_SECTOR_SIZE, 512u32.to_le_bytes().to_vec()),
+        ];
+        for (i, (guid, value)) in items.iter().enumerate() {
+            let item_offset = METADATA_TABLE_SIZE + i * 16;
+            let entry = &mut metadata[METADATA_TABLE_HEADER_SIZE + i * METADATA_TABLE_ENTRY_SIZE..];
+            entry[0..16].copy_from_slice(guid);
+            entry[16..20].copy_from_slice(&(item_offset as u32).to_le_bytes());
+            entry[20..24].copy_from_slice(&(value.len() as u32).to_le_bytes());
+            entry[24..28].copy_from_slice(&METADATA_FLAG_IS_REQUIRED.to_le_bytes());
+            metadata[item_offset..item_offset + value.len()].copy_from_slice(value);
+        }
+        metadata
+    }
+
+    /// Creates a dynamic image of three blocks. Block 0 is not present, block 1 is stored at
+    /// `DATA_OFFSET` and filled with 0xa5, block 2 is explicitly zero.
+    fn test_image() -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        write_at(&mut file, 0, VHDX_SIGNATURE);
+        write_at(&mut file, HEADER_OFFSETS[0], &header(1, [0; 16]));
+        write_at(&mut file, HEADER_OFFSETS[1], &header(2, [0; 16]));
+        write_at(&mut file, REGION_TABLE_OFFSETS[0], &region_table());
+        write_at(&mut file, REGION_TABLE_OFFSETS[1], &region_table());
+        write_at(&mut file, METADATA_OFFSET, &metadata(3 * BLOCK_SIZE, 0));
+        let bat = [
+            PAYLOAD_BLOCK_NOT_PRESENT,
+            DATA_OFFSET | PAYLOAD_BLOCK_FULLY_PRESENT,
+            PAYLOAD_BLOCK_ZERO,
+        ];
+        let bat: Vec<u8> = bat.iter().flat_map(|e| e.to_le_bytes()).collect();
+        write_at(&mut file, BAT_OFFSET, &bat);
+        write_at(&mut file, DATA_OFFSET, &vec![0xa5; BLOCK_SIZE as usize]);
+        file
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+    }
+
+    #[test]
+    fn read_blocks() {
+        let mut disk = open(test_image()).unwrap();
+        assert_eq!(disk.get_len().unwrap(), 3 * BLOCK_SIZE);
+        let mut buf = vec![0x55u8; 3 * BLOCK_SIZE as usize];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        let block_size = BLOCK_SIZE as usize;
+        assert!(buf[..block_size].iter().all(|b| *b == 0));
+        assert!(buf[block_size..2 * block_size].iter().all(|b| *b == 0xa5));
+        assert!(buf[2 * block_size..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn corrupt_header_ignored() {
+        let mut file = test_image();
+        // The newer header would require a log replay, but it is invalid so the older one is used.
+        let mut newer = header(3, [1; 16]);
+        newer[100] ^= 1;
+        write_at(&mut file, HEADER_OFFSETS[0], &newer);
+        open(file).unwrap();
+    }
+
+    #[test]
+    fn log_replay_required() {
+        let mut file = test_image();
+        write_at(&mut file, HEADER_OFFSETS[0], &header(3, [1; 16]));
+        assert!(matches!(open(file), Err(Error::LogReplayRequired)));
+    }
+
+    #[test]
+    fn corrupt_region_table_ignored() {
+        let mut file = test_image();
+        write_at(&mut file, REGION_TABLE_OFFSETS[0], &[0xff; 64]);
+        open(file).unwrap();
+    }
+
+    #[test]
+    fn differencing_rejected() {
+        let mut file = test_image();
+        write_at(
+            &mut file,
+            METADATA_OFFSET,
+            &metadata(3 * BLOCK_SIZE, FILE_PARAMETERS_HAS_PARENT),
+        );
+        assert!(matches!(open(file), Err(Error::HasParent)));
+    }
+
+    #[test]
+    fn bat_entry_past_end_of_file() {
+        let mut file = test_image();
+        let entry = (16 * MIB) | PAYLOAD_BLOCK_FULLY_PRESENT;
+        write_at(&mut file, BAT_OFFSET, &entry.to_le_bytes());
+        assert!(matches!(open(file), Err(Error::InvalidBatEntry(_, 0))));
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Read-only support for monolithic sparse VMDK images, which store the descriptor and a single
+//! hosted sparse extent in one file, as described in the VMware Virtual Disk Format 1.1
+//! specification.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+use base::FileReadWriteAtVolatile;
+use base::VolatileSlice;
+use remain::sorted;
+use thiserror::Error;
+
+use crate::block_map::BlockMap;
+use crate::block_map::BlockMappedDisk;
+use crate::block_map::BlockMapping;
+use crate::DiskGetLen;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("compressed (stream optimized) VMDK images are not supported")]
+    Compressed,
+    #[error("failed to get the size of the image file: {0}")]
+    GettingFileSize(io::Error),
+    #[error("delta link VMDK images are not supported")]
+    HasParent,
+    #[error("invalid capacity {0} sectors")]
+    InvalidCapacity(u64),
+    #[error("invalid descriptor")]
+    InvalidDescriptor,
+    #[error("invalid grain directory entry {0:#x}")]
+    InvalidGrainDirectoryEntry(u32),
+    #[error("invalid grain directory offset {0:#x}")]
+    InvalidGrainDirectoryOffset(u64),
+    #[error("invalid grain size {0} sectors")]
+    InvalidGrainSize(u64),
+    #[error("invalid grain table entry {0:#x}")]
+    InvalidGrainTableEntry(u32),
+    #[error("invalid number of grain table entries {0}")]
+    InvalidGrainTableSize(u32),
+    #[error("line endings of the image have been converted")]
+    InvalidLineEndings,
+    #[error("missing embedded descriptor")]
+    MissingDescriptor,
+    #[error("not a VMDK sparse extent")]
+    NotVmdk,
+    #[error("failed to read image: {0}")]
+    ReadingImage(io::Error),
+    #[error("unsupported create type {0}")]
+    UnsupportedCreateType(String),
+    #[error("unsupported version {0}")]
+    UnsupportedVersion(u32),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub const VMDK_MAGIC: u32 = 0x564d444b; // "KDMV" stored little-endian.
+
+const SECTOR_SIZE: u64 = 512;
+const HEADER_SIZE: usize = 512;
+
+const FLAG_VALID_NEWLINE_DETECTION: u32 = 1 << 0;
+const FLAG_COMPRESSED_GRAINS: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+
+// The grain directory of stream optimized images is stored in a footer at the end of the file.
+const GD_AT_END: u64 = u64::MAX;
+
+const NUM_GTES_PER_GT: u32 = 512;
+const MAX_GRAIN_SIZE: u64 = 1 << 18; // 128 MiB in sectors.
+const MAX_CAPACITY: u64 = 1 << 37; // 64 TiB in sectors.
+const MAX_DESCRIPTOR_SIZE: u64 = 2048; // 1 MiB in sectors.
+
+// Grain table entry of a grain that reads as zeroes, as opposed to 0 for an unallocated grain.
+const GTE_ZERO_GRAIN: u32 = 1;
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_exact_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    file.read_exact_at_volatile(VolatileSlice::new(&mut buf), offset)
+        .map_err(Error::ReadingImage)?;
+    Ok(buf)
+}
+
+/// Checks that the embedded descriptor describes a complete disk without a parent.
+fn check_descriptor(descriptor: &[u8]) -> Result<()> {
+    let len = descriptor
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(descriptor.len());
+    let text = std::str::from_utf8(&descriptor[..len]).map_err(|_| Error::InvalidDescriptor)?;
+    let mut create_type = None;
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match key.trim() {
+            "createType" => create_type = Some(value),
+            "parentCID" if !value.eq_ignore_ascii_case("ffffffff") => return Err(Error::HasParent),
+            _ => {}
+        }
+    }
+    match create_type {
+        Some("monolithicSparse") => Ok(()),
+        Some(create_type) => Err(Error::UnsupportedCreateType(create_type.to_string())),
+        None => Err(Error::InvalidDescriptor),
+    }
+}
+
+/// The grain directory and grain tables of a sparse extent.
+#[derive(Debug)]
+pub struct Vmdk {
+    capacity: u64,
+    grain_size: u64,
+    /// For each grain directory entry, the index into `grain_tables` or `None` if unallocated.
+    grain_directory: Vec<Option<usize>>,
+    grain_tables: Vec<Vec<u32>>,
+}
+
+impl Vmdk {
+    fn from_file(file: &mut File) -> Result<Vmdk> {
+        let file_len = file.get_len().map_err(Error::GettingFileSize)?;
+        let header = read_exact_at(file, 0, HEADER_SIZE)?;
+        if read_u32(&header, 0) != VMDK_MAGIC {
+            return Err(Error::NotVmdk);
+        }
+        let version = read_u32(&header, 4);
+        if !(1..=3).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let flags = read_u32(&header, 8);
+        let capacity = read_u64(&header, 12);
+        let grain_size = read_u64(&header, 20);
+        let descriptor_offset = read_u64(&header, 28);
+        let descriptor_size = read_u64(&header, 36);
+        let num_gtes_per_gt = read_u32(&header, 44);
+        let gd_offset = read_u64(&header, 56);
+
+        if flags & FLAG_VALID_NEWLINE_DETECTION != 0 && header[73..77] != *b"\n \r\n" {
+            return Err(Error::InvalidLineEndings);
+        }
+        if flags & (FLAG_COMPRESSED_GRAINS | FLAG_MARKERS) != 0 || gd_offset == GD_AT_END {
+            return Err(Error::Compressed);
+        }
+        if capacity == 0 || capacity > MAX_CAPACITY {
+            return Err(Error::InvalidCapacity(capacity));
+        }
+        if !grain_size.is_power_of_two() || grain_size > MAX_GRAIN_SIZE {
+            return Err(Error::InvalidGrainSize(grain_size));
+        }
+        if num_gtes_per_gt != NUM_GTES_PER_GT {
+            return Err(Error::InvalidGrainTableSize(num_gtes_per_gt));
+        }
+
+        // A sparse extent without an embedded descriptor is part of a disk described elsewhere.
+        if descriptor_offset == 0 || descriptor_size == 0 {
+            return Err(Error::MissingDescriptor);
+        }
+        if descriptor_size > MAX_DESCRIPTOR_SIZE {
+            return Err(Error::InvalidDescriptor);
+        }
+        let descriptor = read_exact_at(
+            file,
+            descriptor_offset.saturating_mul(SECTOR_SIZE),
+            (descriptor_size * SECTOR_SIZE) as usize,
+        )?;
+        check_descriptor(&descriptor)?;
+
+        let grains_per_gt = grain_size * NUM_GTES_PER_GT as u64;
+        let num_gdes = (capacity + grains_per_gt - 1) / grains_per_gt;
+        let gd_bytes = num_gdes * 4;
+        let gd_start = gd_offset.saturating_mul(SECTOR_SIZE);
+        if gd_start.saturating_add(gd_bytes) > file_len {
+            return Err(Error::InvalidGrainDirectoryOffset(gd_offset));
+        }
+        let gd = read_exact_at(file, gd_start, gd_bytes as usize)?;
+
+        // Grain directory entries of a valid image never share a grain table, but load each table
+        // only once regardless so that memory use is bounded by the size of the file.
+        let gt_bytes = NUM_GTES_PER_GT as u64 * 4;
+        let mut table_indices = HashMap::new();
+        let mut grain_tables = Vec::new();
+        let mut grain_directory = Vec::with_capacity(num_gdes as usize);
+        for i in 0..num_gdes as usize {
+            let gde = read_u32(&gd, i * 4);
+            if gde == 0 {
+                grain_directory.push(None);
+                continue;
+            }
+            if let Some(index) = table_indices.get(&gde) {
+                grain_directory.push(Some(*index));
+                continue;
+            }
+            let gt_start = gde as u64 * SECTOR_SIZE;
+            if gt_start + gt_bytes > file_len {
+                return Err(Error::InvalidGrainDirectoryEntry(gde));
+            }
+            let gt = read_exact_at(file, gt_start, gt_bytes as usize)?;
+            let table = (0..NUM_GTES_PER_GT as usize)
+                .map(|j| read_u32(&gt, j * 4))
+                .collect::<Vec<u32>>();
+            for &gte in &table {
+                if gte > GTE_ZERO_GRAIN && gte as u64 * SECTOR_SIZE >= file_len {
+                    return Err(Error::InvalidGrainTableEntry(gte));
+                }
+            }
+            table_indices.insert(gde, grain_tables.len());
+            grain_directory.push(Some(grain_tables.len()));
+            grain_tables.push(table);
+        }
+
+        Ok(Vmdk {
+            capacity,
+            grain_size,
+            grain_directory,
+            grain_tables,
+        })
+    }
+}
+
+impl BlockMap for Vmdk {
+    fn size(&self) -> u64 {
+        self.capacity * SECTOR_SIZE
+    }
+
+    fn block_size(&self) -> u64 {
+        self.grain_size * SECTOR_SIZE
+    }
+
+    fn lookup(&self, grain: u64) -> BlockMapping {
+        let gde = self.grain_directory[(grain / NUM_GTES_PER_GT as u64) as usize];
+        let gte = gde.map_or(0, |index| {
+            self.grain_tables[index][(grain % NUM_GTES_PER_GT as u64) as usize]
+        });
+        // Without a parent, unallocated grains read as zeroes just like zero grains.
+        if gte <= GTE_ZERO_GRAIN {
+            BlockMapping::Zero
+        } else {
+            BlockMapping::Data(gte as u64 * SECTOR_SIZE)
+        }
+    }
+}
+
+/// Opens a monolithic sparse VMDK image for read-only access.
+pub fn open(mut file: File) -> Result<BlockMappedDisk<Vmdk>> {
+    let vmdk = Vmdk::from_file(&mut file)?;
+    Ok(BlockMappedDisk::new(file, vmdk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: &str = "# Disk DescriptorFile\n\
+        version=1\n\
+        CID=12345678\n\
+        parentCID=ffffffff\n\
+        createType=\"monolithicSparse\"\n\
+        \n\
+        RW 8192 SPARSE \"test.vmdk\"\n";
+
+    fn write_at(file: &mut File, offset: u64, data: &[u8]) {
+        let mut data = data.to_vec();
+        file.write_all_at_volatile(VolatileSlice::new(&mut data), offset)
+            .unwrap();
+    }
+
+    fn header(flags: u32) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&VMDK_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&1u32.to_le_bytes());
+        header[8..12].copy_from_slice(&(flags | FLAG_VALID_NEWLINE_DETECTION).to_le_bytes());
+        // 4 MiB disk with 4 KiB grains, so two grain tables.
+        header[12..20].copy_from_slice(&8192u64.to_le_bytes());
+        header[20..28].copy_from_slice(&8u64.to_le_bytes());
+        header[28..36].copy_from_slice(&1u64.to_le_bytes());
+        header[36..44].copy_from_slice(&1u64.to_le_bytes());
+        header[44..48].copy_from_slice(&NUM_GTES_PER_GT.to_le_bytes());
+        header[56..64].copy_from_slice(&2u64.to_le_bytes());
+        header[73..77].copy_from_slice(b"\n \r\n");
+        header
+    }
+
+    /// Creates an image with grain 0 at sector 16 filled with 0x11, grain 300 marked as zero and
+    /// grain 400 at sector 24 filled with 0x22. The second grain table is unallocated.
+    fn test_image(descriptor: &str) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        write_at(&mut file, 0, &header(0));
+        write_at(&mut file, SECTOR_SIZE, descriptor.as_bytes());
+        // The grain directory at sector 2 points to the grain table at sector 3.
+        write_at(&mut file, 2 * SECTOR_SIZE, &[3, 0, 0, 0, 0, 0, 0, 0]);
+        let mut gt = vec![0u32; NUM_GTES_PER_GT as usize];
+        gt[0] = 16;
+        gt[300] = GTE_ZERO_GRAIN;
+        gt[400] = 24;
+        let gt: Vec<u8> = gt.iter().flat_map(|e| e.to_le_bytes()).collect();
+        write_at(&mut file, 3 * SECTOR_SIZE, &gt);
+        write_at(&mut file, 16 * SECTOR_SIZE, &[0x11; 4096]);
+        write_at(&mut file, 24 * SECTOR_SIZE, &[0x22; 4096]);
+        file
+    }
+
+    #[test]
+    fn read_grains() {
+        let mut disk = open(test_image(DESCRIPTOR)).unwrap();
+        assert_eq!(disk.get_len().unwrap(), 4 << 20);
+        let mut buf = vec![0x55u8; 4 << 20];
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut buf), 0)
+            .unwrap();
+        for (grain, data) in buf.chunks(4096).enumerate() {
+            let expected = match grain {
+                0 => 0x11,
+                400 => 0x22,
+                _ => 0,
+            };
+            assert!(data.iter().all(|b| *b == expected), "grain {}", grain);
+        }
+    }
+
+    #[test]
+    fn delta_link_rejected() {
+        let descriptor = DESCRIPTOR.replace("parentCID=ffffffff", "parentCID=87654321");
+        assert!(matches!(
+            open(test_image(&descriptor)),
+            Err(Error::HasParent)
+        ));
+    }
+
+    #[test]
+    fn split_extent_rejected() {
+        let descriptor = DESCRIPTOR.replace("monolithicSparse", "twoGbMaxExtentSparse");
+        assert!(matches!(
+            open(test_image(&descriptor)),
+            Err(Error::UnsupportedCreateType(_))
+        ));
+    }
+
+    #[test]
+    fn stream_optimized_rejected() {
+        let mut file = test_image(DESCRIPTOR);
+        write_at(&mut file, 0, &header(FLAG_COMPRESSED_GRAINS | FLAG_MARKERS));
+        assert!(matches!(open(file), Err(Error::Compressed)));
+    }
+
+    #[test]
+    fn converted_line_endings_rejected() {
+        let mut file = test_image(DESCRIPTOR);
+        write_at(&mut file, 75, b"\n");
+        assert!(matches!(open(file), Err(Error::InvalidLineEndings)));
+    }
+
+    #[test]
+    fn grain_past_end_of_file() {
+        let mut file = test_image(DESCRIPTOR);
+        write_at(&mut file, 3 * SECTOR_SIZE + 4, &1000u32.to_le_bytes());
+        assert!(matches!(
+            open(file),
+            Err(Error::InvalidGrainTableEntry(1000))
+        ));
+    }
+}
@@ -18,7 +18,10 @@ use crosvm_fuzz::fuzz_target;
 use disk::QcowFile;
 
 // Take the first 64 bits of data as an address and the next 64 bits as data to
-// store there. The rest of the data is used as a qcow image.
+// store there. The rest of the data is used as a qcow image. The address is read
+// before it is written to exercise both the read path (including decompression of
+// compressed clusters) and the write path (including copy-on-write of compressed
+// clusters).
 fuzz_target!(|bytes| {
     if bytes.len() < 16 {
         // Need an address and data, each are 8 bytes.
@@ -32,6 +35,9 @@ fuzz_target!(|bytes| {
     disk_file.write_all(&bytes[16..]).unwrap();
     disk_file.seek(SeekFrom::Start(0)).unwrap();
     if let Ok(mut qcow) = QcowFile::from(disk_file, max_nesting_depth) {
+        let mut read_mem = [0u8; size_of::<u64>()];
+        let read_vslice = VolatileSlice::new(&mut read_mem);
+        let _ = qcow.read_exact_at_volatile(read_vslice, addr);
         let mut mem = value.to_le_bytes().to_owned();
         let vslice = VolatileSlice::new(&mut mem);
         let _ = qcow.write_all_at_volatile(vslice, addr);
@@ -0,0 +1,52 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+#![cfg(not(test))]
+#![no_main]
+
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::mem::size_of;
+use std::path::Path;
+
+use base::FileReadWriteAtVolatile;
+use base::VolatileSlice;
+use crosvm_fuzz::fuzz_target;
+use disk::create_disk_file_of_type;
+use disk::ImageType;
+
+// Take the first 64 bits of data as an address to read from. The rest of the data is used as a
+// VMDK image.
+fuzz_target!(|bytes| {
+    if bytes.len() < 8 {
+        // Need an address, which is 8 bytes.
+        return;
+    }
+    let mut disk_image = Cursor::new(bytes);
+    let addr = read_u64(&mut disk_image);
+    let max_nesting_depth = 10;
+    let mut disk_file = tempfile::tempfile().unwrap();
+    disk_file.write_all(&bytes[8..]).unwrap();
+    disk_file.seek(SeekFrom::Start(0)).unwrap();
+    if let Ok(mut disk) = create_disk_file_of_type(
+        disk_file,
+        false,
+        max_nesting_depth,
+        Path::new(""),
+        ImageType::Vmdk,
+    ) {
+        let mut read_mem = [0u8; 4096];
+        let read_vslice = VolatileSlice::new(&mut read_mem);
+        let _ = disk.read_exact_at_volatile(read_vslice, addr);
+    }
+});
+
+fn read_u64<T: Read>(readable: &mut T) -> u64 {
+    let mut buf = [0u8; size_of::<u64>()];
+    readable.read_exact(&mut buf[..]).unwrap();
+    u64::from_le_bytes(buf)
+}
@@ -0,0 +1,1370 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A display backend serving each scanout to VNC clients with the remote framebuffer protocol
+//! (RFB 3.8, also accepting 3.3 and 3.7 clients).
+//!
+//! Each scanout gets its own listener and serves one client at a time; a new connection replaces
+//! the current one. The framebuffer is sent with the raw encoding, only the rows that changed since
+//! the previous update are sent in response to incremental update requests. Updates are written
+//! without blocking, and while a slow client is still receiving one, the frames flipped in the
+//! meantime are merged into the next update instead of being queued. Key events are
+//! translated from X keysyms into Linux key codes assuming a US layout in the guest, and the left
+//! pointer button drives a single touch contact, like the X backend does.
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::ops::Range;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::rc::Weak;
+use std::str::FromStr;
+use std::time::Duration;
+
+use base::error;
+use base::info;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::UnlinkUnixListener;
+use base::VolatileSlice;
+use base::WaitContext;
+use linux_input_sys::constants::*;
+use linux_input_sys::virtio_input_event;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::DisplayT;
+use crate::EventDeviceKind;
+use crate::GpuDisplayError;
+use crate::GpuDisplayEvents;
+use crate::GpuDisplayFramebuffer;
+use crate::GpuDisplayResult;
+use crate::GpuDisplaySurface;
+use crate::SurfaceType;
+use crate::SysDisplayT;
+
+const RFB_VERSION: &[u8; 12] = b"RFB 003.008\n";
+const SECURITY_TYPE_NONE: u8 = 1;
+const SECURITY_RESULT_OK: u32 = 0;
+const SECURITY_RESULT_FAILED: u32 = 1;
+
+// Client to server messages.
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+// Server to client messages.
+const FRAMEBUFFER_UPDATE: u8 = 0;
+const ENCODING_RAW: i32 = 0;
+
+const BYTES_PER_PIXEL: usize = 4;
+const POINTER_BUTTON_LEFT: u8 = 1;
+const READ_SIZE: usize = 4096;
+const MAX_CUT_TEXT_LEN: usize = 1 << 20;
+
+/// Address the VNC server of the first scanout listens on.
+///
+/// The following scanouts are served on the next TCP ports, or on unix sockets whose paths are the
+/// path of the first one followed by `.N`, where `N` is the index of the scanout.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VncAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl VncAddress {
+    fn for_scanout(&self, scanout_id: u32) -> Option<VncAddress> {
+        match self {
+            VncAddress::Tcp(addr) => {
+                let port = u16::try_from(scanout_id)
+                    .ok()
+                    .and_then(|id| addr.port().checked_add(id))?;
+                Some(VncAddress::Tcp(SocketAddr::new(addr.ip(), port)))
+            }
+            VncAddress::Unix(path) if scanout_id == 0 => Some(VncAddress::Unix(path.clone())),
+            VncAddress::Unix(path) => {
+                let mut path = path.clone().into_os_string();
+                path.push(format!(".{}", scanout_id));
+                Some(VncAddress::Unix(path.into()))
+            }
+        }
+    }
+
+    fn listen(&self) -> io::Result<VncListener> {
+        match self {
+            VncAddress::Tcp(addr) => TcpListener::bind(addr).map(VncListener::Tcp),
+            VncAddress::Unix(path) => UnixListener::bind(path)
+                .map(|listener| VncListener::Unix(UnlinkUnixListener(listener))),
+        }
+    }
+}
+
+impl FromStr for VncAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            Ok(VncAddress::Tcp(addr))
+        } else if s.contains('/') {
+            Ok(VncAddress::Unix(PathBuf::from(s)))
+        } else {
+            Err(format!(
+                "invalid VNC address `{}`: expected IP:PORT or the path of a unix socket",
+                s
+            ))
+        }
+    }
+}
+
+impl fmt::Display for VncAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VncAddress::Tcp(addr) => write!(f, "{}", addr),
+            VncAddress::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+enum VncListener {
+    Tcp(TcpListener),
+    Unix(UnlinkUnixListener),
+}
+
+impl VncListener {
+    fn accept(&self) -> io::Result<VncStream> {
+        let stream = match self {
+            VncListener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                // The GPU worker must not wait for a slow client.
+                stream.set_nonblocking(true)?;
+                VncStream::Tcp(stream)
+            }
+            VncListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                VncStream::Unix(stream)
+            }
+        };
+        Ok(stream)
+    }
+}
+
+impl AsRawDescriptor for VncListener {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match self {
+            VncListener::Tcp(listener) => listener.as_raw_descriptor(),
+            VncListener::Unix(listener) => listener.as_raw_descriptor(),
+        }
+    }
+}
+
+enum VncStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for VncStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            VncStream::Tcp(stream) => stream.read(buf),
+            VncStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for VncStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            VncStream::Tcp(stream) => stream.write(buf),
+            VncStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            VncStream::Tcp(stream) => stream.flush(),
+            VncStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawDescriptor for VncStream {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match self {
+            VncStream::Tcp(stream) => stream.as_raw_descriptor(),
+            VncStream::Unix(stream) => stream.as_raw_descriptor(),
+        }
+    }
+}
+
+/// A true colour pixel format, as exchanged in the ServerInit and SetPixelFormat messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// The XRGB8888 format of the surfaces, which clients get unless they ask for another one.
+    const NATIVE: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn from_bytes(b: &[u8; 16]) -> io::Result<PixelFormat> {
+        let format = PixelFormat {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            red_max: u16::from_be_bytes([b[4], b[5]]),
+            green_max: u16::from_be_bytes([b[6], b[7]]),
+            blue_max: u16::from_be_bytes([b[8], b[9]]),
+            red_shift: b[10],
+            green_shift: b[11],
+            blue_shift: b[12],
+        };
+        // Colour maps are not supported.
+        if b[3] == 0 {
+            return Err(invalid_data("colour map pixel formats are not supported"));
+        }
+        if !matches!(format.bits_per_pixel, 8 | 16 | 32)
+            || [format.red_shift, format.green_shift, format.blue_shift]
+                .iter()
+                .any(|&shift| shift >= format.bits_per_pixel)
+        {
+            return Err(invalid_data("invalid pixel format"));
+        }
+        Ok(format)
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let [red_max_hi, red_max_lo] = self.red_max.to_be_bytes();
+        let [green_max_hi, green_max_lo] = self.green_max.to_be_bytes();
+        let [blue_max_hi, blue_max_lo] = self.blue_max.to_be_bytes();
+        [
+            self.bits_per_pixel,
+            self.depth,
+            self.big_endian as u8,
+            1, // true colour
+            red_max_hi,
+            red_max_lo,
+            green_max_hi,
+            green_max_lo,
+            blue_max_hi,
+            blue_max_lo,
+            self.red_shift,
+            self.green_shift,
+            self.blue_shift,
+            0,
+            0,
+            0,
+        ]
+    }
+
+    /// Appends the XRGB8888 pixels of `src` to `out`, converted to this format.
+    fn encode(&self, src: &[u8], out: &mut Vec<u8>) {
+        if *self == PixelFormat::NATIVE {
+            out.extend_from_slice(src);
+            return;
+        }
+        let scale = |component: u32, max: u16| component * max as u32 / 255;
+        for pixel in src.chunks_exact(BYTES_PER_PIXEL) {
+            let [blue, green, red, _] = [pixel[0], pixel[1], pixel[2], pixel[3]].map(u32::from);
+            let value = scale(red, self.red_max) << self.red_shift
+                | scale(green, self.green_max) << self.green_shift
+                | scale(blue, self.blue_max) << self.blue_shift;
+            match (self.bits_per_pixel, self.big_endian) {
+                (8, _) => out.push(value as u8),
+                (16, false) => out.extend_from_slice(&(value as u16).to_le_bytes()),
+                (16, true) => out.extend_from_slice(&(value as u16).to_be_bytes()),
+                (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+                (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+            }
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A message sent by a client once initialized, that needs to be acted upon.
+#[derive(Debug, PartialEq, Eq)]
+enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    FramebufferUpdateRequest {
+        incremental: bool,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    KeyEvent {
+        down: bool,
+        keysym: u32,
+    },
+    PointerEvent {
+        buttons: u8,
+        x: u16,
+        y: u16,
+    },
+}
+
+/// Parses the client message at the beginning of `buf`.
+///
+/// Returns the length of the message and the message if it needs to be acted upon, or `None` if
+/// `buf` does not hold a complete message yet.
+fn parse_client_message(buf: &[u8]) -> io::Result<Option<(usize, Option<ClientMessage>)>> {
+    let Some(&message_type) = buf.first() else {
+        return Ok(None);
+    };
+    let len = match message_type {
+        SET_PIXEL_FORMAT => 20,
+        SET_ENCODINGS => match buf.get(2..4) {
+            Some(count) => 4 + 4 * u16::from_be_bytes([count[0], count[1]]) as usize,
+            None => return Ok(None),
+        },
+        FRAMEBUFFER_UPDATE_REQUEST => 10,
+        KEY_EVENT => 8,
+        POINTER_EVENT => 6,
+        CLIENT_CUT_TEXT => match buf.get(4..8) {
+            Some(len) => {
+                let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+                if len > MAX_CUT_TEXT_LEN {
+                    return Err(invalid_data("cut text too long"));
+                }
+                8 + len
+            }
+            None => return Ok(None),
+        },
+        _ => return Err(invalid_data("unknown client message")),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+    let message = match message_type {
+        SET_PIXEL_FORMAT => Some(ClientMessage::SetPixelFormat(PixelFormat::from_bytes(
+            buf[4..20].try_into().unwrap(),
+        )?)),
+        FRAMEBUFFER_UPDATE_REQUEST => Some(ClientMessage::FramebufferUpdateRequest {
+            incremental: buf[1] != 0,
+            x: u16_at(2),
+            y: u16_at(4),
+            width: u16_at(6),
+            height: u16_at(8),
+        }),
+        KEY_EVENT => Some(ClientMessage::KeyEvent {
+            down: buf[1] != 0,
+            keysym: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        }),
+        POINTER_EVENT => Some(ClientMessage::PointerEvent {
+            buttons: buf[1],
+            x: u16_at(2),
+            y: u16_at(4),
+        }),
+        // Only the raw encoding is used, and the clipboard is not shared with the guest.
+        _ => None,
+    };
+    Ok(Some((len, message)))
+}
+
+/// Translates an X keysym into the Linux key code of the key producing it on a US keyboard.
+fn keysym_to_linux_keycode(keysym: u32) -> Option<u16> {
+    let keycode = match keysym {
+        0x20 => KEY_SPACE,
+        0x21 | 0x31 => KEY_1, // ! 1
+        0x40 | 0x32 => KEY_2, // @ 2
+        0x23 | 0x33 => KEY_3, // # 3
+        0x24 | 0x34 => KEY_4, // $ 4
+        0x25 | 0x35 => KEY_5, // % 5
+        0x5e | 0x36 => KEY_6, // ^ 6
+        0x26 | 0x37 => KEY_7, // & 7
+        0x2a | 0x38 => KEY_8, // * 8
+        0x28 | 0x39 => KEY_9, // ( 9
+        0x29 | 0x30 => KEY_0, // ) 0
+        0x5f | 0x2d => KEY_MINUS,
+        0x2b | 0x3d => KEY_EQUAL,
+        0x7b | 0x5b => KEY_LEFTBRACE,
+        0x7d | 0x5d => KEY_RIGHTBRACE,
+        0x3a | 0x3b => KEY_SEMICOLON,
+        0x22 | 0x27 => KEY_APOSTROPHE,
+        0x7e | 0x60 => KEY_GRAVE,
+        0x7c | 0x5c => KEY_BACKSLASH,
+        0x3c | 0x2c => KEY_COMMA,
+        0x3e | 0x2e => KEY_DOT,
+        0x3f | 0x2f => KEY_SLASH,
+        0x41..=0x5a => return keysym_to_linux_keycode(keysym + 0x20),
+        0x61 => KEY_A,
+        0x62 => KEY_B,
+        0x63 => KEY_C,
+        0x64 => KEY_D,
+        0x65 => KEY_E,
+        0x66 => KEY_F,
+        0x67 => KEY_G,
+        0x68 => KEY_H,
+        0x69 => KEY_I,
+        0x6a => KEY_J,
+        0x6b => KEY_K,
+        0x6c => KEY_L,
+        0x6d => KEY_M,
+        0x6e => KEY_N,
+        0x6f => KEY_O,
+        0x70 => KEY_P,
+        0x71 => KEY_Q,
+        0x72 => KEY_R,
+        0x73 => KEY_S,
+        0x74 => KEY_T,
+        0x75 => KEY_U,
+        0x76 => KEY_V,
+        0x77 => KEY_W,
+        0x78 => KEY_X,
+        0x79 => KEY_Y,
+        0x7a => KEY_Z,
+        0xfe03 => KEY_RIGHTALT, // ISO_Level3_Shift (AltGr)
+        0xff08 => KEY_BACKSPACE,
+        0xff09 => KEY_TAB,
+        0xff0d => KEY_ENTER,
+        0xff13 => KEY_PAUSE,
+        0xff14 => KEY_SCROLLLOCK,
+        0xff15 => KEY_SYSRQ,
+        0xff1b => KEY_ESC,
+        0xff50 => KEY_HOME,
+        0xff51 => KEY_LEFT,
+        0xff52 => KEY_UP,
+        0xff53 => KEY_RIGHT,
+        0xff54 => KEY_DOWN,
+        0xff55 => KEY_PAGEUP,
+        0xff56 => KEY_PAGEDOWN,
+        0xff57 => KEY_END,
+        0xff61 => KEY_SYSRQ, // Print
+        0xff63 => KEY_INSERT,
+        0xff67 => KEY_COMPOSE, // Menu
+        0xff7f => KEY_NUMLOCK,
+        0xff8d => KEY_KPENTER,
+        0xff95 => KEY_KP7,   // KP_Home
+        0xff96 => KEY_KP4,   // KP_Left
+        0xff97 => KEY_KP8,   // KP_Up
+        0xff98 => KEY_KP6,   // KP_Right
+        0xff99 => KEY_KP2,   // KP_Down
+        0xff9a => KEY_KP9,   // KP_Page_Up
+        0xff9b => KEY_KP3,   // KP_Page_Down
+        0xff9c => KEY_KP1,   // KP_End
+        0xff9d => KEY_KP5,   // KP_Begin
+        0xff9e => KEY_KP0,   // KP_Insert
+        0xff9f => KEY_KPDOT, // KP_Delete
+        0xffaa => KEY_KPASTERISK,
+        0xffab => KEY_KPPLUS,
+        0xffac => KEY_KPCOMMA,
+        0xffad => KEY_KPMINUS,
+        0xffae => KEY_KPDOT,
+        0xffaf => KEY_KPSLASH,
+        0xffb0 => KEY_KP0,
+        0xffb1 => KEY_KP1,
+        0xffb2 => KEY_KP2,
+        0xffb3 => KEY_KP3,
+        0xffb4 => KEY_KP4,
+        0xffb5 => KEY_KP5,
+        0xffb6 => KEY_KP6,
+        0xffb7 => KEY_KP7,
+        0xffb8 => KEY_KP8,
+        0xffb9 => KEY_KP9,
+        0xffbd => KEY_KPEQUAL,
+        0xffbe..=0xffc7 => KEY_F1 + (keysym - 0xffbe) as u16, // F1 to F10
+        0xffc8 => KEY_F11,
+        0xffc9 => KEY_F12,
+        0xffe1 => KEY_LEFTSHIFT,
+        0xffe2 => KEY_RIGHTSHIFT,
+        0xffe3 => KEY_LEFTCTRL,
+        0xffe4 => KEY_RIGHTCTRL,
+        0xffe5 => KEY_CAPSLOCK,
+        0xffe7 | 0xffeb => KEY_LEFTMETA,  // Meta_L, Super_L
+        0xffe8 | 0xffec => KEY_RIGHTMETA, // Meta_R, Super_R
+        0xffe9 => KEY_LEFTALT,
+        0xffea => KEY_RIGHTALT,
+        0xffff => KEY_DELETE,
+        _ => return None,
+    };
+    Some(keycode)
+}
+
+enum ClientState {
+    /// Waiting for the ProtocolVersion message.
+    Version,
+    /// Waiting for the security type chosen by the client.
+    Security,
+    /// Waiting for the ClientInit message.
+    Init,
+    /// Exchanging normal protocol messages.
+    Ready,
+}
+
+struct VncClient {
+    stream: VncStream,
+    state: ClientState,
+    minor_version: u8,
+    input: Vec<u8>,
+    /// Messages queued for the client, written as fast as it reads them.
+    output: VecDeque<u8>,
+    /// Whether the client is polled for writability, because some output is left.
+    polling_writable: bool,
+    pixel_format: PixelFormat,
+    /// Whether an incremental update request is waiting for the framebuffer to change.
+    update_requested: bool,
+    buttons: u8,
+}
+
+impl VncClient {
+    fn new(stream: VncStream) -> VncClient {
+        VncClient {
+            stream,
+            state: ClientState::Version,
+            minor_version: 8,
+            input: Vec::new(),
+            output: RFB_VERSION.iter().copied().collect(),
+            polling_writable: false,
+            pixel_format: PixelFormat::NATIVE,
+            update_requested: false,
+            buttons: 0,
+        }
+    }
+
+    /// Queues `msg` to be sent to the client.
+    fn send(&mut self, msg: &[u8]) {
+        self.output.extend(msg);
+    }
+
+    /// Writes as much of the queued output as the client accepts without blocking.
+    fn write_output(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            let (front, _) = self.output.as_slices();
+            match self.stream.write(front) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(len) => {
+                    self.output.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the handshake as far as the received bytes allow. Returns true once it is complete.
+    fn handshake(&mut self, width: u32, height: u32, name: &str) -> io::Result<bool> {
+        loop {
+            match self.state {
+                ClientState::Version => {
+                    let Some(version) = self.input.get(..12) else {
+                        return Ok(false);
+                    };
+                    let minor_version = match version {
+                        [b'R', b'F', b'B', b' ', b'0', b'0', b'3', b'.', minor @ .., b'\n'] => {
+                            std::str::from_utf8(minor)
+                                .ok()
+                                .and_then(|minor| minor.parse::<u16>().ok())
+                                .ok_or_else(|| invalid_data("invalid protocol version"))?
+                        }
+                        _ => return Err(invalid_data("invalid protocol version")),
+                    };
+                    self.input.drain(..12);
+                    if minor_version < 7 {
+                        // Version 3.3: the server decides on the security type.
+                        self.minor_version = 3;
+                        self.send(&(SECURITY_TYPE_NONE as u32).to_be_bytes());
+                        self.state = ClientState::Init;
+                    } else {
+                        self.minor_version = min(minor_version, 8) as u8;
+                        self.send(&[1, SECURITY_TYPE_NONE]);
+                        self.state = ClientState::Security;
+                    }
+                }
+                ClientState::Security => {
+                    let Some(&security_type) = self.input.first() else {
+                        return Ok(false);
+                    };
+                    self.input.drain(..1);
+                    if security_type != SECURITY_TYPE_NONE {
+                        if self.minor_version >= 8 {
+                            let reason = b"unsupported security type";
+                            let mut msg = SECURITY_RESULT_FAILED.to_be_bytes().to_vec();
+                            msg.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+                            msg.extend_from_slice(reason);
+                            self.send(&msg);
+                        }
+                        return Err(invalid_data("unsupported security type"));
+                    }
+                    if self.minor_version >= 8 {
+                        self.send(&SECURITY_RESULT_OK.to_be_bytes());
+                    }
+                    self.state = ClientState::Init;
+                }
+                ClientState::Init => {
+                    // The shared flag does not matter since there is a single client at a time.
+                    if self.input.is_empty() {
+                        return Ok(false);
+                    }
+                    self.input.drain(..1);
+                    let mut msg = Vec::new();
+                    msg.extend_from_slice(&(width as u16).to_be_bytes());
+                    msg.extend_from_slice(&(height as u16).to_be_bytes());
+                    msg.extend_from_slice(&self.pixel_format.to_bytes());
+                    msg.extend_from_slice(&(name.len() as u32).to_be_bytes());
+                    msg.extend_from_slice(name.as_bytes());
+                    self.send(&msg);
+                    self.state = ClientState::Ready;
+                }
+                ClientState::Ready => return Ok(true),
+            }
+        }
+    }
+
+    /// Queues the given rows of `framebuffer`.
+    fn send_update(&mut self, framebuffer: &[u8], stride: usize, rows: Range<usize>) {
+        let width = stride / BYTES_PER_PIXEL;
+        let bytes_per_pixel = self.pixel_format.bits_per_pixel as usize / 8;
+        let mut msg = Vec::with_capacity(16 + width * rows.len() * bytes_per_pixel);
+        msg.extend_from_slice(&[FRAMEBUFFER_UPDATE, 0]);
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        for v in [0, rows.start, width, rows.len()] {
+            msg.extend_from_slice(&(v as u16).to_be_bytes());
+        }
+        msg.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+        for row in rows {
+            self.pixel_format
+                .encode(&framebuffer[row * stride..][..stride], &mut msg);
+        }
+        self.send(&msg);
+    }
+}
+
+/// The VNC server of a scanout, shared by its surface and the display.
+struct VncServer {
+    wait_ctx: Rc<WaitContext<VncToken>>,
+    surface_id: u32,
+    listener: VncListener,
+    client: Option<VncClient>,
+    name: String,
+    width: u32,
+    height: u32,
+    /// The framebuffer shown to clients.
+    framebuffer: Vec<u8>,
+    /// The rows that changed since the last update sent to the client, or that it asked for.
+    dirty_rows: Option<Range<usize>>,
+    /// Input events received from the client, to be sent to the event devices.
+    events: VecDeque<GpuDisplayEvents>,
+    tracking_id: u16,
+}
+
+impl VncServer {
+    fn stride(&self) -> usize {
+        self.width as usize * BYTES_PER_PIXEL
+    }
+
+    fn client_token(&self) -> VncToken {
+        VncToken::Client {
+            surface_id: self.surface_id,
+        }
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        let stream = self.listener.accept()?;
+        self.disconnect();
+        let client = VncClient::new(stream);
+        self.wait_ctx.add(&client.stream, self.client_token())?;
+        info!("VNC client connected to {}", self.name);
+        self.client = Some(client);
+        self.dirty_rows = None;
+        self.flush_client()
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(client) = self.client.take() {
+            let _ = self.wait_ctx.delete(&client.stream);
+            info!("VNC client disconnected from {}", self.name);
+            if client.buttons & POINTER_BUTTON_LEFT != 0 {
+                // Lift the finger the client left on the touchscreen.
+                self.push_touch_events(POINTER_BUTTON_LEFT, 0, 0, 0);
+            }
+        }
+    }
+
+    /// Reads what the client sent and processes the complete messages.
+    fn read_client(&mut self) -> io::Result<()> {
+        let Some(client) = self.client.as_mut() else {
+            return Ok(());
+        };
+        let mut buf = [0u8; READ_SIZE];
+        let len = match client.stream.read(&mut buf) {
+            Ok(len) => len,
+            // The client may only have been signaled as writable.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        client.input.extend_from_slice(&buf[..len]);
+        if !client.handshake(self.width, self.height, &self.name)? {
+            return Ok(());
+        }
+        let mut messages = Vec::new();
+        while let Some((len, message)) = parse_client_message(&client.input)? {
+            client.input.drain(..len);
+            messages.extend(message);
+        }
+        for message in messages {
+            self.handle_message(message);
+        }
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message: ClientMessage) {
+        // Only called with a client.
+        let client = self.client.as_mut().unwrap();
+        match message {
+            ClientMessage::SetPixelFormat(pixel_format) => client.pixel_format = pixel_format,
+            ClientMessage::FramebufferUpdateRequest {
+                incremental,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                client.update_requested = true;
+                // The requested rows are sent in full even if they did not change, once the client
+                // has received the previous update.
+                let y = min(y as u32, self.height) as usize;
+                let height = min(height as usize, self.height as usize - y);
+                if !incremental && (x as u32) < self.width && width > 0 && height > 0 {
+                    self.mark_dirty(y..y + height);
+                }
+            }
+            ClientMessage::KeyEvent { down, keysym } => {
+                if let Some(keycode) = keysym_to_linux_keycode(keysym) {
+                    self.events.push_back(GpuDisplayEvents {
+                        events: vec![virtio_input_event::key(keycode, down, false)],
+                        device_type: EventDeviceKind::Keyboard,
+                    });
+                }
+            }
+            ClientMessage::PointerEvent { buttons, x, y } => {
+                let previous_buttons = client.buttons;
+                client.buttons = buttons;
+                let x = min(x as u32, self.width.saturating_sub(1)) as i32;
+                let y = min(y as u32, self.height.saturating_sub(1)) as i32;
+                self.push_touch_events(previous_buttons, buttons, x, y);
+            }
+        }
+    }
+
+    /// Turns the left button state and the pointer position into a single touch contact.
+    fn push_touch_events(&mut self, previous_buttons: u8, buttons: u8, x: i32, y: i32) {
+        let was_touching = previous_buttons & POINTER_BUTTON_LEFT != 0;
+        let touching = buttons & POINTER_BUTTON_LEFT != 0;
+        // The slot event *must* be first per the Linux input subsystem's guidance.
+        let mut events = vec![virtio_input_event::multitouch_slot(0)];
+        match (was_touching, touching) {
+            (false, false) => return,
+            (false, true) => {
+                self.tracking_id = self.tracking_id.wrapping_add(1);
+                events.push(virtio_input_event::multitouch_tracking_id(
+                    self.tracking_id as i32,
+                ));
+                events.push(virtio_input_event::multitouch_absolute_x(x));
+                events.push(virtio_input_event::multitouch_absolute_y(y));
+            }
+            (true, true) => {
+                events.push(virtio_input_event::multitouch_absolute_x(x));
+                events.push(virtio_input_event::multitouch_absolute_y(y));
+            }
+            (true, false) => events.push(virtio_input_event::multitouch_tracking_id(-1)),
+        }
+        self.events.push_back(GpuDisplayEvents {
+            events,
+            device_type: EventDeviceKind::Touchscreen,
+        });
+    }
+
+    /// Adds `rows` to the rows sent in the next update.
+    fn mark_dirty(&mut self, rows: Range<usize>) {
+        self.dirty_rows = Some(match self.dirty_rows.take() {
+            Some(dirty) => min(dirty.start, rows.start)..dirty.end.max(rows.end),
+            None => rows,
+        });
+    }
+
+    /// Writes the output queued for the client, and answers its pending update request once the
+    /// previous update was fully written. The client is polled for writability while some output
+    /// is left.
+    fn flush_client(&mut self) -> io::Result<()> {
+        let stride = self.stride();
+        let token = self.client_token();
+        let Some(client) = self.client.as_mut() else {
+            return Ok(());
+        };
+        client.write_output()?;
+        if client.output.is_empty() && client.update_requested {
+            if let Some(rows) = self.dirty_rows.take() {
+                client.update_requested = false;
+                client.send_update(&self.framebuffer, stride, rows);
+                client.write_output()?;
+            }
+        }
+        let polling_writable = !client.output.is_empty();
+        if polling_writable != client.polling_writable {
+            let event_type = if polling_writable {
+                EventType::ReadWrite
+            } else {
+                EventType::Read
+            };
+            self.wait_ctx.modify(&client.stream, event_type, token)?;
+            client.polling_writable = polling_writable;
+        }
+        Ok(())
+    }
+
+    /// Replaces the framebuffer shown to clients with `framebuffer`.
+    fn update(&mut self, framebuffer: &[u8]) -> io::Result<()> {
+        if matches!(
+            &self.client,
+            Some(VncClient {
+                state: ClientState::Ready,
+                ..
+            })
+        ) {
+            let stride = self.stride();
+            let rows = self.framebuffer.len() / stride;
+            let row_changed = |row: &usize| {
+                self.framebuffer[row * stride..][..stride] != framebuffer[row * stride..][..stride]
+            };
+            if let Some(first) = (0..rows).find(row_changed) {
+                let last = (first..rows).rev().find(row_changed).unwrap_or(first);
+                self.mark_dirty(first..last + 1);
+            }
+        }
+        self.framebuffer.copy_from_slice(framebuffer);
+        self.flush_client()
+    }
+}
+
+struct VncSurface {
+    surface_id: u32,
+    server: Rc<RefCell<VncServer>>,
+    /// The buffer the guest draws into, copied to the server on flip.
+    buffer: Vec<u8>,
+    stride: u32,
+}
+
+impl GpuDisplaySurface for VncSurface {
+    fn surface_descriptor(&self) -> u64 {
+        self.surface_id as u64
+    }
+
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(self.buffer.as_mut_slice()),
+            self.stride,
+            BYTES_PER_PIXEL as u32,
+        ))
+    }
+
+    fn flip(&mut self) {
+        let mut server = self.server.borrow_mut();
+        if let Err(e) = server.update(&self.buffer) {
+            error!(
+                "failed to send a framebuffer update to the VNC client: {}",
+                e
+            );
+            server.disconnect();
+        }
+    }
+}
+
+#[derive(EventToken, Clone, Copy, Debug)]
+enum VncToken {
+    Listener { surface_id: u32 },
+    Client { surface_id: u32 },
+}
+
+pub struct DisplayVnc {
+    address: VncAddress,
+    /// Shared with the servers, which poll their client for writability while it has output left.
+    wait_ctx: Rc<WaitContext<VncToken>>,
+    servers: BTreeMap<u32, Weak<RefCell<VncServer>>>,
+    /// Tokens that were signaled and are yet to be handled.
+    ready: RefCell<VecDeque<VncToken>>,
+}
+
+impl DisplayVnc {
+    pub fn new(address: VncAddress) -> GpuDisplayResult<DisplayVnc> {
+        Ok(DisplayVnc {
+            address,
+            wait_ctx: Rc::new(WaitContext::new()?),
+            servers: BTreeMap::new(),
+            ready: Default::default(),
+        })
+    }
+
+    fn server(&self, surface_id: u32) -> Option<Rc<RefCell<VncServer>>> {
+        self.servers.get(&surface_id)?.upgrade()
+    }
+
+    fn handle_token(&mut self, token: VncToken) {
+        match token {
+            VncToken::Listener { surface_id } => {
+                let Some(server) = self.server(surface_id) else {
+                    return;
+                };
+                // The signaled client, if any, is about to be replaced.
+                self.ready.get_mut().retain(
+                    |t| !matches!(t, VncToken::Client { surface_id: id } if *id == surface_id),
+                );
+                let mut server = server.borrow_mut();
+                if let Err(e) = server.accept() {
+                    error!("failed to accept a VNC client on {}: {}", server.name, e);
+                }
+            }
+            VncToken::Client { surface_id } => {
+                let Some(server) = self.server(surface_id) else {
+                    return;
+                };
+                let mut server = server.borrow_mut();
+                if let Err(e) = server.read_client().and_then(|()| server.flush_client()) {
+                    if e.kind() != io::ErrorKind::UnexpectedEof {
+                        error!("VNC client of {} failed: {}", server.name, e);
+                    }
+                    server.disconnect();
+                }
+            }
+        }
+    }
+}
+
+impl DisplayT for DisplayVnc {
+    fn pending_events(&self) -> bool {
+        if self
+            .servers
+            .values()
+            .filter_map(Weak::upgrade)
+            .any(|server| !server.borrow().events.is_empty())
+        {
+            return true;
+        }
+        let mut ready = self.ready.borrow_mut();
+        if ready.is_empty() {
+            match self.wait_ctx.wait_timeout(Duration::ZERO) {
+                Ok(events) => ready.extend(events.iter().map(|e| e.token)),
+                Err(e) => error!("failed to wait for VNC clients: {}", e),
+            }
+        }
+        !ready.is_empty()
+    }
+
+    fn next_event(&mut self) -> GpuDisplayResult<u64> {
+        self.servers.retain(|_, server| server.strong_count() > 0);
+        for (&surface_id, server) in &self.servers {
+            if server
+                .upgrade()
+                .is_some_and(|server| !server.borrow().events.is_empty())
+            {
+                return Ok(surface_id as u64);
+            }
+        }
+        let Some(token) = self.ready.get_mut().pop_front() else {
+            return Ok(0);
+        };
+        self.handle_token(token);
+        match token {
+            VncToken::Listener { surface_id } | VncToken::Client { surface_id } => {
+                Ok(surface_id as u64)
+            }
+        }
+    }
+
+    fn handle_next_event(
+        &mut self,
+        surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Option<GpuDisplayEvents> {
+        let server = self.server(surface.surface_descriptor() as u32)?;
+        let events = server.borrow_mut().events.pop_front();
+        events
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        surface_id: u32,
+        scanout_id: Option<u32>,
+        width: u32,
+        height: u32,
+        _surf_type: SurfaceType,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        // Cursors are not composited into the framebuffer sent to clients.
+        if parent_surface_id.is_some() {
+            return Err(GpuDisplayError::Unsupported);
+        }
+        if width == 0 || height == 0 || width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err(GpuDisplayError::CreateSurface);
+        }
+
+        let scanout_id = scanout_id.unwrap_or(0);
+        let address = self
+            .address
+            .for_scanout(scanout_id)
+            .ok_or(GpuDisplayError::CreateSurface)?;
+        let listener = address.listen().map_err(|e| {
+            error!("failed to listen for VNC clients on {}: {}", address, e);
+            GpuDisplayError::IoError(e)
+        })?;
+        self.wait_ctx
+            .add(&listener, VncToken::Listener { surface_id })?;
+        info!("serving scanout {} over VNC on {}", scanout_id, address);
+
+        let buffer_size = width as usize * height as usize * BYTES_PER_PIXEL;
+        let server = Rc::new(RefCell::new(VncServer {
+            wait_ctx: self.wait_ctx.clone(),
+            surface_id,
+            listener,
+            client: None,
+            name: format!("crosvm scanout {}", scanout_id),
+            width,
+            height,
+            framebuffer: vec![0; buffer_size],
+            dirty_rows: None,
+            events: VecDeque::new(),
+            tracking_id: 0,
+        }));
+        self.servers.insert(surface_id, Rc::downgrade(&server));
+
+        Ok(Box::new(VncSurface {
+            surface_id,
+            server,
+            buffer: vec![0; buffer_size],
+            stride: width * BYTES_PER_PIXEL as u32,
+        }))
+    }
+}
+
+impl SysDisplayT for DisplayVnc {}
+
+impl AsRawDescriptor for DisplayVnc {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.wait_ctx.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Handles the pending client messages, returning the input events they produced.
+    fn dispatch(
+        display: &mut DisplayVnc,
+        surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Vec<virtio_input_event> {
+        display
+            .wait_ctx
+            .wait_timeout(Duration::from_secs(5))
+            .unwrap();
+        let mut events = Vec::new();
+        while display.pending_events() {
+            if display.next_event().unwrap() != surface.surface_descriptor() {
+                continue;
+            }
+            if let Some(e) = display.handle_next_event(surface) {
+                events.extend(e.events);
+            }
+        }
+        events
+    }
+
+    fn read_bytes(client: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            "127.0.0.1:5900".parse(),
+            Ok(VncAddress::Tcp(SocketAddr::new(
+                Ipv4Addr::LOCALHOST.into(),
+                5900
+            )))
+        );
+        assert_eq!(
+            "/run/vnc.sock".parse(),
+            Ok(VncAddress::Unix(PathBuf::from("/run/vnc.sock")))
+        );
+        assert!("localhost".parse::<VncAddress>().is_err());
+
+        let tcp: VncAddress = "[::1]:5900".parse().unwrap();
+        assert_eq!(tcp.for_scanout(2).unwrap().to_string(), "[::1]:5902");
+        let unix: VncAddress = "/run/vnc.sock".parse().unwrap();
+        assert_eq!(unix.for_scanout(0).unwrap().to_string(), "/run/vnc.sock");
+        assert_eq!(unix.for_scanout(1).unwrap().to_string(), "/run/vnc.sock.1");
+    }
+
+    #[test]
+    fn keysyms() {
+        assert_eq!(keysym_to_linux_keycode(0x61), Some(KEY_A)); // a
+        assert_eq!(keysym_to_linux_keycode(0x41), Some(KEY_A)); // A
+        assert_eq!(keysym_to_linux_keycode(0x21), Some(KEY_1)); // !
+        assert_eq!(keysym_to_linux_keycode(0xff0d), Some(KEY_ENTER));
+        assert_eq!(keysym_to_linux_keycode(0xffc0), Some(KEY_F3));
+        assert_eq!(keysym_to_linux_keycode(0xffc9), Some(KEY_F12));
+        assert_eq!(keysym_to_linux_keycode(0xffe1), Some(KEY_LEFTSHIFT));
+        assert_eq!(keysym_to_linux_keycode(0x20ac), None); // EuroSign
+    }
+
+    #[test]
+    fn pixel_format_conversion() {
+        let rgb565_be = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        assert_eq!(
+            PixelFormat::from_bytes(&rgb565_be.to_bytes()).unwrap(),
+            rgb565_be
+        );
+        let mut out = Vec::new();
+        // XRGB8888 pixels: pure red, then pure blue.
+        rgb565_be.encode(&[0, 0, 0xff, 0, 0xff, 0, 0, 0], &mut out);
+        assert_eq!(out, [0xf8, 0x00, 0x00, 0x1f]);
+
+        let mut colour_map = PixelFormat::NATIVE.to_bytes();
+        colour_map[3] = 0;
+        assert!(PixelFormat::from_bytes(&colour_map).is_err());
+    }
+
+    /// Connects a client to the first surface of `display` and completes the handshake.
+    fn connect(display: &mut DisplayVnc, surface: &mut Box<dyn GpuDisplaySurface>) -> TcpStream {
+        let server = display.server(1).unwrap();
+        let addr = match &server.borrow().listener {
+            VncListener::Tcp(listener) => listener.local_addr().unwrap(),
+            VncListener::Unix(_) => unreachable!(),
+        };
+        let (width, height) = (server.borrow().width as u16, server.borrow().height as u16);
+        let mut client = TcpStream::connect(addr).unwrap();
+        dispatch(display, surface);
+        assert_eq!(read_bytes(&mut client, 12), RFB_VERSION);
+        client.write_all(RFB_VERSION).unwrap();
+        dispatch(display, surface);
+        assert_eq!(read_bytes(&mut client, 2), [1, SECURITY_TYPE_NONE]);
+        client.write_all(&[SECURITY_TYPE_NONE]).unwrap();
+        dispatch(display, surface);
+        assert_eq!(read_bytes(&mut client, 4), [0, 0, 0, 0]);
+        client.write_all(&[1]).unwrap();
+        dispatch(display, surface);
+        let server_init = read_bytes(&mut client, 24);
+        assert_eq!(server_init[..2], width.to_be_bytes());
+        assert_eq!(server_init[2..4], height.to_be_bytes());
+        assert_eq!(server_init[4..20], PixelFormat::NATIVE.to_bytes());
+        let name_len = u32::from_be_bytes(server_init[20..24].try_into().unwrap());
+        assert_eq!(
+            read_bytes(&mut client, name_len as usize),
+            b"crosvm scanout 0"
+        );
+        client
+    }
+
+    #[test]
+    fn serve_client() {
+        let mut display =
+            DisplayVnc::new(VncAddress::Tcp((Ipv4Addr::LOCALHOST, 0).into())).unwrap();
+        let mut surface = display
+            .create_surface(None, 1, Some(0), 2, 2, SurfaceType::Scanout)
+            .unwrap();
+        let mut client = connect(&mut display, &mut surface);
+
+        let pixels: Vec<u8> = (0..16).collect();
+        surface
+            .framebuffer()
+            .unwrap()
+            .as_volatile_slice()
+            .copy_from(&pixels);
+        surface.flip();
+        client
+            .write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 0, 0, 0, 0, 2, 0, 2])
+            .unwrap();
+        dispatch(&mut display, &mut surface);
+        assert_eq!(
+            read_bytes(&mut client, 16),
+            [0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 0]
+        );
+        assert_eq!(read_bytes(&mut client, 16), pixels);
+
+        // An incremental update only carries the rows that changed.
+        client
+            .write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 0, 2, 0, 2])
+            .unwrap();
+        dispatch(&mut display, &mut surface);
+        surface
+            .framebuffer()
+            .unwrap()
+            .as_volatile_slice()
+            .write_bytes(0xff);
+        surface.flip();
+        assert_eq!(
+            read_bytes(&mut client, 16),
+            [0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 0]
+        );
+        assert_eq!(read_bytes(&mut client, 16), [0xff; 16]);
+        client
+            .write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 0, 2, 0, 2])
+            .unwrap();
+        dispatch(&mut display, &mut surface);
+        surface
+            .framebuffer()
+            .unwrap()
+            .as_volatile_slice()
+            .sub_slice(8, 8)
+            .unwrap()
+            .write_bytes(0);
+        surface.flip();
+        assert_eq!(
+            read_bytes(&mut client, 16),
+            [0, 0, 0, 1, 0, 0, 0, 1, 0, 2, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(read_bytes(&mut client, 8), [0; 8]);
+
+        // Pressing `a` and touching the screen at (1, 5), which is clamped to the surface.
+        client
+            .write_all(&[
+                KEY_EVENT,
+                1,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0x61,
+                POINTER_EVENT,
+                1,
+                0,
+                1,
+                0,
+                5,
+            ])
+            .unwrap();
+        let events = dispatch(&mut display, &mut surface);
+        assert_eq!(
+            events,
+            [
+                virtio_input_event::key(KEY_A, true, false),
+                virtio_input_event::multitouch_slot(0),
+                virtio_input_event::multitouch_tracking_id(1),
+                virtio_input_event::multitouch_absolute_x(1),
+                virtio_input_event::multitouch_absolute_y(1),
+            ]
+        );
+
+        // The finger is lifted when the client goes away.
+        drop(client);
+        let events = dispatch(&mut display, &mut surface);
+        assert_eq!(
+            events,
+            [
+                virtio_input_event::multitouch_slot(0),
+                virtio_input_event::multitouch_tracking_id(-1),
+            ]
+        );
+    }
+    #[test]
+    fn slow_client() {
+        // A frame is far larger than the socket buffers.
+        const SIZE: u32 = 2048;
+        let mut display =
+            DisplayVnc::new(VncAddress::Tcp((Ipv4Addr::LOCALHOST, 0).into())).unwrap();
+        let mut surface = display
+            .create_surface(None, 1, Some(0), SIZE, SIZE, SurfaceType::Scanout)
+            .unwrap();
+        let mut client = connect(&mut display, &mut surface);
+        client
+            .write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 0, 0, 0, 8, 0, 8, 0])
+            .unwrap();
+        dispatch(&mut display, &mut surface);
+
+        // The client doesn't read the first update, so the frames flipped meanwhile are merged
+        // instead of being queued behind it.
+        for i in 0..10 {
+            client
+                .write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 8, 0, 8, 0])
+                .unwrap();
+            dispatch(&mut display, &mut surface);
+            surface
+                .framebuffer()
+                .unwrap()
+                .as_volatile_slice()
+                .write_bytes(i);
+            surface.flip();
+        }
+        let update_len = 16 + (SIZE * SIZE) as usize * BYTES_PER_PIXEL;
+        {
+            let server = display.server(1).unwrap();
+            let server = server.borrow();
+            let client = server.client.as_ref().unwrap();
+            assert!(client.polling_writable);
+            assert!(!client.output.is_empty() && client.output.len() < update_len);
+        }
+
+        // Once the client catches up, it gets a single update with the last frame.
+        let mut reader = client.try_clone().unwrap();
+        let reader = std::thread::spawn(move || {
+            read_bytes(&mut reader, update_len);
+            read_bytes(&mut reader, update_len)
+        });
+        while !reader.is_finished() {
+            while display.pending_events() {
+                display.next_event().unwrap();
+            }
+        }
+        let update = reader.join().unwrap();
+        assert_eq!(update[..4], [0, 0, 0, 1]);
+        assert!(update[16..].iter().all(|&b| b == 9));
+    }
+}
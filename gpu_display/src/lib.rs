@@ -3,7 +3,7 @@
 // found in the LICENSE file.
 
 //! Crate for displaying simple surfaces and GPU buffers over a low-level display backend such as
-//! Wayland or X.
+//! Wayland, X or VNC.
 
 use std::collections::BTreeMap;
 use std::io::Error as IoError;
@@ -28,6 +28,8 @@ use vulkano::VulkanLibrary;
 
 mod event_device;
 mod gpu_display_stub;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod gpu_display_vnc;
 #[cfg(windows)]
 mod gpu_display_win;
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -42,6 +44,8 @@ pub mod vulkan;
 
 pub use event_device::EventDevice;
 pub use event_device::EventDeviceKind;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use gpu_display_vnc::VncAddress;
 #[cfg(windows)]
 pub use gpu_display_win::DisplayProperties as WinDisplayProperties;
 #[cfg(windows)]
@@ -8,6 +8,7 @@ use base::AsRawDescriptor;
 use base::RawDescriptor;
 use base::WaitContext;
 
+use crate::gpu_display_vnc::DisplayVnc;
 use crate::gpu_display_wl::DisplayWl;
 use crate::DisplayEventToken;
 use crate::DisplayT;
@@ -15,6 +16,7 @@ use crate::EventDevice;
 use crate::GpuDisplay;
 use crate::GpuDisplayExt;
 use crate::GpuDisplayResult;
+use crate::VncAddress;
 
 pub(crate) trait UnixDisplayT: DisplayT {}
 
@@ -45,6 +47,9 @@ impl GpuDisplayExt for GpuDisplay {
 pub trait UnixGpuDisplayExt {
     /// Opens a fresh connection to the compositor.
     fn open_wayland<P: AsRef<Path>>(wayland_path: Option<P>) -> GpuDisplayResult<GpuDisplay>;
+
+    /// Opens a VNC server for each scanout, the first one listening on `address`.
+    fn open_vnc(address: &VncAddress) -> GpuDisplayResult<GpuDisplay>;
 }
 
 impl UnixGpuDisplayExt for GpuDisplay {
@@ -65,6 +70,21 @@ impl UnixGpuDisplayExt for GpuDisplay {
             wait_ctx,
         })
     }
+
+    fn open_vnc(address: &VncAddress) -> GpuDisplayResult<GpuDisplay> {
+        let display = DisplayVnc::new(address.clone())?;
+
+        let wait_ctx = WaitContext::new()?;
+        wait_ctx.add(&display, DisplayEventToken::Display)?;
+
+        Ok(GpuDisplay {
+            inner: Box::new(display),
+            next_id: 1,
+            event_devices: Default::default(),
+            surfaces: Default::default(),
+            wait_ctx,
+        })
+    }
 }
 
 impl AsRawDescriptor for GpuDisplay {
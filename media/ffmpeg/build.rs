@@ -22,6 +22,10 @@ fn main() {
         .atleast_version("60")
         .probe("libavcodec")
         .unwrap();
+    Config::new()
+        .atleast_version("60")
+        .probe("libavformat")
+        .unwrap();
     Config::new()
         .atleast_version("58")
         .probe("libavutil")
@@ -35,11 +39,16 @@ fn main() {
         .header("src/bindings.h")
         .allowlist_function("av_.*")
         .allowlist_function("avcodec_.*")
+        .allowlist_function("avformat_.*")
+        .allowlist_function("avio_.*")
         .allowlist_function("sws_.*")
         .allowlist_function("av_image_.*")
         .allowlist_var("FF_PROFILE.*")
         .allowlist_var("AV_.*")
         .allowlist_var("AVERROR_.*")
+        .allowlist_var("AVFMT_.*")
+        .allowlist_var("AVIO_FLAG_.*")
+        .allowlist_var("AVSEEK_.*")
         // Skip va_list and functions that use it to avoid ABI problems on aarch64.
         .blocklist_type(".*va_list.*")
         .blocklist_function("av_log_.*")
@@ -224,6 +224,14 @@ impl EncoderContextBuilder {
         context.pix_fmt = fmt.pix_fmt();
     }
 
+    /// Set the `AV_CODEC_FLAG_*` flags for this encoding context.
+    pub fn set_flags(&mut self, flags: u32) {
+        // SAFETY:
+        // Safe because our context member is properly allocated and owned by us.
+        let context = unsafe { &mut *(self.context.0) };
+        context.flags = flags as c_int;
+    }
+
     /// Build a encoder AvCodecContext from the configured options.
     pub fn build(mut self) -> Result<AvCodecContext, AvCodecOpenError> {
         self.context.init(self.codec)?;
@@ -718,6 +726,12 @@ impl<'a> AsRef<ffi::AVPacket> for AvPacket<'a> {
     }
 }
 
+impl<'a> AsMut<ffi::AVPacket> for AvPacket<'a> {
+    fn as_mut(&mut self) -> &mut ffi::AVPacket {
+        &mut self.packet
+    }
+}
+
 impl<'a> AvPacket<'a> {
     /// Create an empty AvPacket without buffers.
     ///
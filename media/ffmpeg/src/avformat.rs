@@ -0,0 +1,284 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! This module implements a lightweight and safe muxer interface over `libavformat`, allowing
+//! the packets produced by an encoder to be written to a container file.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use libc::c_int;
+use libc::c_void;
+use thiserror::Error as ThisError;
+
+use crate::avcodec::AvCodecContext;
+use crate::avcodec::AvError;
+use crate::avcodec::AvPacket;
+use crate::ffi;
+
+#[derive(Debug, ThisError)]
+pub enum AvOutputError {
+    #[error("error while calling libavformat: {0}")]
+    AvError(#[from] AvError),
+    #[error("failed to allocate AVStream object")]
+    StreamAllocation,
+    #[error("failed to allocate AVIOContext object")]
+    IoContextAllocation,
+    #[error("no container format matches the name of the file")]
+    UnknownFormat,
+    #[error("the header has already been written")]
+    HeaderAlreadyWritten,
+    #[error("the header has not been written yet")]
+    HeaderNotWritten,
+}
+
+/// A container file holding a single stream, to which encoded packets can be written.
+pub struct AvOutputContext {
+    context: *mut ffi::AVFormatContext,
+    // Written to by the I/O context of `context`, so it must be freed last.
+    file: *mut File,
+    header_written: bool,
+    trailer_written: bool,
+}
+
+/// The size of the buffer through which libavformat writes to the file.
+const IO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Converts an I/O error to the negative error code expected by libavformat.
+fn io_error_code(e: io::Error) -> c_int {
+    -e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// Writes the data buffered by libavformat to the `File` passed as `opaque`.
+extern "C" fn write_file(opaque: *mut c_void, buf: *const u8, buf_size: c_int) -> c_int {
+    // SAFETY:
+    // Safe because `opaque` is the file owned by the `AvOutputContext`, which outlives its I/O
+    // context, and `buf` holds `buf_size` bytes.
+    let (file, data) = unsafe {
+        (
+            &mut *(opaque as *mut File),
+            std::slice::from_raw_parts(buf, buf_size as usize),
+        )
+    };
+    match file.write_all(data) {
+        Ok(()) => buf_size,
+        Err(e) => io_error_code(e),
+    }
+}
+
+/// Moves the position of the `File` passed as `opaque`, or returns its size for `AVSEEK_SIZE`.
+extern "C" fn seek_file(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    // SAFETY:
+    // Safe because `opaque` is the file owned by the `AvOutputContext`, which outlives its I/O
+    // context.
+    let file = unsafe { &mut *(opaque as *mut File) };
+    let pos = match whence & !(ffi::AVSEEK_FORCE as c_int) {
+        whence if whence == ffi::AVSEEK_SIZE as c_int => {
+            return match file.metadata() {
+                Ok(metadata) => metadata.len() as i64,
+                Err(e) => io_error_code(e) as i64,
+            };
+        }
+        libc::SEEK_SET => match u64::try_from(offset) {
+            Ok(offset) => SeekFrom::Start(offset),
+            Err(_) => return -libc::EINVAL as i64,
+        },
+        libc::SEEK_CUR => SeekFrom::Current(offset),
+        libc::SEEK_END => SeekFrom::End(offset),
+        _ => return -libc::EINVAL as i64,
+    };
+    match file.seek(pos) {
+        Ok(pos) => pos as i64,
+        Err(e) => io_error_code(e) as i64,
+    }
+}
+
+impl AvOutputContext {
+    /// Create a context writing to `file`, in the container format matching the extension of
+    /// `path`.
+    ///
+    /// `path` is only used to choose the format, which lets sandboxed processes produce files they
+    /// could not open themselves. `file` must be seekable for the formats that rewrite their header
+    /// once all the packets are known, and is closed when the context is dropped.
+    pub fn new(path: &Path, file: File) -> Result<Self, AvOutputError> {
+        let filename =
+            CString::new(path.as_os_str().as_bytes()).map_err(|_| AvOutputError::UnknownFormat)?;
+        let mut context = std::ptr::null_mut();
+        // SAFETY:
+        // Safe because `context` is a valid location to store the new context and `filename` is a
+        // valid NUL-terminated string.
+        let ret = unsafe {
+            ffi::avformat_alloc_output_context2(
+                &mut context,
+                std::ptr::null(),
+                std::ptr::null(),
+                filename.as_ptr(),
+            )
+        };
+        if ret < 0 || context.is_null() {
+            return Err(AvOutputError::UnknownFormat);
+        }
+        let output = Self {
+            context,
+            file: Box::into_raw(Box::new(file)),
+            header_written: false,
+            trailer_written: false,
+        };
+
+        // SAFETY:
+        // Safe because the size is non-zero. The buffer is freed along with the I/O context.
+        let buffer = unsafe { ffi::av_malloc(IO_BUFFER_SIZE) } as *mut u8;
+        if buffer.is_null() {
+            return Err(AvOutputError::IoContextAllocation);
+        }
+        // The const-ness of the buffer given to the write callback differs between FFmpeg
+        // versions, so the callback is cast to the type of the bindings.
+        // SAFETY:
+        // Safe because both function pointer types only differ by the mutability of `buf`, which
+        // `write_file` doesn't write to.
+        let write_packet = unsafe {
+            std::mem::transmute::<extern "C" fn(*mut c_void, *const u8, c_int) -> c_int, _>(
+                write_file,
+            )
+        };
+        // SAFETY:
+        // Safe because `buffer` holds `IO_BUFFER_SIZE` bytes, and `output.file` is a valid file
+        // that stays alive until the I/O context is freed.
+        let pb = unsafe {
+            ffi::avio_alloc_context(
+                buffer,
+                IO_BUFFER_SIZE as c_int,
+                1,
+                output.file as *mut c_void,
+                None,
+                Some(write_packet),
+                Some(seek_file),
+            )
+        };
+        if pb.is_null() {
+            // SAFETY:
+            // Safe because `buffer` was allocated with `av_malloc` and is not used by anything.
+            unsafe { ffi::av_free(buffer as *mut c_void) };
+            return Err(AvOutputError::IoContextAllocation);
+        }
+        // SAFETY:
+        // Safe because `output.context` is a valid context, which takes `pb` as its output.
+        unsafe {
+            (*output.context).pb = pb;
+            (*output.context).flags |= ffi::AVFMT_FLAG_CUSTOM_IO as c_int;
+        }
+
+        Ok(output)
+    }
+
+    /// Whether the codec must be configured with `AV_CODEC_FLAG_GLOBAL_HEADER` before being opened,
+    /// because the container stores the codec's global headers separately from the packets.
+    pub fn needs_global_header(&self) -> bool {
+        // SAFETY:
+        // Safe because `oformat` is set to a valid static format when the context is created.
+        let flags = unsafe { (*(*self.context).oformat).flags };
+        flags & ffi::AVFMT_GLOBALHEADER as c_int != 0
+    }
+
+    /// Add the stream that will receive the packets of `codec` and write the header of the file.
+    ///
+    /// This must be called once, after `codec` has been opened and before any packet is written.
+    pub fn write_header(&mut self, codec: &AvCodecContext) -> Result<(), AvOutputError> {
+        if self.header_written {
+            return Err(AvOutputError::HeaderAlreadyWritten);
+        }
+
+        // SAFETY:
+        // Safe because `self.context` is a valid context, to which the new stream is attached.
+        let stream = unsafe { ffi::avformat_new_stream(self.context, std::ptr::null()) };
+        if stream.is_null() {
+            return Err(AvOutputError::StreamAllocation);
+        }
+        // SAFETY:
+        // Safe because `stream` was just allocated with valid codec parameters and `codec` is an
+        // opened codec context.
+        AvError::result(unsafe {
+            (*stream).time_base = codec.as_ref().time_base;
+            ffi::avcodec_parameters_from_context((*stream).codecpar, codec.as_ref())
+        })?;
+
+        // SAFETY:
+        // Safe because `self.context` is a valid context with an opened output and one stream.
+        AvError::result(unsafe { ffi::avformat_write_header(self.context, std::ptr::null_mut()) })?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    /// Write a packet produced by the codec given to `write_header`, whose timestamps are in
+    /// `time_base` units.
+    pub fn write_packet(
+        &mut self,
+        packet: &mut AvPacket<'_>,
+        time_base: ffi::AVRational,
+    ) -> Result<(), AvOutputError> {
+        if !self.header_written {
+            return Err(AvOutputError::HeaderNotWritten);
+        }
+
+        let packet = packet.as_mut();
+        // SAFETY:
+        // Safe because the context has exactly one stream once the header has been written, and
+        // `packet` is a valid packet. `av_interleaved_write_frame` takes ownership of the packet's
+        // data and leaves it blank.
+        AvError::result(unsafe {
+            let stream = *(*self.context).streams;
+            ffi::av_packet_rescale_ts(packet, time_base, (*stream).time_base);
+            packet.stream_index = 0;
+            ffi::av_interleaved_write_frame(self.context, packet)
+        })?;
+        Ok(())
+    }
+
+    /// Write the packets still buffered for interleaving and the trailer of the file.
+    ///
+    /// The trailer is also written when the context is dropped, but errors are then ignored.
+    pub fn finish(&mut self) -> Result<(), AvOutputError> {
+        if !self.header_written {
+            return Err(AvOutputError::HeaderNotWritten);
+        }
+        if self.trailer_written {
+            return Ok(());
+        }
+
+        self.trailer_written = true;
+        // SAFETY:
+        // Safe because `self.context` is a valid context whose header has been written.
+        AvError::result(unsafe { ffi::av_write_trailer(self.context) })?;
+        Ok(())
+    }
+}
+
+impl Drop for AvOutputContext {
+    fn drop(&mut self) {
+        if self.header_written {
+            let _ = self.finish();
+        }
+        // SAFETY:
+        // Safe because `self.context` is a valid context owned by us, and its `pb` member is either
+        // null or the I/O context allocated in `new`, whose buffer was allocated with `av_malloc`.
+        // The file is only freed once nothing can write to it anymore.
+        unsafe {
+            let mut pb = (*self.context).pb;
+            if !pb.is_null() {
+                ffi::avio_flush(pb);
+                ffi::av_freep(&mut (*pb).buffer as *mut *mut u8 as *mut c_void);
+                ffi::avio_context_free(&mut pb);
+                (*self.context).pb = std::ptr::null_mut();
+            }
+            ffi::avformat_free_context(self.context);
+            drop(Box::from_raw(self.file));
+        }
+    }
+}
@@ -5,6 +5,7 @@
 #![cfg(any(target_os = "android", target_os = "linux"))]
 
 pub mod avcodec;
+pub mod avformat;
 mod avutil;
 pub use avutil::*;
 mod error;
@@ -22,10 +23,12 @@ mod ffi {
 pub mod swscale;
 
 pub use ffi::AVPictureType_AV_PICTURE_TYPE_I;
+pub use ffi::AVPixelFormat_AV_PIX_FMT_BGR0;
 pub use ffi::AVPixelFormat_AV_PIX_FMT_NV12;
 pub use ffi::AVPixelFormat_AV_PIX_FMT_YUV420P;
 pub use ffi::AVRational;
 pub use ffi::AV_CODEC_CAP_DR1;
+pub use ffi::AV_CODEC_FLAG_GLOBAL_HEADER;
 pub use ffi::AV_PKT_FLAG_KEY;
 pub use ffi::FF_PROFILE_H264_BASELINE;
 pub use ffi::FF_PROFILE_H264_EXTENDED;
@@ -292,6 +292,11 @@ impl<T: NumberType, D: Details<T>> DetailedHistogram<T, D> {
         ))
     }
 
+    /// Returns the range and stats of each bucket, in the order the ranges were given.
+    pub fn buckets(&self) -> impl Iterator<Item = (&Range<T>, &SimpleStat<T>)> {
+        self.buckets.iter().map(|b| (&b.range, &b.simple_stat))
+    }
+
     /// Returns simple stat for the histogram.
     pub fn simple_stat(&self) -> SimpleStat<T> {
         let count = self.count();
@@ -600,6 +605,20 @@ mod tests {
         assert_eq!(histogram.values, None);
     }
 
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::new(&[0..4, 4..10, 10..100]).unwrap();
+        for v in [5, 50, 9] {
+            histogram.add(v).unwrap();
+        }
+
+        let buckets: Vec<_> = histogram
+            .buckets()
+            .map(|(range, stat)| (range.clone(), stat.count()))
+            .collect();
+        assert_eq!(buckets, vec![(0..4, 0), (4..10, 2), (10..100, 1)]);
+    }
+
     #[derive(Clone, Debug, PartialEq)]
     struct MyDetails(u64, u64);
     impl Details<u64> for MyDetails {
@@ -34,7 +34,7 @@ use thiserror::Error as ThisError;
 
 #[cfg(feature = "slirp")]
 pub mod slirp;
-#[cfg(all(feature = "slirp", windows))]
+#[cfg(feature = "slirp")]
 pub use slirp::Slirp;
 
 #[sorted]
@@ -55,7 +55,7 @@ pub enum Error {
     /// Couldn't open /dev/net/tun.
     #[error("failed to open /dev/net/tun: {0}")]
     OpenTun(SysError),
-    #[cfg(all(feature = "slirp", windows))]
+    #[cfg(feature = "slirp")]
     #[error("slirp related error")]
     Slirp(slirp::SlirpError),
 }
@@ -70,7 +70,7 @@ impl Error {
             Error::CreateTap(e) => *e,
             Error::CloneTap(e) => *e,
             Error::IoctlError(e) => *e,
-            #[cfg(all(feature = "slirp", windows))]
+            #[cfg(feature = "slirp")]
             Error::Slirp(e) => e.sys_error(),
         }
     }
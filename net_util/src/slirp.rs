@@ -6,8 +6,6 @@
 //! level interfaces to libslirp that are used to implement that loop, and
 //! diagnostic tools.
 
-#![cfg(windows)]
-
 #[path = "../../third_party/libslirp-rs/src/context.rs"]
 pub mod context;
 
@@ -15,8 +13,19 @@ pub mod context;
 pub mod packet_ring_buffer;
 
 pub mod sys;
+use std::fmt;
+use std::fmt::Display;
+use std::net::AddrParseError;
+use std::net::Ipv4Addr;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
 use base::Error as SysError;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
 pub use sys::Slirp;
 use thiserror::Error as ThisError;
 
@@ -24,10 +33,12 @@ use thiserror::Error as ThisError;
 /// <http://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2050006>
 pub const ETHERNET_FRAME_SIZE: usize = 1526;
 
-#[cfg(windows)]
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum SlirpError {
+    /// libslirp could not listen on the host side of a port forwarding rule.
+    #[error("failed to forward {0}: {1}")]
+    AddHostFwd(HostFwd, std::io::Error),
     #[error("pipe was closed: {0}")]
     BrokenPipe(std::io::Error),
     #[error("failed to clone object: {0}")]
@@ -40,20 +51,195 @@ pub enum SlirpError {
     /// Error encountered while in a Slirp related poll operation.
     #[error("slirp poll failed: {0}")]
     SlirpPollError(SysError),
+    #[cfg(windows)]
     #[error("WSAStartup failed with code: {0}")]
     WSAStartupError(SysError),
 }
 
-#[cfg(windows)]
 impl SlirpError {
     pub fn sys_error(&self) -> SysError {
         match self {
+            SlirpError::AddHostFwd(_, e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::BrokenPipe(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::CloneFailed(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::OverlappedError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpIOPollError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpPollError(e) => *e,
+            #[cfg(windows)]
             SlirpError::WSAStartupError(e) => *e,
         }
     }
 }
+
+#[sorted]
+#[derive(ThisError, Debug, PartialEq, Eq)]
+pub enum HostFwdError {
+    /// Failed to parse an IPv4 address.
+    #[error("invalid address: {0}")]
+    InvalidAddress(AddrParseError),
+    /// The rule is not of the form `PROTOCOL:[HOST_ADDR]:HOST_PORT-[GUEST_ADDR]:GUEST_PORT`.
+    #[error("invalid port forwarding rule: {0}")]
+    InvalidFormat(String),
+    /// Failed to parse a port number.
+    #[error("invalid port: {0}")]
+    InvalidPort(ParseIntError),
+    /// The protocol is neither `tcp` nor `udp`.
+    #[error("invalid protocol: {0}")]
+    InvalidProtocol(String),
+}
+
+/// Transport protocol of a port forwarding rule.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HostFwdProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Forwards connections to a port of the host to a port of the guest.
+///
+/// The textual form follows QEMU's `hostfwd` option:
+/// `PROTOCOL:[HOST_ADDR]:HOST_PORT-[GUEST_ADDR]:GUEST_PORT`, e.g. `tcp:127.0.0.1:2222-:22`. An
+/// omitted host address listens on all the interfaces of the host, and an omitted guest address
+/// targets the address leased to the guest by the DHCP server.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HostFwd {
+    pub protocol: HostFwdProtocol,
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_addr: Ipv4Addr,
+    pub guest_port: u16,
+}
+
+// Parses `[ADDR]:PORT`, defaulting to the unspecified address.
+fn parse_addr_port(s: &str) -> std::result::Result<(Ipv4Addr, u16), HostFwdError> {
+    let (addr, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| HostFwdError::InvalidFormat(s.to_owned()))?;
+    let addr = if addr.is_empty() {
+        Ipv4Addr::UNSPECIFIED
+    } else {
+        addr.parse().map_err(HostFwdError::InvalidAddress)?
+    };
+    let port = port.parse().map_err(HostFwdError::InvalidPort)?;
+    Ok((addr, port))
+}
+
+impl FromStr for HostFwd {
+    type Err = HostFwdError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (protocol, addrs) = s
+            .split_once(':')
+            .ok_or_else(|| HostFwdError::InvalidFormat(s.to_owned()))?;
+        let protocol = match protocol {
+            "tcp" => HostFwdProtocol::Tcp,
+            "udp" => HostFwdProtocol::Udp,
+            _ => return Err(HostFwdError::InvalidProtocol(protocol.to_owned())),
+        };
+        let (host, guest) = addrs
+            .split_once('-')
+            .ok_or_else(|| HostFwdError::InvalidFormat(s.to_owned()))?;
+        let (host_addr, host_port) = parse_addr_port(host)?;
+        let (guest_addr, guest_port) = parse_addr_port(guest)?;
+        Ok(HostFwd {
+            protocol,
+            host_addr,
+            host_port,
+            guest_addr,
+            guest_port,
+        })
+    }
+}
+
+impl Display for HostFwd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            HostFwdProtocol::Tcp => "tcp",
+            HostFwdProtocol::Udp => "udp",
+        };
+        write!(f, "{}:", protocol)?;
+        if !self.host_addr.is_unspecified() {
+            write!(f, "{}", self.host_addr)?;
+        }
+        write!(f, ":{}-", self.host_port)?;
+        if !self.guest_addr.is_unspecified() {
+            write!(f, "{}", self.guest_addr)?;
+        }
+        write!(f, ":{}", self.guest_port)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostFwd {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for HostFwd {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_host_fwd() {
+        assert_eq!(
+            "tcp:127.0.0.1:2222-:22".parse::<HostFwd>().unwrap(),
+            HostFwd {
+                protocol: HostFwdProtocol::Tcp,
+                host_addr: Ipv4Addr::LOCALHOST,
+                host_port: 2222,
+                guest_addr: Ipv4Addr::UNSPECIFIED,
+                guest_port: 22,
+            }
+        );
+        assert_eq!(
+            "udp::5353-10.0.2.15:53".parse::<HostFwd>().unwrap(),
+            HostFwd {
+                protocol: HostFwdProtocol::Udp,
+                host_addr: Ipv4Addr::UNSPECIFIED,
+                host_port: 5353,
+                guest_addr: Ipv4Addr::new(10, 0, 2, 15),
+                guest_port: 53,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_host_fwd_invalid() {
+        assert!(matches!(
+            "sctp::80-:80".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidProtocol(_))
+        ));
+        assert!(matches!(
+            "tcp:8080:80".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidFormat(_))
+        ));
+        assert!(matches!(
+            "tcp:localhost:8080-:80".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            "tcp::65536-:80".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidPort(_))
+        ));
+    }
+
+    #[test]
+    fn host_fwd_round_trip() {
+        for rule in ["tcp::2222-:22", "udp:127.0.0.1:5353-10.0.2.15:53"] {
+            assert_eq!(rule.parse::<HostFwd>().unwrap().to_string(), rule);
+        }
+    }
+}
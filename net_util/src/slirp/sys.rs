@@ -3,11 +3,14 @@
 // found in the LICENSE file.
 
 cfg_if::cfg_if! {
-    if #[cfg(windows)] {
+    if #[cfg(any(target_os = "android", target_os = "linux"))] {
+        pub mod linux;
+        use linux as platform;
+    } else if #[cfg(windows)] {
         pub mod windows;
         use windows as platform;
     } else {
-        compile_error!("Unsupported platform (slirp supported only on Windows)");
+        compile_error!("Unsupported platform");
     }
 }
 
@@ -0,0 +1,217 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+pub mod handler;
+
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::mem::size_of;
+use std::net;
+use std::os::raw::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+
+use base::error;
+use base::info;
+use base::volatile_impl;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FileReadWriteVolatile;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::UnixSeqpacket;
+use cros_async::IntoAsync;
+use virtio_sys::virtio_net::virtio_net_hdr_v1;
+
+use crate::slirp::HostFwd;
+use crate::slirp::SlirpError;
+use crate::sys::linux::TapTLinux;
+use crate::Error;
+use crate::MacAddress;
+use crate::Result;
+use crate::TapT;
+use crate::TapTCommon;
+
+/// The MTU of the virtual network provided by libslirp.
+const SLIRP_MTU: u16 = 1500;
+
+/// Handle for a pseudo-tap interface backed by libslirp.
+///
+/// Frames (prefixed by a virtio-net header) are exchanged with the libslirp loop, which runs in a
+/// separate process (see `Slirp::run_slirp_process`), over a `SOCK_SEQPACKET` socket pair so that
+/// frame boundaries are preserved just like on a tap device.
+pub struct Slirp {
+    guest_socket: UnixSeqpacket,
+}
+
+impl Slirp {
+    /// Creates the guest side of the connection to libslirp, along with the socket that must be
+    /// handed to `Slirp::run_slirp_process`.
+    pub fn new() -> Result<(Slirp, UnixSeqpacket)> {
+        let (guest_socket, slirp_socket) = UnixSeqpacket::pair()
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        for socket in [&guest_socket, &slirp_socket] {
+            socket
+                .set_nonblocking(true)
+                .map_err(SysError::from)
+                .map_err(Error::CreateSocket)?;
+        }
+        Ok((Slirp { guest_socket }, slirp_socket))
+    }
+
+    /// Start the Slirp listening loop. This is meant to be called from the (sandboxed) process
+    /// dedicated to libslirp, and returns once the guest side of the connection is closed.
+    pub fn run_slirp_process(slirp_socket: UnixSeqpacket, host_forwards: &[HostFwd]) {
+        let disable_access_to_host = !cfg!(feature = "guest-to-host-net-loopback");
+
+        info!("starting slirp loop...");
+        match handler::start_slirp(slirp_socket, disable_access_to_host, host_forwards) {
+            Err(Error::Slirp(SlirpError::BrokenPipe(e))) => {
+                warn!("exited slirp listening loop: {}", e)
+            }
+            Err(e) => error!("error while running slirp listening loop: {}", e),
+            Ok(()) => {}
+        }
+    }
+}
+
+impl TapT for Slirp {}
+
+impl TapTCommon for Slirp {
+    fn new_with_name(_name: &[u8], _vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        // Slirp handles are created with `Slirp::new`.
+        Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<Slirp> {
+        Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>> {
+        if vq_pairs != 1 {
+            error!("libslirp is single threaded; only one vq pair is supported");
+            return Err(Error::CreateTap(SysError::new(libc::EINVAL)));
+        }
+
+        Ok(vec![self])
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(SLIRP_MTU)
+    }
+
+    fn set_mtu(&self, _mtu: u16) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_offload(&self, flags: c_uint) -> Result<()> {
+        // Slirp does not support offload.
+        if flags != 0 {
+            return Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)));
+        }
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Slirp {
+            guest_socket: self
+                .guest_socket
+                .try_clone()
+                .map_err(|e| Error::Slirp(SlirpError::CloneFailed(e)))?,
+        })
+    }
+
+    unsafe fn from_raw_descriptor(_descriptor: RawDescriptor) -> Result<Self> {
+        // Slirp handles are created with `Slirp::new`.
+        Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)))
+    }
+}
+
+impl TapTLinux for Slirp {
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()> {
+        // The libslirp loop always expects (and produces) a virtio_net_hdr_v1.
+        if size != size_of::<virtio_net_hdr_v1>() {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn if_flags(&self) -> u32 {
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR
+    }
+}
+
+impl Read for Slirp {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.guest_socket.recv(buf)
+    }
+}
+
+impl Write for Slirp {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.guest_socket.send(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Slirp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl AsRawDescriptor for Slirp {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl ReadNotifier for Slirp {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        self
+    }
+}
+
+impl IntoAsync for Slirp {}
+volatile_impl!(Slirp);
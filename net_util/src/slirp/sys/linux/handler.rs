@@ -0,0 +1,434 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use std::time::Instant;
+
+use base::add_fd_flags;
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use base::Timer;
+use base::TimerTrait;
+use base::UnixSeqpacket;
+use virtio_sys::virtio_net::virtio_net_hdr;
+use virtio_sys::virtio_net::virtio_net_hdr_mrg_rxbuf;
+use zerocopy::AsBytes;
+
+use crate::slirp::context::CallbackHandler;
+use crate::slirp::context::Context;
+use crate::slirp::context::PollEvents;
+use crate::slirp::HostFwd;
+use crate::slirp::HostFwdProtocol;
+use crate::slirp::SlirpError;
+use crate::slirp::ETHERNET_FRAME_SIZE;
+use crate::Error;
+use crate::Result;
+
+const VETH_HEADER_LENGTH: usize = 12;
+
+struct Handler {
+    start: Instant,
+    socket: UnixSeqpacket,
+    buf: [u8; ETHERNET_FRAME_SIZE],
+    // Stores a clone of each timer along with its callback. The timers themselves are owned by
+    // libslirp, and created/released via `timer_new` and `timer_free`. The clone is used to
+    // acknowledge the expiration of the timerfd before running its callback.
+    timer_callbacks: HashMap<RawDescriptor, (Timer, Box<dyn FnMut()>)>,
+}
+
+impl CallbackHandler for Handler {
+    type Timer = base::Timer;
+
+    fn clock_get_ns(&mut self) -> i64 {
+        const NANOS_PER_SEC: u64 = 1_000_000_000;
+        let running_duration = self.start.elapsed();
+        (running_duration.as_secs() * NANOS_PER_SEC + running_duration.subsec_nanos() as u64) as i64
+    }
+
+    /// Sends a packet to the guest.
+    fn send_packet(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let vnet_hdr = virtio_net_hdr_mrg_rxbuf {
+            hdr: virtio_net_hdr {
+                flags: 0,
+                gso_size: 0,
+                hdr_len: 0,
+                csum_start: 0,
+                csum_offset: 0,
+                gso_type: 0,
+            },
+            num_buffers: 1,
+        };
+        let send_buf = [vnet_hdr.as_bytes(), buf].concat();
+        // SAFETY:
+        // Safe because send only reads send_buf, and we check the return value. MSG_NOSIGNAL keeps
+        // the process alive if the guest side went away; the loop notices it on the next read.
+        let ret = unsafe {
+            libc::send(
+                self.socket.as_raw_descriptor(),
+                send_buf.as_ptr() as *const libc::c_void,
+                send_buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        let res = if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        };
+        match res {
+            Ok(_) => Ok(buf.len()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // The guest is not keeping up; drop the frame like a NIC with a full ring would.
+                Ok(0)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Not required per https://github.com/rootless-containers/slirp4netns/blob/7f6a4a654a84d4356c881a10417bab77fd5be325/slirp4netns.c
+    fn register_poll_fd(&mut self, _fd: i32) {}
+    fn unregister_poll_fd(&mut self, _fd: i32) {}
+
+    fn guest_error(&mut self, msg: &str) {
+        warn!("guest error: {}", msg);
+    }
+
+    // Not required per https://github.com/rootless-containers/slirp4netns/blob/7f6a4a654a84d4356c881a10417bab77fd5be325/slirp4netns.c
+    fn notify(&mut self) {}
+
+    fn timer_new(&mut self, callback: Box<dyn FnMut()>) -> Box<Self::Timer> {
+        let timer = Timer::new().expect("failed to create network timer");
+        let timer_clone = timer.try_clone().expect("failed to clone network timer");
+        // An earlier callback may rearm an expired timer before it runs, so reading its expiration
+        // count must not block.
+        add_fd_flags(timer.as_raw_descriptor(), libc::O_NONBLOCK)
+            .expect("failed to make network timer non blocking");
+        self.timer_callbacks
+            .insert(timer.as_raw_descriptor(), (timer_clone, callback));
+        Box::new(timer)
+    }
+
+    fn timer_mod(&mut self, timer: &mut Self::Timer, expire_time: i64) {
+        // expire_time is a clock_get_ns relative deadline in milliseconds. A zero duration would
+        // disarm the timer, so deadlines in the past fire as soon as possible instead.
+        let timer_duration = Duration::from_millis(expire_time as u64)
+            .saturating_sub(Duration::from_nanos(self.clock_get_ns() as u64))
+            .max(Duration::from_nanos(1));
+
+        timer
+            .reset(timer_duration, None)
+            .expect("failed to modify network timer");
+    }
+
+    fn timer_free(&mut self, timer: Box<Self::Timer>) {
+        self.timer_callbacks.remove(&timer.as_raw_descriptor());
+        // The actual Timer is freed implicitly by the Box drop.
+    }
+
+    fn get_timers<'a>(&'a self) -> Box<dyn Iterator<Item = &RawDescriptor> + 'a> {
+        Box::new(self.timer_callbacks.keys())
+    }
+
+    fn execute_timer(&mut self, timer: RawDescriptor) {
+        // A previous callback may have freed this timer.
+        if let Some((timer, timer_callback)) = self.timer_callbacks.get_mut(&timer) {
+            // Acknowledge the expiration so that the timer stops being reported by poll. This
+            // cannot block because the timer is non blocking (see `timer_new`).
+            if let Err(e) = timer.mark_waited() {
+                error!("failed to wait for network timer: {}", e);
+            }
+            timer_callback()
+        }
+    }
+
+    fn begin_read_from_guest(&mut self) -> io::Result<()> {
+        // Reads are done synchronously (and without blocking) in end_read_from_guest.
+        Ok(())
+    }
+
+    fn end_read_from_guest(&mut self) -> io::Result<&[u8]> {
+        match self.socket.recv(&mut self.buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the guest's virtio-net frontend closed the connection",
+            )),
+            // Skip over the veth header (12 bytes, created by the frontend per the virtio spec).
+            Ok(len) if len >= VETH_HEADER_LENGTH => Ok(&self.buf[VETH_HEADER_LENGTH..len]),
+            Ok(len) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Too few bytes ({}) read from the guest's virtio-net frontend.",
+                    len
+                ),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn slirp_events_to_poll_events(events: PollEvents) -> libc::c_short {
+    let mut poll_events = 0;
+    if events.has_in() {
+        poll_events |= libc::POLLIN;
+    }
+    if events.has_out() {
+        poll_events |= libc::POLLOUT;
+    }
+    if events.has_pri() {
+        poll_events |= libc::POLLPRI;
+    }
+    poll_events
+}
+
+fn poll_events_to_slirp_events(events: libc::c_short) -> PollEvents {
+    let mut slirp_events = PollEvents::empty();
+    if events & libc::POLLIN != 0 {
+        slirp_events |= PollEvents::poll_in();
+    }
+    if events & libc::POLLOUT != 0 {
+        slirp_events |= PollEvents::poll_out();
+    }
+    if events & libc::POLLPRI != 0 {
+        slirp_events |= PollEvents::poll_pri();
+    }
+    if events & libc::POLLERR != 0 {
+        slirp_events |= PollEvents::poll_err();
+    }
+    if events & libc::POLLHUP != 0 {
+        slirp_events |= PollEvents::poll_hup();
+    }
+    slirp_events
+}
+
+fn poll(poll_fds: &mut [libc::pollfd], timeout_ms: u32) -> io::Result<()> {
+    let timeout = if timeout_ms == u32::MAX {
+        -1
+    } else {
+        timeout_ms.min(i32::MAX as u32) as libc::c_int
+    };
+    loop {
+        // SAFETY:
+        // Safe because poll only writes to the revents of the provided fds, and we check the
+        // return value.
+        let ret = unsafe {
+            libc::poll(
+                poll_fds.as_mut_ptr(),
+                poll_fds.len() as libc::nfds_t,
+                timeout,
+            )
+        };
+        if ret >= 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Starts libslirp's main loop attached to host_socket. Packets are exchanged between host_socket
+/// and the host's network stack. Returns when the guest side of host_socket is closed.
+///
+/// host_socket must be non blocking & of type `SOCK_SEQPACKET`.
+pub fn start_slirp(
+    host_socket: UnixSeqpacket,
+    disable_access_to_host: bool,
+    host_forwards: &[HostFwd],
+) -> Result<()> {
+    let host_socket_fd = host_socket.as_raw_descriptor();
+    let mut context = create_slirp_context(host_socket, disable_access_to_host)?;
+    for fwd in host_forwards {
+        context
+            .add_hostfwd(
+                fwd.protocol == HostFwdProtocol::Udp,
+                fwd.host_addr,
+                fwd.host_port,
+                fwd.guest_addr,
+                fwd.guest_port,
+            )
+            .map_err(|e| Error::Slirp(SlirpError::AddHostFwd(*fwd, e)))?;
+    }
+
+    loop {
+        // Request the FDs that we should poll from Slirp. Slirp hands them to us through a
+        // callback which returns the index later used by pollfds_poll to query the poll results
+        // for that FD. The host socket and timers are appended after libslirp's FDs.
+        let mut poll_fds = Vec::new();
+        // We'd like to sleep as long as possible (assuming no actionable notifications arrive).
+        let mut timeout_ms: u32 = u32::MAX;
+        context.pollfds_fill(&mut timeout_ms, |fd: i32, events: PollEvents| {
+            poll_fds.push(libc::pollfd {
+                fd,
+                events: slirp_events_to_poll_events(events),
+                revents: 0,
+            });
+            (poll_fds.len() - 1) as i32
+        });
+        let slirp_fds_len = poll_fds.len();
+
+        poll_fds.push(libc::pollfd {
+            fd: host_socket_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        poll_fds.extend(context.get_timers().map(|timer| libc::pollfd {
+            fd: *timer,
+            events: libc::POLLIN,
+            revents: 0,
+        }));
+
+        poll(&mut poll_fds, timeout_ms)
+            .map_err(|e| Error::Slirp(SlirpError::SlirpIOPollError(e)))?;
+
+        if poll_fds[slirp_fds_len].revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0 {
+            // Collect input from the guest & inject into Slirp. This step must happen between
+            // pollfds_fill & pollfds_poll.
+            context.handle_guest_input()?;
+        }
+
+        let expired_timers: Vec<RawDescriptor> = poll_fds[slirp_fds_len + 1..]
+            .iter()
+            .filter(|pollfd| pollfd.revents & libc::POLLIN != 0)
+            .map(|pollfd| pollfd.fd)
+            .collect();
+        for timer in expired_timers {
+            context.execute_timer(timer);
+        }
+
+        // It's possible no socket notified and we got here from a timeout. This is fine, because
+        // libslirp wants to be woken up if timeout has expired (even if no sockets are ready).
+        context.pollfds_poll(false, |fd_index: i32| {
+            poll_events_to_slirp_events(poll_fds[fd_index as usize].revents)
+        });
+    }
+}
+
+fn create_slirp_context(
+    host_socket: UnixSeqpacket,
+    disable_access_to_host: bool,
+) -> Result<Box<Context<Handler>>> {
+    let handler = Handler {
+        start: Instant::now(),
+        socket: host_socket,
+        buf: [0; ETHERNET_FRAME_SIZE],
+        timer_callbacks: HashMap::new(),
+    };
+
+    // Address & mask of the virtual network.
+    let v4_network_addr = Ipv4Addr::new(10, 0, 2, 0);
+    let v4_network_mask = Ipv4Addr::new(255, 255, 255, 0);
+
+    // Address of the host machine on the virtual network (if the feature is enabled).
+    let host_v4_addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    // Address of the libslirp provided DNS proxy (packets to this address are intercepted by
+    // libslirp & routed to the first nameserver configured on the machine's NICs by libslirp).
+    let dns_addr = Ipv4Addr::new(10, 0, 2, 3);
+
+    // DHCP range should start *after* the statically assigned addresses.
+    let dhcp_start_addr = Ipv4Addr::new(10, 0, 2, 4);
+
+    // IPv6 network address. This is the same ULA (unique local address) network as on Windows.
+    let v6_network_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 0);
+
+    let v6_host_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 2);
+    let v6_dns_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 3);
+    Context::new(
+        disable_access_to_host,
+        /* IPv4 enabled */
+        true,
+        v4_network_addr,
+        v4_network_mask,
+        host_v4_addr,
+        /* IPv6 enabled */ true,
+        v6_network_addr,
+        /* virtual_network_v6_prefix_len */ 64,
+        /* host_v6_address */ v6_host_addr,
+        /* host_hostname */ None,
+        dhcp_start_addr,
+        dns_addr,
+        /* dns_server_v6_addr */ v6_dns_addr,
+        /* virtual_network_dns_search_domains */ Vec::new(),
+        /* dns_server_domain_name */ None,
+        handler,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_events_round_trip() {
+        let events = PollEvents::poll_in() | PollEvents::poll_out() | PollEvents::poll_pri();
+        assert_eq!(
+            poll_events_to_slirp_events(slirp_events_to_poll_events(events)),
+            events
+        );
+        assert_eq!(
+            poll_events_to_slirp_events(libc::POLLERR | libc::POLLHUP),
+            PollEvents::poll_err() | PollEvents::poll_hup()
+        );
+    }
+
+    #[test]
+    fn end_read_from_guest() {
+        let (guest, host) = UnixSeqpacket::pair().unwrap();
+        host.set_nonblocking(true).unwrap();
+        let mut handler = Handler {
+            start: Instant::now(),
+            socket: host,
+            buf: [0; ETHERNET_FRAME_SIZE],
+            timer_callbacks: HashMap::new(),
+        };
+
+        assert_eq!(
+            handler.end_read_from_guest().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let mut frame = vec![0u8; VETH_HEADER_LENGTH];
+        frame.extend_from_slice(&[1, 2, 3, 4]);
+        guest.send(&frame).unwrap();
+        assert_eq!(handler.end_read_from_guest().unwrap(), &[1, 2, 3, 4]);
+
+        guest.send(&[0u8; 4]).unwrap();
+        assert_eq!(
+            handler.end_read_from_guest().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        drop(guest);
+        assert_eq!(
+            handler.end_read_from_guest().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn send_packet_adds_vnet_header() {
+        let (guest, host) = UnixSeqpacket::pair().unwrap();
+        let mut handler = Handler {
+            start: Instant::now(),
+            socket: host,
+            buf: [0; ETHERNET_FRAME_SIZE],
+            timer_callbacks: HashMap::new(),
+        };
+
+        assert_eq!(handler.send_packet(&[5, 6, 7]).unwrap(), 3);
+        let mut buf = [0u8; ETHERNET_FRAME_SIZE];
+        let len = guest.recv(&mut buf).unwrap();
+        assert_eq!(len, VETH_HEADER_LENGTH + 3);
+        // num_buffers is the last field of the header.
+        assert_eq!(&buf[VETH_HEADER_LENGTH - 2..VETH_HEADER_LENGTH], &[1, 0]);
+        assert_eq!(&buf[VETH_HEADER_LENGTH..len], &[5, 6, 7]);
+    }
+}
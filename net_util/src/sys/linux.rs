@@ -2,8 +2,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+pub mod socket;
 pub mod tap;
 use base::FileReadWriteVolatile;
+pub use socket::FrameSocket;
 pub use tap::Tap;
 
 use crate::TapTCommon;
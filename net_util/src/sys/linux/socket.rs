@@ -0,0 +1,776 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::mem::size_of;
+use std::net;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::net::UdpSocket;
+use std::os::raw::c_uint;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+
+use base::error;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FileReadWriteVolatile;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::UnixSeqpacket;
+use base::VolatileSlice;
+use cros_async::IntoAsync;
+use virtio_sys::virtio_net::virtio_net_hdr_mrg_rxbuf;
+use zerocopy::AsBytes;
+
+use crate::sys::linux::TapTLinux;
+use crate::Error;
+use crate::MacAddress;
+use crate::Result;
+use crate::TapT;
+use crate::TapTCommon;
+
+/// The MTU advertised to the guest. Peers are other VMs or user-mode switches, which all expect
+/// regular ethernet frames.
+const FRAME_SOCKET_MTU: u16 = 1500;
+
+/// Size of the largest frame that can be received, which is the largest possible datagram.
+const MAX_FRAME_SIZE: usize = 65536;
+
+/// Size of the virtio-net header exchanged with the device before each frame.
+const VNET_HDR_LEN: usize = size_of::<virtio_net_hdr_mrg_rxbuf>();
+
+/// Size of the big-endian length that precedes each frame on a stream socket.
+const STREAM_LEN_PREFIX: usize = size_of::<u32>();
+
+/// How long to wait for the peer of a stream socket to accept the rest of a partially sent frame.
+const STREAM_SEND_TIMEOUT_MS: libc::c_int = 1000;
+
+enum Socket {
+    /// Connected unix `SOCK_SEQPACKET` socket, one frame per packet.
+    Seqpacket(UnixSeqpacket),
+    /// Connected unix `SOCK_STREAM` socket, each frame preceded by its length as a 32-bit
+    /// big-endian integer. `received` counts the bytes of the length and the frame read so far.
+    Stream { stream: UnixStream, received: usize },
+    /// Unix datagram socket, one frame per datagram sent to `remote`.
+    UnixDgram {
+        socket: UnixDatagram,
+        remote: PathBuf,
+    },
+    /// UDP sockets sharing a multicast group, one frame per datagram. Frames are received on
+    /// `socket` and sent from `send_socket`, whose address identifies the frames looped back to us.
+    Multicast {
+        socket: UdpSocket,
+        send_socket: UdpSocket,
+        send_addr: SocketAddr,
+    },
+}
+
+/// Pseudo-tap interface exchanging raw ethernet frames over a socket, e.g. with another VM or with
+/// a user-mode switch such as passt or QEMU's `-netdev stream`/`dgram`.
+///
+/// Unlike with a tap device, the peer does not know about virtio-net headers: they are stripped
+/// from the frames sent by the guest and a blank one is prepended to each received frame. As a
+/// consequence, no offload is supported.
+pub struct FrameSocket {
+    socket: Socket,
+    rx_buf: Vec<u8>,
+    tx_buf: Vec<u8>,
+}
+
+impl FrameSocket {
+    fn with_socket(socket: Socket) -> FrameSocket {
+        FrameSocket {
+            socket,
+            rx_buf: vec![0; VNET_HDR_LEN + MAX_FRAME_SIZE],
+            tx_buf: Vec::with_capacity(VNET_HDR_LEN + MAX_FRAME_SIZE),
+        }
+    }
+
+    /// Connects to the `SOCK_STREAM` unix socket listening at `path`, e.g. that of passt or of
+    /// QEMU's `-netdev stream`.
+    pub fn connect_stream<P: AsRef<Path>>(path: P) -> Result<FrameSocket> {
+        let stream = UnixStream::connect(path)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        stream
+            .set_nonblocking(true)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        Ok(FrameSocket::with_socket(Socket::Stream {
+            stream,
+            received: 0,
+        }))
+    }
+
+    /// Connects to the `SOCK_SEQPACKET` unix socket listening at `path`.
+    pub fn connect_seqpacket<P: AsRef<Path>>(path: P) -> Result<FrameSocket> {
+        let socket = UnixSeqpacket::connect(path)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        socket
+            .set_nonblocking(true)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        Ok(FrameSocket::with_socket(Socket::Seqpacket(socket)))
+    }
+
+    /// Binds a unix datagram socket at `local` and sends frames to `remote`.
+    ///
+    /// A stale socket left at `local` by a previous instance is removed. Frames sent while nobody
+    /// is bound at `remote` are dropped, so the peers can be started in any order.
+    pub fn unix_dgram<P: AsRef<Path>, Q: AsRef<Path>>(local: P, remote: Q) -> Result<FrameSocket> {
+        let local = local.as_ref();
+        if let Ok(metadata) = fs::symlink_metadata(local) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(local)
+                    .map_err(SysError::from)
+                    .map_err(Error::CreateSocket)?;
+            }
+        }
+        let socket = UnixDatagram::bind(local)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        socket
+            .set_nonblocking(true)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        Ok(FrameSocket::with_socket(Socket::UnixDgram {
+            socket,
+            remote: remote.as_ref().to_path_buf(),
+        }))
+    }
+
+    /// Joins the UDP multicast `group`, which acts as a hub: frames are sent to every other
+    /// member of the group.
+    pub fn multicast(group: SocketAddrV4) -> Result<FrameSocket> {
+        if !group.ip().is_multicast() {
+            return Err(Error::CreateSocket(SysError::new(libc::EINVAL)));
+        }
+
+        // All the members on the host must be able to bind the same address, which can only be
+        // requested before binding.
+        let socket = reusable_udp_socket(group).map_err(Error::CreateSocket)?;
+        socket
+            .join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+
+        let send_socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        // Members running on the same host receive the frames through the loopback.
+        send_socket
+            .set_multicast_loop_v4(true)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        // Connecting picks the source address of the frames we send, which the other members of
+        // the group may share (on the same host) but not together with the port.
+        send_socket
+            .connect(group)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        let send_addr = send_socket
+            .local_addr()
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+
+        for socket in [&socket, &send_socket] {
+            socket
+                .set_nonblocking(true)
+                .map_err(SysError::from)
+                .map_err(Error::CreateSocket)?;
+        }
+        Ok(FrameSocket::with_socket(Socket::Multicast {
+            socket,
+            send_socket,
+            send_addr,
+        }))
+    }
+
+    /// Receives the next frame in `rx_buf`, after a virtio-net header, and returns the length of
+    /// both.
+    fn recv_frame(&mut self) -> IoResult<usize> {
+        let buf = &mut self.rx_buf[VNET_HDR_LEN..];
+        let len = match &mut self.socket {
+            Socket::Seqpacket(socket) => {
+                let len = socket.recv(buf)?;
+                if len == 0 {
+                    // The peer closed the connection.
+                    return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+                }
+                len
+            }
+            Socket::Stream { stream, received } => recv_stream_frame(
+                stream,
+                received,
+                &mut self.rx_buf[VNET_HDR_LEN - STREAM_LEN_PREFIX..],
+            )?,
+            Socket::UnixDgram { socket, .. } => socket.recv(buf)?,
+            Socket::Multicast {
+                socket, send_addr, ..
+            } => loop {
+                let (len, addr) = socket.recv_from(buf)?;
+                // Skip the frames we sent ourselves.
+                if addr != *send_addr {
+                    break len;
+                }
+            },
+        };
+
+        let vnet_hdr = virtio_net_hdr_mrg_rxbuf {
+            num_buffers: 1,
+            ..Default::default()
+        };
+        self.rx_buf[..VNET_HDR_LEN].copy_from_slice(vnet_hdr.as_bytes());
+        Ok(VNET_HDR_LEN + len)
+    }
+
+    /// Sends the frame following the virtio-net header in `buf`.
+    fn send_frame(socket: &Socket, buf: &[u8]) -> IoResult<()> {
+        let frame = buf
+            .get(VNET_HDR_LEN..)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let res = match socket {
+            Socket::Seqpacket(socket) => {
+                // SAFETY:
+                // Safe because send only reads frame, and we check the return value.
+                // MSG_NOSIGNAL turns the death of the peer into an error instead of a SIGPIPE.
+                let ret = unsafe {
+                    libc::send(
+                        socket.as_raw_descriptor(),
+                        frame.as_ptr() as *const libc::c_void,
+                        frame.len(),
+                        libc::MSG_NOSIGNAL,
+                    )
+                };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            }
+            Socket::Stream { stream, .. } => return send_stream_frame(stream, frame),
+            Socket::UnixDgram { socket, remote } => socket.send_to(frame, remote),
+            Socket::Multicast { send_socket, .. } => send_socket.send(frame),
+        };
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) if matches!(socket, Socket::Seqpacket(_)) => Err(e),
+            // Datagrams are lost when the peer is missing or not keeping up, like frames on a wire.
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::ENOENT | libc::ECONNREFUSED | libc::EAGAIN | libc::ENOBUFS)
+                ) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Reads the next frame from a stream socket, preceded by its length, into `buf`, which must have
+/// room for the length before the frame. `received` keeps track of a frame that was only partially
+/// read when the socket ran out of data, and `WouldBlock` is returned until it is complete.
+fn recv_stream_frame(
+    mut stream: &UnixStream,
+    received: &mut usize,
+    buf: &mut [u8],
+) -> IoResult<usize> {
+    loop {
+        // Only read up to the end of the current frame, so that the next one stays in the socket.
+        let end = if *received < STREAM_LEN_PREFIX {
+            STREAM_LEN_PREFIX
+        } else {
+            let len = u32::from_be_bytes(buf[..STREAM_LEN_PREFIX].try_into().unwrap()) as usize;
+            if len > MAX_FRAME_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame of {} bytes is too large", len),
+                ));
+            }
+            if *received == STREAM_LEN_PREFIX + len {
+                *received = 0;
+                // Skip empty frames.
+                if len == 0 {
+                    continue;
+                }
+                return Ok(len);
+            }
+            STREAM_LEN_PREFIX + len
+        };
+        match stream.read(&mut buf[*received..end]) {
+            // The peer closed the connection.
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            Ok(count) => *received += count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends `frame` preceded by its length on a stream socket. Like with datagrams, the frame is
+/// dropped if the peer is not keeping up, but once part of it was sent the rest must follow to
+/// keep the stream in sync, so this waits for the peer to accept it.
+fn send_stream_frame(stream: &UnixStream, frame: &[u8]) -> IoResult<()> {
+    let prefix = (frame.len() as u32).to_be_bytes();
+    let total = prefix.len() + frame.len();
+    let mut sent = 0;
+    while sent < total {
+        let (prefix_rest, frame_rest) = if sent < prefix.len() {
+            (&prefix[sent..], frame)
+        } else {
+            (&prefix[..0], &frame[sent - prefix.len()..])
+        };
+        let mut iovecs = [
+            libc::iovec {
+                iov_base: prefix_rest.as_ptr() as *mut libc::c_void,
+                iov_len: prefix_rest.len(),
+            },
+            libc::iovec {
+                iov_base: frame_rest.as_ptr() as *mut libc::c_void,
+                iov_len: frame_rest.len(),
+            },
+        ];
+        // SAFETY:
+        // Safe because msghdr is plain data that is fully initialized below.
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = iovecs.as_mut_ptr();
+        msg.msg_iovlen = iovecs.len() as _;
+        // SAFETY:
+        // Safe because sendmsg only reads the buffers described by `msg`, and we check the return
+        // value. MSG_NOSIGNAL turns the death of the peer into an error instead of a SIGPIPE.
+        let ret = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if ret >= 0 {
+            sent += ret as usize;
+            continue;
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => {}
+            io::ErrorKind::WouldBlock if sent == 0 => return Ok(()),
+            io::ErrorKind::WouldBlock => wait_writable(stream)?,
+            _ => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn wait_writable(stream: &UnixStream) -> IoResult<()> {
+    let mut pollfd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    // SAFETY:
+    // Safe because poll only accesses `pollfd`, and we check the return value.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, STREAM_SEND_TIMEOUT_MS) };
+    match ret {
+        0 => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        ret if ret < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Creates a UDP socket bound to `addr` with `SO_REUSEADDR` set.
+fn reusable_udp_socket(addr: SocketAddrV4) -> std::result::Result<UdpSocket, SysError> {
+    // SAFETY:
+    // Safe because socket doesn't take any pointer, and we check the return value.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(SysError::last());
+    }
+    // SAFETY:
+    // Safe because we own the freshly created fd.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let enable: libc::c_int = 1;
+    // SAFETY:
+    // Safe because setsockopt only reads `enable`, and we check the return value.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &enable as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(SysError::last());
+    }
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY:
+    // Safe because bind only reads `sockaddr`, and we check the return value.
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(SysError::last());
+    }
+    Ok(socket)
+}
+
+impl TapT for FrameSocket {}
+
+impl TapTCommon for FrameSocket {
+    fn new_with_name(_name: &[u8], _vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        // Frame sockets are created by the `FrameSocket` constructors.
+        Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<FrameSocket> {
+        Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>> {
+        if vq_pairs != 1 {
+            error!("frames are exchanged over a single socket; only one vq pair is supported");
+            return Err(Error::CreateTap(SysError::new(libc::EINVAL)));
+        }
+
+        Ok(vec![self])
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(FRAME_SOCKET_MTU)
+    }
+
+    fn set_mtu(&self, _mtu: u16) -> Result<()> {
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        // Only used by the plugin system.
+        Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn set_offload(&self, flags: c_uint) -> Result<()> {
+        // The peer only handles complete, checksummed frames.
+        if flags != 0 {
+            return Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)));
+        }
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        let socket = match &self.socket {
+            Socket::Seqpacket(socket) => Socket::Seqpacket(socket.try_clone().map_err(clone_err)?),
+            // A frame partially read by this handle can't be finished by the clone, so only one of
+            // them may receive frames.
+            Socket::Stream { stream, .. } => Socket::Stream {
+                stream: stream.try_clone().map_err(clone_err)?,
+                received: 0,
+            },
+            Socket::UnixDgram { socket, remote } => Socket::UnixDgram {
+                socket: socket.try_clone().map_err(clone_err)?,
+                remote: remote.clone(),
+            },
+            Socket::Multicast {
+                socket,
+                send_socket,
+                send_addr,
+            } => Socket::Multicast {
+                socket: socket.try_clone().map_err(clone_err)?,
+                send_socket: send_socket.try_clone().map_err(clone_err)?,
+                send_addr: *send_addr,
+            },
+        };
+        Ok(FrameSocket::with_socket(socket))
+    }
+
+    unsafe fn from_raw_descriptor(_descriptor: RawDescriptor) -> Result<Self> {
+        // Frame sockets are created by the `FrameSocket` constructors.
+        Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)))
+    }
+}
+
+fn clone_err(e: io::Error) -> Error {
+    Error::CloneTap(SysError::from(e))
+}
+
+impl TapTLinux for FrameSocket {
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()> {
+        // Only the header of the virtio-net device is added and stripped.
+        if size != VNET_HDR_LEN {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn if_flags(&self) -> u32 {
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR
+    }
+}
+
+impl Read for FrameSocket {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let len = self.recv_frame()?;
+        let copied = len.min(buf.len());
+        buf[..copied].copy_from_slice(&self.rx_buf[..copied]);
+        Ok(copied)
+    }
+}
+
+impl Write for FrameSocket {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        FrameSocket::send_frame(&self.socket, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl FileReadWriteVolatile for FrameSocket {
+    fn read_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.read_vectored_volatile(&[slice])
+    }
+
+    fn read_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        // Like a tap device, the frame is truncated if it does not fit in the buffers.
+        let len = self.recv_frame()?;
+        let mut copied = 0;
+        for buf in bufs {
+            if copied == len {
+                break;
+            }
+            let count = buf.size().min(len - copied);
+            buf.copy_from(&self.rx_buf[copied..copied + count]);
+            copied += count;
+        }
+        Ok(copied)
+    }
+
+    fn write_volatile(&mut self, slice: VolatileSlice) -> IoResult<usize> {
+        self.write_vectored_volatile(&[slice])
+    }
+
+    fn write_vectored_volatile(&mut self, bufs: &[VolatileSlice]) -> IoResult<usize> {
+        // The frame must be sent as a single datagram, so gather it first.
+        self.tx_buf.clear();
+        for buf in bufs {
+            let start = self.tx_buf.len();
+            self.tx_buf.resize(start + buf.size(), 0);
+            buf.copy_to(&mut self.tx_buf[start..]);
+        }
+        FrameSocket::send_frame(&self.socket, &self.tx_buf)?;
+        Ok(self.tx_buf.len())
+    }
+}
+
+impl AsRawFd for FrameSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_raw_descriptor()
+    }
+}
+
+impl AsRawDescriptor for FrameSocket {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match &self.socket {
+            Socket::Seqpacket(socket) => socket.as_raw_descriptor(),
+            Socket::Stream { stream, .. } => stream.as_raw_fd(),
+            Socket::UnixDgram { socket, .. } => socket.as_raw_fd(),
+            Socket::Multicast { socket, .. } => socket.as_raw_fd(),
+        }
+    }
+}
+
+impl ReadNotifier for FrameSocket {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        self
+    }
+}
+
+impl IntoAsync for FrameSocket {}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn vnet_hdr() -> Vec<u8> {
+        virtio_net_hdr_mrg_rxbuf {
+            num_buffers: 1,
+            ..Default::default()
+        }
+        .as_bytes()
+        .to_vec()
+    }
+
+    #[test]
+    fn seqpacket_frames() {
+        let (peer, socket) = UnixSeqpacket::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let mut tap = FrameSocket::with_socket(Socket::Seqpacket(socket));
+
+        // The header of outgoing frames is stripped, even when spread across several buffers.
+        let mut hdr = [0u8; VNET_HDR_LEN];
+        let (hdr_start, hdr_end) = hdr.split_at_mut(8);
+        let mut frame = [1u8, 2, 3, 4];
+        let bufs = [
+            VolatileSlice::new(hdr_start),
+            VolatileSlice::new(hdr_end),
+            VolatileSlice::new(&mut frame),
+        ];
+        assert_eq!(
+            tap.write_vectored_volatile(&bufs).unwrap(),
+            VNET_HDR_LEN + 4
+        );
+        let mut buf = [0u8; 64];
+        assert_eq!(peer.recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[1, 2, 3, 4]);
+
+        // A header is prepended to incoming frames.
+        peer.send(&[5, 6, 7]).unwrap();
+        let mut rx = [0u8; 64];
+        let len = tap
+            .read_vectored_volatile(&[VolatileSlice::new(&mut rx)])
+            .unwrap();
+        assert_eq!(len, VNET_HDR_LEN + 3);
+        assert_eq!(&rx[..VNET_HDR_LEN], &vnet_hdr()[..]);
+        assert_eq!(&rx[VNET_HDR_LEN..len], &[5, 6, 7]);
+
+        // Nothing left to read.
+        let err = tap.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(peer);
+        let err = tap.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn seqpacket_short_frame() {
+        let (_peer, socket) = UnixSeqpacket::pair().unwrap();
+        let mut tap = FrameSocket::with_socket(Socket::Seqpacket(socket));
+        let err = tap.write(&[0u8; VNET_HDR_LEN - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn stream_frames() {
+        let (mut peer, stream) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut tap = FrameSocket::with_socket(Socket::Stream {
+            stream,
+            received: 0,
+        });
+
+        // Outgoing frames are preceded by their length.
+        let mut frame = vnet_hdr();
+        frame.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(tap.write(&frame).unwrap(), frame.len());
+        let mut buf = [0u8; 7];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 3, 1, 2, 3]);
+
+        // Incoming frames are delimited by their length, even when split across several reads.
+        peer.write_all(&[0, 0, 0, 2, 4]).unwrap();
+        let mut rx = [0u8; 64];
+        let err = tap.read(&mut rx).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        peer.write_all(&[5, 0, 0, 0, 0, 0, 0, 0, 1, 6]).unwrap();
+        assert_eq!(tap.read(&mut rx).unwrap(), VNET_HDR_LEN + 2);
+        assert_eq!(&rx[..VNET_HDR_LEN], &vnet_hdr()[..]);
+        assert_eq!(&rx[VNET_HDR_LEN..VNET_HDR_LEN + 2], &[4, 5]);
+        // The empty frame is skipped.
+        assert_eq!(tap.read(&mut rx).unwrap(), VNET_HDR_LEN + 1);
+        assert_eq!(rx[VNET_HDR_LEN], 6);
+
+        // Oversized frames can't be resynchronized.
+        peer.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let err = tap.read(&mut rx).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        drop(peer);
+        let mut tap = FrameSocket::with_socket(Socket::Stream {
+            stream: UnixStream::pair().unwrap().0,
+            received: 0,
+        });
+        let err = tap.read(&mut rx).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn unix_dgram_frames() {
+        let dir = TempDir::new().unwrap();
+        let a_path = dir.path().join("a.sock");
+        let b_path = dir.path().join("b.sock");
+
+        // Frames are dropped until the peer shows up.
+        let mut a = FrameSocket::unix_dgram(&a_path, &b_path).unwrap();
+        let mut frame = vnet_hdr();
+        frame.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(a.write(&frame).unwrap(), frame.len());
+
+        let mut b = FrameSocket::unix_dgram(&b_path, &a_path).unwrap();
+        assert_eq!(a.write(&frame).unwrap(), frame.len());
+        let mut buf = [0u8; 64];
+        assert_eq!(b.read(&mut buf).unwrap(), frame.len());
+        assert_eq!(&buf[..frame.len()], &frame[..]);
+        let err = b.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // A stale socket is replaced.
+        drop(b);
+        FrameSocket::unix_dgram(&b_path, &a_path).unwrap();
+    }
+}
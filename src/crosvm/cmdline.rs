@@ -43,6 +43,8 @@ use devices::virtio::GpuDisplayParameters;
 use devices::virtio::GpuMouseMode;
 #[cfg(feature = "gpu")]
 use devices::virtio::GpuParameters;
+#[cfg(all(any(target_os = "android", target_os = "linux"), feature = "gpu"))]
+use devices::virtio::GpuVncAddress;
 #[cfg(all(unix, feature = "net"))]
 use devices::virtio::NetParameters;
 #[cfg(all(unix, feature = "net"))]
@@ -65,6 +67,8 @@ use serde::Deserializer;
 use serde::Serialize;
 #[cfg(feature = "gpu")]
 use serde_keyvalue::FromKeyValues;
+use vm_control::DiskThrottleConfig;
+use vm_control::NetRateLimitConfig;
 
 #[cfg(feature = "gpu")]
 use super::gpu_config::fixup_gpu_display_options;
@@ -83,6 +87,7 @@ use crate::crosvm::config::parse_mmio_address_range;
 use crate::crosvm::config::parse_pflash_parameters;
 use crate::crosvm::config::parse_serial_options;
 use crate::crosvm::config::parse_touch_device_option;
+use crate::crosvm::config::parse_touch_frame;
 use crate::crosvm::config::parse_vhost_user_fs_option;
 use crate::crosvm::config::BatteryConfig;
 use crate::crosvm::config::CpuOptions;
@@ -145,9 +150,12 @@ pub enum CrossPlatformCommands {
     Disk(DiskCommand),
     #[cfg(feature = "gpu")]
     Gpu(GpuCommand),
+    Input(InputCommand),
     MakeRT(MakeRTCommand),
+    Net(NetCommand),
     Resume(ResumeCommand),
     Run(RunCommand),
+    Scsi(ScsiCommand),
     Stop(StopCommand),
     Suspend(SuspendCommand),
     Swap(SwapCommand),
@@ -256,6 +264,23 @@ pub struct CreateQcow2Command {
 #[argh(subcommand)]
 pub enum DiskSubcommand {
     Resize(ResizeDiskSubcommand),
+    Snapshot(SnapshotDiskSubcommand),
+    ChangeMedia(ChangeMediaDiskSubcommand),
+    Eject(EjectDiskSubcommand),
+    SetThrottle(SetThrottleDiskSubcommand),
+    Stats(StatsDiskSubcommand),
+    #[cfg(feature = "qcow")]
+    Create(CreateDiskSubcommand),
+    #[cfg(feature = "qcow")]
+    Info(InfoDiskSubcommand),
+    #[cfg(feature = "qcow")]
+    Check(CheckDiskSubcommand),
+    #[cfg(feature = "qcow")]
+    Commit(CommitDiskSubcommand),
+    #[cfg(feature = "qcow")]
+    Stream(StreamDiskSubcommand),
+    LiveCommit(LiveCommitDiskSubcommand),
+    LiveStream(LiveStreamDiskSubcommand),
 }
 
 #[derive(FromArgs)]
@@ -273,6 +298,241 @@ pub struct ResizeDiskSubcommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+/// replace the disk image of a running VM
+#[argh(subcommand, name = "change-media")]
+pub struct ChangeMediaDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "DISK_PATH")]
+    /// path to the new disk image
+    pub disk_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(switch)]
+    /// open the new disk image read-only, only allowed for read-only disks
+    pub read_only: bool,
+}
+
+#[derive(FromArgs)]
+/// eject the disk image, leaving the disk with no medium
+#[argh(subcommand, name = "eject")]
+pub struct EjectDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// set the I/O limits of a disk, replacing the current ones
+#[argh(subcommand, name = "set-throttle")]
+pub struct SetThrottleDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(
+        positional,
+        arg_name = "key=value[,key=value[,...]]",
+        from_str_fn(from_key_values)
+    )]
+    /// I/O limits, empty to remove all limits. Valid keys are read-iops, write-iops, read-bps,
+    /// write-bps, read-iops-burst, write-iops-burst, read-bps-burst and write-bps-burst.
+    pub throttle: DiskThrottleConfig,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// print the latency, size and queue depth statistics of the requests to a disk
+#[argh(subcommand, name = "stats")]
+pub struct StatsDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(switch)]
+    /// clear the statistics after printing them
+    pub reset: bool,
+    #[argh(switch)]
+    /// print the statistics as JSON
+    pub json: bool,
+}
+
+#[cfg(feature = "qcow")]
+#[derive(FromArgs)]
+/// create a qcow2 image
+#[argh(subcommand, name = "create")]
+pub struct CreateDiskSubcommand {
+    #[argh(positional, arg_name = "PATH")]
+    /// path to the new qcow2 file to create
+    pub file_path: String,
+    #[argh(positional, arg_name = "SIZE")]
+    /// desired size of the image in bytes; required if not using --backing-file
+    pub size: Option<u64>,
+    #[argh(option)]
+    /// path to backing file; if specified, the image will be the same size as the backing file,
+    /// and SIZE may not be specified
+    pub backing_file: Option<String>,
+}
+
+#[cfg(feature = "qcow")]
+#[derive(FromArgs)]
+/// show the format, size and backing chain of a disk image
+#[argh(subcommand, name = "info")]
+pub struct InfoDiskSubcommand {
+    #[argh(positional, arg_name = "PATH")]
+    /// path to the disk image
+    pub file_path: String,
+    #[argh(switch)]
+    /// print the information as JSON
+    pub json: bool,
+}
+
+#[cfg(feature = "qcow")]
+#[derive(FromArgs)]
+/// check the refcounts of a qcow2 image for leaked or multiply referenced clusters
+#[argh(subcommand, name = "check")]
+pub struct CheckDiskSubcommand {
+    #[argh(positional, arg_name = "PATH")]
+    /// path to the qcow2 image, which must not be in use
+    pub file_path: String,
+    #[argh(switch)]
+    /// rebuild the refcounts if any problems are found
+    pub repair: bool,
+    #[argh(switch)]
+    /// print the result as JSON
+    pub json: bool,
+}
+
+#[cfg(feature = "qcow")]
+#[derive(FromArgs)]
+/// merge a qcow2 image into its backing file
+#[argh(subcommand, name = "commit")]
+pub struct CommitDiskSubcommand {
+    #[argh(positional, arg_name = "PATH")]
+    /// path to the qcow2 image, which must not be in use
+    pub file_path: String,
+}
+
+#[cfg(feature = "qcow")]
+#[derive(FromArgs)]
+/// copy the data of the backing file into a qcow2 image and drop the backing file
+#[argh(subcommand, name = "stream")]
+pub struct StreamDiskSubcommand {
+    #[argh(positional, arg_name = "PATH")]
+    /// path to the qcow2 image, which must not be in use
+    pub file_path: String,
+}
+
+#[derive(FromArgs)]
+/// merge the disk image of a running VM into its backing file
+#[argh(subcommand, name = "live-commit")]
+pub struct LiveCommitDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "BACKING_PATH")]
+    /// path to the backing file of the disk image
+    pub backing_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// copy the data of the backing file into the disk image of a running VM and drop the backing file
+#[argh(subcommand, name = "live-stream")]
+pub struct LiveStreamDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// create a snapshot of the disk contents
+#[argh(subcommand, name = "create")]
+pub struct CreateDiskSnapshotCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// list the snapshots stored in the disk image
+#[argh(subcommand, name = "list")]
+pub struct ListDiskSnapshotsCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// revert the disk contents to a snapshot
+#[argh(subcommand, name = "apply")]
+pub struct ApplyDiskSnapshotCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name or ID
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// delete a snapshot
+#[argh(subcommand, name = "delete")]
+pub struct DeleteDiskSnapshotCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name or ID
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum DiskSnapshotSubcommand {
+    Create(CreateDiskSnapshotCommand),
+    List(ListDiskSnapshotsCommand),
+    Apply(ApplyDiskSnapshotCommand),
+    Delete(DeleteDiskSnapshotCommand),
+}
+
+#[derive(FromArgs)]
+/// manage internal snapshots of a disk image, pause the VM first for consistent snapshots
+#[argh(subcommand, name = "snapshot")]
+pub struct SnapshotDiskSubcommand {
+    #[argh(subcommand)]
+    pub command: DiskSnapshotSubcommand,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "disk")]
 /// Manage attached virtual disk devices
@@ -281,6 +541,258 @@ pub struct DiskCommand {
     pub command: DiskSubcommand,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum ScsiSubcommand {
+    Add(AddScsiSubcommand),
+    ChangeMedia(ChangeMediaScsiSubcommand),
+    Eject(EjectScsiSubcommand),
+    Remove(RemoveScsiSubcommand),
+}
+
+#[derive(FromArgs)]
+/// attach a disk image to the first free target of the virtio-scsi controller
+#[argh(subcommand, name = "add")]
+pub struct AddScsiSubcommand {
+    #[argh(positional, arg_name = "DISK_PATH")]
+    /// path to the disk image
+    pub disk_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(switch)]
+    /// expose the disk as read-only
+    pub read_only: bool,
+}
+
+#[derive(FromArgs)]
+/// insert a disk image into a SCSI CD-ROM drive, replacing the current medium
+#[argh(subcommand, name = "change-media")]
+pub struct ChangeMediaScsiSubcommand {
+    #[argh(positional, arg_name = "TARGET")]
+    /// target ID of the CD-ROM drive
+    pub target: u8,
+    #[argh(positional, arg_name = "IMAGE_PATH")]
+    /// path to the disk image
+    pub image_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// remove the medium from a SCSI CD-ROM drive
+#[argh(subcommand, name = "eject")]
+pub struct EjectScsiSubcommand {
+    #[argh(positional, arg_name = "TARGET")]
+    /// target ID of the CD-ROM drive
+    pub target: u8,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(switch)]
+    /// eject the medium even if the guest prevented its removal
+    pub force: bool,
+}
+
+#[derive(FromArgs)]
+/// detach the logical unit of a target from the virtio-scsi controller
+#[argh(subcommand, name = "remove")]
+pub struct RemoveScsiSubcommand {
+    #[argh(positional, arg_name = "TARGET")]
+    /// target ID of the logical unit
+    pub target: u8,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "scsi")]
+/// Manage the logical units of the virtio-scsi controller
+pub struct ScsiCommand {
+    #[argh(subcommand)]
+    pub command: ScsiSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum NetSubcommand {
+    StartCapture(StartCaptureNetSubcommand),
+    StopCapture(StopCaptureNetSubcommand),
+    SetRateLimit(SetRateLimitNetSubcommand),
+}
+
+#[derive(FromArgs)]
+/// capture the frames going through a network device to a pcapng file
+#[argh(subcommand, name = "start-capture")]
+pub struct StartCaptureNetSubcommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// index of the network device, in the order of the --net options
+    pub net_index: usize,
+    #[argh(positional, arg_name = "PCAP_PATH")]
+    /// path of the pcapng file to create
+    pub pcap_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// stop the frame capture of a network device
+#[argh(subcommand, name = "stop-capture")]
+pub struct StopCaptureNetSubcommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// index of the network device, in the order of the --net options
+    pub net_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// set the rate limits of a network device, replacing the current ones
+#[argh(subcommand, name = "set-rate-limit")]
+pub struct SetRateLimitNetSubcommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// index of the network device, in the order of the --net options
+    pub net_index: usize,
+    #[argh(
+        positional,
+        arg_name = "key=value[,key=value[,...]]",
+        from_str_fn(from_key_values)
+    )]
+    /// rate limits, empty to remove all limits. Valid keys are rx-bps, tx-bps, rx-pps, tx-pps,
+    /// rx-bps-burst, tx-bps-burst, rx-pps-burst and tx-pps-burst.
+    pub rate_limit: NetRateLimitConfig,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "net")]
+/// Manage the network devices
+pub struct NetCommand {
+    #[argh(subcommand)]
+    pub command: NetSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum InputSubcommand {
+    Key(KeyInputSubcommand),
+    Type(TypeInputSubcommand),
+    Move(MoveInputSubcommand),
+    Click(ClickInputSubcommand),
+    Touch(TouchInputSubcommand),
+}
+
+#[derive(FromArgs)]
+/// press and release keys together, the keys being released in the reverse order
+#[argh(subcommand, name = "key")]
+pub struct KeyInputSubcommand {
+    #[argh(switch)]
+    /// only press the keys
+    pub press: bool,
+    #[argh(switch)]
+    /// only release the keys
+    pub release: bool,
+    #[argh(positional, arg_name = "INPUT_INDEX")]
+    /// index of the input device, in the order of the --input options
+    pub input_index: usize,
+    #[argh(positional, arg_name = "KEYS")]
+    /// keys separated by '+', named after their Linux KEY_* constants, e.g. "leftctrl+c"
+    pub keys: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// type a text, assuming a US layout in the guest
+#[argh(subcommand, name = "type")]
+pub struct TypeInputSubcommand {
+    #[argh(positional, arg_name = "INPUT_INDEX")]
+    /// index of the input device, in the order of the --input options
+    pub input_index: usize,
+    #[argh(positional, arg_name = "TEXT")]
+    /// text to type
+    pub text: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// move the pointer to a position, or by an offset with --relative
+#[argh(subcommand, name = "move")]
+pub struct MoveInputSubcommand {
+    #[argh(switch)]
+    /// move the pointer by X and Y instead of to (X, Y). Negative offsets must follow "--"
+    pub relative: bool,
+    #[argh(positional, arg_name = "INPUT_INDEX")]
+    /// index of the input device, in the order of the --input options
+    pub input_index: usize,
+    #[argh(positional, arg_name = "X")]
+    /// horizontal position or offset
+    pub x: i32,
+    #[argh(positional, arg_name = "Y")]
+    /// vertical position or offset
+    pub y: i32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// press and release a mouse button
+#[argh(subcommand, name = "click")]
+pub struct ClickInputSubcommand {
+    #[argh(option, default = "String::from(\"left\")")]
+    /// button to click: left (default), right, middle, side or extra
+    pub button: String,
+    #[argh(switch)]
+    /// only press the button
+    pub press: bool,
+    #[argh(switch)]
+    /// only release the button
+    pub release: bool,
+    #[argh(positional, arg_name = "INPUT_INDEX")]
+    /// index of the input device, in the order of the --input options
+    pub input_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// touch a device with one or more fingers, lifting them at the end unless --hold is given
+#[argh(subcommand, name = "touch")]
+pub struct TouchInputSubcommand {
+    #[argh(option, arg_name = "X,Y[:X,Y...]", from_str_fn(parse_touch_frame))]
+    /// positions of the fingers touching the device, one per finger. Can be given several times
+    /// to move the fingers, e.g. to swipe or pinch
+    pub frame: Vec<Vec<(u32, u32)>>,
+    #[argh(switch)]
+    /// keep the fingers on the device after the last frame
+    pub hold: bool,
+    #[argh(positional, arg_name = "INPUT_INDEX")]
+    /// index of the input device, in the order of the --input options
+    pub input_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "input")]
+/// Inject synthetic events into the virtio-input devices
+pub struct InputCommand {
+    #[argh(subcommand)]
+    pub command: InputSubcommand,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "make_rt")]
 /// Enables real-time vcpu priority for crosvm instances started with `--delay-rt`
@@ -562,7 +1074,10 @@ pub enum GpuSubCommand {
     AddDisplays(GpuAddDisplaysCommand),
     ListDisplays(GpuListDisplaysCommand),
     RemoveDisplays(GpuRemoveDisplaysCommand),
+    Screenshot(GpuScreenshotCommand),
     SetDisplayMouseMode(GpuSetDisplayMouseModeCommand),
+    StartRecording(GpuStartRecordingCommand),
+    StopRecording(GpuStopRecordingCommand),
 }
 
 #[cfg(feature = "gpu")]
@@ -602,6 +1117,25 @@ pub struct GpuRemoveDisplaysCommand {
     pub socket_path: String,
 }
 
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Save the contents of a display attached to the GPU device to a PNG file.
+#[argh(subcommand, name = "screenshot")]
+pub struct GpuScreenshotCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(switch)]
+    /// write raw RGBA8888 pixels instead of a PNG image
+    pub raw: bool,
+    #[argh(positional, arg_name = "OUTPUT")]
+    /// path of the file to write
+    pub output: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[cfg(feature = "gpu")]
 #[derive(FromArgs)]
 /// Sets the mouse mode of a display attached to the GPU device.
@@ -618,6 +1152,36 @@ pub struct GpuSetDisplayMouseModeCommand {
     pub socket_path: String,
 }
 
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Record the frames shown on a display to a video file.
+#[argh(subcommand, name = "start-recording")]
+pub struct GpuStartRecordingCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(positional, arg_name = "OUTPUT")]
+    /// path of the video file: .y4m, or any container supported by ffmpeg (e.g. .mkv) if crosvm
+    /// was built with the ffmpeg feature
+    pub output: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Stop recording a display.
+#[argh(subcommand, name = "stop-recording")]
+pub struct GpuStopRecordingCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 pub enum UsbSubCommand {
@@ -1000,7 +1564,10 @@ pub struct RunCommand {
     /// parameters for setting up a block device.
     /// Valid keys:
     ///     path=PATH - Path to the disk image. Can be specified
-    ///         without the key as the first argument.
+    ///         without the key as the first argument. An NBD
+    ///         export can be used instead with a URI of the form
+    ///         nbd://HOST[:PORT][/EXPORT] or
+    ///         nbd+unix:///[EXPORT]?socket=PATH.
     ///     ro=BOOL - Whether the block should be read-only.
     ///         (default: false)
     ///     root=BOOL - Whether the block device should be mounted
@@ -1032,6 +1599,16 @@ pub struct RunCommand {
     ///         after failing to boot from the device with
     ///         bootindex=1.
     ///     pci-address=ADDR - Preferred PCI address, e.g. "00:01.0".
+    ///     throttle=[key=value,...] - I/O limits of the device,
+    ///         unlimited if not set. Valid keys:
+    ///         read-iops=NUM, write-iops=NUM - Maximum read and
+    ///             write requests per second.
+    ///         read-bps=NUM, write-bps=NUM - Maximum bytes read
+    ///             and written per second.
+    ///         read-iops-burst=NUM, write-iops-burst=NUM,
+    ///         read-bps-burst=NUM, write-bps-burst=NUM - Amount
+    ///             that can be used at once after the device has
+    ///             been idle. (default: the matching limit)
     block: Vec<DiskOptionWithId>,
 
     #[cfg(target_arch = "x86_64")]
@@ -1369,6 +1946,10 @@ pub struct RunCommand {
     ///     vertical-dpi=INT - The vertical DPI of the display
     ///        (default: 320)
     ///        Deprecated - use `dpi` instead.
+    ///     record=PATH - Record the frames shown on the display
+    ///        to a video file: uncompressed for a .y4m file, or
+    ///        encoded with FFV1 in the container matching the
+    ///        extension (e.g. .mkv) with the ffmpeg feature.
     pub gpu: Vec<FixedGpuParameters>,
 
     #[cfg(all(unix, feature = "gpu"))]
@@ -1459,12 +2040,14 @@ pub struct RunCommand {
     /// TYPE is an input device type, and OPTIONS are key=value
     /// pairs specific to the device type:
     ///     evdev[path=PATH]
+    ///     gamepad[path=PATH,axis-min=N,axis-max=N,trigger-max=N,name=N]
     ///     keyboard[path=PATH]
     ///     mouse[path=PATH]
     ///     multi-touch[path=PATH,width=W,height=H,name=N]
     ///     rotary[path=PATH]
     ///     single-touch[path=PATH,width=W,height=H,name=N]
     ///     switches[path=PATH]
+    ///     tablet[path=PATH,width=W,height=H,name=N]
     ///     trackpad[path=PATH,width=W,height=H,name=N]
     /// See <https://crosvm.dev/book/devices/input.html> for more
     /// information.
@@ -1578,7 +2161,7 @@ pub struct RunCommand {
     #[cfg(all(unix, feature = "net"))]
     #[argh(
         option,
-        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS),vhost-net=VHOST_NET,vq-pairs=N,pci-address=ADDR"
+        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS|unix-seqpacket=PATH,mac=MAC_ADDRESS|unix-stream=PATH,mac=MAC_ADDRESS|dgram-local=PATH,dgram-remote=PATH,mac=MAC_ADDRESS|mcast=ADDR:PORT,mac=MAC_ADDRESS|slirp,host-fwd=[RULE,...],mac=MAC_ADDRESS),vhost-net=VHOST_NET,vq-pairs=N,pci-address=ADDR,pcap=PATH,rss,rate-limit=[key=value,...]"
     )]
     #[serde(default)]
     #[merge(strategy = append)]
@@ -1602,6 +2185,41 @@ pub struct RunCommand {
     ///       AND
     ///         mac=STRING      - MAC address for VM.
     ///      )
+    ///    OR
+    ///      unix-seqpacket=PATH - Exchange frames with the
+    ///                          SOCK_SEQPACKET unix socket
+    ///                          listening at PATH.
+    ///      mac=STRING      - MAC address for VM. [Optional]
+    ///    OR
+    ///      unix-stream=PATH - Exchange length-prefixed frames
+    ///                          with the SOCK_STREAM unix socket
+    ///                          listening at PATH (passt, QEMU
+    ///                          -netdev stream).
+    ///      mac=STRING      - MAC address for VM. [Optional]
+    ///    OR
+    ///      (
+    ///         dgram-local=PATH  - Unix datagram socket to
+    ///                               receive frames on.
+    ///       AND
+    ///         dgram-remote=PATH - Unix datagram socket to
+    ///                               send frames to.
+    ///       AND
+    ///         mac=STRING        - MAC address for VM. [Optional]
+    ///      )
+    ///    OR
+    ///      mcast=ADDR:PORT - Exchange frames with all the VMs
+    ///                          joining the same UDP multicast
+    ///                          group, e.g. 230.0.0.1:1234.
+    ///      mac=STRING      - MAC address for VM. [Optional]
+    ///    OR
+    ///      slirp           - User-mode networking through
+    ///                          libslirp (NAT, DHCP and DNS on
+    ///                          10.0.2.0/24). Needs no privilege.
+    ///      host-fwd=[RULE,...] - Forward host ports to the guest,
+    ///                          RULE being
+    ///                          tcp|udp:[HOSTADDR]:HOSTPORT-[GUESTADDR]:GUESTPORT
+    ///                          e.g. tcp::2222-:22. [Optional]
+    ///      mac=STRING      - MAC address for VM. [Optional]
     ///   )
     /// AND
     ///   vhost-net
@@ -1619,9 +2237,36 @@ pub struct RunCommand {
     ///                       Default: false.  [Optional]
     ///   pci-address     - preferred PCI address, e.g. "00:01.0"
     ///                       Default: automatic PCI address assignment. [Optional]
+    ///   pcap=PATH       - capture the frames going through the
+    ///                       device to the pcapng file at PATH.
+    ///                       Not supported with vhost-net.
+    ///                       [Optional]
+    ///   rss             - steer the received frames to the
+    ///                       queue pairs in software, as
+    ///                       configured by the guest (RSS).
+    ///                       The TAP is used with a single queue.
+    ///                       Only supported with TAP devices.
+    ///                       Default: false.  [Optional]
+    ///   rate-limit=[key=value,...] - limits of the frames
+    ///                       received and transmitted by the
+    ///                       guest, unlimited if not set. Not
+    ///                       supported with vhost-net. Valid
+    ///                       keys:
+    ///       rx-bps=NUM, tx-bps=NUM - Maximum bytes received
+    ///           and transmitted per second.
+    ///       rx-pps=NUM, tx-pps=NUM - Maximum frames received
+    ///           and transmitted per second.
+    ///       rx-bps-burst=NUM, tx-bps-burst=NUM,
+    ///       rx-pps-burst=NUM, tx-pps-burst=NUM - Amount that
+    ///           can go through at once after the device has
+    ///           been idle. (default: the matching limit)
+    ///                       [Optional]
     ///
-    /// Either one tap_name, one tap_fd or a triplet of host_ip,
-    /// netmask and mac must be specified.
+    /// Either one tap_name, one tap_fd, a triplet of host_ip,
+    /// netmask and mac, one socket (unix-seqpacket,
+    /// unix-stream, the dgram-local and dgram-remote pair, or
+    /// mcast), or slirp
+    /// must be specified.
     pub net: Vec<NetParameters>,
 
     #[cfg(all(unix, feature = "net"))]
@@ -1950,6 +2595,15 @@ pub struct RunCommand {
     ///         as the root filesystem. This will add the required
     ///         parameters to the kernel command-line. Can only be
     ///         specified once. (default: false)
+    ///     cdrom=BOOL - Expose the image as a read-only CD-ROM
+    ///         drive with 2048-byte sectors. The medium can be
+    ///         changed with `crosvm scsi`. block_size is ignored.
+    ///         (default: false)
+    ///     shared=BOOL - Share the raw disk image with other crosvm
+    ///         instances, including its persistent reservations.
+    ///         (default: false)
+    ///     initiator-id=ID - Identifies this instance among those
+    ///         sharing the disk image. Required with shared.
     // TODO(b/300580119): Add O_DIRECT and sparse file support.
     scsi_block: Vec<ScsiOption>,
 
@@ -2473,6 +3127,17 @@ pub struct RunCommand {
     ///         per device.
     pub virtio_snd: Vec<SndParameters>,
 
+    #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "gpu"))]
+    #[argh(option, arg_name = "ADDR")]
+    #[serde(skip)]
+    #[merge(strategy = overwrite_option)]
+    /// serve the GPU displays to VNC clients. ADDR is either
+    ///     IP:PORT, for the first display to listen on, the next
+    ///     displays using the following ports, or the path of a
+    ///     unix socket, the next displays using the same path with
+    ///     a .N suffix, N being the index of the display.
+    pub vnc_display: Option<GpuVncAddress>,
+
     #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE]")]
     #[serde(default)]
     #[merge(strategy = overwrite_option)]
@@ -2860,6 +3525,11 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.x_display = cmd.x_display;
         }
 
+        #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "gpu"))]
+        {
+            cfg.vnc_display = cmd.vnc_display;
+        }
+
         cfg.display_window_keyboard = cmd.display_window_keyboard.unwrap_or_default();
         cfg.display_window_mouse = cmd.display_window_mouse.unwrap_or_default();
 
@@ -3186,6 +3856,9 @@ impl TryFrom<RunCommand> for super::config::Config {
                     vq_pairs: cmd.net_vq_pairs,
                     packed_queue: false,
                     pci_address: None,
+                    pcap: None,
+                    rss: false,
+                    rate_limit: Default::default(),
                 });
             }
 
@@ -3200,6 +3873,9 @@ impl TryFrom<RunCommand> for super::config::Config {
                     vq_pairs: cmd.net_vq_pairs,
                     packed_queue: false,
                     pci_address: None,
+                    pcap: None,
+                    rss: false,
+                    rate_limit: Default::default(),
                 });
             }
 
@@ -3239,6 +3915,9 @@ impl TryFrom<RunCommand> for super::config::Config {
                     vq_pairs: cmd.net_vq_pairs,
                     packed_queue: false,
                     pci_address: None,
+                    pcap: None,
+                    rss: false,
+                    rate_limit: Default::default(),
                 });
             }
 
@@ -26,6 +26,8 @@ use devices::virtio::block::DiskOption;
 use devices::virtio::device_constants::video::VideoDeviceConfig;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::GpuParameters;
+#[cfg(all(any(target_os = "android", target_os = "linux"), feature = "gpu"))]
+use devices::virtio::gpu::GpuVncAddress;
 use devices::virtio::scsi::ScsiOption;
 #[cfg(feature = "audio")]
 use devices::virtio::snd::parameters::Parameters as SndParameters;
@@ -230,6 +232,9 @@ pub fn parse_vhost_user_fs_option(param: &str) -> Result<VhostUserFsOption, Stri
 
 pub const DEFAULT_TOUCH_DEVICE_HEIGHT: u32 = 1024;
 pub const DEFAULT_TOUCH_DEVICE_WIDTH: u32 = 1280;
+pub const DEFAULT_GAMEPAD_AXIS_MIN: i32 = -32768;
+pub const DEFAULT_GAMEPAD_AXIS_MAX: i32 = 32767;
+pub const DEFAULT_GAMEPAD_TRIGGER_MAX: i32 = 255;
 
 #[derive(Serialize, Deserialize, Debug, FromKeyValues)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
@@ -295,6 +300,16 @@ pub enum InputDeviceOption {
     Evdev {
         path: PathBuf,
     },
+    Gamepad {
+        path: PathBuf,
+        #[serde(rename = "axis-min")]
+        axis_min: Option<i32>,
+        #[serde(rename = "axis-max")]
+        axis_max: Option<i32>,
+        #[serde(rename = "trigger-max")]
+        trigger_max: Option<i32>,
+        name: Option<String>,
+    },
     Keyboard {
         path: PathBuf,
     },
@@ -319,6 +334,12 @@ pub enum InputDeviceOption {
     Switches {
         path: PathBuf,
     },
+    Tablet {
+        path: PathBuf,
+        width: Option<u32>,
+        height: Option<u32>,
+        name: Option<String>,
+    },
     Trackpad {
         path: PathBuf,
         width: Option<u32>,
@@ -489,6 +510,41 @@ pub fn parse_bus_id_addr(v: &str) -> Result<(u8, u8, u16, u16), String> {
     }
 }
 
+/// Parses keys pressed together, such as "leftctrl+c", into their key codes.
+pub fn parse_input_keys(v: &str) -> Result<Vec<u16>, String> {
+    v.split('+')
+        .map(|name| {
+            vm_control::input::key_code(name).ok_or_else(|| format!("unknown key {:?}", name))
+        })
+        .collect()
+}
+
+/// Parses the name of a mouse button, such as "left" or "btn_right", into its key code.
+pub fn parse_input_button(v: &str) -> Result<u16, String> {
+    let name = v.to_ascii_lowercase();
+    let name = name.strip_prefix("btn_").unwrap_or(&name);
+    vm_control::input::key_code(&format!("btn_{}", name))
+        .ok_or_else(|| format!("unknown button {:?}", v))
+}
+
+/// Parses the positions of the fingers touching a device, given as `X,Y` pairs separated by `:`.
+/// An empty string means that no finger touches the device.
+pub fn parse_touch_frame(v: &str) -> Result<Vec<(u32, u32)>, String> {
+    if v.is_empty() {
+        return Ok(Vec::new());
+    }
+    v.split(':')
+        .map(|contact| {
+            let (x, y) = contact
+                .split_once(',')
+                .ok_or_else(|| format!("invalid touch contact {:?}, expected X,Y", contact))?;
+            let x = x.parse::<u32>().map_err(|e| format!("x: {}", e))?;
+            let y = y.parse::<u32>().map_err(|e| format!("y: {}", e))?;
+            Ok((x, y))
+        })
+        .collect()
+}
+
 pub fn invalid_value_err<T: AsRef<str>, S: ToString>(value: T, expected: S) -> String {
     format!("invalid value {}: {}", value.as_ref(), expected.to_string())
 }
@@ -819,6 +875,8 @@ pub struct Config {
     #[cfg(feature = "audio")]
     #[serde(skip)]
     pub virtio_snds: Vec<SndParameters>,
+    #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "gpu"))]
+    pub vnc_display: Option<GpuVncAddress>,
     pub vsock: Option<VsockConfig>,
     #[cfg(feature = "vtpm")]
     pub vtpm_proxy: bool,
@@ -1020,6 +1078,8 @@ impl Default for Config {
             virtio_input: Vec::new(),
             #[cfg(feature = "audio")]
             virtio_snds: Vec::new(),
+            #[cfg(all(any(target_os = "android", target_os = "linux"), feature = "gpu"))]
+            vnc_display: None,
             #[cfg(feature = "vtpm")]
             vtpm_proxy: false,
             wayland_socket_paths: BTreeMap::new(),
@@ -1193,6 +1253,33 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         return Err("'swap' and 'disable-sandbox' are mutually exclusive".to_string());
     }
 
+    for input in &cfg.virtio_input {
+        if let InputDeviceOption::Gamepad {
+            axis_min,
+            axis_max,
+            trigger_max,
+            ..
+        } = input
+        {
+            let axis_min = axis_min.unwrap_or(DEFAULT_GAMEPAD_AXIS_MIN);
+            let axis_max = axis_max.unwrap_or(DEFAULT_GAMEPAD_AXIS_MAX);
+            if axis_min >= axis_max {
+                return Err(format!(
+                    "gamepad `axis-min` ({}) must be lower than `axis-max` ({})",
+                    axis_min, axis_max
+                ));
+            }
+            if let Some(trigger_max) = *trigger_max {
+                if trigger_max <= 0 {
+                    return Err(format!(
+                        "gamepad `trigger-max` ({}) must be greater than 0",
+                        trigger_max
+                    ));
+                }
+            }
+        }
+    }
+
     set_default_serial_parameters(
         &mut cfg.serial_parameters,
         cfg.vhost_user
@@ -1399,6 +1486,33 @@ mod tests {
         CpuSet::from_str("0,1,2,").expect_err("parse should have failed");
     }
 
+    #[test]
+    fn parse_input_keys_combination() {
+        assert_eq!(parse_input_keys("a"), Ok(vec![30]));
+        assert_eq!(parse_input_keys("LeftCtrl+KEY_C"), Ok(vec![29, 46]));
+        parse_input_keys("leftctrl+").expect_err("parse should have failed");
+        parse_input_keys("nosuchkey").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_input_button_names() {
+        assert_eq!(parse_input_button("left"), Ok(0x110));
+        assert_eq!(parse_input_button("BTN_RIGHT"), Ok(0x111));
+        parse_input_button("up").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_touch_frames() {
+        assert_eq!(parse_touch_frame(""), Ok(Vec::new()));
+        assert_eq!(parse_touch_frame("10,20"), Ok(vec![(10, 20)]));
+        assert_eq!(
+            parse_touch_frame("10,20:30,40"),
+            Ok(vec![(10, 20), (30, 40)])
+        );
+        parse_touch_frame("10").expect_err("parse should have failed");
+        parse_touch_frame("10,-1").expect_err("parse should have failed");
+    }
+
     #[test]
     fn parse_cpu_affinity_global() {
         assert_eq!(
@@ -2252,4 +2366,92 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn virtio_tablet() {
+        let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &[
+                "--input",
+                "tablet[path=/dev/tablet-test,width=1920,height=1080]",
+                "/dev/null",
+            ],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+        assert_eq!(
+            config.virtio_input,
+            vec![InputDeviceOption::Tablet {
+                path: PathBuf::from("/dev/tablet-test"),
+                width: Some(1920),
+                height: Some(1080),
+                name: None
+            }]
+        );
+    }
+
+    #[test]
+    fn virtio_gamepad() {
+        let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &[
+                "--input",
+                "gamepad[path=/dev/gamepad-test,axis-min=-512,axis-max=511,trigger-max=1023]",
+                "/dev/null",
+            ],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+        assert_eq!(
+            config.virtio_input,
+            vec![InputDeviceOption::Gamepad {
+                path: PathBuf::from("/dev/gamepad-test"),
+                axis_min: Some(-512),
+                axis_max: Some(511),
+                trigger_max: Some(1023),
+                name: None
+            }]
+        );
+    }
+
+    #[test]
+    fn virtio_gamepad_invalid_axis_range() {
+        assert!(TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &[
+                    "--input",
+                    "gamepad[path=/dev/gamepad-test,axis-min=100,axis-max=-100]",
+                    "/dev/null",
+                ],
+            )
+            .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn virtio_gamepad_invalid_trigger_max() {
+        for trigger_max in ["0", "-1"] {
+            assert!(TryInto::<Config>::try_into(
+                crate::crosvm::cmdline::RunCommand::from_args(
+                    &[],
+                    &[
+                        "--input",
+                        &format!(
+                            "gamepad[path=/dev/gamepad-test,trigger-max={}]",
+                            trigger_max
+                        ),
+                        "/dev/null",
+                    ],
+                )
+                .unwrap()
+            )
+            .is_err());
+        }
+    }
 }
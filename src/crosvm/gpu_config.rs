@@ -477,6 +477,18 @@ mod tests {
         assert!(parse_gpu_display_options("refresh-rate=30,refresh-rate=60").is_err());
     }
 
+    #[test]
+    fn parse_gpu_display_options_record() {
+        let display_params = parse_gpu_display_options("record=/tmp/display.y4m").unwrap();
+        assert_eq!(
+            display_params.record,
+            Some(std::path::PathBuf::from("/tmp/display.y4m"))
+        );
+
+        let display_params = parse_gpu_display_options("hidden").unwrap();
+        assert_eq!(display_params.record, None);
+    }
+
     #[test]
     fn parse_gpu_display_options_dpi() {
         const HORIZONTAL_DPI: u32 = 160;
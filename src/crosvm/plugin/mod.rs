@@ -604,6 +604,16 @@ pub fn run_config(cfg: Config) -> Result<()> {
                 }
                 tap_interfaces.push(tap);
             }
+            NetParametersMode::UnixSeqpacket { .. }
+            | NetParametersMode::UnixStream { .. }
+            | NetParametersMode::UnixDgram { .. }
+            | NetParametersMode::Mcast { .. } => {
+                bail!("socket networking not supported with plugin");
+            }
+            #[cfg(feature = "slirp")]
+            NetParametersMode::Slirp { .. } => {
+                bail!("slirp not supported with plugin");
+            }
         }
     }
 
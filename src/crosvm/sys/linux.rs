@@ -191,6 +191,9 @@ use crate::crosvm::config::FileBackedMappingParameters;
 use crate::crosvm::config::HypervisorKind;
 use crate::crosvm::config::InputDeviceOption;
 use crate::crosvm::config::IrqChipKind;
+use crate::crosvm::config::DEFAULT_GAMEPAD_AXIS_MAX;
+use crate::crosvm::config::DEFAULT_GAMEPAD_AXIS_MIN;
+use crate::crosvm::config::DEFAULT_GAMEPAD_TRIGGER_MAX;
 use crate::crosvm::config::DEFAULT_TOUCH_DEVICE_HEIGHT;
 use crate::crosvm::config::DEFAULT_TOUCH_DEVICE_WIDTH;
 #[cfg(feature = "gdb")]
@@ -219,6 +222,9 @@ fn create_virtio_devices(
     #[cfg(feature = "balloon")] balloon_inflate_tube: Option<Tube>,
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
+    scsi_device_tube: Option<Tube>,
+    #[cfg_attr(not(feature = "net"), allow(unused_variables))] net_device_tubes: &mut Vec<Tube>,
+    input_injector_channels: &mut Vec<StreamChannel>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
@@ -378,7 +384,7 @@ fn create_virtio_devices(
     }
 
     if !cfg.scsis.is_empty() {
-        let scsi_config = ScsiConfig(&cfg.scsis);
+        let scsi_config = ScsiConfig::new(&cfg.scsis, scsi_device_tube);
         devs.push(
             scsi_config.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?,
         );
@@ -411,23 +417,51 @@ fn create_virtio_devices(
         }
     }
 
+    let mut gamepad_idx = 0;
     let mut keyboard_idx = 0;
     let mut mouse_idx = 0;
     let mut rotary_idx = 0;
     let mut switches_idx = 0;
     let mut multi_touch_idx = 0;
     let mut single_touch_idx = 0;
+    let mut tablet_idx = 0;
     let mut trackpad_idx = 0;
     for input in &cfg.virtio_input {
+        let event_injector = input_injector_channels.remove(0);
         let input_dev = match input {
-            InputDeviceOption::Evdev { path } => {
-                create_vinput_device(cfg.protection_type, &cfg.jail_config, path.as_path())?
+            InputDeviceOption::Evdev { path } => create_vinput_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                path.as_path(),
+                event_injector,
+            )?,
+            InputDeviceOption::Gamepad {
+                path,
+                axis_min,
+                axis_max,
+                trigger_max,
+                name,
+            } => {
+                let dev = create_gamepad_device(
+                    cfg.protection_type,
+                    &cfg.jail_config,
+                    path.as_path(),
+                    event_injector,
+                    axis_min.unwrap_or(DEFAULT_GAMEPAD_AXIS_MIN),
+                    axis_max.unwrap_or(DEFAULT_GAMEPAD_AXIS_MAX),
+                    trigger_max.unwrap_or(DEFAULT_GAMEPAD_TRIGGER_MAX),
+                    name.as_deref(),
+                    gamepad_idx,
+                )?;
+                gamepad_idx += 1;
+                dev
             }
             InputDeviceOption::Keyboard { path } => {
                 let dev = create_keyboard_device(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
+                    event_injector,
                     keyboard_idx,
                 )?;
                 keyboard_idx += 1;
@@ -438,6 +472,7 @@ fn create_virtio_devices(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
+                    event_injector,
                     mouse_idx,
                 )?;
                 mouse_idx += 1;
@@ -463,6 +498,7 @@ fn create_virtio_devices(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
+                    event_injector,
                     width.unwrap_or(DEFAULT_TOUCH_DEVICE_WIDTH),
                     height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
                     name.as_deref(),
@@ -476,6 +512,7 @@ fn create_virtio_devices(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
+                    event_injector,
                     rotary_idx,
                 )?;
                 rotary_idx += 1;
@@ -501,6 +538,7 @@ fn create_virtio_devices(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
+                    event_injector,
                     width.unwrap_or(DEFAULT_TOUCH_DEVICE_WIDTH),
                     height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
                     name.as_deref(),
@@ -514,11 +552,41 @@ fn create_virtio_devices(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
+                    event_injector,
                     switches_idx,
                 )?;
                 switches_idx += 1;
                 dev
             }
+            InputDeviceOption::Tablet {
+                path,
+                width,
+                height,
+                name,
+            } => {
+                let mut width = *width;
+                let mut height = *height;
+                if tablet_idx == 0 {
+                    if width.is_none() {
+                        width = cfg.display_input_width;
+                    }
+                    if height.is_none() {
+                        height = cfg.display_input_height;
+                    }
+                }
+                let dev = create_tablet_device(
+                    cfg.protection_type,
+                    &cfg.jail_config,
+                    path.as_path(),
+                    event_injector,
+                    width.unwrap_or(DEFAULT_TOUCH_DEVICE_WIDTH),
+                    height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
+                    name.as_deref(),
+                    tablet_idx,
+                )?;
+                tablet_idx += 1;
+                dev
+            }
             InputDeviceOption::Trackpad {
                 path,
                 width,
@@ -529,6 +597,7 @@ fn create_virtio_devices(
                     cfg.protection_type,
                     &cfg.jail_config,
                     path.as_path(),
+                    event_injector,
                     width.unwrap_or(DEFAULT_TOUCH_DEVICE_WIDTH),
                     height.unwrap_or(DEFAULT_TOUCH_DEVICE_HEIGHT),
                     name.as_deref(),
@@ -570,7 +639,9 @@ fn create_virtio_devices(
 
     #[cfg(feature = "net")]
     for opt in &cfg.net {
-        let dev = opt.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?;
+        let net_config = NetConfig::new(opt, Some(net_device_tubes.remove(0)));
+        let dev =
+            net_config.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?;
         devs.push(dev);
     }
 
@@ -705,6 +776,9 @@ fn create_devices(
     #[cfg(feature = "balloon")] balloon_device_tube: Option<Tube>,
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
+    scsi_device_tube: Option<Tube>,
+    #[cfg_attr(not(feature = "net"), allow(unused_variables))] net_device_tubes: &mut Vec<Tube>,
+    input_injector_channels: &mut Vec<StreamChannel>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
     #[cfg(feature = "usb")] usb_provider: DeviceProvider,
@@ -844,6 +918,9 @@ fn create_devices(
         #[cfg(feature = "balloon")]
         init_balloon_size,
         disk_device_tubes,
+        scsi_device_tube,
+        net_device_tubes,
+        input_injector_channels,
         pmem_device_tubes,
         fs_device_tubes,
         #[cfg(feature = "gpu")]
@@ -1739,6 +1816,39 @@ where
         disk_device_tubes.push(disk_device_tube);
     }
 
+    // The virtio-scsi controller has a single control socket for all of its logical units.
+    let (scsi_host_tube, scsi_device_tube) = if !cfg.scsis.is_empty() {
+        let (host, device) = Tube::pair().context("failed to create tube")?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
+
+    // Create one control socket per network device.
+    let mut net_device_tubes = Vec::new();
+    let mut net_host_tubes = Vec::new();
+    for _ in 0..cfg.net.len() {
+        let (net_host_tube, net_device_tube) = Tube::pair().context("failed to create tube")?;
+        net_host_tubes.push(net_host_tube);
+        net_device_tubes.push(net_device_tube);
+    }
+
+    // Create one channel per virtio-input device to inject synthetic events into it. The host end
+    // is non-blocking so that a device whose guest driver is not loaded cannot block the control
+    // loop.
+    let mut input_injector_channels = Vec::new();
+    let mut input_injector_host_channels = Vec::new();
+    for _ in 0..cfg.virtio_input.len() {
+        let (mut host_channel, device_channel) =
+            StreamChannel::pair(BlockingMode::Blocking, FramingMode::Message)
+                .context("failed to create input injection channel")?;
+        host_channel
+            .set_nonblocking(true)
+            .context("failed to make input injection channel non-blocking")?;
+        input_injector_host_channels.push(host_channel);
+        input_injector_channels.push(device_channel);
+    }
+
     let mut pmem_device_tubes = Vec::new();
     let pmem_count = cfg.pmem_devices.len();
     for _ in 0..pmem_count {
@@ -1857,6 +1967,9 @@ where
         #[cfg(feature = "balloon")]
         init_balloon_size,
         &mut disk_device_tubes,
+        scsi_device_tube,
+        &mut net_device_tubes,
+        &mut input_injector_channels,
         &mut pmem_device_tubes,
         &mut fs_device_tubes,
         #[cfg(feature = "usb")]
@@ -2122,6 +2235,9 @@ where
         #[cfg(feature = "balloon")]
         balloon_host_tube,
         &disk_host_tubes,
+        scsi_host_tube,
+        &net_host_tubes,
+        input_injector_host_channels,
         #[cfg(feature = "gpu")]
         gpu_control_host_tube,
         #[cfg(feature = "usb")]
@@ -2432,6 +2548,11 @@ fn handle_hotplug_net_command<V: VmArch, Vcpu: VcpuArch>(
         NetControlCommand::RemoveTap(bus) => {
             handle_hotplug_net_remove(linux, sys_allocator, hotplug_manager, bus)
         }
+        NetControlCommand::StartCapture { .. }
+        | NetControlCommand::StopCapture
+        | NetControlCommand::SetRateLimit(_) => VmResponse::ErrString(
+            "capture and rate limit commands must target a network device".to_owned(),
+        ),
     }
 }
 
@@ -2455,6 +2576,9 @@ fn handle_hotplug_net_add<V: VmArch, Vcpu: VcpuArch>(
         vq_pairs: None,
         packed_queue: false,
         pci_address: None,
+        pcap: None,
+        rss: false,
+        rate_limit: Default::default(),
     };
     let ret = add_hotplug_net(
         linux,
@@ -2731,6 +2855,9 @@ struct ControlLoopState<'a, V: VmArch, Vcpu: VcpuArch> {
     sys_allocator: &'a Arc<Mutex<SystemAllocator>>,
     control_tubes: &'a BTreeMap<usize, TaggedControlTube>,
     disk_host_tubes: &'a [Tube],
+    scsi_host_tube: Option<&'a Tube>,
+    net_host_tubes: &'a [Tube],
+    input_injector_channels: &'a mut [StreamChannel],
     #[cfg(feature = "gpu")]
     gpu_control_tube: &'a Tube,
     #[cfg(feature = "usb")]
@@ -2801,6 +2928,20 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                 VmResponse::Ok
             }
         }
+        VmRequest::ScsiCommand(command) => match state.scsi_host_tube {
+            Some(tube) => vm_control::handle_scsi_command(&command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
+        VmRequest::NetCommand { net_index, command } => match state.net_host_tubes.get(net_index) {
+            Some(tube) => vm_control::handle_net_command(&command, tube),
+            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+        },
+        VmRequest::InjectInput { device, events } => {
+            match state.input_injector_channels.get_mut(device) {
+                Some(channel) => vm_control::input::handle_inject_input(&events, channel),
+                None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+            }
+        }
         #[cfg(feature = "pci-hotplug")]
         VmRequest::HotPlugNetCommand(net_cmd) => {
             if let Some(hotplug_manager) = state.hotplug_manager.as_mut() {
@@ -2876,7 +3017,7 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             }
         }
         _ => {
-            let response = request.execute(
+            let mut response = request.execute(
                 &mut run_mode_opt,
                 state.disk_host_tubes,
                 &mut state.linux.pm,
@@ -2928,7 +3069,9 @@ fn process_vm_request<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                     let send_tube = tube.try_clone_send_tube().unwrap();
                     let suspend_evt = state.linux.suspend_evt.try_clone().unwrap();
                     let guest_suspended_cvar = state.guest_suspended_cvar.clone();
-                    let delayed_response = response.clone();
+                    // The s2idle_wait thread sends the response once the guest has suspended, so
+                    // the one returned from here is never sent.
+                    let delayed_response = std::mem::replace(&mut response, VmResponse::Ok);
                     let pm = state.linux.pm.clone();
 
                     std::thread::Builder::new()
@@ -3154,6 +3297,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     control_tubes: Vec<TaggedControlTube>,
     #[cfg(feature = "balloon")] balloon_host_tube: Option<Tube>,
     disk_host_tubes: &[Tube],
+    scsi_host_tube: Option<Tube>,
+    net_host_tubes: &[Tube],
+    mut input_injector_channels: Vec<StreamChannel>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "usb")] usb_control_tube: Tube,
     vm_evt_rdtube: RecvTube,
@@ -3683,6 +3829,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                             sys_allocator: &sys_allocator_mutex,
                             control_tubes: &control_tubes,
                             disk_host_tubes,
+                            scsi_host_tube: scsi_host_tube.as_ref(),
+                            net_host_tubes,
+                            input_injector_channels: &mut input_injector_channels,
                             #[cfg(feature = "gpu")]
                             gpu_control_tube: &gpu_control_tube,
                             #[cfg(feature = "usb")]
@@ -4503,7 +4652,8 @@ pub fn start_devices(opts: DevicesCommand) -> anyhow::Result<()> {
     // Create network devices.
     #[cfg(feature = "net")]
     for (i, params) in opts.net.iter().enumerate() {
-        add_device(i, &params.device, &params.vhost, &jail, &mut devices_jails)?;
+        let net_config = NetConfig::new(&params.device, None);
+        add_device(i, net_config, &params.vhost, &jail, &mut devices_jails)?;
     }
 
     // No device created, that's probably not intended - print the help in that case.
@@ -4,6 +4,7 @@
 
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::fs::File;
 use std::fs::OpenOptions;
 use std::ops::RangeInclusive;
 use std::os::unix::net::UnixStream;
@@ -72,16 +73,22 @@ use hypervisor::Vm;
 use jail::*;
 use minijail::Minijail;
 #[cfg(feature = "net")]
+use net_util::sys::linux::FrameSocket;
+#[cfg(feature = "net")]
 use net_util::sys::linux::Tap;
 #[cfg(feature = "net")]
 use net_util::MacAddress;
 #[cfg(feature = "net")]
+use net_util::TapT;
+#[cfg(feature = "net")]
 use net_util::TapTCommon;
 use resources::Alloc;
 use resources::AllocOptions;
 use resources::SystemAllocator;
 use sync::Mutex;
 use vm_control::api::VmMemoryClient;
+#[cfg(feature = "net")]
+use vm_control::NetRateLimitConfig;
 use vm_memory::GuestAddress;
 
 use crate::crosvm::config::VhostUserFrontendOption;
@@ -290,11 +297,66 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
 
         Ok(block)
     }
+
+    fn create_jail(
+        &self,
+        jail_config: &Option<JailConfig>,
+        virtio_transport: VirtioDeviceType,
+    ) -> anyhow::Result<Option<Minijail>> {
+        #[cfg(feature = "nbd")]
+        if let (Some(uri), Some(jail_config)) = (
+            self.disk.path.to_str().filter(|path| disk::is_nbd_uri(path)),
+            jail_config,
+        ) {
+            // The device reconnects to the NBD server from the jail when the connection drops, which
+            // its seccomp policy allows.
+            let uri = disk::NbdUri::parse(uri)?;
+            let mut config = SandboxConfig::new(
+                jail_config,
+                &virtio_transport.seccomp_policy_file("block_nbd"),
+            );
+            match uri.socket_path() {
+                // The directory of the socket is mounted at the same path in the jail.
+                Some(path) if path.is_relative() => {
+                    bail!(
+                        "the NBD socket path {} must be absolute when sandboxed",
+                        path.display()
+                    )
+                }
+                Some(_) => config.bind_mounts = true,
+                None => config.namespace_net = false,
+            }
+            let mut jail =
+                create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+            if let Some(dir) = uri.socket_path().and_then(Path::parent) {
+                jail.mount_bind(dir, dir, true)?;
+            }
+            return Ok(Some(jail));
+        }
+
+        simple_jail(
+            jail_config,
+            &virtio_transport.seccomp_policy_file(Self::NAME),
+        )
+    }
+}
+
+/// A one-shot configuration structure for implementing `VirtioDeviceBuilder` for the virtio-scsi
+/// controller, which can be passed an optional control tube.
+pub struct ScsiConfig<'a> {
+    /// Options of the logical units of the controller.
+    scsis: &'a [ScsiOption],
+    /// Optional control tube for the device.
+    device_tube: Option<Tube>,
 }
 
-pub struct ScsiConfig<'a>(pub &'a [ScsiOption]);
+impl<'a> ScsiConfig<'a> {
+    pub fn new(scsis: &'a [ScsiOption], device_tube: Option<Tube>) -> Self {
+        Self { scsis, device_tube }
+    }
+}
 
-impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
+impl<'a> VirtioDeviceBuilder for ScsiConfig<'a> {
     const NAME: &'static str = "scsi";
 
     fn create_virtio_device(
@@ -303,7 +365,7 @@ impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
         let base_features = virtio::base_features(protection_type);
         let disks = self
-            .0
+            .scsis
             .iter()
             .map(|op| {
                 info!("Trying to attach a scsi device: {}", op.path.display());
@@ -312,10 +374,13 @@ impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
                     file,
                     block_size: op.block_size,
                     read_only: op.read_only,
+                    cdrom: op.cdrom,
+                    reservation_file: op.open_reservation_file()?,
+                    initiator_id: op.initiator_id.unwrap_or_default(),
                 })
             })
             .collect::<anyhow::Result<_>>()?;
-        let controller = virtio::ScsiController::new(base_features, disks)
+        let controller = virtio::ScsiController::new(base_features, disks, self.device_tube)
             .context("failed to create a scsi controller")?;
         Ok(Box::new(controller))
     }
@@ -462,6 +527,7 @@ pub fn create_single_touch_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     single_touch_socket: T,
+    event_injector: StreamChannel,
     width: u32,
     height: u32,
     name: Option<&str>,
@@ -471,7 +537,7 @@ pub fn create_single_touch_device<T: IntoUnixStream>(
         .into_unix_stream()
         .context("failed configuring virtio single touch")?;
 
-    let dev = virtio::input::new_single_touch(
+    let mut dev = virtio::input::new_single_touch(
         idx,
         socket,
         width,
@@ -480,6 +546,7 @@ pub fn create_single_touch_device<T: IntoUnixStream>(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
         jail: simple_jail(jail_config, "input_device")?,
@@ -490,6 +557,7 @@ pub fn create_multi_touch_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     multi_touch_socket: T,
+    event_injector: StreamChannel,
     width: u32,
     height: u32,
     name: Option<&str>,
@@ -499,7 +567,7 @@ pub fn create_multi_touch_device<T: IntoUnixStream>(
         .into_unix_stream()
         .context("failed configuring virtio multi touch")?;
 
-    let dev = virtio::input::new_multi_touch(
+    let mut dev = virtio::input::new_multi_touch(
         idx,
         socket,
         width,
@@ -508,6 +576,7 @@ pub fn create_multi_touch_device<T: IntoUnixStream>(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -519,6 +588,7 @@ pub fn create_trackpad_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     trackpad_socket: T,
+    event_injector: StreamChannel,
     width: u32,
     height: u32,
     name: Option<&str>,
@@ -528,7 +598,38 @@ pub fn create_trackpad_device<T: IntoUnixStream>(
         .into_unix_stream()
         .context("failed configuring virtio trackpad")?;
 
-    let dev = virtio::input::new_trackpad(
+    let mut dev = virtio::input::new_trackpad(
+        idx,
+        socket,
+        width,
+        height,
+        name,
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "input_device")?,
+    })
+}
+
+pub fn create_tablet_device<T: IntoUnixStream>(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    tablet_socket: T,
+    event_injector: StreamChannel,
+    width: u32,
+    height: u32,
+    name: Option<&str>,
+    idx: u32,
+) -> DeviceResult {
+    let socket = tablet_socket
+        .into_unix_stream()
+        .context("failed configuring virtio tablet")?;
+
+    let mut dev = virtio::input::new_tablet(
         idx,
         socket,
         width,
@@ -537,6 +638,40 @@ pub fn create_trackpad_device<T: IntoUnixStream>(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "input_device")?,
+    })
+}
+
+pub fn create_gamepad_device<T: IntoUnixStream>(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    gamepad_socket: T,
+    event_injector: StreamChannel,
+    axis_min: i32,
+    axis_max: i32,
+    trigger_max: i32,
+    name: Option<&str>,
+    idx: u32,
+) -> DeviceResult {
+    let socket = gamepad_socket
+        .into_unix_stream()
+        .context("failed configuring virtio gamepad")?;
+
+    let mut dev = virtio::input::new_gamepad(
+        idx,
+        socket,
+        axis_min,
+        axis_max,
+        trigger_max,
+        name,
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -548,14 +683,16 @@ pub fn create_mouse_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     mouse_socket: T,
+    event_injector: StreamChannel,
     idx: u32,
 ) -> DeviceResult {
     let socket = mouse_socket
         .into_unix_stream()
         .context("failed configuring virtio mouse")?;
 
-    let dev = virtio::input::new_mouse(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_mouse(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -567,14 +704,16 @@ pub fn create_keyboard_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     keyboard_socket: T,
+    event_injector: StreamChannel,
     idx: u32,
 ) -> DeviceResult {
     let socket = keyboard_socket
         .into_unix_stream()
         .context("failed configuring virtio keyboard")?;
 
-    let dev = virtio::input::new_keyboard(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_keyboard(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -586,14 +725,16 @@ pub fn create_switches_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     switches_socket: T,
+    event_injector: StreamChannel,
     idx: u32,
 ) -> DeviceResult {
     let socket = switches_socket
         .into_unix_stream()
         .context("failed configuring virtio switches")?;
 
-    let dev = virtio::input::new_switches(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_switches(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -605,14 +746,16 @@ pub fn create_rotary_device<T: IntoUnixStream>(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     rotary_socket: T,
+    event_injector: StreamChannel,
     idx: u32,
 ) -> DeviceResult {
     let socket = rotary_socket
         .into_unix_stream()
         .context("failed configuring virtio rotary")?;
 
-    let dev = virtio::input::new_rotary(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_rotary(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -624,6 +767,7 @@ pub fn create_vinput_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     dev_path: &Path,
+    event_injector: StreamChannel,
 ) -> DeviceResult {
     let dev_file = OpenOptions::new()
         .read(true)
@@ -631,8 +775,9 @@ pub fn create_vinput_device(
         .open(dev_path)
         .with_context(|| format!("failed to open vinput device {}", dev_path.display()))?;
 
-    let dev = virtio::input::new_evdev(dev_file, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_evdev(dev_file, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_event_injector(event_injector);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -671,45 +816,110 @@ pub fn create_balloon_device(
     })
 }
 
+/// A one-shot configuration structure for implementing `VirtioDeviceBuilder` for virtio-net
+/// devices, which can be passed an optional control tube.
 #[cfg(feature = "net")]
-impl VirtioDeviceBuilder for &NetParameters {
+pub struct NetConfig<'a> {
+    /// Options for the network device.
+    net: &'a NetParameters,
+    /// Optional control tube for the device.
+    device_tube: Option<Tube>,
+}
+
+#[cfg(feature = "net")]
+impl<'a> NetConfig<'a> {
+    pub fn new(net: &'a NetParameters, device_tube: Option<Tube>) -> Self {
+        Self { net, device_tube }
+    }
+
+    /// Hands the control tube to a freshly created device, and applies the rate limits and starts
+    /// the capture requested on the command line, if any.
+    fn finish_net_device<T: 'static + TapT + ReadNotifier>(
+        self,
+        mut dev: virtio::Net<T>,
+    ) -> anyhow::Result<Box<dyn VirtioDevice>> {
+        if let Some(device_tube) = self.device_tube {
+            dev.set_control_tube(device_tube);
+        }
+        dev.set_rate_limit(self.net.rate_limit)
+            .context("failed to set the rate limits")?;
+        if let Some(pcap) = &self.net.pcap {
+            let file = File::create(pcap)
+                .with_context(|| format!("failed to create capture file {}", pcap.display()))?;
+            dev.start_capture(file)
+                .context("failed to start capturing frames")?;
+        }
+        Ok(Box::new(dev))
+    }
+}
+
+#[cfg(feature = "net")]
+impl<'a> VirtioDeviceBuilder for NetConfig<'a> {
     const NAME: &'static str = "net";
 
     fn create_virtio_device(
         self,
         protection_type: ProtectionType,
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
-        let vq_pairs = self.vq_pairs.unwrap_or(1);
-        let multi_vq = vq_pairs > 1 && self.vhost_net.is_none();
+        let vq_pairs = self.net.vq_pairs.unwrap_or(1);
+        // With software RSS, the frames are steered by the device and the tap has a single queue.
+        let multi_vq = vq_pairs > 1 && self.net.vhost_net.is_none() && !self.net.rss;
 
         let features = virtio::base_features(protection_type);
-        let (tap, mac) = create_tap_for_net_device(&self.mode, multi_vq)?;
 
-        Ok(if let Some(vhost_net) = &self.vhost_net {
-            Box::new(
+        if let Some((socket, mac)) = create_socket_for_net_device(&self.net.mode)? {
+            if self.net.vhost_net.is_some() || vq_pairs != 1 || self.net.rss {
+                bail!("socket networking supports neither vhost-net, multiple queue pairs nor rss");
+            }
+            let dev = virtio::Net::new_without_offload(
+                features,
+                socket,
+                mac,
+                self.net.packed_queue,
+                self.net.pci_address,
+            )
+            .context("failed to set up virtio networking")?;
+            return self.finish_net_device(dev);
+        }
+
+        let (tap, mac) = create_tap_for_net_device(&self.net.mode, multi_vq)?;
+
+        if let Some(vhost_net) = &self.net.vhost_net {
+            // Frames never go through crosvm with vhost-net, so there is nothing to capture, steer
+            // or limit.
+            if self.net.pcap.is_some() {
+                bail!("frame capture is not supported with vhost-net");
+            }
+            if self.net.rss {
+                bail!("rss is not supported with vhost-net");
+            }
+            if self.net.rate_limit != NetRateLimitConfig::default() {
+                bail!("rate limits are not supported with vhost-net");
+            }
+            return Ok(Box::new(
                 virtio::vhost::Net::<_, vhost::Net<_>>::new(
                     &vhost_net.device,
                     features,
                     tap,
                     mac,
-                    self.packed_queue,
-                    self.pci_address,
+                    self.net.packed_queue,
+                    self.net.pci_address,
                 )
                 .context("failed to set up virtio-vhost networking")?,
-            ) as Box<dyn VirtioDevice>
-        } else {
-            Box::new(
-                virtio::Net::new(
-                    features,
-                    tap,
-                    vq_pairs,
-                    mac,
-                    self.packed_queue,
-                    self.pci_address,
-                )
-                .context("failed to set up virtio networking")?,
-            ) as Box<dyn VirtioDevice>
-        })
+            ));
+        }
+
+        let dev = virtio::Net::new(
+            features,
+            tap,
+            vq_pairs,
+            mac,
+            self.net.packed_queue,
+            self.net.pci_address,
+            self.net.rss,
+        )
+        .context("failed to set up virtio networking")?;
+        self.finish_net_device(dev)
     }
 
     fn create_jail(
@@ -717,12 +927,28 @@ impl VirtioDeviceBuilder for &NetParameters {
         jail_config: &Option<JailConfig>,
         virtio_transport: VirtioDeviceType,
     ) -> anyhow::Result<Option<Minijail>> {
-        let policy = if self.vhost_net.is_some() {
+        let policy = if self.net.vhost_net.is_some() {
             "vhost_net"
         } else {
             "net"
         };
 
+        if let (NetParametersMode::UnixDgram { dgram_remote, .. }, Some(jail_config)) =
+            (&self.net.mode, jail_config)
+        {
+            // Frames are sent to the path of the remote socket, so its directory must be
+            // reachable from the jail.
+            let mut config =
+                SandboxConfig::new(jail_config, &virtio_transport.seccomp_policy_file(policy));
+            config.bind_mounts = true;
+            let mut jail =
+                create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+            if let Some(dir) = dgram_remote.parent().filter(|d| !d.as_os_str().is_empty()) {
+                jail.mount_bind(dir, dir, true)?;
+            }
+            return Ok(Some(jail));
+        }
+
         simple_jail(jail_config, &virtio_transport.seccomp_policy_file(policy))
     }
 
@@ -730,9 +956,18 @@ impl VirtioDeviceBuilder for &NetParameters {
         self,
         keep_rds: &mut Vec<RawDescriptor>,
     ) -> anyhow::Result<Box<dyn VhostUserDevice>> {
-        let vq_pairs = self.vq_pairs.unwrap_or(1);
-        let multi_vq = vq_pairs > 1 && self.vhost_net.is_none();
-        let (tap, _mac) = create_tap_for_net_device(&self.mode, multi_vq)?;
+        if self.net.pcap.is_some() {
+            bail!("frame capture is not supported by vhost-user network devices");
+        }
+        if self.net.rss {
+            bail!("rss is not supported by vhost-user network devices");
+        }
+        if self.net.rate_limit != NetRateLimitConfig::default() {
+            bail!("rate limits are not supported by vhost-user network devices");
+        }
+        let vq_pairs = self.net.vq_pairs.unwrap_or(1);
+        let multi_vq = vq_pairs > 1 && self.net.vhost_net.is_none();
+        let (tap, _mac) = create_tap_for_net_device(&self.net.mode, multi_vq)?;
 
         let backend = NetBackend::new(tap)?;
 
@@ -740,6 +975,87 @@ impl VirtioDeviceBuilder for &NetParameters {
 
         Ok(Box::new(backend))
     }
+
+    fn create_virtio_device_and_jail(
+        self,
+        protection_type: ProtectionType,
+        jail_config: &Option<JailConfig>,
+    ) -> DeviceResult {
+        #[cfg(feature = "slirp")]
+        if let NetParametersMode::Slirp {
+            slirp,
+            host_fwd,
+            mac,
+        } = &self.net.mode
+        {
+            if !slirp {
+                bail!("slirp=false is not a valid network configuration");
+            }
+            if self.net.vhost_net.is_some() || self.net.vq_pairs.unwrap_or(1) != 1 || self.net.rss {
+                bail!("slirp networking supports neither vhost-net, multiple queue pairs nor rss");
+            }
+            let slirp = start_slirp_process(jail_config, host_fwd)?;
+            let dev = virtio::Net::new_without_offload(
+                virtio::base_features(protection_type),
+                slirp,
+                *mac,
+                self.net.packed_queue,
+                self.net.pci_address,
+            )
+            .context("failed to set up virtio networking")?;
+            let jail = self.create_jail(jail_config, VirtioDeviceType::Regular)?;
+            let dev = self.finish_net_device(dev)?;
+            return Ok(VirtioDeviceStub { dev, jail });
+        }
+
+        let jail = self.create_jail(jail_config, VirtioDeviceType::Regular)?;
+        let dev = self.create_virtio_device(protection_type)?;
+        Ok(VirtioDeviceStub { dev, jail })
+    }
+}
+
+/// Forks a sandboxed process running the libslirp loop and returns the pseudo-tap connected to it.
+///
+/// libslirp opens sockets on behalf of the guest, so the process keeps access to the host's network
+/// namespace (and to /etc/resolv.conf for DNS forwarding). It exits once the device side of the
+/// connection is closed.
+#[cfg(all(feature = "net", feature = "slirp"))]
+fn start_slirp_process(
+    jail_config: &Option<JailConfig>,
+    host_fwds: &[net_util::slirp::HostFwd],
+) -> DeviceResult<net_util::Slirp> {
+    let (slirp, slirp_socket) = net_util::Slirp::new().context("failed to create slirp socket")?;
+
+    let jail = match jail_config {
+        Some(jail_config) => {
+            let mut config = SandboxConfig::new(jail_config, "slirp");
+            config.namespace_net = false;
+            config.bind_mounts = true;
+            let mut jail =
+                create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+            jail_mount_bind_if_exists(&mut jail, &["/etc/resolv.conf"])?;
+            jail
+        }
+        None => create_base_minijail(Path::new("/"), MAX_OPEN_FILES_DEFAULT)?,
+    };
+
+    let mut keep_rds = vec![slirp_socket.as_raw_descriptor()];
+    syslog::push_descriptors(&mut keep_rds);
+    cros_tracing::push_descriptors!(&mut keep_rds);
+
+    let host_fwds = host_fwds.to_vec();
+    let child = base::linux::process::fork_process(
+        jail,
+        keep_rds,
+        Some(String::from("slirp")),
+        move || net_util::Slirp::run_slirp_process(slirp_socket, &host_fwds),
+    )
+    .context("failed to fork slirp process")?;
+    // Like device processes, the slirp process is reaped by `wait_all_children()` when crosvm
+    // exits.
+    child.into_pid();
+
+    Ok(slirp)
 }
 
 /// Create a new tap interface based on NetParametersMode.
@@ -779,9 +1095,58 @@ fn create_tap_for_net_device(
             tap.enable().map_err(NetError::TapEnable)?;
             Ok((tap, None))
         }
+        NetParametersMode::UnixSeqpacket { .. }
+        | NetParametersMode::UnixStream { .. }
+        | NetParametersMode::UnixDgram { .. }
+        | NetParametersMode::Mcast { .. } => {
+            bail!("socket networking is only supported by the regular virtio-net device")
+        }
+        #[cfg(feature = "slirp")]
+        NetParametersMode::Slirp { .. } => {
+            bail!("slirp networking is only supported by the regular virtio-net device")
+        }
     }
 }
 
+/// Opens the socket of a network device exchanging frames over a socket, or returns `None` if
+/// `mode` is not such a device.
+#[cfg(feature = "net")]
+fn create_socket_for_net_device(
+    mode: &NetParametersMode,
+) -> DeviceResult<Option<(FrameSocket, Option<MacAddress>)>> {
+    let socket = match mode {
+        NetParametersMode::UnixSeqpacket {
+            unix_seqpacket,
+            mac,
+        } => (
+            FrameSocket::connect_seqpacket(unix_seqpacket)
+                .with_context(|| format!("failed to connect to {}", unix_seqpacket.display()))?,
+            *mac,
+        ),
+        NetParametersMode::UnixStream { unix_stream, mac } => (
+            FrameSocket::connect_stream(unix_stream)
+                .with_context(|| format!("failed to connect to {}", unix_stream.display()))?,
+            *mac,
+        ),
+        NetParametersMode::UnixDgram {
+            dgram_local,
+            dgram_remote,
+            mac,
+        } => (
+            FrameSocket::unix_dgram(dgram_local, dgram_remote)
+                .with_context(|| format!("failed to bind {}", dgram_local.display()))?,
+            *mac,
+        ),
+        NetParametersMode::Mcast { mcast, mac } => (
+            FrameSocket::multicast(*mcast)
+                .with_context(|| format!("failed to join multicast group {}", mcast))?,
+            *mac,
+        ),
+        _ => return Ok(None),
+    };
+    Ok(Some(socket))
+}
+
 pub fn create_wayland_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
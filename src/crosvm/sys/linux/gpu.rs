@@ -133,6 +133,10 @@ pub fn create_gpu_device(
         );
     }
 
+    if let Some(address) = &cfg.vnc_display {
+        display_backends.insert(0, virtio::DisplayBackend::Vnc(address.clone()));
+    }
+
     let dev = virtio::Gpu::new(
         exit_evt_wrtube
             .try_clone()
@@ -146,11 +150,16 @@ pub fn create_gpu_device(
         virtio::base_features(cfg.protection_type),
         &cfg.wayland_socket_paths,
         cfg.gpu_cgroup_path.as_ref(),
-    );
+    )
+    .context("failed to create the GPU device")?;
 
     let jail = if let Some(jail_config) = &cfg.jail_config {
         let mut config = SandboxConfig::new(jail_config, "gpu_device");
         config.bind_mounts = true;
+        // VNC clients connect over the host's network.
+        if let Some(virtio::GpuVncAddress::Tcp(_)) = &cfg.vnc_display {
+            config.namespace_net = false;
+        }
         // Allow changes made externally take effect immediately to allow shaders to be dynamically
         // added by external processes.
         config.remount_mode = Some(libc::MS_SLAVE);
@@ -191,6 +200,21 @@ pub fn create_gpu_device(
             jail.mount_bind(dir, dir, true)?;
         }
 
+        // The VNC sockets are created next to the one given on the command line, in its directory
+        // mounted at the same path in the jail.
+        if let Some(virtio::GpuVncAddress::Unix(socket_path)) = &cfg.vnc_display {
+            if socket_path.is_relative() {
+                bail!(
+                    "the VNC socket path '{}' must be absolute when sandboxed",
+                    socket_path.display()
+                );
+            }
+            let dir = socket_path.parent().with_context(|| {
+                format!("VNC socket path '{}' has no parent", socket_path.display())
+            })?;
+            jail.mount_bind(dir, dir, true)?;
+        }
+
         Some(jail)
     } else {
         None
@@ -16,6 +16,7 @@ use devices::VirtioPciDevice;
 use hypervisor::ProtectionType;
 use vm_memory::GuestMemory;
 
+use crate::crosvm::sys::linux::NetConfig;
 use crate::crosvm::sys::linux::VirtioDeviceBuilder;
 
 /// Builds HotPlugPci from NetResourceCarrier and NetLocalParameters.
@@ -26,8 +27,7 @@ pub fn build_hotplug_net_device(
     let pci_address = net_carrier_device
         .pci_address
         .context("PCI address not allocated")?;
-    let virtio_device = net_carrier_device
-        .net_param
+    let virtio_device = NetConfig::new(&net_carrier_device.net_param, None)
         .create_virtio_device(net_local_parameters.protection_type)
         .context("create virtio device")?;
     let mut virtio_pci_device = VirtioPciDevice::new(
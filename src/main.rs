@@ -27,6 +27,8 @@ mod crosvm;
 use crosvm::cmdline;
 #[cfg(feature = "plugin")]
 use crosvm::config::executable_is_plugin;
+use crosvm::config::parse_input_button;
+use crosvm::config::parse_input_keys;
 use crosvm::config::Config;
 use devices::virtio::vhost::user::device::run_block_device;
 #[cfg(feature = "gpu")]
@@ -41,6 +43,8 @@ use disk::create_composite_disk;
 use disk::create_disk_file;
 #[cfg(feature = "composite-disk")]
 use disk::create_zero_filler;
+#[cfg(feature = "qcow")]
+use disk::DiskGetLen;
 #[cfg(feature = "composite-disk")]
 use disk::ImagePartitionType;
 #[cfg(feature = "composite-disk")]
@@ -53,6 +57,8 @@ use crosvm::cmdline::CrossPlatformCommands;
 use crosvm::cmdline::CrossPlatformDevicesCommands;
 #[cfg(windows)]
 use sys::windows::setup_metrics_reporting;
+use vm_control::client::do_disk_change_media;
+use vm_control::client::do_disk_commit;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_add;
 #[cfg(feature = "gpu")]
@@ -60,33 +66,45 @@ use vm_control::client::do_gpu_display_list;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_remove;
 #[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_screenshot;
+#[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_set_display_mouse_mode;
+#[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_start_recording;
+#[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_stop_recording;
 use vm_control::client::do_modify_battery;
 #[cfg(feature = "pci-hotplug")]
 use vm_control::client::do_net_add;
 #[cfg(feature = "pci-hotplug")]
 use vm_control::client::do_net_remove;
+use vm_control::client::do_net_start_capture;
+use vm_control::client::do_scsi_add;
+use vm_control::client::do_scsi_change_media;
 use vm_control::client::do_swap_status;
 use vm_control::client::do_usb_attach;
 use vm_control::client::do_usb_detach;
 use vm_control::client::do_usb_list;
-#[cfg(feature = "balloon")]
 use vm_control::client::handle_request;
 use vm_control::client::vms_request;
 #[cfg(feature = "gpu")]
 use vm_control::client::ModifyGpuResult;
 use vm_control::client::ModifyUsbResult;
+use vm_control::input::InputEvent;
 #[cfg(feature = "balloon")]
 use vm_control::BalloonControlCommand;
 use vm_control::DiskControlCommand;
+use vm_control::DiskControlResult;
+use vm_control::DiskSnapshotCommand;
 use vm_control::HotPlugDeviceInfo;
 use vm_control::HotPlugDeviceType;
+use vm_control::NetControlCommand;
 use vm_control::RestoreCommand;
+use vm_control::ScsiControlCommand;
 use vm_control::SnapshotCommand;
 use vm_control::SwapCommand;
 use vm_control::UsbControlResult;
 use vm_control::VmRequest;
-#[cfg(feature = "balloon")]
 use vm_control::VmResponse;
 
 use crate::sys::error_to_exit_code;
@@ -489,18 +507,231 @@ fn create_qcow2(cmd: cmdline::CreateQcow2Command) -> std::result::Result<(), ()>
         (Some(size), None) => QcowFile::new(file, size).map_err(|e| {
             error!("Failed to create qcow file at '{}': {}", cmd.file_path, e);
         })?,
-        (None, Some(backing_file)) => {
-            QcowFile::new_from_backing(file, &backing_file, disk::MAX_NESTING_DEPTH).map_err(
-                |e| {
-                    error!("Failed to create qcow file at '{}': {}", cmd.file_path, e);
-                },
-            )?
-        }
+        (None, Some(backing_file)) => QcowFile::new_from_backing_at(
+            file,
+            Path::new(&cmd.file_path),
+            &backing_file,
+            disk::MAX_NESTING_DEPTH,
+        )
+        .map_err(|e| {
+            error!("Failed to create qcow file at '{}': {}", cmd.file_path, e);
+        })?,
         _ => unreachable!(),
     };
     Ok(())
 }
 
+/// Information about a disk image and its backing chain printed by `crosvm disk info`.
+#[cfg(feature = "qcow")]
+#[derive(serde::Serialize)]
+struct DiskImageInfo {
+    filename: String,
+    format: &'static str,
+    virtual_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cluster_size: Option<u64>,
+    /// Bytes of guest data stored in the image itself rather than in its backing file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allocated_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backing_file: Option<Box<DiskImageInfo>>,
+}
+
+#[cfg(feature = "qcow")]
+impl std::fmt::Display for DiskImageInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "image: {}", self.filename)?;
+        writeln!(f, "file format: {}", self.format)?;
+        write!(f, "virtual size: {}", self.virtual_size)?;
+        if let Some(cluster_size) = self.cluster_size {
+            write!(f, "\ncluster size: {}", cluster_size)?;
+        }
+        if let Some(allocated_size) = self.allocated_size {
+            write!(f, "\nallocated size: {}", allocated_size)?;
+        }
+        if let Some(backing_file) = &self.backing_file {
+            write!(f, "\nbacking file: {}", backing_file.filename)?;
+            // Print the rest of the chain after this image.
+            write!(f, "\n\n{}", backing_file)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "qcow")]
+fn disk_image_info(path: &Path) -> Result<DiskImageInfo> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .with_context(|| format!("failed to open disk image {}", path.display()))?;
+    let image_type = disk::detect_image_type(&file, false)
+        .with_context(|| format!("failed to detect the format of {}", path.display()))?;
+    let format = match image_type {
+        disk::ImageType::Raw => "raw",
+        disk::ImageType::Qcow2 => "qcow2",
+        disk::ImageType::CompositeDisk => "composite",
+        disk::ImageType::AndroidSparse => "android-sparse",
+        disk::ImageType::Vhdx => "vhdx",
+        disk::ImageType::Vmdk => "vmdk",
+    };
+    let mut info = DiskImageInfo {
+        filename: path.display().to_string(),
+        format,
+        virtual_size: 0,
+        cluster_size: None,
+        allocated_size: None,
+        backing_file: None,
+    };
+    if image_type == disk::ImageType::Qcow2 {
+        let mut qcow = QcowFile::from_path(file, path, disk::MAX_NESTING_DEPTH)
+            .with_context(|| format!("failed to open qcow2 image {}", path.display()))?;
+        info.virtual_size = qcow.get_len()?;
+        info.cluster_size = Some(qcow.cluster_size());
+        info.allocated_size = Some(qcow.allocated_clusters()? * qcow.cluster_size());
+        if let Some(backing_file_path) = qcow.backing_file_path() {
+            let backing_path = disk::backing_file_full_path(path, backing_file_path);
+            info.backing_file = Some(Box::new(disk_image_info(&backing_path)?));
+        }
+    } else {
+        let disk = disk::create_disk_file_of_type(
+            file,
+            /* is_sparse_file= */ false,
+            disk::MAX_NESTING_DEPTH,
+            path,
+            image_type,
+        )
+        .with_context(|| format!("failed to open disk image {}", path.display()))?;
+        info.virtual_size = disk.get_len()?;
+    }
+    Ok(info)
+}
+
+#[cfg(feature = "qcow")]
+fn disk_info(cmd: cmdline::InfoDiskSubcommand) -> std::result::Result<(), ()> {
+    let info = disk_image_info(Path::new(&cmd.file_path)).map_err(|e| error!("{:#}", e))?;
+    if cmd.json {
+        let info_json = serde_json::to_string_pretty(&info)
+            .map_err(|e| error!("Failed to serialize into JSON: {}", e))?;
+        println!("{}", info_json);
+    } else {
+        println!("{}", info);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "qcow")]
+fn disk_check(cmd: cmdline::CheckDiskSubcommand) -> std::result::Result<(), ()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(cmd.repair)
+        .open(&cmd.file_path)
+        .map_err(|e| {
+            error!("Failed opening qcow file at '{}': {}", cmd.file_path, e);
+        })?;
+    let mut qcow = QcowFile::from_path(file, Path::new(&cmd.file_path), disk::MAX_NESTING_DEPTH)
+        .map_err(|e| {
+            error!("Failed to open qcow file at '{}': {}", cmd.file_path, e);
+        })?;
+    let result = qcow.check(cmd.repair).map_err(|e| {
+        error!("Failed to check qcow file at '{}': {}", cmd.file_path, e);
+    })?;
+
+    if cmd.json {
+        let result_json = serde_json::to_string_pretty(&result)
+            .map_err(|e| error!("Failed to serialize into JSON: {}", e))?;
+        println!("{}", result_json);
+    } else {
+        for leak in &result.leaks {
+            println!(
+                "Leaked cluster at {:#x}: refcount={} references={}",
+                leak.offset, leak.refcount, leak.references
+            );
+        }
+        for err in &result.errors {
+            println!(
+                "ERROR cluster at {:#x}: refcount={} references={}",
+                err.offset, err.refcount, err.references
+            );
+        }
+        if result.is_clean() {
+            println!("No errors were found on the image.");
+        } else {
+            println!(
+                "{} leaked clusters and {} errors were found on the image.",
+                result.leaks.len(),
+                result.errors.len()
+            );
+        }
+        if result.repaired {
+            println!("The refcounts have been rebuilt.");
+        }
+    }
+
+    // Leaks only waste space, only fail for errors that could corrupt data.
+    if result.errors.is_empty() || result.repaired {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+#[cfg(feature = "qcow")]
+fn open_qcow_for_writing(file_path: &str) -> std::result::Result<QcowFile, ()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file_path)
+        .map_err(|e| {
+            error!("Failed opening qcow file at '{}': {}", file_path, e);
+        })?;
+    QcowFile::from_path(file, Path::new(file_path), disk::MAX_NESTING_DEPTH).map_err(|e| {
+        error!("Failed to open qcow file at '{}': {}", file_path, e);
+    })
+}
+
+#[cfg(feature = "qcow")]
+fn disk_commit(cmd: cmdline::CommitDiskSubcommand) -> std::result::Result<(), ()> {
+    let mut qcow = open_qcow_for_writing(&cmd.file_path)?;
+    let backing_path = match qcow.backing_file_path() {
+        Some(path) => disk::backing_file_full_path(Path::new(&cmd.file_path), path),
+        None => {
+            error!("'{}' has no backing file", cmd.file_path);
+            return Err(());
+        }
+    };
+    let backing_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&backing_path)
+        .map_err(|e| {
+            error!(
+                "Failed opening backing file at '{}': {}",
+                backing_path.display(),
+                e
+            );
+        })?;
+    qcow.commit(backing_file, disk::MAX_NESTING_DEPTH)
+        .map_err(|e| {
+            error!(
+                "Failed to commit '{}' to '{}': {}",
+                cmd.file_path,
+                backing_path.display(),
+                e
+            );
+        })
+}
+
+#[cfg(feature = "qcow")]
+fn disk_stream(cmd: cmdline::StreamDiskSubcommand) -> std::result::Result<(), ()> {
+    let mut qcow = open_qcow_for_writing(&cmd.file_path)?;
+    qcow.stream().map_err(|e| {
+        error!(
+            "Failed to stream backing file into '{}': {}",
+            cmd.file_path, e
+        );
+    })
+}
+
 fn start_device(opts: cmdline::DeviceCommand) -> std::result::Result<(), ()> {
     if let Some(async_executor) = opts.async_executor {
         cros_async::Executor::set_default_executor_kind(async_executor.into())
@@ -536,9 +767,243 @@ fn disk_cmd(cmd: cmdline::DiskCommand) -> std::result::Result<(), ()> {
             };
             vms_request(&request, cmd.socket_path)
         }
+        cmdline::DiskSubcommand::Snapshot(cmd) => {
+            use cmdline::DiskSnapshotSubcommand::*;
+            let (disk_index, command, socket_path) = match cmd.command {
+                Create(cmd) => (
+                    cmd.disk_index,
+                    DiskSnapshotCommand::Create { name: cmd.name },
+                    cmd.socket_path,
+                ),
+                List(cmd) => (cmd.disk_index, DiskSnapshotCommand::List, cmd.socket_path),
+                Apply(cmd) => (
+                    cmd.disk_index,
+                    DiskSnapshotCommand::Apply { name: cmd.name },
+                    cmd.socket_path,
+                ),
+                Delete(cmd) => (
+                    cmd.disk_index,
+                    DiskSnapshotCommand::Delete { name: cmd.name },
+                    cmd.socket_path,
+                ),
+            };
+            let request = VmRequest::DiskCommand {
+                disk_index,
+                command: DiskControlCommand::Snapshot(command),
+            };
+            match handle_request(&request, socket_path)? {
+                VmResponse::Ok => Ok(()),
+                VmResponse::DiskResponse(result) => {
+                    println!("{}", result);
+                    Ok(())
+                }
+                r => {
+                    println!("unexpected response: {}", r);
+                    Err(())
+                }
+            }
+        }
+        cmdline::DiskSubcommand::ChangeMedia(cmd) => do_disk_change_media(
+            cmd.socket_path,
+            cmd.disk_index,
+            &cmd.disk_path,
+            cmd.read_only,
+        )
+        .map_err(|e| error!("{:#}", e)),
+        cmdline::DiskSubcommand::SetThrottle(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::SetThrottle(cmd.throttle),
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::Stats(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::GetStats { reset: cmd.reset },
+            };
+            match handle_request(&request, cmd.socket_path)? {
+                VmResponse::DiskResponse(DiskControlResult::Stats(stats)) => {
+                    if cmd.json {
+                        let stats_json = serde_json::to_string_pretty(&stats)
+                            .map_err(|e| error!("Failed to serialize into JSON: {}", e))?;
+                        println!("{}", stats_json);
+                    } else {
+                        print!("{}", stats);
+                    }
+                    Ok(())
+                }
+                r => {
+                    println!("unexpected response: {}", r);
+                    Err(())
+                }
+            }
+        }
+        cmdline::DiskSubcommand::Eject(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Eject,
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        #[cfg(feature = "qcow")]
+        cmdline::DiskSubcommand::Create(cmd) => create_qcow2(cmdline::CreateQcow2Command {
+            file_path: cmd.file_path,
+            size: cmd.size,
+            backing_file: cmd.backing_file,
+        }),
+        #[cfg(feature = "qcow")]
+        cmdline::DiskSubcommand::Info(cmd) => disk_info(cmd),
+        #[cfg(feature = "qcow")]
+        cmdline::DiskSubcommand::Check(cmd) => disk_check(cmd),
+        #[cfg(feature = "qcow")]
+        cmdline::DiskSubcommand::Commit(cmd) => disk_commit(cmd),
+        #[cfg(feature = "qcow")]
+        cmdline::DiskSubcommand::Stream(cmd) => disk_stream(cmd),
+        cmdline::DiskSubcommand::LiveCommit(cmd) => {
+            do_disk_commit(cmd.socket_path, cmd.disk_index, &cmd.backing_path)
+                .map_err(|e| error!("{:#}", e))
+        }
+        cmdline::DiskSubcommand::LiveStream(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Stream,
+            };
+            vms_request(&request, cmd.socket_path)
+        }
     }
 }
 
+fn scsi_cmd(cmd: cmdline::ScsiCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::ScsiSubcommand::Add(cmd) => {
+            let target = do_scsi_add(cmd.socket_path, &cmd.disk_path, cmd.read_only)
+                .map_err(|e| error!("{:#}", e))?;
+            info!(
+                "{} attached to SCSI target {}",
+                cmd.disk_path.display(),
+                target
+            );
+            Ok(())
+        }
+        cmdline::ScsiSubcommand::ChangeMedia(cmd) => {
+            do_scsi_change_media(cmd.socket_path, cmd.target, &cmd.image_path)
+                .map_err(|e| error!("{:#}", e))
+        }
+        cmdline::ScsiSubcommand::Eject(cmd) => {
+            let request = VmRequest::ScsiCommand(ScsiControlCommand::Eject {
+                target: cmd.target,
+                force: cmd.force,
+            });
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::ScsiSubcommand::Remove(cmd) => {
+            let request =
+                VmRequest::ScsiCommand(ScsiControlCommand::RemoveTarget { target: cmd.target });
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
+fn net_cmd(cmd: cmdline::NetCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::NetSubcommand::StartCapture(cmd) => {
+            do_net_start_capture(cmd.socket_path, cmd.net_index, &cmd.pcap_path)
+                .map_err(|e| error!("{:#}", e))
+        }
+        cmdline::NetSubcommand::StopCapture(cmd) => {
+            let request = VmRequest::NetCommand {
+                net_index: cmd.net_index,
+                command: NetControlCommand::StopCapture,
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::NetSubcommand::SetRateLimit(cmd) => {
+            let request = VmRequest::NetCommand {
+                net_index: cmd.net_index,
+                command: NetControlCommand::SetRateLimit(cmd.rate_limit),
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
+/// Returns the events pressing `codes` in order and releasing them in the reverse order, or only
+/// pressing or only releasing them.
+fn key_events(
+    codes: &[u16],
+    press: bool,
+    release: bool,
+) -> std::result::Result<Vec<InputEvent>, ()> {
+    if press && release {
+        error!("--press and --release are mutually exclusive");
+        return Err(());
+    }
+    let mut events = Vec::new();
+    if !release {
+        events.extend(codes.iter().map(|&code| InputEvent::Key {
+            code,
+            pressed: true,
+        }));
+    }
+    if !press {
+        events.extend(codes.iter().rev().map(|&code| InputEvent::Key {
+            code,
+            pressed: false,
+        }));
+    }
+    Ok(events)
+}
+
+fn input_cmd(cmd: cmdline::InputCommand) -> std::result::Result<(), ()> {
+    let (input_index, events, socket_path) = match cmd.command {
+        cmdline::InputSubcommand::Key(cmd) => {
+            let codes = parse_input_keys(&cmd.keys).map_err(|e| error!("{}", e))?;
+            let events = key_events(&codes, cmd.press, cmd.release)?;
+            (cmd.input_index, events, cmd.socket_path)
+        }
+        cmdline::InputSubcommand::Type(cmd) => (
+            cmd.input_index,
+            vec![InputEvent::Text(cmd.text)],
+            cmd.socket_path,
+        ),
+        cmdline::InputSubcommand::Move(cmd) => {
+            let event = if cmd.relative {
+                InputEvent::MoveBy {
+                    dx: cmd.x,
+                    dy: cmd.y,
+                }
+            } else {
+                match (u32::try_from(cmd.x), u32::try_from(cmd.y)) {
+                    (Ok(x), Ok(y)) => InputEvent::MoveTo { x, y },
+                    _ => {
+                        error!("absolute positions cannot be negative");
+                        return Err(());
+                    }
+                }
+            };
+            (cmd.input_index, vec![event], cmd.socket_path)
+        }
+        cmdline::InputSubcommand::Click(cmd) => {
+            let code = parse_input_button(&cmd.button).map_err(|e| error!("{}", e))?;
+            let events = key_events(&[code], cmd.press, cmd.release)?;
+            (cmd.input_index, events, cmd.socket_path)
+        }
+        cmdline::InputSubcommand::Touch(cmd) => {
+            let mut events: Vec<_> = cmd.frame.into_iter().map(InputEvent::Touch).collect();
+            if !cmd.hold {
+                events.push(InputEvent::Touch(Vec::new()));
+            }
+            (cmd.input_index, events, cmd.socket_path)
+        }
+    };
+    let request = VmRequest::InjectInput {
+        device: input_index,
+        events,
+    };
+    vms_request(&request, socket_path)
+}
+
 fn make_rt(cmd: cmdline::MakeRTCommand) -> std::result::Result<(), ()> {
     vms_request(&VmRequest::MakeRT, cmd.socket_path)
 }
@@ -558,18 +1023,54 @@ fn gpu_display_remove(cmd: cmdline::GpuRemoveDisplaysCommand) -> ModifyGpuResult
     do_gpu_display_remove(cmd.socket_path, cmd.display_id)
 }
 
+#[cfg(feature = "gpu")]
+fn gpu_screenshot(cmd: cmdline::GpuScreenshotCommand) -> std::result::Result<(), ()> {
+    let screenshot = do_gpu_screenshot(cmd.socket_path, cmd.display_id)
+        .map_err(|e| error!("failed to take a screenshot: {}", e))?;
+    let result = if cmd.raw {
+        std::fs::write(&cmd.output, &screenshot.rgba)
+    } else {
+        let mut png = Vec::new();
+        screenshot
+            .write_png(&mut png)
+            .and_then(|()| std::fs::write(&cmd.output, png))
+    };
+    result.map_err(|e| error!("failed to write {}: {}", cmd.output.display(), e))?;
+    println!(
+        "display {} ({}x{}) saved to {}",
+        cmd.display_id,
+        screenshot.width,
+        screenshot.height,
+        cmd.output.display()
+    );
+    Ok(())
+}
+
 #[cfg(feature = "gpu")]
 fn gpu_set_display_mouse_mode(cmd: cmdline::GpuSetDisplayMouseModeCommand) -> ModifyGpuResult {
     do_gpu_set_display_mouse_mode(cmd.socket_path, cmd.display_id, cmd.mouse_mode)
 }
 
+#[cfg(feature = "gpu")]
+fn gpu_start_recording(cmd: cmdline::GpuStartRecordingCommand) -> ModifyGpuResult {
+    do_gpu_start_recording(cmd.socket_path, cmd.display_id, &cmd.output)
+}
+
+#[cfg(feature = "gpu")]
+fn gpu_stop_recording(cmd: cmdline::GpuStopRecordingCommand) -> ModifyGpuResult {
+    do_gpu_stop_recording(cmd.socket_path, cmd.display_id)
+}
+
 #[cfg(feature = "gpu")]
 fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
     let result = match cmd.command {
         cmdline::GpuSubCommand::AddDisplays(cmd) => gpu_display_add(cmd),
         cmdline::GpuSubCommand::ListDisplays(cmd) => gpu_display_list(cmd),
         cmdline::GpuSubCommand::RemoveDisplays(cmd) => gpu_display_remove(cmd),
+        cmdline::GpuSubCommand::Screenshot(cmd) => return gpu_screenshot(cmd),
         cmdline::GpuSubCommand::SetDisplayMouseMode(cmd) => gpu_set_display_mouse_mode(cmd),
+        cmdline::GpuSubCommand::StartRecording(cmd) => gpu_start_recording(cmd),
+        cmdline::GpuSubCommand::StopRecording(cmd) => gpu_stop_recording(cmd),
     };
     match result {
         Ok(response) => {
@@ -809,13 +1310,22 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Gpu(cmd) => {
                         modify_gpu(cmd).map_err(|_| anyhow!("gpu subcommand failed"))
                     }
+                    CrossPlatformCommands::Input(cmd) => {
+                        input_cmd(cmd).map_err(|_| anyhow!("input subcommand failed"))
+                    }
                     CrossPlatformCommands::MakeRT(cmd) => {
                         make_rt(cmd).map_err(|_| anyhow!("make_rt subcommand failed"))
                     }
+                    CrossPlatformCommands::Net(cmd) => {
+                        net_cmd(cmd).map_err(|_| anyhow!("net subcommand failed"))
+                    }
                     CrossPlatformCommands::Resume(cmd) => {
                         resume_vms(cmd).map_err(|_| anyhow!("resume subcommand failed"))
                     }
                     CrossPlatformCommands::Run(_) => unreachable!(),
+                    CrossPlatformCommands::Scsi(cmd) => {
+                        scsi_cmd(cmd).map_err(|_| anyhow!("scsi subcommand failed"))
+                    }
                     CrossPlatformCommands::Stop(cmd) => {
                         stop_vms(cmd).map_err(|_| anyhow!("stop subcommand failed"))
                     }
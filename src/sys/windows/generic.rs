@@ -228,7 +228,7 @@ pub(super) fn create_gpu(
     _product_args: GpuBackendConfigProduct,
     wndproc_thread: WindowProcedureThread,
 ) -> Result<Gpu> {
-    Ok(Gpu::new(
+    Gpu::new(
         vm_evt_wrtube
             .try_clone()
             .exit_context(Exit::CloneTube, "failed to clone tube")?,
@@ -241,7 +241,7 @@ pub(super) fn create_gpu(
         features,
         &BTreeMap::new(),
         wndproc_thread,
-    ))
+    )
 }
 
 #[cfg(feature = "gpu")]
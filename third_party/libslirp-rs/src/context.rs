@@ -596,6 +596,35 @@ impl<H: CallbackHandler> Context<H> {
         Ok(())
     }
 
+    /// Forwards connections (or datagrams) received on `host_addr:host_port` to
+    /// `guest_addr:guest_port`. An unspecified `guest_addr` designates the first address leased by
+    /// the DHCP server.
+    pub fn add_hostfwd(
+        &mut self,
+        is_udp: bool,
+        host_addr: Ipv4Addr,
+        host_port: u16,
+        guest_addr: Ipv4Addr,
+        guest_port: u16,
+    ) -> io::Result<()> {
+        // SAFETY:
+        // Safe because self.slirp is guaranteed to be valid and the addresses are passed by value.
+        let ret = unsafe {
+            slirp_add_hostfwd(
+                self.slirp,
+                is_udp as c_int,
+                host_addr.into(),
+                host_port as c_int,
+                guest_addr.into(),
+                guest_port as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     pub fn connection_info(&mut self) -> &str {
         str::from_utf8(
             // TODO(b/315998194): Add safety comment
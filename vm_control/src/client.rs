@@ -8,6 +8,7 @@ use std::path::PathBuf;
 
 #[cfg(feature = "pci-hotplug")]
 use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result as AnyHowResult;
 use base::open_file_or_duplicate;
 use remain::sorted;
@@ -104,6 +105,154 @@ pub fn do_net_remove<T: AsRef<Path> + std::fmt::Debug>(
     bail!("Unsupported: pci-hotplug feature disabled");
 }
 
+/// Send a `VmRequest` to replace the image of the disk at `disk_index` with the one at
+/// `disk_path`.
+pub fn do_disk_change_media<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    disk_index: usize,
+    disk_path: &Path,
+    read_only: bool,
+) -> AnyHowResult<()> {
+    let file = open_file_or_duplicate(disk_path, OpenOptions::new().read(true).write(!read_only))
+        .with_context(|| format!("failed to open disk image {}", disk_path.display()))?;
+    // Lock the disk image to prevent other crosvm instances from using it.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    {
+        let lock_op = if read_only {
+            base::FlockOperation::LockShared
+        } else {
+            base::FlockOperation::LockExclusive
+        };
+        base::flock(&file, lock_op, true)
+            .with_context(|| format!("failed to lock disk image {}", disk_path.display()))?;
+    }
+
+    let request = VmRequest::DiskCommand {
+        disk_index,
+        command: DiskControlCommand::ChangeMedia {
+            path: disk_path.to_path_buf(),
+            file,
+            read_only,
+        },
+    };
+    match handle_request(&request, socket_path) {
+        Ok(VmResponse::Ok) => Ok(()),
+        Ok(VmResponse::Err(e)) => Err(e).context("failed to change media"),
+        Ok(r) => anyhow::bail!("unexpected response: {}", r),
+        Err(()) => anyhow::bail!("socket error"),
+    }
+}
+
+/// Send a `VmRequest` to insert the image at `image_path` into the SCSI CD-ROM drive of `target`.
+pub fn do_scsi_change_media<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    target: u8,
+    image_path: &Path,
+) -> AnyHowResult<()> {
+    let file = open_file_or_duplicate(image_path, OpenOptions::new().read(true))
+        .with_context(|| format!("failed to open disk image {}", image_path.display()))?;
+    // Lock the disk image to prevent other crosvm instances from writing to it.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    base::flock(&file, base::FlockOperation::LockShared, true)
+        .with_context(|| format!("failed to lock disk image {}", image_path.display()))?;
+
+    let request = VmRequest::ScsiCommand(ScsiControlCommand::ChangeMedia {
+        target,
+        path: image_path.to_path_buf(),
+        file,
+    });
+    match handle_request(&request, socket_path) {
+        Ok(VmResponse::Ok) => Ok(()),
+        Ok(VmResponse::Err(e)) => Err(e).context("failed to change media"),
+        Ok(r) => anyhow::bail!("unexpected response: {}", r),
+        Err(()) => anyhow::bail!("socket error"),
+    }
+}
+
+/// Send a `VmRequest` to capture the frames of the network device at `net_index` to a pcapng file
+/// created at `pcap_path`.
+pub fn do_net_start_capture<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    net_index: usize,
+    pcap_path: &Path,
+) -> AnyHowResult<()> {
+    let file = open_file_or_duplicate(
+        pcap_path,
+        OpenOptions::new().write(true).create(true).truncate(true),
+    )
+    .with_context(|| format!("failed to create {}", pcap_path.display()))?;
+
+    let request = VmRequest::NetCommand {
+        net_index,
+        command: NetControlCommand::StartCapture { file },
+    };
+    match handle_request(&request, socket_path) {
+        Ok(VmResponse::Ok) => Ok(()),
+        Ok(VmResponse::Err(e)) => Err(e).context("failed to start capture"),
+        Ok(r) => anyhow::bail!("unexpected response: {}", r),
+        Err(()) => anyhow::bail!("socket error"),
+    }
+}
+
+/// Send a `VmRequest` to attach the disk image at `disk_path` to the virtio-scsi controller.
+/// Returns the target ID of the new logical unit.
+pub fn do_scsi_add<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    disk_path: &Path,
+    read_only: bool,
+) -> AnyHowResult<u8> {
+    let file = open_file_or_duplicate(disk_path, OpenOptions::new().read(true).write(!read_only))
+        .with_context(|| format!("failed to open disk image {}", disk_path.display()))?;
+    // Lock the disk image to prevent other crosvm instances from using it.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    {
+        let lock_op = if read_only {
+            base::FlockOperation::LockShared
+        } else {
+            base::FlockOperation::LockExclusive
+        };
+        base::flock(&file, lock_op, true)
+            .with_context(|| format!("failed to lock disk image {}", disk_path.display()))?;
+    }
+
+    let request = VmRequest::ScsiCommand(ScsiControlCommand::AddDisk {
+        path: disk_path.to_path_buf(),
+        file,
+        read_only,
+    });
+    match handle_request(&request, socket_path) {
+        Ok(VmResponse::ScsiHotPlugResponse { target }) => Ok(target),
+        Ok(VmResponse::Err(e)) => Err(e).context("failed to attach disk"),
+        Ok(r) => anyhow::bail!("unexpected response: {}", r),
+        Err(()) => anyhow::bail!("socket error"),
+    }
+}
+
+/// Send a `VmRequest` to merge the image of the disk at `disk_index` into its backing file at
+/// `backing_path`.
+/// The device refuses the request if `backing_path` isn't the backing file its image was opened
+/// with.
+pub fn do_disk_commit<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    disk_index: usize,
+    backing_path: &Path,
+) -> AnyHowResult<()> {
+    let backing_file =
+        open_file_or_duplicate(backing_path, OpenOptions::new().read(true).write(true))
+            .with_context(|| format!("failed to open backing file {}", backing_path.display()))?;
+
+    let request = VmRequest::DiskCommand {
+        disk_index,
+        command: DiskControlCommand::Commit { backing_file },
+    };
+    match handle_request(&request, socket_path) {
+        Ok(VmResponse::Ok) => Ok(()),
+        Ok(VmResponse::Err(e)) => Err(e).context("failed to commit disk image"),
+        Ok(r) => anyhow::bail!("unexpected response: {}", r),
+        Err(()) => anyhow::bail!("socket error"),
+    }
+}
+
 pub fn do_usb_attach<T: AsRef<Path> + std::fmt::Debug>(
     socket_path: T,
     dev_path: &Path,
@@ -5,8 +5,21 @@
 use std::collections::BTreeMap as Map;
 use std::fmt;
 use std::fmt::Display;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
 
+use base::open_file_or_duplicate;
+use base::with_as_descriptor;
+use base::MemoryMappingBuilder;
+use base::MmapError;
+use base::SharedMemory;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use flate2::Crc;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
@@ -70,6 +83,9 @@ pub struct DisplayParameters {
     pub __horizontal_dpi_compat: Option<u32>,
     #[serde(rename = "vertical-dpi")]
     pub __vertical_dpi_compat: Option<u32>,
+    /// Path of a video file to record the frames shown on the display to, from startup.
+    #[serde(default)]
+    pub record: Option<PathBuf>,
 }
 
 impl DisplayParameters {
@@ -87,6 +103,7 @@ impl DisplayParameters {
             dpi: Some((horizontal_dpi, vertical_dpi)),
             __horizontal_dpi_compat: None,
             __vertical_dpi_compat: None,
+            record: None,
         }
     }
 
@@ -130,23 +147,46 @@ pub enum GpuControlCommand {
     RemoveDisplays {
         display_ids: Vec<u32>,
     },
+    Screenshot {
+        display_id: u32,
+    },
     SetDisplayMouseMode {
         display_id: u32,
         mouse_mode: MouseMode,
     },
+    /// Record the frames shown on a display to `file`, which was opened from `path`. The format
+    /// of the recording is chosen from the extension of `path`.
+    StartRecording {
+        display_id: u32,
+        path: PathBuf,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    StopRecording {
+        display_id: u32,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum GpuControlResult {
     DisplaysUpdated,
     DisplayList {
         displays: Map<u32, DisplayParameters>,
     },
+    /// Contents of a display as RGBA8888 pixels, row by row without padding. The pixels are passed
+    /// in shared memory as they would not fit in a control socket message.
+    DisplayScreenshot {
+        width: u32,
+        height: u32,
+        rgba: SharedMemory,
+    },
     TooManyDisplays(usize),
     NoSuchDisplay {
         display_id: u32,
     },
     DisplayMouseModeSet,
+    DisplayRecordingStarted,
+    DisplayRecordingStopped,
     ErrString(String),
 }
 
@@ -164,9 +204,14 @@ impl Display for GpuControlResult {
                     serde_json::to_string_pretty(&json).map_err(|_| std::fmt::Error)?;
                 write!(f, "{}", json_pretty)
             }
+            DisplayScreenshot { width, height, .. } => {
+                write!(f, "display_screenshot {}x{}", width, height)
+            }
             TooManyDisplays(n) => write!(f, "too_many_displays {}", n),
             NoSuchDisplay { display_id } => write!(f, "no_such_display {}", display_id),
             DisplayMouseModeSet => write!(f, "display_mouse_mode_set"),
+            DisplayRecordingStarted => write!(f, "display_recording_started"),
+            DisplayRecordingStopped => write!(f, "display_recording_stopped"),
             ErrString(reason) => write!(f, "err_string {}", reason),
         }
     }
@@ -177,6 +222,8 @@ pub enum ModifyGpuError {
     UnexpectedResponse(VmResponse),
     UnknownCommand(String),
     GpuControl(GpuControlResult),
+    ReadScreenshot(MmapError),
+    CreateRecording(base::Error),
 }
 
 impl fmt::Display for ModifyGpuError {
@@ -188,6 +235,8 @@ impl fmt::Display for ModifyGpuError {
             UnexpectedResponse(r) => write!(f, "unexpected response: {}", r),
             UnknownCommand(c) => write!(f, "unknown display command: `{}`", c),
             GpuControl(e) => write!(f, "{}", e),
+            ReadScreenshot(e) => write!(f, "failed to read screenshot: {}", e),
+            CreateRecording(e) => write!(f, "failed to create recording file: {}", e),
         }
     }
 }
@@ -245,3 +294,184 @@ pub fn do_gpu_set_display_mouse_mode<T: AsRef<Path> + std::fmt::Debug>(
         .map_err(|_| ModifyGpuError::SocketFailed)?
         .into()
 }
+
+pub fn do_gpu_start_recording<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    path: &Path,
+) -> ModifyGpuResult {
+    let file = open_file_or_duplicate(
+        path,
+        OpenOptions::new().write(true).create(true).truncate(true),
+    )
+    .map_err(ModifyGpuError::CreateRecording)?;
+    let request = VmRequest::GpuCommand(GpuControlCommand::StartRecording {
+        display_id,
+        path: path.to_path_buf(),
+        file,
+    });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
+
+pub fn do_gpu_stop_recording<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+) -> ModifyGpuResult {
+    let request = VmRequest::GpuCommand(GpuControlCommand::StopRecording { display_id });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
+
+/// Contents of a display returned by `do_gpu_screenshot`.
+pub struct GpuScreenshot {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA8888 pixels, row by row without padding.
+    pub rgba: Vec<u8>,
+}
+
+impl GpuScreenshot {
+    /// Writes the screenshot as a PNG image.
+    pub fn write_png<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let row_size = self.width as usize * 4;
+        if row_size == 0 || self.rgba.len() != row_size * self.height as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "screenshot size does not match its dimensions",
+            ));
+        }
+
+        w.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // 8-bit RGBA, deflate compression, adaptive filtering and no interlacing.
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        write_png_chunk(&mut w, b"IHDR", &header)?;
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for row in self.rgba.chunks_exact(row_size) {
+            // Each row starts with its filter type, which is always "none" here.
+            encoder.write_all(&[0])?;
+            encoder.write_all(row)?;
+        }
+        write_png_chunk(&mut w, b"IDAT", &encoder.finish()?)?;
+
+        write_png_chunk(&mut w, b"IEND", &[])
+    }
+}
+
+fn write_png_chunk<W: Write>(w: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "PNG chunk is too large"))?;
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(chunk_type)?;
+    w.write_all(data)?;
+    w.write_all(&crc.sum().to_be_bytes())
+}
+
+pub fn do_gpu_screenshot<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+) -> std::result::Result<GpuScreenshot, ModifyGpuError> {
+    let request = VmRequest::GpuCommand(GpuControlCommand::Screenshot { display_id });
+    let result: ModifyGpuResult = handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into();
+    match result? {
+        GpuControlResult::DisplayScreenshot {
+            width,
+            height,
+            rgba,
+        } => {
+            let mut pixels = vec![0u8; rgba.size() as usize];
+            MemoryMappingBuilder::new(pixels.len())
+                .from_shared_memory(&rgba)
+                .build()
+                .map_err(ModifyGpuError::ReadScreenshot)?
+                .read_slice(&mut pixels, 0)
+                .map_err(ModifyGpuError::ReadScreenshot)?;
+            Ok(GpuScreenshot {
+                width,
+                height,
+                rgba: pixels,
+            })
+        }
+        r => Err(ModifyGpuError::GpuControl(r)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    // Splits a PNG file into its chunks, checking their CRC.
+    fn png_chunks(mut png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        png = &png[8..];
+        let mut chunks = Vec::new();
+        while !png.is_empty() {
+            let len = u32::from_be_bytes(png[..4].try_into().unwrap()) as usize;
+            let chunk_type: [u8; 4] = png[4..8].try_into().unwrap();
+            let data = png[8..8 + len].to_vec();
+            let mut crc = Crc::new();
+            crc.update(&png[4..8 + len]);
+            assert_eq!(
+                u32::from_be_bytes(png[8 + len..12 + len].try_into().unwrap()),
+                crc.sum()
+            );
+            chunks.push((chunk_type, data));
+            png = &png[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn screenshot_png() {
+        let screenshot = GpuScreenshot {
+            width: 2,
+            height: 2,
+            rgba: (0..16).collect(),
+        };
+        let mut png = Vec::new();
+        screenshot.write_png(&mut png).unwrap();
+
+        let chunks = png_chunks(&png);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(&chunks[0].0, b"IHDR");
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+        assert_eq!(&chunks[1].0, b"IDAT");
+        let mut image = Vec::new();
+        ZlibDecoder::new(&chunks[1].1[..])
+            .read_to_end(&mut image)
+            .unwrap();
+        assert_eq!(
+            image,
+            [0, 0, 1, 2, 3, 4, 5, 6, 7, 0, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        assert_eq!(&chunks[2].0, b"IEND");
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[test]
+    fn screenshot_png_size_mismatch() {
+        let screenshot = GpuScreenshot {
+            width: 2,
+            height: 2,
+            rgba: vec![0; 12],
+        };
+        assert!(screenshot.write_png(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,655 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Synthetic events injected into the virtio-input devices through the control socket.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+
+use base::error;
+use base::Error as SysError;
+use base::StreamChannel;
+use libc::EIO;
+use linux_input_sys::constants::*;
+use linux_input_sys::virtio_input_event;
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use zerocopy::AsBytes;
+
+use crate::VmResponse;
+
+/// Maximum number of simultaneous contacts of a touch event, which is the number of slots the
+/// multi-touch devices created by crosvm have.
+pub const MAX_TOUCH_CONTACTS: usize = 10;
+
+/// Maximum number of `virtio_input_event`s sent to a device in a single message, which the device
+/// must be able to read at once. Messages only hold whole reports, the largest of which is a touch
+/// event of `MAX_TOUCH_CONTACTS` contacts.
+pub const MAX_INJECTED_MESSAGE_EVENTS: usize = 4096;
+
+#[sorted]
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InputEventError {
+    #[error("{0} touch contacts given, at most {MAX_TOUCH_CONTACTS} are supported")]
+    TooManyContacts(usize),
+    #[error("{0:?} cannot be typed on a US keyboard")]
+    UntypableCharacter(char),
+}
+
+/// A high-level input event, translated into the `virtio_input_event`s sent to the guest.
+///
+/// The events a device does not support are dropped by the guest, so that pointer events can be
+/// sent to a mouse, a tablet or a touchscreen alike.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Press or release a key or button, given by its Linux `KEY_*` or `BTN_*` code.
+    Key { code: u16, pressed: bool },
+    /// Type each character of the string, assuming a US layout in the guest.
+    Text(String),
+    /// Move the pointer to absolute coordinates.
+    MoveTo { x: u32, y: u32 },
+    /// Move the pointer by the given offsets.
+    MoveBy { dx: i32, dy: i32 },
+    /// Set the positions of the fingers touching the device, the fingers missing from the list
+    /// being lifted. The same finger must keep the same index across events.
+    Touch(Vec<(u32, u32)>),
+}
+
+impl InputEvent {
+    /// Appends the `virtio_input_event`s making up this event to `events`, each group of events
+    /// being terminated by a `SYN_REPORT`.
+    fn to_virtio_events(
+        &self,
+        events: &mut Vec<virtio_input_event>,
+    ) -> Result<(), InputEventError> {
+        match self {
+            InputEvent::Key { code, pressed } => {
+                events.push(virtio_input_event::key(*code, *pressed, false));
+                events.push(virtio_input_event::syn());
+            }
+            InputEvent::Text(text) => {
+                for c in text.chars() {
+                    let (code, shift) =
+                        char_to_key(c).ok_or(InputEventError::UntypableCharacter(c))?;
+                    if shift {
+                        events.push(virtio_input_event::key(KEY_LEFTSHIFT, true, false));
+                    }
+                    events.push(virtio_input_event::key(code, true, false));
+                    events.push(virtio_input_event::syn());
+                    events.push(virtio_input_event::key(code, false, false));
+                    if shift {
+                        events.push(virtio_input_event::key(KEY_LEFTSHIFT, false, false));
+                    }
+                    events.push(virtio_input_event::syn());
+                }
+            }
+            InputEvent::MoveTo { x, y } => {
+                events.push(virtio_input_event::absolute_x(*x as i32));
+                events.push(virtio_input_event::absolute_y(*y as i32));
+                events.push(virtio_input_event::syn());
+            }
+            InputEvent::MoveBy { dx, dy } => {
+                events.push(virtio_input_event::relative_x(*dx));
+                events.push(virtio_input_event::relative_y(*dy));
+                events.push(virtio_input_event::syn());
+            }
+            InputEvent::Touch(contacts) => {
+                if contacts.len() > MAX_TOUCH_CONTACTS {
+                    return Err(InputEventError::TooManyContacts(contacts.len()));
+                }
+                push_touch_events(contacts, events);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends the events of a report setting the positions of at most `MAX_TOUCH_CONTACTS` fingers.
+fn push_touch_events(contacts: &[(u32, u32)], events: &mut Vec<virtio_input_event>) {
+    // The slots of the lifted fingers are reset unconditionally, the guest ignoring the values
+    // that do not change.
+    for slot in 0..MAX_TOUCH_CONTACTS {
+        events.push(virtio_input_event::multitouch_slot(slot as i32));
+        match contacts.get(slot) {
+            Some((x, y)) => {
+                events.push(virtio_input_event::multitouch_tracking_id(slot as i32));
+                events.push(virtio_input_event::multitouch_absolute_x(*x as i32));
+                events.push(virtio_input_event::multitouch_absolute_y(*y as i32));
+            }
+            None => events.push(virtio_input_event::multitouch_tracking_id(-1)),
+        }
+    }
+    // Single-touch devices follow the first finger.
+    events.push(virtio_input_event::touch(!contacts.is_empty()));
+    if let Some((x, y)) = contacts.first() {
+        events.push(virtio_input_event::absolute_x(*x as i32));
+        events.push(virtio_input_event::absolute_y(*y as i32));
+    }
+    events.push(virtio_input_event::syn());
+}
+
+/// Translates `events` into the `virtio_input_event`s to send to the device.
+pub fn virtio_input_events(
+    events: &[InputEvent],
+) -> Result<Vec<virtio_input_event>, InputEventError> {
+    let mut virtio_events = Vec::new();
+    for event in events {
+        event.to_virtio_events(&mut virtio_events)?;
+    }
+    Ok(virtio_events)
+}
+
+fn is_syn_report(event: &virtio_input_event) -> bool {
+    event.type_.to_native() == EV_SYN && event.code.to_native() == SYN_REPORT
+}
+
+/// Updates the set of keys and buttons held down after `event`.
+fn update_pressed_keys(pressed: &mut BTreeSet<u16>, event: &virtio_input_event) {
+    if event.type_.to_native() == EV_KEY {
+        if event.value.to_native() == 0 {
+            pressed.remove(&event.code.to_native());
+        } else {
+            pressed.insert(event.code.to_native());
+        }
+    }
+}
+
+/// Splits `events` into messages of at most `MAX_INJECTED_MESSAGE_EVENTS` whole reports. Messages
+/// preferably end after a report that leaves no key pressed, so that a batch cut short between two
+/// messages is unlikely to leave keys stuck.
+fn split_messages(events: &[virtio_input_event]) -> Vec<&[virtio_input_event]> {
+    let mut messages = Vec::new();
+    let mut start = 0;
+    // The end of the last report, and of the last report after which no key is pressed.
+    let mut report_end = 0;
+    let mut released_end = 0;
+    let mut pressed = BTreeSet::new();
+    for (i, event) in events.iter().enumerate() {
+        update_pressed_keys(&mut pressed, event);
+        if !is_syn_report(event) {
+            continue;
+        }
+        while i + 1 - start > MAX_INJECTED_MESSAGE_EVENTS {
+            let end = if released_end > start {
+                released_end
+            } else {
+                report_end
+            };
+            if end <= start {
+                break;
+            }
+            messages.push(&events[start..end]);
+            start = end;
+        }
+        report_end = i + 1;
+        if pressed.is_empty() {
+            released_end = i + 1;
+        }
+    }
+    if start < events.len() {
+        messages.push(&events[start..]);
+    }
+    messages
+}
+
+/// Returns the events releasing the keys and buttons, and lifting the fingers, that `events` leave
+/// pressed.
+fn release_events(events: &[virtio_input_event]) -> Vec<virtio_input_event> {
+    let mut pressed = BTreeSet::new();
+    for event in events {
+        update_pressed_keys(&mut pressed, event);
+    }
+    let mut release = Vec::new();
+    if pressed.remove(&BTN_TOUCH) {
+        push_touch_events(&[], &mut release);
+    }
+    if !pressed.is_empty() {
+        for code in pressed {
+            release.push(virtio_input_event::key(code, false, false));
+        }
+        release.push(virtio_input_event::syn());
+    }
+    release
+}
+
+/// Sends `events` to a virtio-input device through the host end of its injection channel, which
+/// must be non-blocking so that a device whose guest driver is not loaded cannot block the caller.
+///
+/// The events are sent in as few messages as possible, each of which the device queues entirely or
+/// not at all. If the device stops reading before the whole batch is sent, the keys left pressed by
+/// the part that was sent are released.
+pub fn handle_inject_input(events: &[InputEvent], channel: &mut StreamChannel) -> VmResponse {
+    let virtio_events = match virtio_input_events(events) {
+        Ok(virtio_events) => virtio_events,
+        Err(e) => return VmResponse::ErrString(e.to_string()),
+    };
+
+    let mut sent = 0;
+    for message in split_messages(&virtio_events) {
+        match channel.write(message.as_bytes()) {
+            Ok(_) => sent += message.len(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let release = release_events(&virtio_events[..sent]);
+                if !release.is_empty() {
+                    if let Err(e) = channel.write(release.as_bytes()) {
+                        error!("failed to release the keys of a partial input batch: {}", e);
+                    }
+                }
+                return VmResponse::ErrString(format!(
+                    "the input device is not reading events, is its guest driver loaded? {} of {} \
+                     events were sent",
+                    sent,
+                    virtio_events.len()
+                ));
+            }
+            Err(e) => {
+                error!("failed to inject input events: {}", e);
+                return VmResponse::Err(SysError::new(EIO));
+            }
+        }
+    }
+    VmResponse::Ok
+}
+
+/// Returns the key code and whether shift must be held to type `c` on a US keyboard.
+fn char_to_key(c: char) -> Option<(u16, bool)> {
+    let key = match c {
+        'a'..='z' => (LETTER_KEYS[c as usize - 'a' as usize], false),
+        'A'..='Z' => (LETTER_KEYS[c as usize - 'A' as usize], true),
+        '1'..='9' => (KEY_1 + (c as u16 - '1' as u16), false),
+        '0' => (KEY_0, false),
+        '!' => (KEY_1, true),
+        '@' => (KEY_2, true),
+        '#' => (KEY_3, true),
+        '$' => (KEY_4, true),
+        '%' => (KEY_5, true),
+        '^' => (KEY_6, true),
+        '&' => (KEY_7, true),
+        '*' => (KEY_8, true),
+        '(' => (KEY_9, true),
+        ')' => (KEY_0, true),
+        ' ' => (KEY_SPACE, false),
+        '\n' => (KEY_ENTER, false),
+        '\t' => (KEY_TAB, false),
+        '-' => (KEY_MINUS, false),
+        '_' => (KEY_MINUS, true),
+        '=' => (KEY_EQUAL, false),
+        '+' => (KEY_EQUAL, true),
+        '[' => (KEY_LEFTBRACE, false),
+        '{' => (KEY_LEFTBRACE, true),
+        ']' => (KEY_RIGHTBRACE, false),
+        '}' => (KEY_RIGHTBRACE, true),
+        '\\' => (KEY_BACKSLASH, false),
+        '|' => (KEY_BACKSLASH, true),
+        ';' => (KEY_SEMICOLON, false),
+        ':' => (KEY_SEMICOLON, true),
+        '\'' => (KEY_APOSTROPHE, false),
+        '"' => (KEY_APOSTROPHE, true),
+        '`' => (KEY_GRAVE, false),
+        '~' => (KEY_GRAVE, true),
+        ',' => (KEY_COMMA, false),
+        '<' => (KEY_COMMA, true),
+        '.' => (KEY_DOT, false),
+        '>' => (KEY_DOT, true),
+        '/' => (KEY_SLASH, false),
+        '?' => (KEY_SLASH, true),
+        _ => return None,
+    };
+    Some(key)
+}
+
+const LETTER_KEYS: [u16; 26] = [
+    KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_H, KEY_I, KEY_J, KEY_K, KEY_L, KEY_M,
+    KEY_N, KEY_O, KEY_P, KEY_Q, KEY_R, KEY_S, KEY_T, KEY_U, KEY_V, KEY_W, KEY_X, KEY_Y, KEY_Z,
+];
+
+/// Names of the keys and buttons accepted by `key_code`, taken from the Linux constants.
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("esc", KEY_ESC),
+    ("1", KEY_1),
+    ("2", KEY_2),
+    ("3", KEY_3),
+    ("4", KEY_4),
+    ("5", KEY_5),
+    ("6", KEY_6),
+    ("7", KEY_7),
+    ("8", KEY_8),
+    ("9", KEY_9),
+    ("0", KEY_0),
+    ("minus", KEY_MINUS),
+    ("equal", KEY_EQUAL),
+    ("backspace", KEY_BACKSPACE),
+    ("tab", KEY_TAB),
+    ("q", KEY_Q),
+    ("w", KEY_W),
+    ("e", KEY_E),
+    ("r", KEY_R),
+    ("t", KEY_T),
+    ("y", KEY_Y),
+    ("u", KEY_U),
+    ("i", KEY_I),
+    ("o", KEY_O),
+    ("p", KEY_P),
+    ("leftbrace", KEY_LEFTBRACE),
+    ("rightbrace", KEY_RIGHTBRACE),
+    ("enter", KEY_ENTER),
+    ("leftctrl", KEY_LEFTCTRL),
+    ("a", KEY_A),
+    ("s", KEY_S),
+    ("d", KEY_D),
+    ("f", KEY_F),
+    ("g", KEY_G),
+    ("h", KEY_H),
+    ("j", KEY_J),
+    ("k", KEY_K),
+    ("l", KEY_L),
+    ("semicolon", KEY_SEMICOLON),
+    ("apostrophe", KEY_APOSTROPHE),
+    ("grave", KEY_GRAVE),
+    ("leftshift", KEY_LEFTSHIFT),
+    ("backslash", KEY_BACKSLASH),
+    ("z", KEY_Z),
+    ("x", KEY_X),
+    ("c", KEY_C),
+    ("v", KEY_V),
+    ("b", KEY_B),
+    ("n", KEY_N),
+    ("m", KEY_M),
+    ("comma", KEY_COMMA),
+    ("dot", KEY_DOT),
+    ("slash", KEY_SLASH),
+    ("rightshift", KEY_RIGHTSHIFT),
+    ("kpasterisk", KEY_KPASTERISK),
+    ("leftalt", KEY_LEFTALT),
+    ("space", KEY_SPACE),
+    ("capslock", KEY_CAPSLOCK),
+    ("f1", KEY_F1),
+    ("f2", KEY_F2),
+    ("f3", KEY_F3),
+    ("f4", KEY_F4),
+    ("f5", KEY_F5),
+    ("f6", KEY_F6),
+    ("f7", KEY_F7),
+    ("f8", KEY_F8),
+    ("f9", KEY_F9),
+    ("f10", KEY_F10),
+    ("numlock", KEY_NUMLOCK),
+    ("scrolllock", KEY_SCROLLLOCK),
+    ("kp7", KEY_KP7),
+    ("kp8", KEY_KP8),
+    ("kp9", KEY_KP9),
+    ("kpminus", KEY_KPMINUS),
+    ("kp4", KEY_KP4),
+    ("kp5", KEY_KP5),
+    ("kp6", KEY_KP6),
+    ("kpplus", KEY_KPPLUS),
+    ("kp1", KEY_KP1),
+    ("kp2", KEY_KP2),
+    ("kp3", KEY_KP3),
+    ("kp0", KEY_KP0),
+    ("kpdot", KEY_KPDOT),
+    ("f11", KEY_F11),
+    ("f12", KEY_F12),
+    ("kpenter", KEY_KPENTER),
+    ("rightctrl", KEY_RIGHTCTRL),
+    ("kpslash", KEY_KPSLASH),
+    ("sysrq", KEY_SYSRQ),
+    ("rightalt", KEY_RIGHTALT),
+    ("home", KEY_HOME),
+    ("up", KEY_UP),
+    ("pageup", KEY_PAGEUP),
+    ("left", KEY_LEFT),
+    ("right", KEY_RIGHT),
+    ("end", KEY_END),
+    ("down", KEY_DOWN),
+    ("pagedown", KEY_PAGEDOWN),
+    ("insert", KEY_INSERT),
+    ("delete", KEY_DELETE),
+    ("mute", KEY_MUTE),
+    ("volumedown", KEY_VOLUMEDOWN),
+    ("volumeup", KEY_VOLUMEUP),
+    ("power", KEY_POWER),
+    ("pause", KEY_PAUSE),
+    ("leftmeta", KEY_LEFTMETA),
+    ("rightmeta", KEY_RIGHTMETA),
+    ("compose", KEY_COMPOSE),
+    ("back", KEY_BACK),
+    ("forward", KEY_FORWARD),
+    ("sleep", KEY_SLEEP),
+    ("wakeup", KEY_WAKEUP),
+    ("brightnessdown", KEY_BRIGHTNESSDOWN),
+    ("brightnessup", KEY_BRIGHTNESSUP),
+    ("btn_left", BTN_LEFT),
+    ("btn_right", BTN_RIGHT),
+    ("btn_middle", BTN_MIDDLE),
+    ("btn_side", BTN_SIDE),
+    ("btn_extra", BTN_EXTRA),
+    ("btn_forward", BTN_FORWARD),
+    ("btn_back", BTN_BACK),
+    ("btn_south", BTN_SOUTH),
+    ("btn_a", BTN_A),
+    ("btn_east", BTN_EAST),
+    ("btn_b", BTN_B),
+    ("btn_north", BTN_NORTH),
+    ("btn_x", BTN_X),
+    ("btn_west", BTN_WEST),
+    ("btn_y", BTN_Y),
+    ("btn_tl", BTN_TL),
+    ("btn_tr", BTN_TR),
+    ("btn_tl2", BTN_TL2),
+    ("btn_tr2", BTN_TR2),
+    ("btn_select", BTN_SELECT),
+    ("btn_start", BTN_START),
+    ("btn_mode", BTN_MODE),
+    ("btn_thumbl", BTN_THUMBL),
+    ("btn_thumbr", BTN_THUMBR),
+    ("btn_touch", BTN_TOUCH),
+];
+
+/// Returns the code of the key or button called `name`, which is the name of its Linux constant
+/// in any case, the `KEY_` prefix being optional: "a", "KEY_ENTER", "leftctrl" or "BTN_LEFT".
+pub fn key_code(name: &str) -> Option<u16> {
+    let name = name.to_ascii_lowercase();
+    let name = name.strip_prefix("key_").unwrap_or(&name);
+    KEY_NAMES
+        .iter()
+        .find(|(key_name, _)| *key_name == name)
+        .map(|(_, code)| *code)
+}
+
+#[cfg(test)]
+mod tests {
+    use base::BlockingMode;
+    use base::FramingMode;
+
+    use super::*;
+
+    fn event(type_: u16, code: u16, value: i32) -> virtio_input_event {
+        virtio_input_event {
+            type_: type_.into(),
+            code: code.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn key_names() {
+        assert_eq!(key_code("a"), Some(KEY_A));
+        assert_eq!(key_code("KEY_ENTER"), Some(KEY_ENTER));
+        assert_eq!(key_code("LeftCtrl"), Some(KEY_LEFTCTRL));
+        assert_eq!(key_code("1"), Some(KEY_1));
+        assert_eq!(key_code("btn_left"), Some(BTN_LEFT));
+        assert_eq!(key_code("nosuchkey"), None);
+    }
+
+    #[test]
+    fn type_text() {
+        let events = virtio_input_events(&[InputEvent::Text("a!".to_owned())]).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                event(EV_KEY, KEY_A, 1),
+                event(EV_SYN, SYN_REPORT, 0),
+                event(EV_KEY, KEY_A, 0),
+                event(EV_SYN, SYN_REPORT, 0),
+                event(EV_KEY, KEY_LEFTSHIFT, 1),
+                event(EV_KEY, KEY_1, 1),
+                event(EV_SYN, SYN_REPORT, 0),
+                event(EV_KEY, KEY_1, 0),
+                event(EV_KEY, KEY_LEFTSHIFT, 0),
+                event(EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+        assert_eq!(
+            virtio_input_events(&[InputEvent::Text("é".to_owned())]),
+            Err(InputEventError::UntypableCharacter('é'))
+        );
+    }
+
+    #[test]
+    fn pointer_moves() {
+        let events = virtio_input_events(&[
+            InputEvent::MoveTo { x: 10, y: 20 },
+            InputEvent::MoveBy { dx: -1, dy: 2 },
+            InputEvent::Key {
+                code: BTN_LEFT,
+                pressed: true,
+            },
+        ])
+        .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                event(EV_ABS, ABS_X, 10),
+                event(EV_ABS, ABS_Y, 20),
+                event(EV_SYN, SYN_REPORT, 0),
+                event(EV_REL, REL_X, -1),
+                event(EV_REL, REL_Y, 2),
+                event(EV_SYN, SYN_REPORT, 0),
+                event(EV_KEY, BTN_LEFT, 1),
+                event(EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn touch_contacts() {
+        let events = virtio_input_events(&[InputEvent::Touch(vec![(1, 2), (3, 4)])]).unwrap();
+        assert_eq!(
+            events[..8],
+            [
+                event(EV_ABS, ABS_MT_SLOT, 0),
+                event(EV_ABS, ABS_MT_TRACKING_ID, 0),
+                event(EV_ABS, ABS_MT_POSITION_X, 1),
+                event(EV_ABS, ABS_MT_POSITION_Y, 2),
+                event(EV_ABS, ABS_MT_SLOT, 1),
+                event(EV_ABS, ABS_MT_TRACKING_ID, 1),
+                event(EV_ABS, ABS_MT_POSITION_X, 3),
+                event(EV_ABS, ABS_MT_POSITION_Y, 4),
+            ]
+        );
+        assert_eq!(
+            events[8..10],
+            [
+                event(EV_ABS, ABS_MT_SLOT, 2),
+                event(EV_ABS, ABS_MT_TRACKING_ID, -1),
+            ]
+        );
+        assert_eq!(
+            events[events.len() - 4..],
+            [
+                event(EV_KEY, BTN_TOUCH, 1),
+                event(EV_ABS, ABS_X, 1),
+                event(EV_ABS, ABS_Y, 2),
+                event(EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        let events = virtio_input_events(&[InputEvent::Touch(Vec::new())]).unwrap();
+        assert_eq!(events.len(), MAX_TOUCH_CONTACTS * 2 + 2);
+        assert_eq!(
+            events[events.len() - 2..],
+            [event(EV_KEY, BTN_TOUCH, 0), event(EV_SYN, SYN_REPORT, 0)]
+        );
+
+        assert_eq!(
+            virtio_input_events(&[InputEvent::Touch(vec![(0, 0); MAX_TOUCH_CONTACTS + 1])]),
+            Err(InputEventError::TooManyContacts(MAX_TOUCH_CONTACTS + 1))
+        );
+    }
+
+    #[test]
+    fn split_long_batches() {
+        // Each typed character takes 4 events, so the text fills exactly 4 messages.
+        let events =
+            virtio_input_events(&[InputEvent::Text("a".repeat(MAX_INJECTED_MESSAGE_EVENTS))])
+                .unwrap();
+        let messages = split_messages(&events);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages.concat(), events);
+
+        // Keys held during the whole batch can't be released between messages.
+        let events = virtio_input_events(&[
+            InputEvent::Key {
+                code: KEY_LEFTCTRL,
+                pressed: true,
+            },
+            InputEvent::Text("ab".repeat(MAX_INJECTED_MESSAGE_EVENTS / 4)),
+            InputEvent::Key {
+                code: KEY_LEFTCTRL,
+                pressed: false,
+            },
+        ])
+        .unwrap();
+        let messages = split_messages(&events);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages.concat(), events);
+        for message in messages {
+            assert!(message.len() <= MAX_INJECTED_MESSAGE_EVENTS);
+            assert!(is_syn_report(message.last().unwrap()));
+        }
+    }
+
+    #[test]
+    fn release_pressed_keys() {
+        let events = virtio_input_events(&[
+            InputEvent::Key {
+                code: KEY_LEFTCTRL,
+                pressed: true,
+            },
+            InputEvent::Text("c".to_owned()),
+            InputEvent::Touch(vec![(1, 2)]),
+        ])
+        .unwrap();
+        let lift = virtio_input_events(&[InputEvent::Touch(Vec::new())]).unwrap();
+        let release = release_events(&events);
+        assert_eq!(release[..lift.len()], lift);
+        assert_eq!(
+            release[lift.len()..],
+            [event(EV_KEY, KEY_LEFTCTRL, 0), event(EV_SYN, SYN_REPORT, 0)]
+        );
+
+        assert!(release_events(&lift).is_empty());
+    }
+
+    #[test]
+    fn inject_batch_in_one_message() {
+        let (mut host_channel, device_channel) =
+            StreamChannel::pair(BlockingMode::Blocking, FramingMode::Message).unwrap();
+        host_channel.set_nonblocking(true).unwrap();
+        let events = [InputEvent::Text("hi".to_owned())];
+        assert!(matches!(
+            handle_inject_input(&events, &mut host_channel),
+            VmResponse::Ok
+        ));
+        let expected = virtio_input_events(&events).unwrap();
+        assert_eq!(
+            device_channel.peek_size().unwrap(),
+            expected.as_bytes().len()
+        );
+    }
+}
@@ -15,6 +15,7 @@ pub mod api;
 pub mod gdb;
 #[cfg(feature = "gpu")]
 pub mod gpu;
+pub mod input;
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
 use base::linux::MemoryMappingBuilderUnix;
@@ -39,6 +40,7 @@ use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
 use std::fs::File;
+use std::num::NonZeroU64;
 use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::str::FromStr;
@@ -117,6 +119,7 @@ pub use crate::gdb::VcpuDebugStatusMessage;
 use crate::gpu::GpuControlCommand;
 #[cfg(feature = "gpu")]
 use crate::gpu::GpuControlResult;
+use crate::input::InputEvent;
 
 /// Control the state of a particular VM CPU.
 #[derive(Clone, Debug)]
@@ -201,6 +204,30 @@ pub const USB_CONTROL_MAX_PORTS: usize = 16;
 pub enum DiskControlCommand {
     /// Resize a disk to `new_size` in bytes.
     Resize { new_size: u64 },
+    /// Manage the internal snapshots of the disk image.
+    Snapshot(DiskSnapshotCommand),
+    /// Replace the disk image with `file`, which was opened from `path`. The device's capacity is
+    /// updated to the size of the new image.
+    ChangeMedia {
+        path: PathBuf,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+        read_only: bool,
+    },
+    /// Close the disk image, leaving the device with no medium and a capacity of zero.
+    Eject,
+    /// Replace the I/O limits of the disk.
+    SetThrottle(DiskThrottleConfig),
+    /// Merge the data of the disk image into its backing file. `backing_file` is a writable handle
+    /// to the backing file, which the device only has open read-only.
+    Commit {
+        #[serde(with = "with_as_descriptor")]
+        backing_file: File,
+    },
+    /// Copy the data of the backing file into the disk image and drop the backing file.
+    Stream,
+    /// Get the request statistics of the disk, then clear them if `reset` is set.
+    GetStats { reset: bool },
 }
 
 impl Display for DiskControlCommand {
@@ -209,22 +236,301 @@ impl Display for DiskControlCommand {
 
         match self {
             Resize { new_size } => write!(f, "disk_resize {}", new_size),
+            Snapshot(command) => write!(f, "disk_snapshot {}", command),
+            ChangeMedia {
+                path, read_only, ..
+            } => write!(
+                f,
+                "disk_change_media {} (read_only={})",
+                path.display(),
+                read_only
+            ),
+            Eject => write!(f, "disk_eject"),
+            SetThrottle(config) => write!(f, "disk_set_throttle {:?}", config),
+            Commit { .. } => write!(f, "disk_commit"),
+            Stream => write!(f, "disk_stream"),
+            GetStats { reset } => write!(f, "disk_get_stats (reset={})", reset),
+        }
+    }
+}
+
+/// I/O limits of a disk. Limits that are not set are not enforced.
+///
+/// Each burst size is the number of operations or bytes that can be used at once after the disk
+/// has been idle, and defaults to one second worth of the corresponding limit.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DiskThrottleConfig {
+    /// Maximum read requests per second.
+    pub read_iops: Option<NonZeroU64>,
+    /// Maximum write, discard and write zeroes requests per second.
+    pub write_iops: Option<NonZeroU64>,
+    /// Maximum bytes read per second.
+    pub read_bps: Option<NonZeroU64>,
+    /// Maximum bytes written per second.
+    pub write_bps: Option<NonZeroU64>,
+    pub read_iops_burst: Option<NonZeroU64>,
+    pub write_iops_burst: Option<NonZeroU64>,
+    pub read_bps_burst: Option<NonZeroU64>,
+    pub write_bps_burst: Option<NonZeroU64>,
+}
+
+/// Internal snapshot commands for disk image formats that support them, such as qcow2. Snapshots
+/// are identified by their name or ID.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DiskSnapshotCommand {
+    /// Create a snapshot named `name` of the current disk contents.
+    Create { name: String },
+    /// List the snapshots stored in the disk image.
+    List,
+    /// Revert the disk contents to snapshot `name`.
+    Apply { name: String },
+    /// Delete snapshot `name`.
+    Delete { name: String },
+}
+
+impl Display for DiskSnapshotCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DiskSnapshotCommand::*;
+
+        match self {
+            Create { name } => write!(f, "create {}", name),
+            List => write!(f, "list"),
+            Apply { name } => write!(f, "apply {}", name),
+            Delete { name } => write!(f, "delete {}", name),
         }
     }
 }
 
+/// Describes an internal snapshot of a disk image.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiskSnapshotInfo {
+    pub id: String,
+    pub name: String,
+    /// Creation time in seconds since the epoch.
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    /// Size of the VM state saved with the snapshot, zero for disk-only snapshots.
+    pub vm_state_size: u64,
+    /// Size of the virtual disk when the snapshot was taken, if recorded.
+    pub disk_size: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum DiskControlResult {
     Ok,
     Err(SysError),
+    /// Snapshots stored in the disk image.
+    Snapshots(Vec<DiskSnapshotInfo>),
+    /// Request statistics of the disk.
+    Stats(DiskStats),
 }
 
-/// Net control commands for adding and removing tap devices.
-#[cfg(feature = "pci-hotplug")]
+impl Display for DiskControlResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DiskControlResult::*;
+
+        match self {
+            Ok => write!(f, "ok"),
+            Err(e) => write!(f, "error: {}", e),
+            Snapshots(snapshots) => write!(
+                f,
+                "{}",
+                serde_json::to_string_pretty(snapshots).map_err(|_| fmt::Error)?
+            ),
+            Stats(stats) => write!(f, "{}", stats),
+        }
+    }
+}
+
+/// Commands for the logical units of the virtio-scsi controller. Logical units are identified by
+/// their target ID, the 0-based index of the `--scsi-block` option that created them or the ID
+/// returned when they were added.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ScsiControlCommand {
+    /// Insert `file`, which was opened from `path`, into the CD-ROM drive of `target`, replacing
+    /// any medium that is already present.
+    ChangeMedia {
+        target: u8,
+        path: PathBuf,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Remove the medium from the CD-ROM drive of `target`. Unless `force` is set, this fails if
+    /// the guest has prevented medium removal.
+    Eject { target: u8, force: bool },
+    /// Attach `file`, which was opened from `path`, as a new disk to the first free target.
+    AddDisk {
+        path: PathBuf,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+        read_only: bool,
+    },
+    /// Detach the logical unit of `target`.
+    RemoveTarget { target: u8 },
+}
+
+impl Display for ScsiControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ScsiControlCommand::*;
+
+        match self {
+            ChangeMedia { target, path, .. } => {
+                write!(f, "scsi_change_media {} {}", target, path.display())
+            }
+            Eject { target, force } => write!(f, "scsi_eject {} (force={})", target, force),
+            AddDisk { path, .. } => write!(f, "scsi_add_disk {}", path.display()),
+            RemoveTarget { target } => write!(f, "scsi_remove_target {}", target),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScsiControlResult {
+    Ok,
+    /// A logical unit was attached to `target`.
+    TargetAdded {
+        target: u8,
+    },
+    Err(SysError),
+}
+
+/// Distribution of the values of one measurement taken by a block device.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskHistogram {
+    pub count: u64,
+    pub sum: u64,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+    /// The buckets that hold at least one value, in increasing order.
+    pub buckets: Vec<DiskHistogramBucket>,
+}
+
+/// Number of values in the range `start..end` of a `DiskHistogram`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskHistogramBucket {
+    pub start: u64,
+    pub end: u64,
+    pub count: u64,
+}
+
+impl Display for DiskHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => {
+                writeln!(f, "min {} avg {} max {}", min, self.sum / self.count, max)?
+            }
+            _ => writeln!(f, "none")?,
+        }
+        for bucket in &self.buckets {
+            writeln!(
+                f,
+                "    [{}, {}): {}",
+                bucket.start, bucket.end, bucket.count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Statistics of one type of block request.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskRequestStats {
+    /// Number of requests that completed with an error.
+    pub errors: u64,
+    /// Time from when the device received each request until it completed, in microseconds.
+    pub latency_us: DiskHistogram,
+    /// Number of bytes covered by each request that completed successfully.
+    pub size_bytes: DiskHistogram,
+}
+
+/// Request statistics of a block device, accumulated since the device was created or the
+/// statistics were last reset.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub read: DiskRequestStats,
+    pub write: DiskRequestStats,
+    pub flush: DiskRequestStats,
+    pub discard: DiskRequestStats,
+    pub write_zeroes: DiskRequestStats,
+    /// Number of requests currently being processed.
+    pub in_flight: u64,
+    /// Number of requests being processed when each request was received, including itself.
+    pub queue_depth: DiskHistogram,
+}
+
+impl Display for DiskStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "in flight: {}", self.in_flight)?;
+        write!(f, "queue depth: {}", self.queue_depth)?;
+        for (name, stats) in [
+            ("read", &self.read),
+            ("write", &self.write),
+            ("flush", &self.flush),
+            ("discard", &self.discard),
+            ("write zeroes", &self.write_zeroes),
+        ] {
+            writeln!(
+                f,
+                "{}: {} requests, {} errors, {} bytes",
+                name, stats.latency_us.count, stats.errors, stats.size_bytes.sum
+            )?;
+            if stats.latency_us.count == 0 {
+                continue;
+            }
+            write!(f, "  latency (us): {}", stats.latency_us)?;
+            write!(f, "  size (bytes): {}", stats.size_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Net control commands for adding and removing tap devices, and for controlling the frame
+/// capture and the rate limits of a network device.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum NetControlCommand {
+    #[cfg(feature = "pci-hotplug")]
     AddTap(String),
+    #[cfg(feature = "pci-hotplug")]
     RemoveTap(u8),
+    /// Write every frame going through the device to `file`, in the pcapng format. A capture in
+    /// progress is stopped first.
+    StartCapture {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Stop the frame capture in progress, if any.
+    StopCapture,
+    /// Replace the rate limits of the device.
+    SetRateLimit(NetRateLimitConfig),
+}
+
+/// Rate limits of a network device, for the frames received and transmitted by the guest. Limits
+/// that are not set are not enforced.
+///
+/// Each burst size is the number of frames or bytes that can go through at once after the device
+/// has been idle, and defaults to one second worth of the corresponding limit.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct NetRateLimitConfig {
+    /// Maximum bytes received per second.
+    pub rx_bps: Option<NonZeroU64>,
+    /// Maximum bytes transmitted per second.
+    pub tx_bps: Option<NonZeroU64>,
+    /// Maximum frames received per second.
+    pub rx_pps: Option<NonZeroU64>,
+    /// Maximum frames transmitted per second.
+    pub tx_pps: Option<NonZeroU64>,
+    pub rx_bps_burst: Option<NonZeroU64>,
+    pub tx_bps_burst: Option<NonZeroU64>,
+    pub rx_pps_burst: Option<NonZeroU64>,
+    pub tx_pps_burst: Option<NonZeroU64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum NetControlResult {
+    Ok,
+    Err(SysError),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -1311,6 +1617,20 @@ pub enum VmRequest {
         disk_index: usize,
         command: DiskControlCommand,
     },
+    /// Send a command to the virtio-scsi controller.
+    ScsiCommand(ScsiControlCommand),
+    /// Send a command to a network device chosen by `net_index`, the 0-based index of the `--net`
+    /// option that created it.
+    NetCommand {
+        net_index: usize,
+        command: NetControlCommand,
+    },
+    /// Inject synthetic events into a virtio-input device chosen by `device`, the 0-based index of
+    /// the `--input` option that created it.
+    InjectInput {
+        device: usize,
+        events: Vec<InputEvent>,
+    },
     /// Command to use controller.
     UsbCommand(UsbControlCommand),
     /// Command to modify the gpu.
@@ -1436,6 +1756,7 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     match disk_host_tube.recv() {
         Ok(DiskControlResult::Ok) => VmResponse::Ok,
         Ok(DiskControlResult::Err(e)) => VmResponse::Err(e),
+        Ok(result) => VmResponse::DiskResponse(result),
         Err(e) => {
             error!("disk socket recv failed: {}", e);
             VmResponse::Err(SysError::new(EINVAL))
@@ -1443,6 +1764,41 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     }
 }
 
+pub fn handle_scsi_command(command: &ScsiControlCommand, scsi_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the virtio-scsi device process via its control socket.
+    if let Err(e) = scsi_host_tube.send(command) {
+        error!("scsi socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match scsi_host_tube.recv() {
+        Ok(ScsiControlResult::Ok) => VmResponse::Ok,
+        Ok(ScsiControlResult::TargetAdded { target }) => VmResponse::ScsiHotPlugResponse { target },
+        Ok(ScsiControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("scsi socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+pub fn handle_net_command(command: &NetControlCommand, net_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the virtio-net device process via its control socket.
+    if let Err(e) = net_host_tube.send(command) {
+        error!("net socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match net_host_tube.recv() {
+        Ok(NetControlResult::Ok) => VmResponse::Ok,
+        Ok(NetControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("net socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
 /// WARNING: descriptor must be a mapping handle on Windows.
 fn map_descriptor(
     descriptor: &dyn AsRawDescriptor,
@@ -1884,6 +2240,15 @@ impl VmRequest {
                 Some(tube) => handle_disk_command(command, tube),
                 None => VmResponse::Err(SysError::new(ENODEV)),
             },
+            VmRequest::ScsiCommand(_) => {
+                VmResponse::ErrString("virtio-scsi control not supported".to_owned())
+            }
+            VmRequest::NetCommand { .. } => {
+                VmResponse::ErrString("virtio-net control not supported".to_owned())
+            }
+            VmRequest::InjectInput { .. } => {
+                VmResponse::ErrString("virtio-input injection not supported".to_owned())
+            }
             #[cfg(feature = "gpu")]
             VmRequest::GpuCommand(ref cmd) => match gpu_control_tube {
                 Some(gpu_control) => {
@@ -2202,7 +2567,7 @@ pub fn do_restore(
 /// Indication of success or failure of a `VmRequest`.
 ///
 /// Success is usually indicated `VmResponse::Ok` unless there is data associated with the response.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug)]
 #[must_use]
 pub enum VmResponse {
     /// Indicates the request was executed successfully.
@@ -2226,8 +2591,12 @@ pub enum VmResponse {
     /// Results of PCI hot plug
     #[cfg(feature = "pci-hotplug")]
     PciHotPlugResponse { bus: u8 },
+    /// Results of SCSI hot plug
+    ScsiHotPlugResponse { target: u8 },
     /// Results of usb control commands.
     UsbResponse(UsbControlResult),
+    /// Results of disk control commands that return data.
+    DiskResponse(DiskControlResult),
     #[cfg(feature = "gpu")]
     /// Results of gpu control commands.
     GpuResponse(GpuControlResult),
@@ -2276,8 +2645,10 @@ impl Display for VmResponse {
                 )
             }
             UsbResponse(result) => write!(f, "usb control request get result {:?}", result),
+            DiskResponse(result) => write!(f, "{}", result),
             #[cfg(feature = "pci-hotplug")]
             PciHotPlugResponse { bus } => write!(f, "pci hotplug bus {:?}", bus),
+            ScsiHotPlugResponse { target } => write!(f, "scsi hotplug target {:?}", target),
             #[cfg(feature = "gpu")]
             GpuResponse(result) => write!(f, "gpu control request result {:?}", result),
             BatResponse(result) => write!(f, "{}", result),